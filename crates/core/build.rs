@@ -0,0 +1,36 @@
+// passes the commit being built to `version::BuildInfo` as `DEWEY_GIT_HASH`
+//
+// `GIT_COMMIT` is taken as it is when it's set, for builds outside of the repo,
+// and otherwise git is asked, with no hash if it can't say
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new("git").args(args).output().ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    (output.status.success() && !stdout.is_empty()).then_some(stdout)
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+
+    // the hash changes with whatever HEAD points to, which is only looked at if it's a file
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        let git_dir = std::path::Path::new(&git_dir);
+        let head_ref = git(&["symbolic-ref", "-q", "HEAD"]);
+        for path in std::iter::once("HEAD").chain(head_ref.as_deref()) {
+            if git_dir.join(path).is_file() {
+                println!("cargo:rerun-if-changed={}", git_dir.join(path).display());
+            }
+        }
+    }
+
+    let hash = match std::env::var("GIT_COMMIT") {
+        Ok(hash) if !hash.trim().is_empty() => Some(hash.trim().to_string()),
+        _ => git(&["rev-parse", "--short=12", "HEAD"]),
+    };
+
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=DEWEY_GIT_HASH={}", hash);
+    }
+}
@@ -0,0 +1,611 @@
+use std::collections::HashMap;
+
+use crate::cache::BlockStore;
+use crate::dbio;
+use crate::embedding::{Embedding, EmbeddingSource};
+use crate::hnsw::{Query, SearchMode, HNSW, QUERY_BLOCKS};
+use crate::logger::Logger;
+use crate::parsing::{path_source, planned_chunks, title_source, BatchCounter, SkippedSource};
+use crate::{error, info};
+
+// near-duplicate files in the store in scope, like docs copied between repos
+//
+// each file's centroid is searched for against the index to find the files it could be a copy of,
+// and the files whose centroids are close enough are grouped together
+
+// the cosine similarity between two files' centroids past which they're taken for copies
+pub const DEFAULT_DUPLICATE_THRESHOLD: f32 = 0.95;
+
+// how many of the closest chunks of other files each centroid turns up
+const DUPLICATE_NEIGHBORS: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DuplicateFile {
+    pub filepath: String,
+    // to the group's representative
+    pub similarity: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DuplicateGroup {
+    // the file most similar to the rest of the group
+    pub representative: String,
+    // everything else in the group, most similar to the representative first
+    pub files: Vec<DuplicateFile>,
+    // the most similar pair in the group
+    pub similarity: f32,
+}
+
+// centroids only keep the direction of their chunks, so they're compared by angle
+fn similarity(a: &Embedding, b: &Embedding) -> f32 {
+    crate::hnsw::dot(a, b) / (crate::hnsw::dot(a, a) * crate::hnsw::dot(b, b)).sqrt()
+}
+
+// the sets of files joined by a pair of them
+struct UnionFind {
+    parents: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(size: usize) -> Self {
+        Self {
+            parents: (0..size).collect(),
+        }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+
+        i
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parents[b.max(a)] = a.min(b);
+        }
+    }
+}
+
+// the groups of files in the store in scope whose centroids are more similar than `threshold`,
+// most similar first
+//
+// a file's own chunks are left out of its search through the candidates the meta index has,
+// so that it only ever finds other files
+pub fn find_duplicates(
+    index: &HNSW,
+    threshold: f32,
+) -> Result<Vec<DuplicateGroup>, std::io::Error> {
+    if !(-1.0..=1.0).contains(&threshold) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("duplicate threshold {} isn't between -1 and 1", threshold),
+        ));
+    }
+
+    let centroids = dbio::read_centroids()?;
+    let positions = centroids
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.source_file.filepath.as_str(), i))
+        .collect::<HashMap<_, _>>();
+
+    let entries = dbio::read_directory_entries()?;
+    let mut file_ids: HashMap<&str, Vec<u64>> = HashMap::new();
+    for (id, filepath, _) in entries.iter() {
+        file_ids.entry(filepath).or_default().push(*id as u64);
+    }
+    for ids in file_ids.values_mut() {
+        ids.sort();
+    }
+
+    // every id in the store, from the meta index if it's up to date
+    let ids = match dbio::read_meta_index()? {
+        Some(meta_index) => meta_index.ids().to_vec(),
+        None => {
+            let mut ids = entries
+                .iter()
+                .map(|(id, _, _)| *id as u64)
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        }
+    };
+
+    let store = BlockStore::new(QUERY_BLOCKS);
+    let ef = SearchMode::Balanced.ef().max(DUPLICATE_NEIGHBORS);
+    let mut pairs = HashMap::new();
+    for (i, centroid) in centroids.iter().enumerate() {
+        let own = file_ids
+            .get(centroid.source_file.filepath.as_str())
+            .map(|ids| ids.as_slice())
+            .unwrap_or_default();
+
+        let mut embedding = centroid.clone();
+        index.metric.prepare(&mut embedding);
+        let query = Query {
+            embedding,
+            filters: Vec::new(),
+            exclude_paths: true,
+            trace: false,
+            mode: SearchMode::Balanced,
+            candidates: Some(dbio::sorted_difference(&ids, own)),
+            scope: None,
+            meta: None,
+        };
+
+        for (chunk, _) in index
+            .query_in(&query, DUPLICATE_NEIGHBORS, ef, &store)
+            .results
+        {
+            let j = match positions.get(chunk.source_file.filepath.as_str()) {
+                Some(&j) if j != i => j,
+                _ => continue,
+            };
+
+            let s = similarity(centroid, &centroids[j]);
+            if s > threshold {
+                pairs.insert((i.min(j), i.max(j)), s);
+            }
+        }
+    }
+
+    let mut sets = UnionFind::new(centroids.len());
+    for &(i, j) in pairs.keys() {
+        sets.union(i, j);
+    }
+
+    let mut members: HashMap<usize, Vec<usize>> = HashMap::new();
+    for &(i, j) in pairs.keys() {
+        for k in [i, j] {
+            let root = sets.find(k);
+            let group = members.entry(root).or_default();
+            if !group.contains(&k) {
+                group.push(k);
+            }
+        }
+    }
+
+    let mut groups = members
+        .into_values()
+        .map(|group| {
+            let total = |i: usize| {
+                group
+                    .iter()
+                    .filter(|&&j| j != i)
+                    .map(|&j| similarity(&centroids[i], &centroids[j]))
+                    .sum::<f32>()
+            };
+            let representative = group
+                .iter()
+                .copied()
+                .max_by(|&a, &b| total(a).total_cmp(&total(b)))
+                .unwrap();
+
+            let mut files = group
+                .iter()
+                .filter(|&&j| j != representative)
+                .map(|&j| DuplicateFile {
+                    filepath: centroids[j].source_file.filepath.clone(),
+                    similarity: similarity(&centroids[representative], &centroids[j]),
+                })
+                .collect::<Vec<_>>();
+            files.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+
+            let similarity = pairs
+                .iter()
+                .filter(|((i, _), _)| group.contains(i))
+                .map(|(_, s)| *s)
+                .fold(f32::MIN, f32::max);
+
+            DuplicateGroup {
+                representative: centroids[representative].source_file.filepath.clone(),
+                files,
+                similarity,
+            }
+        })
+        .collect::<Vec<_>>();
+    groups.sort_by(|a, b| {
+        b.similarity
+            .total_cmp(&a.similarity)
+            .then_with(|| a.representative.cmp(&b.representative))
+    });
+
+    info!(
+        "found {} groups of near-duplicates among {} files",
+        groups.len(),
+        centroids.len()
+    );
+
+    Ok(groups)
+}
+
+// how the ledger comes out of the chunk planner under the current indexing rules,
+// for choosing rules like `--maxlength` and `--minlength` from what's actually there
+//
+// token lengths are in characters, like `max_batch_tokens`
+
+// chunk lengths at a few percentiles, by nearest rank
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LengthPercentiles {
+    pub min: usize,
+    pub p10: usize,
+    pub p50: usize,
+    pub p90: usize,
+    pub p99: usize,
+    pub max: usize,
+}
+
+// the chunks with at least `min` and fewer than `max` tokens
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct HistogramBucket {
+    pub min: usize,
+    pub max: usize,
+    pub chunks: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ExtensionStats {
+    // empty for files without one
+    pub extension: String,
+    pub files: usize,
+    pub chunks: usize,
+}
+
+// the chunks a filter rule, or being blank, kept from being embedded
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FilterStats {
+    pub removed_by: String,
+    pub chunks: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CorpusStats {
+    // the files that could be read, of those in the ledger
+    pub files: usize,
+    // the chunks of their contents, without their path and title embeddings
+    pub chunks: usize,
+    // most files first
+    pub extensions: Vec<ExtensionStats>,
+    // the span of the file each chunk covers
+    pub bytes: LengthPercentiles,
+    pub tokens: LengthPercentiles,
+    // token lengths, in buckets of powers of two
+    pub histogram: Vec<HistogramBucket>,
+    // most chunks first
+    pub removed: Vec<FilterStats>,
+    // what embedding the whole ledger would send, path and title embeddings and all
+    pub batches: usize,
+    pub skipped: Vec<SkippedSource>,
+}
+
+fn percentiles(lengths: &mut [usize]) -> LengthPercentiles {
+    if lengths.is_empty() {
+        return LengthPercentiles::default();
+    }
+
+    lengths.sort_unstable();
+    let at = |p: usize| lengths[(lengths.len() * p).div_ceil(100).max(1) - 1];
+
+    LengthPercentiles {
+        min: lengths[0],
+        p10: at(10),
+        p50: at(50),
+        p90: at(90),
+        p99: at(99),
+        max: lengths[lengths.len() - 1],
+    }
+}
+
+// every bucket from the shortest chunk's to the longest's, empty ones included
+fn histogram(lengths: &[usize]) -> Vec<HistogramBucket> {
+    let bucket = |length: usize| length.max(1).ilog2();
+    let (first, last) = match (lengths.iter().min(), lengths.iter().max()) {
+        (Some(&min), Some(&max)) => (bucket(min), bucket(max)),
+        _ => return Vec::new(),
+    };
+
+    let mut buckets = (first..=last)
+        .map(|b| HistogramBucket {
+            min: 1 << b,
+            max: 1 << (b + 1),
+            chunks: 0,
+        })
+        .collect::<Vec<_>>();
+    for &length in lengths {
+        buckets[(bucket(length) - first) as usize].chunks += 1;
+    }
+
+    buckets
+}
+
+// plans every file in the ledger into chunks the way an embed would, without embedding anything
+//
+// files are planned one at a time, and only the lengths of their chunks are kept,
+// so a large corpus is never held in memory
+//
+// a file that can't be read is skipped, and leaves nothing behind in the stats
+pub fn analyze_corpus() -> Result<CorpusStats, std::io::Error> {
+    let ledger = crate::ledger::read_ledger()?;
+    let indexing_rules = crate::ledger::get_indexing_rules()?;
+    let settings = crate::config::get_embed_settings();
+
+    let mut batches = BatchCounter::new(&settings);
+    let mut extensions: HashMap<String, ExtensionStats> = HashMap::new();
+    let mut removed: HashMap<String, usize> = HashMap::new();
+    let mut bytes = Vec::new();
+    let mut tokens = Vec::new();
+    let mut skipped = Vec::new();
+    let mut embedded = Vec::new();
+
+    for entry in ledger.iter() {
+        let source = EmbeddingSource {
+            filepath: entry.filepath.clone(),
+            meta: entry.meta.clone(),
+            subset: None,
+            hash: String::new(),
+            chunk_hash: None,
+        };
+
+        // the whole file is planned before any of it counts
+        let planned = planned_chunks(&source, &indexing_rules).and_then(|mut planned| {
+            let lengths = planned
+                .by_ref()
+                .map(|chunk| {
+                    chunk.map(|chunk| {
+                        let (start, end) = chunk.source.subset.unwrap_or((0, 0));
+                        ((end - start) as usize, chunk.text.len())
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok((planned.model, lengths, planned.removed))
+        });
+
+        let (model, lengths, file_removed) = match planned {
+            Ok(planned) => planned,
+            Err(e) => {
+                error!(
+                    "skipping {}, which couldn't be read: {}",
+                    source.filepath, e
+                );
+                skipped.push(SkippedSource::new(&source.filepath, &e));
+                continue;
+            }
+        };
+
+        let extension = std::path::Path::new(&source.filepath)
+            .extension()
+            .map(|e| e.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let stats = extensions
+            .entry(extension.clone())
+            .or_insert(ExtensionStats {
+                extension,
+                files: 0,
+                chunks: 0,
+            });
+        stats.files += 1;
+        stats.chunks += lengths.len();
+
+        for chunk in file_removed {
+            *removed.entry(chunk.removed_by).or_default() += 1;
+        }
+
+        batches.add(
+            &model,
+            lengths.iter().map(|&(_, token_length)| token_length),
+        );
+        for &(byte_length, token_length) in lengths.iter() {
+            bytes.push(byte_length);
+            tokens.push(token_length);
+        }
+
+        // a file with nothing to embed is left out, path and title embeddings and all
+        if !lengths.is_empty() {
+            embedded.push(source);
+        }
+    }
+
+    // the path and title embeddings go out after every file's contents, as they do in an embed
+    for field_source in [path_source, title_source] {
+        for source in embedded.iter() {
+            let planned = match planned_chunks(&field_source(source), &indexing_rules) {
+                Ok(planned) => planned,
+                Err(_) => continue,
+            };
+
+            let model = planned.model.clone();
+            batches.add(&model, planned.flatten().map(|chunk| chunk.text.len()));
+        }
+    }
+
+    let mut extensions = extensions.into_values().collect::<Vec<_>>();
+    extensions.sort_by(|a, b| {
+        b.files
+            .cmp(&a.files)
+            .then_with(|| a.extension.cmp(&b.extension))
+    });
+
+    let mut removed = removed
+        .into_iter()
+        .map(|(removed_by, chunks)| FilterStats { removed_by, chunks })
+        .collect::<Vec<_>>();
+    removed.sort_by(|a, b| {
+        b.chunks
+            .cmp(&a.chunks)
+            .then_with(|| a.removed_by.cmp(&b.removed_by))
+    });
+
+    let files = extensions.iter().map(|e| e.files).sum();
+    info!(
+        "analyzed {} files into {} chunks and {} batches",
+        files,
+        tokens.len(),
+        batches.batches
+    );
+
+    Ok(CorpusStats {
+        files,
+        chunks: tokens.len(),
+        extensions,
+        bytes: percentiles(&mut bytes),
+        histogram: histogram(&tokens),
+        tokens: percentiles(&mut tokens),
+        removed,
+        batches: batches.batches,
+        skipped,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync;
+    use crate::test_common::*;
+    use crate::write_file;
+
+    #[test]
+    fn find_duplicates_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let target = crate::config::get_home_dir().join("test_repo");
+        let topics = [
+            "parser tokens and grammar",
+            "network sockets and retries",
+            "cache eviction under pressure",
+            "file locks across processes",
+        ];
+        for (tf, topic) in get_tracked_files().iter().zip(topics) {
+            write_file!(target.join(tf), format!("fn main() {{ {} }}", topic));
+        }
+
+        // the same docs copied into two places, and something else entirely
+        let copied = "# Deploying\n\nBuild the release image, push it to the registry, and roll the service forward one region at a time.";
+        write_file!(target.join("deploy.txt"), copied);
+        write_file!(target.join("deploy_copy.txt"), copied);
+        write_file!(
+            target.join("recipes.txt"),
+            "Whisk the eggs with sugar until pale, then fold in flour and bake for twenty minutes."
+        );
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok());
+        let index = dbio::build_index().unwrap().unwrap();
+
+        let groups = find_duplicates(&index, DEFAULT_DUPLICATE_THRESHOLD).unwrap();
+        assert_eq!(groups.len(), 1);
+
+        let group = &groups[0];
+        let mut files = std::iter::once(group.representative.clone())
+            .chain(group.files.iter().map(|f| f.filepath.clone()))
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            ["deploy.txt", "deploy_copy.txt"]
+                .iter()
+                .map(|f| target
+                    .join(f)
+                    .canonicalize()
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string())
+                .collect::<Vec<_>>()
+        );
+        assert!(group.similarity > 0.99);
+        assert!(group.files.iter().all(|f| f.similarity > 0.99));
+
+        assert_eq!(
+            find_duplicates(&index, 1.5).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+    }
+
+    // the fixture's four tracked rust files, and two text files split by line
+    // with a filter for the short lines
+    #[test]
+    fn analyze_corpus_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let target = crate::config::get_home_dir().join("test_repo");
+        write_file!(
+            target.join("notes.txt"),
+            "short\na line that's long enough to be kept\n"
+        );
+        write_file!(target.join("todo.txt"), "also short\n");
+        let rules = crate::config::get_config_dir().join("rules");
+        let contents = std::fs::read_to_string(&rules).unwrap();
+        write_file!(
+            &rules,
+            format!("{}\ntxt --split \\n --minlength 12", contents)
+        );
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        let stats = analyze_corpus().unwrap();
+
+        assert_eq!(stats.files, 6);
+        assert_eq!(
+            stats
+                .extensions
+                .iter()
+                .map(|e| (e.extension.as_str(), e.files))
+                .collect::<Vec<_>>(),
+            [("rs", 4), ("txt", 2)]
+        );
+        assert_eq!(stats.extensions[1].chunks, 1);
+        assert_eq!(
+            stats.chunks,
+            stats.extensions.iter().map(|e| e.chunks).sum::<usize>()
+        );
+        assert!(stats.skipped.is_empty());
+
+        let removed = stats
+            .removed
+            .iter()
+            .find(|f| f.removed_by.starts_with("--minlength"))
+            .unwrap();
+        assert_eq!(removed.chunks, 2);
+
+        // the same chunks `plan_chunks` makes of each file
+        let rules = crate::ledger::get_indexing_rules().unwrap();
+        let mut planned = Vec::new();
+        let mut sources = Vec::new();
+        for entry in crate::ledger::read_ledger().unwrap() {
+            let source = EmbeddingSource {
+                filepath: entry.filepath,
+                meta: entry.meta,
+                subset: None,
+                hash: String::new(),
+                chunk_hash: None,
+            };
+            let plan = crate::parsing::plan_chunks(&source, &rules).unwrap();
+            planned.extend(plan.chunks.iter().map(|c| c.text.len()));
+            sources.push(source);
+        }
+        assert_eq!(planned.len(), stats.chunks);
+        assert_eq!(planned.iter().min(), Some(&stats.tokens.min));
+        assert_eq!(planned.iter().max(), Some(&stats.tokens.max));
+        assert!(stats.tokens.p10 <= stats.tokens.p50 && stats.tokens.p50 <= stats.tokens.p90);
+        assert_eq!(
+            stats.histogram.iter().map(|b| b.chunks).sum::<usize>(),
+            stats.chunks
+        );
+
+        // and the batches an embed would make of them
+        let path_sources = sources.iter().map(path_source).collect::<Vec<_>>();
+        let title_sources = sources.iter().map(title_source).collect::<Vec<_>>();
+        sources.extend(path_sources);
+        sources.extend(title_sources);
+        let (batches, _) =
+            crate::parsing::batch_sources(&sources, &crate::config::get_embed_settings()).unwrap();
+        assert_eq!(stats.batches, batches.len());
+    }
+}
@@ -1,10 +1,26 @@
-use dewey_lib::logger::Logger;
+use std::io::Read;
+
+use dewey_lib::lock::{DataLock, LockMode};
+use dewey_lib::logger::{LogTarget, Logger};
 use dewey_lib::lprint;
-use dewey_lib::{config, dbio, hnsw, info, ledger};
+use dewey_lib::message::{DeweyResponse, DeweyResponseItem, Granularity, GroupBy, GroupScore};
+use dewey_lib::{
+    analysis, config, dbio, export, hnsw, housekeeping, info, journal, ledger, lock, selftest,
+    sync, DeweyClient, SearchOptions, ServerState,
+};
+
+const DEFAULT_RESULTS: usize = 10;
+
+// how many of the journal's entries --journal prints without a number after it
+const DEFAULT_JOURNAL_ENTRIES: usize = 20;
 
 struct Flags {
     query: String,
     query_filters: Vec<String>,
+    results: usize,
+    offset: usize,
+    group_by: Option<GroupBy>,
+    group_score: GroupScore,
     sync: bool,
     embed: bool,
     full_embed: bool,
@@ -13,6 +29,56 @@ struct Flags {
     full_help: bool,
     test: bool,
     reblock: bool,
+    normalize_store: bool,
+    recompute_entries: bool,
+    dry_run: bool,
+    bulk: bool,
+    partial: bool,
+    resume: bool,
+    no_snapshot: bool,
+    snapshot: bool,
+    list_snapshots: bool,
+    journal: Option<usize>,
+    rollback: Option<String>,
+    backup: Option<std::path::PathBuf>,
+    restore: Option<std::path::PathBuf>,
+    blocks: bool,
+    list: bool,
+    duplicates: Option<f32>,
+    analyze: bool,
+    status: bool,
+    wait: bool,
+    stdin: bool,
+    save_query: bool,
+    include_boilerplate: bool,
+    no_cache: bool,
+    debug_query: bool,
+    model: Option<String>,
+    collection: Option<String>,
+    dedupe: bool,
+    only: Vec<String>,
+    paths: Vec<String>,
+    mode: hnsw::SearchMode,
+    granularity: Granularity,
+    export_graph: Option<(usize, std::path::PathBuf)>,
+    dump_embeddings: Option<std::path::PathBuf>,
+    vector_dims: Option<usize>,
+    import_embeddings: Option<std::path::PathBuf>,
+    lenient: bool,
+    no_housekeeping: bool,
+    yes: bool,
+    json: bool,
+    explain_chunks: Option<std::path::PathBuf>,
+    check_rules: bool,
+    schema: bool,
+    version: bool,
+    out_dir: Option<std::path::PathBuf>,
+    force: bool,
+    data_dir: Option<std::path::PathBuf>,
+    init: bool,
+    self_test: bool,
+    keep: bool,
+    rebase: Option<(String, String)>,
 }
 
 fn parse_flags() -> Flags {
@@ -20,6 +86,10 @@ fn parse_flags() -> Flags {
     let mut flags = Flags {
         query: "".to_string(),
         query_filters: Vec::new(),
+        results: DEFAULT_RESULTS,
+        offset: 0,
+        group_by: None,
+        group_score: GroupScore::Max,
         sync: false,
         embed: false,
         full_embed: false,
@@ -28,15 +98,76 @@ fn parse_flags() -> Flags {
         full_help: false,
         test: false,
         reblock: false,
+        normalize_store: false,
+        recompute_entries: false,
+        dry_run: false,
+        bulk: false,
+        partial: false,
+        resume: false,
+        no_snapshot: false,
+        snapshot: false,
+        list_snapshots: false,
+        journal: None,
+        rollback: None,
+        backup: None,
+        restore: None,
+        blocks: false,
+        list: false,
+        duplicates: None,
+        analyze: false,
+        status: false,
+        wait: false,
+        stdin: false,
+        save_query: false,
+        include_boilerplate: false,
+        no_cache: false,
+        debug_query: false,
+        model: None,
+        collection: None,
+        dedupe: false,
+        only: Vec::new(),
+        paths: Vec::new(),
+        mode: hnsw::SearchMode::Balanced,
+        granularity: Granularity::Chunk,
+        export_graph: None,
+        dump_embeddings: None,
+        vector_dims: None,
+        import_embeddings: None,
+        lenient: false,
+        no_housekeeping: false,
+        yes: false,
+        json: false,
+        explain_chunks: None,
+        check_rules: false,
+        schema: false,
+        version: false,
+        out_dir: None,
+        force: false,
+        data_dir: None,
+        init: false,
+        self_test: false,
+        keep: false,
+        rebase: None,
     };
 
-    if args.len() < 1 {
+    if args.is_empty() {
         usage();
         std::process::exit(1);
     }
 
-    for (i, arg) in args.iter().skip(1).enumerate() {
-        if arg.starts_with("-") && !arg.starts_with("--") {
+    let mut args_iter = args.iter().skip(1).peekable();
+    while let Some(arg) = args_iter.next() {
+        if arg == "-k" || arg == "--results" {
+            match args_iter.next().map(|n| n.parse::<usize>()) {
+                Some(Ok(n)) if n > 0 => flags.results = n,
+                _ => panic!("error: {} expects a positive number", arg),
+            }
+        } else if arg == "--offset" {
+            match args_iter.next().map(|n| n.parse::<usize>()) {
+                Some(Ok(n)) => flags.offset = n,
+                _ => panic!("error: --offset expects a number"),
+            }
+        } else if arg.starts_with("-") && !arg.starts_with("--") {
             for c in arg.chars().skip(1) {
                 match c {
                     's' => flags.sync = true,
@@ -53,11 +184,9 @@ fn parse_flags() -> Flags {
         } else if arg.starts_with("--") {
             match arg.as_str() {
                 "--filter" => {
-                    if let Some(filter_value) = args.get(i + 1) {
-                        if filter_value.matches(",").count() != 1 {
-                            panic!(
-                                "error: malformed filter value, expected format is 'field,value'"
-                            );
+                    if let Some(filter_value) = args_iter.next() {
+                        if let Err(e) = hnsw::Filter::from_string(filter_value) {
+                            panic!("error: malformed filter: {}", e);
                         }
 
                         flags.query_filters.push(filter_value.clone());
@@ -65,6 +194,150 @@ fn parse_flags() -> Flags {
                         panic!("error: missing filter value after --filter");
                     }
                 }
+                "--group-by" => {
+                    flags.group_by = match args_iter.next().map(|g| g.as_str()) {
+                        Some("file") => Some(GroupBy::File),
+                        Some("dir") | Some("directory") => Some(GroupBy::Directory { depth: 1 }),
+                        Some(g) => match g.strip_prefix("dir:").map(|d| d.parse::<usize>()) {
+                            Some(Ok(depth)) if depth > 0 => Some(GroupBy::Directory { depth }),
+                            _ => panic!("error: --group-by expects file, dir, or dir:DEPTH"),
+                        },
+                        None => panic!("error: missing grouping after --group-by"),
+                    }
+                }
+                "--group-score" => {
+                    flags.group_score = match args_iter.next().map(|g| g.as_str()) {
+                        Some("max") => GroupScore::Max,
+                        Some("mean") => GroupScore::Mean,
+                        _ => panic!("error: --group-score expects max or mean"),
+                    }
+                }
+                "--dry-run" => flags.dry_run = true,
+                "--bulk" => flags.bulk = true,
+                "--partial" => flags.partial = true,
+                "--resume" => flags.resume = true,
+                "--no-snapshot" => flags.no_snapshot = true,
+                "--normalize-store" => flags.normalize_store = true,
+                "--recompute-entries" => flags.recompute_entries = true,
+                "--snapshot" => flags.snapshot = true,
+                "--snapshots" => flags.list_snapshots = true,
+                "--journal" => {
+                    flags.journal = Some(match args_iter.next_if(|n| n.parse::<usize>().is_ok()) {
+                        Some(n) => n.parse().unwrap(),
+                        None => DEFAULT_JOURNAL_ENTRIES,
+                    })
+                }
+                "--blocks" => flags.blocks = true,
+                "--list" => flags.list = true,
+                "--duplicates" => {
+                    flags.duplicates = Some(match args_iter.next_if(|t| t.parse::<f32>().is_ok()) {
+                        Some(t) => t.parse().unwrap(),
+                        None => analysis::DEFAULT_DUPLICATE_THRESHOLD,
+                    })
+                }
+                "--analyze" => flags.analyze = true,
+                "--status" => flags.status = true,
+                "--wait" => flags.wait = true,
+                "--stdin" => flags.stdin = true,
+                "--save-query" => flags.save_query = true,
+                "--include-boilerplate" => flags.include_boilerplate = true,
+                "--no-cache" => flags.no_cache = true,
+                "--debug-query" => flags.debug_query = true,
+                "--model" => match args_iter.next() {
+                    Some(model) => flags.model = Some(model.clone()),
+                    None => panic!("error: missing model after --model"),
+                },
+                "--collection" => match args_iter.next() {
+                    Some(collection) => flags.collection = Some(collection.clone()),
+                    None => panic!("error: missing collection after --collection"),
+                },
+                "--dedupe" => flags.dedupe = true,
+                "--only" => match args_iter.next() {
+                    Some(glob) => flags.only.push(glob.clone()),
+                    None => panic!("error: missing glob after --only"),
+                },
+                "--in" => match args_iter.next() {
+                    Some(path) => flags.paths.push(path.clone()),
+                    None => panic!("error: missing path after --in"),
+                },
+                "--mode" => match args_iter.next().map(|m| hnsw::SearchMode::from_string(m)) {
+                    Some(Ok(mode)) => flags.mode = mode,
+                    Some(Err(e)) => panic!("error: {}", e),
+                    None => panic!("error: missing mode after --mode"),
+                },
+                "--granularity" => {
+                    flags.granularity = match args_iter.next().map(|g| g.as_str()) {
+                        Some("chunk") => Granularity::Chunk,
+                        Some("file") => Granularity::File,
+                        _ => panic!("error: --granularity expects chunk or file"),
+                    }
+                }
+                "--yes" => flags.yes = true,
+                "--json" => flags.json = true,
+                "--check-rules" => flags.check_rules = true,
+                "--schema" => flags.schema = true,
+                "--version" => flags.version = true,
+                "--out-dir" => match args_iter.next() {
+                    Some(dir) => flags.out_dir = Some(dir.into()),
+                    None => panic!("error: missing directory after --out-dir"),
+                },
+                "--force" => flags.force = true,
+                "--explain-chunks" => match args_iter.next() {
+                    Some(file) => flags.explain_chunks = Some(file.into()),
+                    None => panic!("error: missing file after --explain-chunks"),
+                },
+                "--export-graph" => {
+                    let layer = match args_iter.next().map(|l| l.parse::<usize>()) {
+                        Some(Ok(layer)) => layer,
+                        _ => panic!("error: --export-graph expects a layer number"),
+                    };
+
+                    match args_iter.next() {
+                        Some(file) => flags.export_graph = Some((layer, file.into())),
+                        None => panic!("error: missing file after --export-graph {}", layer),
+                    }
+                }
+                "--dump-embeddings" => match args_iter.next() {
+                    Some(file) => flags.dump_embeddings = Some(file.into()),
+                    None => panic!("error: missing file after --dump-embeddings"),
+                },
+                "--no-vectors" => flags.vector_dims = Some(0),
+                "--vector-dims" => match args_iter.next().map(|d| d.parse::<usize>()) {
+                    Some(Ok(dims)) => flags.vector_dims = Some(dims),
+                    _ => panic!("error: --vector-dims expects a number"),
+                },
+                "--import-embeddings" => match args_iter.next() {
+                    Some(file) => flags.import_embeddings = Some(file.into()),
+                    None => panic!("error: missing file after --import-embeddings"),
+                },
+                "--lenient" => flags.lenient = true,
+                "--no-housekeeping" => flags.no_housekeeping = true,
+                "--data-dir" => match args_iter.next() {
+                    Some(dir) => flags.data_dir = Some(dir.into()),
+                    None => panic!("error: missing directory after --data-dir"),
+                },
+                "--init" => flags.init = true,
+                "--self-test" => flags.self_test = true,
+                "--keep" => flags.keep = true,
+                "--backup" => match args_iter.next() {
+                    Some(path) => flags.backup = Some(path.into()),
+                    None => panic!("error: missing path after --backup"),
+                },
+                "--restore" => match args_iter.next() {
+                    Some(path) => flags.restore = Some(path.into()),
+                    None => panic!("error: missing path after --restore"),
+                },
+                "--rebase" => match (args_iter.next(), args_iter.next()) {
+                    (Some(old), Some(new)) => flags.rebase = Some((old.clone(), new.clone())),
+                    _ => panic!("error: --rebase expects the old and new path roots"),
+                },
+                "--rollback" => {
+                    if let Some(label) = args_iter.next() {
+                        flags.rollback = Some(label.clone());
+                    } else {
+                        panic!("error: missing snapshot label after --rollback");
+                    }
+                }
                 _ => panic!("error: unknown flag: {}", arg),
             }
         } else {
@@ -91,7 +364,9 @@ fn man() {
     println!("\x1b[1mOPTIONS\x1b[0m");
     println!("    \x1b[1m-s\x1b[0m, \x1b[1m--sync\x1b[0m");
     println!("        Synchronize the ledger with the configuration file. This updates the");
-    println!("        document collection based on your current configuration.\n");
+    println!("        document collection based on your current configuration. A file that");
+    println!("        was moved or renamed without changing keeps its embeddings under the new");
+    println!("        path instead of being embedded again.\n");
 
     println!("    \x1b[1m-e\x1b[0m, \x1b[1m--embed\x1b[0m");
     println!(
@@ -104,13 +379,300 @@ fn man() {
 
     println!("    \x1b[1m-r\x1b[0m, \x1b[1m--reindex\x1b[0m");
     println!("        Rebuild the search index using the current embeddings. This can improve");
-    println!("        search performance.\n");
+    println!("        search performance. The index is built with the metric in the config");
+    println!("        (cosine, dot, or l2; cosine by default), which queries have to match.");
+    println!("        With --only, only the nodes of the matching files are taken out and");
+    println!("        inserted again, dropping the nodes of embeddings that are gone, leaving");
+    println!("        the rest of the graph as it was. Since -e and -f give every embedding a");
+    println!("        new id, an index that's older than them is still rebuilt whole.\n");
 
     println!("    \x1b[1m-b\x1b[0m, \x1b[1m--reblock\x1b[0m");
     println!("        Reorganize the embedding blocks for optimal performance.\n");
 
-    println!("    \x1b[1m--filter\x1b[0m \x1b[4mFIELD,VALUE\x1b[0m");
-    println!("        Filter search results based on document metadata. Format: field,value\n");
+    println!("    \x1b[1m--normalize-store\x1b[0m");
+    println!("        Rewrite the embedding blocks with every embedding scaled to unit length,");
+    println!("        and keep them that way from then on, so that distances don't depend on");
+    println!("        how the embeddings were read. Only for the cosine metric. The index is");
+    println!("        rebuilt afterwards.\n");
+
+    println!("    \x1b[1m--recompute-entries\x1b[0m");
+    println!("        Pick the entry points pinned in the search index again, spread out over");
+    println!("        the embeddings as they are now, without rebuilding the index. Queries");
+    println!("        search from each of them along with the top of the index.\n");
+
+    println!("    \x1b[1m--yes\x1b[0m");
+    println!("        Let -s go through even if it would remove more of the ledger than");
+    println!("        ledger_removal_limit in the config allows (0.25 by default).\n");
+
+    println!("    \x1b[1m--json\x1b[0m");
+    println!("        Print what -s added, removed, and changed in the ledger as JSON, or the");
+    println!("        chunks from --explain-chunks, the diagnostics from --check-rules, the");
+    println!("        groups from --duplicates, the stats from --analyze, or the entries from");
+    println!("        --journal.\n");
+
+    println!("    \x1b[1m--dry-run\x1b[0m");
+    println!("        With -e or -f, report how many documents would be embedded without");
+    println!("        embedding anything. Files are also considered stale when the indexing");
+    println!("        rules for their extension have changed since they were embedded.\n");
+
+    println!("    \x1b[1m--only\x1b[0m \x1b[4mGLOB\x1b[0m");
+    println!("        With -e or -f, embed only the ledger files matching GLOB, leaving the");
+    println!("        embeddings of every other file as they are. With -r, rebuild the index");
+    println!("        over the matching files only. Can be given more than once.\n");
+
+    println!("    \x1b[1m--partial\x1b[0m");
+    println!("        With -e or -f, embed as many files as fit in the quota from max_run_chunks");
+    println!("        and max_run_tokens in the config, smallest first (or in the order of the");
+    println!("        --only globs they match), instead of stopping before embedding anything.");
+    println!("        The files left over are kept for --resume.\n");
+
+    println!("    \x1b[1m--resume\x1b[0m");
+    println!("        Embed the files a --partial embed left over, under the same quota.\n");
+
+    println!("    \x1b[1m--bulk\x1b[0m");
+    println!("        With -e or -f, write embeddings to new blocks as each batch of files is");
+    println!("        embedded, without holding everything in memory or locking the data");
+    println!("        directory while the embedding API works. The search index is left as it");
+    println!("        was, and searches miss the new embeddings until it's rebuilt with -r.\n");
+
+    println!("    \x1b[1m--snapshot\x1b[0m");
+    println!("        Save a snapshot of the embedding blocks, directory, and index.\n");
+
+    println!("    \x1b[1m--snapshots\x1b[0m");
+    println!("        List the available snapshots, oldest first.\n");
+
+    println!("    \x1b[1m--journal\x1b[0m [\x1b[4mN\x1b[0m]");
+    println!("        Print the last N operations that changed the data directory (20 by");
+    println!("        default): syncs, reblocks, edits, deletes, imports, rollbacks, and");
+    println!("        restores, with when they ran, how long they took, how they went, and the generation");
+    println!("        they left behind. The journal is kept in journal.log in the data");
+    println!("        directory. With --json, the entries are printed as JSON.\n");
+
+    println!("    \x1b[1m--rollback\x1b[0m \x1b[4mLABEL\x1b[0m");
+    println!("        Restore the data directory from a snapshot. LABEL is either the full");
+    println!("        snapshot name or its label, in which case the newest match is used.\n");
+
+    println!("    \x1b[1m--backup\x1b[0m \x1b[4mPATH\x1b[0m");
+    println!("        Copy the blocks, directory, and index to PATH, which can be on another");
+    println!("        drive, along with a manifest of their checksums. Searches and edits");
+    println!("        carry on while the files are copied, and the copy is checked against");
+    println!("        the checksums before the manifest is written, so a copy without one is");
+    println!("        never complete. Set backup_dir in the config to back up with every");
+    println!("        scheduled maintenance of a server.\n");
+
+    println!("    \x1b[1m--restore\x1b[0m \x1b[4mPATH\x1b[0m");
+    println!("        Restore the backup at PATH into an empty data directory, after checking");
+    println!("        every file against the backup's manifest.\n");
+
+    println!("    \x1b[1m--export-graph\x1b[0m \x1b[4mLAYER\x1b[0m \x1b[4mFILE\x1b[0m");
+    println!("        Write a layer of the search index to FILE, as JSON if FILE ends in .json");
+    println!("        and as a Graphviz digraph otherwise. Layer 0 is the top. Layers of more");
+    println!("        than 500 nodes are cut down to the 500 closest to the entry point.\n");
+
+    println!("    \x1b[1m--dump-embeddings\x1b[0m \x1b[4mFILE\x1b[0m");
+    println!("        Write every embedding to FILE as a line of JSON, with its id, filepath,");
+    println!("        subset, meta, and vector. Takes --filter to dump only some of them.\n");
+
+    println!("    \x1b[1m--no-vectors\x1b[0m, \x1b[1m--vector-dims\x1b[0m \x1b[4mN\x1b[0m");
+    println!("        Leave the vectors out of --dump-embeddings, or cut them to N values.\n");
+
+    println!("    \x1b[1m--import-embeddings\x1b[0m \x1b[4mFILE\x1b[0m");
+    println!("        Load embeddings made with the configured model from FILE, in the format");
+    println!("        --dump-embeddings writes. They replace any embeddings of the same files,");
+    println!("        and the files are added to the ledger as they are now. The index is");
+    println!("        updated too if there is one.\n");
+
+    println!("    \x1b[1m--lenient\x1b[0m");
+    println!("        Skip malformed lines in --import-embeddings instead of importing nothing.\n");
+
+    println!("    \x1b[1m--blocks\x1b[0m");
+    println!("        Report the embedding count, size, and fill factor of every block, along");
+    println!("        with whether reblocking would reclaim any space.\n");
+
+    println!("    \x1b[1m--list\x1b[0m");
+    println!("        List every file with embeddings, with its chunk count and when it was");
+    println!("        last embedded. Files from blocks written before embedding times were");
+    println!("        kept show as unknown until they're embedded again. With --json, print");
+    println!("        the list as JSON.\n");
+
+    println!("    \x1b[1m--duplicates\x1b[0m [\x1b[4mTHRESHOLD\x1b[0m]");
+    println!("        Report groups of files that are near copies of each other, like docs");
+    println!("        copied between repos. Each file's centroid is searched for in the index,");
+    println!("        and files whose centroids have a cosine similarity past THRESHOLD (0.95");
+    println!("        by default) are grouped, with the file most like the rest listed first.");
+    println!("        Needs an index from -r. With --json, print the groups as JSON.\n");
+
+    println!("    \x1b[1m--analyze\x1b[0m");
+    println!("        Plan every file in the ledger into chunks under the current indexing");
+    println!("        rules, without embedding anything, and report the files and chunks of");
+    println!("        each extension, percentiles and a histogram of chunk lengths, how many");
+    println!("        chunks each filter rule removed, and how many batches embedding it all");
+    println!("        would take. Tokens are counted in characters, like max_batch_tokens.");
+    println!("        With --json, print the stats as JSON.\n");
+
+    println!("    \x1b[1m--explain-chunks\x1b[0m \x1b[4mFILE\x1b[0m");
+    println!("        Print the chunks FILE would be embedded as under the current indexing");
+    println!("        rules: their byte and line ranges, lengths, and the rule behind each,");
+    println!("        along with the chunks a filter rule would drop. Nothing is embedded.\n");
+
+    println!("    \x1b[1m--check-rules\x1b[0m");
+    println!("        Report what's wrong with the indexing rules, with the line and token of");
+    println!("        each: unknown flags, invalid or missing values, splitting rules and lines");
+    println!("        a later one takes the place of, and extensions no file in the ledger has.");
+    println!("        Exits with an error if any rule is dropped. With --json, print the");
+    println!("        diagnostics as JSON. The same report is printed the first time the rules");
+    println!("        are read from a terminal.\n");
+
+    println!("    \x1b[1m--schema\x1b[0m");
+    println!("        Print JSON Schema for every message the server takes and answers with,");
+    println!("        for writing clients in other languages. The schema's version is the");
+    println!("        protocol version, which changes whenever a message changes in a way the");
+    println!("        other end can't read. Only in builds with the schema feature.\n");
+
+    println!("    \x1b[1m--status\x1b[0m");
+    println!("        Report the configured embedding model along with the models the");
+    println!("        embedding blocks and search index were made with, the metric the index");
+    println!("        compares embeddings with, and the embed_workers, max_batch_items, and");
+    println!("        max_batch_tokens embeddings are requested with, along with the");
+    println!("        api_timeout, batch_deadline (in seconds), and batch_attempts each");
+    println!("        request is held to. DEWEY_API_TIMEOUT, DEWEY_BATCH_DEADLINE, and");
+    println!("        DEWEY_BATCH_ATTEMPTS override them from the environment. The model's");
+    println!("        provider limits come from max_input_tokens and max_batch_items, or from");
+    println!("        a `provider MODEL max_input_tokens N max_batch_items M` config line.");
+    println!("        Chunks are capped to max_input_tokens, and a sync re-chunks files");
+    println!("        embedded under a provider that took more.\n");
+
+    println!("    \x1b[1m--wait\x1b[0m");
+    println!("        Wait for the data directory lock instead of exiting when another dewey");
+    println!("        process (e.g. a running server) is using it.\n");
+
+    println!("    \x1b[1m--data-dir\x1b[0m \x1b[4mDIR\x1b[0m");
+    println!("        Keep the config, ledgers, and data under DIR instead of ~/.config/dewey");
+    println!("        and ~/.local/dewey, laid out as DIR/config, DIR/data, and DIR/ledger.");
+    println!("        DIR has to exist unless --init is also given. Logs stay where they are.");
+    println!("        Without it, DEWEY_CONFIG_DIR and DEWEY_DATA_DIR take the place of");
+    println!("        ~/.config/dewey and ~/.local/dewey, which is how to run dewey where");
+    println!("        HOME is unset or `/`. Logs then go to DEWEY_DATA_DIR/logs.\n");
+
+    println!("    \x1b[1m--init\x1b[0m");
+    println!("        Create the data directory, along with empty ledgers and rules.\n");
+
+    println!("    \x1b[1m--no-snapshot\x1b[0m");
+    println!("        Skip the automatic snapshot taken before -f, -b, --normalize-store, and");
+    println!("        --import-embeddings.\n");
+
+    println!("    \x1b[1m--no-housekeeping\x1b[0m");
+    println!("        Skip the cleanup done at the end of every run, which deletes saved queries");
+    println!("        older than query_max_age_days (180) and logs older than log_max_age_days");
+    println!("        (30), gzips logs untouched for compress_logs_after_days (7), and deletes");
+    println!("        the oldest of both past housekeeping_max_bytes (512MiB) in the config.\n");
+
+    println!("    \x1b[1m--filter\x1b[0m \x1b[4mFILTER\x1b[0m");
+    println!("        Filter search results based on document metadata. FILTER is written");
+    println!("        \"[eq|ne] value\", where value is a meta tag and the comparator defaults");
+    println!("        to eq. Servers take filters in the same format. Can be repeated.");
+    println!("        When few of the nodes a search visits pass its filters, it scans the");
+    println!("        nodes carrying the eq filters' tags instead, or failing that, widens a");
+    println!("        fast search; filtered_ef_cap in the config bounds both (2000). After -e,");
+    println!("        -f, or -b, filters narrow down the nodes that can pass up front, and");
+    println!("        up to filtered_ef_cap of them are scanned without the index.\n");
+
+    println!("    \x1b[1m--in\x1b[0m \x1b[4mPATH\x1b[0m");
+    println!("        Only search the embedded files at PATH, which can be a glob like the ones");
+    println!("        --only takes. Can be repeated. Paths that match no embedded file are");
+    println!("        reported along with the results.\n");
+
+    println!("    \x1b[1m-k\x1b[0m, \x1b[1m--results\x1b[0m \x1b[4mN\x1b[0m");
+    println!("        Number of search results to print. Defaults to 10.\n");
+
+    println!("    \x1b[1m--offset\x1b[0m \x1b[4mN\x1b[0m");
+    println!("        Skip the first N results, to page through them with -k. A query's");
+    println!("        pages come from the same results until the index changes.\n");
+
+    println!("    \x1b[1m--stdin\x1b[0m");
+    println!("        Read the query from stdin instead of the command line.\n");
+
+    println!("    \x1b[1m--save-query\x1b[0m");
+    println!("        Keep a copy of the query in ~/.local/dewey/queries.\n");
+
+    println!("    \x1b[1m--include-boilerplate\x1b[0m");
+    println!("        Rank chunks shared by many files (license headers, import blocks) like");
+    println!("        any other. They're ranked lower by default; the number of files that");
+    println!("        makes a chunk boilerplate is boilerplate_threshold in the config.\n");
+
+    println!("    \x1b[1m--no-cache\x1b[0m");
+    println!("        Have the server search its index again, even if it answered the same");
+    println!("        query in the last query_cache_ttl seconds (30 by default).\n");
+
+    println!("    \x1b[1m--debug-query\x1b[0m");
+    println!("        Print how the search went through each layer of the index after the");
+    println!("        results: nodes visited and expanded, candidates dropped by filters, and");
+    println!("        whether the bottom layer ran out of nodes before filling its ef budget.\n");
+
+    println!("    \x1b[1m--out-dir\x1b[0m \x1b[4mDIR\x1b[0m");
+    println!("        Also write each result's chunk to DIR/RANK_FILENAME.txt, with the query,");
+    println!("        the state generation, and each result's path, subset, and score in");
+    println!("        DIR/manifest.json, written after the chunks. DIR is created if it doesn't");
+    println!("        exist, and refused if it isn't empty unless --force is given to clear it.\n");
+
+    println!("    \x1b[1m--model\x1b[0m \x1b[4mNAME\x1b[0m");
+    println!("        Search the embeddings made with NAME, for extensions the rules route to it");
+    println!("        with --model. Embeddings from different models are never searched");
+    println!("        together, and the configured model's are searched by default. With");
+    println!("        --collection, the query is embedded with NAME and the snapshot has to");
+    println!("        have been taken with it, to compare models across a migration.\n");
+
+    println!("    \x1b[1m--collection\x1b[0m \x1b[4msnapshot:LABEL\x1b[0m");
+    println!("        Search the snapshot LABEL finds, the same one --rollback would, instead");
+    println!("        of the live index. The snapshot is only read, and is searched by chunk");
+    println!("        with the configured model unless --model names another.\n");
+
+    println!("    \x1b[1m--dedupe\x1b[0m");
+    println!("        Drop results that are near copies of one ranked ahead of them, like the");
+    println!("        same docs copied into several repos, and fill the page with the next");
+    println!("        ones down. Results count as copies past a cosine similarity of 0.97.\n");
+
+    println!("    \x1b[1m--mode\x1b[0m \x1b[4mfast|balanced|thorough\x1b[0m");
+    println!("        How much of the index to search. fast looks at fewer candidates and cuts");
+    println!("        the search of the bottom layer short, while thorough looks at more of them");
+    println!("        starting from several entry points. Defaults to balanced.\n");
+
+    println!("    \x1b[1m--granularity\x1b[0m \x1b[4mchunk|file\x1b[0m");
+    println!("        Search whole files instead of chunks, matching each file by the mean of");
+    println!("        its chunks. A file whose chunks each match a little can come out ahead");
+    println!("        of one with a single close chunk. With --group-by dir, directories are");
+    println!("        ranked by their files. Defaults to chunk.\n");
+
+    println!("    \x1b[1m--group-by\x1b[0m \x1b[4mfile|dir|dir:DEPTH\x1b[0m");
+    println!("        Group search results by file, or by the first DEPTH directories of their");
+    println!("        path below the home directory (1 for dir), and print N groups instead.\n");
+
+    println!("    \x1b[1m--group-score\x1b[0m \x1b[4mmax|mean\x1b[0m");
+    println!("        Score groups by their best chunk or by the mean of their chunks.");
+    println!("        Defaults to max.\n");
+
+    println!("    \x1b[1m--rebase\x1b[0m \x1b[4mOLD\x1b[0m \x1b[4mNEW\x1b[0m");
+    println!(
+        "        With path_root set in the config, the ledger, blocks, and directory keep the"
+    );
+    println!("        paths under it relative to it, so an index can move along with its files.");
+    println!(
+        "        After moving the files under OLD to NEW, this points the stored paths at NEW"
+    );
+    println!("        without embedding anything again. The config ledger and path_root in the");
+    println!("        config still name OLD, and need updating by hand.\n");
+
+    println!("    \x1b[1m--self-test\x1b[0m [\x1b[1m--keep\x1b[0m]");
+    println!("        Run the whole pipeline, from syncing a ledger to compacting the blocks,");
+    println!("        over a few files in a temp dir, with embeddings that don't need the API.");
+    println!("        Prints whether each stage passed. Nothing outside the temp dir is touched");
+    println!(
+        "        but the log, and the temp dir is removed afterwards unless --keep is given.\n"
+    );
+
+    println!("    \x1b[1m--version\x1b[0m");
+    println!("        Print the version dewey was built as, the commit it was built from, the");
+    println!("        protocol version of its messages, and the features it was built with.\n");
 
     println!("    \x1b[1m-h\x1b[0m, \x1b[1m--help\x1b[0m");
     println!("        Display this help message and exit.\n");
@@ -121,8 +683,43 @@ fn man() {
     println!("            Sync the ledger and generate missing embeddings\n");
 
     println!("    Search with filters:");
-    println!("        \x1b[1mdewey \"machine learning\" --filter type,research\x1b[0m");
-    println!("            Search for \"machine learning\" in research documents\n");
+    println!(
+        "        \x1b[1mdewey \"machine learning\" --filter research --filter \"ne draft\"\x1b[0m"
+    );
+    println!(
+        "            Search for \"machine learning\" in research documents that aren't drafts\n"
+    );
+
+    println!("    Search with the contents of a file:");
+    println!("        \x1b[1mcat notes.txt | dewey --stdin -k 5\x1b[0m");
+    println!("            Print the 5 indexed chunks most similar to notes.txt\n");
+
+    println!("    Queries go to the server in ~/.config/dewey/config (\x1b[1mserver address:port\x1b[0m)");
+    println!("    when one is reachable, and are otherwise run against the local index.\n");
+
+    println!("    Ledger entries can be kept behind an acl with \x1b[1m--acl NAME\x1b[0m, e.g.");
+    println!("        \x1b[1m~/shared/hr --acl private\x1b[0m");
+    println!("    A server only returns their chunks to requests carrying a token granted the");
+    println!("    acl by a \x1b[1macl_token TOKEN NAME...\x1b[0m line in its config. Queries send");
+    println!("    DEWEY_TOKEN as theirs, and local queries see everything.\n");
+
+    println!("    Every file's title (its first heading, the first line of a leading doc comment,");
+    println!("    or its name) is embedded too. With title_weight in the config (0 to 1, 0 by");
+    println!("    default), that much of each chunk's score comes from how well the query");
+    println!("    matches its file's title.\n");
+
+    println!("    Filters match the meta a file had in the ledger when it was last blocked.");
+    println!("    With \x1b[1mlive_meta true\x1b[0m in the config, they match the ledger's meta as it is");
+    println!("    now, so retagging a directory takes effect without a reblock. Searches of a");
+    println!("    --collection still see the meta their snapshot was taken with.\n");
+
+    println!("    Boilerplate can be cut out of queries before they're embedded with rules in");
+    println!("    ~/.config/dewey/query_rules, one per line and applied in order:");
+    println!("        \x1b[1mprefix Answer using the context:\x1b[0m");
+    println!("        \x1b[1msuffix Be brief.\x1b[0m");
+    println!("        \x1b[1mbetween <question> </question>\x1b[0m");
+    println!("        \x1b[1mcollapse-whitespace\x1b[0m");
+    println!("    Saved queries keep the text as it was sent.\n");
 
     println!("    Maintenance operations:");
     println!("        \x1b[1mdewey -r -b\x1b[0m");
@@ -137,17 +734,407 @@ fn usage() {
     println!("  -f         re-embed all documents");
     println!("  -r         rebuild search index");
     println!("  -b         reblock embeddings");
-    println!("  --filter   field,value  filter results");
+    println!("  --normalize-store  rewrite embeddings at unit length");
+    println!("  --recompute-entries  pick the index's entry points again");
+    println!("  --dry-run  report what -e/-f would embed");
+    println!("  --only glob  limit -e/-f/-r to matching files");
+    println!("  --partial  let -e/-f embed what fits in the quota");
+    println!("  --resume   embed what a --partial embed left over");
+    println!("  --bulk     embed with -e/-f in batches, leaving the index for -r");
+    println!("  --yes      let -s remove a large part of the ledger");
+    println!("  --json     print the ledger changes from -s, --list, --duplicates, --explain-chunks, or --check-rules as JSON");
+    println!("  --snapshot  save a snapshot of the data directory");
+    println!("  --snapshots  list snapshots");
+    println!("  --journal [n]  print the last n operations on the data directory");
+    println!("  --rollback  label  restore a snapshot");
+    println!("  --backup path  copy the data directory to path");
+    println!("  --restore path  restore a backup into an empty data directory");
+    println!("  --no-snapshot  skip the automatic snapshot before -f/-b/--normalize-store");
+    println!("  --blocks   report block usage");
+    println!("  --list     list embedded files and when they were embedded");
+    println!("  --duplicates [threshold]  report groups of near-duplicate files");
+    println!("  --analyze  report chunk lengths and counts over the ledger");
+    println!("  --explain-chunks file  show how file would be chunked");
+    println!("  --check-rules  report problems with the indexing rules");
+    println!("  --schema   print JSON Schema for the server's messages");
+    println!("  --version  print what dewey was built from");
+    println!("  --export-graph layer file  write a layer of the index as DOT or JSON");
+    println!("  --dump-embeddings file  write embeddings as JSON lines");
+    println!("  --no-vectors  leave vectors out of the dump");
+    println!("  --vector-dims n  cut dumped vectors to n values");
+    println!("  --import-embeddings file  load embeddings from JSON lines");
+    println!("  --lenient  skip malformed lines when importing");
+    println!("  --status   report the embedding model in use");
+    println!("  --wait     wait on the data directory lock instead of exiting");
+    println!("  --data-dir dir  keep everything under dir");
+    println!("  --init     create the data directory if it doesn't exist");
+    println!("  --no-housekeeping  keep old queries and logs around");
+    println!("  --rebase old new  point paths stored under the root old at new");
+    println!("  --self-test  check the install end to end in a temp dir");
+    println!("  --keep     keep the temp dir of --self-test");
+    println!("  --filter   \"[eq|ne] value\"  filter results");
+    println!("  --in path  only search the files at path (repeatable)");
+    println!("  -k n       number of results to print");
+    println!("  --offset n skip the first n results");
+    println!("  --stdin    read the query from stdin");
+    println!("  --save-query  keep a copy of the query");
+    println!("  --include-boilerplate  don't rank boilerplate chunks lower");
+    println!("  --no-cache  skip the server's cache of recent queries");
+    println!("  --debug-query  print a trace of the search");
+    println!("  --out-dir dir  write each result's chunk and a manifest to dir");
+    println!("  --force    let --out-dir clear a directory that isn't empty");
+    println!("  --model name  search the embeddings made with another model");
+    println!("  --collection snapshot:label  search a snapshot instead of the index");
+    println!("  --dedupe   drop near copies of higher results");
+    println!("  --mode fast|balanced|thorough  trade recall for speed");
+    println!("  --granularity chunk|file  search chunks or whole files");
+    println!("  --group-by file|dir|dir:n  group results");
+    println!("  --group-score max|mean  how groups are scored");
     println!("  -h         show this message\n");
     println!("  -H         show full help message\n");
     println!("Example: dewey -se \"machine learning\"");
 }
 
+// unix seconds in local time, `unknown` for embeddings from before their times were kept
+fn format_timestamp(timestamp: i64) -> String {
+    match chrono::DateTime::from_timestamp(timestamp, 0) {
+        Some(time) if timestamp > 0 => time
+            .with_timezone(&chrono::Local)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+        _ => "unknown".to_string(),
+    }
+}
+
+// `<time> <operation> <outcome> in <duration>, generation <n>: <params>`
+fn format_journal_entry(entry: &journal::Entry) -> String {
+    let time = chrono::DateTime::parse_from_rfc3339(&entry.timestamp)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|_| entry.timestamp.clone());
+    let outcome = match &entry.outcome {
+        journal::Outcome::Completed => "completed".to_string(),
+        journal::Outcome::Failed { error } => format!("failed ({})", error),
+    };
+    let params = entry
+        .params
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    format!(
+        "{} {:<22} {} in {}ms, generation {}: {}",
+        time, entry.operation, outcome, entry.duration_ms, entry.generation, params
+    )
+}
+
+// `<stage> passed in <duration>: <detail>`
+fn format_stage(stage: &selftest::Stage) -> String {
+    let outcome = match &stage.outcome {
+        selftest::Outcome::Passed(detail) => format!("passed: {}", detail),
+        selftest::Outcome::Failed(error) => format!("FAILED: {}", error),
+        selftest::Outcome::Skipped => "skipped".to_string(),
+    };
+
+    format!(
+        "{:<10} {:>6}ms  {}",
+        stage.name,
+        stage.duration.as_millis(),
+        outcome
+    )
+}
+
+fn format_explained(chunk: &sync::ExplainedChunk) -> String {
+    format!(
+        "{}. bytes {}..{}, lines {}-{}, {} chars{} ({})",
+        chunk.index + 1,
+        chunk.bytes.0,
+        chunk.bytes.1,
+        chunk.lines.0,
+        chunk.lines.1,
+        chunk.length,
+        match &chunk.tag {
+            Some(tag) => format!(" [{}]", tag),
+            None => String::new(),
+        },
+        chunk.rule
+    )
+}
+
+fn print_explanation(explanation: &sync::ChunkExplanation) {
+    println!("{}", explanation.filepath);
+    println!(
+        "{} chunks for {}, normalized with {}",
+        explanation.chunks.len(),
+        explanation.model,
+        match explanation.transforms.is_empty() {
+            true => "nothing".to_string(),
+            false => explanation.transforms.join(", "),
+        }
+    );
+
+    for chunk in explanation.chunks.iter() {
+        println!("  {}", format_explained(chunk));
+    }
+
+    if !explanation.removed.is_empty() {
+        println!("{} removed", explanation.removed.len());
+        for chunk in explanation.removed.iter() {
+            println!(
+                "  {} by {}",
+                format_explained(chunk),
+                chunk.removed_by.as_deref().unwrap_or_default()
+            );
+        }
+    }
+}
+
+fn print_analysis(stats: &analysis::CorpusStats) {
+    println!(
+        "{} files, {} chunks, {} batches to embed",
+        stats.files, stats.chunks, stats.batches
+    );
+    for extension in stats.extensions.iter() {
+        let name = match extension.extension.is_empty() {
+            true => "(none)",
+            false => &extension.extension,
+        };
+        println!(
+            "  {:<8} {:>6} files {:>8} chunks",
+            name, extension.files, extension.chunks
+        );
+    }
+
+    for (name, lengths) in [("bytes", &stats.bytes), ("tokens", &stats.tokens)] {
+        println!(
+            "{:<6}  min {}  p10 {}  p50 {}  p90 {}  p99 {}  max {}",
+            name, lengths.min, lengths.p10, lengths.p50, lengths.p90, lengths.p99, lengths.max
+        );
+    }
+
+    let widest = stats.histogram.iter().map(|b| b.chunks).max().unwrap_or(0);
+    for bucket in stats.histogram.iter() {
+        let bar = match widest {
+            0 => 0,
+            widest => (bucket.chunks * 40).div_ceil(widest),
+        };
+        println!(
+            "  {:>7}-{:<7} {:>8} {}",
+            bucket.min,
+            bucket.max - 1,
+            bucket.chunks,
+            "#".repeat(bar)
+        );
+    }
+
+    if !stats.removed.is_empty() {
+        println!("removed");
+        for filter in stats.removed.iter() {
+            println!("  {:>8} by {}", filter.chunks, filter.removed_by);
+        }
+    }
+
+    for skipped in stats.skipped.iter() {
+        println!("skipped {}", skipped);
+    }
+}
+
+fn format_result(result: &DeweyResponseItem) -> String {
+    if result.file_match {
+        return format!(
+            "{} {:.3}{}",
+            result.filepath,
+            result.score,
+            if result.stale { " (stale)" } else { "" }
+        );
+    }
+
+    format!(
+        "{} [{}..{}] {:.3}{}{}",
+        result.filepath,
+        result.subset.0,
+        result.subset.1,
+        result.score,
+        if result.path_match { " (path)" } else { "" },
+        if result.stale { " (stale)" } else { "" }
+    )
+}
+
+fn print_trace(trace: &hnsw::QueryTrace) {
+    println!("query trace (ef {}, {} mode):", trace.ef, trace.mode);
+    if trace.filter_strategy != hnsw::FilterStrategy::Graph {
+        println!("  filters: {}", trace.filter_strategy);
+    }
+
+    for layer in trace.layers.iter() {
+        println!(
+            "  layer {}: {} visited, {} expanded, {} filtered, {} kept{}",
+            layer.layer,
+            layer.visited,
+            layer.expanded,
+            layer.filtered,
+            layer.kept,
+            if layer.exhausted {
+                " (ran out of nodes)"
+            } else {
+                ""
+            }
+        );
+    }
+
+    println!(
+        "  distances: {}",
+        trace
+            .distances
+            .iter()
+            .map(|d| format!("{:.3}", d))
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+}
+
+fn search_options(flags: &Flags) -> SearchOptions {
+    SearchOptions {
+        k: flags.results,
+        offset: flags.offset,
+        filters: flags.query_filters.clone(),
+        exclude_paths: false,
+        group_by: flags.group_by.clone(),
+        group_score: flags.group_score,
+        save_query: flags.save_query,
+        include_boilerplate: flags.include_boilerplate,
+        no_cache: flags.no_cache,
+        debug: flags.debug_query,
+        model: flags.model.clone(),
+        mode: flags.mode,
+        granularity: flags.granularity,
+        paths: (!flags.paths.is_empty()).then(|| flags.paths.iter().map(|p| absolute(p)).collect()),
+        collection: flags.collection.clone(),
+        dedupe_threshold: flags.dedupe.then_some(dewey_lib::DEFAULT_DEDUPE_THRESHOLD),
+        // a local query can read the files anyway, and a server decides for itself
+        acls: None,
+    }
+}
+
+// relative paths are made absolute here, since a server would resolve them against its own
+// working directory
+fn absolute(path: &str) -> String {
+    if path.starts_with(['~', '$']) {
+        return path.to_string();
+    }
+
+    match std::path::absolute(path) {
+        Ok(path) => path.to_string_lossy().to_string(),
+        Err(_) => path.to_string(),
+    }
+}
+
+// queries go through the configured server when it's reachable,
+// falling back to loading the index from disk
+fn query(query: &str, flags: &Flags) -> Result<DeweyResponse, std::io::Error> {
+    let server = config::get_config_value("server").and_then(|s| {
+        s.rsplit_once(':')
+            .map(|(a, p)| (a.to_string(), p.parse::<u32>()))
+    });
+
+    match server {
+        Some((address, Ok(port))) => {
+            let client = DeweyClient {
+                token: std::env::var("DEWEY_TOKEN").ok(),
+                ..DeweyClient::new(address.clone(), port)
+            };
+            match client.search(query.to_string(), search_options(flags)) {
+                Ok(response) => return Ok(response),
+                // a local query wouldn't get any further without the embedding API
+                Err(e) if e.kind() == std::io::ErrorKind::NetworkUnreachable => return Err(e),
+                Err(e) => {
+                    lprint!(
+                        info,
+                        "Server at {}:{} unavailable, querying locally: {}",
+                        address,
+                        port,
+                        e
+                    );
+                }
+            }
+        }
+        Some((address, Err(e))) => {
+            lprint!(
+                error,
+                "Ignoring malformed server port for {}: {}",
+                address,
+                e
+            );
+        }
+        None => {}
+    }
+
+    ServerState::new()?.search(query, &search_options(flags))
+}
+
+// the whole of stdin, as a query
+//
+// the size limit is left to the query embedding, which reports it the same way for every query
+fn read_stdin_query() -> Result<String, std::io::Error> {
+    let mut query = String::new();
+    std::io::stdin().read_to_string(&mut query)?;
+
+    if query.trim().is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "nothing was read from stdin",
+        ));
+    }
+
+    Ok(query)
+}
+
+#[cfg(feature = "schema")]
+fn print_schema() -> Result<(), Box<dyn std::error::Error>> {
+    let schema = dewey_lib::message::json_schema();
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "schema"))]
+fn print_schema() -> Result<(), Box<dyn std::error::Error>> {
+    Err("dewey was built without the schema feature".into())
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    config::setup();
     let flags = parse_flags();
+
+    // everything runs against the --data-dir layout when it's given
+    let paths = config::data_dir_paths(flags.data_dir.as_deref(), flags.init)?;
+    paths.scope(|| run(flags))
+}
+
+fn run(flags: Flags) -> Result<(), Box<dyn std::error::Error>> {
+    // the schema is the same everywhere, so it doesn't need a config or data directory
+    if flags.schema {
+        return print_schema();
+    }
+
+    if flags.version {
+        println!("{}", dewey_lib::version::BuildInfo::current());
+        return Ok(());
+    }
+
+    // the self-test's embeddings never reach the API, so it doesn't need a key
+    if flags.self_test {
+        selftest::use_test_embeddings();
+    }
+
+    // nothing can be logged yet, so the reason goes straight to the terminal
+    if let Err(e) = config::setup(LogTarget::Cli) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
     let mut no_flags = true;
 
+    lock::set_wait(flags.wait);
+
     lprint!(
         info,
         "Compiled for regression testing: {}",
@@ -164,26 +1151,526 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
+    // the self-test works in a temp dir of its own, whatever --data-dir says
+    if flags.self_test {
+        let report = selftest::run(flags.keep)?;
+        for stage in report.stages.iter() {
+            println!("{}", format_stage(stage));
+        }
+
+        if report.kept {
+            println!("Kept {}", report.dir.display());
+        }
+
+        return match report.passed() {
+            true => Ok(()),
+            false => Err("self-test failed".into()),
+        };
+    }
+
+    // nothing is touched, so there's nothing to clean up after either
+    if let Some(filepath) = &flags.explain_chunks {
+        let explanation = sync::explain_chunks(&filepath.to_string_lossy())?;
+        match flags.json {
+            true => println!("{}", serde_json::to_string(&explanation)?),
+            false => print_explanation(&explanation),
+        }
+
+        return Ok(());
+    }
+
+    if flags.check_rules {
+        let diagnostics = ledger::validate_rules();
+        match flags.json {
+            true => println!("{}", serde_json::to_string(&diagnostics)?),
+            false => {
+                for diagnostic in diagnostics.iter() {
+                    println!("{}", diagnostic);
+                }
+                println!("{}", ledger::summarize_rules(&diagnostics));
+            }
+        }
+
+        return match diagnostics.iter().any(|d| d.issue.is_error()) {
+            true => Err("the rules have errors".into()),
+            false => Ok(()),
+        };
+    }
+
+    // paths are pointed at where the files are now before anything goes looking for them
+    if let Some((old, new)) = &flags.rebase {
+        no_flags = false;
+        dbio::rebase(old, new)?;
+        println!("Stored paths under {} now resolve under {}", old, new);
+    }
+
     if flags.sync {
         no_flags = false;
-        ledger::sync_ledger_config()?;
+        let report = ledger::sync_ledger_config(flags.yes, Some(&|line| println!("{}", line)))?;
+        if flags.json {
+            println!("{}", serde_json::to_string(&report.diff)?);
+        }
+
+        if report.diff.refused {
+            return Err("ledger sync refused, nothing was changed".into());
+        }
     }
 
-    if flags.embed || flags.full_embed {
+    if flags.embed || flags.full_embed || flags.resume {
         no_flags = false;
-        dbio::sync_index(flags.full_embed)?;
+        if flags.bulk && !flags.only.is_empty() {
+            return Err("--only can't be used with --bulk".into());
+        }
+
+        if flags.bulk && (flags.partial || flags.resume) {
+            return Err("--partial and --resume can't be used with --bulk".into());
+        }
+
+        match flags.bulk && !flags.dry_run {
+            true => {
+                sync::bulk_sync_index(flags.full_embed, !flags.no_snapshot)?;
+                if !flags.reindex {
+                    println!("The search index doesn't have the new embeddings yet, run -r to rebuild it");
+                }
+            }
+            false => {
+                let only = match flags.only.is_empty() {
+                    true => None,
+                    false => Some(ledger::PathFilter::new(&flags.only)?),
+                };
+
+                let over_quota = match flags.partial || flags.resume {
+                    true => sync::OverQuota::Partial,
+                    false => sync::OverQuota::Stop,
+                };
+
+                sync::sync_index(
+                    flags.full_embed && !flags.resume,
+                    flags.dry_run,
+                    !flags.no_snapshot,
+                    only.as_ref(),
+                    over_quota,
+                    flags.resume,
+                )?;
+            }
+        }
     }
 
+    // every model's store gets an index of its own
+    //
+    // with --only, an index whose ids a sync has since renumbered is rebuilt whole instead
     if flags.reindex {
         no_flags = false;
-        let index = hnsw::HNSW::new(true)?;
+        let only = match flags.only.is_empty() {
+            true => None,
+            false => Some(ledger::PathFilter::new(&flags.only)?),
+        };
+
+        for store in config::get_paths().model_stores()? {
+            let model = store.scope(config::get_embedding_model);
+            let graft = match &only {
+                Some(only) if store.scope(dbio::read_generation)?.ids_stable() => {
+                    store.scope(|| dbio::graft_index(only))?
+                }
+                Some(_) => {
+                    println!(
+                        "The embeddings made with {} were renumbered since the index was built, rebuilding all of it",
+                        model
+                    );
+                    None
+                }
+                None => None,
+            };
 
-        let data_dir = config::get_data_dir();
-        index.serialize(&data_dir.join("index").to_str().unwrap().to_string())?;
+            if let Some(graft) = graft {
+                println!(
+                    "Rebuilt the index of {} over the matching files: {} nodes removed, {} inserted",
+                    model, graft.removed, graft.inserted
+                );
+                continue;
+            }
+
+            match store.scope(dbio::build_index)? {
+                Some(index) => println!("Indexed {} embeddings made with {}", index.size, model),
+                None => println!("Too few embeddings made with {} to index", model),
+            }
+        }
     }
 
     if flags.reblock {
-        dbio::reblock()?;
+        no_flags = false;
+        dbio::reblock(!flags.no_snapshot)?;
+    }
+
+    if flags.recompute_entries {
+        no_flags = false;
+        for store in config::get_paths().model_stores()? {
+            let model = store.scope(config::get_embedding_model);
+            match store.scope(dbio::recompute_entry_points)? {
+                Some(entry_points) => println!(
+                    "Pinned {} entry points for {}: {:?}",
+                    entry_points.len(),
+                    model,
+                    entry_points
+                ),
+                None => println!("No index of embeddings made with {}", model),
+            }
+        }
+    }
+
+    if flags.normalize_store {
+        no_flags = false;
+        for store in config::get_paths().model_stores()? {
+            let model = store.scope(config::get_embedding_model);
+            let normalized = store.scope(|| dbio::normalize_store(!flags.no_snapshot))?;
+            println!("Normalized {} embeddings made with {}", normalized, model);
+        }
+    }
+
+    if flags.snapshot {
+        no_flags = false;
+        let _lock = DataLock::acquire(LockMode::Shared, "snapshot")?;
+        let name = dbio::snapshot("manual")?;
+        println!("Created snapshot {}", name);
+    }
+
+    if flags.list_snapshots {
+        no_flags = false;
+        for name in dbio::list_snapshots()? {
+            println!("{}", name);
+        }
+    }
+
+    if let Some(n) = flags.journal {
+        no_flags = false;
+        let entries = journal::read_recent(n)?;
+        match flags.json {
+            true => println!("{}", serde_json::to_string(&entries)?),
+            false => {
+                for entry in entries.iter() {
+                    println!("{}", format_journal_entry(entry));
+                }
+            }
+        }
+    }
+
+    if let Some(label) = &flags.rollback {
+        no_flags = false;
+        dbio::rollback(label)?;
+        println!("Rolled back to snapshot {}", label);
+    }
+
+    if let Some(path) = &flags.backup {
+        no_flags = false;
+        let manifest = dbio::backup_to(path)?;
+        println!(
+            "Backed up {} files at generation {} to {}",
+            manifest.files.len(),
+            manifest.generation,
+            path.display()
+        );
+    }
+
+    if let Some(path) = &flags.restore {
+        no_flags = false;
+        let manifest = dbio::restore_from(path)?;
+        println!(
+            "Restored {} files from {}, backed up at generation {}",
+            manifest.files.len(),
+            path.display(),
+            manifest.generation
+        );
+    }
+
+    if let Some((layer, path)) = &flags.export_graph {
+        no_flags = false;
+        let _lock = DataLock::acquire(LockMode::Shared, "export_graph")?;
+        let index = hnsw::HNSW::new(false)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => index.export_json(*layer, path)?,
+            _ => index.export_dot(*layer, path)?,
+        }
+
+        println!("Exported layer {} to {}", layer, path.display());
+    }
+
+    if let Some(path) = &flags.dump_embeddings {
+        no_flags = false;
+        let filters = flags
+            .query_filters
+            .iter()
+            .map(|f| hnsw::Filter::from_string(f))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let _lock = DataLock::acquire(LockMode::Shared, "dump_embeddings")?;
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let written = dbio::dump_jsonl(&mut writer, &filters, flags.vector_dims)?;
+        println!("Dumped {} embeddings to {}", written, path.display());
+    }
+
+    if let Some(path) = &flags.import_embeddings {
+        no_flags = false;
+        let index_path = config::get_data_dir().join("index");
+        let mut index = match index_path.exists() {
+            true => {
+                let _lock = DataLock::acquire(LockMode::Shared, "import_embeddings")?;
+                Some(hnsw::HNSW::new(false)?)
+            }
+            false => None,
+        };
+
+        let imported = dbio::import_jsonl(path, flags.lenient, !flags.no_snapshot, index.as_mut())?;
+        println!("Imported {} embeddings from {}", imported, path.display());
+
+        match index {
+            Some(index) => {
+                let _lock = DataLock::acquire(LockMode::Exclusive, "import_embeddings")?;
+                index.serialize(&index_path)?;
+            }
+            None => println!("No index to update, run -r to build one"),
+        }
+    }
+
+    if flags.blocks {
+        no_flags = false;
+        let reports = dbio::block_report()?;
+        println!(
+            "{:>8} {:>10} {:>6} {:>6} {:>12} {:>11}",
+            "block", "embeddings", "fill", "files", "bytes", "unreachable"
+        );
+
+        for r in reports.iter() {
+            println!(
+                "{:>8} {:>10} {:>5.0}% {:>6} {:>12} {:>11}",
+                r.block_number,
+                r.embeddings,
+                r.fill_factor * 100.0,
+                r.files,
+                r.bytes,
+                r.unreachable
+            );
+        }
+
+        println!(
+            "{} blocks, {} embeddings, {} bytes",
+            reports.len(),
+            reports.iter().map(|r| r.embeddings).sum::<usize>(),
+            reports.iter().map(|r| r.bytes).sum::<u64>()
+        );
+
+        if let Some(recommendation) = dbio::compaction_recommendation(&reports) {
+            println!("{}", recommendation);
+        }
+    }
+
+    if flags.list {
+        no_flags = false;
+        let files = dbio::list_files()?;
+        match flags.json {
+            true => println!("{}", serde_json::to_string(&files)?),
+            false => {
+                for file in files.iter() {
+                    println!(
+                        "{:<19} {:>6} {}",
+                        format_timestamp(file.embedded_at),
+                        file.chunk_count,
+                        file.filepath
+                    );
+                }
+
+                println!("{} files", files.len());
+            }
+        }
+    }
+
+    if let Some(threshold) = flags.duplicates {
+        no_flags = false;
+        let groups = {
+            let _lock = DataLock::acquire(LockMode::Shared, "duplicates")?;
+            analysis::find_duplicates(&hnsw::HNSW::new(false)?, threshold)?
+        };
+
+        match flags.json {
+            true => println!("{}", serde_json::to_string(&groups)?),
+            false => {
+                for group in groups.iter() {
+                    println!("{:.3} {}", group.similarity, group.representative);
+                    for file in group.files.iter() {
+                        println!("  {:.3} {}", file.similarity, file.filepath);
+                    }
+                }
+
+                println!("{} groups of near-duplicate files", groups.len());
+            }
+        }
+    }
+
+    // only the ledger and the files are read
+    if flags.analyze {
+        no_flags = false;
+        let stats = analysis::analyze_corpus()?;
+        match flags.json {
+            true => println!("{}", serde_json::to_string(&stats)?),
+            false => print_analysis(&stats),
+        }
+    }
+
+    if flags.status {
+        no_flags = false;
+        let model = config::get_embedding_model();
+        println!("configured model: {}", model);
+
+        let settings = config::get_embed_settings();
+        let max_items = match settings.max_batch_items {
+            usize::MAX => "any number of".to_string(),
+            n => n.to_string(),
+        };
+        println!(
+            "embedding with {} workers, in batches of {} chunks up to {} characters",
+            settings.workers, max_items, settings.max_batch_tokens
+        );
+        let limits = config::get_provider_limits(&model);
+        let per_request = match limits.max_batch_items {
+            usize::MAX => "any number".to_string(),
+            n => n.to_string(),
+        };
+        println!(
+            "provider: inputs up to {} characters, {} per request",
+            limits.max_input_tokens, per_request
+        );
+        println!("embedding API: {}", config::get_api_timeouts());
+
+        match dbio::get_blocks_model()? {
+            Some(m) if m.name != model => {
+                println!("blocks: embedded with {} (run -f to re-embed)", m)
+            }
+            Some(m) => println!("blocks: embedded with {}", m),
+            None => println!("blocks: nothing embedded yet"),
+        }
+
+        let metric = hnsw::Metric::current();
+        match hnsw::HNSW::read_header(&config::get_data_dir().join("index")) {
+            Ok((m, _)) if m.name != model => {
+                println!("index: built with {} (run -r to rebuild)", m)
+            }
+            Ok((m, index_metric)) if index_metric != metric => println!(
+                "index: built with {} under the {} metric, configured is {} (run -r to rebuild)",
+                m, index_metric, metric
+            ),
+            Ok((m, index_metric)) => {
+                println!("index: built with {} under the {} metric", m, index_metric)
+            }
+            Err(_) => println!("index: not built yet"),
+        }
+
+        if dbio::read_generation()?.index_behind() {
+            println!("index: missing embeddings from a bulk embed (run -r to rebuild)");
+        }
+
+        println!("state generation: {}", dbio::read_state_generation()?);
+
+        for store in config::get_paths().model_stores()?.into_iter().skip(1) {
+            let indexed = store.scope(|| config::get_data_dir().join("index").exists());
+            println!(
+                "routed: {}{}",
+                store.model.unwrap_or_default(),
+                if indexed { "" } else { " (no index, run -r)" }
+            );
+        }
+    }
+
+    let query_text = match flags.stdin {
+        true => Some(read_stdin_query()?),
+        false => Some(flags.query.clone()).filter(|q| !q.is_empty()),
+    };
+
+    if let Some(query_text) = query_text {
+        no_flags = false;
+        // a directory that would be refused is refused before the query's made
+        if let Some(dir) = &flags.out_dir {
+            export::prepare_out_dir(dir, flags.force)?;
+        }
+
+        let response = match query(&query_text, &flags) {
+            Ok(response) => response,
+            Err(e) if e.kind() == std::io::ErrorKind::NetworkUnreachable => {
+                println!("offline: {}", e);
+                std::process::exit(1);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if response.degraded {
+            println!("offline: the embedding API is unreachable, searching with this query's last embedding");
+        }
+
+        if response.index_behind {
+            println!("warning: the index is missing the newest embeddings, run -r to rebuild it");
+        }
+
+        for path in response.unknown_paths.iter() {
+            println!("warning: no embedded files match --in {}", path);
+        }
+
+        if response.results.is_empty() && response.groups.is_empty() {
+            println!("No results");
+        }
+
+        for (i, result) in response.results.iter().enumerate() {
+            println!("{:>3}. {}", flags.offset + i + 1, format_result(result));
+        }
+
+        for (i, group) in response.groups.iter().enumerate() {
+            println!(
+                "{:>3}. {} ({:.3})",
+                flags.offset + i + 1,
+                group.key,
+                group.score
+            );
+            for chunk in group.top_chunks.iter() {
+                println!("       {}", format_result(chunk));
+            }
+        }
+
+        if response.has_more {
+            println!(
+                "more results, see them with --offset {}",
+                flags.offset + flags.results
+            );
+        }
+
+        if let Some(trace) = &response.trace {
+            print_trace(trace);
+        }
+
+        if let Some(dir) = &flags.out_dir {
+            let manifest = export::export_results(&query_text, &response, flags.offset, dir)?;
+            println!(
+                "Wrote {} results to {}",
+                manifest
+                    .items
+                    .iter()
+                    .filter(|item| item.file.is_some())
+                    .count(),
+                dir.display()
+            );
+        }
+    }
+
+    // a failed cleanup is logged, but doesn't fail whatever the run did
+    if !flags.no_housekeeping {
+        match housekeeping::run(&config::get_housekeeping_policy()) {
+            Ok(report) if !report.is_empty() && !flags.json => println!(
+                "Housekeeping removed {} files ({} bytes) and compressed {} logs",
+                report.removed.len(),
+                report.freed_bytes,
+                report.compressed.len()
+            ),
+            Ok(_) => {}
+            Err(e) => {
+                lprint!(error, "Housekeeping failed: {}", e);
+            }
+        }
     }
 
     if no_flags {
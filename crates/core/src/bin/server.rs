@@ -1,16 +1,31 @@
 use std::io::{Read, Write};
-use std::net::TcpListener;
-use std::sync::{Arc, Mutex};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, RwLock};
 use std::thread;
 
 use dewey_lib::config;
-use dewey_lib::logger::Logger;
+use dewey_lib::logger::{LogTarget, Logger};
 use dewey_lib::message::DeweyRequest;
+use dewey_lib::version::BuildInfo;
+use dewey_lib::ServerState;
 use dewey_lib::{error, info, lprint};
 
+// anything bigger is taken as garbage rather than read into memory
+const MAX_REQUEST_BYTES: usize = 64 * 1024 * 1024;
+
+const HOUSEKEEPING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+// how often the queued edits are checked for any that have gone quiet
+const EDIT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
 struct Flags {
     address: String,
     port: usize,
+    no_housekeeping: bool,
+    no_schedule: bool,
+    data_dir: Option<std::path::PathBuf>,
+    init: bool,
+    version: bool,
 }
 
 fn parse_flags() -> Flags {
@@ -18,14 +33,32 @@ fn parse_flags() -> Flags {
     let mut flags = Flags {
         address: String::from("127.0.0.1"),
         port: 5050,
+        no_housekeeping: false,
+        no_schedule: false,
+        data_dir: None,
+        init: false,
+        version: false,
     };
 
-    if args.len() < 1 {
+    if args.is_empty() {
         std::process::exit(1);
     }
 
     for (i, arg) in args.iter().skip(1).enumerate() {
-        if arg.starts_with("-") && !arg.starts_with("--") {
+        if arg == "--no-housekeeping" {
+            flags.no_housekeeping = true;
+        } else if arg == "--no-schedule" {
+            flags.no_schedule = true;
+        } else if arg == "--data-dir" {
+            match args.get(i + 2) {
+                Some(dir) => flags.data_dir = Some(dir.into()),
+                None => panic!("error: missing directory after --data-dir"),
+            }
+        } else if arg == "--init" {
+            flags.init = true;
+        } else if arg == "--version" {
+            flags.version = true;
+        } else if arg.starts_with("-") && !arg.starts_with("--") {
             for c in arg.chars().skip(1) {
                 match c {
                     'a' => {
@@ -44,11 +77,30 @@ fn parse_flags() -> Flags {
 }
 
 pub fn main() -> std::io::Result<()> {
-    config::setup();
     let flags = parse_flags();
 
-    let listener = TcpListener::bind(format!("{}:{}", flags.address, flags.port)).unwrap();
+    if flags.version {
+        println!("{}", BuildInfo::current());
+        return Ok(());
+    }
+
+    // everything runs against the --data-dir layout when it's given,
+    // and the threads serving it have to be scoped to it too
+    let paths = config::data_dir_paths(flags.data_dir.as_deref(), flags.init)?;
+    paths.scope(|| serve(flags))
+}
+
+fn serve(flags: Flags) -> std::io::Result<()> {
+    // nothing can be logged yet, so the reason goes straight to the terminal
+    if let Err(e) = config::setup(LogTarget::Server) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+    let paths = config::get_paths();
+
+    let listener = TcpListener::bind(format!("{}:{}", flags.address, flags.port))?;
     lprint!(info, "Server listening on {}:{}", flags.address, flags.port);
+    lprint!(info, "Running {}", BuildInfo::current());
 
     lprint!(
         info,
@@ -56,71 +108,111 @@ pub fn main() -> std::io::Result<()> {
         cfg!(feature = "regression")
     );
 
-    let state = Arc::new(Mutex::new(dewey_lib::ServerState::new()?));
+    lprint!(info, "Embedding API: {}", config::get_api_timeouts());
+
+    // handlers that panic are answered with an error and the server carries on,
+    // so the log is the only place the panic itself shows up,
+    // with a backtrace when RUST_BACKTRACE asks for one
+    std::panic::set_hook(Box::new(|info| {
+        let backtrace = std::backtrace::Backtrace::capture();
+        match backtrace.status() {
+            std::backtrace::BacktraceStatus::Captured => error!("{}\n{}", info, backtrace),
+            _ => error!("{}", info),
+        }
+    }));
+
+    let state = Arc::new(RwLock::new(dewey_lib::ServerState::new()?));
+
+    // edits leave the index dirty in memory, and it's written out at most once per interval
+    let flush_interval = config::get_flush_interval();
+    let flush_state = Arc::clone(&state);
+    let flush_paths = paths.clone();
+    thread::spawn(move || {
+        flush_paths.scope(|| loop {
+            thread::sleep(flush_interval);
+
+            let mut state = flush_state
+                .write()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            match state.flush_index() {
+                Ok(true) => info!("wrote index after edits"),
+                Ok(false) => {}
+                Err(e) => error!("failed to write index: {}", e),
+            }
+        })
+    });
+
+    // edited files are embedded again once their edits go quiet,
+    // and queries carry on while the edits are only being checked on
+    let edit_state = Arc::clone(&state);
+    let edit_paths = paths.clone();
+    thread::spawn(move || {
+        edit_paths.scope(|| loop {
+            thread::sleep(EDIT_POLL_INTERVAL);
+
+            let due = edit_state
+                .read()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .has_due_edits();
+            if !due {
+                continue;
+            }
+
+            let mut state = edit_state
+                .write()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            let reembedded = state.reindex_pending(false);
+            info!("embedded {} edited files again", reembedded);
+        })
+    });
+
+    // stale files are embedded again and the blocks compacted on the configured schedule,
+    // with housekeeping along with them
+    let policy = (!flags.no_housekeeping).then(config::get_housekeeping_policy);
+    let _scheduler = match flags.no_schedule {
+        false => {
+            let schedule = config::get_maintenance_schedule();
+            lprint!(info, "Maintenance scheduled for {:?}", schedule);
+            Some(dewey_lib::maintenance::Scheduler::spawn(
+                Arc::clone(&state),
+                schedule,
+                policy,
+            ))
+        }
+        // old queries and logs are still cleaned up once a day, starting now
+        true => {
+            if let Some(policy) = policy {
+                let paths = paths.clone();
+                thread::spawn(move || {
+                    paths.scope(|| loop {
+                        if let Err(e) = dewey_lib::housekeeping::run(&policy) {
+                            error!("housekeeping failed: {}", e);
+                        }
+
+                        thread::sleep(HOUSEKEEPING_INTERVAL);
+                    })
+                });
+            }
+
+            None
+        }
+    };
 
     for stream in listener.incoming() {
         match stream {
             Ok(mut stream) => {
                 let state = Arc::clone(&state);
+                let paths = paths.clone();
                 thread::spawn(move || {
-                    let mut state = state.lock().unwrap();
-
-                    let mut size_buffer = [0u8; 4];
-                    match stream.read_exact(&mut size_buffer) {
-                        Ok(_) => {}
-                        Err(e) => error!("Error reading size header: {}", e),
-                    };
-
-                    let message_size = u32::from_be_bytes(size_buffer) as usize;
-
-                    let mut buffer = vec![0u8; message_size];
-                    match stream.read_exact(&mut buffer) {
-                        Ok(_) => {}
-                        Err(e) => error!("Error reading message: {}", e),
-                    };
-
-                    let request: DeweyRequest =
-                        match serde_json::from_str(&String::from_utf8_lossy(&buffer)) {
-                            Ok(r) => r,
-                            Err(e) => {
-                                error!("Error parsing request: {}", e);
-                                return;
-                            }
-                        };
-
-                    let response = match request.message_type.as_str() {
-                        "query" => match state.query(request.payload) {
-                            Ok(r) => r,
-                            Err(e) => {
-                                let r = format!("Error handling client: {}", e);
-                                error!("{}", r);
-                                r
-                            }
-                        },
-                        "edit" => match state.reindex(request.payload) {
-                            Ok(r) => r,
-                            Err(e) => {
-                                let r = format!("Error handling client: {}", e);
-                                error!("{}", r);
-                                r
-                            }
-                        },
-                        _ => format!("Invalid message_type: {}", request.message_type),
-                    };
-
-                    let mut bytes = Vec::new();
-                    bytes.extend((response.len() as u32).to_be_bytes());
-                    bytes.extend_from_slice(response.as_bytes());
-
-                    match stream.write(&bytes) {
-                        Ok(bytes_written) => {
-                            stream.flush().unwrap();
-                            info!("wrote {} bytes to stream", bytes_written);
-                        }
-                        Err(e) => {
-                            error!("Failed to write response: {}", e);
+                    paths.scope(|| {
+                        let peer = stream
+                            .peer_addr()
+                            .map_or_else(|_| String::from("unknown peer"), |a| a.to_string());
+
+                        if let Err(e) = handle_connection(&mut stream, &state) {
+                            error!("Error handling client {}: {}", peer, e);
                         }
-                    };
+                    })
                 });
             }
             Err(e) => {
@@ -131,5 +223,83 @@ pub fn main() -> std::io::Result<()> {
 
     lprint!(info, "shutting down server");
 
+    // the flushing thread never lets go of its handle, so pending edits are written here
+    state
+        .write()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .flush_index()?;
+
     Ok(())
 }
+
+// a length-prefixed request, read off a connection
+//
+// a request that can't be read or parsed is `ErrorKind::InvalidData`,
+// unless the client closed the connection before sending anything
+fn read_request(stream: &mut TcpStream) -> std::io::Result<DeweyRequest> {
+    let mut size_buffer = [0u8; 4];
+    stream.read_exact(&mut size_buffer)?;
+
+    let message_size = u32::from_be_bytes(size_buffer) as usize;
+    if message_size > MAX_REQUEST_BYTES {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "request of {} bytes is over the limit of {}",
+                message_size, MAX_REQUEST_BYTES
+            ),
+        ));
+    }
+
+    let mut buffer = Vec::with_capacity(message_size);
+    stream.take(message_size as u64).read_to_end(&mut buffer)?;
+    if buffer.len() < message_size {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "request ended after {} of {} bytes",
+                buffer.len(),
+                message_size
+            ),
+        ));
+    }
+
+    serde_json::from_slice(&buffer).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("malformed request: {}", e),
+        )
+    })
+}
+
+fn write_response(stream: &mut TcpStream, response: &str) -> std::io::Result<()> {
+    let mut bytes = Vec::new();
+    bytes.extend((response.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(response.as_bytes());
+
+    stream.write_all(&bytes)?;
+    stream.flush()?;
+    info!("wrote {} bytes to stream", bytes.len());
+
+    Ok(())
+}
+
+// serves the one request a connection carries
+//
+// a request that can't be read gets an `invalid_request` error back, and one whose handler
+// panicked gets an `internal_error` (see `ServerState::handle_locked`),
+// as long as the client is still there to take it
+fn handle_connection(stream: &mut TcpStream, state: &RwLock<ServerState>) -> std::io::Result<()> {
+    let request = match read_request(stream) {
+        Ok(request) => request,
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+            let response = dewey_lib::error_response("invalid_request", e.to_string());
+            write_response(stream, &serde_json::to_string(&response)?)?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let response = ServerState::handle_locked(state, request);
+    write_response(stream, &response)
+}
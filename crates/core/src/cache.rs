@@ -2,10 +2,15 @@ use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Debug};
 use std::marker::PhantomData;
 use std::ptr::NonNull;
+use std::sync::{Arc, PoisonError, RwLock};
 
-use crate::dbio::{get_directory, read_embedding_block, BLOCK_SIZE};
+use crate::config::get_data_dir;
+use crate::dbio::{
+    get_directory, read_embedding_block, read_state_generation, read_tombstones, BLOCK_SIZE,
+};
+use crate::embedding::Embedding;
 use crate::logger::Logger;
-use crate::openai::Embedding;
+use crate::message::DeweyResponse;
 use crate::{error, info};
 
 // most of this is ripped from https://rust-unofficial.github.io/too-many-lists/sixth-final.html
@@ -108,7 +113,7 @@ impl<T> LinkedList<T> {
         }
     }
 
-    pub fn iter(&self) -> Iter<T> {
+    pub fn iter(&self) -> Iter<'_, T> {
         Iter {
             front: self.front,
             len: self.len,
@@ -116,7 +121,7 @@ impl<T> LinkedList<T> {
         }
     }
 
-    pub fn iter_mut(&mut self) -> IterMut<T> {
+    pub fn iter_mut(&mut self) -> IterMut<'_, T> {
         IterMut {
             front: self.front,
             len: self.len,
@@ -196,6 +201,12 @@ impl<T: Debug> Debug for LinkedList<T> {
     }
 }
 
+// where searches get embeddings from, either a cache of their own or a view of a `BlockStore`
+pub trait EmbeddingLookup {
+    fn get(&mut self, embedding_id: u32) -> Result<Box<Embedding>, std::io::Error>;
+    fn is_deleted(&self, embedding_id: u32) -> bool;
+}
+
 // lru: a list of embedding ids
 // node_map: a map of embedding ids to their corresponding nodes in the lru
 // embeddings: a map of embedding ids to their corresponding embeddings
@@ -214,6 +225,9 @@ pub struct EmbeddingCache {
     // dirty embeddings are accounted for and removed on cache reads
     dirty_embeddings: HashSet<u32>,
 
+    // ids that were deleted from the blocks, which an index can still lead to
+    deleted: HashSet<u32>,
+
     // ideally this is some multiple of the number of embeddings in a block
     // this _must_ be greater or equal to the number of embeddings in a block
     max_size: u32,
@@ -238,11 +252,23 @@ impl EmbeddingCache {
             node_map: HashMap::new(),
             embeddings: HashMap::new(),
             dirty_embeddings: HashSet::new(),
+            deleted: read_tombstones()?.into_iter().map(|id| id as u32).collect(),
             directory: directory.id_map,
             max_size,
         })
     }
 
+    pub fn is_deleted(&self, embedding_id: u32) -> bool {
+        self.deleted.contains(&embedding_id)
+    }
+
+    fn not_found(embedding_id: u32) -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("embedding {} not found", embedding_id),
+        )
+    }
+
     fn load_embedding_block(&mut self, embedding_id: u32) -> Result<(), std::io::Error> {
         let block_number = match self.directory.get(&embedding_id) {
             Some(block_number) => *block_number,
@@ -286,7 +312,13 @@ impl EmbeddingCache {
     //
     // cloning the embeddings isn't ideal
     // but neither is the borrow checker
+    //
+    // deleted embeddings are never found, without anything being logged
     pub fn get(&mut self, embedding_id: u32) -> Result<Box<Embedding>, std::io::Error> {
+        if self.deleted.contains(&embedding_id) {
+            return Err(Self::not_found(embedding_id));
+        }
+
         // fetch the embedding
         let embedding = match self.embeddings.get(&embedding_id).cloned() {
             Some(embedding) => {
@@ -294,14 +326,20 @@ impl EmbeddingCache {
                     self.load_embedding_block(embedding_id)?;
                     self.dirty_embeddings.remove(&embedding_id);
 
-                    self.embeddings.get(&embedding_id).unwrap().clone()
+                    self.embeddings
+                        .get(&embedding_id)
+                        .ok_or_else(|| Self::not_found(embedding_id))?
+                        .clone()
                 } else {
                     embedding
                 }
             }
             None => {
                 self.load_embedding_block(embedding_id)?;
-                self.embeddings.get(&embedding_id).unwrap().clone()
+                self.embeddings
+                    .get(&embedding_id)
+                    .ok_or_else(|| Self::not_found(embedding_id))?
+                    .clone()
             }
         };
 
@@ -326,3 +364,436 @@ impl EmbeddingCache {
         Ok(Box::new(embedding))
     }
 }
+
+impl EmbeddingLookup for EmbeddingCache {
+    fn get(&mut self, embedding_id: u32) -> Result<Box<Embedding>, std::io::Error> {
+        EmbeddingCache::get(self, embedding_id)
+    }
+
+    fn is_deleted(&self, embedding_id: u32) -> bool {
+        EmbeddingCache::is_deleted(self, embedding_id)
+    }
+}
+
+type Block = Arc<HashMap<u32, Embedding>>;
+
+// what a data directory looked like at one state generation
+struct Snapshot {
+    generation: u64,
+    directory: Arc<HashMap<u32, u64>>,
+    titles: Arc<HashMap<String, u32>>,
+    deleted: Arc<HashSet<u32>>,
+    blocks: HashMap<u64, Block>,
+    // block numbers in the order they were loaded, the oldest going first
+    order: std::collections::VecDeque<u64>,
+}
+
+// blocks shared by every query searching at the same time
+//
+// blocks are only ever read whole and never changed once they're read,
+// so the lock is only held to look one up or put one in,
+// and whoever's holding a block keeps it even after it's dropped from the store
+//
+// each data directory gets a snapshot of its own, which starts over at every state generation
+pub struct BlockStore {
+    // in blocks, for each data directory
+    capacity: usize,
+    snapshots: RwLock<HashMap<std::path::PathBuf, Snapshot>>,
+}
+
+impl BlockStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            snapshots: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // a view of the data directory in scope, as it is now, for one search
+    pub fn view(&self) -> Result<BlockView<'_>, std::io::Error> {
+        let data_dir = get_data_dir();
+        let generation = read_state_generation()?;
+
+        let current = self
+            .snapshots
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&data_dir)
+            .filter(|s| s.generation == generation)
+            .map(|s| (s.directory.clone(), s.titles.clone(), s.deleted.clone()));
+
+        let (directory, titles, deleted) = match current {
+            Some(current) => current,
+            None => {
+                let read = get_directory()?;
+                let directory = Arc::new(read.id_map);
+                let titles = Arc::new(read.title_map);
+                let deleted = Arc::new(
+                    read_tombstones()?
+                        .into_iter()
+                        .map(|id| id as u32)
+                        .collect::<HashSet<_>>(),
+                );
+
+                info!(
+                    "block store starting over for {} at generation {}",
+                    data_dir.display(),
+                    generation
+                );
+                self.snapshots
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(
+                        data_dir.clone(),
+                        Snapshot {
+                            generation,
+                            directory: directory.clone(),
+                            titles: titles.clone(),
+                            deleted: deleted.clone(),
+                            blocks: HashMap::new(),
+                            order: std::collections::VecDeque::new(),
+                        },
+                    );
+
+                (directory, titles, deleted)
+            }
+        };
+
+        Ok(BlockView {
+            store: self,
+            data_dir,
+            generation,
+            directory,
+            titles,
+            deleted,
+            blocks: HashMap::new(),
+            hits: 0,
+            loads: 0,
+        })
+    }
+
+    // the block is read without holding the lock,
+    // so two searches missing the same block can both end up reading it
+    //
+    // along with whether it had to be read from disk
+    fn block(
+        &self,
+        data_dir: &std::path::Path,
+        generation: u64,
+        block_number: u64,
+    ) -> Result<(Block, bool), std::io::Error> {
+        let stored = self
+            .snapshots
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(data_dir)
+            .filter(|s| s.generation == generation)
+            .and_then(|s| s.blocks.get(&block_number).cloned());
+        if let Some(block) = stored {
+            return Ok((block, false));
+        }
+
+        let mut span = crate::trace::Span::start("dewey.cache.load_block");
+        span.set("block", block_number);
+        let block: Block = Arc::new(
+            read_embedding_block(block_number)?
+                .embeddings
+                .into_iter()
+                .map(|e| (e.id as u32, e))
+                .collect(),
+        );
+        span.set("embeddings", block.len());
+        drop(span);
+
+        let mut snapshots = self
+            .snapshots
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        // a snapshot that's moved on since the view was made is left to the views of its own
+        if let Some(snapshot) = snapshots
+            .get_mut(data_dir)
+            .filter(|s| s.generation == generation)
+        {
+            if snapshot
+                .blocks
+                .insert(block_number, block.clone())
+                .is_none()
+            {
+                snapshot.order.push_back(block_number);
+            }
+
+            while snapshot.order.len() > self.capacity {
+                if let Some(oldest) = snapshot.order.pop_front() {
+                    snapshot.blocks.remove(&oldest);
+                }
+            }
+        }
+
+        Ok((block, true))
+    }
+}
+
+// one search's look at a `BlockStore`,
+// which keeps the blocks it's used so it only goes to the store once for each
+pub struct BlockView<'a> {
+    store: &'a BlockStore,
+    data_dir: std::path::PathBuf,
+    generation: u64,
+    directory: Arc<HashMap<u32, u64>>,
+    // the id of each file's title embedding
+    titles: Arc<HashMap<String, u32>>,
+    deleted: Arc<HashSet<u32>>,
+    blocks: HashMap<u64, Block>,
+    // lookups answered by a block already in memory, and blocks that had to be read from disk
+    pub hits: usize,
+    pub loads: usize,
+}
+
+impl BlockView<'_> {
+    pub fn deleted(&self) -> Arc<HashSet<u32>> {
+        self.deleted.clone()
+    }
+
+    // the title embedding of `filepath`, `None` if it was embedded without one
+    pub fn title(&mut self, filepath: &str) -> Result<Option<Box<Embedding>>, std::io::Error> {
+        match self.titles.get(filepath) {
+            Some(&id) => self.get(id).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl EmbeddingLookup for BlockView<'_> {
+    fn get(&mut self, embedding_id: u32) -> Result<Box<Embedding>, std::io::Error> {
+        if self.deleted.contains(&embedding_id) {
+            return Err(EmbeddingCache::not_found(embedding_id));
+        }
+
+        let block_number = match self.directory.get(&embedding_id) {
+            Some(block_number) => *block_number,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("embedding {} not found in directory", embedding_id),
+                ))
+            }
+        };
+
+        let block = match self.blocks.get(&block_number) {
+            Some(block) => {
+                self.hits += 1;
+                block.clone()
+            }
+            None => {
+                let (block, loaded) =
+                    self.store
+                        .block(&self.data_dir, self.generation, block_number)?;
+                match loaded {
+                    true => self.loads += 1,
+                    false => self.hits += 1,
+                }
+                self.blocks.insert(block_number, block.clone());
+                block
+            }
+        };
+
+        block
+            .get(&embedding_id)
+            .map(|e| Box::new(e.clone()))
+            .ok_or_else(|| EmbeddingCache::not_found(embedding_id))
+    }
+
+    fn is_deleted(&self, embedding_id: u32) -> bool {
+        self.deleted.contains(&embedding_id)
+    }
+}
+
+struct CachedQuery {
+    generation: u64,
+    // how many candidates the index was searched for
+    ef: usize,
+    created: std::time::Instant,
+    last_used: u64,
+    response: DeweyResponse,
+}
+
+// recent query results, keyed by the query text and its options
+//
+// an entry only answers queries made against the index generation it was made under,
+// and only for `ttl` after it was made
+//
+// it also only answers queries that need no more candidates than it was searched for,
+// so every page of a query is cut from the same results
+pub struct QueryCache {
+    capacity: usize,
+    ttl: std::time::Duration,
+    entries: HashMap<String, CachedQuery>,
+    // ticks on every lookup, to find the least recently used entry
+    clock: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize, ttl: std::time::Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: HashMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&mut self, key: &str, generation: u64, ef: usize) -> Option<DeweyResponse> {
+        self.clock += 1;
+
+        let fresh = self.entries.get(key).is_some_and(|entry| {
+            entry.generation == generation && entry.ef >= ef && entry.created.elapsed() < self.ttl
+        });
+
+        if !fresh {
+            self.entries.remove(key);
+            self.misses += 1;
+            return None;
+        }
+
+        self.hits += 1;
+        let entry = self.entries.get_mut(key).unwrap();
+        entry.last_used = self.clock;
+
+        Some(entry.response.clone())
+    }
+
+    pub fn insert(&mut self, key: String, generation: u64, ef: usize, response: DeweyResponse) {
+        // anything from an older generation can't be hit again
+        self.entries
+            .retain(|_, entry| entry.generation == generation);
+
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            if let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.entries.insert(
+            key,
+            CachedQuery {
+                generation,
+                ef,
+                created: std::time::Instant::now(),
+                last_used: self.clock,
+                response,
+            },
+        );
+
+        info!("{} queries cached", self.entries.len());
+    }
+}
+
+// the embeddings of recent queries, by their exact text,
+// for answering them while the embedding API can't be reached
+//
+// the oldest query goes once there are `capacity` of them
+pub struct QueryEmbeddings {
+    capacity: usize,
+    order: std::collections::VecDeque<String>,
+    embeddings: HashMap<String, Embedding>,
+}
+
+impl QueryEmbeddings {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: std::collections::VecDeque::new(),
+            embeddings: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, query: &str) -> Option<Embedding> {
+        self.embeddings.get(query).cloned()
+    }
+
+    pub fn insert(&mut self, query: &str, embedding: &Embedding) {
+        if self
+            .embeddings
+            .insert(query.to_string(), embedding.clone())
+            .is_some()
+        {
+            return;
+        }
+
+        self.order.push_back(query.to_string());
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.embeddings.remove(&oldest);
+            }
+        }
+    }
+}
+
+// the meta of every file in a ledger, by filepath
+pub type FileMeta = HashMap<String, HashSet<String>>;
+
+// a ledger's meta as it was when the ledger was last written
+struct LedgerRead {
+    path: std::path::PathBuf,
+    written: std::time::SystemTime,
+    meta: Arc<FileMeta>,
+}
+
+// the current ledger's meta, for filtering against in place of the meta embeddings were made with,
+// which only catches up with the ledger on a reblock
+//
+// it's read again once the ledger's been written since, outside of the lock,
+// so that queries carry on with the meta they have in the meantime
+#[derive(Default)]
+pub struct LedgerMeta {
+    current: RwLock<Option<LedgerRead>>,
+}
+
+impl LedgerMeta {
+    // the meta along with when the ledger it was read from was written
+    pub fn get(&self) -> Result<(std::time::SystemTime, Arc<FileMeta>), std::io::Error> {
+        let path = crate::config::get_local_dir().join("ledger");
+        let written = std::fs::metadata(&path)?.modified()?;
+        if let Some(read) = &*self.current.read().unwrap_or_else(PoisonError::into_inner) {
+            if read.path == path && read.written == written {
+                return Ok((written, read.meta.clone()));
+            }
+        }
+
+        let meta = Arc::new(
+            crate::ledger::read_ledger()?
+                .into_iter()
+                .map(|entry| (entry.filepath, entry.meta))
+                .collect::<FileMeta>(),
+        );
+        info!("read the meta of {} files from the ledger", meta.len());
+
+        // a read that finished after one of a newer ledger is left behind
+        let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+        let newer = current
+            .as_ref()
+            .is_some_and(|read| read.path == path && read.written > written);
+        if !newer {
+            *current = Some(LedgerRead {
+                path,
+                written,
+                meta: meta.clone(),
+            });
+        }
+
+        Ok((written, meta))
+    }
+}
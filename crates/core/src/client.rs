@@ -0,0 +1,372 @@
+use std::io::{Read, Write};
+
+use crate::error;
+use crate::logger::Logger;
+use crate::message::*;
+use crate::version::BuildInfo;
+use crate::SearchOptions;
+
+pub struct DeweyClient {
+    pub address: String,
+    pub port: u32,
+    // pins every request to a state generation from an earlier response,
+    // so a flow of requests fails with `ErrorKind::StaleNetworkFileHandle` once the index changes
+    pub expect_generation: Option<u64>,
+    // sent with every request, for the server to check the acls of what it returns against
+    pub token: Option<String>,
+}
+
+impl DeweyClient {
+    pub fn new(address: String, port: u32) -> Self {
+        Self {
+            address,
+            port,
+            expect_generation: None,
+            token: None,
+        }
+    }
+
+    fn send<T: serde::de::DeserializeOwned>(
+        &self,
+        message: DeweyRequest,
+    ) -> Result<T, std::io::Error> {
+        let destination = format!("{}:{}", self.address, self.port);
+        let mut stream = std::net::TcpStream::connect(destination.clone())?;
+
+        let message = serde_json::to_string(&message)?;
+        let mut bytes = Vec::new();
+        bytes.extend((message.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(message.as_bytes());
+
+        match stream.write_all(&bytes) {
+            Ok(_) => {
+                stream.flush().unwrap();
+            }
+            Err(e) => {
+                error!("Failed to write response: {}", e);
+                return Err(e);
+            }
+        };
+
+        let mut length_bytes = [0u8; 4];
+        stream.read_exact(&mut length_bytes)?;
+        let length = u32::from_be_bytes(length_bytes) as usize;
+
+        let mut buffer = vec![0u8; length];
+        stream.read_exact(&mut buffer)?;
+        let buffer = String::from_utf8_lossy(&buffer);
+
+        // the server couldn't serve the request, and said why
+        if let Ok(response) = serde_json::from_str::<DeweyErrorResponse>(&buffer) {
+            error!("server error {}: {}", response.error, response.message);
+            let kind = match response.error.as_str() {
+                "invalid_filter" => std::io::ErrorKind::InvalidInput,
+                "embedding_unavailable" => std::io::ErrorKind::NetworkUnreachable,
+                "stale_generation" => std::io::ErrorKind::StaleNetworkFileHandle,
+                "invalid_request" => std::io::ErrorKind::InvalidData,
+                _ => std::io::ErrorKind::Other,
+            };
+
+            return Err(std::io::Error::new(kind, response.message));
+        }
+
+        match serde_json::from_str(&buffer) {
+            Ok(resp) => Ok(resp),
+            Err(e) => {
+                error!("Failed to parse response: {}", e);
+                error!("buffer: {:?}", buffer);
+                Err(e.into())
+            }
+        }
+    }
+
+    pub fn query(
+        &self,
+        request: String,
+        k: usize,
+        filters: Vec<String>,
+    ) -> Result<DeweyResponse, std::io::Error> {
+        self.search(
+            request,
+            SearchOptions {
+                filters,
+                ..SearchOptions::new(k)
+            },
+        )
+    }
+
+    // same as `query`, but with each result as a citation of where it is in its file now
+    //
+    // the citations read the files, so this needs the `embed` feature
+    #[cfg(feature = "embed")]
+    pub fn query_citations(
+        &self,
+        request: String,
+        k: usize,
+        filters: Vec<String>,
+    ) -> Result<Vec<Citation>, std::io::Error> {
+        let response = self.query(request, k, filters)?;
+        Ok(response.results.iter().map(Citation::from_item).collect())
+    }
+
+    // the `k` results after the first `offset`, with `has_more` set if there are more past them
+    pub fn query_page(
+        &self,
+        request: String,
+        k: usize,
+        offset: usize,
+        filters: Vec<String>,
+    ) -> Result<DeweyResponse, std::io::Error> {
+        self.search(
+            request,
+            SearchOptions {
+                filters,
+                offset,
+                ..SearchOptions::new(k)
+            },
+        )
+    }
+
+    // same as `query`, but only matches against file contents and never file paths
+    pub fn query_contents(
+        &self,
+        request: String,
+        k: usize,
+        filters: Vec<String>,
+    ) -> Result<DeweyResponse, std::io::Error> {
+        self.search(
+            request,
+            SearchOptions {
+                filters,
+                exclude_paths: true,
+                ..SearchOptions::new(k)
+            },
+        )
+    }
+
+    // same as `query`, but only matches the files at `paths`, which can be globs
+    //
+    // paths that don't match any embedded file come back in `unknown_paths`
+    pub fn query_in(
+        &self,
+        paths: Vec<String>,
+        request: String,
+        k: usize,
+    ) -> Result<DeweyResponse, std::io::Error> {
+        self.search(
+            request,
+            SearchOptions {
+                paths: Some(paths),
+                ..SearchOptions::new(k)
+            },
+        )
+    }
+
+    pub fn search(
+        &self,
+        request: String,
+        options: SearchOptions,
+    ) -> Result<DeweyResponse, std::io::Error> {
+        let message = DeweyRequest {
+            message_type: "query".to_string(),
+            payload: RequestPayload::Query {
+                query: request,
+                k: options.k,
+                offset: options.offset,
+                filters: options.filters,
+                exclude_paths: options.exclude_paths,
+                group_by: options.group_by,
+                group_score: options.group_score,
+                discard_query: !options.save_query,
+                include_boilerplate: options.include_boilerplate,
+                no_cache: options.no_cache,
+                debug: options.debug,
+                model: options.model,
+                search_mode: options.mode,
+                granularity: options.granularity,
+                paths: options.paths,
+                collection: options.collection,
+                dedupe_threshold: options.dedupe_threshold,
+            },
+            expect_generation: self.expect_generation,
+            trace_id: None,
+            token: self.token.clone(),
+        };
+
+        self.send(message)
+    }
+
+    pub fn stats(&self) -> Result<DeweyStatsResponse, std::io::Error> {
+        let message = DeweyRequest {
+            message_type: "stats".to_string(),
+            payload: RequestPayload::Stats {},
+            expect_generation: self.expect_generation,
+            trace_id: None,
+            token: self.token.clone(),
+        };
+
+        self.send(message)
+    }
+
+    // what the server was built from, or `None` from a server older than the field
+    pub fn server_version(&self) -> Result<Option<BuildInfo>, std::io::Error> {
+        Ok(self.stats()?.health.version)
+    }
+
+    // `ranges` are the byte ranges of the file that changed, if the caller knows them
+    //
+    // the file is only queued to be embedded again unless it's `sync`,
+    // when it's done before the response
+    pub fn reindex(
+        &self,
+        filepath: String,
+        ranges: Option<Vec<(u64, u64)>>,
+        sync: bool,
+    ) -> Result<DeweyEditResponse, std::io::Error> {
+        let message = DeweyRequest {
+            message_type: "edit".to_string(),
+            payload: RequestPayload::Edit {
+                filepath,
+                ranges,
+                collection: None,
+                sync,
+            },
+            expect_generation: self.expect_generation,
+            trace_id: None,
+            token: self.token.clone(),
+        };
+
+        self.send(message)
+    }
+
+    pub fn file_info(&self, filepath: String) -> Result<DeweyFileInfoResponse, std::io::Error> {
+        let message = DeweyRequest {
+            message_type: "file_info".to_string(),
+            payload: RequestPayload::FileInfo { filepath },
+            expect_generation: self.expect_generation,
+            trace_id: None,
+            token: self.token.clone(),
+        };
+
+        self.send(message)
+    }
+
+    pub fn flush(&self) -> Result<DeweyFlushResponse, std::io::Error> {
+        let message = DeweyRequest {
+            message_type: "flush".to_string(),
+            payload: RequestPayload::Flush {},
+            expect_generation: self.expect_generation,
+            trace_id: None,
+            token: self.token.clone(),
+        };
+
+        self.send(message)
+    }
+}
+
+// nothing here needs the index or embedding, so these run in a `client`-only build too
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // answers one request with `response`, handing back the request it read
+    fn serve_once(response: serde_json::Value) -> (u32, std::thread::JoinHandle<DeweyRequest>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut length_bytes = [0u8; 4];
+            stream.read_exact(&mut length_bytes).unwrap();
+            let mut buffer = vec![0u8; u32::from_be_bytes(length_bytes) as usize];
+            stream.read_exact(&mut buffer).unwrap();
+
+            let response = response.to_string();
+            stream
+                .write_all(&(response.len() as u32).to_be_bytes())
+                .unwrap();
+            stream.write_all(response.as_bytes()).unwrap();
+
+            serde_json::from_slice(&buffer).unwrap()
+        });
+
+        (port, handle)
+    }
+
+    #[test]
+    fn client_request_test() {
+        let (port, handle) = serve_once(serde_json::json!({
+            "error": "stale_generation",
+            "message": "the index changed",
+        }));
+
+        let mut client = DeweyClient::new("127.0.0.1".to_string(), port);
+        client.expect_generation = Some(3);
+        client.token = Some("secret".to_string());
+        let error = client
+            .search(
+                "a query".to_string(),
+                SearchOptions {
+                    filters: vec!["eq rust".to_string()],
+                    ..SearchOptions::new(5)
+                },
+            )
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::StaleNetworkFileHandle);
+
+        let request = handle.join().unwrap();
+        assert_eq!(request.message_type, "query");
+        assert_eq!(request.expect_generation, Some(3));
+        assert_eq!(request.token.as_deref(), Some("secret"));
+        match request.payload {
+            RequestPayload::Query {
+                query, k, filters, ..
+            } => {
+                assert_eq!(query, "a query");
+                assert_eq!(k, 5);
+                assert_eq!(filters, vec!["eq rust".to_string()]);
+            }
+            payload => panic!("not a query: {:?}", payload),
+        }
+    }
+
+    // the client has to build without the index or anything it pulls in
+    #[cfg(not(any(feature = "index", feature = "embed")))]
+    #[test]
+    fn client_only_test() {
+        let features = BuildInfo::current().features;
+        assert!(features.contains(&"client".to_string()));
+        assert!(!features.contains(&"index".to_string()));
+        assert!(!features.contains(&"embed".to_string()));
+    }
+
+    // a store that's already embedded can be read and searched without the API client or parsing
+    #[cfg(all(feature = "index", not(feature = "embed")))]
+    #[test]
+    fn index_only_test() {
+        let features = BuildInfo::current().features;
+        assert!(features.contains(&"index".to_string()));
+        assert!(!features.contains(&"embed".to_string()));
+        let _ = crate::dbio::build_index;
+        let _ = crate::hnsw::HNSW::query;
+    }
+
+    // files can be chunked and embedded without the index to keep them in
+    #[cfg(all(feature = "embed", not(feature = "index")))]
+    #[test]
+    fn embed_only_test() {
+        let features = BuildInfo::current().features;
+        assert!(features.contains(&"embed".to_string()));
+        assert!(!features.contains(&"index".to_string()));
+        let _ = crate::parsing::read_lines;
+        let _ = crate::openai::embed_bulk;
+    }
+
+    // citations read the files they cite, which only comes with embedding
+    #[cfg(feature = "embed")]
+    #[test]
+    fn client_citations_test() {
+        let features = BuildInfo::current().features;
+        assert!(features.contains(&"embed".to_string()));
+        let _ = DeweyClient::query_citations;
+    }
+}
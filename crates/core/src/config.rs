@@ -1,106 +1,1057 @@
+use crate::error;
+use crate::logger::Logger;
+pub use crate::types::{EmbedSettings, DEFAULT_EMBED_WORKERS};
+
 #[cfg(debug_assertions)]
 const DEBUG: bool = true;
 #[cfg(not(debug_assertions))]
 const DEBUG: bool = false;
 
-fn create_if_nonexistent(path: &std::path::PathBuf) {
-    if !path.exists() {
-        match std::fs::create_dir_all(&path) {
-            Ok(_) => (),
-            Err(e) => panic!("Failed to create directory: {:?}, {}", path, e),
-        };
+fn env_var(key: &str) -> Option<String> {
+    std::env::var(key).ok()
+}
+
+// the home directory the environment gives, if it's one worth keeping anything under
+//
+// containers often leave HOME unset, or set it to `/`, and neither counts
+fn env_home_dir(var: impl Fn(&str) -> Option<String>) -> Option<std::path::PathBuf> {
+    var("HOME")
+        .or_else(|| var("USERPROFILE"))
+        .or_else(|| Some(format!("{}{}", var("HOMEDRIVE")?, var("HOMEPATH")?)))
+        .filter(|dir| !dir.is_empty() && std::path::Path::new(dir) != std::path::Path::new("/"))
+        .map(std::path::PathBuf::from)
+}
+
+fn find_home_dir() -> Option<std::path::PathBuf> {
+    if cfg!(test) || cfg!(feature = "regression") {
+        Some(std::env::temp_dir().join("dewey_testing"))
+    } else {
+        env_home_dir(env_var)
     }
 }
 
-fn touch_file(path: &std::path::PathBuf) {
-    if !path.exists() {
-        match std::fs::File::create(&path) {
-            Ok(_) => (),
-            Err(e) => panic!("Failed to create file: {:?}, {}", path, e),
+// without a home directory, `~` is the root, which is where containers tend to put it
+pub fn get_home_dir() -> std::path::PathBuf {
+    find_home_dir().unwrap_or_else(|| std::path::PathBuf::from("/"))
+}
+
+// where an index keeps its config, ledger, and data
+//
+// the default is ~/.config/dewey and ~/.local/dewey,
+// while `DataPaths::at` keeps all of it under a single directory
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataPaths {
+    pub config_dir: std::path::PathBuf,
+    pub local_dir: std::path::PathBuf,
+    pub data_dir: std::path::PathBuf,
+    // the model of a `for_model` store, in place of the configured one
+    pub model: Option<String>,
+    // the name of a `snapshot` store, which is only ever read
+    pub snapshot: Option<String>,
+}
+
+// stores for models other than the configured one are kept in $DATA_DIR/models/<model>
+const MODELS_DIR: &str = "models";
+
+// every store keeps the centroids of its files in $DATA_DIR/files
+const CENTROIDS_DIR: &str = "files";
+
+// snapshots of the configured model's store are kept in $DATA_DIR/snapshots/<name>
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+
+// the operations that changed the data directory, one JSON object per line
+const JOURNAL_FILE: &str = "journal.log";
+
+// the root that stored paths are relative to, see `PathResolver`
+const PATH_ROOT_FILE: &str = "path_root";
+
+thread_local! {
+    static SCOPED_PATHS: std::cell::RefCell<Option<DataPaths>> = const { std::cell::RefCell::new(None) };
+}
+
+// `DEWEY_CONFIG_DIR` and `DEWEY_DATA_DIR` stand in for ~/.config/dewey and ~/.local/dewey,
+// and are the only way to place them without a home directory
+//
+// test builds never read them, so a test can't end up writing to a real data directory
+fn home_paths(
+    home: Option<std::path::PathBuf>,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<DataPaths, std::io::Error> {
+    let dir = |key: &str, under_home: &[&str]| match (var(key), &home) {
+        (Some(dir), _) if !dir.is_empty() => Ok(std::path::PathBuf::from(dir)),
+        (_, Some(home)) => Ok(under_home.iter().fold(home.clone(), |path, p| path.join(p))),
+        (_, None) => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "there's no home directory to keep dewey's files under, \
+             set HOME, or DEWEY_CONFIG_DIR and DEWEY_DATA_DIR",
+        )),
+    };
+
+    let local_dir = dir("DEWEY_DATA_DIR", &[".local", "dewey"])?;
+    Ok(DataPaths {
+        config_dir: dir("DEWEY_CONFIG_DIR", &[".config", "dewey"])?,
+        data_dir: local_dir.join("data"),
+        local_dir,
+        model: None,
+        snapshot: None,
+    })
+}
+
+impl DataPaths {
+    // the paths outside of `--data-dir`, from the environment
+    pub fn from_env() -> Result<Self, std::io::Error> {
+        let var = |key: &str| match cfg!(test) || cfg!(feature = "regression") {
+            true => None,
+            false => env_var(key),
         };
+
+        home_paths(find_home_dir(), var)
+    }
+
+    // `config::setup` turns away an environment without any paths,
+    // so the temporary directory is only ever used by code that runs before it
+    pub fn home() -> Self {
+        Self::from_env().unwrap_or_else(|_| Self::at(&std::env::temp_dir().join("dewey")))
+    }
+
+    pub fn at(root: &std::path::Path) -> Self {
+        Self {
+            config_dir: root.join("config"),
+            local_dir: root.to_path_buf(),
+            data_dir: root.join("data"),
+            model: None,
+            snapshot: None,
+        }
+    }
+
+    // the directories, along with empty ledgers and rules if there aren't any yet
+    pub fn create(&self) -> Result<(), std::io::Error> {
+        for dir in [
+            &self.config_dir,
+            &self.local_dir,
+            &self.data_dir,
+            &self.local_dir.join("queries"),
+        ] {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        for file in [
+            self.local_dir.join("ledger"),
+            self.config_dir.join("ledger"),
+            self.config_dir.join("rules"),
+        ] {
+            if !file.exists() {
+                std::fs::File::create(file)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // the store of `model`'s embeddings: blocks, directory, and index
+    //
+    // the configured model's store is the data directory itself,
+    // and everything else shares the config and ledgers
+    pub fn for_model(&self, model: &str) -> Self {
+        if *model == self.scope(configured_model) {
+            return Self {
+                data_dir: self.root_data_dir(),
+                model: None,
+                snapshot: None,
+                ..self.clone()
+            };
+        }
+
+        Self {
+            data_dir: self.root_data_dir().join(MODELS_DIR).join(model),
+            model: Some(model.to_string()),
+            snapshot: None,
+            ..self.clone()
+        }
+    }
+
+    // the snapshot `name` of the configured model's store, opened read-only
+    //
+    // it's searched like any other store, but anything that would write to it is turned away
+    pub fn snapshot(&self, name: &str) -> Self {
+        Self {
+            data_dir: self.root_data_dir().join(SNAPSHOTS_DIR).join(name),
+            model: None,
+            snapshot: Some(name.to_string()),
+            ..self.clone()
+        }
+    }
+
+    // the store of the file centroids of this one, laid out like a store of its own
+    pub fn centroids(&self) -> Self {
+        Self {
+            data_dir: self.data_dir.join(CENTROIDS_DIR),
+            ..self.clone()
+        }
+    }
+
+    fn root_data_dir(&self) -> std::path::PathBuf {
+        match self.model.is_some() || self.snapshot.is_some() {
+            true => self
+                .data_dir
+                .parent()
+                .and_then(|p| p.parent())
+                .map(|p| p.to_path_buf())
+                .unwrap_or_else(|| self.data_dir.clone()),
+            false => self.data_dir.clone(),
+        }
+    }
+
+    // the journal of every operation on the data directory, which every model's store shares
+    pub fn journal_path(&self) -> std::path::PathBuf {
+        self.root_data_dir().join(JOURNAL_FILE)
+    }
+
+    // where the path root is recorded, which every store shares along with the ledger
+    pub fn path_root_path(&self) -> std::path::PathBuf {
+        self.local_dir.join(PATH_ROOT_FILE)
+    }
+
+    // where the data directory lock of this store is kept,
+    // which for a snapshot is the lock of the data directory it was taken from
+    pub fn lock_path(&self) -> std::path::PathBuf {
+        match self.snapshot {
+            Some(_) => self.root_data_dir().join(".lock"),
+            None => self.data_dir.join(".lock"),
+        }
+    }
+
+    // the configured model's store, followed by every other model's store that's been made
+    pub fn model_stores(&self) -> Result<Vec<Self>, std::io::Error> {
+        let root = self.for_model(&self.scope(configured_model));
+        let mut stores = vec![root.clone()];
+
+        let models_dir = root.data_dir.join(MODELS_DIR);
+        if !models_dir.is_dir() {
+            return Ok(stores);
+        }
+
+        let mut models = Vec::new();
+        for entry in std::fs::read_dir(&models_dir)? {
+            let entry = entry?;
+            if entry.path().is_dir() {
+                models.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+
+        models.sort();
+        stores.extend(models.iter().map(|model| root.for_model(model)));
+
+        Ok(stores)
+    }
+
+    // runs `f` with every path on this thread (`get_data_dir` and the rest) pointing here
+    //
+    // threads spawned inside `f` start back at the default paths,
+    // so anything that spawns threads touching the data directory has to pass the paths along
+    pub fn scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        struct Restore(Option<DataPaths>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                SCOPED_PATHS.with(|paths| *paths.borrow_mut() = self.0.take());
+            }
+        }
+
+        let _restore = Restore(SCOPED_PATHS.with(|paths| paths.replace(Some(self.clone()))));
+        f()
     }
 }
 
-pub fn get_home_dir() -> std::path::PathBuf {
-    if cfg!(test) || cfg!(feature = "regression") {
-        std::env::temp_dir().join("dewey_testing")
-    } else {
-        match std::env::var("HOME")
-            .or_else(|_| std::env::var("USERPROFILE"))
-            .or_else(|_| {
-                std::env::var("HOMEDRIVE").and_then(|homedrive| {
-                    std::env::var("HOMEPATH").map(|homepath| format!("{}{}", homedrive, homepath))
-                })
-            }) {
-            Ok(dir) => std::path::PathBuf::from(dir),
-            Err(_) => panic!("Failed to get home directory"),
+// the paths of a `--data-dir` flag, laid out like `DataPaths::at`, or the defaults without one
+//
+// the directory has to exist already unless `init` is set,
+// which creates it along with the rest of the layout
+pub fn data_dir_paths(
+    data_dir: Option<&std::path::Path>,
+    init: bool,
+) -> Result<DataPaths, std::io::Error> {
+    let root = match data_dir {
+        Some(root) => root,
+        None => {
+            let paths = DataPaths::from_env()?;
+            if init {
+                paths.create()?;
+            }
+
+            return Ok(paths);
         }
+    };
+
+    if init {
+        std::fs::create_dir_all(root)?;
+    } else if !root.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!(
+                "data directory {} doesn't exist, pass --init to create it",
+                root.display()
+            ),
+        ));
+    }
+
+    let paths = DataPaths::at(&root.canonicalize()?);
+    if init {
+        paths.create()?;
     }
+
+    Ok(paths)
+}
+
+// the paths of the innermost `DataPaths::scope` on this thread, or the defaults outside of one
+pub fn get_paths() -> DataPaths {
+    SCOPED_PATHS
+        .with(|paths| paths.borrow().clone())
+        .unwrap_or_else(DataPaths::home)
 }
 
 pub fn get_config_dir() -> std::path::PathBuf {
-    let home_dir = get_home_dir();
-    home_dir.join(".config/dewey")
+    get_paths().config_dir
 }
 
 pub fn get_local_dir() -> std::path::PathBuf {
-    let home_dir = get_home_dir();
-    home_dir.join(".local/dewey")
+    get_paths().local_dir
+}
+
+// saved queries, named `<timestamp micros>-<random suffix>`
+pub fn get_queries_dir() -> std::path::PathBuf {
+    get_local_dir().join("queries")
 }
 
 pub fn get_data_dir() -> std::path::PathBuf {
-    let home_dir = get_home_dir();
-    home_dir.join(".local/dewey/data")
+    get_paths().data_dir
+}
+
+// expands a leading `~` to the home directory, and `~user` to that user's
+//
+// only a bare `~` or one followed by a separator is expanded,
+// so files that happen to start with `~` (or name users that don't exist) are left alone
+pub fn expand_home(path: &str) -> std::path::PathBuf {
+    let rest = match path.strip_prefix('~') {
+        Some(rest) => rest,
+        None => return std::path::PathBuf::from(path),
+    };
+
+    let (user, rest) = rest.split_at(rest.find(std::path::is_separator).unwrap_or(rest.len()));
+    let rest = rest.trim_start_matches(std::path::is_separator);
+
+    let home = match user {
+        "" => Some(get_home_dir()),
+        user => std::fs::read_to_string("/etc/passwd")
+            .ok()
+            .and_then(|passwd| user_home(&passwd, user)),
+    };
+
+    match home {
+        Some(home) if rest.is_empty() => home,
+        Some(home) => home.join(rest),
+        None => std::path::PathBuf::from(path),
+    }
+}
+
+// a user's home directory from passwd lines, `name:password:uid:gid:info:home:shell`
+pub fn user_home(passwd: &str, user: &str) -> Option<std::path::PathBuf> {
+    passwd
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() >= 6 && fields[0] == user)
+        .map(|fields| std::path::PathBuf::from(fields[5]))
+}
+
+// expands `$VAR` and `${VAR}` from the environment
+//
+// a `$` that isn't followed by a variable name (like a `$5` or an unclosed `${`) is kept as it is,
+// and a variable that isn't set is an error naming it
+pub fn expand_vars(value: &str) -> Result<String, String> {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        let (name, remaining) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => ("", after),
+            },
+            None => {
+                let end = after.find(|c| !is_name(c)).unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            }
+        };
+
+        if name.is_empty()
+            || name.starts_with(|c: char| c.is_ascii_digit())
+            || !name.chars().all(is_name)
+        {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+
+        match std::env::var(name) {
+            Ok(value) => expanded.push_str(&value),
+            Err(_) => return Err(name.to_string()),
+        }
+
+        rest = remaining;
+    }
+
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+// variables first, so that a variable can expand to a path starting with `~`
+pub fn expand_path(path: &str) -> Result<std::path::PathBuf, String> {
+    Ok(expand_home(&expand_vars(path)?))
+}
+
+// turns the filepaths an index keeps between the absolute paths everything else works with
+// and the way they're stored in the ledger, blocks, and directory
+//
+// paths under the root are stored relative to it and joined back onto it when they're read,
+// so the index can move along with its files, while paths outside of it are stored as they are
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PathResolver {
+    root: Option<std::path::PathBuf>,
+}
+
+impl PathResolver {
+    pub fn new(root: Option<std::path::PathBuf>) -> Self {
+        Self { root }
+    }
+
+    // the root recorded for the paths in scope
+    //
+    // without one, the `path_root` in the config is recorded the first time it's used,
+    // after which the config no longer moves it, and only `dbio::rebase` does
+    pub fn current() -> Self {
+        let recorded = get_paths().path_root_path();
+        if let Ok(root) = std::fs::read_to_string(&recorded) {
+            if !root.trim().is_empty() {
+                return Self::new(Some(root.trim().into()));
+            }
+        }
+
+        let root = match get_config_value("path_root") {
+            Some(root) => expand_home(&root),
+            None => return Self::default(),
+        };
+        let root = root.canonicalize().unwrap_or(root);
+
+        match root.is_absolute() {
+            true => {
+                if let Err(e) = std::fs::write(&recorded, root.to_string_lossy().as_bytes()) {
+                    error!("failed to record the path root {}: {}", root.display(), e);
+                }
+
+                Self::new(Some(root))
+            }
+            false => {
+                error!(
+                    "ignoring path_root {}, which isn't absolute",
+                    root.display()
+                );
+                Self::default()
+            }
+        }
+    }
+
+    pub fn root(&self) -> Option<&std::path::Path> {
+        self.root.as_deref()
+    }
+
+    // how `filepath` is kept on disk
+    pub fn store(&self, filepath: &str) -> String {
+        let relative = self
+            .root
+            .as_ref()
+            .and_then(|root| std::path::Path::new(filepath).strip_prefix(root).ok())
+            .filter(|relative| !relative.as_os_str().is_empty());
+
+        match relative {
+            Some(relative) => relative.to_string_lossy().to_string(),
+            None => filepath.to_string(),
+        }
+    }
+
+    // the absolute path of a filepath as it was kept on disk
+    pub fn resolve(&self, stored: &str) -> String {
+        match &self.root {
+            Some(root) if std::path::Path::new(stored).is_relative() => {
+                root.join(stored).to_string_lossy().to_string()
+            }
+            _ => stored.to_string(),
+        }
+    }
+}
+
+pub const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+// settings are housed in ~/.config/dewey/config, formatted as `key value` on each line
+// lines starting with `#` are comments
+//
+// a missing file or key just means the default is used
+pub fn get_config_value(key: &str) -> Option<String> {
+    let contents = std::fs::read_to_string(get_config_dir().join("config")).ok()?;
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| value.trim().to_string())
+}
+
+// the model of the store in scope, which outside of a `DataPaths::for_model` store is the configured one
+pub fn get_embedding_model() -> String {
+    get_paths().model.unwrap_or_else(configured_model)
+}
+
+fn configured_model() -> String {
+    get_config_value("model").unwrap_or_else(|| DEFAULT_EMBEDDING_MODEL.to_string())
+}
+
+// the distance new indexes are built with, from the config's `metric`
+//
+// anything other than `cosine`, `dot`, or `l2` falls back to cosine
+#[cfg(feature = "index")]
+pub fn get_metric() -> crate::hnsw::Metric {
+    get_config_value("metric")
+        .and_then(|m| crate::hnsw::Metric::from_string(&m).ok())
+        .unwrap_or_default()
+}
+
+// positive numbers only, anything else falls back to the default
+fn get_positive_config_value(key: &str) -> Option<usize> {
+    match get_config_value(key).map(|v| v.parse::<usize>()) {
+        Some(Ok(value)) if value > 0 => Some(value),
+        _ => None,
+    }
+}
+
+pub fn get_embed_settings() -> EmbedSettings {
+    let defaults = EmbedSettings::default();
+    EmbedSettings {
+        workers: get_positive_config_value("embed_workers").unwrap_or(defaults.workers),
+        max_batch_items: get_positive_config_value("max_batch_items")
+            .unwrap_or(defaults.max_batch_items),
+        max_batch_tokens: get_positive_config_value("max_batch_tokens")
+            .unwrap_or(defaults.max_batch_tokens),
+    }
+}
+
+// what a model's provider takes in a single request, from the config's
+// `max_input_tokens` and `max_batch_items`
+//
+// a model can have its own with a line like
+// `provider <model> max_input_tokens 512 max_batch_items 32`,
+// falling back to the plain keys, then to the defaults
+//
+// tokens are in characters, like `max_batch_tokens`
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ProviderLimits {
+    pub max_input_tokens: usize,
+    pub max_batch_items: usize,
+}
+
+impl Default for ProviderLimits {
+    fn default() -> Self {
+        Self {
+            max_input_tokens: crate::types::TOKEN_LIMIT,
+            max_batch_items: usize::MAX,
+        }
+    }
+}
+
+// the `key value` pairs of the `provider` line for `model`, if there is one
+fn provider_config(model: &str) -> Vec<(String, String)> {
+    let contents = match std::fs::read_to_string(get_config_dir().join("config")) {
+        Ok(contents) => contents,
+        Err(_) => return Vec::new(),
+    };
+
+    let line = contents
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|words| words.len() >= 2 && words[0] == "provider" && words[1] == model);
+
+    match line {
+        Some(words) => words[2..]
+            .chunks_exact(2)
+            .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+pub fn get_provider_limits(model: &str) -> ProviderLimits {
+    let defaults = ProviderLimits::default();
+    let provider = provider_config(model);
+    let value = |key: &str| {
+        let declared = provider
+            .iter()
+            .find(|(k, _)| k == key)
+            .and_then(|(_, v)| v.parse::<usize>().ok())
+            .filter(|v| *v > 0);
+
+        declared.or_else(|| get_positive_config_value(key))
+    };
+
+    ProviderLimits {
+        max_input_tokens: value("max_input_tokens").unwrap_or(defaults.max_input_tokens),
+        max_batch_items: value("max_batch_items").unwrap_or(defaults.max_batch_items),
+    }
+}
+
+// the most a single embedding run is allowed to send, from the config's
+// `max_run_chunks` and `max_run_tokens`, unlimited where they aren't set
+//
+// tokens are in characters, like `max_batch_tokens`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EmbedQuota {
+    pub max_chunks: Option<usize>,
+    pub max_tokens: Option<usize>,
+}
+
+impl EmbedQuota {
+    pub fn is_limited(&self) -> bool {
+        self.max_chunks.is_some() || self.max_tokens.is_some()
+    }
+
+    pub fn allows(&self, chunks: usize, tokens: usize) -> bool {
+        self.max_chunks.is_none_or(|max| chunks <= max)
+            && self.max_tokens.is_none_or(|max| tokens <= max)
+    }
+}
+
+impl std::fmt::Display for EmbedQuota {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let limit = |max: Option<usize>| max.map_or(String::from("unlimited"), |m| m.to_string());
+        write!(
+            f,
+            "max_run_chunks {}, max_run_tokens {}",
+            limit(self.max_chunks),
+            limit(self.max_tokens)
+        )
+    }
+}
+
+pub fn get_embed_quota() -> EmbedQuota {
+    EmbedQuota {
+        max_chunks: get_positive_config_value("max_run_chunks"),
+        max_tokens: get_positive_config_value("max_run_tokens"),
+    }
+}
+
+pub const DEFAULT_API_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_BATCH_DEADLINE_SECS: u64 = 120;
+pub const DEFAULT_BATCH_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ApiTimeouts {
+    // how long connecting, or any single read or write, can take
+    pub timeout: std::time::Duration,
+    // how long a batch's whole request can take before it's abandoned
+    pub deadline: std::time::Duration,
+    // how many times a batch is tried, each on its own connection
+    pub attempts: usize,
+}
+
+impl Default for ApiTimeouts {
+    fn default() -> Self {
+        Self {
+            timeout: std::time::Duration::from_secs(DEFAULT_API_TIMEOUT_SECS),
+            deadline: std::time::Duration::from_secs(DEFAULT_BATCH_DEADLINE_SECS),
+            attempts: DEFAULT_BATCH_ATTEMPTS,
+        }
+    }
+}
+
+impl std::fmt::Display for ApiTimeouts {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}s timeout, {}s batch deadline, {} attempts per batch",
+            self.timeout.as_secs(),
+            self.deadline.as_secs(),
+            self.attempts
+        )
+    }
+}
+
+// an environment variable takes precedence over the config, for one-off runs
+fn get_env_or_config_value(var: &str, key: &str) -> Option<usize> {
+    match std::env::var(var).map(|v| v.parse::<usize>()) {
+        Ok(Ok(value)) if value > 0 => Some(value),
+        _ => get_positive_config_value(key),
+    }
+}
+
+// from `api_timeout`, `batch_deadline` (both in seconds), and `batch_attempts`,
+// or DEWEY_API_TIMEOUT, DEWEY_BATCH_DEADLINE, and DEWEY_BATCH_ATTEMPTS
+pub fn get_api_timeouts() -> ApiTimeouts {
+    let secs = |var: &str, key: &str, default: std::time::Duration| {
+        get_env_or_config_value(var, key)
+            .map_or(default, |secs| std::time::Duration::from_secs(secs as u64))
+    };
+
+    let defaults = ApiTimeouts::default();
+    ApiTimeouts {
+        timeout: secs("DEWEY_API_TIMEOUT", "api_timeout", defaults.timeout),
+        deadline: secs("DEWEY_BATCH_DEADLINE", "batch_deadline", defaults.deadline),
+        attempts: get_env_or_config_value("DEWEY_BATCH_ATTEMPTS", "batch_attempts")
+            .unwrap_or(defaults.attempts),
+    }
+}
+
+pub const DEFAULT_BLOCK_READ_WORKERS: usize = 4;
+
+// how many blocks are read at once when every block is loaded, from `block_read_workers`
+pub fn get_block_read_workers() -> usize {
+    get_positive_config_value("block_read_workers").unwrap_or(DEFAULT_BLOCK_READ_WORKERS)
+}
+
+pub const DEFAULT_QUERY_CACHE_TTL_SECS: u64 = 30;
+
+// how long a server answers repeated queries from its cache, from `query_cache_ttl` in seconds
+pub fn get_query_cache_ttl() -> std::time::Duration {
+    let secs = get_positive_config_value("query_cache_ttl")
+        .map_or(DEFAULT_QUERY_CACHE_TTL_SECS, |secs| secs as u64);
+
+    std::time::Duration::from_secs(secs)
+}
+
+pub const DEFAULT_FLUSH_INTERVAL_SECS: u64 = 5;
+
+// how often a server writes its index back to disk after edits, from `flush_interval` in seconds
+pub fn get_flush_interval() -> std::time::Duration {
+    let secs = get_positive_config_value("flush_interval")
+        .map_or(DEFAULT_FLUSH_INTERVAL_SECS, |secs| secs as u64);
+
+    std::time::Duration::from_secs(secs)
+}
+
+pub const DEFAULT_EDIT_QUIET_MS: u64 = 2000;
+
+// how long a server waits after the last edit of a file before embedding it again,
+// from `edit_quiet_ms` in milliseconds
+pub fn get_edit_quiet_period() -> std::time::Duration {
+    let ms =
+        get_positive_config_value("edit_quiet_ms").map_or(DEFAULT_EDIT_QUIET_MS, |ms| ms as u64);
+
+    std::time::Duration::from_millis(ms)
+}
+
+pub const DEFAULT_QUERY_RETENTION: usize = 1000;
+
+// how many saved queries are kept, from `query_retention`, before the oldest are deleted
+pub fn get_query_retention() -> usize {
+    get_positive_config_value("query_retention").unwrap_or(DEFAULT_QUERY_RETENTION)
 }
 
-pub fn setup() {
-    let now = match DEBUG {
-        true => "debug".to_string(),
-        false => chrono::Local::now().format("%Y-%m-%d_%H-%M-%S").to_string(),
+pub const DEFAULT_QUERY_MAX_AGE_DAYS: u64 = 180;
+pub const DEFAULT_LOG_MAX_AGE_DAYS: u64 = 30;
+pub const DEFAULT_COMPRESS_LOGS_AFTER_DAYS: u64 = 7;
+pub const DEFAULT_HOUSEKEEPING_MAX_BYTES: u64 = 512 * 1024 * 1024;
+
+// what housekeeping keeps around, from the config's `query_max_age_days`,
+// `log_max_age_days`, `compress_logs_after_days`, and `housekeeping_max_bytes`
+#[cfg(feature = "full")]
+pub fn get_housekeeping_policy() -> crate::housekeeping::Policy {
+    let days = |key: &str, default: u64| {
+        let days = get_positive_config_value(key).map_or(default, |days| days as u64);
+        std::time::Duration::from_secs(days * 24 * 60 * 60)
     };
 
-    let now = if cfg!(feature = "regression") {
+    crate::housekeeping::Policy {
+        query_max_age: days("query_max_age_days", DEFAULT_QUERY_MAX_AGE_DAYS),
+        log_max_age: days("log_max_age_days", DEFAULT_LOG_MAX_AGE_DAYS),
+        compress_after: days("compress_logs_after_days", DEFAULT_COMPRESS_LOGS_AFTER_DAYS),
+        max_bytes: get_positive_config_value("housekeeping_max_bytes")
+            .map_or(DEFAULT_HOUSEKEEPING_MAX_BYTES, |bytes| bytes as u64),
+    }
+}
+
+pub const DEFAULT_MAINTENANCE_HOURS: &str = "3";
+
+// when a server runs its maintenance, every `maintenance_interval` seconds if that's set,
+// or otherwise in the local hours of `maintenance_hours` (see `maintenance::parse_hours`)
+#[cfg(feature = "full")]
+pub fn get_maintenance_schedule() -> crate::maintenance::Schedule {
+    if let Some(secs) = get_positive_config_value("maintenance_interval") {
+        return crate::maintenance::Schedule::Interval(std::time::Duration::from_secs(secs as u64));
+    }
+
+    let hours = get_config_value("maintenance_hours")
+        .and_then(|spec| crate::maintenance::parse_hours(&spec))
+        .or_else(|| crate::maintenance::parse_hours(DEFAULT_MAINTENANCE_HOURS))
+        .unwrap_or_default();
+
+    crate::maintenance::Schedule::Hours(hours)
+}
+
+// chunks are tagged as boilerplate once this many files have near-identical copies of them
+pub const DEFAULT_BOILERPLATE_THRESHOLD: usize = 3;
+
+// a chunk that only shows up in one file is never boilerplate,
+// so anything below 2 falls back to the default
+pub fn get_boilerplate_threshold() -> usize {
+    match get_config_value("boilerplate_threshold").map(|t| t.parse::<usize>()) {
+        Some(Ok(threshold)) if threshold > 1 => threshold,
+        _ => DEFAULT_BOILERPLATE_THRESHOLD,
+    }
+}
+
+pub const DEFAULT_TITLE_WEIGHT: f32 = 0.0;
+
+// how much of a chunk's score comes from its file's title, from `title_weight`
+pub fn get_title_weight() -> f32 {
+    match get_config_value("title_weight").map(|w| w.parse::<f32>()) {
+        Some(Ok(weight)) if (0.0..=1.0).contains(&weight) => weight,
+        _ => DEFAULT_TITLE_WEIGHT,
+    }
+}
+
+pub const DEFAULT_FILTERED_EF_CAP: usize = 2000;
+
+// the most a selective filter can widen a query's ef to, from `filtered_ef_cap`,
+// which also bounds how many nodes carrying the filtered meta are scanned instead
+pub fn get_filtered_ef_cap() -> usize {
+    match get_config_value("filtered_ef_cap").map(|c| c.parse::<usize>()) {
+        Some(Ok(cap)) if cap > 0 => cap,
+        _ => DEFAULT_FILTERED_EF_CAP,
+    }
+}
+
+// whether indexes are loaded with their bottom layer left on disk, from `low_memory`,
+// which is for indexes too big to keep in memory whole
+pub fn get_low_memory() -> bool {
+    get_config_value("low_memory").is_some_and(|v| v == "true")
+}
+
+// whether queries filter against the ledger's meta as it is now, from `live_meta`,
+// rather than the meta files had when they were embedded, which only changes on a reblock
+pub fn get_live_meta() -> bool {
+    get_config_value("live_meta").is_some_and(|v| v == "true")
+}
+
+pub const DEFAULT_MAX_PARSE_BYTES: usize = 16 * 1024 * 1024;
+
+// the biggest file that's read whole to be split by function or by markdown fence,
+// from `max_parse_bytes`; anything bigger is split as it's read instead
+pub fn get_max_parse_bytes() -> usize {
+    get_positive_config_value("max_parse_bytes").unwrap_or(DEFAULT_MAX_PARSE_BYTES)
+}
+
+// where scheduled maintenance backs the data directory up to, from `backup_dir`,
+// which isn't backed up on a schedule without it
+pub fn get_backup_dir() -> Option<std::path::PathBuf> {
+    expand_path(&get_config_value("backup_dir")?).ok()
+}
+
+pub const DEFAULT_LEDGER_REMOVAL_LIMIT: f32 = 0.25;
+
+// the fraction of the ledger a sync can drop without being confirmed, from `ledger_removal_limit`
+pub fn get_ledger_removal_limit() -> f32 {
+    match get_config_value("ledger_removal_limit").map(|l| l.parse::<f32>()) {
+        Some(Ok(limit)) if (0.0..=1.0).contains(&limit) => limit,
+        _ => DEFAULT_LEDGER_REMOVAL_LIMIT,
+    }
+}
+
+// the acls `token` can see past, from the config's `acl_token TOKEN NAME...` lines,
+// every one of them for a token on more than one line
+//
+// a request without a token only sees what isn't kept behind any acl
+pub fn get_token_acls(token: Option<&str>) -> std::collections::BTreeSet<String> {
+    let (token, contents) = match (
+        token,
+        std::fs::read_to_string(get_config_dir().join("config")),
+    ) {
+        (Some(token), Ok(contents)) => (token, contents),
+        _ => return std::collections::BTreeSet::new(),
+    };
+
+    contents
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .filter(|words| words.len() >= 2 && words[0] == "acl_token" && words[1] == token)
+        .flat_map(|words| {
+            words[2..]
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+// logs go under the real home directory, even in test builds,
+// and next to the ledger when there isn't one
+pub fn get_logs_dir() -> std::path::PathBuf {
+    match env_home_dir(env_var) {
+        Some(home) => home.join(".local").join("dewey"),
+        None => get_local_dir(),
+    }
+    .join("logs")
+}
+
+// log files are named for when the process started,
+// apart from debug and regression builds, which always log to the same file
+fn get_log_name() -> String {
+    if cfg!(feature = "regression") {
         "regression".to_string()
+    } else if DEBUG {
+        "debug".to_string()
     } else {
-        now
-    };
+        chrono::Local::now().format("%Y-%m-%d_%H-%M-%S").to_string()
+    }
+}
 
-    match std::env::var("OPENAI_API_KEY") {
-        Ok(_) => (),
-        Err(_) => panic!("OPENAI_API_KEY environment variable not set"),
+// the file `target` logs to, which is only stable across processes in debug and regression builds
+pub fn get_log_path(target: crate::logger::LogTarget) -> std::path::PathBuf {
+    get_logs_dir().join(target.file_name(&get_log_name()))
+}
+
+// errors are meant to be printed as they are, since nothing can be logged yet
+//
+// the log is the one thing that can't stop dewey from starting,
+// and goes to stderr if its directory can't be written to
+pub fn setup(target: crate::logger::LogTarget) -> Result<(), std::io::Error> {
+    // unit tests and self-tests never reach the API, and builds without `embed` can't
+    #[cfg(feature = "embed")]
+    if !cfg!(test) && !crate::openai::uses_test_embeddings() && env_var("OPENAI_API_KEY").is_none()
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "OPENAI_API_KEY environment variable not set",
+        ));
     }
 
-    let config_path = get_config_dir();
+    if SCOPED_PATHS.with(|paths| paths.borrow().is_none()) {
+        DataPaths::from_env()?;
+    }
+
+    let logging_path = get_logs_dir();
+    if let Err(e) = std::fs::create_dir_all(&logging_path) {
+        eprintln!("couldn't create {}: {}", logging_path.display(), e);
+    }
+
+    crate::logger::Logger::init(&logging_path, &get_log_name(), target);
+
     let local_path = get_local_dir();
-    let data_path = get_data_dir();
-
-    let queries_path = local_path.join("queries");
-    let logging_path = match std::env::var("HOME")
-        .or_else(|_| std::env::var("USERPROFILE"))
-        .or_else(|_| {
-            std::env::var("HOMEDRIVE").and_then(|homedrive| {
-                std::env::var("HOMEPATH").map(|homepath| format!("{}{}", homedrive, homepath))
-            })
-        }) {
-        Ok(dir) => std::path::PathBuf::from(dir),
-        Err(_) => panic!("Failed to get home directory"),
-    }
-    .join(".local")
-    .join("dewey")
-    .join("logs");
-
-    create_if_nonexistent(&local_path);
-    create_if_nonexistent(&config_path);
-    create_if_nonexistent(&logging_path);
-    create_if_nonexistent(&data_path);
-    create_if_nonexistent(&queries_path);
-
-    crate::logger::Logger::init(format!(
-        "{}/{}.log",
-        logging_path.to_str().unwrap(),
-        now.clone()
-    ));
-
-    touch_file(&local_path.join("ledger"));
-    touch_file(&config_path.join("ledger"));
+    let config_path = get_config_dir();
+    for dir in [
+        &local_path,
+        &config_path,
+        &get_data_dir(),
+        &get_queries_dir(),
+    ] {
+        std::fs::create_dir_all(dir).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("couldn't create {}: {}", dir.display(), e),
+            )
+        })?;
+    }
+
+    for ledger in [local_path.join("ledger"), config_path.join("ledger")] {
+        if !ledger.exists() {
+            std::fs::File::create(&ledger).map_err(|e| {
+                std::io::Error::new(
+                    e.kind(),
+                    format!("couldn't create {}: {}", ledger.display(), e),
+                )
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "full"))]
+mod tests {
+    use super::*;
+    use crate::test_common::Cleanup;
+
+    // containers without a home directory get their paths from DEWEY_CONFIG_DIR and DEWEY_DATA_DIR,
+    // and an error that says so when they aren't set either
+    #[test]
+    fn home_paths_test() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |key: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+
+        let paths = home_paths(env_home_dir(env(&[("HOME", "/home/me")])), env(&[])).unwrap();
+        assert_eq!(
+            paths.config_dir,
+            std::path::Path::new("/home/me/.config/dewey")
+        );
+        assert_eq!(
+            paths.data_dir,
+            std::path::Path::new("/home/me/.local/dewey/data")
+        );
+
+        for vars in [&[][..], &[("HOME", "/")][..], &[("HOME", "")][..]] {
+            assert!(env_home_dir(env(vars)).is_none());
+            let e = home_paths(env_home_dir(env(vars)), env(vars)).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+            assert!(e.to_string().contains("DEWEY_DATA_DIR"), "{}", e);
+        }
+
+        let vars = env(&[
+            ("HOME", "/"),
+            ("DEWEY_CONFIG_DIR", "/etc/dewey"),
+            ("DEWEY_DATA_DIR", "/var/lib/dewey"),
+        ]);
+        let paths = home_paths(env_home_dir(vars), vars).unwrap();
+        assert_eq!(paths.config_dir, std::path::Path::new("/etc/dewey"));
+        assert_eq!(paths.local_dir, std::path::Path::new("/var/lib/dewey"));
+        assert_eq!(paths.data_dir, std::path::Path::new("/var/lib/dewey/data"));
+
+        // one without the other still needs a home for the rest
+        let vars = env(&[("DEWEY_DATA_DIR", "/var/lib/dewey")]);
+        assert!(home_paths(None, vars).is_err());
+    }
+
+    // setting up without HOME works, with the logs kept next to the ledger
+    #[test]
+    fn setup_without_home_test() {
+        let _cleanup = Cleanup;
+
+        struct RestoreHome(Option<String>);
+        impl Drop for RestoreHome {
+            fn drop(&mut self) {
+                if let Some(home) = self.0.take() {
+                    std::env::set_var("HOME", home);
+                }
+            }
+        }
+
+        let _restore = RestoreHome(env_var("HOME"));
+        std::env::remove_var("HOME");
+        std::env::remove_var("USERPROFILE");
+
+        let root = get_home_dir().join("container");
+        let paths = DataPaths::at(&root);
+        paths.scope(|| {
+            assert_eq!(get_logs_dir(), root.join("logs"));
+            assert!(setup(crate::logger::LogTarget::Library).is_ok());
+            assert!(root.join("logs").is_dir());
+            assert!(root.join("ledger").is_file());
+            assert!(root.join("config").join("ledger").is_file());
+            assert!(root.join("data").is_dir());
+        });
+    }
 }
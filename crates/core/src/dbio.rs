@@ -2,289 +2,1333 @@ use std::collections::{HashMap, HashSet};
 use std::io::Write;
 
 use serialize_macros::Serialize;
+use sha2::{Digest, Sha256};
 
 use crate::cache::EmbeddingCache;
-use crate::config::get_data_dir;
-use crate::hnsw::{normalize, HNSW};
+use crate::config::{get_data_dir, get_paths, PathResolver};
+use crate::embedding::{
+    is_chunk_meta, is_field_source, Embedding, EmbeddingModel, EmbeddingSource, TITLE_META,
+};
+use crate::hnsw::{is_normalized, normalize, Filter, FilterComparator, Metric, HNSW};
+use crate::journal::{self, Params};
+use crate::lock::{write_atomic, DataLock, LockMode};
 use crate::logger::Logger;
-use crate::openai::{embed_bulk, Embedding, EmbeddingSource};
 use crate::serialization::Serialize;
-use crate::{error, info};
+pub use crate::types::{BlockReport, FileInfo, StableChunk};
+use crate::{error, info, lprint};
 
 // TODO: this could probably be a config parameter
 pub const BLOCK_SIZE: usize = 1024;
 
-#[derive(Serialize)]
+// the version of the block format, written after `BLOCK_MAGIC` at the start of every block
+//
+// 0 is the format from before there was a version,
+// which starts with the block number and has no `embedded_at` in its embeddings
+pub const BLOCK_VERSION: u32 = 1;
+
+// stands in for the block number of a versioned block, since no block number gets near it
+const BLOCK_MAGIC: u64 = u64::MAX;
+
+// the format version of the block starting at `cursor`, and the size of its version header
+fn parse_block_version(bytes: &[u8], cursor: usize) -> Result<(u32, usize), std::io::Error> {
+    let (magic, size) = u64::from_bytes(bytes, cursor)?;
+    if magic != BLOCK_MAGIC {
+        return Ok((0, 0));
+    }
+
+    let (version, count) = u32::from_bytes(bytes, cursor + size)?;
+    if version > BLOCK_VERSION {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "block format version {} is newer than the {} this build reads",
+                version, BLOCK_VERSION
+            ),
+        ));
+    }
+
+    Ok((version, size + count))
+}
+
+// an embedding as version 0 blocks have it
+fn parse_unversioned_embedding(
+    bytes: &[u8],
+    cursor: usize,
+) -> Result<(Embedding, usize), std::io::Error> {
+    let (id, mut size) = u64::from_bytes(bytes, cursor)?;
+    let (source_file, count) = EmbeddingSource::from_bytes(bytes, cursor + size)?;
+    size += count;
+    let (data, count) = <[f32; crate::embedding::EMBED_DIM]>::from_bytes(bytes, cursor + size)?;
+    size += count;
+
+    Ok((
+        Embedding {
+            id,
+            source_file,
+            embedded_at: 0,
+            data,
+        },
+        size,
+    ))
+}
+
 pub struct EmbeddingBlock {
     block: u64,
+    pub model: EmbeddingModel,
+    // the files in `embeddings`, brought up to date whenever the block is written
+    filter: FileFilter,
     pub embeddings: Vec<Embedding>,
 }
 
 impl EmbeddingBlock {
-    fn to_file(&self, filename: &str) -> Result<(), std::io::Error> {
-        let mut file = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(filename)?;
+    pub(crate) fn new(block: u64, model: EmbeddingModel, embeddings: Vec<Embedding>) -> Self {
+        Self {
+            block,
+            model,
+            filter: FileFilter::of(&embeddings),
+            embeddings,
+        }
+    }
+
+    pub(crate) fn write_to(&mut self, filename: &std::path::Path) -> Result<(), std::io::Error> {
+        // a normalized store stays that way, whatever's written to it
+        if is_store_normalized() {
+            self.embeddings
+                .iter_mut()
+                .filter(|e| !is_normalized(e))
+                .for_each(normalize);
+        }
 
+        self.filter = FileFilter::of(&self.embeddings);
         let bytes = self.to_bytes();
-        info!("Writing {} bytes to {}", bytes.len(), filename);
-        file.write_all(&bytes)?;
+        info!("Writing {} bytes to {}", bytes.len(), filename.display());
+        write_atomic(filename, &bytes)
+    }
+}
 
-        Ok(())
+impl Serialize for EmbeddingBlock {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = BLOCK_MAGIC.to_bytes();
+        bytes.extend(BLOCK_VERSION.to_bytes());
+        bytes.extend(self.block.to_bytes());
+        bytes.extend(self.model.to_bytes());
+        bytes.extend(self.filter.to_bytes());
+
+        // filepaths go out as they're stored, which only differs from how they're held with a root
+        let paths = PathResolver::current();
+        match paths.root() {
+            Some(_) => bytes.extend(
+                self.embeddings
+                    .iter()
+                    .map(|e| {
+                        let mut e = e.clone();
+                        e.source_file.filepath = paths.store(&e.source_file.filepath);
+                        e
+                    })
+                    .collect::<Vec<_>>()
+                    .to_bytes(),
+            ),
+            None => bytes.extend(self.embeddings.to_bytes()),
+        }
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8], cursor: usize) -> Result<(Self, usize), std::io::Error> {
+        let (version, mut size) = parse_block_version(bytes, cursor)?;
+        let (block, count) = u64::from_bytes(bytes, cursor + size)?;
+        size += count;
+        let (model, count) = EmbeddingModel::from_bytes(bytes, cursor + size)?;
+        size += count;
+        let (filter, count) = FileFilter::from_bytes(bytes, cursor + size)?;
+        size += count;
+
+        let mut embeddings = match version {
+            0 => {
+                let (len, count) = u32::from_bytes(bytes, cursor + size)?;
+                size += count;
+
+                let mut embeddings = Vec::with_capacity(len as usize);
+                for _ in 0..len {
+                    let (embedding, count) = parse_unversioned_embedding(bytes, cursor + size)?;
+                    size += count;
+                    embeddings.push(embedding);
+                }
+
+                embeddings
+            }
+            _ => {
+                let (embeddings, count) = Vec::<Embedding>::from_bytes(bytes, cursor + size)?;
+                size += count;
+                embeddings
+            }
+        };
+
+        let paths = PathResolver::current();
+        if paths.root().is_some() {
+            for e in embeddings.iter_mut() {
+                e.source_file.filepath = paths.resolve(&e.source_file.filepath);
+            }
+        }
+
+        Ok((
+            Self {
+                block,
+                model,
+                filter,
+                embeddings,
+            },
+            size,
+        ))
+    }
+}
+
+// bits in each block's file filter, a fixed 2KB so the header can be read without the rest
+//
+// a full block of distinct files comes out to about 1 false positive in 400 lookups
+const FILTER_BITS: usize = 16 * 1024;
+const FILTER_HASHES: u64 = 4;
+
+// a bloom filter of the filepaths in a block,
+// so the blocks that might hold a file can be found from their headers alone
+#[derive(Serialize)]
+pub struct FileFilter {
+    bits: Vec<u64>,
+}
+
+impl FileFilter {
+    // the filter holds the filepaths as they're stored, so it stays good when the root moves
+    fn of(embeddings: &[Embedding]) -> Self {
+        let paths = PathResolver::current();
+        let mut bits = vec![0u64; FILTER_BITS / 64];
+        for e in embeddings.iter() {
+            for bit in Self::bits_of(&paths.store(&e.source_file.filepath)) {
+                bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+
+        Self { bits }
+    }
+
+    // the hash is sha256 rather than std's, which isn't guaranteed to stay the same across releases
+    fn bits_of(filepath: &str) -> impl Iterator<Item = usize> {
+        use sha2::{Digest, Sha256};
+
+        let digest = Sha256::digest(filepath.as_bytes());
+        let word = |i: usize| u64::from_le_bytes(digest[i..i + 8].try_into().unwrap());
+        let (h1, h2) = (word(0), word(8));
+
+        (0..FILTER_HASHES)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % FILTER_BITS as u64) as usize)
+    }
+
+    // whether `filepath`, as it's stored, might be in the block
+    //
+    // false positives are possible, false negatives aren't
+    pub fn may_contain(&self, filepath: &str) -> bool {
+        // a filter of some other size can't be checked, so it may as well hold everything
+        if self.bits.len() != FILTER_BITS / 64 {
+            return true;
+        }
+
+        Self::bits_of(filepath).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
     }
 }
 
-struct DirectoryEntry {
-    id: u32,
+#[derive(Debug, PartialEq)]
+pub(crate) struct DirectoryEntry {
+    pub(crate) id: u32,
     filepath: String,
+    // whether the entry is the file's title embedding
+    title: bool,
+}
+
+impl DirectoryEntry {
+    pub(crate) fn of(embedding: &Embedding) -> Self {
+        DirectoryEntry {
+            id: embedding.id as u32,
+            filepath: embedding.source_file.filepath.clone(),
+            title: embedding.source_file.meta.contains(TITLE_META),
+        }
+    }
 }
 
 pub struct Directory {
-    pub file_map: HashMap<String, u64>,
+    // every block holding embeddings of the file, in order
+    pub file_map: HashMap<String, Vec<u64>>,
     pub id_map: HashMap<u32, u64>,
     pub file_id_map: HashMap<String, u32>,
+    // the id of each file's title embedding
+    pub title_map: HashMap<String, u32>,
 }
 
 impl Directory {
     pub fn len(&self) -> usize {
         self.id_map.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.id_map.is_empty()
+    }
 }
 
-fn write_directory(entries: &Vec<(DirectoryEntry, u32)>) -> Result<(), std::io::Error> {
-    let directory = entries
-        .into_iter()
-        .map(|d| format!("{} {} {}", d.0.id, d.0.filepath, d.1))
-        .collect::<Vec<_>>();
-    let count = directory.len();
-    let directory = directory.join("\n");
+// ids whose embeddings were taken out of the blocks while an index on disk may still have them,
+// one to a line in $DATA_DIR/deleted_ids
+//
+// queries pass over them until a rebuild or reblock drops them from the index as well
+const TOMBSTONES_FILE: &str = "deleted_ids";
 
-    std::fs::write(
-        format!("{}/directory", get_data_dir().to_str().unwrap()),
-        directory,
-    )?;
+pub fn read_tombstones() -> Result<HashSet<u64>, std::io::Error> {
+    match std::fs::read_to_string(get_data_dir().join(TOMBSTONES_FILE)) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .filter_map(|line| line.trim().parse().ok())
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(e) => Err(e),
+    }
+}
 
-    info!("Wrote directory with {} entries", count);
+pub(crate) fn write_tombstones(tombstones: &HashSet<u64>) -> Result<(), std::io::Error> {
+    let path = get_data_dir().join(TOMBSTONES_FILE);
+    if tombstones.is_empty() {
+        return match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+
+    let mut ids = tombstones.iter().collect::<Vec<_>>();
+    ids.sort();
+
+    let contents = ids.iter().map(|id| format!("{}\n", id)).collect::<String>();
+    write_atomic(&path, contents.as_bytes())
+}
+
+pub(crate) fn add_tombstones(ids: impl IntoIterator<Item = u64>) -> Result<(), std::io::Error> {
+    let mut tombstones = read_tombstones()?;
+    let count = tombstones.len();
+    tombstones.extend(ids);
+
+    if tombstones.len() > count {
+        info!("{} deleted ids waiting on the index", tombstones.len());
+        write_tombstones(&tombstones)?;
+    }
 
     Ok(())
 }
 
-// synchronizes the index with the current ledger
-// TODO: ledgers need to include subsets of files
-//       we also need a proper tokenizer
-pub fn sync_index(full_embed: bool) -> Result<(), std::io::Error> {
-    let stale_sources = match full_embed {
-        true => crate::ledger::read_ledger()?
-            .into_iter()
-            .map(|entry| EmbeddingSource {
-                filepath: entry.filepath.clone(),
-                meta: entry.meta.clone(),
-                subset: None,
-            })
-            .collect::<Vec<_>>(),
-        false => {
-            let stale_files = crate::ledger::get_stale_files()?;
-            stale_files
-                .iter()
-                .map(|entry| EmbeddingSource {
-                    filepath: entry.filepath.clone(),
-                    meta: entry.meta.clone(),
-                    subset: None,
-                })
-                .collect::<Vec<_>>()
-        }
+// forgets the tombstones of ids that `index` doesn't have anymore,
+// once it's been written out
+pub fn prune_tombstones(index: &HNSW) -> Result<(), std::io::Error> {
+    let tombstones = read_tombstones()?;
+    let graph = index.get_last_layer();
+    let pending = tombstones
+        .iter()
+        .copied()
+        .filter(|&id| graph.contains(id))
+        .collect::<HashSet<_>>();
+
+    if pending.len() < tombstones.len() {
+        info!(
+            "cleared {} deleted ids from the index",
+            tombstones.len() - pending.len()
+        );
+        write_tombstones(&pending)?;
+    }
+
+    Ok(())
+}
+
+// the id each chunk's `EmbeddingSource::stable_id` has in the blocks,
+// as `stable_id id` lines in $DATA_DIR/stable_ids
+//
+// syncs and edits keep it up, and anything else that moves ids leaves it to be rebuilt
+// by the next lookup it can't answer, see `find_stable_id`
+pub(crate) const STABLE_IDS_FILE: &str = "stable_ids";
+
+pub(crate) fn stable_id_line(stable_id: &str, id: u64) -> String {
+    format!("{} {}", stable_id, id)
+}
+
+// a file's identical chunks share a stable id, which goes to the first of them listed
+pub fn read_stable_ids() -> Result<HashMap<String, u64>, std::io::Error> {
+    let contents = match std::fs::read_to_string(get_data_dir().join(STABLE_IDS_FILE)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
     };
 
-    let mut embeddings = embed_bulk(&stale_sources)?;
+    let mut stable_ids = HashMap::new();
+    for (stable_id, id) in contents.lines().filter_map(|line| line.split_once(' ')) {
+        if let Ok(id) = id.trim().parse() {
+            stable_ids.entry(stable_id.to_string()).or_insert(id);
+        }
+    }
 
-    for (i, e) in embeddings.iter_mut().enumerate() {
-        e.id = i as u64;
+    Ok(stable_ids)
+}
+
+pub(crate) fn write_stable_ids(stable_ids: &HashMap<String, u64>) -> Result<(), std::io::Error> {
+    let mut lines = stable_ids.iter().collect::<Vec<_>>();
+    lines.sort_by_key(|(_, id)| **id);
+
+    let contents = lines
+        .iter()
+        .map(|(stable_id, id)| format!("{}\n", stable_id_line(stable_id, **id)))
+        .collect::<String>();
+    write_atomic(&get_data_dir().join(STABLE_IDS_FILE), contents.as_bytes())
+}
+
+// the chunk of the store in scope with `stable_id`, `None` if none of them have it
+//
+// a map that doesn't have it or points somewhere else is rebuilt from the block headers
+// before giving up, since something other than a sync or edit may have moved the chunk
+pub fn find_stable_id(stable_id: &str) -> Result<Option<StableChunk>, std::io::Error> {
+    let directory = match get_directory() {
+        Ok(directory) => directory,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let paths = PathResolver::current();
+    if let Some(chunk) = lookup_stable_id(stable_id, &read_stable_ids()?, &directory, &paths)? {
+        return Ok(Some(chunk));
     }
 
-    let mut directory = Vec::new();
+    let stable_ids = rebuild_stable_ids(&directory, &paths)?;
+    lookup_stable_id(stable_id, &stable_ids, &directory, &paths)
+}
 
-    let data_dir = get_data_dir();
+fn lookup_stable_id(
+    stable_id: &str,
+    stable_ids: &HashMap<String, u64>,
+    directory: &Directory,
+    paths: &PathResolver,
+) -> Result<Option<StableChunk>, std::io::Error> {
+    let Some(&id) = stable_ids.get(stable_id) else {
+        return Ok(None);
+    };
+    let Some(&block_number) = directory.id_map.get(&(id as u32)) else {
+        return Ok(None);
+    };
 
-    let existing_blocks = std::fs::read_dir(data_dir.clone())?;
-    for entry in existing_blocks {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_file() {
-            if let Some(filename) = path.file_name() {
-                if let Some(filename) = filename.to_str() {
-                    if filename.parse::<u64>().is_ok() {
-                        std::fs::remove_file(path)?;
-                    }
-                }
+    Ok(read_embedding_block_headers(block_number)?
+        .into_iter()
+        .find(|h| h.id == id && h.source_file.stable_id(paths).as_deref() == Some(stable_id))
+        .map(|h| StableChunk {
+            stable_id: stable_id.to_string(),
+            id,
+            filepath: h.source_file.filepath,
+            subset: h.source_file.subset,
+        }))
+}
+
+fn rebuild_stable_ids(
+    directory: &Directory,
+    paths: &PathResolver,
+) -> Result<HashMap<String, u64>, std::io::Error> {
+    let mut stable_ids = HashMap::new();
+    for block_number in block_numbers()? {
+        for header in read_embedding_block_headers(block_number)? {
+            // embeddings the directory points elsewhere are leftovers of an older embedding
+            if directory.id_map.get(&(header.id as u32)) != Some(&block_number) {
+                continue;
+            }
+
+            if let Some(stable_id) = header.source_file.stable_id(paths) {
+                let id = stable_ids.entry(stable_id).or_insert(header.id);
+                *id = (*id).min(header.id);
             }
         }
     }
 
-    let blocks = embeddings.chunks(BLOCK_SIZE);
-    for (i, block) in blocks.enumerate() {
-        let filename = format!("{}/{}", data_dir.to_str().unwrap(), i);
-        let embedding_block = EmbeddingBlock {
-            block: i as u64,
-            embeddings: block.to_vec(),
-        };
+    info!("rebuilt the stable ids of {} chunks", stable_ids.len());
+    write_stable_ids(&stable_ids)?;
+
+    Ok(stable_ids)
+}
 
-        embedding_block.to_file(&filename)?;
+// the id after every one in use, including deleted ones an index might still have,
+// so that new embeddings are never mistaken for them
+pub(crate) fn next_embedding_id(ids: impl IntoIterator<Item = u32>) -> Result<u64, std::io::Error> {
+    let directory = ids.into_iter().map(|id| id as u64 + 1).max().unwrap_or(0);
+    let deleted = read_tombstones()?.into_iter().map(|id| id + 1).max();
 
-        for e in block {
-            directory.push((
-                DirectoryEntry {
-                    id: e.id as u32,
-                    filepath: e.source_file.filepath.clone(),
-                },
-                i as u32,
-            ));
-        }
+    Ok(deleted.map_or(directory, |deleted| directory.max(deleted)))
+}
+
+pub(crate) fn directory_line(entry: &DirectoryEntry, block: u32, paths: &PathResolver) -> String {
+    let line = format!("{} {} {}", entry.id, paths.store(&entry.filepath), block);
+    match entry.title {
+        true => format!("{} {}", line, TITLE_MARKER),
+        false => line,
     }
+}
 
-    // TODO: need some sort of follow-up to handle unfinished business regarding the directory
-    match write_directory(&directory) {
-        Ok(_) => {}
-        Err(e) => {
-            error!("error writing directory: {}", e);
-            return Err(e);
-        }
-    };
+pub(crate) fn write_directory(entries: &[(DirectoryEntry, u32)]) -> Result<(), std::io::Error> {
+    let paths = PathResolver::current();
+    let directory = entries
+        .iter()
+        .map(|(entry, block)| directory_line(entry, *block, &paths))
+        .collect::<Vec<_>>();
+    let count = directory.len();
+    let directory = directory.join("\n");
+
+    write_atomic(&get_data_dir().join("directory"), directory.as_bytes())?;
+
+    info!("Wrote directory with {} entries", count);
 
     Ok(())
 }
 
-// optimizes embedding placement in blocks based on their distance from their neighbors
-// also syncs meta changes from the ledger
-pub fn reblock() -> Result<(), std::io::Error> {
-    let index = match HNSW::new(false) {
-        Ok(index) => index,
-        Err(e) => {
-            eprintln!("Error creating index: {}", e);
-            eprintln!("Note: this operation requires an index to be present");
-            eprintln!("Run `hnsw -s` to recreate your index");
-            return Err(e);
-        }
+// builds the index of the store in scope over whatever is in its blocks, and writes it out
+//
+// an index needs at least two nodes to have any layers,
+// so a smaller store is left without one
+pub fn build_index() -> Result<Option<HNSW>, std::io::Error> {
+    let index_path = get_data_dir().join("index");
+    let nodes = match read_directory_entries() {
+        Ok(entries) => entries.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
     };
 
-    let full_graph = index.get_last_layer();
+    if nodes < 2 {
+        if index_path.exists() {
+            std::fs::remove_file(&index_path)?;
+        }
 
-    let mut blocks = vec![Vec::new()];
-    let mut i = 0;
+        return Ok(None);
+    }
 
-    let mut visited = HashSet::new();
-    let mut stack = Vec::new();
-    stack.push(*full_graph.iter().nth(0).unwrap().0);
+    let _lock = DataLock::acquire(LockMode::Exclusive, "build_index")?;
+    bump_state_generation()?;
+    let index = HNSW::build(&crate::hnsw::HNSWParams::default())?;
+    index.serialize(&index_path)?;
+    prune_tombstones(&index)?;
 
-    while let Some(current) = stack.pop() {
-        if visited.contains(&current) {
-            continue;
-        }
+    // stores embedded before there were centroids get theirs here
+    rebuild_centroids()?;
+
+    let generation = read_generation()?;
+    write_generation(&Generation {
+        index: generation.blocks,
+        ..generation
+    })?;
+
+    Ok(Some(index))
+}
+
+// the nodes a scoped rebuild took out of the index and put back in
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct IndexGraft {
+    pub removed: usize,
+    pub inserted: usize,
+}
 
-        if visited.len() % (full_graph.len() / 10) == 0 {
-            info!("blocked {} nodes into {} blocks", visited.len(), i + 1);
+// rebuilds the part of the index of the store in scope over the files `only` matches,
+// taking their nodes out and inserting their current embeddings again,
+// so the rest of the graph only changes where it had edges into those nodes
+//
+// nodes of embeddings that are gone are taken out along the way, whichever file they were of,
+// since the directory can't say anymore
+//
+// only embeddings inserted or deleted one file at a time keep the ids of everything else,
+// so an index that a sync has since renumbered the store under is turned away
+//
+// returns `None` if there's no index to graft onto
+pub fn graft_index(only: &crate::ledger::PathFilter) -> Result<Option<IndexGraft>, std::io::Error> {
+    journal::record("graft_index", |params| {
+        let index_path = get_data_dir().join("index");
+        if !index_path.exists() {
+            return Ok(None);
         }
 
-        if blocks[i].len() >= BLOCK_SIZE {
-            blocks.push(Vec::new());
-            i += 1;
+        let _lock = DataLock::acquire(LockMode::Exclusive, "graft_index")?;
+        if !read_generation()?.ids_stable() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the embeddings were given new ids since the index was built, \
+                 so it has to be rebuilt whole with -r",
+            ));
         }
 
-        blocks[i].push(current);
-        visited.insert(current);
+        bump_state_generation()?;
 
-        let mut neighbors = full_graph.get(&current).unwrap().clone();
-        neighbors.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        let entries = read_directory_entries()?;
+        let live = entries
+            .iter()
+            .map(|(id, _, _)| *id as u64)
+            .collect::<HashSet<_>>();
+        let mut matched = entries
+            .iter()
+            .filter(|(_, filepath, _)| only.matches(filepath))
+            .map(|(id, _, _)| *id as u64)
+            .collect::<Vec<_>>();
+        matched.sort();
 
-        for (neighbor, _) in neighbors {
-            if !visited.contains(&neighbor) {
-                stack.push(neighbor);
-            }
+        let mut index = HNSW::new(false)?;
+        let removed = index
+            .get_last_layer()
+            .nodes()
+            .into_iter()
+            .filter(|id| !live.contains(id) || matched.binary_search(id).is_ok())
+            .collect::<Vec<_>>();
+        for id in removed.iter() {
+            index.remove_node(*id)?;
         }
-    }
 
-    let mut cache = EmbeddingCache::new(10 * BLOCK_SIZE as u32)?;
+        index.insert_nodes(&matched)?;
+        index.serialize(&index_path)?;
+        prune_tombstones(&index)?;
 
-    // update meta
-    let ledger = crate::ledger::read_ledger()?;
-    let mut ledger_map = std::collections::HashMap::new();
-    for entry in ledger.iter() {
-        ledger_map.insert(entry.filepath.clone(), entry.meta.clone());
-    }
+        // the index only catches up if nothing outside of `only` was waiting on it
+        let graph = index.get_last_layer();
+        if live.iter().all(|&id| graph.contains(id)) {
+            let generation = read_generation()?;
+            write_generation(&Generation {
+                index: generation.blocks,
+                ..generation
+            })?;
+        }
 
-    // create a temp directory in $DATA_DIR to hold all the blocks
-    let data_dir = get_data_dir();
-    let temp_dir = format!("{}/temp", data_dir.to_str().unwrap());
+        let graft = IndexGraft {
+            removed: removed.len(),
+            inserted: matched.len(),
+        };
+        params.insert("removed".to_string(), graft.removed.into());
+        params.insert("inserted".to_string(), graft.inserted.into());
 
-    if std::fs::metadata(&temp_dir).is_ok() {
-        std::fs::remove_dir_all(&temp_dir)?;
-    }
+        Ok(Some(graft))
+    })
+}
 
-    std::fs::create_dir(&temp_dir)?;
+// picks the pinned entry points of the index of the store in scope again, without rebuilding it
+//
+// returns the nodes picked, `None` if there's no index
+pub fn recompute_entry_points() -> Result<Option<Vec<u64>>, std::io::Error> {
+    journal::record("recompute_entries", |params| {
+        let index_path = get_data_dir().join("index");
+        if !index_path.exists() {
+            return Ok(None);
+        }
 
-    let mut directory = Vec::new();
-    for (i, block) in blocks.iter().enumerate() {
-        let filename = format!("{}/{}", temp_dir, i);
-        let mut embeddings = Vec::new();
-        for id in block {
-            let mut embedding = cache.get(*id as u32).unwrap();
-            embedding.source_file.meta = match ledger_map.get(&embedding.source_file.filepath) {
-                Some(meta) => meta.clone(),
-                None => {
-                    error!(
-                        "File {} unaccounted for in ledger! Ignoring meta",
-                        embedding.source_file.filepath
-                    );
-                    embedding.source_file.meta
-                }
+        let _lock = DataLock::acquire(LockMode::Exclusive, "recompute_entries")?;
+        bump_state_generation()?;
+
+        let mut index = HNSW::new(false)?;
+        index.recompute_entry_points(crate::hnsw::ENTRY_POINTS, None)?;
+        index.serialize(&index_path)?;
+
+        params.insert("entry_points".to_string(), index.entry_points.len().into());
+        Ok(Some(index.entry_points))
+    })
+}
+
+// points the paths stored relative to the path root at `new`, for when the files under `old` moved there
+//
+// only the recorded root changes, so nothing is embedded or rewritten,
+// and paths that were stored before there was a root stay as they were
+pub fn rebase(old: &str, new: &str) -> Result<(), std::io::Error> {
+    journal::record("rebase", |params| {
+        params.insert("old".to_string(), old.into());
+        params.insert("new".to_string(), new.into());
+
+        let _lock = DataLock::acquire(LockMode::Exclusive, "rebase")?;
+        let root =
+            match PathResolver::current().root() {
+                Some(root) => root.to_path_buf(),
+                None => return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "there's no path root to rebase, set path_root in the config before embedding",
+                )),
             };
 
-            directory.push((
-                DirectoryEntry {
-                    id: embedding.id as u32,
-                    filepath: embedding.source_file.filepath.clone(),
-                },
-                i as u32,
+        let old = crate::config::expand_home(old);
+        if old != root {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("the path root is {}, not {}", root.display(), old.display()),
             ));
+        }
 
-            embeddings.push(*embedding);
+        let new = crate::config::expand_home(new).canonicalize()?;
+        if !new.is_dir() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} isn't a directory", new.display()),
+            ));
         }
 
-        let embedding_block = EmbeddingBlock {
-            block: i as u64,
-            embeddings,
-        };
+        // every store's filepaths move with the root, so none of their blocks can be kept as they were
+        for store in get_paths().model_stores()? {
+            store.scope(bump_state_generation)?;
+        }
 
-        embedding_block.to_file(&filename)?;
+        write_atomic(
+            &get_paths().path_root_path(),
+            new.to_string_lossy().as_bytes(),
+        )?;
+        lprint!(
+            info,
+            "Moved the path root from {} to {}",
+            root.display(),
+            new.display()
+        );
+
+        Ok(())
+    })
+}
+
+// moves the embeddings of files that were renamed without changing to their `(old, new)` paths,
+// in every store, so they don't need embedded again
+//
+// only the blocks holding them, the directory, and the centroids are rewritten--
+// ids stay the same, so the index is left as it is,
+// though the path embeddings keep the old names in them until the files are next embedded
+//
+// the caller holds the data directory lock
+//
+// returns the number of embeddings moved
+pub fn rename_files(renames: &[(String, String)]) -> Result<usize, std::io::Error> {
+    journal::record("rename_files", |params| {
+        params.insert("files".to_string(), renames.len().into());
+
+        let renames = renames
+            .iter()
+            .map(|(old, new)| (old.as_str(), new.as_str()))
+            .collect::<HashMap<_, _>>();
+
+        let mut moved = 0;
+        for store in get_paths().model_stores()? {
+            moved += store.scope(|| rename_in_store(&renames))?;
+        }
+
+        params.insert("embeddings".to_string(), moved.into());
+        Ok(moved)
+    })
+}
+
+fn rename_in_store(renames: &HashMap<&str, &str>) -> Result<usize, std::io::Error> {
+    if !get_data_dir().join("directory").exists() {
+        return Ok(0);
     }
 
-    for entry in std::fs::read_dir(data_dir.clone())? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_file() {
-            if let Some(filename) = path.file_name() {
-                if let Some(filename) = filename.to_str() {
-                    if filename.parse::<u64>().is_ok() {
-                        std::fs::remove_file(path)?;
-                    }
-                }
+    let mut entries = read_directory()?;
+    let blocks = entries
+        .iter()
+        .filter(|(entry, _)| renames.contains_key(entry.filepath.as_str()))
+        .map(|(_, block)| *block as u64)
+        .collect::<std::collections::BTreeSet<_>>();
+    if blocks.is_empty() {
+        return Ok(0);
+    }
+
+    bump_state_generation()?;
+
+    let mut moved = 0;
+    for block_number in blocks {
+        let mut block = read_embedding_block(block_number)?;
+        for e in block.embeddings.iter_mut() {
+            if let Some(new) = renames.get(e.source_file.filepath.as_str()) {
+                e.source_file.filepath = new.to_string();
+                moved += 1;
             }
         }
+
+        block.write_to(&get_data_dir().join(block_number.to_string()))?;
     }
 
-    std::fs::remove_file(format!("{}/directory", data_dir.to_str().unwrap()))?;
+    for (entry, _) in entries.iter_mut() {
+        if let Some(new) = renames.get(entry.filepath.as_str()) {
+            entry.filepath = new.to_string();
+        }
+    }
+    write_directory(&entries)?;
 
-    for entry in std::fs::read_dir(temp_dir.clone())? {
-        let entry = entry?;
-        let path = entry.path();
+    update_centroids(
+        &renames
+            .iter()
+            .flat_map(|(old, new)| [old.to_string(), new.to_string()])
+            .collect(),
+    )?;
+
+    info!(
+        "moved {} embeddings of renamed files in {}",
+        moved,
+        get_data_dir().display()
+    );
+
+    Ok(moved)
+}
+
+// how many times the blocks of the store in scope have changed without its index changing with them,
+// and how many of those changes the index was last built over
+//
+// kept in $DATA_DIR/generation as `blocks index renumbered`, and missing until anything's embedded
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Generation {
+    pub blocks: u64,
+    pub index: u64,
+    // the blocks generation a sync last gave every embedding a new id at
+    pub renumbered: u64,
+}
+
+impl Generation {
+    // the blocks have embeddings that the index doesn't
+    pub fn index_behind(&self) -> bool {
+        self.index < self.blocks
+    }
+
+    // the ids the index was built over still name the same embeddings,
+    // so it can be patched up instead of rebuilt
+    pub fn ids_stable(&self) -> bool {
+        self.renumbered <= self.index
+    }
+}
+
+pub fn read_generation() -> Result<Generation, std::io::Error> {
+    let contents = match std::fs::read_to_string(get_data_dir().join("generation")) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Generation::default()),
+        Err(e) => return Err(e),
+    };
+
+    // files from before renumbering was kept track of are taken to have just been renumbered
+    let mut parts = contents.split_whitespace().map(|p| p.parse::<u64>());
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(blocks)), Some(Ok(index)), renumbered) => match renumbered {
+            None => Ok(Generation {
+                blocks,
+                index,
+                renumbered: blocks,
+            }),
+            Some(Ok(renumbered)) => Ok(Generation {
+                blocks,
+                index,
+                renumbered,
+            }),
+            Some(Err(_)) => malformed_generation(&contents),
+        },
+        _ => malformed_generation(&contents),
+    }
+}
+
+fn malformed_generation(contents: &str) -> Result<Generation, std::io::Error> {
+    error!("malformed generation file: {:?}", contents);
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "malformed generation file",
+    ))
+}
+
+pub(crate) fn write_generation(generation: &Generation) -> Result<(), std::io::Error> {
+    write_atomic(
+        &get_data_dir().join("generation"),
+        format!(
+            "{} {} {}",
+            generation.blocks, generation.index, generation.renumbered
+        )
+        .as_bytes(),
+    )
+}
+
+pub(crate) fn bump_blocks_generation() -> Result<(), std::io::Error> {
+    let mut generation = read_generation()?;
+    generation.blocks += 1;
+    write_generation(&generation)
+}
+
+// how many times anything that can be searched has been changed, in any store,
+// for clients to tell whether what they've been given is still current
+//
+// kept in $LOCAL_DIR/state_generation, which every model's store shares,
+// and 0 until anything's been changed
+pub fn read_state_generation() -> Result<u64, std::io::Error> {
+    let path = crate::config::get_local_dir().join("state_generation");
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    contents.trim().parse::<u64>().map_err(|_| {
+        error!("malformed state generation file: {:?}", contents);
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "malformed state generation file",
+        )
+    })
+}
+
+// marks a store whose blocks only hold normalized embeddings, once it's been through `normalize_store`
+const NORMALIZED_FILE: &str = "normalized";
+
+// whether everything in the blocks of the store in scope is normalized,
+// which everything written to them is from then on
+pub fn is_store_normalized() -> bool {
+    get_data_dir().join(NORMALIZED_FILE).exists()
+}
+
+// a cosine store with embeddings that haven't been through `normalize_store`,
+// whose distances can come out differently depending on how its embeddings were read
+pub fn needs_normalizing() -> Result<bool, std::io::Error> {
+    Ok(Metric::current() == Metric::Cosine
+        && !is_store_normalized()
+        && !block_numbers()?.is_empty())
+}
+
+// readies an embedding read from the blocks to be compared under `metric`
+//
+// a normalized store's embeddings are ready as they are, which is only checked in debug builds
+pub(crate) fn prepare_stored(metric: Metric, normalized: bool, embedding: &mut Embedding) {
+    match normalized {
+        true => debug_assert!(
+            is_normalized(embedding),
+            "embedding {} of a normalized store isn't unit length",
+            embedding.id
+        ),
+        false => metric.prepare(embedding),
+    }
+}
+
+// every operation that changes the blocks, the index, or the ledger's embeddings bumps this
+// before it changes anything, so even a failed one leaves the old generation behind
+pub(crate) fn bump_state_generation() -> Result<(), std::io::Error> {
+    let generation = read_state_generation()? + 1;
+    write_atomic(
+        &crate::config::get_local_dir().join("state_generation"),
+        generation.to_string().as_bytes(),
+    )
+}
+
+// the centroid of each file is the mean of its chunk embeddings, normalized,
+// which matches the file as a whole instead of any one part of it
+//
+// path and title embeddings are left out, since they'd pull every file toward its name
+#[derive(Default)]
+pub(crate) struct Centroids {
+    // the running sums, until `into_embeddings`
+    files: HashMap<String, Embedding>,
+}
+
+impl Centroids {
+    pub(crate) fn add(&mut self, embedding: &Embedding) {
+        if is_field_source(&embedding.source_file) {
+            return;
+        }
+
+        let source = &embedding.source_file;
+        let centroid = self
+            .files
+            .entry(source.filepath.clone())
+            .or_insert_with(|| Embedding {
+                id: 0,
+                data: [0.0; crate::embedding::EMBED_DIM],
+                source_file: EmbeddingSource {
+                    filepath: source.filepath.clone(),
+                    meta: source
+                        .meta
+                        .iter()
+                        .filter(|m| !is_chunk_meta(m))
+                        .cloned()
+                        .collect(),
+                    subset: Some((0, 0)),
+                    hash: source.hash.clone(),
+                    chunk_hash: None,
+                },
+                embedded_at: 0,
+            });
+
+        // a file's centroid is as recent as its newest chunk
+        centroid.embedded_at = centroid.embedded_at.max(embedding.embedded_at);
+
+        // every chunk counts the same, however its vector was scaled
+        let length = embedding.data.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if length > 0.0 {
+            for (sum, x) in centroid.data.iter_mut().zip(embedding.data.iter()) {
+                *sum += x / length;
+            }
+        }
+    }
+
+    pub(crate) fn into_embeddings(self) -> Vec<Embedding> {
+        let mut centroids = self
+            .files
+            .into_values()
+            .filter(|c| c.data.iter().any(|x| *x != 0.0))
+            .collect::<Vec<_>>();
+        centroids.sort_by(|a, b| a.source_file.filepath.cmp(&b.source_file.filepath));
+
+        for c in centroids.iter_mut() {
+            normalize(c);
+        }
+
+        centroids
+    }
+}
+
+// replaces the centroids of the store in scope
+pub(crate) fn write_centroids(mut centroids: Vec<Embedding>) -> Result<(), std::io::Error> {
+    get_paths().centroids().scope(|| {
+        std::fs::create_dir_all(get_data_dir())?;
+        for (i, c) in centroids.iter_mut().enumerate() {
+            c.id = i as u64;
+        }
+
+        write_blocks(&centroids)
+    })
+}
+
+// computes the centroids of the store in scope over again from its blocks, one block at a time
+pub fn rebuild_centroids() -> Result<(), std::io::Error> {
+    let mut centroids = Centroids::default();
+    for block_number in block_numbers()? {
+        for e in read_embedding_block(block_number)?.embeddings.iter() {
+            centroids.add(e);
+        }
+    }
+
+    write_centroids(centroids.into_embeddings())
+}
+
+// recomputes the centroids of `files` in the store in scope, after their embeddings changed,
+// dropping the ones of files that don't have any embeddings left
+//
+// a store without centroids is left for `build_index` to give them to
+pub(crate) fn update_centroids(files: &HashSet<String>) -> Result<(), std::io::Error> {
+    let paths = get_paths().centroids();
+    if !paths.data_dir.join("directory").exists() {
+        return Ok(());
+    }
+
+    let blocks = read_directory_entries()?
+        .into_iter()
+        .filter(|(_, filepath, _)| files.contains(filepath))
+        .map(|(_, _, block)| block)
+        .collect::<HashSet<_>>();
+
+    let mut updated = Centroids::default();
+    for block_number in blocks {
+        for e in read_embedding_block(block_number)?.embeddings.iter() {
+            if files.contains(&e.source_file.filepath) {
+                updated.add(e);
+            }
+        }
+    }
+
+    let mut centroids = read_centroids()?
+        .into_iter()
+        .filter(|c| !files.contains(&c.source_file.filepath))
+        .collect::<Vec<_>>();
+    centroids.extend(updated.into_embeddings());
+    centroids.sort_by(|a, b| a.source_file.filepath.cmp(&b.source_file.filepath));
+
+    write_centroids(centroids)
+}
+
+// the file centroids of the store in scope
+pub fn read_centroids() -> Result<Vec<Embedding>, std::io::Error> {
+    let paths = get_paths().centroids();
+    if !paths.data_dir.join("directory").exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "files haven't been summarized yet, rebuild the index with -r",
+        ));
+    }
+
+    paths.scope(|| {
+        Ok(get_all_blocks()?
+            .into_iter()
+            .map(|be| *be.embedding)
+            .collect())
+    })
+}
+
+// replaces every block in the data directory with `embeddings`, in order,
+// and writes the matching directory
+//
+// the blocks are marked as made with the configured model
+pub fn write_blocks(embeddings: &[Embedding]) -> Result<(), std::io::Error> {
+    let mut directory = Vec::new();
+    let model = EmbeddingModel::current();
+
+    let data_dir = get_data_dir();
+
+    let existing_blocks = std::fs::read_dir(data_dir.clone())?;
+    for entry in existing_blocks {
+        let entry = entry?;
+        let path = entry.path();
+        if path.is_file() {
+            if let Some(filename) = path.file_name() {
+                if let Some(filename) = filename.to_str() {
+                    if filename.parse::<u64>().is_ok() {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+            }
+        }
+    }
+
+    let blocks = embeddings.chunks(BLOCK_SIZE);
+    for (i, block) in blocks.enumerate() {
+        let filename = data_dir.join(i.to_string());
+        let mut embedding_block = EmbeddingBlock::new(i as u64, model.clone(), block.to_vec());
+        embedding_block.write_to(&filename)?;
+
+        for e in block {
+            directory.push((DirectoryEntry::of(e), i as u32));
+        }
+    }
+
+    // TODO: need some sort of follow-up to handle unfinished business regarding the directory
+    match write_directory(&directory) {
+        Ok(_) => {}
+        Err(e) => {
+            error!("error writing directory: {}", e);
+            return Err(e);
+        }
+    };
+
+    Ok(())
+}
+
+// rewrites the blocks of the store in scope with every embedding normalized, then marks it normalized,
+// so embeddings compare the same whether they're read through a cache or all at once
+//
+// only cosine ignores the magnitudes normalizing drops, so the store has to be under it,
+// and the index is built again since its edges were measured between the old vectors
+//
+// returns how many embeddings weren't normalized already
+pub fn normalize_store(snapshot: bool) -> Result<usize, std::io::Error> {
+    journal::record("normalize_store", |params| {
+        params.insert("snapshot".to_string(), snapshot.into());
+        let normalized = run_normalize_store(snapshot, params)?;
+        if normalized > 0 && get_data_dir().join("index").exists() {
+            build_index()?;
+        }
+
+        Ok(normalized)
+    })
+}
+
+fn run_normalize_store(snapshot: bool, params: &mut Params) -> Result<usize, std::io::Error> {
+    let metric = Metric::current();
+    if metric != Metric::Cosine {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "the {} metric compares the magnitudes normalizing would drop, only a cosine store can be normalized",
+                metric
+            ),
+        ));
+    }
+
+    let _lock = DataLock::acquire(LockMode::Exclusive, "normalize_store")?;
+    if snapshot {
+        auto_snapshot("normalize")?;
+    }
+
+    bump_state_generation()?;
+
+    let mut normalized = 0;
+    let mut rewritten = 0;
+    for block_number in block_numbers()? {
+        let mut block = read_embedding_block(block_number)?;
+        let mut count = 0;
+        for e in block.embeddings.iter_mut().filter(|e| !is_normalized(e)) {
+            normalize(e);
+            count += 1;
+        }
+
+        if count > 0 {
+            block.write_to(&get_data_dir().join(block_number.to_string()))?;
+            normalized += count;
+            rewritten += 1;
+        }
+    }
+
+    // the mark goes last, so a store that didn't make it all the way isn't taken as normalized
+    write_atomic(&get_data_dir().join(NORMALIZED_FILE), b"")?;
+
+    params.insert("normalized".to_string(), normalized.into());
+    params.insert("blocks".to_string(), rewritten.into());
+    lprint!(
+        info,
+        "normalized {} embeddings in {} blocks",
+        normalized,
+        rewritten
+    );
+
+    Ok(normalized)
+}
+
+// optimizes embedding placement in blocks based on their distance from their neighbors
+// also syncs meta changes from the ledger
+//
+// `snapshot` takes an automatic snapshot of the blocks beforehand
+//
+// this is the store's compaction, and goes in the journal
+pub fn reblock(snapshot: bool) -> Result<(), std::io::Error> {
+    journal::record("reblock", |params| {
+        params.insert("snapshot".to_string(), snapshot.into());
+        run_reblock(snapshot, params)
+    })
+}
+
+fn run_reblock(snapshot: bool, params: &mut Params) -> Result<(), std::io::Error> {
+    let _lock = DataLock::acquire(LockMode::Exclusive, "reblock")?;
+    bump_state_generation()?;
+
+    let mut index = match HNSW::new(false) {
+        Ok(index) => index,
+        Err(e) => {
+            eprintln!("Error creating index: {}", e);
+            eprintln!("Note: this operation requires an index to be present");
+            eprintln!("Run `hnsw -s` to recreate your index");
+            return Err(e);
+        }
+    };
+
+    if snapshot {
+        auto_snapshot("reblock")?;
+    }
+
+    // reblocking only moves embeddings around, so they keep the model they were made with
+    let model = get_blocks_model()?.unwrap_or_else(EmbeddingModel::current);
+
+    let full_graph = index.get_last_layer();
+
+    // deleted nodes are still walked through to reach their neighbors, they just aren't kept
+    let deleted = read_tombstones()?;
+    let mut blocks = vec![Vec::new()];
+    let mut i = 0;
+
+    let mut visited = HashSet::new();
+    let mut stack = Vec::new();
+
+    // a node nothing links to can't be reached from the others,
+    // so every node the walks so far have missed starts one of its own
+    for root in full_graph.nodes() {
+        if visited.contains(&root) {
+            continue;
+        }
+
+        stack.push(root);
+        while let Some(current) = stack.pop() {
+            if visited.contains(&current) {
+                continue;
+            }
+
+            if visited.len() % std::cmp::max(full_graph.len() / 10, 1) == 0 {
+                info!("blocked {} nodes into {} blocks", visited.len(), i + 1);
+            }
+
+            visited.insert(current);
+            if !deleted.contains(&current) {
+                if blocks[i].len() >= BLOCK_SIZE {
+                    blocks.push(Vec::new());
+                    i += 1;
+                }
+
+                blocks[i].push(current);
+            }
+
+            let mut neighbors = full_graph.neighbors(current).unwrap().into_owned();
+            neighbors.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+
+            for (neighbor, _) in neighbors {
+                if !visited.contains(&neighbor) {
+                    stack.push(neighbor);
+                }
+            }
+        }
+    }
+
+    let mut cache = EmbeddingCache::new(10 * BLOCK_SIZE as u32)?;
+
+    // update meta
+    let ledger = crate::ledger::read_ledger()?;
+    let mut ledger_map = std::collections::HashMap::new();
+    for entry in ledger.iter() {
+        ledger_map.insert(entry.filepath.clone(), entry.meta.clone());
+    }
+
+    // create a temp directory in $DATA_DIR to hold all the blocks
+    let data_dir = get_data_dir();
+    let temp_dir = data_dir.join("temp");
+
+    if std::fs::metadata(&temp_dir).is_ok() {
+        std::fs::remove_dir_all(&temp_dir)?;
+    }
+
+    std::fs::create_dir(&temp_dir)?;
+
+    let mut directory = Vec::new();
+    for (i, block) in blocks.iter().enumerate() {
+        let filename = temp_dir.join(i.to_string());
+        let mut embeddings = Vec::new();
+        for id in block {
+            let mut embedding = cache.get(*id as u32).unwrap();
+            embedding.source_file.meta = match ledger_map.get(&embedding.source_file.filepath) {
+                Some(meta) => {
+                    let mut meta = meta.clone();
+                    meta.extend(
+                        embedding
+                            .source_file
+                            .meta
+                            .iter()
+                            .filter(|tag| is_chunk_meta(tag))
+                            .cloned(),
+                    );
+
+                    meta
+                }
+                None => {
+                    error!(
+                        "File {} unaccounted for in ledger! Ignoring meta",
+                        embedding.source_file.filepath
+                    );
+                    embedding.source_file.meta
+                }
+            };
+
+            directory.push((DirectoryEntry::of(&embedding), i as u32));
+
+            embeddings.push(*embedding);
+        }
+
+        let mut embedding_block = EmbeddingBlock::new(i as u64, model.clone(), embeddings);
+        embedding_block.write_to(&filename)?;
+    }
+
+    params.insert("embeddings".to_string(), directory.len().into());
+    params.insert("blocks".to_string(), blocks.len().into());
+
+    for entry in std::fs::read_dir(data_dir.clone())? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.is_file() {
+            if let Some(filename) = path.file_name() {
+                if let Some(filename) = filename.to_str() {
+                    if filename.parse::<u64>().is_ok() {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+            }
+        }
+    }
+
+    std::fs::remove_file(data_dir.join("directory"))?;
+
+    for entry in std::fs::read_dir(temp_dir.clone())? {
+        let entry = entry?;
+        let path = entry.path();
         if path.is_file() {
             if let Some(filename) = path.file_name() {
                 if let Some(filename) = filename.to_str() {
                     if filename.parse::<u64>().is_ok() {
-                        std::fs::rename(
-                            path.clone(),
-                            format!("{}/{}", data_dir.to_str().unwrap(), filename),
-                        )?;
+                        std::fs::rename(path.clone(), data_dir.join(filename))?;
                     }
                 }
             }
@@ -301,6 +1345,19 @@ pub fn reblock() -> Result<(), std::io::Error> {
         }
     };
 
+    // the ledger's meta was just brought in, and the meta index goes with it
+    write_meta_index()?;
+
+    // the deleted nodes are gone from the blocks, and now from the index too
+    if !deleted.is_empty() {
+        for &id in deleted.iter() {
+            index.remove_node(id)?;
+        }
+
+        index.serialize(&data_dir.join("index"))?;
+        prune_tombstones(&index)?;
+    }
+
     Ok(())
 }
 
@@ -309,9 +1366,16 @@ pub fn reblock() -> Result<(), std::io::Error> {
 pub fn read_embedding_blocks(
     filenames: &Vec<String>,
 ) -> Result<Vec<Box<Embedding>>, std::io::Error> {
+    let metric = Metric::current();
+    let normalized = is_store_normalized();
     let mut embeddings = Vec::new();
     for filename in filenames {
-        let block_number = match filename.split("/").last().unwrap().parse::<u64>() {
+        let block_number = match std::path::Path::new(filename)
+            .file_name()
+            .and_then(|f| f.to_str())
+            .unwrap_or_default()
+            .parse::<u64>()
+        {
             Ok(block_number) => block_number,
             Err(e) => {
                 eprintln!(
@@ -331,7 +1395,7 @@ pub fn read_embedding_blocks(
                 .embeddings
                 .into_iter()
                 .map(|mut embedding| {
-                    normalize(&mut embedding);
+                    prepare_stored(metric, normalized, &mut embedding);
                     Box::new(embedding)
                 })
                 .collect::<Vec<_>>(),
@@ -344,7 +1408,7 @@ pub fn read_embedding_blocks(
 pub fn read_embedding_block(block_number: u64) -> Result<EmbeddingBlock, std::io::Error> {
     let data_dir = get_data_dir();
 
-    let bytes = match std::fs::read(&format!("{}/{}", data_dir.to_str().unwrap(), block_number)) {
+    let bytes = match std::fs::read(data_dir.join(block_number.to_string())) {
         Ok(b) => b,
         Err(e) => {
             error!("error reading block file {}: {}", block_number, e);
@@ -363,17 +1427,606 @@ pub fn read_embedding_block(block_number: u64) -> Result<EmbeddingBlock, std::io
     Ok(block)
 }
 
-pub struct BlockEmbedding {
-    pub block_number: u64,
-    pub embedding: Box<Embedding>,
-    pub source_file: String,
+// an embedding without its data
+pub struct EmbeddingHeader {
+    pub id: u64,
+    pub source_file: EmbeddingSource,
+    pub embedded_at: i64,
+    // serialized size of the whole embedding, data included
+    pub bytes: usize,
 }
 
-// returns boxes of the embeddings and the block files from which they were read
+// parses `parse` from the file at `offset`, reading only as much of the file as it takes
+//
+// a parse that runs out of bytes is retried with a bigger read, until the file runs out too
+fn parse_at<T>(
+    file: &mut std::fs::File,
+    offset: u64,
+    parse: impl Fn(&[u8]) -> Result<T, std::io::Error>,
+) -> Result<T, std::io::Error> {
+    use std::io::{Read, Seek};
+
+    let mut window = 1024;
+    loop {
+        file.seek(std::io::SeekFrom::Start(offset))?;
+        let mut bytes = Vec::new();
+        (&mut *file).take(window).read_to_end(&mut bytes)?;
+
+        match parse(&bytes) {
+            Err(e)
+                if e.kind() == std::io::ErrorKind::UnexpectedEof
+                    && bytes.len() as u64 == window =>
+            {
+                window *= 4
+            }
+            result => return result,
+        }
+    }
+}
+
+// reads a block's embeddings while seeking over their data,
+// which is the bulk of every block
+//
+// this relies on `Embedding` serializing its fields in order: `id`, `source_file`, `embedded_at`, `data`
+pub fn read_embedding_block_headers(
+    block_number: u64,
+) -> Result<Vec<EmbeddingHeader>, std::io::Error> {
+    let mut file = std::fs::File::open(get_data_dir().join(block_number.to_string()))?;
+    let length = file.metadata()?.len();
+    let invalid = |e: std::io::Error| {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("block file {} is truncated", block_number),
+            )
+        } else {
+            e
+        }
+    };
+
+    let (version, count, mut cursor) = parse_at(&mut file, 0, |bytes| {
+        let (version, mut cursor) = parse_block_version(bytes, 0)?;
+        let (_, size) = u64::from_bytes(bytes, cursor)?;
+        cursor += size;
+        let (_, size) = EmbeddingModel::from_bytes(bytes, cursor)?;
+        cursor += size;
+        let (_, size) = FileFilter::from_bytes(bytes, cursor)?;
+        cursor += size;
+        let (count, size) = u32::from_bytes(bytes, cursor)?;
+
+        Ok((version, count, (cursor + size) as u64))
+    })
+    .map_err(invalid)?;
+
+    let paths = PathResolver::current();
+    let data_size = (crate::embedding::EMBED_DIM * std::mem::size_of::<f32>()) as u64;
+    let mut headers = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let (id, mut source_file, embedded_at, size) = parse_at(&mut file, cursor, |bytes| {
+            let (id, mut size) = u64::from_bytes(bytes, 0)?;
+            let (source_file, count) = EmbeddingSource::from_bytes(bytes, size)?;
+            size += count;
+            let embedded_at = match version {
+                0 => 0,
+                _ => {
+                    let (embedded_at, count) = i64::from_bytes(bytes, size)?;
+                    size += count;
+                    embedded_at
+                }
+            };
+
+            Ok((id, source_file, embedded_at, size as u64 + data_size))
+        })
+        .map_err(invalid)?;
+
+        cursor += size;
+        if cursor > length {
+            return Err(invalid(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        source_file.filepath = paths.resolve(&source_file.filepath);
+        headers.push(EmbeddingHeader {
+            id,
+            source_file,
+            embedded_at,
+            bytes: size as usize,
+        });
+    }
+
+    Ok(headers)
+}
+
+// reads the model a block was embedded with from the start of its file
+pub fn read_block_model(block_number: u64) -> Result<EmbeddingModel, std::io::Error> {
+    use std::io::Read;
+
+    // the model is the only variable-length part of the header,
+    // and no model name comes anywhere near this
+    const HEADER_LIMIT: u64 = 1024;
+
+    let file = std::fs::File::open(get_data_dir().join(block_number.to_string()))?;
+    let mut bytes = Vec::new();
+    file.take(HEADER_LIMIT).read_to_end(&mut bytes)?;
+
+    if bytes.len() < 8 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("block file {} is truncated", block_number),
+        ));
+    }
+
+    let (_, size) = parse_block_version(&bytes, 0)?;
+    let (model, _) = EmbeddingModel::from_bytes(&bytes, size + 8)?;
+
+    Ok(model)
+}
+
+// reads the file filter that follows the model in a block's header
+pub fn read_block_filter(block_number: u64) -> Result<FileFilter, std::io::Error> {
+    use std::io::Read;
+
+    // room for the version, the block number, any model, and the filter with its length
+    const HEADER_LIMIT: u64 = 12 + 1024 + 4 + FILTER_BITS as u64 / 8;
+
+    let file = std::fs::File::open(get_data_dir().join(block_number.to_string()))?;
+    let mut bytes = Vec::new();
+    file.take(HEADER_LIMIT).read_to_end(&mut bytes)?;
+
+    if bytes.len() < 8 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("block file {} is truncated", block_number),
+        ));
+    }
+
+    let (_, start) = parse_block_version(&bytes, 0)?;
+    let (_, size) = EmbeddingModel::from_bytes(&bytes, start + 8)?;
+    let (filter, _) = FileFilter::from_bytes(&bytes, start + 8 + size)?;
+
+    Ok(filter)
+}
+
+// the blocks whose filters say they might hold embeddings of `filepath`,
+// some of which may turn out not to
+pub fn blocks_with_file(filepath: &str) -> Result<Vec<u64>, std::io::Error> {
+    let stored = PathResolver::current().store(filepath);
+    let mut blocks = Vec::new();
+    for block_number in get_block_numbers()? {
+        if read_block_filter(block_number)?.may_contain(&stored) {
+            blocks.push(block_number);
+        }
+    }
+
+    Ok(blocks)
+}
+
+// the model every block was embedded with, or `None` if nothing has been embedded
+//
+// blocks disagreeing on the model is an error, since their embeddings can't be compared
+pub fn get_blocks_model() -> Result<Option<EmbeddingModel>, std::io::Error> {
+    let mut blocks_model: Option<EmbeddingModel> = None;
+    for block_number in get_block_numbers()? {
+        let model = read_block_model(block_number)?;
+        match &blocks_model {
+            Some(m) if *m != model => {
+                error!(
+                    "block {} was embedded with {}, but earlier blocks were embedded with {}",
+                    block_number, model, m
+                );
+
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "blocks were embedded with mixed models ({} and {})",
+                        m.name, model.name
+                    ),
+                ));
+            }
+            Some(_) => {}
+            None => blocks_model = Some(model),
+        }
+    }
+
+    Ok(blocks_model)
+}
+
+pub(crate) fn get_block_numbers() -> Result<Vec<u64>, std::io::Error> {
+    let mut block_numbers = Vec::new();
+    for entry in std::fs::read_dir(get_data_dir())? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+
+        if let Some(block_number) = path
+            .file_name()
+            .and_then(|f| f.to_str())
+            .and_then(|f| f.parse::<u64>().ok())
+        {
+            block_numbers.push(block_number);
+        }
+    }
+
+    block_numbers.sort();
+
+    Ok(block_numbers)
+}
+
+// per-block usage of the embedding store, sorted by block number
+pub fn block_report() -> Result<Vec<BlockReport>, std::io::Error> {
+    let block_numbers = get_block_numbers()?;
+    if block_numbers.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let directory = get_directory()?;
+
+    let mut reports = Vec::new();
+    for block_number in block_numbers {
+        let headers = read_embedding_block_headers(block_number)?;
+        let unreachable = headers
+            .iter()
+            .filter(|h| directory.id_map.get(&(h.id as u32)) != Some(&block_number))
+            .collect::<Vec<_>>();
+
+        reports.push(BlockReport {
+            block_number,
+            embeddings: headers.len(),
+            bytes: std::fs::metadata(get_data_dir().join(block_number.to_string()))?.len(),
+            fill_factor: headers.len() as f32 / BLOCK_SIZE as f32,
+            files: headers
+                .iter()
+                .map(|h| &h.source_file.filepath)
+                .collect::<HashSet<_>>()
+                .len(),
+            unreachable: unreachable.len(),
+            unreachable_bytes: unreachable.iter().map(|h| h.bytes as u64).sum(),
+        });
+    }
+
+    Ok(reports)
+}
+
+// the embeddings the store in scope has of `filepath`, `None` if it has none
+//
+// only the headers of the file's blocks are read
+pub fn file_info(filepath: &str) -> Result<Option<FileInfo>, std::io::Error> {
+    let directory = get_directory()?;
+    let blocks = match directory.file_map.get(filepath) {
+        Some(blocks) => blocks.clone(),
+        None => return Ok(None),
+    };
+
+    let mut info = FileInfo {
+        chunk_count: 0,
+        blocks: blocks.clone(),
+        ids: Vec::new(),
+        subsets: Vec::new(),
+        stable_ids: Vec::new(),
+        meta: HashSet::new(),
+        last_embedded_hash: String::new(),
+        embedded_at: 0,
+    };
+
+    let paths = PathResolver::current();
+    for block_number in blocks {
+        for header in read_embedding_block_headers(block_number)? {
+            // embeddings the directory points elsewhere are leftovers of an older embedding
+            if header.source_file.filepath != filepath
+                || directory.id_map.get(&(header.id as u32)) != Some(&block_number)
+            {
+                continue;
+            }
+
+            info.ids.push(header.id);
+            info.subsets.push(header.source_file.subset);
+            info.stable_ids.push(header.source_file.stable_id(&paths));
+            info.embedded_at = info.embedded_at.max(header.embedded_at);
+            if !is_field_source(&header.source_file) {
+                info.meta.extend(header.source_file.meta);
+            }
+
+            if !header.source_file.hash.is_empty() {
+                info.last_embedded_hash = header.source_file.hash;
+            }
+        }
+    }
+
+    info.chunk_count = info.ids.len();
+
+    Ok(Some(info))
+}
+
+// a file the store in scope has embeddings of
+#[derive(Debug, serde::Serialize)]
+pub struct FileListing {
+    pub filepath: String,
+    pub chunk_count: usize,
+    // unix seconds of the file's newest embedding, 0 if its blocks don't say
+    pub embedded_at: i64,
+}
+
+// every file the store in scope has embeddings of, sorted by path
+//
+// like `file_info`, only the block headers are read
+pub fn list_files() -> Result<Vec<FileListing>, std::io::Error> {
+    let block_numbers = get_block_numbers()?;
+    if block_numbers.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let directory = get_directory()?;
+
+    let mut files = HashMap::<String, FileListing>::new();
+    for block_number in block_numbers {
+        for header in read_embedding_block_headers(block_number)? {
+            if directory.id_map.get(&(header.id as u32)) != Some(&block_number) {
+                continue;
+            }
+
+            let listing = files
+                .entry(header.source_file.filepath.clone())
+                .or_insert_with(|| FileListing {
+                    filepath: header.source_file.filepath,
+                    chunk_count: 0,
+                    embedded_at: 0,
+                });
+
+            listing.chunk_count += 1;
+            listing.embedded_at = listing.embedded_at.max(header.embedded_at);
+        }
+    }
+
+    let mut files = files.into_values().collect::<Vec<_>>();
+    files.sort_by(|a, b| a.filepath.cmp(&b.filepath));
+
+    Ok(files)
+}
+
+// the embeddings of the files a query is limited to
+#[derive(Debug, Default)]
+pub struct PathScope {
+    // sorted
+    pub ids: Vec<u64>,
+    pub files: HashSet<String>,
+    // the paths or globs that matched none of the store's files
+    pub unknown: Vec<String>,
+}
+
+// resolves file paths or globs against the files the store in scope has embeddings of,
+// the same way `--only` matches them against the ledger
+pub fn resolve_paths(paths: &[String]) -> Result<PathScope, std::io::Error> {
+    let entries = match read_directory_entries() {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut scope = PathScope::default();
+    for path in paths.iter() {
+        let filter = crate::ledger::PathFilter::new(std::slice::from_ref(path))?;
+
+        let mut matched = false;
+        for (id, filepath, _) in entries.iter().filter(|e| filter.matches(&e.1)) {
+            matched = true;
+            scope.ids.push(*id as u64);
+            scope.files.insert(filepath.clone());
+        }
+
+        if !matched {
+            scope.unknown.push(path.clone());
+        }
+    }
+
+    scope.ids.sort();
+    scope.ids.dedup();
+
+    Ok(scope)
+}
+
+// the ids of the store in scope carrying each meta tag, from the block headers
+pub fn read_meta_ids() -> Result<crate::hnsw::MetaIds, std::io::Error> {
+    let block_numbers = get_block_numbers()?;
+    if block_numbers.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let directory = get_directory()?;
+
+    let mut meta_ids = HashMap::new();
+    for block_number in block_numbers {
+        for header in read_embedding_block_headers(block_number)? {
+            if directory.id_map.get(&(header.id as u32)) == Some(&block_number) {
+                crate::hnsw::add_meta(&mut meta_ids, header.id, &header.source_file.meta);
+            }
+        }
+    }
+
+    Ok(meta_ids)
+}
+
+// the ids carrying each meta tag, sorted, in $DATA_DIR/meta_index,
+// for a query's filters to narrow down its candidates before the graph is searched
+//
+// `sync_index` and `reblock` write it, and it's passed over once anything else changes the store,
+// since it only holds for the state generation it was written in
+#[derive(Serialize)]
+pub struct MetaIndex {
+    generation: u64,
+    // every id in the store, for `ne` filters to take away from
+    ids: Vec<u64>,
+    tags: HashMap<String, Vec<u64>>,
+}
+
+const META_INDEX_FILE: &str = "meta_index";
+
+impl MetaIndex {
+    fn new(generation: u64, ids: Vec<u64>, tags: crate::hnsw::MetaIds) -> Self {
+        let mut ids = ids;
+        ids.sort();
+
+        let tags = tags
+            .into_iter()
+            .map(|(tag, ids)| {
+                let mut ids = ids.into_iter().collect::<Vec<_>>();
+                ids.sort();
+                (tag, ids)
+            })
+            .collect();
+
+        Self {
+            generation,
+            ids,
+            tags,
+        }
+    }
+
+    // every id in the store, sorted
+    pub fn ids(&self) -> &[u64] {
+        &self.ids
+    }
+
+    // the ids that pass every one of `filters`, sorted,
+    // or `None` if any of them is on a tag nothing in the store has
+    pub fn select(&self, filters: &[Filter]) -> Option<Vec<u64>> {
+        if filters.iter().any(|f| !self.tags.contains_key(&f.value)) {
+            return None;
+        }
+
+        let (eq, ne): (Vec<_>, Vec<_>) = filters
+            .iter()
+            .partition(|f| f.comparator == FilterComparator::Equal);
+
+        let mut selected = match eq.split_first() {
+            Some((first, rest)) => rest.iter().fold(self.tags[&first.value].clone(), |ids, f| {
+                sorted_intersection(&ids, &self.tags[&f.value])
+            }),
+            None => self.ids.clone(),
+        };
+
+        for f in ne {
+            selected = sorted_difference(&selected, &self.tags[&f.value]);
+        }
+
+        Some(selected)
+    }
+}
+
+pub fn sorted_intersection(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut result = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                result.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+
+    result
+}
+
+pub fn sorted_difference(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut result = Vec::new();
+    let mut j = 0;
+    for &id in a {
+        while j < b.len() && b[j] < id {
+            j += 1;
+        }
+
+        if j >= b.len() || b[j] != id {
+            result.push(id);
+        }
+    }
+
+    result
+}
+
+// rebuilds the meta index of the store in scope from its block headers
+pub fn write_meta_index() -> Result<(), std::io::Error> {
+    let ids = get_directory()?
+        .id_map
+        .keys()
+        .map(|&id| id as u64)
+        .collect();
+    let meta_index = MetaIndex::new(read_state_generation()?, ids, read_meta_ids()?);
+    info!(
+        "writing meta index of {} ids and {} tags",
+        meta_index.ids.len(),
+        meta_index.tags.len()
+    );
+
+    write_atomic(
+        &get_data_dir().join(META_INDEX_FILE),
+        &meta_index.to_bytes(),
+    )
+}
+
+// the meta index of the store in scope,
+// `None` if there isn't one or the store has changed since it was written
+pub fn read_meta_index() -> Result<Option<MetaIndex>, std::io::Error> {
+    let bytes = match std::fs::read(get_data_dir().join(META_INDEX_FILE)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let (meta_index, _) = MetaIndex::from_bytes(&bytes, 0)?;
+    let generation = read_state_generation()?;
+    if meta_index.generation != generation {
+        info!(
+            "meta index is from generation {}, at {}; passing over it",
+            meta_index.generation, generation
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(meta_index))
+}
+
+// a suggestion to reblock if the store uses more blocks than it needs
+// or holds embeddings that can't be reached
+pub fn compaction_recommendation(reports: &[BlockReport]) -> Option<String> {
+    let live = reports
+        .iter()
+        .map(|r| r.embeddings - r.unreachable)
+        .sum::<usize>();
+    let needed = live.div_ceil(BLOCK_SIZE);
+    let reclaimable = reports.iter().map(|r| r.unreachable_bytes).sum::<u64>();
+
+    if reports.len() <= needed && reclaimable == 0 {
+        return None;
+    }
+
+    Some(format!(
+        "run --reblock to pack {} embeddings into {} blocks (currently {}) and reclaim ~{:.1} MB",
+        live,
+        needed,
+        reports.len(),
+        reclaimable as f64 / (1024.0 * 1024.0)
+    ))
+}
+
+pub struct BlockEmbedding {
+    pub block_number: u64,
+    pub embedding: Box<Embedding>,
+    pub source_file: String,
+}
+
+// returns boxes of the embeddings and the block files from which they were read,
+// in block order
 pub fn get_all_blocks() -> Result<Vec<BlockEmbedding>, std::io::Error> {
-    let data_dir = get_data_dir();
+    read_all_blocks(crate::config::get_block_read_workers())
+}
+
+// the numbers of the block files in the data directory, in order
+pub(crate) fn block_numbers() -> Result<Vec<u64>, std::io::Error> {
     let mut block_numbers = Vec::new();
-    for entry in std::fs::read_dir(data_dir.clone())? {
+    for entry in std::fs::read_dir(get_data_dir())? {
         let entry = entry?;
         let path = entry.path();
         if path.is_file() {
@@ -387,123 +2040,2253 @@ pub fn get_all_blocks() -> Result<Vec<BlockEmbedding>, std::io::Error> {
         }
     }
 
-    let mut block_embeddings = Vec::new();
-    for block_number in block_numbers {
-        let filename = format!("{}/{}", data_dir.to_str().unwrap(), block_number);
+    block_numbers.sort();
+
+    Ok(block_numbers)
+}
+
+// blocks are read and deserialized by `workers` threads at once
+fn read_all_blocks(workers: usize) -> Result<Vec<BlockEmbedding>, std::io::Error> {
+    let data_dir = get_data_dir();
+    let block_numbers = block_numbers()?;
+    let metric = Metric::current();
+    let normalized = is_store_normalized();
+
+    let read_block = |block_number: u64| -> Result<Vec<BlockEmbedding>, std::io::Error> {
+        let filename = data_dir
+            .join(block_number.to_string())
+            .to_string_lossy()
+            .to_string();
         let block = read_embedding_block(block_number)?;
 
-        for be in block
+        Ok(block
             .embeddings
             .into_iter()
             .map(|mut embedding| {
-                normalize(&mut embedding);
-                Box::new(embedding)
+                prepare_stored(metric, normalized, &mut embedding);
+                BlockEmbedding {
+                    block_number,
+                    embedding: Box::new(embedding),
+                    source_file: filename.clone(),
+                }
+            })
+            .collect())
+    };
+
+    // each worker takes the next unread block until there are none left,
+    // and the blocks are put back in order once they're all in
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let paths = crate::config::get_paths();
+    let mut blocks = std::thread::scope(|scope| {
+        let threads = (0..workers.clamp(1, block_numbers.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    paths.scope(|| {
+                        let mut read = Vec::new();
+                        loop {
+                            let i = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            match block_numbers.get(i) {
+                                Some(&block_number) => read.push((i, read_block(block_number))),
+                                None => break read,
+                            }
+                        }
+                    })
+                })
             })
+            .collect::<Vec<_>>();
+
+        threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
             .collect::<Vec<_>>()
-        {
-            block_embeddings.push(BlockEmbedding {
-                block_number,
-                embedding: be,
-                source_file: filename.clone(),
-            });
-        }
+    });
+
+    blocks.sort_by_key(|(i, _)| *i);
+
+    let mut block_embeddings = Vec::new();
+    for (_, block) in blocks {
+        block_embeddings.extend(block?);
     }
 
     Ok(block_embeddings)
 }
 
-// TODO: at what point should we worry about holding this whole thing in memory?
-pub fn get_directory() -> Result<Directory, std::io::Error> {
-    let data_dir = get_data_dir();
-    let directory = std::fs::read_to_string(format!("{}/directory", data_dir.to_str().unwrap()))?;
-    let directory = directory
-        .split("\n")
-        .map(|d| {
-            let parts = d.split(" ").collect::<Vec<&str>>();
-            let id = parts[0].parse::<u32>().unwrap();
-            let filepath = parts[1..parts.len() - 1].join("");
-            let block = parts[parts.len() - 1].parse::<u64>().unwrap();
-
-            (id, filepath, block)
-        })
-        .collect::<Vec<_>>();
+#[derive(serde::Serialize)]
+struct DumpLine<'a> {
+    id: u64,
+    filepath: &'a str,
+    subset: Option<(u64, u64)>,
+    meta: Vec<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vector: Option<&'a [f32]>,
+}
 
-    let mut id_map = HashMap::new();
-    let mut file_map = HashMap::new();
-    let mut file_id_map = HashMap::new();
+// writes every embedding passing `filters` to `writer` as a line of JSON,
+// a block at a time so that the whole store is never in memory at once
+//
+// vectors are written as stored, cut down to their first `vector_dims` values
+// if there's a limit, and left out entirely when the limit is 0
+//
+// returns the number of embeddings written
+pub fn dump_jsonl(
+    writer: &mut impl Write,
+    filters: &[crate::hnsw::Filter],
+    vector_dims: Option<usize>,
+) -> Result<usize, std::io::Error> {
+    let mut written = 0;
+    for block_number in get_block_numbers()? {
+        let block = read_embedding_block(block_number)?;
+        for embedding in block.embeddings.iter() {
+            let source = &embedding.source_file;
+            if !filters.iter().all(|f| f.matches(&source.meta)) {
+                continue;
+            }
 
-    for entry in directory.iter() {
-        id_map.insert(entry.0, entry.2);
-        file_map.insert(entry.1.clone(), entry.2);
-        file_id_map.insert(entry.1.clone(), entry.0);
-    }
+            let mut meta = source.meta.iter().collect::<Vec<_>>();
+            meta.sort();
 
-    Ok(Directory {
-        id_map,
-        file_map,
-        file_id_map,
-    })
-}
+            let vector = match vector_dims {
+                Some(0) => None,
+                Some(dims) => Some(&embedding.data[..dims.min(embedding.data.len())]),
+                None => Some(&embedding.data[..]),
+            };
 
-// TODO: how does this affect indexing?
-//       i think things need reindexed + reblocked after updates here
-pub fn update_file_embeddings(filepath: &str, index: &mut HNSW) -> Result<(), std::io::Error> {
-    let directory = match get_directory() {
-        Ok(d) => d,
-        Err(e) => {
-            error!("error reading directory: {}", e);
-            return Err(e);
+            let line = DumpLine {
+                id: embedding.id,
+                filepath: &source.filepath,
+                subset: source.subset,
+                meta,
+                vector,
+            };
+
+            serde_json::to_writer(&mut *writer, &line)?;
+            writer.write_all(b"\n")?;
+            written += 1;
         }
-    };
+    }
 
-    let id_start = directory.id_map.len() as u64 + 1;
+    writer.flush()?;
+    info!("dumped {} embeddings", written);
 
-    let target_block = match directory.file_map.get(filepath) {
-        Some(b) => b,
-        None => {
-            error!(
-                "filepath {} not catalogued in Directory, aborting update",
-                filepath
-            );
-            return Ok(());
-        }
-    };
+    Ok(written)
+}
 
-    let mut block = read_embedding_block(*target_block)?;
+#[derive(serde::Deserialize)]
+struct ImportLine {
+    filepath: String,
+    #[serde(default)]
+    subset: Option<(u64, u64)>,
+    #[serde(default)]
+    meta: Vec<String>,
+    vector: Option<Vec<f32>>,
+}
 
-    let mut meta = HashSet::new();
-    let mut to_delete = Vec::new();
-    for e in block.embeddings.iter() {
-        if e.source_file.filepath == filepath {
-            meta = e.source_file.meta.clone();
-            to_delete.push(e.id);
-        }
+// a line of `dump_jsonl` output, minus its id
+fn parse_import_line(line: &str) -> Result<Embedding, String> {
+    let line = serde_json::from_str::<ImportLine>(line).map_err(|e| e.to_string())?;
+    let vector = line.vector.ok_or("missing vector")?;
+    let data = <[f32; crate::embedding::EMBED_DIM]>::try_from(vector.as_slice()).map_err(|_| {
+        format!(
+            "vector has {} values, expected {}",
+            vector.len(),
+            crate::embedding::EMBED_DIM
+        )
+    })?;
+
+    if line.filepath.is_empty() {
+        return Err("empty filepath".to_string());
     }
 
-    block
-        .embeddings
-        .retain(|e| e.source_file.filepath != filepath);
+    Ok(Embedding {
+        id: 0,
+        source_file: EmbeddingSource {
+            filepath: line.filepath,
+            meta: line.meta.into_iter().collect(),
+            subset: line.subset,
+            hash: String::new(),
+            chunk_hash: None,
+        },
+        embedded_at: 0,
+        data,
+    })
+}
 
-    let mut new_embeddings = embed_bulk(&vec![EmbeddingSource {
-        filepath: filepath.to_string(),
-        meta,
-        subset: None,
-    }])?;
+// loads embeddings made elsewhere (with the configured model) from a file of `dump_jsonl` lines
+//
+// imported embeddings replace every embedding the store already has for their files,
+// are given ids after the highest one in the directory,
+// and the files get ledger entries hashed as they are now so that they aren't stale.
+// files that don't exist here are imported but left out of the ledger
+//
+// malformed lines are reported with their line numbers,
+// and are skipped if `lenient` and otherwise fail the import before anything is written
+//
+// with an `index`, the replaced embeddings are taken out of it and the imported ones put in,
+// though writing it back out is left to the caller
+//
+// returns the number of embeddings imported
+pub fn import_jsonl(
+    path: &std::path::Path,
+    lenient: bool,
+    snapshot: bool,
+    index: Option<&mut HNSW>,
+) -> Result<usize, std::io::Error> {
+    journal::record("import", |params| {
+        params.insert("path".to_string(), path.to_string_lossy().into());
+        params.insert("lenient".to_string(), lenient.into());
+        let imported = run_import_jsonl(path, lenient, snapshot, index, params)?;
+        params.insert("embeddings".to_string(), imported.into());
 
-    for (i, e) in new_embeddings.iter_mut().enumerate() {
-        e.id = id_start + i as u64;
-    }
+        Ok(imported)
+    })
+}
 
-    block.embeddings.extend(new_embeddings);
+fn run_import_jsonl(
+    path: &std::path::Path,
+    lenient: bool,
+    snapshot: bool,
+    index: Option<&mut HNSW>,
+    params: &mut Params,
+) -> Result<usize, std::io::Error> {
+    let _lock = DataLock::acquire(LockMode::Exclusive, "import_jsonl")?;
+    bump_state_generation()?;
 
-    let block_path = format!("{}/{}", get_data_dir().to_str().unwrap(), target_block);
-    block.to_file(&block_path)?;
+    let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut imported = Vec::new();
+    let mut malformed = 0;
+    for (i, line) in std::io::BufRead::lines(reader).enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
 
-    for node in to_delete {
-        index.remove_node(node);
+        match parse_import_line(&line) {
+            Ok(embedding) => imported.push(embedding),
+            Err(e) => {
+                lprint!(error, "{}:{}: {}", path.display(), i + 1, e);
+                malformed += 1;
+            }
+        }
     }
 
-    index.serialize(&get_data_dir().join("index").to_str().unwrap().to_string())?;
+    if malformed > 0 && !lenient {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "{} malformed lines in {}, nothing was imported (rerun with --lenient to skip them)",
+                malformed,
+                path.display()
+            ),
+        ));
+    }
+
+    if malformed > 0 {
+        lprint!(info, "Skipped {} malformed lines", malformed);
+    }
+
+    params.insert("malformed".to_string(), malformed.into());
+
+    if imported.is_empty() {
+        lprint!(info, "Nothing to import from {}", path.display());
+        return Ok(0);
+    }
+
+    if snapshot {
+        auto_snapshot("import")?;
+    }
+
+    let files = imported
+        .iter()
+        .map(|e| e.source_file.filepath.clone())
+        .collect::<HashSet<_>>();
+    let count = imported.len();
+    let replaced = replace_files(&files, imported, index)?;
+    params.insert("files".to_string(), files.len().into());
+    params.insert("replaced".to_string(), replaced.into());
+
+    lprint!(
+        info,
+        "Imported {} embeddings for {} files, replacing {}",
+        count,
+        files.len(),
+        replaced
+    );
+
+    Ok(count)
+}
+
+// takes a file's embeddings out of the store and the file out of the ledger
+//
+// returns the number of embeddings removed
+pub fn delete_file(filepath: &str, index: Option<&mut HNSW>) -> Result<usize, std::io::Error> {
+    journal::record("delete_file", |params| {
+        params.insert("filepath".to_string(), filepath.into());
+        let removed = run_delete_file(filepath, index)?;
+        params.insert("embeddings".to_string(), removed.into());
+
+        Ok(removed)
+    })
+}
+
+fn run_delete_file(filepath: &str, index: Option<&mut HNSW>) -> Result<usize, std::io::Error> {
+    let _lock = DataLock::acquire(LockMode::Exclusive, "delete_file")?;
+    bump_state_generation()?;
+
+    // a file that no block's filter has is only in the ledger, if anywhere,
+    // and every block doesn't need rewritten to find that out
+    if blocks_with_file(filepath)?.is_empty() {
+        crate::ledger::update_entries(&HashSet::from([filepath.to_string()]), &[])?;
+        lprint!(info, "{} has no embeddings to delete", filepath);
+        return Ok(0);
+    }
+
+    let removed = replace_files(&HashSet::from([filepath.to_string()]), Vec::new(), index)?;
+    lprint!(info, "Deleted {} embeddings of {}", removed, filepath);
+
+    Ok(removed)
+}
+
+// swaps every stored embedding of `files` for `embeddings`,
+// which get ids after the highest one in the directory
+//
+// files with new embeddings get ledger entries hashed as they are now so that they aren't stale,
+// unless they don't exist here, and the rest of `files` are dropped from the ledger
+//
+// the caller holds the data directory lock
+//
+// returns the number of embeddings replaced
+pub(crate) fn replace_files(
+    files: &HashSet<String>,
+    mut embeddings: Vec<Embedding>,
+    index: Option<&mut HNSW>,
+) -> Result<usize, std::io::Error> {
+    let current = EmbeddingModel::current();
+    if let Some(model) = get_blocks_model()? {
+        if model != current && !embeddings.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "existing embeddings were made with {}, but the configured model is {}",
+                    model, current
+                ),
+            ));
+        }
+    }
+
+    let id_start = match read_directory_entries() {
+        Ok(entries) => next_embedding_id(entries.iter().map(|e| e.0))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+
+    let metric = Metric::current();
+    for (i, e) in embeddings.iter_mut().enumerate() {
+        e.id = id_start + i as u64;
+        metric.prepare(e);
+    }
+
+    let mut replaced = Vec::new();
+    let mut kept = Vec::new();
+    for be in get_all_blocks()? {
+        match files.contains(&be.embedding.source_file.filepath) {
+            true => replaced.push(be.embedding.id),
+            false => kept.push(*be.embedding),
+        }
+    }
+
+    // the ledger keeps a file's meta without the tags that belong to single chunks
+    let mut ledger_entries = HashMap::new();
+    for e in embeddings.iter() {
+        let filepath = &e.source_file.filepath;
+        if !std::path::Path::new(filepath).is_file() {
+            continue;
+        }
+
+        let entry =
+            ledger_entries
+                .entry(filepath.clone())
+                .or_insert_with(|| crate::ledger::LedgerEntry {
+                    filepath: filepath.clone(),
+                    hash: String::new(),
+                    meta: HashSet::new(),
+                });
+        entry.meta.extend(
+            e.source_file
+                .meta
+                .iter()
+                .filter(|m| !is_chunk_meta(m))
+                .cloned(),
+        );
+    }
+
+    let embedded = embeddings
+        .iter()
+        .map(|e| e.source_file.filepath.clone())
+        .collect::<HashSet<_>>();
+    let new_ids = embeddings.iter().map(|e| e.id).collect::<Vec<_>>();
+
+    kept.extend(embeddings);
+    write_blocks(&kept)?;
+    add_tombstones(replaced.iter().copied())?;
+
+    let mut ledger_entries = ledger_entries.into_values().collect::<Vec<_>>();
+    for entry in ledger_entries.iter_mut() {
+        entry.hash = crate::ledger::get_hash(&entry.filepath)?;
+    }
+
+    if ledger_entries.len() < embedded.len() {
+        lprint!(
+            error,
+            "warning: {} of the files don't exist here and were left out of the ledger",
+            embedded.len() - ledger_entries.len()
+        );
+    }
+
+    crate::ledger::update_entries(files, &ledger_entries)?;
+
+    match index {
+        Some(index) => {
+            for id in replaced.iter() {
+                index.remove_node(*id)?;
+            }
+
+            index.insert_nodes(&new_ids)?;
+        }
+        None => bump_blocks_generation()?,
+    }
+
+    update_centroids(files)?;
+
+    Ok(replaced.len())
+}
+
+// directory lines are `id filepath block`, followed by `title` for title embeddings
+//
+// the id and block never contain spaces, so everything between them is the filepath
+const TITLE_MARKER: &str = "title";
+
+fn parse_directory_line(line: &str) -> Option<(DirectoryEntry, u64)> {
+    let (line, title) = match line.strip_suffix(TITLE_MARKER) {
+        Some(line) => (line.strip_suffix(' ')?, true),
+        None => (line, false),
+    };
+
+    let (id, rest) = line.split_once(' ')?;
+    let (filepath, block) = rest.rsplit_once(' ')?;
+
+    Some((
+        DirectoryEntry {
+            id: id.parse().ok()?,
+            filepath: filepath.to_string(),
+            title,
+        },
+        block.parse().ok()?,
+    ))
+}
+
+// the `(id, filepath, block)` entries of the directory, in the order they're written
+pub fn read_directory_entries() -> Result<Vec<(u32, String, u64)>, std::io::Error> {
+    Ok(read_directory()?
+        .into_iter()
+        .map(|(entry, block)| (entry.id, entry.filepath, block as u64))
+        .collect())
+}
+
+pub(crate) fn read_directory() -> Result<Vec<(DirectoryEntry, u32)>, std::io::Error> {
+    let data_dir = get_data_dir();
+    let directory = std::fs::read_to_string(data_dir.join("directory"))?;
+    let paths = PathResolver::current();
+    directory
+        .lines()
+        .filter(|d| !d.is_empty())
+        .map(|d| match parse_directory_line(d) {
+            Some((entry, block)) => Ok((
+                DirectoryEntry {
+                    filepath: paths.resolve(&entry.filepath),
+                    ..entry
+                },
+                block as u32,
+            )),
+            None => {
+                error!("malformed directory entry: {}", d);
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("malformed directory entry: {}", d),
+                ))
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()
+}
+
+// TODO: at what point should we worry about holding this whole thing in memory?
+pub fn get_directory() -> Result<Directory, std::io::Error> {
+    let directory = read_directory()?;
+
+    let mut id_map = HashMap::new();
+    let mut file_map = HashMap::new();
+    let mut file_id_map = HashMap::new();
+    let mut title_map = HashMap::new();
+
+    for (entry, block) in directory {
+        let block = block as u64;
+        id_map.insert(entry.id, block);
+        file_map
+            .entry(entry.filepath.clone())
+            .or_insert_with(Vec::new)
+            .push(block);
+        if entry.title {
+            title_map.insert(entry.filepath.clone(), entry.id);
+        }
+        file_id_map.insert(entry.filepath, entry.id);
+    }
+
+    for blocks in file_map.values_mut() {
+        blocks.sort();
+        blocks.dedup();
+    }
+
+    Ok(Directory {
+        id_map,
+        file_map,
+        file_id_map,
+        title_map,
+    })
+}
+
+// automatic snapshots beyond this count are deleted, oldest first
+pub const SNAPSHOT_RETENTION: usize = 5;
+
+// prefix for the snapshots taken before destructive operations
+const AUTO_SNAPSHOT_PREFIX: &str = "auto-";
+
+// where a rollback moves the live index files while the snapshot's go in
+const ROLLBACK_PREVIOUS_DIR: &str = "rollback-previous";
+
+// the files in $DATA_DIR that make up the index:
+// the numbered blocks, the directory, the serialized HNSW, the rules hashes, the frequency table,
+// the generations of the blocks and index, and the deleted ids
+fn get_data_files() -> Result<Vec<std::path::PathBuf>, std::io::Error> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(get_data_dir())? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+
+        if let Some(filename) = path.file_name().and_then(|f| f.to_str()) {
+            if filename.parse::<u64>().is_ok()
+                || filename == "directory"
+                || filename == "index"
+                || filename == "rules_hashes"
+                || filename == "frequencies"
+                || filename == "generation"
+                || filename == NORMALIZED_FILE
+                || filename == TOMBSTONES_FILE
+                || filename == STABLE_IDS_FILE
+            {
+                files.push(path);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+// hard links are preferred since blocks can be large,
+// falling back to copies on filesystems that don't support them
+fn link_or_copy(from: &std::path::Path, to: &std::path::Path) -> Result<(), std::io::Error> {
+    if std::fs::hard_link(from, to).is_err() {
+        std::fs::copy(from, to)?;
+    }
+
+    Ok(())
+}
+
+pub fn get_snapshots_dir() -> std::path::PathBuf {
+    get_data_dir().join(crate::config::SNAPSHOTS_DIR)
+}
+
+// snapshot names are `<timestamp>-<label>`, so sorting them sorts by age
+pub fn list_snapshots() -> Result<Vec<String>, std::io::Error> {
+    let snapshots_dir = get_snapshots_dir();
+    if !snapshots_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut snapshots = Vec::new();
+    for entry in std::fs::read_dir(snapshots_dir)? {
+        let entry = entry?;
+        if entry.path().is_dir() {
+            snapshots.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+
+    snapshots.sort();
+
+    Ok(snapshots)
+}
+
+// copies the current index files into `$DATA_DIR/snapshots/<timestamp>-<label>/`
+// returns the name of the new snapshot
+pub fn snapshot(label: &str) -> Result<String, std::io::Error> {
+    if label.is_empty() || label.contains(std::path::MAIN_SEPARATOR) || label.contains('/') {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid snapshot label: {:?}", label),
+        ));
+    }
+
+    let name = format!(
+        "{}-{}",
+        chrono::Local::now().format("%Y-%m-%d_%H-%M-%S-%3f"),
+        label
+    );
+
+    let snapshot_dir = get_snapshots_dir().join(&name);
+    std::fs::create_dir_all(&snapshot_dir)?;
+
+    let files = get_data_files()?;
+    for file in files.iter() {
+        link_or_copy(file, &snapshot_dir.join(file.file_name().unwrap()))?;
+    }
+
+    lprint!(info, "Created snapshot {} with {} files", name, files.len());
+
+    Ok(name)
+}
+
+// finds a snapshot by its full name, or the most recent one with the given label
+pub fn find_snapshot(label: &str) -> Result<String, std::io::Error> {
+    let snapshots = list_snapshots()?;
+    let suffix = format!("-{}", label);
+    match snapshots
+        .iter()
+        .rev()
+        .find(|s| *s == label || s.ends_with(&suffix))
+    {
+        Some(s) => Ok(s.clone()),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no snapshot matching {}", label),
+        )),
+    }
+}
+
+// restores the index files from a snapshot, replacing the current ones
+//
+// the snapshot is staged next to the live files first, and the live files are moved aside
+// rather than removed, so a failure at any point before the last rename leaves the live index as it was
+pub fn rollback(label: &str) -> Result<(), std::io::Error> {
+    journal::record("rollback", |params| {
+        params.insert("label".to_string(), label.into());
+        run_rollback(label, params)
+    })
+}
+
+fn run_rollback(label: &str, params: &mut Params) -> Result<(), std::io::Error> {
+    let _lock = DataLock::acquire(LockMode::Exclusive, "rollback")?;
+    bump_state_generation()?;
+
+    let name = find_snapshot(label)?;
+    params.insert("snapshot".to_string(), name.clone().into());
+    let snapshot_dir = get_snapshots_dir().join(&name);
+    let data_dir = get_data_dir();
+
+    // a rollback that stopped partway through its swap can leave some of the live files only here
+    let previous_dir = data_dir.join(ROLLBACK_PREVIOUS_DIR);
+    if previous_dir.exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!(
+                "{} is left from a rollback that didn't finish and may hold some of the index, move its files back into {} first",
+                previous_dir.display(),
+                data_dir.display()
+            ),
+        ));
+    }
+
+    let staging_dir = data_dir.join("rollback");
+    if staging_dir.exists() {
+        std::fs::remove_dir_all(&staging_dir)?;
+    }
+
+    std::fs::create_dir(&staging_dir)?;
+
+    let mut staged = Vec::new();
+    for entry in std::fs::read_dir(&snapshot_dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            let filename = path.file_name().unwrap().to_os_string();
+            std::fs::copy(&path, staging_dir.join(&filename))?;
+            staged.push(filename);
+        }
+    }
+
+    let live = get_data_files()?
+        .into_iter()
+        .map(|file| file.file_name().unwrap().to_os_string())
+        .collect::<Vec<_>>();
+
+    std::fs::create_dir(&previous_dir)?;
+    let mut moved_aside = Vec::new();
+    let mut moved_in = Vec::new();
+    let swapped = rename_into(&data_dir, &previous_dir, &live, &mut moved_aside)
+        .and_then(|_| rename_into(&staging_dir, &data_dir, &staged, &mut moved_in));
+
+    if let Err(e) = swapped {
+        error!(
+            "failed to swap in snapshot {}, restoring the live index: {}",
+            name, e
+        );
+        let restored = moved_in
+            .iter()
+            .try_for_each(|filename| std::fs::remove_file(data_dir.join(filename)))
+            .and_then(|_| rename_into(&previous_dir, &data_dir, &moved_aside, &mut Vec::new()))
+            .and_then(|_| std::fs::remove_dir(&previous_dir));
+
+        return match restored {
+            Ok(()) => Err(e),
+            Err(restore_error) => Err(std::io::Error::new(
+                e.kind(),
+                format!(
+                    "failed to swap in snapshot {} ({}), then to restore the live index ({}), whose files are left in {}",
+                    name,
+                    e,
+                    restore_error,
+                    previous_dir.display()
+                ),
+            )),
+        };
+    }
+
+    std::fs::remove_dir_all(&previous_dir)?;
+    std::fs::remove_dir_all(&staging_dir)?;
+
+    // the snapshot doesn't have the centroids, which are worked out again from its blocks
+    if data_dir.join("directory").exists() {
+        rebuild_centroids()?;
+    }
+
+    lprint!(info, "Rolled back to snapshot {}", name);
+
+    Ok(())
+}
+
+// moves each of `filenames` from `from` to `to`, adding it to `moved` once it's there
+fn rename_into(
+    from: &std::path::Path,
+    to: &std::path::Path,
+    filenames: &[std::ffi::OsString],
+    moved: &mut Vec<std::ffi::OsString>,
+) -> Result<(), std::io::Error> {
+    for filename in filenames {
+        std::fs::rename(from.join(filename), to.join(filename))?;
+        moved.push(filename.clone());
+    }
+
+    Ok(())
+}
+
+// snapshots the index before a destructive operation
+// and deletes automatic snapshots past the retention limit
+pub fn auto_snapshot(operation: &str) -> Result<(), std::io::Error> {
+    if get_data_files()?.is_empty() {
+        info!("nothing to snapshot before {}", operation);
+        return Ok(());
+    }
+
+    snapshot(&format!("{}{}", AUTO_SNAPSHOT_PREFIX, operation))?;
+
+    let automatic = list_snapshots()?
+        .into_iter()
+        .filter(|s| s.contains(&format!("-{}", AUTO_SNAPSHOT_PREFIX)))
+        .collect::<Vec<_>>();
+
+    if automatic.len() > SNAPSHOT_RETENTION {
+        for s in automatic.iter().take(automatic.len() - SNAPSHOT_RETENTION) {
+            info!("removing old snapshot {}", s);
+            std::fs::remove_dir_all(get_snapshots_dir().join(s))?;
+        }
+    }
 
     Ok(())
 }
+
+// the manifest of a backup, which is written after everything else in it
+// so that a backup without one is never taken for a complete copy
+pub const BACKUP_MANIFEST: &str = "manifest.json";
+
+// how much of a file is copied or hashed at a time
+const BACKUP_CHUNK: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BackupManifest {
+    // RFC 3339, in local time
+    pub created: String,
+    // the state generation the backup was taken at
+    pub generation: u64,
+    pub files: Vec<BackupFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BackupFile {
+    pub name: String,
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+// copies the configured model's store to `dest`, with a checksum of every file in its manifest
+//
+// the data directory is only locked long enough to link its files somewhere they can't change,
+// so edits and queries carry on while they're copied, and the copy is read back and checked
+// against the checksums before the manifest goes in
+//
+// a backup already at `dest` loses its manifest before anything is copied over it
+//
+// the centroids aren't copied, since they're worked out again from the blocks on restore
+pub fn backup_to(dest: &std::path::Path) -> Result<BackupManifest, std::io::Error> {
+    let staging_dir = get_data_dir().join(format!(
+        "backup-{}",
+        chrono::Local::now().format("%Y-%m-%d_%H-%M-%S-%6f")
+    ));
+
+    let generation = {
+        let _lock = DataLock::acquire(LockMode::Shared, "backup")?;
+        let mut files = get_data_files()?;
+        if files.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "there's nothing in the data directory to back up",
+            ));
+        }
+
+        let meta_index = get_data_dir().join(META_INDEX_FILE);
+        if meta_index.exists() {
+            files.push(meta_index);
+        }
+
+        std::fs::create_dir(&staging_dir)?;
+        let linked = files
+            .iter()
+            .try_for_each(|file| link_or_copy(file, &staging_dir.join(file.file_name().unwrap())));
+        if let Err(e) = linked {
+            std::fs::remove_dir_all(&staging_dir)?;
+            return Err(e);
+        }
+
+        read_state_generation()?
+    };
+
+    let copied = copy_backup(&staging_dir, dest, generation);
+    std::fs::remove_dir_all(&staging_dir)?;
+    let manifest = copied?;
+
+    lprint!(
+        info,
+        "Backed up {} files at generation {} to {}",
+        manifest.files.len(),
+        manifest.generation,
+        dest.display()
+    );
+
+    Ok(manifest)
+}
+
+fn copy_backup(
+    staging_dir: &std::path::Path,
+    dest: &std::path::Path,
+    generation: u64,
+) -> Result<BackupManifest, std::io::Error> {
+    std::fs::create_dir_all(dest)?;
+    match std::fs::remove_file(dest.join(BACKUP_MANIFEST)) {
+        Ok(_) => {
+            info!("replacing the backup at {}", dest.display());
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(staging_dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let (bytes, sha256) = copy_hashed(&path, &dest.join(&name))?;
+        files.push(BackupFile {
+            name,
+            bytes,
+            sha256,
+        });
+    }
+
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    let manifest = BackupManifest {
+        created: chrono::Local::now().to_rfc3339(),
+        generation,
+        files,
+    };
+
+    check_backup_files(dest, &manifest)?;
+    write_atomic(
+        &dest.join(BACKUP_MANIFEST),
+        &serde_json::to_vec_pretty(&manifest).map_err(std::io::Error::other)?,
+    )?;
+
+    Ok(manifest)
+}
+
+// copies `from` to `to`, returning how many bytes were copied and the sha256 of them
+fn copy_hashed(
+    from: &std::path::Path,
+    to: &std::path::Path,
+) -> Result<(u64, String), std::io::Error> {
+    let mut writer = std::fs::File::create(to)?;
+    let hashed = hash_into(from, &mut writer)?;
+    writer.sync_all()?;
+
+    Ok(hashed)
+}
+
+fn hash_file(path: &std::path::Path) -> Result<(u64, String), std::io::Error> {
+    hash_into(path, &mut std::io::sink())
+}
+
+// the length and sha256 of the file at `path`, which is written out to `writer` as it's read
+fn hash_into(
+    path: &std::path::Path,
+    writer: &mut impl Write,
+) -> Result<(u64, String), std::io::Error> {
+    let mut reader = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; BACKUP_CHUNK];
+    let mut bytes = 0;
+    loop {
+        let read = std::io::Read::read(&mut reader, &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+
+        Digest::update(&mut hasher, &buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+        bytes += read as u64;
+    }
+
+    Ok((bytes, format!("{:x}", hasher.finalize())))
+}
+
+// reads every file of a backup back and checks it against the manifest,
+// failing with `ErrorKind::InvalidData` that names every file that's missing or doesn't match
+fn check_backup_files(
+    dir: &std::path::Path,
+    manifest: &BackupManifest,
+) -> Result<(), std::io::Error> {
+    let mut bad = Vec::new();
+    for file in manifest.files.iter() {
+        match hash_file(&dir.join(&file.name)) {
+            Ok((bytes, sha256)) if bytes == file.bytes && sha256 == file.sha256 => {}
+            Ok(_) => {
+                lprint!(
+                    error,
+                    "{} in the backup at {} doesn't match its checksum",
+                    file.name,
+                    dir.display()
+                );
+                bad.push(file.name.clone());
+            }
+            Err(e) => {
+                lprint!(
+                    error,
+                    "{} in the backup at {} can't be read: {}",
+                    file.name,
+                    dir.display(),
+                    e
+                );
+                bad.push(file.name.clone());
+            }
+        }
+    }
+
+    match bad.is_empty() {
+        true => Ok(()),
+        false => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "backup at {} failed verification, {} of {} files are missing or corrupt: {}",
+                dir.display(),
+                bad.len(),
+                manifest.files.len(),
+                bad.join(", ")
+            ),
+        )),
+    }
+}
+
+// the manifest of the backup at `dir`, once every file in it has been checked against it
+//
+// a backup without a manifest was never finished, and fails with `ErrorKind::InvalidData`
+pub fn verify_backup(dir: &std::path::Path) -> Result<BackupManifest, std::io::Error> {
+    let manifest = match std::fs::read(dir.join(BACKUP_MANIFEST)) {
+        Ok(bytes) => serde_json::from_slice::<BackupManifest>(&bytes).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("malformed backup manifest in {}: {}", dir.display(), e),
+            )
+        })?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "{} has no {}, so it isn't a complete backup",
+                    dir.display(),
+                    BACKUP_MANIFEST
+                ),
+            ))
+        }
+        Err(e) => return Err(e),
+    };
+
+    // names come from the manifest, and can't be let out of the backup
+    if let Some(file) = manifest.files.iter().find(|f| {
+        f.name.contains('/')
+            || f.name.contains(std::path::MAIN_SEPARATOR)
+            || f.name.starts_with('.')
+    }) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("backup manifest names an invalid file: {:?}", file.name),
+        ));
+    }
+
+    check_backup_files(dir, &manifest)?;
+
+    Ok(manifest)
+}
+
+// restores a backup into a data directory that has nothing in it yet
+//
+// the backup is verified before anything is copied, and every file is checked again as it is,
+// with the files staged next to the data directory's so that a failure leaves it empty
+pub fn restore_from(src: &std::path::Path) -> Result<BackupManifest, std::io::Error> {
+    journal::record("restore", |params| {
+        params.insert("path".to_string(), src.to_string_lossy().into());
+        let manifest = run_restore_from(src)?;
+        params.insert("files".to_string(), manifest.files.len().into());
+        params.insert("backup_generation".to_string(), manifest.generation.into());
+
+        Ok(manifest)
+    })
+}
+
+fn run_restore_from(src: &std::path::Path) -> Result<BackupManifest, std::io::Error> {
+    std::fs::create_dir_all(get_data_dir())?;
+    let _lock = DataLock::acquire(LockMode::Exclusive, "restore")?;
+
+    if !get_data_files()?.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!(
+                "{} already has an index, backups are only restored into an empty data directory",
+                get_data_dir().display()
+            ),
+        ));
+    }
+
+    let manifest = verify_backup(src)?;
+    bump_state_generation()?;
+
+    let data_dir = get_data_dir();
+    let staging_dir = data_dir.join("restore");
+    if staging_dir.exists() {
+        std::fs::remove_dir_all(&staging_dir)?;
+    }
+
+    std::fs::create_dir(&staging_dir)?;
+
+    // the backup could have changed since it was verified
+    let staged = manifest.files.iter().try_for_each(|file| {
+        let (bytes, sha256) = copy_hashed(&src.join(&file.name), &staging_dir.join(&file.name))?;
+        match bytes == file.bytes && sha256 == file.sha256 {
+            true => Ok(()),
+            false => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} changed in the backup while it was restored", file.name),
+            )),
+        }
+    });
+
+    if let Err(e) = staged {
+        std::fs::remove_dir_all(&staging_dir)?;
+        return Err(e);
+    }
+
+    for file in manifest.files.iter() {
+        std::fs::rename(staging_dir.join(&file.name), data_dir.join(&file.name))?;
+    }
+
+    std::fs::remove_dir_all(&staging_dir)?;
+
+    // the meta index was written at the backup's generation, and the centroids weren't kept
+    if data_dir.join("directory").exists() {
+        write_meta_index()?;
+        rebuild_centroids()?;
+    }
+
+    lprint!(
+        info,
+        "Restored {} files from the backup at {}, taken at generation {}",
+        manifest.files.len(),
+        src.display(),
+        manifest.generation
+    );
+
+    Ok(manifest)
+}
+
+#[cfg(all(test, feature = "full"))]
+mod tests {
+    use super::*;
+    use crate::embedding::PATH_META;
+    use crate::sync::{insert_file, sync_index, update_file_embeddings, OverQuota};
+    use crate::test_common::*;
+    use crate::write_file;
+
+    // windows paths with spaces survive both the directory and the block format
+    #[test]
+    fn windows_paths_test() {
+        let filepath = "C:\\Users\\Me My Docs\\notes.md".to_string();
+        let entry = |id, title| DirectoryEntry {
+            id,
+            filepath: filepath.clone(),
+            title,
+        };
+        assert_eq!(
+            parse_directory_line(&format!("12 {} 3", filepath)),
+            Some((entry(12, false), 3))
+        );
+        assert_eq!(
+            parse_directory_line(&format!("13 {} 3 title", filepath)),
+            Some((entry(13, true), 3))
+        );
+        assert_eq!(parse_directory_line("12 3"), None);
+        assert_eq!(parse_directory_line("12 3 title"), None);
+
+        let block = EmbeddingBlock::new(
+            3,
+            EmbeddingModel::current(),
+            vec![Embedding {
+                id: 12,
+                source_file: EmbeddingSource {
+                    filepath: filepath.clone(),
+                    meta: HashSet::from(["notes".to_string()]),
+                    subset: Some((0, 10)),
+                    hash: String::new(),
+                    chunk_hash: None,
+                },
+                embedded_at: 7,
+                data: [0.5; crate::embedding::EMBED_DIM],
+            }],
+        );
+
+        let (block, _) = EmbeddingBlock::from_bytes(&block.to_bytes(), 0).unwrap();
+        assert_eq!(block.embeddings[0].source_file.filepath, filepath);
+    }
+
+    // 100 embeddings spread across 3 blocks, 5 of which the directory doesn't know about
+    #[test]
+    fn block_report_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let embeddings = setup_embeddings(100).unwrap();
+        assert_eq!(block_report().unwrap().len(), 1);
+        assert!(compaction_recommendation(&block_report().unwrap()).is_none());
+
+        std::fs::remove_file(get_data_dir().join("0")).unwrap();
+
+        let mut directory = Vec::new();
+        for (i, block) in [&embeddings[..40], &embeddings[40..80], &embeddings[80..]]
+            .iter()
+            .enumerate()
+        {
+            let mut embedding_block =
+                EmbeddingBlock::new(i as u64, EmbeddingModel::current(), block.to_vec());
+
+            assert!(embedding_block
+                .write_to(&get_data_dir().join(i.to_string()))
+                .is_ok());
+
+            for e in block.iter().filter(|e| e.id < 95) {
+                directory.push((DirectoryEntry::of(e), i as u32));
+            }
+        }
+
+        assert!(write_directory(&directory).is_ok());
+
+        let reports = block_report().unwrap();
+        assert_eq!(
+            reports.iter().map(|r| r.embeddings).collect::<Vec<_>>(),
+            vec![40, 40, 20]
+        );
+        assert_eq!(
+            reports.iter().map(|r| r.unreachable).collect::<Vec<_>>(),
+            vec![0, 0, 5]
+        );
+        assert!(reports.iter().all(|r| r.files == get_tracked_files().len()));
+        assert_eq!(reports[2].fill_factor, 20.0 / BLOCK_SIZE as f32);
+
+        let headers = read_embedding_block_headers(1).unwrap();
+        let block = read_embedding_block(1).unwrap();
+        assert_eq!(
+            headers.iter().map(|h| h.id).collect::<Vec<_>>(),
+            block.embeddings.iter().map(|e| e.id).collect::<Vec<_>>()
+        );
+        // the magic and version, then the block number
+        let header_bytes = 12
+            + 8
+            + EmbeddingModel::current().to_bytes().len() as u64
+            + block.filter.to_bytes().len() as u64
+            + 4;
+        assert_eq!(
+            headers.iter().map(|h| h.bytes as u64).sum::<u64>() + header_bytes,
+            reports[1].bytes
+        );
+
+        let recommendation = compaction_recommendation(&reports).unwrap();
+        assert!(recommendation.contains("pack 95 embeddings into 1 blocks (currently 3)"));
+    }
+
+    #[test]
+    fn parallel_block_read_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(setup_embeddings(BLOCK_SIZE * 5 / 2).is_ok());
+
+        let serial = read_all_blocks(1).unwrap();
+        let parallel = read_all_blocks(4).unwrap();
+        assert_eq!(serial.len(), BLOCK_SIZE * 5 / 2);
+        assert_eq!(serial.len(), parallel.len());
+
+        let blocks = parallel.iter().map(|b| b.block_number).collect::<Vec<_>>();
+        assert!(blocks.is_sorted());
+        assert_eq!(blocks.last(), Some(&2));
+
+        for (s, p) in serial.iter().zip(parallel.iter()) {
+            assert_eq!(s.block_number, p.block_number);
+            assert_eq!(s.source_file, p.source_file);
+            assert_eq!(s.embedding.id, p.embedding.id);
+            assert_eq!(
+                s.embedding.source_file.subset,
+                p.embedding.source_file.subset
+            );
+            assert_eq!(s.embedding.data, p.embedding.data);
+        }
+    }
+
+    #[test]
+    fn dump_jsonl_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let embeddings = setup_embeddings(BLOCK_SIZE + 10).unwrap();
+
+        let mut dump = Vec::new();
+        assert_eq!(dump_jsonl(&mut dump, &[], None).unwrap(), embeddings.len());
+
+        let lines = String::from_utf8(dump).unwrap();
+        let lines = lines
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), embeddings.len());
+
+        for (line, embedding) in lines.iter().zip(embeddings.iter()) {
+            assert_eq!(line["id"], embedding.id);
+            assert_eq!(line["filepath"], embedding.source_file.filepath.as_str());
+            let (start, end) = embedding.source_file.subset.unwrap();
+            assert_eq!(line["subset"], serde_json::json!([start, end]));
+            assert_eq!(line["meta"], serde_json::json!(["code", "rust", "tracked"]));
+
+            let vector = line["vector"].as_array().unwrap();
+            assert_eq!(vector.len(), crate::embedding::EMBED_DIM);
+            assert_eq!(vector[0].as_f64().unwrap() as f32, embedding.data[0]);
+        }
+
+        let mut dump = Vec::new();
+        assert_eq!(
+            dump_jsonl(&mut dump, &[], Some(0)).unwrap(),
+            embeddings.len()
+        );
+        assert!(String::from_utf8(dump).unwrap().lines().all(|l| {
+            let line = serde_json::from_str::<serde_json::Value>(l).unwrap();
+            line.get("vector").is_none() && line.get("id").is_some()
+        }));
+
+        let mut dump = Vec::new();
+        assert!(dump_jsonl(&mut dump, &[], Some(8)).is_ok());
+        let line = String::from_utf8(dump).unwrap();
+        let line = serde_json::from_str::<serde_json::Value>(line.lines().next().unwrap()).unwrap();
+        assert_eq!(line["vector"].as_array().unwrap().len(), 8);
+
+        let filters = [crate::hnsw::Filter::from_string("ne rust").unwrap()];
+        let mut dump = Vec::new();
+        assert_eq!(dump_jsonl(&mut dump, &filters, None).unwrap(), 0);
+        assert!(dump.is_empty());
+    }
+
+    // dumping the store and importing it into an empty one gives back the same embeddings,
+    // and an index built over them finds the same chunks
+    #[test]
+    fn import_jsonl_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        let embeddings = setup_embeddings(300).unwrap();
+        let index = HNSW::build(&crate::hnsw::HNSWParams::default()).unwrap();
+
+        let dump_path = crate::config::get_home_dir().join("dump.jsonl");
+        let mut dump = Vec::new();
+        assert!(dump_jsonl(&mut dump, &[], None).is_ok());
+        write_file!(&dump_path, &dump);
+
+        for file in get_data_files().unwrap() {
+            std::fs::remove_file(file).unwrap();
+        }
+        write_file!(crate::config::get_local_dir().join("ledger"), "");
+
+        assert_eq!(
+            import_jsonl(&dump_path, false, false, None).unwrap(),
+            embeddings.len()
+        );
+
+        let imported = get_all_blocks().unwrap();
+        assert_eq!(imported.len(), embeddings.len());
+        for (i, e) in imported.iter().zip(embeddings.iter()) {
+            assert_eq!(i.embedding.source_file.filepath, e.source_file.filepath);
+            assert_eq!(i.embedding.source_file.subset, e.source_file.subset);
+            assert_eq!(i.embedding.source_file.meta, e.source_file.meta);
+            assert!(crate::hnsw::dot(&i.embedding, e) > 0.9999);
+        }
+
+        // the files are in the ledger, and nothing needs embedding again
+        assert_eq!(
+            crate::ledger::read_ledger().unwrap().len(),
+            get_tracked_files().len()
+        );
+        assert!(crate::ledger::get_stale_files().unwrap().is_empty());
+
+        let reimported = HNSW::build(&crate::hnsw::HNSWParams::default()).unwrap();
+        for e in embeddings.iter().step_by(30) {
+            let query = crate::hnsw::Query {
+                embedding: e.clone(),
+                filters: Vec::new(),
+                exclude_paths: false,
+                trace: false,
+                mode: crate::hnsw::SearchMode::Balanced,
+                candidates: None,
+                scope: None,
+                meta: None,
+            };
+
+            let before = index.query(&query, 1, 50).results;
+            let after = reimported.query(&query, 1, 50).results;
+            assert_eq!(
+                before[0].0.source_file.subset,
+                after[0].0.source_file.subset
+            );
+        }
+
+        // one bad line stops the whole import, unless it's lenient
+        let mut lines = std::fs::read_to_string(&dump_path)
+            .unwrap()
+            .lines()
+            .take(2)
+            .map(|l| l.to_string())
+            .collect::<Vec<_>>();
+        lines.push("{\"filepath\": \"short\", \"vector\": [0.5, 0.5]}".to_string());
+        lines.push("not json".to_string());
+        write_file!(&dump_path, lines.join("\n"));
+
+        let error = import_jsonl(&dump_path, false, false, None).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("2 malformed lines"));
+        assert_eq!(get_all_blocks().unwrap().len(), embeddings.len());
+
+        // the lines are for files the store already has, so those files are replaced
+        let mut index = reimported;
+        assert_eq!(
+            import_jsonl(&dump_path, true, false, Some(&mut index)).unwrap(),
+            2
+        );
+
+        let blocks = get_all_blocks().unwrap();
+        let replaced = embeddings
+            .iter()
+            .filter(|e| e.source_file.filepath == embeddings[0].source_file.filepath)
+            .count();
+        assert_eq!(blocks.len(), embeddings.len() - replaced * 2 + 2);
+        assert_eq!(index.get_last_layer().len(), blocks.len());
+        assert!(blocks
+            .iter()
+            .all(|b| index.get_last_layer().contains(b.embedding.id)));
+    }
+
+    // moves the later half of a file's embeddings out of block 0 and into a block 1 of their own
+    fn spread_file(filepath: &str) {
+        let mut block = read_embedding_block(0).unwrap();
+        let mut moved = block
+            .embeddings
+            .iter()
+            .filter(|e| e.source_file.filepath == filepath)
+            .map(|e| e.id)
+            .collect::<Vec<_>>();
+        moved.drain(..moved.len() / 2);
+
+        let (spread, kept) = block
+            .embeddings
+            .drain(..)
+            .partition::<Vec<_>, _>(|e| moved.contains(&e.id));
+        block.embeddings = kept;
+        block.write_to(&get_data_dir().join("0")).unwrap();
+        EmbeddingBlock::new(1, EmbeddingModel::current(), spread)
+            .write_to(&get_data_dir().join("1"))
+            .unwrap();
+
+        let entries = read_directory()
+            .unwrap()
+            .into_iter()
+            .map(|(entry, _)| {
+                let block = moved.contains(&(entry.id as u64)) as u32;
+                (entry, block)
+            })
+            .collect::<Vec<_>>();
+        write_directory(&entries).unwrap();
+    }
+
+    // edits and deletes reach every block a file's chunks ended up in
+    #[test]
+    fn spread_file_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let filepath = crate::config::get_home_dir().join("test_repo").join("a.rs");
+        let contents = (0..300).map(|i| format!("word{} ", i)).collect::<String>();
+        write_file!(&filepath, &contents);
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+
+        let filepath = filepath.to_string_lossy().to_string();
+        let file_ids = || {
+            get_all_blocks()
+                .unwrap()
+                .into_iter()
+                .filter(|be| be.embedding.source_file.filepath == filepath)
+                .map(|be| (be.block_number, be.embedding.id))
+                .collect::<Vec<_>>()
+        };
+
+        spread_file(&filepath);
+        assert_eq!(get_directory().unwrap().file_map[&filepath], vec![0, 1]);
+        assert_eq!(blocks_with_file(&filepath).unwrap(), vec![0, 1]);
+
+        let before = file_ids();
+        assert!(before.iter().any(|(block, _)| *block == 1));
+
+        let mut index = HNSW::build(&crate::hnsw::HNSWParams::default()).unwrap();
+        assert!(update_file_embeddings(&filepath, None, &mut index).is_ok());
+
+        // none of the old embeddings are left behind in either block
+        let after = file_ids();
+        assert_eq!(after.len(), before.len());
+        assert!(after
+            .iter()
+            .all(|(block, id)| *block == 0 && !before.iter().any(|(_, old)| old == id)));
+        assert!(!read_block_filter(1).unwrap().may_contain(&filepath));
+        assert_eq!(get_directory().unwrap().file_map[&filepath], vec![0]);
+
+        spread_file(&filepath);
+        assert_eq!(delete_file(&filepath, None).unwrap(), after.len());
+        assert!(file_ids().is_empty());
+        assert!(blocks_with_file(&filepath).unwrap().is_empty());
+
+        // a file the filters have never seen is turned away without rewriting the blocks
+        let block = std::fs::read(get_data_dir().join("0")).unwrap();
+        assert_eq!(delete_file("/nowhere/z.rs", None).unwrap(), 0);
+        assert_eq!(std::fs::read(get_data_dir().join("0")).unwrap(), block);
+    }
+
+    // a file that keeps growing spills out of its block instead of overfilling it
+    #[test]
+    fn block_overflow_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let rules = crate::config::get_config_dir().join("rules");
+        let rule_contents = std::fs::read_to_string(&rules).unwrap();
+        write_file!(&rules, format!("{}\ntxt --split =====", rule_contents));
+
+        let filepath = crate::config::get_home_dir()
+            .join("test_repo")
+            .join("log.txt");
+        let section = |i: usize| format!("entry{} =====", i);
+        let mut contents = (0..300).map(section).collect::<String>();
+        write_file!(&filepath, &contents);
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+
+        let mut index = HNSW::build(&crate::hnsw::HNSWParams::default()).unwrap();
+        let filepath = filepath.to_string_lossy().to_string();
+        for edit in 1..4 {
+            let start = contents.len() as u64;
+            contents.extend((edit * 300..(edit + 1) * 300).map(section));
+            write_file!(&filepath, &contents);
+
+            let range = (start, contents.len() as u64);
+            assert!(update_file_embeddings(&filepath, Some(&[range]), &mut index).is_ok());
+
+            for block_number in block_numbers().unwrap() {
+                assert!(read_embedding_block_headers(block_number).unwrap().len() <= BLOCK_SIZE);
+            }
+        }
+
+        let directory = get_directory().unwrap();
+        assert!(directory.file_map[&filepath].len() > 1);
+
+        // every chunk is where the directory says it is, and the index can reach it
+        let chunks = get_all_blocks()
+            .unwrap()
+            .into_iter()
+            .filter(|be| be.embedding.source_file.filepath == filepath)
+            .collect::<Vec<_>>();
+        assert_eq!(chunks.len(), 1200 + 2);
+        for be in chunks.iter() {
+            assert_eq!(directory.id_map[&(be.embedding.id as u32)], be.block_number);
+            assert!(index.get_last_layer().contains(be.embedding.id));
+        }
+
+        // an index built from scratch reads every chunk back through the directory
+        let rebuilt = HNSW::build(&crate::hnsw::HNSWParams::default()).unwrap();
+        for be in chunks.iter() {
+            assert!(rebuilt.get_last_layer().contains(be.embedding.id));
+        }
+    }
+
+    // a deleted file stays out of results from an index that still has it,
+    // until a reblock takes it out of the index for good
+    #[test]
+    fn tombstone_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let target = crate::config::get_home_dir().join("test_repo");
+        let files = crate::test_common::get_tracked_files();
+        for (i, tf) in files.iter().enumerate() {
+            let contents = (0..40)
+                .map(|j| format!("file{} word{} ", i, j))
+                .collect::<String>();
+            write_file!(target.join(tf), contents);
+        }
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+        assert!(build_index().unwrap().is_some());
+
+        let deleted = target.join(&files[0]).to_string_lossy().to_string();
+        let embedding = get_all_blocks()
+            .unwrap()
+            .into_iter()
+            .map(|be| *be.embedding)
+            .find(|e| e.source_file.filepath == deleted)
+            .unwrap();
+
+        let removed = delete_file(&deleted, None).unwrap();
+        assert!(removed > 0);
+        assert_eq!(read_tombstones().unwrap().len(), removed);
+
+        // the index on disk still has the file's nodes
+        let index = HNSW::new(false).unwrap();
+        assert!(index.get_last_layer().contains(embedding.id));
+
+        let query = crate::hnsw::Query {
+            embedding,
+            filters: Vec::new(),
+            exclude_paths: false,
+            trace: false,
+            mode: crate::hnsw::SearchMode::Balanced,
+            candidates: None,
+            scope: None,
+            meta: None,
+        };
+        let results = index.query(&query, 5, 50).results;
+        assert!(!results.is_empty());
+        assert!(results
+            .iter()
+            .all(|(e, _)| e.source_file.filepath != deleted));
+
+        // nothing new is given a deleted id
+        let max_deleted = *read_tombstones().unwrap().iter().max().unwrap();
+        assert!(
+            next_embedding_id(read_directory_entries().unwrap().iter().map(|e| e.0)).unwrap()
+                > max_deleted
+        );
+
+        assert!(reblock(false).is_ok());
+        assert!(read_tombstones().unwrap().is_empty());
+
+        let index = HNSW::new(false).unwrap();
+        assert!(!index.get_last_layer().contains(query.embedding.id));
+        assert_eq!(
+            index.get_last_layer().len(),
+            read_directory_entries().unwrap().len()
+        );
+        assert!(index
+            .query(&query, 5, 50)
+            .results
+            .iter()
+            .all(|(e, _)| e.source_file.filepath != deleted));
+    }
+
+    #[test]
+    fn snapshot_rollback_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let embeddings = setup_embeddings(100).unwrap();
+
+        let index = HNSW::build(&crate::hnsw::HNSWParams::default()).unwrap();
+        let data_dir = get_data_dir();
+        let index_path = data_dir.join("index");
+        assert!(index.serialize(&index_path).is_ok());
+
+        let block = std::fs::read(data_dir.join("0")).unwrap();
+        let directory = std::fs::read(data_dir.join("directory")).unwrap();
+        let serialized_index = std::fs::read(&index_path).unwrap();
+
+        let name = snapshot("test").unwrap();
+        assert_eq!(list_snapshots().unwrap(), vec![name]);
+
+        // the data files are hard-linked into the snapshot,
+        // so they're corrupted the same way they're written--by replacement
+        assert!(write_atomic(&data_dir.join("0"), b"garbage").is_ok());
+        assert!(write_atomic(&data_dir.join("directory"), b"garbage").is_ok());
+        std::fs::remove_file(&index_path).unwrap();
+
+        assert!(rollback("test").is_ok());
+
+        assert_eq!(std::fs::read(data_dir.join("0")).unwrap(), block);
+        assert_eq!(
+            std::fs::read(data_dir.join("directory")).unwrap(),
+            directory
+        );
+        assert_eq!(std::fs::read(&index_path).unwrap(), serialized_index);
+
+        let index = HNSW::new(false).unwrap();
+        let query = crate::hnsw::Query {
+            embedding: embeddings[42].clone(),
+            filters: Vec::new(),
+            exclude_paths: false,
+            trace: false,
+            mode: crate::hnsw::SearchMode::Balanced,
+            candidates: None,
+            scope: None,
+            meta: None,
+        };
+
+        let results = index.query(&query, 5, 50).results;
+        assert!(results.first().is_some_and(|r| r.0.id == embeddings[42].id));
+    }
+
+    // a rollback that fails partway through its swap puts the live files back as they were
+    #[test]
+    fn rollback_swap_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        setup_embeddings(100).unwrap();
+
+        let data_dir = get_data_dir();
+        let index_path = data_dir.join("index");
+        let index = HNSW::build(&crate::hnsw::HNSWParams::default()).unwrap();
+        assert!(index.serialize(&index_path).is_ok());
+        snapshot("test").unwrap();
+
+        assert!(write_atomic(&data_dir.join("0"), b"garbage").is_ok());
+        let live = get_data_files()
+            .unwrap()
+            .into_iter()
+            .filter(|file| *file != index_path)
+            .map(|file| (file.clone(), std::fs::read(file).unwrap()))
+            .collect::<Vec<_>>();
+
+        // the snapshot's index can't be renamed over a directory
+        std::fs::remove_file(&index_path).unwrap();
+        std::fs::create_dir(&index_path).unwrap();
+
+        assert!(rollback("test").is_err());
+        assert!(!data_dir.join(ROLLBACK_PREVIOUS_DIR).exists());
+        assert_eq!(get_data_files().unwrap().len(), live.len());
+        for (file, contents) in live.iter() {
+            assert_eq!(&std::fs::read(file).unwrap(), contents);
+        }
+
+        // files left aside by a rollback that didn't finish aren't swapped over
+        std::fs::remove_dir(&index_path).unwrap();
+        std::fs::create_dir(data_dir.join(ROLLBACK_PREVIOUS_DIR)).unwrap();
+        let error = rollback("test").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(data_dir.join("0")).unwrap(), b"garbage");
+
+        std::fs::remove_dir(data_dir.join(ROLLBACK_PREVIOUS_DIR)).unwrap();
+        assert!(rollback("test").is_ok());
+        assert!(index_path.is_file());
+        assert_ne!(std::fs::read(data_dir.join("0")).unwrap(), b"garbage");
+    }
+
+    // a scoped rebuild only touches the nodes of the matching files,
+    // and every other node keeps its edges unless they ran into one of those
+    #[test]
+    fn graft_index_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+        assert!(build_index().unwrap().is_some());
+
+        let target = crate::config::get_home_dir().join("test_repo");
+        let only =
+            crate::ledger::PathFilter::new(&[format!("{}/src/*", target.display())]).unwrap();
+        let ids_of = |filepath: &std::path::Path| {
+            read_directory_entries()
+                .unwrap()
+                .into_iter()
+                .filter(|(_, f, _)| std::path::Path::new(f) == filepath)
+                .map(|(id, _, _)| id as u64)
+                .collect::<HashSet<_>>()
+        };
+
+        let before = HNSW::new(false).unwrap();
+        let filepath = target.join("src").join("e.rs");
+        let old_ids = ids_of(&filepath);
+
+        // inserting a file on its own keeps every other id as it was
+        write_file!(&filepath, "fn e() {}\n".repeat(300));
+        let meta = crate::ledger::read_ledger()
+            .unwrap()
+            .into_iter()
+            .find(|e| std::path::Path::new(&e.filepath) == filepath)
+            .unwrap()
+            .meta;
+        assert!(insert_file(&filepath.to_string_lossy(), meta, None).is_ok());
+        assert!(read_generation().unwrap().index_behind());
+        let new_ids = ids_of(&filepath);
+
+        let graft = graft_index(&only).unwrap().unwrap();
+        assert_eq!(graft.removed, old_ids.len());
+        assert_eq!(graft.inserted, new_ids.len());
+        assert!(!read_generation().unwrap().index_behind());
+
+        let after = HNSW::new(false).unwrap();
+        let mut live = get_directory()
+            .unwrap()
+            .id_map
+            .keys()
+            .map(|&id| id as u64)
+            .collect::<Vec<_>>();
+        live.sort();
+        assert_eq!(after.get_last_layer().nodes(), live);
+
+        let touched = old_ids.union(&new_ids).copied().collect::<HashSet<_>>();
+        let bits = |neighbors: &[(u64, f32)]| {
+            neighbors
+                .iter()
+                .map(|(n, d)| (*n, d.to_bits()))
+                .collect::<Vec<_>>()
+        };
+
+        let mut untouched = 0;
+        for (layer, graph) in before.layers.iter().enumerate() {
+            for (node, neighbors) in graph.iter() {
+                let grafted = match after.layers[layer].get(node) {
+                    Some(grafted) => grafted,
+                    None => {
+                        assert!(touched.contains(node));
+                        continue;
+                    }
+                };
+
+                let near_graft = |n: &[(u64, f32)]| n.iter().any(|(n, _)| touched.contains(n));
+                if touched.contains(node) || near_graft(neighbors) || near_graft(grafted) {
+                    continue;
+                }
+
+                assert_eq!(bits(neighbors), bits(grafted), "node {}", node);
+                untouched += 1;
+            }
+        }
+
+        assert!(untouched > 0);
+
+        // a sync gives everything new ids, which the index can't be patched up to match
+        write_file!(&filepath, "fn e() {}\n".repeat(200));
+        assert!(sync_index(false, false, false, Some(&only), OverQuota::Stop, false).is_ok());
+        let e = graft_index(&only).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(build_index().unwrap().is_some());
+        assert!(graft_index(&only).unwrap().is_some());
+
+        // there's nothing to graft onto without an index
+        std::fs::remove_file(get_data_dir().join("index")).unwrap();
+        assert!(graft_index(&only).unwrap().is_none());
+    }
+
+    #[test]
+    fn file_info_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+
+        let target = crate::config::get_home_dir().join("test_repo");
+        let indexed = target.join("a.rs").to_string_lossy().to_string();
+        let unindexed = target.join("d.md").to_string_lossy().to_string();
+
+        let info = file_info(&indexed).unwrap().unwrap();
+        let blocks = get_all_blocks().unwrap();
+        let mut ids = blocks
+            .iter()
+            .filter(|be| be.embedding.source_file.filepath == indexed)
+            .map(|be| be.embedding.id)
+            .collect::<Vec<_>>();
+        ids.sort();
+
+        let mut info_ids = info.ids.clone();
+        info_ids.sort();
+        assert_eq!(info_ids, ids);
+        assert_eq!(info.chunk_count, ids.len());
+        assert_eq!(info.subsets.len(), ids.len());
+        assert!(info.subsets.contains(&Some((0, 0))));
+        assert_eq!(info.blocks, get_directory().unwrap().file_map[&indexed]);
+        assert!(info.meta.contains("rust") && !info.meta.contains(PATH_META));
+
+        let entry = crate::ledger::entry_for(&indexed).unwrap().unwrap();
+        assert_eq!(info.last_embedded_hash, entry.hash);
+
+        assert!(file_info(&unindexed).unwrap().is_none());
+        assert!(crate::ledger::entry_for(&unindexed).unwrap().is_none());
+
+        // the request the client sends reads back as an edit, which the server takes too
+        let request = serde_json::to_string(&crate::message::DeweyRequest {
+            message_type: "file_info".to_string(),
+            payload: crate::message::RequestPayload::FileInfo {
+                filepath: indexed.clone(),
+            },
+            expect_generation: None,
+            trace_id: None,
+            token: None,
+        })
+        .unwrap();
+        let request: crate::message::DeweyRequest = serde_json::from_str(&request).unwrap();
+
+        let state = crate::ServerState::with_index(build_index().unwrap().unwrap());
+        let response: crate::message::DeweyFileInfoResponse =
+            serde_json::from_str(&state.file_info(request.payload, None).unwrap()).unwrap();
+        assert_eq!(response.info.unwrap().ids, info.ids);
+        assert_eq!(response.ledger.unwrap().hash, entry.hash);
+    }
+
+    // blocks from before the format version still read, with no embedding times
+    #[test]
+    fn unversioned_block_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        std::fs::create_dir_all(get_data_dir()).unwrap();
+
+        let embeddings = setup_embeddings(3).unwrap();
+        let mut bytes = 5u64.to_bytes();
+        bytes.extend(EmbeddingModel::current().to_bytes());
+        bytes.extend(FileFilter::of(&embeddings).to_bytes());
+        bytes.extend((embeddings.len() as u32).to_bytes());
+        for e in embeddings.iter() {
+            bytes.extend(e.id.to_bytes());
+            bytes.extend(e.source_file.to_bytes());
+            bytes.extend(e.data.to_bytes());
+        }
+        std::fs::write(get_data_dir().join("5"), &bytes).unwrap();
+
+        let block = read_embedding_block(5).unwrap();
+        assert_eq!(block.block, 5);
+        assert_eq!(block.embeddings.len(), embeddings.len());
+        for (read, written) in block.embeddings.iter().zip(embeddings.iter()) {
+            assert_eq!(read.id, written.id);
+            assert_eq!(read.source_file.filepath, written.source_file.filepath);
+            assert_eq!(read.data, written.data);
+            assert_eq!(read.embedded_at, 0);
+        }
+
+        let headers = read_embedding_block_headers(5).unwrap();
+        assert_eq!(headers.len(), embeddings.len());
+        assert!(headers.iter().all(|h| h.embedded_at == 0));
+        assert_eq!(read_block_model(5).unwrap(), EmbeddingModel::current());
+        let filepath = &embeddings[0].source_file.filepath;
+        assert!(read_block_filter(5).unwrap().may_contain(filepath));
+
+        // writing it again moves it to the current version, times and all
+        let mut block = block;
+        block.embeddings[0].embedded_at = 7;
+        block.write_to(&get_data_dir().join("5")).unwrap();
+        let bytes = std::fs::read(get_data_dir().join("5")).unwrap();
+        assert_eq!(parse_block_version(&bytes, 0).unwrap(), (BLOCK_VERSION, 12));
+        assert_eq!(read_embedding_block_headers(5).unwrap()[0].embedded_at, 7);
+        assert_eq!(
+            read_embedding_block(5).unwrap().embeddings[0].embedded_at,
+            7
+        );
+        assert_eq!(read_block_model(5).unwrap(), EmbeddingModel::current());
+    }
+
+    #[test]
+    fn meta_index_select_test() {
+        let tags = HashMap::from([
+            ("a".to_string(), HashSet::from([1, 2, 3, 4])),
+            ("b".to_string(), HashSet::from([3, 4, 5])),
+            ("c".to_string(), HashSet::from([9])),
+        ]);
+        let meta_index = MetaIndex::new(0, (0..10).rev().collect(), tags);
+        let select = |filters: &[&str]| {
+            meta_index.select(
+                &filters
+                    .iter()
+                    .map(|f| Filter::from_string(f).unwrap())
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        assert_eq!(select(&["eq a"]), Some(vec![1, 2, 3, 4]));
+        assert_eq!(select(&["a", "eq b"]), Some(vec![3, 4]));
+        assert_eq!(select(&["eq a", "eq c"]), Some(vec![]));
+        assert_eq!(select(&["ne b"]), Some(vec![0, 1, 2, 6, 7, 8, 9]));
+        assert_eq!(select(&["ne b", "ne c"]), Some(vec![0, 1, 2, 6, 7, 8]));
+        assert_eq!(select(&["eq a", "ne b"]), Some(vec![1, 2]));
+        assert_eq!(select(&["ne a", "eq b"]), Some(vec![5]));
+        assert_eq!(select(&[]), Some((0..10).collect()));
+
+        // a tag nothing has can't be told apart from one that wasn't indexed
+        assert_eq!(select(&["eq d"]), None);
+        assert_eq!(select(&["eq a", "ne d"]), None);
+    }
+
+    // the meta index is written with the blocks, and passed over once they change without it
+    #[test]
+    fn meta_index_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(read_meta_index().unwrap().is_none());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+
+        let meta_index = read_meta_index().unwrap().unwrap();
+        let directory = get_directory().unwrap();
+        let mut ids = directory
+            .id_map
+            .keys()
+            .map(|&id| id as u64)
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(meta_index.ids, ids);
+        for (tag, tagged) in read_meta_ids().unwrap() {
+            let mut tagged = tagged.into_iter().collect::<Vec<_>>();
+            tagged.sort();
+            assert_eq!(meta_index.tags[&tag], tagged, "{}", tag);
+        }
+        assert!(!meta_index.tags.contains_key(PATH_META));
+
+        let rust = Filter::from_string("eq rust").unwrap();
+        assert_eq!(meta_index.select(std::slice::from_ref(&rust)), Some(ids));
+
+        let target = crate::config::get_home_dir().join("test_repo");
+        let edited = target.join("a.rs").to_string_lossy().to_string();
+        write_file!(target.join("a.rs"), "c".repeat(10000));
+        let mut index = build_index().unwrap().unwrap();
+        assert!(update_file_embeddings(&edited, None, &mut index).is_ok());
+        assert!(read_meta_index().unwrap().is_none());
+
+        index.serialize(&get_data_dir().join("index")).unwrap();
+        assert!(reblock(false).is_ok());
+        let meta_index = read_meta_index().unwrap().unwrap();
+        assert_eq!(
+            meta_index.select(&[rust]).unwrap().len(),
+            get_directory().unwrap().len()
+        );
+    }
+
+    // every operation that changes the data directory leaves an entry behind, in order
+    #[test]
+    fn journal_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        let target = crate::config::get_home_dir().join("test_repo");
+        let path = |file: &str| target.join(file).to_string_lossy().to_string();
+
+        // a dry run doesn't change anything, and isn't journaled
+        assert!(sync_index(true, true, false, None, OverQuota::Stop, false).is_ok());
+        assert!(crate::journal::read_recent(10).unwrap().is_empty());
+
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+        assert!(build_index().unwrap().is_some());
+        assert!(reblock(true).is_ok());
+
+        let mut index = build_index().unwrap().unwrap();
+        write_file!(target.join("a.rs"), "fn a() {}");
+        assert!(update_file_embeddings(&path("a.rs"), Some(&[(0, 4)]), &mut index).is_ok());
+        assert!(delete_file(&path("b.rs"), Some(&mut index)).is_ok());
+        assert!(rollback("missing").is_err());
+
+        let entries = crate::journal::read_recent(10).unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| e.operation.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "sync_index",
+                "reblock",
+                "update_file_embeddings",
+                "delete_file",
+                "rollback"
+            ]
+        );
+
+        assert_eq!(entries[0].params["full"], true);
+        assert_eq!(entries[0].params["files"], get_tracked_files().len());
+        assert_eq!(entries[1].params["blocks"], block_numbers().unwrap().len());
+        assert_eq!(entries[2].params["filepath"], path("a.rs"));
+        assert_eq!(entries[2].params["ranges"], 1);
+        assert!(entries[3].params["embeddings"].as_u64().unwrap() > 0);
+
+        for entry in entries.iter().take(4) {
+            assert_eq!(entry.outcome, crate::journal::Outcome::Completed);
+        }
+
+        assert!(matches!(
+            &entries[4].outcome,
+            crate::journal::Outcome::Failed { error } if error.contains("missing")
+        ));
+
+        // each one bumped the generation before anything else, failed or not
+        let generations = entries.iter().map(|e| e.generation).collect::<Vec<_>>();
+        assert!(generations.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(generations[4], read_state_generation().unwrap());
+    }
+
+    // a backup is checked file by file, and a corrupt one is never restored
+    #[test]
+    fn backup_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+        assert!(build_index().unwrap().is_some());
+
+        let dest = crate::config::get_home_dir().join("backup");
+        let manifest = backup_to(&dest).unwrap();
+        assert_eq!(manifest.generation, read_state_generation().unwrap());
+        assert_eq!(manifest.files.len(), get_data_files().unwrap().len() + 1);
+        assert!(manifest.files.iter().any(|f| f.name == "0"));
+        assert!(manifest.files.iter().any(|f| f.name == META_INDEX_FILE));
+        assert_eq!(verify_backup(&dest).unwrap(), manifest);
+
+        // nothing's left behind in the data directory
+        assert!(std::fs::read_dir(get_data_dir()).unwrap().all(|e| !e
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with("backup")));
+
+        // there's only one index to restore into
+        let error = restore_from(&dest).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+
+        let corrupt = dest.join("0");
+        let original = std::fs::read(&corrupt).unwrap();
+        let mut bytes = original.clone();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        write_file!(&corrupt, &bytes);
+
+        let error = verify_backup(&dest).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(
+            error.to_string().contains("failed verification"),
+            "{}",
+            error
+        );
+        assert!(error.to_string().ends_with(": 0"), "{}", error);
+
+        let files = get_data_files().unwrap();
+        let directory = std::fs::read(get_data_dir().join("directory")).unwrap();
+        for file in files.iter() {
+            std::fs::remove_file(file).unwrap();
+        }
+
+        let error = restore_from(&dest).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(get_data_files().unwrap().is_empty());
+
+        // a backup cut short before its manifest isn't one
+        write_file!(&corrupt, &original);
+        std::fs::remove_file(dest.join(BACKUP_MANIFEST)).unwrap();
+        let error = restore_from(&dest).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(
+            error.to_string().contains("isn't a complete backup"),
+            "{}",
+            error
+        );
+
+        write_file!(
+            dest.join(BACKUP_MANIFEST),
+            serde_json::to_vec(&manifest).unwrap()
+        );
+        assert_eq!(restore_from(&dest).unwrap(), manifest);
+        assert_eq!(get_data_files().unwrap().len(), files.len());
+        assert_eq!(
+            std::fs::read(get_data_dir().join("directory")).unwrap(),
+            directory
+        );
+        assert!(read_meta_index().unwrap().is_some());
+        assert!(read_state_generation().unwrap() > manifest.generation);
+
+        let journal = crate::journal::read_recent(3).unwrap();
+        assert_eq!(
+            journal
+                .iter()
+                .map(|e| (
+                    e.operation.as_str(),
+                    e.outcome == crate::journal::Outcome::Completed
+                ))
+                .collect::<Vec<_>>(),
+            vec![("restore", false), ("restore", false), ("restore", true)]
+        );
+    }
+
+    // until a store is normalized, the same pair of embeddings is measured differently
+    // through the cache than through `get_all_blocks`, and afterwards every search agrees
+    // with comparing the vectors as they were embedded by angle
+    #[test]
+    fn normalize_store_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        // scaled out of unit length, as a provider that doesn't normalize would leave them
+        let mut raw = setup_embeddings(300).unwrap();
+        for (i, e) in raw.iter_mut().enumerate() {
+            let scale = 0.5 + (i % 7) as f32;
+            e.data.iter_mut().for_each(|x| *x *= scale);
+        }
+        write_blocks(&raw).unwrap();
+        assert!(build_index().unwrap().is_some());
+        assert!(needs_normalizing().unwrap());
+
+        // even an embedding's distance to itself
+        let all = get_all_blocks().unwrap();
+        let cached = EmbeddingCache::new(BLOCK_SIZE as u32)
+            .unwrap()
+            .get(3)
+            .unwrap();
+        assert!(crate::hnsw::distance(&cached, &cached, Metric::Cosine) < -1.0);
+        assert!(
+            crate::hnsw::distance(&all[3].embedding, &all[3].embedding, Metric::Cosine).abs()
+                < 1e-4
+        );
+
+        assert_eq!(normalize_store(false).unwrap(), raw.len());
+        assert!(is_store_normalized());
+        assert!(!needs_normalizing().unwrap());
+        for block_number in block_numbers().unwrap() {
+            assert!(read_embedding_block(block_number)
+                .unwrap()
+                .embeddings
+                .iter()
+                .all(is_normalized));
+        }
+
+        // brute force, over the vectors as they were written
+        let cosine = |a: &Embedding, b: &Embedding| {
+            crate::hnsw::dot(a, b) / (crate::hnsw::dot(a, a).sqrt() * crate::hnsw::dot(b, b).sqrt())
+        };
+
+        let index = HNSW::new(false).unwrap();
+        for e in raw.iter().step_by(30) {
+            let mut embedding = e.clone();
+            normalize(&mut embedding);
+            let query = crate::hnsw::Query {
+                embedding,
+                filters: Vec::new(),
+                exclude_paths: false,
+                trace: false,
+                mode: crate::hnsw::SearchMode::Balanced,
+                candidates: None,
+                scope: None,
+                meta: None,
+            };
+
+            let results = index.query(&query, 5, 50).results;
+            assert_eq!(results[0].0.id, e.id);
+            for (result, distance) in results.iter() {
+                let expected = 1.0 - cosine(e, &raw[result.id as usize]);
+                assert!(
+                    (distance - expected).abs() < 1e-4,
+                    "{} to {} is {}, not {}",
+                    e.id,
+                    result.id,
+                    distance,
+                    expected
+                );
+            }
+        }
+
+        let mut cache = EmbeddingCache::new(BLOCK_SIZE as u32).unwrap();
+        for be in get_all_blocks().unwrap().iter().step_by(10) {
+            assert_eq!(
+                cache.get(be.embedding.id as u32).unwrap().data,
+                be.embedding.data
+            );
+        }
+
+        // anything written from then on is normalized on the way in, and there's nothing left to do
+        let mut block = read_embedding_block(0).unwrap();
+        block.embeddings[0].data.iter_mut().for_each(|x| *x *= 3.0);
+        block.write_to(&get_data_dir().join("0")).unwrap();
+        assert!(is_normalized(
+            &read_embedding_block(0).unwrap().embeddings[0]
+        ));
+        assert_eq!(normalize_store(false).unwrap(), 0);
+
+        let entry = journal::last_entry().unwrap().unwrap();
+        assert_eq!(entry.operation, "normalize_store");
+        assert_eq!(entry.params["normalized"], 0);
+
+        // the other metrics compare the magnitudes that normalizing drops
+        write_file!(
+            crate::config::get_config_dir().join("config"),
+            "metric dot\n"
+        );
+        assert_eq!(
+            normalize_store(false).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+    }
+}
@@ -0,0 +1,122 @@
+// what an embedding is and what it's tagged with,
+// for both the index that keeps embeddings and the API client that makes them
+
+use serialize_macros::Serialize;
+
+use crate::serialization::Serialize;
+
+pub const EMBED_DIM: usize = 1536;
+
+// meta marker for the embedding of a file's path, rather than its contents
+pub const PATH_META: &str = "__path__";
+
+// meta marker for the embedding of a file's title,
+// which is blended into the scores of its chunks rather than returned itself
+pub const TITLE_META: &str = "__title__";
+
+// meta marker for chunks that are near-identical across many files,
+// like license headers and import blocks
+pub const BOILERPLATE_META: &str = "boilerplate";
+
+// meta tags that belong to a single chunk rather than the whole file,
+// which are kept when the file's meta is replaced with the ledger's
+pub fn is_chunk_meta(tag: &str) -> bool {
+    tag == PATH_META || tag == TITLE_META || tag == BOILERPLATE_META || tag.starts_with("lang:")
+}
+
+// whether a source is one of the embeddings made from something about a file,
+// like its path or title, rather than from its contents
+pub fn is_field_source(source: &EmbeddingSource) -> bool {
+    source.meta.contains(PATH_META) || source.meta.contains(TITLE_META)
+}
+
+// a uuid for a chunk made from its file, as stored, and `chunk_hash` of its contents,
+// so a chunk embedded again without changing keeps it whatever id the blocks give it
+//
+// the bytes are the start of a sha256, marked as a version 8 (custom) uuid
+pub fn stable_id(filepath: &str, chunk_hash: u64) -> String {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    hasher.update(filepath.as_bytes());
+    hasher.update([0]);
+    hasher.update(chunk_hash.to_be_bytes());
+    let mut bytes = hasher.finalize()[..16].to_vec();
+    bytes[6] = (bytes[6] & 0x0f) | 0x80;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+// the model a set of embeddings was made with
+// vectors from different models live in different spaces and can't be compared
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EmbeddingModel {
+    pub name: String,
+    pub dimensions: u32,
+}
+
+impl EmbeddingModel {
+    // the model new embeddings are made with
+    pub fn current() -> Self {
+        Self {
+            name: crate::config::get_embedding_model(),
+            dimensions: EMBED_DIM as u32,
+        }
+    }
+}
+
+impl std::fmt::Display for EmbeddingModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({} dimensions)", self.name, self.dimensions)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingSource {
+    pub filepath: String,
+    pub meta: std::collections::HashSet<String>,
+    pub subset: Option<(u64, u64)>,
+    // the ledger hash of the file when it was embedded,
+    // empty for sources that aren't files or were embedded before hashes were kept
+    pub hash: String,
+    // `parsing::chunk_hash` of the chunk's contents, to find it again after the file changes
+    pub chunk_hash: Option<u64>,
+}
+
+impl EmbeddingSource {
+    // `stable_id` of the chunk, which is made from what's already kept of it
+    // rather than written to the blocks, with the filepath as `paths` stores it
+    // so that it outlives moving the index along with `path_root`
+    //
+    // `None` for sources that aren't chunks, and chunks from before their hashes were kept
+    pub fn stable_id(&self, paths: &crate::config::PathResolver) -> Option<String> {
+        if is_field_source(self) {
+            return None;
+        }
+
+        let chunk_hash = self.chunk_hash?;
+        Some(stable_id(&paths.store(&self.filepath), chunk_hash))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Embedding {
+    pub id: u64,
+    pub source_file: EmbeddingSource,
+    // unix seconds of when the embedding came back from the API,
+    // 0 if that isn't known, like for blocks written before it was kept
+    pub embedded_at: i64,
+    pub data: [f32; EMBED_DIM],
+}
@@ -0,0 +1,4322 @@
+// searching the index, and the server and local handles that search it
+
+use std::io::Write;
+
+use crate::embedding::{Embedding, EmbeddingModel, EmbeddingSource};
+use crate::hnsw::{Filter, Query, HNSW};
+use crate::logger::Logger;
+use crate::message::{
+    DeweyEditResponse, DeweyErrorResponse, DeweyFileInfoResponse, DeweyFlushResponse, DeweyRequest,
+    DeweyResponse, DeweyResponseGroup, DeweyResponseItem, DeweyStatsResponse, Granularity, GroupBy,
+    GroupScore, MaintenanceOutcome, MaintenanceRun, QueryCacheStats, RequestPayload, ServerHealth,
+};
+use crate::openai::{embed_text, is_network_error};
+use crate::{
+    cache, config, dbio, embedding, hnsw, housekeeping, journal, ledger, lock, parsing, sync,
+    trace, SearchOptions,
+};
+use crate::{error, info, lprint};
+
+// how many recent queries a server keeps the results of
+const QUERY_CACHE_SIZE: usize = 128;
+
+// how many chunks each group of a grouped query shows
+const GROUP_CHUNKS: usize = 3;
+
+// the most candidates a query is searched for, however far it pages
+const MAX_CANDIDATES: usize = 1000;
+
+// boilerplate chunks have their similarity scaled down by this,
+// since they're similar to everything and would otherwise crowd out
+// the content that's actually relevant
+const BOILERPLATE_WEIGHT: f32 = 0.8;
+
+// candidates are sorted closest-first, and stay that way
+//
+// euclidean distances have no similarity to scale, so they're stretched instead
+fn penalize_boilerplate(candidates: &mut [(Box<Embedding>, f32)], metric: hnsw::Metric) {
+    for (e, distance) in candidates.iter_mut() {
+        if e.source_file.meta.contains(embedding::BOILERPLATE_META) {
+            *distance = match metric {
+                hnsw::Metric::L2 => *distance / BOILERPLATE_WEIGHT,
+                _ => 1.0 - (1.0 - *distance) * BOILERPLATE_WEIGHT,
+            };
+        }
+    }
+
+    candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+}
+
+// each chunk's distance is moved `weight` of the way toward its file's title's,
+// so that the chunks of files whose titles match the query rank higher
+//
+// path embeddings and the chunks of files embedded without a title are left as they are
+fn blend_titles(
+    candidates: &mut [(Box<Embedding>, f32)],
+    query: &Embedding,
+    weight: f32,
+    metric: hnsw::Metric,
+    blocks: &cache::BlockStore,
+) -> Result<(), std::io::Error> {
+    let mut view = blocks.view()?;
+    let mut titles = std::collections::HashMap::new();
+    for (e, distance) in candidates.iter_mut() {
+        if embedding::is_field_source(&e.source_file) {
+            continue;
+        }
+
+        let title = match titles.get(&e.source_file.filepath) {
+            Some(title) => *title,
+            None => {
+                let title = view
+                    .title(&e.source_file.filepath)?
+                    .map(|title| hnsw::distance(query, &title, metric));
+                titles.insert(e.source_file.filepath.clone(), title);
+                title
+            }
+        };
+
+        if let Some(title) = title {
+            *distance = (1.0 - weight) * *distance + weight * title;
+        }
+    }
+
+    Ok(())
+}
+
+// the cosine similarity past which `--dedupe` takes a result for a copy of one ranked ahead of it
+pub const DEFAULT_DEDUPE_THRESHOLD: f32 = 0.97;
+
+// drops every candidate more similar than `threshold` to one that's kept ahead of it,
+// so the candidates behind it move up to fill the page
+//
+// candidates are compared by angle whatever the index's metric,
+// since copies of a chunk can be embedded a little differently
+fn dedupe(candidates: &mut Vec<(Box<Embedding>, f32)>, threshold: f32) {
+    let similarity =
+        |a: &Embedding, b: &Embedding| hnsw::dot(a, b) / (hnsw::dot(a, a) * hnsw::dot(b, b)).sqrt();
+
+    let mut kept: Vec<(Box<Embedding>, f32)> = Vec::with_capacity(candidates.len());
+    for candidate in candidates.drain(..) {
+        if kept
+            .iter()
+            .all(|(k, _)| similarity(k, &candidate.0) <= threshold)
+        {
+            kept.push(candidate);
+        }
+    }
+
+    *candidates = kept;
+}
+
+// parses every filter rather than stopping at the first bad one,
+// returning the filters that didn't parse as the error
+fn parse_filters(filters: &[String]) -> Result<Vec<Filter>, Vec<String>> {
+    let mut parsed = Vec::new();
+    let mut invalid = Vec::new();
+    for filter in filters {
+        match Filter::from_string(filter) {
+            Ok(f) => parsed.push(f),
+            Err(e) => {
+                error!("{}", e);
+                invalid.push(filter.clone());
+            }
+        }
+    }
+
+    if invalid.is_empty() {
+        Ok(parsed)
+    } else {
+        Err(invalid)
+    }
+}
+
+// queries that differ only in whitespace share an entry,
+// as do the pages of a query, which are all cut from the same results
+//
+// snapshot collections are keyed by the snapshot their label found,
+// since a newer snapshot can take the label over
+//
+// `meta_written` is when the ledger was written, for queries checked against its current meta,
+// whether for acls or, with `live_meta`, for filters
+fn query_cache_key(
+    query: &str,
+    options: &SearchOptions,
+    store: &config::DataPaths,
+    meta_written: Option<std::time::SystemTime>,
+    live_meta: bool,
+) -> String {
+    let query = query.split_whitespace().collect::<Vec<_>>().join(" ");
+    let meta_written = meta_written.map(|written| {
+        written
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    });
+    serde_json::json!([
+        query,
+        options.filters,
+        options.exclude_paths,
+        options.group_by,
+        options.group_score,
+        options.include_boilerplate,
+        options.model,
+        options.mode,
+        options.granularity,
+        options.paths,
+        options.dedupe_threshold,
+        options.acls,
+        store.snapshot,
+        meta_written,
+        live_meta,
+    ])
+    .to_string()
+}
+
+// collections other than the live index are named `<kind>:<name>`,
+// and `snapshot:<label>` is the snapshot `label` finds, the same one `--rollback` would
+pub const SNAPSHOT_COLLECTION: &str = "snapshot:";
+
+// the store of the snapshot `collection` names, `None` for the live index
+fn collection_store(collection: Option<&str>) -> Result<Option<config::DataPaths>, std::io::Error> {
+    let collection = match collection {
+        Some(collection) => collection,
+        None => return Ok(None),
+    };
+
+    match collection.strip_prefix(SNAPSHOT_COLLECTION) {
+        Some(label) => Ok(Some(
+            config::get_paths().snapshot(&dbio::find_snapshot(label)?),
+        )),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "unknown collection {:?}, collections are named {}<label>",
+                collection, SNAPSHOT_COLLECTION
+            ),
+        )),
+    }
+}
+
+// snapshots are only there to be searched
+fn snapshot_edit_error(
+    collection: Option<&str>,
+) -> Result<Option<DeweyErrorResponse>, std::io::Error> {
+    Ok(collection_store(collection)?.map(|snapshot| {
+        error_response(
+            "read_only_collection",
+            format!(
+                "snapshot {} can't be edited",
+                snapshot.snapshot.unwrap_or_default()
+            ),
+        )
+    }))
+}
+
+// snapshots only have chunks, without the centroids of their files
+fn check_snapshot_options(options: &SearchOptions) -> Result<(), std::io::Error> {
+    if options.granularity == Granularity::File {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "snapshots don't keep the centroids of their files, search them by chunk",
+        ));
+    }
+
+    Ok(())
+}
+
+// an error along with the state generation it was made in,
+// which is left at 0 if even that can't be read
+pub fn error_response(error: &str, message: String) -> DeweyErrorResponse {
+    DeweyErrorResponse {
+        generation: dbio::read_state_generation().unwrap_or_default(),
+        ..DeweyErrorResponse::new(error, message)
+    }
+}
+
+// the response to a request, unless it was pinned to a `stale` generation
+fn respond(
+    stale: Result<Option<DeweyErrorResponse>, std::io::Error>,
+    handle: impl FnOnce() -> Result<String, std::io::Error>,
+) -> String {
+    let response = stale.and_then(|stale| match stale {
+        Some(stale) => serde_json::to_string(&stale).map_err(std::io::Error::other),
+        None => handle(),
+    });
+
+    response.unwrap_or_else(|e| {
+        error!("Error handling client: {}", e);
+        serde_json::to_string(&error_response("request_failed", e.to_string())).unwrap()
+    })
+}
+
+fn invalid_filters_message(invalid: &[String]) -> String {
+    format!("invalid filters {:?}, expected \"[eq|ne] value\"", invalid)
+}
+
+fn not_embedded(model: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("nothing has been indexed with {} yet", model),
+    )
+}
+
+fn response_item(
+    embedding: &Embedding,
+    distance: f32,
+    paths: &config::PathResolver,
+) -> DeweyResponseItem {
+    DeweyResponseItem {
+        filepath: embedding.source_file.filepath.clone(),
+        subset: embedding.source_file.subset.unwrap_or_default(),
+        score: 1.0 - distance,
+        path_match: embedding
+            .source_file
+            .meta
+            .contains(crate::embedding::PATH_META),
+        stale: false,
+        file_match: false,
+        embedded_at: embedding.embedded_at,
+        stable_id: embedding.source_file.stable_id(paths),
+    }
+}
+
+// flags the items whose file changed since they were embedded,
+// moving each one's subset to wherever its chunk can still be found
+//
+// `candidates` are the embeddings the items were made from
+fn mark_stale<'a>(
+    items: impl Iterator<Item = &'a mut DeweyResponseItem>,
+    candidates: &[(Box<Embedding>, f32)],
+) {
+    let sources = candidates
+        .iter()
+        .map(|(e, _)| {
+            (
+                (e.source_file.filepath.as_str(), e.source_file.subset),
+                &e.source_file,
+            )
+        })
+        .collect::<std::collections::HashMap<_, _>>();
+
+    // each file is read once, as its current hash and contents
+    let mut files: std::collections::HashMap<String, Option<(String, String)>> =
+        std::collections::HashMap::new();
+    for item in items {
+        if item.path_match {
+            continue;
+        }
+
+        let source = match sources.get(&(item.filepath.as_str(), Some(item.subset))) {
+            Some(source) if !source.hash.is_empty() => source,
+            _ => continue,
+        };
+
+        let current = files.entry(item.filepath.clone()).or_insert_with(|| {
+            let bytes = std::fs::read(&item.filepath).ok()?;
+            let hash = ledger::hash_contents(&bytes);
+            Some((hash, String::from_utf8_lossy(&bytes).to_string()))
+        });
+
+        let (hash, contents) = match current {
+            Some((hash, _)) if *hash == source.hash => continue,
+            Some(current) => current,
+            None => {
+                item.stale = true;
+                continue;
+            }
+        };
+
+        info!(
+            "{} changed since it was embedded ({} -> {})",
+            item.filepath, source.hash, hash
+        );
+        item.stale = true;
+
+        let (start, end) = item.subset;
+        let length = (end - start) as usize;
+        if let Some(chunk_hash) = source.chunk_hash {
+            if let Some(found) = parsing::find_chunk(contents, length, chunk_hash, start as usize) {
+                item.subset = (found as u64, (found + length) as u64);
+            }
+        }
+    }
+}
+
+fn group_key(filepath: &str, group_by: &GroupBy) -> String {
+    let depth = match group_by {
+        GroupBy::File => return filepath.to_string(),
+        GroupBy::Directory { depth } => *depth,
+    };
+
+    let path = std::path::Path::new(filepath);
+    let (base, relative) = match path.strip_prefix(config::get_home_dir()) {
+        Ok(relative) => (config::get_home_dir(), relative),
+        Err(_) => (std::path::PathBuf::new(), path),
+    };
+
+    // the file itself never counts toward the depth
+    let directories = relative
+        .parent()
+        .map(|p| p.components())
+        .into_iter()
+        .flatten();
+
+    let mut key = base;
+    for component in directories.take(depth) {
+        key.push(component);
+    }
+
+    key.to_string_lossy().to_string()
+}
+
+// groups query candidates, sorted closest-first, into at most `k` groups, best first
+fn group_results(
+    candidates: &[(Box<Embedding>, f32)],
+    group_by: &GroupBy,
+    group_score: GroupScore,
+    k: usize,
+) -> Vec<DeweyResponseGroup> {
+    let mut groups: Vec<(String, Vec<DeweyResponseItem>)> = Vec::new();
+    let mut positions = std::collections::HashMap::new();
+    let paths = config::PathResolver::current();
+    for (embedding, distance) in candidates.iter() {
+        let key = group_key(&embedding.source_file.filepath, group_by);
+        let position = *positions.entry(key.clone()).or_insert_with(|| {
+            groups.push((key, Vec::new()));
+            groups.len() - 1
+        });
+
+        groups[position]
+            .1
+            .push(response_item(embedding, *distance, &paths));
+    }
+
+    let mut groups = groups
+        .into_iter()
+        .map(|(key, mut chunks)| {
+            let score = match group_score {
+                GroupScore::Max => chunks.iter().map(|c| c.score).fold(f32::MIN, f32::max),
+                GroupScore::Mean => {
+                    chunks.iter().map(|c| c.score).sum::<f32>() / chunks.len() as f32
+                }
+            };
+
+            chunks.truncate(GROUP_CHUNKS);
+            DeweyResponseGroup {
+                key,
+                score,
+                top_chunks: chunks,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    groups.sort_by(|a, b| b.score.total_cmp(&a.score));
+    groups.truncate(k);
+
+    groups
+}
+
+// writes `query` to a new file in the queries directory, returning its path
+//
+// the random suffix keeps queries made in the same microsecond from sharing a file,
+// and the file is created fresh so that they couldn't overwrite each other anyway
+fn save_query(query: &str) -> Result<std::path::PathBuf, std::io::Error> {
+    let queries_dir = config::get_queries_dir();
+    std::fs::create_dir_all(&queries_dir)?;
+
+    let (path, mut file) = loop {
+        let name = format!(
+            "{}-{:08x}",
+            chrono::Utc::now().timestamp_micros(),
+            rand::random::<u32>()
+        );
+        let path = queries_dir.join(name);
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => break (path, file),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                error!("error creating query file {}: {}", path.display(), e);
+                return Err(e);
+            }
+        }
+    };
+
+    if let Err(e) = file
+        .write_all(query.as_bytes())
+        .and_then(|_| file.sync_all())
+    {
+        error!("error writing query to file {}: {}", path.display(), e);
+        return Err(e);
+    }
+
+    info!("Wrote query to {}", path.display());
+
+    sweep_queries(config::get_query_retention())?;
+
+    Ok(path)
+}
+
+// deletes the oldest saved queries past `retention`
+//
+// query names start with their timestamp, so sorting them sorts by age
+fn sweep_queries(retention: usize) -> Result<(), std::io::Error> {
+    let mut queries = std::fs::read_dir(config::get_queries_dir())?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+
+    if queries.len() <= retention {
+        return Ok(());
+    }
+
+    queries.sort();
+    for path in queries.iter().take(queries.len() - retention) {
+        match std::fs::remove_file(path) {
+            Ok(_) => {}
+            // another query's sweep got to it first
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                error!("error deleting saved query {}: {}", path.display(), e);
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl SearchOptions {
+    // the candidates the index is searched for, enough to reach the end of the page
+    fn ef(&self) -> usize {
+        self.mode.ef().max(self.offset + self.k).min(MAX_CANDIDATES)
+    }
+}
+
+// cuts the page `options` asks for out of every result of a query
+fn page(mut response: DeweyResponse, options: &SearchOptions) -> DeweyResponse {
+    let window = |total: usize| {
+        let start = options.offset.min(total);
+        (start, (start + options.k).min(total))
+    };
+
+    let grouped = !response.groups.is_empty();
+    let total = response.results.len().max(response.groups.len());
+    let (start, end) = window(total);
+
+    if grouped {
+        response.groups = response.groups.drain(start..end).collect();
+    } else {
+        response.results = response.results.drain(start..end).collect();
+        if let Some(trace) = response.trace.as_mut() {
+            let (start, end) = window(trace.distances.len());
+            trace.distances = trace.distances.drain(start..end).collect();
+        }
+    }
+
+    DeweyResponse {
+        total_candidates: total,
+        has_more: end < total,
+        ..response
+    }
+}
+
+// all server operations should go through this arc-rwlocked state
+// this is needed for thread safety with the addition of db-altering operations
+//
+// queries, file info, and stats only read it, and are handled alongside each other
+// with `handle_shared`, while edits and flushes need it to themselves
+//
+// edits are queued until a file's edits have gone quiet, and then it's embedded again once
+// with `reindex_pending` (on a timer, in the server), unless they ask to be done right away
+//
+// edits only change the index in memory and mark it dirty,
+// and it's written back out with `flush_index` (on a timer, in the server)
+//
+// anything that changes what can be searched bumps the state generation
+// (`dbio::read_state_generation`), which retires the cached query results
+// and turns away requests pinned to the old one
+pub struct ServerState {
+    // the index of the configured model's store
+    index: hnsw::HNSW,
+    // the indexes of the stores of models that extensions are routed to with `--model`,
+    // keyed by model
+    routed: std::collections::HashMap<String, hnsw::HNSW>,
+    dirty: bool,
+    index_writes: u64,
+    // the files of edits waiting to go quiet, by filepath
+    pending_edits: std::collections::HashMap<String, PendingEdit>,
+    edit_quiet_period: std::time::Duration,
+    // files embedded again for edits
+    reembeds: u64,
+    query_cache: std::sync::Mutex<cache::QueryCache>,
+    query_embeddings: std::sync::Mutex<cache::QueryEmbeddings>,
+    centroids: std::sync::Mutex<CentroidCache>,
+    meta_indexes: std::sync::Mutex<MetaIndexCache>,
+    // what filters are checked against with `live_meta`
+    ledger_meta: cache::LedgerMeta,
+    // the blocks queries read, shared by every query running at the same time
+    blocks: cache::BlockStore,
+    // the indexes of the snapshots that have been searched, by name
+    snapshots: std::sync::Mutex<std::collections::HashMap<String, std::sync::Arc<HNSW>>>,
+    maintenance: Option<MaintenanceRun>,
+    health: std::sync::Mutex<ServerHealth>,
+}
+
+// the file centroids of each store, by their directory,
+// along with when the directory was written so they're read again once it changes
+type CentroidCache = std::collections::HashMap<
+    std::path::PathBuf,
+    (std::time::SystemTime, std::sync::Arc<Vec<Embedding>>),
+>;
+
+// the edits of a file that haven't been embedded yet
+struct PendingEdit {
+    // when the last of them came in
+    requested: std::time::Instant,
+    // every range they changed, or `None` once any of them changed the whole file
+    ranges: Option<Vec<(u64, u64)>>,
+}
+
+impl PendingEdit {
+    fn merge(&mut self, ranges: Option<Vec<(u64, u64)>>) {
+        self.requested = std::time::Instant::now();
+        self.ranges = match (self.ranges.take(), ranges) {
+            (Some(mut pending), Some(ranges)) => {
+                pending.extend(ranges);
+                Some(pending)
+            }
+            _ => None,
+        };
+    }
+}
+
+// the meta index of each store, by its data directory,
+// along with the state generation it was read in so it's read again once that moves on
+type MetaIndexCache =
+    std::collections::HashMap<std::path::PathBuf, (u64, Option<std::sync::Arc<dbio::MetaIndex>>)>;
+
+impl ServerState {
+    pub fn new() -> Result<Self, std::io::Error> {
+        for store in config::get_paths().model_stores()? {
+            if store.scope(dbio::needs_normalizing)? {
+                lprint!(
+                    error,
+                    "warning: the embeddings in {} aren't normalized, so searches can measure the same pair differently; run `dewey --normalize-store` to fix them",
+                    store.scope(config::get_data_dir).display()
+                );
+            }
+        }
+
+        let mut state = {
+            let _lock = lock::DataLock::acquire(lock::LockMode::Shared, "server")?;
+            Self::with_index(HNSW::new(false)?)
+        };
+
+        for store in config::get_paths().model_stores()?.into_iter().skip(1) {
+            let index = store.scope(|| match config::get_data_dir().join("index").exists() {
+                true => {
+                    let _lock = lock::DataLock::acquire(lock::LockMode::Shared, "server")?;
+                    HNSW::new(false).map(Some)
+                }
+                false => Ok(None),
+            })?;
+
+            if let (Some(model), Some(index)) = (store.model, index) {
+                state.routed.insert(model, index);
+            }
+        }
+
+        Ok(state)
+    }
+
+    pub(crate) fn with_index(index: HNSW) -> Self {
+        Self {
+            index,
+            routed: std::collections::HashMap::new(),
+            dirty: false,
+            index_writes: 0,
+            pending_edits: std::collections::HashMap::new(),
+            edit_quiet_period: config::get_edit_quiet_period(),
+            reembeds: 0,
+            query_cache: std::sync::Mutex::new(cache::QueryCache::new(
+                QUERY_CACHE_SIZE,
+                config::get_query_cache_ttl(),
+            )),
+            query_embeddings: std::sync::Mutex::new(cache::QueryEmbeddings::new(QUERY_CACHE_SIZE)),
+            centroids: std::sync::Mutex::new(std::collections::HashMap::new()),
+            meta_indexes: std::sync::Mutex::new(std::collections::HashMap::new()),
+            ledger_meta: cache::LedgerMeta::default(),
+            blocks: cache::BlockStore::new(hnsw::QUERY_BLOCKS),
+            snapshots: std::sync::Mutex::new(std::collections::HashMap::new()),
+            maintenance: None,
+            health: std::sync::Mutex::new(ServerHealth::default()),
+        }
+    }
+
+    // counts a request handled with the state a panicking handler left behind
+    fn note_degraded(&self) {
+        self.health
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .degraded_requests += 1;
+    }
+
+    // marks the server unhealthy after a request handler panicked with `message`
+    fn record_panic(&self, message: String) {
+        let mut health = self
+            .health
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+        health.healthy = false;
+        health.degraded_requests += 1;
+        health.last_panic = Some(message);
+    }
+
+    // writes the index to disk if it's changed since it was last written
+    // returns whether anything was written
+    pub fn flush_index(&mut self) -> Result<bool, std::io::Error> {
+        if !self.dirty {
+            return Ok(false);
+        }
+
+        {
+            let _lock = lock::DataLock::acquire(lock::LockMode::Exclusive, "flush_index")?;
+            self.index
+                .serialize(&config::get_data_dir().join("index"))?;
+            dbio::prune_tombstones(&self.index)?;
+        }
+
+        for (model, index) in self.routed.iter() {
+            config::get_paths().for_model(model).scope(|| {
+                let _lock = lock::DataLock::acquire(lock::LockMode::Exclusive, "flush_index")?;
+                index.serialize(&config::get_data_dir().join("index"))?;
+                dbio::prune_tombstones(index)
+            })?;
+        }
+
+        self.dirty = false;
+        self.index_writes += 1;
+
+        Ok(true)
+    }
+
+    // answers a server request with its serialized response, which is an error response if it failed
+    //
+    // a request pinned to a generation other than the current one gets `stale_generation`,
+    // since whatever the client got before it may have changed since
+    pub fn handle(&mut self, request: DeweyRequest) -> String {
+        if Self::is_shared(&request) {
+            return self.handle_shared(request);
+        }
+
+        let stale = self.check_generation(request.expect_generation);
+        respond(stale, || match request.message_type.as_str() {
+            "edit" => self.edit(request.payload),
+            // lets tests see what's left of the server after a handler panics holding the state
+            #[cfg(any(test, feature = "regression"))]
+            "panic" => panic!("panic requested"),
+            _ => self.flush(),
+        })
+    }
+
+    // whether `handle_shared` can take the request, which is everything but edits and flushes
+    // (and the panics that tests ask for)
+    pub fn is_shared(request: &DeweyRequest) -> bool {
+        let panic = cfg!(any(test, feature = "regression")) && request.message_type == "panic";
+        !(panic || matches!(request.message_type.as_str(), "edit" | "flush"))
+    }
+
+    pub fn handle_shared(&self, request: DeweyRequest) -> String {
+        let stale = self.check_generation(request.expect_generation);
+        respond(stale, || match request.message_type.as_str() {
+            "query" => self.query(request.payload, request.token.as_deref()),
+            "file_info" => self.file_info(request.payload, request.token.as_deref()),
+            "stats" => self.stats(),
+            "edit" | "flush" => Err(std::io::Error::other(format!(
+                "{} requests can't be handled alongside others",
+                request.message_type
+            ))),
+            _ => serde_json::to_string(&error_response(
+                "invalid_message_type",
+                format!("Invalid message_type: {}", request.message_type),
+            ))
+            .map_err(std::io::Error::other),
+        })
+    }
+
+    // answers a request with the state taken from `state`, for as long or as widely as it needs it
+    //
+    // a handler that panicked while holding the state leaves it poisoned,
+    // but nothing it was doing leaves the index half-written, so the rest carry on,
+    // counted as degraded in the stats, and the one that panicked gets an `internal_error`
+    //
+    // requests that only read the state are handled alongside each other
+    pub fn handle_locked(state: &std::sync::RwLock<Self>, request: DeweyRequest) -> String {
+        let trace_id = request.trace_id.clone();
+        trace::in_trace(trace_id.as_deref(), || {
+            let mut span = trace::Span::start("dewey.request");
+            span.set("message_type", request.message_type.as_str());
+
+            Self::handle_caught(state, request)
+        })
+    }
+
+    fn handle_caught(state: &std::sync::RwLock<Self>, request: DeweyRequest) -> String {
+        let response = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            match Self::is_shared(&request) {
+                true => {
+                    let state = state.read().unwrap_or_else(|poisoned| {
+                        let state = poisoned.into_inner();
+                        state.note_degraded();
+                        state
+                    });
+
+                    state.handle_shared(request)
+                }
+                false => {
+                    let mut state = state.write().unwrap_or_else(|poisoned| {
+                        let state = poisoned.into_inner();
+                        state.note_degraded();
+                        state
+                    });
+
+                    state.handle(request)
+                }
+            }
+        }));
+
+        match response {
+            Ok(response) => response,
+            Err(panic) => {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|m| m.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| String::from("unknown panic"));
+                error!("request handler panicked: {}", message);
+                state
+                    .read()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner)
+                    .record_panic(message.clone());
+
+                serde_json::to_string(&error_response(
+                    "internal_error",
+                    format!("the server failed while handling the request: {}", message),
+                ))
+                .unwrap()
+            }
+        }
+    }
+
+    fn check_generation(
+        &self,
+        expected: Option<u64>,
+    ) -> Result<Option<DeweyErrorResponse>, std::io::Error> {
+        let current = dbio::read_state_generation()?;
+        match expected {
+            Some(expected) if expected != current => {
+                info!("request expected generation {}, at {}", expected, current);
+                Ok(Some(error_response(
+                    "stale_generation",
+                    format!(
+                        "the index changed since generation {}, and is at generation {}",
+                        expected, current
+                    ),
+                )))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // forces pending edits out to disk, embedding any that are still queued first
+    pub fn flush(&mut self) -> Result<String, std::io::Error> {
+        self.reindex_pending(true);
+
+        let response = DeweyFlushResponse {
+            flushed: self.flush_index()?,
+            index_writes: self.index_writes,
+            index_nodes: self.index.get_last_layer().len(),
+            generation: dbio::read_state_generation()?,
+            reembeds: self.reembeds,
+        };
+
+        serde_json::to_string(&response).map_err(std::io::Error::other)
+    }
+
+    // results are limited to the acls `token` grants
+    pub fn query(
+        &self,
+        payload: RequestPayload,
+        token: Option<&str>,
+    ) -> Result<String, std::io::Error> {
+        let (query, options) = match payload {
+            RequestPayload::Query {
+                query,
+                filters,
+                k,
+                offset,
+                exclude_paths,
+                group_by,
+                group_score,
+                discard_query,
+                include_boilerplate,
+                no_cache,
+                debug,
+                model,
+                search_mode,
+                granularity,
+                paths,
+                collection,
+                dedupe_threshold,
+            } => (
+                query,
+                SearchOptions {
+                    k,
+                    offset,
+                    filters,
+                    exclude_paths,
+                    group_by,
+                    group_score,
+                    save_query: !discard_query,
+                    include_boilerplate,
+                    no_cache,
+                    debug,
+                    model,
+                    mode: search_mode,
+                    granularity,
+                    paths,
+                    collection,
+                    dedupe_threshold,
+                    acls: Some(config::get_token_acls(token)),
+                },
+            ),
+            _ => {
+                error!("malformed query request: {:?}", payload);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "malformed query request",
+                ));
+            }
+        };
+
+        info!("payload unpacked");
+
+        // bad filters are the client's mistake, and get a response saying which ones they were
+        if let Err(invalid) = parse_filters(&options.filters) {
+            let mut response = error_response("invalid_filter", invalid_filters_message(&invalid));
+            response.invalid_filters = invalid;
+
+            return serde_json::to_string(&response).map_err(std::io::Error::other);
+        }
+
+        let response = match self.search(&query, &options) {
+            Ok(response) => response,
+            // the embedding API being down gets its own error, to tell it apart from a failed query
+            Err(e) if e.kind() == std::io::ErrorKind::NetworkUnreachable => {
+                let response = error_response("embedding_unavailable", e.to_string());
+                return serde_json::to_string(&response).map_err(std::io::Error::other);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let response = match serde_json::to_string(&response) {
+            Ok(serialized_response) => serialized_response,
+            Err(e) => {
+                error!("Failed to serialize response: {}", e);
+                return Err(std::io::Error::other(e));
+            }
+        };
+
+        Ok(response)
+    }
+
+    // runs a query against the index, for both server requests and local CLI queries
+    //
+    // filters follow `hnsw::Filter`'s syntax
+    //
+    // grouped queries group every candidate the index turns up, and return `k` groups
+    //
+    // the `k` results or groups start `offset` in, and search the index for enough candidates
+    // to reach them, up to `MAX_CANDIDATES`
+    pub fn search(
+        &self,
+        query: &str,
+        options: &SearchOptions,
+    ) -> Result<DeweyResponse, std::io::Error> {
+        // history keeps the query as it was sent,
+        // but what's embedded and cached is the query without its boilerplate
+        let raw = query;
+        let query = &parsing::preprocess_query(raw);
+        if query != raw {
+            info!("query rules cut the query down to {:?}", query);
+        }
+
+        let filters = parse_filters(&options.filters).map_err(|invalid| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                invalid_filters_message(&invalid),
+            )
+        })?;
+
+        // similarities run from -1 to 1, and anything else would keep or drop every result
+        if let Some(threshold) = options.dedupe_threshold {
+            if !(-1.0..=1.0).contains(&threshold) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("dedupe threshold {} isn't between -1 and 1", threshold),
+                ));
+            }
+        }
+
+        let store = match collection_store(options.collection.as_deref())? {
+            // a snapshot keeps whichever model it was taken with,
+            // so a query can ask for an older one to compare against the current store
+            Some(snapshot) => {
+                check_snapshot_options(options)?;
+                config::DataPaths {
+                    model: options
+                        .model
+                        .clone()
+                        .filter(|model| *model != config::get_embedding_model()),
+                    ..snapshot
+                }
+            }
+            None => config::get_paths().for_model(
+                &options
+                    .model
+                    .clone()
+                    .unwrap_or_else(config::get_embedding_model),
+            ),
+        };
+
+        // a bulk embed leaves the index without its newest blocks until it's rebuilt,
+        // which can happen after a response was cached
+        let index_behind = store.scope(dbio::read_generation)?.index_behind();
+        if index_behind {
+            error!(
+                "warning: the index is behind its blocks, rebuild it with -r to search everything"
+            );
+        }
+
+        // with `live_meta`, filters see tag changes in the ledger before a reblock does,
+        // though snapshots keep the meta they were taken with
+        //
+        // acls are always checked against the ledger as well, so that tagging a file `--acl`
+        // hides it right away rather than once it's embedded again
+        let live_meta = config::get_live_meta() && !filters.is_empty() && store.snapshot.is_none();
+        let ledger_meta = match live_meta || options.acls.is_some() {
+            true => Some(self.ledger_meta.get()?),
+            false => None,
+        };
+
+        // a cached response skips both the embedding request and the search
+        //
+        // traced queries always search, since there's no trace to give back otherwise
+        let key = query_cache_key(
+            query,
+            options,
+            &store,
+            ledger_meta.as_ref().map(|(written, _)| *written),
+            live_meta,
+        );
+        let ef = options.ef();
+        let generation = dbio::read_state_generation()?;
+        if !options.no_cache && !options.debug {
+            let cached = self
+                .query_cache
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .get(&key, generation, ef);
+
+            if let Some(response) = cached {
+                info!("query answered from the cache");
+                if options.save_query {
+                    save_query(raw)?;
+                }
+
+                return Ok(page(
+                    DeweyResponse {
+                        index_behind,
+                        generation,
+                        ..response
+                    },
+                    options,
+                ));
+            }
+        }
+
+        let snapshot_index;
+        let index = match &store.snapshot {
+            Some(name) => {
+                snapshot_index = self.snapshot_index(&store, name)?;
+                &*snapshot_index
+            }
+            None => self.index_of(&store)?,
+        };
+
+        let acl_meta = ledger_meta.map(|(_, meta)| meta);
+        let meta = acl_meta.clone().filter(|_| live_meta);
+        let mut response = store
+            .scope(|| self.search_index(index, raw, query, options, filters, meta, acl_meta))?;
+        response.index_behind = index_behind;
+        response.generation = generation;
+
+        // degraded results are only a stand-in until the embedding API is back
+        //
+        // every result is cached, so the query's other pages can be cut from it
+        if !response.degraded && !options.debug {
+            self.query_cache
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .insert(key, generation, ef, response.clone());
+        }
+
+        Ok(page(response, options))
+    }
+
+    // the index of snapshot `name`, read from `store` the first time it's searched
+    //
+    // snapshots never change, so they're kept for as long as the server is up
+    fn snapshot_index(
+        &self,
+        store: &config::DataPaths,
+        name: &str,
+    ) -> Result<std::sync::Arc<HNSW>, std::io::Error> {
+        let mut snapshots = self
+            .snapshots
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if let Some(index) = snapshots.get(name) {
+            return Ok(index.clone());
+        }
+
+        let index = store.scope(|| {
+            let _lock = lock::DataLock::acquire(lock::LockMode::Shared, "snapshot")?;
+            HNSW::new(false)
+        })?;
+
+        info!("opened snapshot {} with {} nodes", name, index.size);
+        let index = std::sync::Arc::new(index);
+        snapshots.insert(name.to_string(), index.clone());
+
+        Ok(index)
+    }
+
+    // the index of `store`, which has to have been embedded into
+    fn index_of(&self, store: &config::DataPaths) -> Result<&HNSW, std::io::Error> {
+        match &store.model {
+            None => Ok(&self.index),
+            Some(model) => self.routed.get(model).ok_or_else(|| not_embedded(model)),
+        }
+    }
+
+    fn index_of_mut(&mut self, store: &config::DataPaths) -> Result<&mut HNSW, std::io::Error> {
+        match &store.model {
+            None => Ok(&mut self.index),
+            Some(model) => self
+                .routed
+                .get_mut(model)
+                .ok_or_else(|| not_embedded(model)),
+        }
+    }
+
+    // builds the index of a store that didn't have one, if it has enough in it now
+    fn load_store(&mut self, store: &config::DataPaths) -> Result<(), std::io::Error> {
+        if let (Some(model), Some(index)) = (&store.model, store.scope(dbio::build_index)?) {
+            self.routed.insert(model.clone(), index);
+        }
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn search_index(
+        &self,
+        index: &HNSW,
+        raw: &str,
+        query: &str,
+        options: &SearchOptions,
+        filters: Vec<Filter>,
+        meta: Option<std::sync::Arc<cache::FileMeta>>,
+        acl_meta: Option<std::sync::Arc<cache::FileMeta>>,
+    ) -> Result<DeweyResponse, std::io::Error> {
+        // blocks are read through the cache as the index is searched
+        let _lock = lock::DataLock::acquire(lock::LockMode::Shared, "query")?;
+
+        // a query embedded with a different model than the index's can't be compared against it
+        let model = EmbeddingModel::current();
+        if index.model != model {
+            error!(
+                "index was built with {}, but queries are embedded with {}",
+                index.model, model
+            );
+
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                match options.model {
+                    Some(_) => format!(
+                        "index was built with {}, but the query asked for {}",
+                        index.model.name, model.name
+                    ),
+                    None => format!(
+                        "index was built with {}, but the configured model is {}; re-embed with -f and rebuild the index",
+                        index.model.name, model.name
+                    ),
+                },
+            ));
+        }
+
+        // distances under one metric mean nothing under another
+        let metric = hnsw::Metric::current();
+        if index.metric != metric {
+            error!(
+                "index was built with the {} metric, but the configured metric is {}",
+                index.metric, metric
+            );
+
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "index was built with the {} metric, but the configured metric is {}; rebuild the index",
+                    index.metric, metric
+                ),
+            ));
+        }
+
+        let mut source = EmbeddingSource {
+            filepath: String::new(),
+            meta: std::collections::HashSet::new(),
+            subset: None,
+            hash: String::new(),
+            chunk_hash: None,
+        };
+
+        if options.save_query {
+            source.filepath = save_query(raw)?.to_string_lossy().to_string();
+        }
+
+        // chunks can be normalized before they're embedded, so queries are too
+        let normalized = parsing::normalize_query(query);
+        let (embedding, degraded) = self.embed_query(&source, &normalized)?;
+
+        // a query limited to some files only searches through their chunks
+        let (scope, unknown_paths) = match &options.paths {
+            Some(paths) => {
+                let mut scope = dbio::resolve_paths(paths)?;
+                if !scope.unknown.is_empty() {
+                    info!("query paths matched no files: {:?}", scope.unknown);
+                }
+
+                let unknown = std::mem::take(&mut scope.unknown);
+                (Some(scope), unknown)
+            }
+            None => (None, Vec::new()),
+        };
+
+        // the meta index only knows the meta the embeddings were made with
+        let selected = match filters.is_empty() || meta.is_some() {
+            true => None,
+            false => self.meta_index()?.and_then(|m| m.select(&filters)),
+        };
+
+        let candidates = match (&scope, selected) {
+            (Some(scope), Some(selected)) => Some(dbio::sorted_intersection(&scope.ids, &selected)),
+            (Some(scope), None) => Some(scope.ids.clone()),
+            (None, selected) => selected,
+        };
+
+        let query = Query {
+            embedding,
+            filters,
+            exclude_paths: options.exclude_paths,
+            trace: options.debug,
+            mode: options.mode,
+            candidates,
+            scope: scope.as_ref().map(|s| s.ids.clone()),
+            meta,
+        };
+
+        // every candidate is kept, since the penalty can reorder them,
+        // and `search` cuts the page out of them
+        let ef = options.ef();
+        let (mut candidates, mut trace) = match options.granularity {
+            Granularity::Chunk => {
+                let hnsw::QueryResults {
+                    results: mut candidates,
+                    trace,
+                } = index.query_in(&query, ef, ef, &self.blocks);
+
+                let title_weight = config::get_title_weight();
+                if title_weight > 0.0 {
+                    blend_titles(
+                        &mut candidates,
+                        &query.embedding,
+                        title_weight,
+                        index.metric,
+                        &self.blocks,
+                    )?;
+                    candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+                }
+
+                if !options.include_boilerplate {
+                    penalize_boilerplate(&mut candidates, index.metric);
+                }
+
+                (candidates, trace)
+            }
+            Granularity::File => (
+                self.search_files(&query, scope.as_ref().map(|s| &s.files), ef)?,
+                None,
+            ),
+        };
+
+        // chunks behind an acl the request wasn't granted are never returned,
+        // whatever else they would have been grouped or deduplicated with
+        //
+        // a chunk is behind the acls it was embedded with along with any its file has in the ledger now,
+        // the same as `file_info` checks
+        let mut filtered_by_acl = 0;
+        if let Some(acls) = &options.acls {
+            let allowed = |meta: &std::collections::HashSet<String>| {
+                ledger::acls_of(meta).all(|acl| acls.contains(acl))
+            };
+            let current = acl_meta.unwrap_or_default();
+            let found = candidates.len();
+            candidates.retain(|(e, _)| {
+                allowed(&e.source_file.meta)
+                    && current.get(&e.source_file.filepath).is_none_or(allowed)
+            });
+            filtered_by_acl = found - candidates.len();
+            if filtered_by_acl > 0 {
+                info!("left out {} results behind acls", filtered_by_acl);
+            }
+        }
+
+        // copies of the same text from different files would otherwise fill the page
+        if let Some(threshold) = options.dedupe_threshold {
+            let found = candidates.len();
+            dedupe(&mut candidates, threshold);
+            info!(
+                "dropped {} near-duplicate results",
+                found - candidates.len()
+            );
+        }
+
+        let file_match = options.granularity == Granularity::File;
+
+        if let Some(group_by) = &options.group_by {
+            let mut groups = group_results(&candidates, group_by, options.group_score, ef);
+            for item in groups.iter_mut().flat_map(|g| g.top_chunks.iter_mut()) {
+                item.file_match = file_match;
+            }
+            mark_stale(
+                groups.iter_mut().flat_map(|g| g.top_chunks.iter_mut()),
+                &candidates,
+            );
+
+            return Ok(DeweyResponse {
+                results: Vec::new(),
+                groups,
+                degraded,
+                trace,
+                mode: options.mode,
+                index_behind: false,
+                total_candidates: 0,
+                has_more: false,
+                generation: 0,
+                unknown_paths,
+                filtered_by_acl,
+            });
+        }
+
+        if let Some(trace) = trace.as_mut() {
+            trace.distances = candidates.iter().map(|(_, d)| *d).collect();
+        }
+
+        let paths = config::PathResolver::current();
+        let mut results = candidates
+            .iter()
+            .map(|p| DeweyResponseItem {
+                file_match,
+                ..response_item(&p.0, p.1, &paths)
+            })
+            .collect::<Vec<_>>();
+        mark_stale(results.iter_mut(), &candidates);
+
+        Ok(DeweyResponse {
+            results,
+            groups: Vec::new(),
+            degraded,
+            trace,
+            mode: options.mode,
+            index_behind: false,
+            total_candidates: 0,
+            has_more: false,
+            generation: 0,
+            unknown_paths,
+            filtered_by_acl,
+        })
+    }
+
+    // the `ef` closest file centroids of the store in scope
+    //
+    // there are few enough files next to chunks that every centroid is compared,
+    // rather than building an index over them too
+    //
+    // only the centroids of `files` are compared, when it's given
+    fn search_files(
+        &self,
+        query: &Query,
+        files: Option<&std::collections::HashSet<String>>,
+        ef: usize,
+    ) -> Result<Vec<(Box<Embedding>, f32)>, std::io::Error> {
+        let centroids = self.centroids_of_store()?;
+        let mut scored = centroids
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| files.is_none_or(|files| files.contains(&c.source_file.filepath)))
+            .filter(|(_, c)| query.matches_filters(&c.source_file))
+            // centroids only keep the direction of their chunks, so they're compared by angle
+            .map(|(i, c)| (i, hnsw::distance(&query.embedding, c, hnsw::Metric::Cosine)))
+            .collect::<Vec<_>>();
+        scored.sort_by(|a, b| a.1.total_cmp(&b.1));
+        scored.truncate(ef);
+
+        Ok(scored
+            .into_iter()
+            .map(|(i, distance)| (Box::new(centroids[i].clone()), distance))
+            .collect())
+    }
+
+    // the meta index of the store in scope, `None` if it has none that's up to date
+    fn meta_index(&self) -> Result<Option<std::sync::Arc<dbio::MetaIndex>>, std::io::Error> {
+        let dir = config::get_data_dir();
+        let generation = dbio::read_state_generation()?;
+
+        let mut cached = self
+            .meta_indexes
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if let Some((at, meta_index)) = cached.get(&dir) {
+            if *at == generation {
+                return Ok(meta_index.clone());
+            }
+        }
+
+        let meta_index = dbio::read_meta_index()?.map(std::sync::Arc::new);
+        cached.insert(dir, (generation, meta_index.clone()));
+
+        Ok(meta_index)
+    }
+
+    fn centroids_of_store(&self) -> Result<std::sync::Arc<Vec<Embedding>>, std::io::Error> {
+        let dir = config::get_paths().centroids().data_dir;
+        let written = std::fs::metadata(dir.join("directory"))
+            .and_then(|m| m.modified())
+            .map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "files haven't been summarized yet, rebuild the index with -r",
+                )
+            })?;
+
+        let mut cached = self
+            .centroids
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if let Some((at, centroids)) = cached.get(&dir) {
+            if *at == written {
+                return Ok(centroids.clone());
+            }
+        }
+
+        let centroids = std::sync::Arc::new(dbio::read_centroids()?);
+        cached.insert(dir, (written, centroids.clone()));
+
+        Ok(centroids)
+    }
+
+    // returns the query's embedding, and whether it's the one remembered from
+    // the last time the query was made because the embedding API couldn't be reached
+    //
+    // queries that can't be embedded either way fail with `ErrorKind::NetworkUnreachable`
+    fn embed_query(
+        &self,
+        source: &EmbeddingSource,
+        query: &str,
+    ) -> Result<(Embedding, bool), std::io::Error> {
+        let embeddings = || {
+            self.query_embeddings
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+        };
+
+        // the same query embeds differently for every model
+        let key = format!("{}\n{}", config::get_embedding_model(), query);
+        match embed_text(source, query) {
+            Ok(embedding) => {
+                info!("embedding created");
+                embeddings().insert(&key, &embedding);
+                Ok((embedding, false))
+            }
+            Err(e) if is_network_error(&e) => match embeddings().get(&key) {
+                Some(embedding) => {
+                    lprint!(
+                        info,
+                        "Embedding API unreachable ({}), using the query's last embedding",
+                        e
+                    );
+                    Ok((embedding, true))
+                }
+                None => {
+                    error!("Embedding API unreachable: {}", e);
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::NetworkUnreachable,
+                        format!(
+                            "the embedding API is unreachable ({}) and the query hasn't been embedded before",
+                            e
+                        ),
+                    ))
+                }
+            },
+            Err(e) => {
+                error!("Failed to create embedding: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn stats(&self) -> Result<String, std::io::Error> {
+        let blocks = crate::dbio::block_report()?;
+        let response = DeweyStatsResponse {
+            recommendation: crate::dbio::compaction_recommendation(&blocks),
+            blocks,
+            embed_settings: config::get_embed_settings(),
+            query_cache: {
+                let cache = self
+                    .query_cache
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+                QueryCacheStats {
+                    hits: cache.hits,
+                    misses: cache.misses,
+                    entries: cache.len(),
+                }
+            },
+            maintenance: self.maintenance.clone(),
+            // stats are still worth having without it
+            journal: journal::last_entry().unwrap_or_else(|e| {
+                error!("failed to read the journal: {}", e);
+                None
+            }),
+            generation: dbio::read_state_generation()?,
+            health: ServerHealth {
+                version: Some(crate::version::BuildInfo::current()),
+                ..self
+                    .health
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner)
+                    .clone()
+            },
+            pending_edits: self.pending_edits.len(),
+        };
+
+        match serde_json::to_string(&response) {
+            Ok(serialized_response) => Ok(serialized_response),
+            Err(e) => {
+                error!("Failed to serialize response: {}", e);
+                Err(std::io::Error::other(e))
+            }
+        }
+    }
+
+    // a file behind an acl `token` doesn't grant is answered as if it weren't indexed
+    pub fn file_info(
+        &self,
+        payload: RequestPayload,
+        token: Option<&str>,
+    ) -> Result<String, std::io::Error> {
+        let requested = match payload {
+            RequestPayload::FileInfo { filepath } | RequestPayload::Edit { filepath, .. } => {
+                filepath
+            }
+            _ => {
+                error!("malformed file_info request: {:?}", payload);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "malformed file_info request",
+                ));
+            }
+        };
+
+        // a chunk's stable id stands in for the file the chunk is in now
+        let chunk = match parsing::is_stable_id(&requested) {
+            true => {
+                let _lock = lock::DataLock::acquire(lock::LockMode::Shared, "file_info")?;
+                dbio::find_stable_id(&requested)?
+            }
+            false => None,
+        };
+        let filepath = chunk
+            .as_ref()
+            .map_or_else(|| requested.clone(), |c| c.filepath.clone());
+
+        let info = sync::file_store(&filepath)?.scope(|| {
+            let _lock = lock::DataLock::acquire(lock::LockMode::Shared, "file_info")?;
+            dbio::file_info(&filepath)
+        })?;
+
+        let entry = ledger::entry_for(&filepath)?;
+
+        let acls = config::get_token_acls(token);
+        let allowed = |meta: &std::collections::HashSet<String>| {
+            ledger::acls_of(meta).all(|acl| acls.contains(acl))
+        };
+        let filtered_by_acl = !entry.as_ref().is_none_or(|e| allowed(&e.meta))
+            || !info.as_ref().is_none_or(|i| allowed(&i.meta));
+        if filtered_by_acl {
+            info!("file info for {} withheld by its acls", filepath);
+        }
+
+        // nor does a stable id give away which file a withheld chunk is in
+        let response = DeweyFileInfoResponse {
+            ledger: entry.filter(|_| !filtered_by_acl),
+            info: info.filter(|_| !filtered_by_acl),
+            filepath: match filtered_by_acl {
+                true => requested,
+                false => filepath,
+            },
+            generation: dbio::read_state_generation()?,
+            filtered_by_acl,
+            chunk: chunk.filter(|_| !filtered_by_acl),
+        };
+
+        serde_json::to_string(&response).map_err(std::io::Error::other)
+    }
+
+    // one cycle of scheduled maintenance, recorded for `stats`
+    //
+    // a cycle that finds the data directory locked is skipped rather than waiting,
+    // since whatever holds it is probably doing the same work by hand
+    pub fn maintain(&mut self, housekeeping: Option<&housekeeping::Policy>) -> &MaintenanceRun {
+        let outcome = match self.run_maintenance(housekeeping) {
+            Ok(outcome) => outcome,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => MaintenanceOutcome::Skipped {
+                reason: e.to_string(),
+            },
+            Err(e) => MaintenanceOutcome::Failed {
+                error: e.to_string(),
+            },
+        };
+
+        self.maintenance.insert(MaintenanceRun {
+            finished: chrono::Local::now().to_rfc3339(),
+            outcome,
+        })
+    }
+
+    // embeds the files that changed since they were last embedded, packs the blocks
+    // if they've fragmented, backs them up if there's a `backup_dir`, and ages out old queries and logs
+    //
+    // only the files already in the ledger are looked at--
+    // picking up the config ledger is left to a sync by hand
+    fn run_maintenance(
+        &mut self,
+        housekeeping: Option<&housekeeping::Policy>,
+    ) -> Result<MaintenanceOutcome, std::io::Error> {
+        // taken and let go right away, just to find out whether anything else is running
+        drop(lock::DataLock::acquire(
+            lock::LockMode::Exclusive,
+            "maintenance",
+        )?);
+
+        // reblocking reads the index from disk, so pending edits go out first
+        self.flush_index()?;
+
+        let mut stale = ledger::get_stale_files()?;
+        if !stale.is_empty() {
+            // nobody's there to rerun it over the quota, so it embeds what fits
+            let skipped =
+                sync::sync_index(false, false, true, None, sync::OverQuota::Partial, false)?;
+
+            // the ledger's hashes are what files are checked against next time,
+            // and the files that couldn't be read or didn't fit in the quota
+            // stay stale for the next run to try again
+            let remainder = sync::read_sync_remainder()?;
+            stale.retain(|entry| {
+                !skipped.iter().any(|s| s.filepath == entry.filepath)
+                    && !remainder.contains(&entry.filepath)
+            });
+            let embedded = stale
+                .iter()
+                .filter_map(|entry| {
+                    Some(ledger::LedgerEntry {
+                        hash: ledger::get_hash(&entry.filepath).ok()?,
+                        ..entry.clone()
+                    })
+                })
+                .collect::<Vec<_>>();
+            ledger::update_entries(&std::collections::HashSet::new(), &embedded)?;
+
+            self.rebuild_indexes()?;
+        }
+
+        let reblocked = dbio::compaction_recommendation(&dbio::block_report()?).is_some();
+        if reblocked {
+            dbio::reblock(true)?;
+            self.rebuild_indexes()?;
+        }
+
+        let backed_up = match config::get_backup_dir() {
+            Some(dir) => {
+                dbio::backup_to(&dir)?;
+                true
+            }
+            None => false,
+        };
+
+        let housekept = match housekeeping {
+            Some(policy) => {
+                let report = housekeeping::run(policy)?;
+                report.removed.len() + report.compressed.len()
+            }
+            None => 0,
+        };
+
+        Ok(MaintenanceOutcome::Completed {
+            embedded: stale.len(),
+            reblocked,
+            housekeeping: housekept,
+            backed_up,
+        })
+    }
+
+    // swaps in indexes built over what's in the blocks now
+    //
+    // a store with too little in it to index keeps whatever index it had
+    fn rebuild_indexes(&mut self) -> Result<(), std::io::Error> {
+        // stores that were emptied out are gone from the data directory
+        let stores = config::get_paths().model_stores()?;
+        self.routed
+            .retain(|model, _| stores.iter().any(|s| s.model.as_ref() == Some(model)));
+
+        for store in stores {
+            let index = match store.scope(dbio::build_index)? {
+                Some(index) => index,
+                None => continue,
+            };
+
+            match &store.model {
+                None => self.index = index,
+                Some(model) => {
+                    self.routed.insert(model.clone(), index);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // queues the file of an edit to be embedded again once its edits have gone quiet,
+    // which another edit of a file already queued just adds to,
+    // unless the edit is `sync` and has it embedded with `reindex` before the response
+    //
+    // this returns a `DeweyEditResponse` on success
+    // or a `DeweyErrorResponse` on error
+    pub fn edit(&mut self, payload: RequestPayload) -> Result<String, std::io::Error> {
+        let (filepath, ranges, collection) = match payload {
+            RequestPayload::Edit {
+                filepath,
+                ranges,
+                collection,
+                sync: false,
+            } => (filepath, ranges, collection),
+            _ => return self.reindex(payload),
+        };
+
+        if let Some(response) = snapshot_edit_error(collection.as_deref())? {
+            return serde_json::to_string(&response).map_err(std::io::Error::other);
+        }
+
+        match self.pending_edits.get_mut(&filepath) {
+            Some(pending) => pending.merge(ranges),
+            None => {
+                info!("queued {} to be embedded again", filepath);
+                self.pending_edits.insert(
+                    filepath,
+                    PendingEdit {
+                        requested: std::time::Instant::now(),
+                        ranges,
+                    },
+                );
+            }
+        }
+
+        serde_json::to_string(&DeweyEditResponse {
+            generation: dbio::read_state_generation()?,
+            queued: true,
+        })
+        .map_err(std::io::Error::other)
+    }
+
+    // whether any file's queued edits have gone quiet
+    pub fn has_due_edits(&self) -> bool {
+        self.pending_edits
+            .values()
+            .any(|pending| pending.requested.elapsed() >= self.edit_quiet_period)
+    }
+
+    // embeds the files whose queued edits have gone quiet again, or every queued file with `all`,
+    // returning how many there were
+    //
+    // nobody's waiting on the response of a queued edit, so a file that fails is only logged
+    pub fn reindex_pending(&mut self, all: bool) -> usize {
+        let quiet_period = self.edit_quiet_period;
+        let (due, waiting) = std::mem::take(&mut self.pending_edits)
+            .into_iter()
+            .partition::<std::collections::HashMap<_, _>, _>(|(_, pending)| {
+            all || pending.requested.elapsed() >= quiet_period
+        });
+        self.pending_edits = waiting;
+
+        for (filepath, pending) in due.iter() {
+            if let Err(e) = self.reembed(filepath, pending.ranges.as_deref()) {
+                error!("failed to embed {} again after edits: {}", filepath, e);
+            }
+        }
+
+        due.len()
+    }
+
+    // embeds an edited file again into its store's index, which is then dirty
+    fn reembed(
+        &mut self,
+        filepath: &str,
+        ranges: Option<&[(u64, u64)]>,
+    ) -> Result<(), std::io::Error> {
+        let store = sync::file_store(filepath)?;
+
+        // blocks may have been written before a failure, so the index is written either way
+        self.dirty = true;
+        self.reembeds += 1;
+        self.index_of_mut(&store)
+            .and_then(|index| store.scope(|| sync::update_file_embeddings(filepath, ranges, index)))
+    }
+
+    // embeds the file of an edit again before returning, whether or not it's `sync`
+    //
+    // this returns a `DeweyEditResponse` on success
+    // or a `DeweyErrorResponse` on error
+    //
+    // the index is only marked dirty, and written out by the next flush
+    pub fn reindex(&mut self, payload: RequestPayload) -> Result<String, std::io::Error> {
+        let (filepath, ranges, collection) = match payload {
+            RequestPayload::Edit {
+                filepath,
+                ranges,
+                collection,
+                ..
+            } => (filepath, ranges, collection),
+            _ => {
+                error!("malformed edit request: {:?}", payload);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "malformed edit request",
+                ));
+            }
+        };
+
+        if let Some(response) = snapshot_edit_error(collection.as_deref())? {
+            return serde_json::to_string(&response).map_err(std::io::Error::other);
+        }
+
+        let response = match self.reembed(&filepath, ranges.as_deref()) {
+            Ok(_) => serde_json::to_string(&DeweyEditResponse {
+                generation: dbio::read_state_generation()?,
+                queued: false,
+            })?,
+            Err(e) => {
+                let response = match parsing::SkippedSource::from_error(&e) {
+                    Some(skipped) => DeweyErrorResponse {
+                        filepath: Some(skipped.filepath.clone()),
+                        ..error_response("unreadable_file", skipped.to_string())
+                    },
+                    None => error_response("reindex_failed", e.to_string()),
+                };
+
+                serde_json::to_string(&response)?
+            }
+        };
+
+        Ok(response)
+    }
+}
+
+// whatever edits haven't been embedded or written yet go out when the state does
+impl Drop for ServerState {
+    fn drop(&mut self) {
+        self.reindex_pending(true);
+        if let Err(e) = self.flush_index() {
+            error!("failed to write the index on shutdown: {}", e);
+        }
+    }
+}
+
+// a dewey index under a directory of its own, laid out like `config::DataPaths::at`,
+// for applications that manage more than one index or want to leave ~/.local/dewey alone
+//
+// the directory has its own config, config ledger, and rules,
+// and everything the handle does runs against it rather than the default paths
+pub struct Dewey {
+    paths: config::DataPaths,
+    // there's nothing to search until something has been embedded
+    state: Option<ServerState>,
+}
+
+impl Dewey {
+    pub fn open(data_dir: &std::path::Path) -> Result<Self, std::io::Error> {
+        let paths = config::DataPaths::at(data_dir);
+        paths.create()?;
+
+        let state = paths.scope(|| match config::get_data_dir().join("index").exists() {
+            true => ServerState::new().map(Some),
+            false => Self::build_state(),
+        })?;
+
+        Ok(Self { paths, state })
+    }
+
+    pub fn paths(&self) -> &config::DataPaths {
+        &self.paths
+    }
+
+    // indexes over whatever is in every store's blocks, written out along with being returned
+    //
+    // there's nothing to search until the configured model's store has an index
+    fn build_state() -> Result<Option<ServerState>, std::io::Error> {
+        let mut stores = config::get_paths().model_stores()?.into_iter();
+        let mut state = match stores.next().map(|root| root.scope(dbio::build_index)) {
+            Some(Ok(Some(index))) => ServerState::with_index(index),
+            Some(Err(e)) => return Err(e),
+            _ => return Ok(None),
+        };
+
+        for store in stores {
+            state.load_store(&store)?;
+        }
+
+        Ok(Some(state))
+    }
+
+    // syncs the ledger with the config ledger, embeds it, and rebuilds the index
+    //
+    // everything is embedded again,
+    // since a partial embed only picks up files that changed after the ledger was synced
+    pub fn sync(&mut self) -> Result<ledger::SyncReport, std::io::Error> {
+        let paths = self.paths.clone();
+        paths.scope(|| {
+            // the old index is written out as it's dropped, so it goes before the new one is built
+            self.state = None;
+
+            let report = ledger::sync_ledger_config(true, None)?;
+            sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false)?;
+            self.state = Self::build_state()?;
+
+            Ok(report)
+        })
+    }
+
+    pub fn query(
+        &self,
+        query: &str,
+        options: &SearchOptions,
+    ) -> Result<DeweyResponse, std::io::Error> {
+        self.paths.scope(|| match &self.state {
+            Some(state) => state.search(query, options),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "nothing has been embedded yet",
+            )),
+        })
+    }
+
+    // embeds a file and adds it to the ledger, whether or not the config ledger covers it
+    //
+    // returns the number of embeddings made
+    pub fn insert_document(
+        &mut self,
+        filepath: &std::path::Path,
+        meta: std::collections::HashSet<String>,
+    ) -> Result<usize, std::io::Error> {
+        let filepath = filepath.canonicalize()?.to_string_lossy().to_string();
+        let paths = self.paths.clone();
+        paths.scope(|| {
+            let store = sync::file_store(&filepath)?;
+            std::fs::create_dir_all(&store.data_dir)?;
+
+            match self.state.as_mut() {
+                Some(state) => {
+                    state.dirty = true;
+                    match state.index_of_mut(&store) {
+                        Ok(index) => {
+                            store.scope(|| sync::insert_file(&filepath, meta, Some(index)))
+                        }
+                        // the first files of a model's store come before its index
+                        Err(_) => {
+                            let count = store.scope(|| sync::insert_file(&filepath, meta, None))?;
+                            state.load_store(&store)?;
+                            Ok(count)
+                        }
+                    }
+                }
+                None => {
+                    let count = store.scope(|| sync::insert_file(&filepath, meta, None))?;
+                    self.state = Self::build_state()?;
+                    Ok(count)
+                }
+            }
+        })
+    }
+
+    // returns the number of embeddings removed
+    pub fn delete_file(&mut self, filepath: &std::path::Path) -> Result<usize, std::io::Error> {
+        let filepath = filepath
+            .canonicalize()
+            .unwrap_or_else(|_| filepath.to_path_buf())
+            .to_string_lossy()
+            .to_string();
+        let paths = self.paths.clone();
+        paths.scope(|| {
+            let store = sync::file_store(&filepath)?;
+            if !store.data_dir.exists() {
+                return Ok(0);
+            }
+
+            let index = match self.state.as_mut() {
+                Some(state) => {
+                    state.dirty = true;
+                    state.index_of_mut(&store).ok()
+                }
+                None => None,
+            };
+
+            store.scope(|| dbio::delete_file(&filepath, index))
+        })
+    }
+
+    // writes the index out if it's changed, returning whether it had
+    pub fn flush(&mut self) -> Result<bool, std::io::Error> {
+        let paths = self.paths.clone();
+        paths.scope(|| match self.state.as_mut() {
+            Some(state) => state.flush_index(),
+            None => Ok(false),
+        })
+    }
+}
+
+// the index goes out to the handle's directory, not the default one
+impl Drop for Dewey {
+    fn drop(&mut self) {
+        let paths = self.paths.clone();
+        paths.scope(|| self.state = None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_common::*;
+    use crate::write_file;
+    use crate::{maintenance, message, openai};
+
+    // the fixture files are all tagged `rust`
+    #[test]
+    fn search_filters_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+
+        let search = |filters: &[&str]| {
+            let options = SearchOptions {
+                filters: filters.iter().map(|f| f.to_string()).collect(),
+                ..SearchOptions::new(5)
+            };
+
+            state.search("aaaa", &options)
+        };
+
+        assert_eq!(search(&[]).unwrap().results.len(), 5);
+        assert_eq!(search(&["rust"]).unwrap().results.len(), 5);
+        assert_eq!(search(&["eq rust", "ne python"]).unwrap().results.len(), 5);
+        assert!(search(&["ne rust"]).unwrap().results.is_empty());
+        assert!(search(&["eq missing"]).unwrap().results.is_empty());
+
+        let error = search(&["type,research", "gt 3"]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+        // requests get every bad filter back, rather than an error
+        let response = state
+            .query(
+                RequestPayload::Query {
+                    k: 5,
+                    offset: 0,
+                    query: "aaaa".to_string(),
+                    filters: vec!["gt 3".to_string(), "rust".to_string(), "eq".to_string()],
+                    exclude_paths: false,
+                    group_by: None,
+                    group_score: GroupScore::Max,
+                    discard_query: true,
+                    include_boilerplate: false,
+                    no_cache: false,
+                    debug: false,
+                    model: None,
+                    search_mode: hnsw::SearchMode::Balanced,
+                    granularity: crate::message::Granularity::Chunk,
+                    paths: None,
+                    collection: None,
+                    dedupe_threshold: None,
+                },
+                None,
+            )
+            .unwrap();
+        let response: DeweyErrorResponse = serde_json::from_str(&response).unwrap();
+        assert_eq!(response.error, "invalid_filter");
+        assert_eq!(response.invalid_filters, vec!["gt 3", "eq"]);
+    }
+
+    // retagging a directory in the ledger reaches filters with `live_meta`, without a reblock
+    #[test]
+    fn live_meta_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+
+        let search = |filter: &str| {
+            let options = SearchOptions {
+                filters: vec![filter.to_string()],
+                ..SearchOptions::new(5)
+            };
+
+            state.search("aaaa", &options).unwrap().results.len()
+        };
+
+        assert_eq!(search("eq tracked"), 5);
+        assert_eq!(search("eq retagged"), 0);
+
+        let ledger = config::get_config_dir().join("ledger");
+        let contents = std::fs::read_to_string(&ledger).unwrap();
+        write_file!(&ledger, contents.replace("--tracked", "--retagged"));
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+
+        // the blocks still have the old tag
+        assert_eq!(search("eq tracked"), 5);
+        assert_eq!(search("eq retagged"), 0);
+
+        write_file!(config::get_config_dir().join("config"), "live_meta true\n");
+        assert_eq!(search("eq tracked"), 0);
+        assert_eq!(search("eq retagged"), 5);
+        assert_eq!(search("eq rust"), 5);
+    }
+
+    // a tracked file deleted before the next sync is left out of the live meta,
+    // rather than failing every filtered query until then
+    #[test]
+    fn live_meta_missing_file_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+        write_file!(config::get_config_dir().join("config"), "live_meta true\n");
+
+        let ledger = crate::ledger::read_ledger().unwrap();
+        std::fs::remove_file(&ledger[0].filepath).unwrap();
+
+        let options = SearchOptions {
+            filters: vec!["eq tracked".to_string()],
+            ..SearchOptions::new(5)
+        };
+        assert_eq!(state.search("aaaa", &options).unwrap().results.len(), 5);
+
+        let (_, meta) = state.ledger_meta.get().unwrap();
+        assert_eq!(meta.len(), ledger.len() - 1);
+        assert!(!meta.contains_key(&ledger[0].filepath));
+    }
+
+    // a query limited to one file never turns up another, and paths that match nothing are named
+    #[test]
+    fn scoped_search_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+
+        let repo = config::get_home_dir().join("test_repo");
+        let search = |paths: &[std::path::PathBuf], granularity: Granularity| {
+            let options = SearchOptions {
+                save_query: false,
+                granularity,
+                paths: Some(
+                    paths
+                        .iter()
+                        .map(|p| p.to_string_lossy().to_string())
+                        .collect(),
+                ),
+                ..SearchOptions::new(10)
+            };
+
+            state.search("aaaa", &options).unwrap()
+        };
+        let files = |response: &DeweyResponse| {
+            let mut files = response
+                .results
+                .iter()
+                .map(|r| {
+                    std::path::Path::new(&r.filepath)
+                        .file_name()
+                        .unwrap()
+                        .to_owned()
+                })
+                .collect::<Vec<_>>();
+            files.dedup();
+            files
+        };
+
+        let missing = repo.join("missing.rs");
+        let response = search(&[repo.join("a.rs"), missing.clone()], Granularity::Chunk);
+        assert_eq!(files(&response), vec!["a.rs"]);
+        assert_eq!(
+            response.unknown_paths,
+            vec![missing.to_string_lossy().to_string()]
+        );
+
+        // globs take in every file they match, and nothing else
+        let response = search(&[repo.join("src").join("*.rs")], Granularity::Chunk);
+        assert_eq!(files(&response), vec!["e.rs"]);
+        assert!(response.unknown_paths.is_empty());
+
+        let response = search(&[missing], Granularity::Chunk);
+        assert!(response.results.is_empty());
+        assert_eq!(response.unknown_paths.len(), 1);
+
+        assert!(crate::dbio::rebuild_centroids().is_ok());
+        let response = search(&[repo.join("b.rs")], Granularity::File);
+        assert_eq!(files(&response), vec!["b.rs"]);
+    }
+
+    // copies of a file only turn up once with a dedupe threshold, and the next result takes their place
+    #[test]
+    fn dedupe_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let rules = config::get_config_dir().join("rules");
+        let contents = std::fs::read_to_string(&rules).unwrap();
+        crate::write_file!(&rules, format!("{}\ntxt --split \\n", contents));
+
+        let target = config::get_home_dir().join("test_repo");
+        for (i, tf) in get_tracked_files().iter().enumerate() {
+            crate::write_file!(target.join(tf), format!("fn fixture{}() {{}}", i));
+        }
+
+        crate::write_file!(target.join("copy.txt"), "alpha bravo charlie delta");
+        crate::write_file!(target.join("pasted.txt"), "alpha bravo charlie delta");
+        crate::write_file!(target.join("other.txt"), "alpha bravo echo foxtrot");
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+        let search = |dedupe_threshold: Option<f32>| {
+            let options = SearchOptions {
+                exclude_paths: true,
+                save_query: false,
+                dedupe_threshold,
+                ..SearchOptions::new(2)
+            };
+
+            state
+                .search("alpha bravo charlie delta", &options)
+                .unwrap()
+                .results
+                .into_iter()
+                .map(|r| {
+                    std::path::Path::new(&r.filepath)
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut copies = search(None);
+        copies.sort();
+        assert_eq!(copies, vec!["copy.txt", "pasted.txt"]);
+
+        let deduped = search(Some(DEFAULT_DEDUPE_THRESHOLD));
+        assert_eq!(deduped.len(), 2);
+        assert!(deduped[0] == "copy.txt" || deduped[0] == "pasted.txt");
+        assert_eq!(deduped[1], "other.txt");
+
+        let error = state
+            .search(
+                "alpha",
+                &SearchOptions {
+                    save_query: false,
+                    dedupe_threshold: Some(1.5),
+                    ..SearchOptions::new(2)
+                },
+            )
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn group_results_test() {
+        let home = config::get_home_dir();
+        let candidate = |filepath: &std::path::Path, distance: f32| {
+            let embedding = Embedding {
+                id: 0,
+                source_file: EmbeddingSource {
+                    filepath: filepath.to_string_lossy().to_string(),
+                    meta: std::collections::HashSet::new(),
+                    subset: Some((0, 10)),
+                    hash: String::new(),
+                    chunk_hash: None,
+                },
+                embedded_at: 0,
+                data: [0.0; crate::embedding::EMBED_DIM],
+            };
+
+            (Box::new(embedding), distance)
+        };
+
+        let a = home.join("repo_a").join("src").join("a.rs");
+        let b = home.join("repo_a").join("b.rs");
+        let c = home.join("repo_b").join("c.rs");
+        let candidates = vec![
+            candidate(&a, 0.1),
+            candidate(&c, 0.2),
+            candidate(&b, 0.3),
+            candidate(&c, 0.4),
+            candidate(&c, 0.5),
+            candidate(&c, 0.6),
+        ];
+
+        let summary = |groups: Vec<DeweyResponseGroup>| {
+            groups
+                .iter()
+                .map(|g| {
+                    let key = std::path::Path::new(&g.key).to_path_buf();
+                    (key, (g.score * 100.0).round() as u32, g.top_chunks.len())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            summary(group_results(
+                &candidates,
+                &GroupBy::File,
+                GroupScore::Max,
+                5
+            )),
+            vec![(a.clone(), 90, 1), (c.clone(), 80, 3), (b.clone(), 70, 1)]
+        );
+
+        assert_eq!(
+            summary(group_results(
+                &candidates,
+                &GroupBy::File,
+                GroupScore::Mean,
+                2
+            )),
+            vec![(a.clone(), 90, 1), (b.clone(), 70, 1)]
+        );
+
+        let by_repo = GroupBy::Directory { depth: 1 };
+        assert_eq!(
+            summary(group_results(&candidates, &by_repo, GroupScore::Mean, 5)),
+            vec![(home.join("repo_a"), 80, 2), (home.join("repo_b"), 58, 3)]
+        );
+
+        let by_source = GroupBy::Directory { depth: 2 };
+        assert_eq!(
+            summary(group_results(&candidates, &by_source, GroupScore::Max, 5))[0].0,
+            home.join("repo_a").join("src")
+        );
+    }
+
+    // every fixture file opens with the same license header
+    #[test]
+    fn boilerplate_search_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        // exactly one chunk long, so every file splits it off on its own
+        let header = format!(
+            "{:<512}",
+            "// copyright the authors, licensed under the terms of the license"
+        );
+        let target = config::get_home_dir().join("test_repo");
+        let topics = [
+            "parser tokens",
+            "network sockets",
+            "cache eviction",
+            "file locks",
+        ];
+        for (tf, topic) in get_tracked_files().iter().zip(topics) {
+            crate::write_file!(
+                target.join(tf),
+                format!("{}fn main() {{ {} for the program }}", header, topic)
+            );
+        }
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let frequencies = crate::sync::read_frequencies().unwrap();
+        assert_eq!(frequencies.values().collect::<Vec<_>>(), vec![&4]);
+
+        let state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+
+        let search = |include_boilerplate: bool| {
+            let options = SearchOptions {
+                exclude_paths: true,
+                include_boilerplate,
+                ..SearchOptions::new(4)
+            };
+
+            state.search("license program", &options).unwrap().results
+        };
+
+        let is_header = |r: &DeweyResponseItem| r.subset == (0, 512);
+
+        // without the penalty the header matches best in every file
+        let results = search(true);
+        assert!(is_header(&results[0]));
+
+        let results = search(false);
+        assert_eq!(results.len(), 4);
+        assert!(results.iter().all(|r| !is_header(r)));
+    }
+
+    // a file that barely mentions the query but is titled with it
+    // only outranks one that's all about it once titles are weighed in
+    #[test]
+    fn title_weight_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let target = config::get_home_dir().join("test_repo");
+        crate::write_file!(target.join("a.rs"), "//! invoice ledger\nstub");
+        crate::write_file!(target.join("b.rs"), "invoice ledger, invoice ledger totals");
+        crate::write_file!(target.join("c.rs"), "network sockets");
+        crate::write_file!(target.join("src").join("e.rs"), "cache eviction");
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        // every file gets a title, and titles are never results themselves
+        let directory = dbio::get_directory().unwrap();
+        assert_eq!(directory.title_map.len(), get_tracked_files().len());
+        let titled = target.join("a.rs").to_string_lossy().to_string();
+        assert_eq!(parsing::file_title(&titled), "invoice ledger");
+
+        let search = |weight: Option<f32>| {
+            if let Some(weight) = weight {
+                crate::write_file!(
+                    config::get_config_dir().join("config"),
+                    format!("title_weight {}\n", weight)
+                );
+            }
+
+            // a fresh state each time, so the results aren't the cached ones
+            let state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+            let options = SearchOptions {
+                save_query: false,
+                exclude_paths: true,
+                ..SearchOptions::new(4)
+            };
+
+            state.search("invoice ledger", &options).unwrap().results
+        };
+
+        let results = search(None);
+        assert!(results.iter().all(|r| r.subset != (0, 0)));
+        assert!(results[0].filepath.ends_with("b.rs"));
+
+        let results = search(Some(0.0));
+        assert!(results[0].filepath.ends_with("b.rs"));
+
+        let results = search(Some(0.8));
+        assert!(results.iter().all(|r| r.subset != (0, 0)));
+        assert_eq!(results[0].filepath, titled);
+    }
+
+    // a file with a little of the query in each of its chunks outranks
+    // one with a single close chunk among many that have nothing to do with it
+    #[test]
+    fn file_granularity_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let rules = config::get_config_dir().join("rules");
+        let contents = std::fs::read_to_string(&rules).unwrap();
+        crate::write_file!(&rules, format!("{}\ntxt --split \\n", contents));
+
+        // the fixture files are all identical, which leaves the index nothing to tell apart
+        let target = config::get_home_dir().join("test_repo");
+        for (i, tf) in get_tracked_files().iter().enumerate() {
+            crate::write_file!(target.join(tf), format!("fn fixture{}() {{}}", i));
+        }
+
+        let mut focused = vec!["alpha bravo charlie delta".to_string()];
+        focused.extend((0..9).map(|i| format!("unrelated{0} filler{0} words{0} here{0}", i)));
+        crate::write_file!(target.join("focused.txt"), focused.join("\n"));
+        crate::write_file!(
+            target.join("spread.txt"),
+            [
+                "alpha noise1 noise2 noise3",
+                "bravo noise4 noise5 noise6",
+                "charlie noise7 noise8 noise9",
+                "delta noise10 noise11 noise12",
+            ]
+            .join("\n")
+        );
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let mut state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+
+        let search = |state: &ServerState, granularity: Granularity| {
+            let options = SearchOptions {
+                exclude_paths: true,
+                no_cache: true,
+                granularity,
+                ..SearchOptions::new(3)
+            };
+
+            state
+                .search("alpha bravo charlie delta", &options)
+                .unwrap()
+                .results
+        };
+
+        let chunks = search(&state, Granularity::Chunk);
+        assert!(chunks[0].filepath.ends_with("focused.txt"));
+        assert!(chunks.iter().all(|r| !r.file_match));
+
+        let files = search(&state, Granularity::File);
+        assert!(files[0].filepath.ends_with("spread.txt"));
+        assert!(files[1].filepath.ends_with("focused.txt"));
+        assert!(files.iter().all(|r| r.file_match && r.subset == (0, 0)));
+
+        // every one of the spread file's chunks falls well behind the focused one
+        let spread = target.join("spread.txt").to_string_lossy().to_string();
+        assert!(chunks
+            .iter()
+            .filter(|r| r.filepath == spread)
+            .all(|r| r.score < chunks[0].score / 2.0));
+
+        // an edit recomputes just that file's centroid
+        crate::write_file!(
+            target.join("spread.txt"),
+            "nothing to see\nin this file anymore"
+        );
+        let edit = RequestPayload::Edit {
+            filepath: spread.clone(),
+            ranges: None,
+            collection: None,
+            sync: true,
+        };
+        let response = state.reindex(edit).unwrap();
+        assert!(serde_json::from_str::<DeweyEditResponse>(&response).is_ok());
+
+        let before = files[0].score;
+        let files = search(&state, Granularity::File);
+        assert!(files[0].filepath.ends_with("focused.txt"));
+        assert!(files
+            .iter()
+            .all(|r| r.filepath != spread || r.score < before / 2.0));
+        assert_eq!(
+            crate::dbio::read_centroids()
+                .unwrap()
+                .iter()
+                .filter(|c| c.source_file.filepath == spread)
+                .count(),
+            1
+        );
+    }
+
+    // results from files changed since they were embedded are flagged,
+    // and moved to wherever their text went
+    #[test]
+    fn stale_results_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let target = config::get_home_dir().join("test_repo");
+        let files = get_tracked_files();
+        let topics = [
+            "parser tokens",
+            "network sockets",
+            "cache eviction",
+            "file locks",
+        ];
+        for (tf, topic) in files.iter().zip(topics) {
+            crate::write_file!(target.join(tf), format!("fn main() {{ {} }}", topic));
+        }
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+
+        // the first file's chunk moves down, the second's is rewritten away
+        let prefix = "// moved down a line\n";
+        let moved = format!("fn main() {{ {} }}", topics[0]);
+        crate::write_file!(target.join(&files[0]), format!("{}{}", prefix, moved));
+        crate::write_file!(target.join(&files[1]), "something else entirely");
+
+        let options = SearchOptions {
+            exclude_paths: true,
+            ..SearchOptions::new(4)
+        };
+        let results = state.search("parser tokens", &options).unwrap().results;
+        assert_eq!(results.len(), 4);
+
+        let result = |file: &str| {
+            let path = target.join(file).to_string_lossy().to_string();
+            results.iter().find(|r| r.filepath == path).unwrap()
+        };
+
+        let relocated = result(&files[0]);
+        assert!(relocated.stale);
+        assert_eq!(
+            relocated.subset,
+            (prefix.len() as u64, (prefix.len() + moved.len()) as u64)
+        );
+
+        let lost = result(&files[1]);
+        assert!(lost.stale);
+        assert_eq!(
+            lost.subset,
+            (0, format!("fn main() {{ {} }}", topics[1]).len() as u64)
+        );
+
+        assert!(!result(&files[2]).stale);
+        assert!(!result(&files[3]).stale);
+    }
+
+    // with a path root, the index keeps working after its files move and it's rebased onto them
+    #[test]
+    fn relocated_index_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let home = config::get_home_dir();
+        let target = home.join("test_repo");
+        let files = get_tracked_files();
+        let topics = [
+            "parser tokens",
+            "network sockets",
+            "cache eviction",
+            "file locks",
+        ];
+        for (tf, topic) in files.iter().zip(topics) {
+            crate::write_file!(target.join(tf), format!("fn main() {{ {} }}", topic));
+        }
+
+        let root = target.canonicalize().unwrap();
+        crate::write_file!(
+            config::get_config_dir().join("config"),
+            format!("path_root {}\n", root.display())
+        );
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+        assert!(dbio::build_index().unwrap().is_some());
+
+        // nothing on disk names the root
+        let root_name = root.to_string_lossy().to_string();
+        assert!(config::get_paths().path_root_path().exists());
+        for stored in [
+            config::get_local_dir().join("ledger"),
+            config::get_data_dir().join("directory"),
+            config::get_data_dir().join("0"),
+        ] {
+            let contents = String::from_utf8_lossy(&std::fs::read(&stored).unwrap()).to_string();
+            assert!(!contents.contains(&root_name), "{}", stored.display());
+        }
+
+        let moved = home.join("moved_repo");
+        std::fs::rename(&target, &moved).unwrap();
+        let moved = moved.canonicalize().unwrap();
+
+        let error = dbio::rebase(&moved.to_string_lossy(), &moved.to_string_lossy()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        dbio::rebase(&root_name, &moved.to_string_lossy()).unwrap();
+
+        let paths = config::PathResolver::current();
+        assert_eq!(paths.root(), Some(moved.as_path()));
+        assert_eq!(paths.store(&moved.join("a.rs").to_string_lossy()), "a.rs");
+        assert_eq!(paths.store("/elsewhere/a.rs"), "/elsewhere/a.rs");
+
+        // the ledger follows the files, which haven't changed
+        let ledger = crate::ledger::read_ledger().unwrap();
+        assert_eq!(ledger.len(), files.len());
+        assert!(ledger
+            .iter()
+            .all(|e| e.filepath.starts_with(&*moved.to_string_lossy())));
+        assert!(crate::ledger::get_stale_files().unwrap().is_empty());
+        let filepath = moved.join(&files[0]).to_string_lossy().to_string();
+        assert!(!dbio::blocks_with_file(&filepath).unwrap().is_empty());
+
+        let state = ServerState::new().unwrap();
+        let options = SearchOptions {
+            exclude_paths: true,
+            save_query: false,
+            ..SearchOptions::new(1)
+        };
+        let results = state.search("parser tokens", &options).unwrap().results;
+        assert_eq!(results[0].filepath, filepath);
+        assert!(!results[0].stale);
+
+        let citation = message::Citation::from_item(&results[0]);
+        assert_eq!(citation.filepath, filepath);
+        assert_eq!(citation.lines, Some((1, 1)));
+    }
+
+    // a snapshot collection keeps answering with what was embedded when it was taken,
+    // and can't be changed through the server
+    #[test]
+    fn snapshot_collection_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let target = config::get_home_dir().join("test_repo");
+        let files = get_tracked_files();
+        let topics = [
+            "parser tokens",
+            "network sockets",
+            "cache eviction",
+            "file locks",
+        ];
+        for (tf, topic) in files.iter().zip(topics) {
+            crate::write_file!(target.join(tf), format!("fn main() {{ {} }}", topic));
+        }
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let index = HNSW::build(&hnsw::HNSWParams::default()).unwrap();
+        index
+            .serialize(&config::get_data_dir().join("index"))
+            .unwrap();
+        let name = crate::dbio::snapshot("before").unwrap();
+
+        let mut state = ServerState::with_index(index);
+        let first = target.join(&files[0]).to_string_lossy().to_string();
+        crate::write_file!(target.join(&files[0]), "fn main() { something else }");
+        let response = state
+            .reindex(RequestPayload::Edit {
+                filepath: first.clone(),
+                ranges: None,
+                collection: None,
+                sync: true,
+            })
+            .unwrap();
+        assert!(serde_json::from_str::<DeweyEditResponse>(&response).is_ok());
+        assert!(state.flush_index().unwrap());
+
+        let search = |collection: Option<&str>| {
+            let options = SearchOptions {
+                exclude_paths: true,
+                save_query: false,
+                collection: collection.map(|c| c.to_string()),
+                ..SearchOptions::new(1)
+            };
+
+            state.search("parser tokens", &options)
+        };
+
+        let live = search(None).unwrap().results;
+        assert_ne!(live[0].filepath, first);
+
+        let snapshot = search(Some("snapshot:before")).unwrap().results;
+        assert_eq!(snapshot[0].filepath, first);
+        assert!(snapshot[0].stale);
+
+        // the full name finds it too, and the live index is still as it was edited
+        let snapshot = search(Some(&format!("snapshot:{}", name))).unwrap().results;
+        assert_eq!(snapshot[0].filepath, first);
+        assert_eq!(search(None).unwrap().results[0].filepath, live[0].filepath);
+
+        let error = search(Some("snapshot:missing")).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+        let error = search(Some("archive:before")).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+        // edits are turned away, and so is anything that would write to the snapshot
+        let response = state
+            .reindex(RequestPayload::Edit {
+                filepath: first.clone(),
+                ranges: None,
+                collection: Some("snapshot:before".to_string()),
+                sync: true,
+            })
+            .unwrap();
+        let response: DeweyErrorResponse = serde_json::from_str(&response).unwrap();
+        assert_eq!(response.error, "read_only_collection");
+
+        let store = config::get_paths().snapshot(&name);
+        let locked = store.scope(|| lock::DataLock::acquire(lock::LockMode::Exclusive, "test"));
+        assert_eq!(
+            locked.err().map(|e| e.kind()),
+            Some(std::io::ErrorKind::PermissionDenied)
+        );
+        assert!(!store.data_dir.join(".lock").exists());
+    }
+
+    // repeated queries skip the embedding request until an edit changes the index
+    // queries that only differ in the boilerplate the query rules strip are the same query
+    #[test]
+    fn query_rules_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        crate::write_file!(
+            config::get_config_dir().join("query_rules"),
+            "prefix Answer using the context:\nbetween <q> </q>\n"
+        );
+
+        let state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+        let options = SearchOptions {
+            save_query: true,
+            ..SearchOptions::new(5)
+        };
+
+        let calls = || crate::openai::TEST_API_CALLS.with(|calls| calls.get());
+        let first = state
+            .search("Answer using the context: aaaa bbbb", &options)
+            .unwrap();
+        let before = calls();
+        let second = state
+            .search("Please help. <q> aaaa bbbb </q> Thanks!", &options)
+            .unwrap();
+        assert_eq!(calls(), before);
+        assert_eq!(first.results.len(), second.results.len());
+
+        assert_eq!(
+            query_cache_key(
+                &parsing::preprocess_query("Answer using the context: aaaa bbbb"),
+                &options,
+                &config::get_paths(),
+                None,
+                false
+            ),
+            query_cache_key(
+                &parsing::preprocess_query("<q>aaaa bbbb</q>"),
+                &options,
+                &config::get_paths(),
+                None,
+                false
+            )
+        );
+
+        // the embedding goes under the stripped query
+        let key = format!("{}\naaaa bbbb", config::get_embedding_model());
+        assert!(state.query_embeddings.lock().unwrap().get(&key).is_some());
+
+        // while history has the queries as they were sent
+        let mut saved = std::fs::read_dir(config::get_queries_dir())
+            .unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect::<Vec<_>>();
+        saved.sort();
+        assert_eq!(
+            saved,
+            vec![
+                "Answer using the context: aaaa bbbb",
+                "Please help. <q> aaaa bbbb </q> Thanks!"
+            ]
+        );
+    }
+
+    #[test]
+    fn citation_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let filepath = config::get_home_dir()
+            .join("test_repo")
+            .join("cited file.rs");
+        let contents = "// ünïcode\nfn a() {}\nfn b() {}\n";
+        write_file!(&filepath, contents);
+
+        let start = contents.find("fn b").unwrap() as u64;
+        let item = DeweyResponseItem {
+            filepath: filepath.to_string_lossy().to_string(),
+            subset: (start, contents.len() as u64),
+            score: 0.5,
+            path_match: false,
+            stale: false,
+            file_match: false,
+            embedded_at: 0,
+            stable_id: None,
+        };
+
+        let citation = message::Citation::from_item(&item);
+        assert_eq!(citation.lines, Some((3, 3)));
+        assert_eq!(
+            citation.hash.as_deref(),
+            Some(&crate::ledger::hash_contents(b"fn b() {}\n")[..12])
+        );
+
+        let path = filepath.to_string_lossy().to_string();
+        assert_eq!(citation.to_string(), format!("{}:L3", path));
+        assert_eq!(
+            citation.format(message::CitationFormat::Markdown),
+            format!("[{}:L3]({}#L3)", path, path.replace(' ', "%20"))
+        );
+        let json = citation.format(message::CitationFormat::Json);
+        assert_eq!(
+            serde_json::from_str::<message::Citation>(&json).unwrap(),
+            citation
+        );
+
+        let file = message::Citation::from_item(&DeweyResponseItem {
+            subset: (0, 0),
+            file_match: true,
+            ..item.clone()
+        });
+        assert_eq!(file.to_string(), format!("{}:L1-L3", path));
+
+        let path_match = message::Citation::from_item(&DeweyResponseItem {
+            path_match: true,
+            ..item.clone()
+        });
+        assert_eq!(path_match.lines, None);
+        assert_eq!(path_match.to_string(), path);
+
+        // a file that's gone is still cited, without lines
+        std::fs::remove_file(&filepath).unwrap();
+        let gone = message::Citation::from_item(&item);
+        assert_eq!((gone.lines, gone.hash), (None, None));
+    }
+
+    // a handler panicking while it holds the state leaves it poisoned,
+    // and the requests after it are still answered, with the stats saying so
+    #[test]
+    fn handler_panic_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        setup_embeddings(50).unwrap();
+
+        let state = std::sync::RwLock::new(ServerState::with_index(
+            HNSW::build(&hnsw::HNSWParams::default()).unwrap(),
+        ));
+        let request = |message_type: &str| DeweyRequest {
+            message_type: message_type.to_string(),
+            payload: RequestPayload::Stats {},
+            expect_generation: None,
+            trace_id: None,
+            token: None,
+        };
+        let health = |state: &std::sync::RwLock<ServerState>| {
+            let response = ServerState::handle_locked(state, request("stats"));
+            serde_json::from_str::<DeweyStatsResponse>(&response)
+                .unwrap()
+                .health
+        };
+
+        assert_eq!(
+            health(&state),
+            ServerHealth {
+                version: Some(crate::version::BuildInfo::current()),
+                ..ServerHealth::default()
+            }
+        );
+
+        let response = ServerState::handle_locked(&state, request("panic"));
+        let response = serde_json::from_str::<DeweyErrorResponse>(&response).unwrap();
+        assert_eq!(response.error, "internal_error");
+        assert!(state.is_poisoned());
+
+        let response = ServerState::handle_locked(&state, request("flush"));
+        assert!(
+            !serde_json::from_str::<DeweyFlushResponse>(&response)
+                .unwrap()
+                .flushed
+        );
+
+        // the panic, the flush, and the stats asking about them
+        let health = health(&state);
+        assert!(!health.healthy);
+        assert_eq!(health.degraded_requests, 3);
+        assert_eq!(health.last_panic.as_deref(), Some("panic requested"));
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn query_spans_test() {
+        use trace::Value;
+
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let state = std::sync::RwLock::new(ServerState::with_index(
+            HNSW::build(&hnsw::HNSWParams::default()).unwrap(),
+        ));
+
+        trace::use_memory_exporter();
+        trace::take_finished();
+
+        let trace_id = "0af7651916cd43dd8448eb211c80319c";
+        let response = ServerState::handle_locked(
+            &state,
+            DeweyRequest {
+                message_type: "query".to_string(),
+                payload: RequestPayload::Query {
+                    k: 3,
+                    offset: 0,
+                    query: "aaaa bbbb".to_string(),
+                    filters: Vec::new(),
+                    exclude_paths: false,
+                    group_by: None,
+                    group_score: GroupScore::Max,
+                    discard_query: true,
+                    include_boilerplate: false,
+                    no_cache: true,
+                    debug: false,
+                    model: None,
+                    search_mode: hnsw::SearchMode::Balanced,
+                    granularity: crate::message::Granularity::Chunk,
+                    paths: None,
+                    collection: None,
+                    dedupe_threshold: None,
+                },
+                expect_generation: None,
+                trace_id: Some(trace_id.to_string()),
+                token: None,
+            },
+        );
+        let results = serde_json::from_str::<DeweyResponse>(&response)
+            .unwrap()
+            .results
+            .len();
+
+        let spans = trace::take_finished();
+        assert!(spans.iter().all(|s| s.trace_id == trace_id));
+
+        let span = |name: &str| spans.iter().find(|s| s.name == name).unwrap();
+        let request = span("dewey.request");
+        assert_eq!(request.parent_span_id, None);
+        assert_eq!(
+            request.attribute("message_type"),
+            Some(&Value::from("query"))
+        );
+
+        let embed = span("dewey.embed");
+        assert_eq!(embed.parent_span_id.as_ref(), Some(&request.span_id));
+
+        let search = span("dewey.hnsw.search");
+        assert_eq!(search.parent_span_id.as_ref(), Some(&request.span_id));
+        // the index is searched for more than the page asked for, to cache the rest
+        let attribute = |key: &str| match search.attribute(key) {
+            Some(Value::Int(value)) => *value as usize,
+            value => panic!("{} is {:?}", key, value),
+        };
+        assert!(attribute("k") >= 3);
+        assert!(attribute("ef") >= attribute("k"));
+        assert!(attribute("results") >= results);
+
+        // a fresh store has to read every block it looks at
+        let loads = spans
+            .iter()
+            .filter(|s| s.name == "dewey.cache.load_block")
+            .count();
+        assert!(loads > 0);
+        assert_eq!(search.attribute("block_loads"), Some(&Value::from(loads)));
+        assert!(search.attribute("cache_hits").is_some());
+    }
+
+    #[test]
+    fn query_cache_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let mut state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+
+        let calls = || crate::openai::TEST_API_CALLS.with(|calls| calls.get());
+        let search = |state: &ServerState, query: &str, no_cache: bool| {
+            let options = SearchOptions {
+                save_query: false,
+                no_cache,
+                ..SearchOptions::new(5)
+            };
+
+            state.search(query, &options).unwrap().results
+        };
+        let cache_stats = |state: &ServerState| {
+            serde_json::from_str::<DeweyStatsResponse>(&state.stats().unwrap())
+                .unwrap()
+                .query_cache
+        };
+
+        let first = search(&state, "aaaa bbbb", false);
+        let before = calls();
+
+        let second = search(&state, "  aaaa\tbbbb ", false);
+        assert_eq!(calls(), before);
+        assert_eq!(
+            first
+                .iter()
+                .map(|r| (&r.filepath, r.subset))
+                .collect::<Vec<_>>(),
+            second
+                .iter()
+                .map(|r| (&r.filepath, r.subset))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            cache_stats(&state),
+            QueryCacheStats {
+                hits: 1,
+                misses: 1,
+                entries: 1
+            }
+        );
+
+        // fewer results are cut from the same entry
+        let options = SearchOptions {
+            save_query: false,
+            ..SearchOptions::new(2)
+        };
+        let fewer = state.search("aaaa bbbb", &options).unwrap().results;
+        assert_eq!(calls(), before);
+        assert_eq!(
+            fewer
+                .iter()
+                .map(|r| (&r.filepath, r.subset))
+                .collect::<Vec<_>>(),
+            first
+                .iter()
+                .take(2)
+                .map(|r| (&r.filepath, r.subset))
+                .collect::<Vec<_>>()
+        );
+
+        // different options are a different query
+        let options = SearchOptions {
+            save_query: false,
+            exclude_paths: true,
+            ..SearchOptions::new(5)
+        };
+        assert!(state.search("aaaa bbbb", &options).is_ok());
+        assert_eq!(calls(), before + 1);
+
+        search(&state, "aaaa bbbb", true);
+        assert_eq!(calls(), before + 2);
+
+        let filepath = config::get_home_dir()
+            .join("test_repo")
+            .join(&get_tracked_files()[0]);
+        crate::write_file!(&filepath, "aaaa bbbb cccc");
+        let response = state
+            .reindex(RequestPayload::Edit {
+                filepath: filepath.to_string_lossy().to_string(),
+                ranges: None,
+                collection: None,
+                sync: true,
+            })
+            .unwrap();
+        assert!(serde_json::from_str::<DeweyEditResponse>(&response).is_ok());
+
+        let after_edit = calls();
+        search(&state, "aaaa bbbb", false);
+        assert_eq!(calls(), after_edit + 1);
+        assert_eq!(cache_stats(&state).entries, 1);
+    }
+
+    // pages of a query are cut from the same results, one after the other
+    #[test]
+    fn pagination_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+
+        let calls = || crate::openai::TEST_API_CALLS.with(|calls| calls.get());
+        let search = |k: usize, offset: usize| {
+            let options = SearchOptions {
+                offset,
+                save_query: false,
+                ..SearchOptions::new(k)
+            };
+
+            state.search("aaaa bbbb", &options).unwrap()
+        };
+        let keys = |results: &[DeweyResponseItem]| {
+            results
+                .iter()
+                .map(|r| (r.filepath.clone(), r.subset))
+                .collect::<Vec<_>>()
+        };
+
+        let first = search(3, 0);
+        let before = calls();
+        let second = search(3, 3);
+        assert_eq!(calls(), before);
+
+        assert_eq!(first.results.len(), 3);
+        assert_eq!(second.results.len(), 3);
+        assert!(first.has_more);
+        assert_eq!(first.total_candidates, second.total_candidates);
+
+        let first = keys(&first.results);
+        let second = keys(&second.results);
+        assert!(first.iter().all(|r| !second.contains(r)));
+        assert_eq!([first, second].concat(), keys(&search(6, 0).results));
+
+        let total = search(3, 0).total_candidates;
+        let last = search(3, total - 1);
+        assert_eq!(last.results.len(), 1);
+        assert!(!last.has_more);
+        assert!(search(3, total).results.is_empty());
+    }
+
+    // a flow pinned to a generation is turned away once an edit lands in the middle of it
+    #[test]
+    fn stale_generation_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let mut state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+        let request = |message_type: &str, payload: serde_json::Value, expect: Option<u64>| {
+            serde_json::from_value::<DeweyRequest>(serde_json::json!({
+                "message_type": message_type,
+                "payload": payload,
+                "expect_generation": expect,
+            }))
+            .unwrap()
+        };
+        let query = |expect: Option<u64>| {
+            request(
+                "query",
+                serde_json::json!({"k": 3, "query": "aaaa bbbb", "filters": [], "discard_query": true}),
+                expect,
+            )
+        };
+
+        let first: DeweyResponse = serde_json::from_str(&state.handle(query(None))).unwrap();
+        let generation = first.generation;
+
+        let stats: DeweyStatsResponse = serde_json::from_str(&state.handle(request(
+            "stats",
+            serde_json::json!({}),
+            Some(generation),
+        )))
+        .unwrap();
+        assert_eq!(stats.generation, generation);
+
+        let pinned: DeweyResponse =
+            serde_json::from_str(&state.handle(query(Some(generation)))).unwrap();
+        assert_eq!(pinned.generation, generation);
+
+        let filepath = config::get_home_dir()
+            .join("test_repo")
+            .join(&get_tracked_files()[0]);
+        crate::write_file!(&filepath, "aaaa bbbb cccc");
+        let edit: DeweyEditResponse = serde_json::from_str(&state.handle(request(
+            "edit",
+            serde_json::json!({"filepath": filepath.to_string_lossy(), "sync": true}),
+            None,
+        )))
+        .unwrap();
+        assert!(!edit.queued);
+        assert!(edit.generation > generation);
+
+        let stale: DeweyErrorResponse =
+            serde_json::from_str(&state.handle(query(Some(generation)))).unwrap();
+        assert_eq!(stale.error, "stale_generation");
+        assert_eq!(stale.generation, edit.generation);
+
+        // the client starts over from the new generation
+        let restarted: DeweyResponse =
+            serde_json::from_str(&state.handle(query(Some(edit.generation)))).unwrap();
+        assert_eq!(restarted.generation, edit.generation);
+    }
+
+    // a burst of edits of one file is queued as one, and embedded once when it's drained
+    #[test]
+    fn queued_edits_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let mut state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+        let filepath = config::get_home_dir()
+            .join("test_repo")
+            .join(&get_tracked_files()[0])
+            .to_string_lossy()
+            .to_string();
+        let edit = |ranges: Option<Vec<(u64, u64)>>| DeweyRequest {
+            message_type: "edit".to_string(),
+            payload: RequestPayload::Edit {
+                filepath: filepath.clone(),
+                ranges,
+                collection: None,
+                sync: false,
+            },
+            expect_generation: None,
+            trace_id: None,
+            token: None,
+        };
+
+        let generation = dbio::read_state_generation().unwrap();
+        for i in 0..20 {
+            crate::write_file!(&filepath, format!("fn edit_{}() {{}}", i));
+            let response: DeweyEditResponse =
+                serde_json::from_str(&state.handle(edit(Some(vec![(0, 4)])))).unwrap();
+            assert!(response.queued);
+            assert_eq!(response.generation, generation);
+        }
+        assert_eq!(state.pending_edits.len(), 1);
+        assert_eq!(
+            state.pending_edits[&filepath]
+                .ranges
+                .as_ref()
+                .unwrap()
+                .len(),
+            20
+        );
+        assert!(!state.has_due_edits());
+
+        let flush: DeweyFlushResponse = serde_json::from_str(&state.flush().unwrap()).unwrap();
+        assert!(flush.flushed);
+        assert_eq!(flush.reembeds, 1);
+        assert!(flush.generation > generation);
+        assert!(state.pending_edits.is_empty());
+
+        // an edit of the whole file takes the place of the ranges before it
+        state.edit_quiet_period = std::time::Duration::ZERO;
+        state.handle(edit(Some(vec![(0, 4)])));
+        state.handle(edit(None));
+        assert_eq!(state.pending_edits[&filepath].ranges, None);
+        assert!(state.has_due_edits());
+        assert_eq!(state.reindex_pending(false), 1);
+        assert_eq!(state.reembeds, 2);
+    }
+
+    // without the embedding API, queries made before are searched with their last embedding
+    // and anything else gets an `embedding_unavailable` error
+    #[test]
+    fn offline_query_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+        let options = SearchOptions {
+            save_query: false,
+            no_cache: true,
+            ..SearchOptions::new(5)
+        };
+
+        let online = state.search("aaaa bbbb", &options).unwrap();
+        assert!(!online.degraded);
+
+        let fail_with = |kind| crate::openai::TEST_API_FAILURE.with(|f| f.set(kind));
+        fail_with(Some(std::io::ErrorKind::ConnectionRefused));
+
+        let offline = state.search("aaaa bbbb", &options).unwrap();
+        assert!(offline.degraded);
+        assert_eq!(
+            online
+                .results
+                .iter()
+                .map(|r| (&r.filepath, r.subset))
+                .collect::<Vec<_>>(),
+            offline
+                .results
+                .iter()
+                .map(|r| (&r.filepath, r.subset))
+                .collect::<Vec<_>>()
+        );
+
+        let response = state
+            .query(
+                RequestPayload::Query {
+                    k: 5,
+                    offset: 0,
+                    query: "never asked before".to_string(),
+                    filters: Vec::new(),
+                    exclude_paths: false,
+                    group_by: None,
+                    group_score: GroupScore::Max,
+                    discard_query: true,
+                    include_boilerplate: false,
+                    no_cache: false,
+                    debug: false,
+                    model: None,
+                    search_mode: hnsw::SearchMode::Balanced,
+                    granularity: crate::message::Granularity::Chunk,
+                    paths: None,
+                    collection: None,
+                    dedupe_threshold: None,
+                },
+                None,
+            )
+            .unwrap();
+        let response: DeweyErrorResponse = serde_json::from_str(&response).unwrap();
+        assert_eq!(response.error, "embedding_unavailable");
+
+        // the API turning a query down isn't the network being down
+        fail_with(Some(std::io::ErrorKind::InvalidData));
+        let error = state.search("aaaa bbbb", &options).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        fail_with(None);
+    }
+
+    // simultaneous queries each get their own saved file and their own results
+    #[test]
+    fn concurrent_queries_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let target = config::get_home_dir().join("test_repo");
+        let topics = [
+            "anchor", "bramble", "cobalt", "dahlia", "ember", "fjord", "garnet", "harbor",
+            "indigo", "juniper",
+        ];
+        for topic in topics {
+            crate::write_file!(target.join(format!("{}.rs", topic)), [topic; 3].join(" "));
+        }
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        // the same graph every run, linking every node to every other,
+        // so the search can't miss a chunk behind the boilerplate copies of `setup`
+        let blocks = crate::dbio::get_all_blocks().unwrap();
+        let params = hnsw::HNSWParams {
+            m_max: Some(blocks.len()),
+            m_max_bottom: Some(blocks.len()),
+            seed: Some(5),
+            ..Default::default()
+        };
+        let state = ServerState::with_index(HNSW::build(&params).unwrap());
+
+        // distinct texts that embed the same as their topic,
+        // since punctuation doesn't make it into the mock embeddings
+        let queries = (0..100)
+            .map(|i| {
+                format!(
+                    "{}{}",
+                    topics[i % topics.len()],
+                    "?".repeat(i / topics.len() + 1)
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let options = SearchOptions {
+            exclude_paths: true,
+            ..SearchOptions::new(3)
+        };
+
+        let results = std::thread::scope(|scope| {
+            let handles = queries
+                .iter()
+                .map(|query| scope.spawn(|| state.search(query, &options)))
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        // each query gets the results it would have gotten on its own
+        let filepaths = |response: &DeweyResponse| {
+            response
+                .results
+                .iter()
+                .map(|r| (r.filepath.clone(), r.subset))
+                .collect::<Vec<_>>()
+        };
+
+        let alone = SearchOptions {
+            save_query: false,
+            ..options
+        };
+        for (query, response) in queries.iter().zip(results.iter()) {
+            let expected = state.search(query, &alone).unwrap();
+            assert_eq!(filepaths(response), filepaths(&expected), "{}", query);
+        }
+
+        // and the top result is the file nearest the query by brute force, so mixed up results would show
+        let metric = state.index.metric;
+        let chunks = blocks
+            .into_iter()
+            .map(|block| {
+                let mut embedding = *block.embedding;
+                metric.prepare(&mut embedding);
+                embedding
+            })
+            .filter(|e| !crate::embedding::is_field_source(&e.source_file))
+            .collect::<Vec<_>>();
+        for (query, response) in queries.iter().zip(results.iter()) {
+            let source = EmbeddingSource {
+                filepath: String::new(),
+                meta: std::collections::HashSet::new(),
+                subset: None,
+                hash: String::new(),
+                chunk_hash: None,
+            };
+            let mut embedding = openai::embed_text(&source, query).unwrap();
+            metric.prepare(&mut embedding);
+            let nearest = chunks
+                .iter()
+                .min_by(|a, b| {
+                    hnsw::distance(a, &embedding, metric)
+                        .total_cmp(&hnsw::distance(b, &embedding, metric))
+                })
+                .unwrap();
+            assert_eq!(
+                response.results[0].filepath, nearest.source_file.filepath,
+                "{}",
+                query
+            );
+        }
+
+        let mut saved = std::fs::read_dir(config::get_queries_dir())
+            .unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect::<Vec<_>>();
+        saved.sort();
+
+        let mut expected = queries.clone();
+        expected.sort();
+        assert_eq!(saved, expected);
+
+        assert!(sweep_queries(10).is_ok());
+        assert_eq!(
+            std::fs::read_dir(config::get_queries_dir())
+                .unwrap()
+                .count(),
+            10
+        );
+    }
+
+    #[test]
+    fn grouped_search_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+
+        let options = SearchOptions {
+            exclude_paths: true,
+            group_by: Some(GroupBy::File),
+            ..SearchOptions::new(10)
+        };
+
+        let response = state.search("aaaa", &options).unwrap();
+        assert!(response.results.is_empty());
+
+        let target = config::get_home_dir().join("test_repo");
+        let mut keys = response.groups.iter().map(|g| &g.key).collect::<Vec<_>>();
+        keys.sort();
+        let mut tracked = get_tracked_files()
+            .iter()
+            .map(|f| target.join(f).to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        tracked.sort();
+        assert_eq!(keys, tracked.iter().collect::<Vec<_>>());
+
+        for group in response.groups.iter() {
+            assert!(!group.top_chunks.is_empty() && group.top_chunks.len() <= GROUP_CHUNKS);
+            assert!(group.top_chunks.iter().all(|c| c.filepath == group.key));
+            assert_eq!(group.score, group.top_chunks[0].score);
+        }
+
+        let options = SearchOptions {
+            group_by: Some(GroupBy::Directory { depth: 1 }),
+            group_score: GroupScore::Mean,
+            ..options
+        };
+
+        let response = state.search("aaaa", &options).unwrap();
+        assert_eq!(
+            response.groups.iter().map(|g| &g.key).collect::<Vec<_>>(),
+            vec![&target.to_string_lossy().to_string()]
+        );
+    }
+
+    // two handles open side by side only ever see their own files,
+    // and leave the default data directory alone
+    #[test]
+    fn independent_handles_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let root = config::get_home_dir();
+        let mut handles = Vec::new();
+        for name in ["first", "second"] {
+            let docs = root.join(format!("{}_docs", name));
+            std::fs::create_dir_all(&docs).unwrap();
+            crate::write_file!(docs.join("notes.md"), format!("{} notes on {}", name, name));
+            crate::write_file!(docs.join("ideas.md"), format!("{} ideas", name));
+
+            let mut handle = Dewey::open(&root.join(name)).unwrap();
+            let config_dir = handle.paths().config_dir.clone();
+            crate::write_file!(
+                config_dir.join("ledger"),
+                format!("{} --{}", docs.display(), name)
+            );
+            crate::write_file!(
+                config_dir.join("rules"),
+                "* --minlength 0 --maxlength 512 --alphanumeric true"
+            );
+
+            assert_eq!(handle.sync().unwrap().diff.added.len(), 2);
+            handles.push((handle, docs));
+        }
+
+        let search = |handle: &Dewey| {
+            let options = SearchOptions {
+                save_query: false,
+                ..SearchOptions::new(10)
+            };
+
+            handle
+                .query("notes", &options)
+                .unwrap()
+                .results
+                .into_iter()
+                .map(|r| r.filepath)
+                .collect::<Vec<_>>()
+        };
+
+        for (handle, docs) in handles.iter() {
+            let results = search(handle);
+            assert_eq!(results.len(), 4);
+            assert!(results
+                .iter()
+                .all(|r| r.starts_with(&*docs.to_string_lossy())));
+        }
+
+        let [(first, first_docs), (second, _)] = &mut handles[..] else {
+            unreachable!()
+        };
+
+        let extra = first_docs.join("extra.md");
+        crate::write_file!(&extra, "extra notes");
+        assert!(
+            first
+                .insert_document(&extra, std::collections::HashSet::new())
+                .unwrap()
+                > 0
+        );
+
+        let extra = extra.to_string_lossy().to_string();
+        assert!(search(first).contains(&extra));
+        assert!(!search(second).contains(&extra));
+        assert!(
+            !std::fs::read_to_string(second.paths().local_dir.join("ledger"))
+                .unwrap()
+                .contains(&extra)
+        );
+
+        let notes = first_docs.join("notes.md");
+        assert_eq!(first.delete_file(&notes).unwrap(), 3);
+        assert!(!search(first).contains(&notes.to_string_lossy().to_string()));
+        assert_eq!(search(second).len(), 4);
+
+        // nothing went into the default paths
+        assert!(!config::get_data_dir().join("directory").exists());
+        assert!(
+            !std::fs::read_to_string(config::get_local_dir().join("ledger"))
+                .unwrap()
+                .contains("_docs")
+        );
+    }
+
+    // files behind an acl only turn up for a token granted it, and the rest are counted as withheld
+    #[test]
+    fn acl_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let repo = config::get_home_dir().join("test_repo");
+        let topics = [
+            "parser grammar",
+            "network retries",
+            "cache eviction",
+            "file locks",
+        ];
+        for (tf, topic) in get_tracked_files().iter().zip(topics) {
+            write_file!(repo.join(tf), format!("fn main() {{ {} }}", topic));
+        }
+
+        let private = config::get_home_dir().join("private_drive");
+        crate::create_dir!(&private);
+        let salary = private.join("salary.txt");
+        write_file!(&salary, "salary review: network retries");
+
+        let ledger = config::get_config_dir().join("ledger");
+        let contents = std::fs::read_to_string(&ledger).unwrap();
+        write_file!(
+            &ledger,
+            format!(
+                "{}\n{} --acl private\n",
+                contents.trim_end(),
+                private.display()
+            )
+        );
+        write_file!(
+            config::get_config_dir().join("config"),
+            "acl_token admin-token private\n"
+        );
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+        let salary = salary.canonicalize().unwrap().to_string_lossy().to_string();
+
+        let request = |payload: RequestPayload, token: &str| DeweyRequest {
+            message_type: match payload {
+                RequestPayload::FileInfo { .. } => "file_info",
+                _ => "query",
+            }
+            .to_string(),
+            payload,
+            expect_generation: None,
+            trace_id: None,
+            token: Some(token.to_string()),
+        };
+        let query = |paths: Option<Vec<String>>, token: &str| {
+            let response = state.handle_shared(request(
+                RequestPayload::Query {
+                    k: 10,
+                    offset: 0,
+                    query: "network retries".to_string(),
+                    filters: Vec::new(),
+                    exclude_paths: false,
+                    group_by: None,
+                    group_score: GroupScore::Max,
+                    discard_query: true,
+                    include_boilerplate: false,
+                    no_cache: false,
+                    debug: false,
+                    model: None,
+                    search_mode: hnsw::SearchMode::Balanced,
+                    granularity: crate::message::Granularity::Chunk,
+                    paths,
+                    collection: None,
+                    dedupe_threshold: None,
+                },
+                token,
+            ));
+
+            serde_json::from_str::<DeweyResponse>(&response).unwrap()
+        };
+
+        let admin = query(None, "admin-token");
+        assert!(admin.results.iter().any(|r| r.filepath == salary));
+        assert_eq!(admin.filtered_by_acl, 0);
+
+        // the cached response for the admin isn't handed to anyone else
+        let guest = query(None, "guest-token");
+        assert!(!guest.results.is_empty());
+        assert!(guest.results.iter().all(|r| r.filepath != salary));
+        assert!(guest.filtered_by_acl > 0);
+
+        let guest = query(Some(vec![salary.clone()]), "guest-token");
+        assert!(guest.results.is_empty());
+        assert!(guest.filtered_by_acl > 0);
+
+        let file_info = |filepath: &str, token: &str| {
+            let response = state.handle_shared(request(
+                RequestPayload::FileInfo {
+                    filepath: filepath.to_string(),
+                },
+                token,
+            ));
+
+            serde_json::from_str::<DeweyFileInfoResponse>(&response).unwrap()
+        };
+
+        let guest = file_info(&salary, "guest-token");
+        assert!(guest.filtered_by_acl);
+        assert!(guest.info.is_none() && guest.ledger.is_none());
+
+        let admin = file_info(&salary, "admin-token");
+        assert!(!admin.filtered_by_acl);
+        assert!(admin.info.is_some() && admin.ledger.is_some());
+
+        // nor does the stable id of one of its chunks say which file it's in
+        let stable_id = admin
+            .info
+            .unwrap()
+            .stable_ids
+            .into_iter()
+            .flatten()
+            .next()
+            .unwrap();
+        let guest = file_info(&stable_id, "guest-token");
+        assert!(guest.filtered_by_acl);
+        assert_eq!(guest.filepath, stable_id);
+        assert!(guest.chunk.is_none() && guest.info.is_none());
+
+        let admin = file_info(&stable_id, "admin-token");
+        assert_eq!(admin.filepath, salary);
+        assert_eq!(admin.chunk.unwrap().stable_id, stable_id);
+        assert!(admin.info.is_some());
+    }
+
+    // tagging files that are already embedded with `--acl` hides them from queries
+    // without waiting on them to be embedded again
+    #[test]
+    fn acl_retag_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        write_file!(
+            config::get_config_dir().join("config"),
+            "acl_token admin-token private\n"
+        );
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+        let query = |token: &str| {
+            let response = state.handle_shared(DeweyRequest {
+                message_type: "query".to_string(),
+                payload: RequestPayload::Query {
+                    k: 5,
+                    offset: 0,
+                    query: "aaaa".to_string(),
+                    filters: Vec::new(),
+                    exclude_paths: false,
+                    group_by: None,
+                    group_score: GroupScore::Max,
+                    discard_query: true,
+                    include_boilerplate: false,
+                    no_cache: false,
+                    debug: false,
+                    model: None,
+                    search_mode: hnsw::SearchMode::Balanced,
+                    granularity: Granularity::Chunk,
+                    paths: None,
+                    collection: None,
+                    dedupe_threshold: None,
+                },
+                expect_generation: None,
+                trace_id: None,
+                token: Some(token.to_string()),
+            });
+
+            serde_json::from_str::<DeweyResponse>(&response).unwrap()
+        };
+
+        let guest = query("guest-token");
+        assert_eq!(guest.results.len(), 5);
+        assert_eq!(guest.filtered_by_acl, 0);
+
+        let ledger = config::get_config_dir().join("ledger");
+        let contents = std::fs::read_to_string(&ledger).unwrap();
+        write_file!(
+            &ledger,
+            contents.replace("--tracked", "--tracked --acl private")
+        );
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(crate::ledger::get_stale_files().unwrap().is_empty());
+
+        // the cached response from before the ledger changed isn't handed back either
+        let guest = query("guest-token");
+        assert!(guest.results.is_empty());
+        assert!(guest.filtered_by_acl > 0);
+
+        let admin = query("admin-token");
+        assert_eq!(admin.results.len(), 5);
+        assert_eq!(admin.filtered_by_acl, 0);
+    }
+
+    // a tracked file that's deleted before the next sync has no ledger entry,
+    // and a data directory without a ledger is an error, neither of them a panic
+    #[test]
+    fn file_info_missing_file_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let state = ServerState::with_index(HNSW::build(&hnsw::HNSWParams::default()).unwrap());
+        let ledger = crate::ledger::read_ledger().unwrap();
+        let (deleted, kept) = (&ledger[0].filepath, &ledger[1].filepath);
+        std::fs::remove_file(deleted).unwrap();
+
+        let file_info = |filepath: &str| {
+            state.handle_shared(DeweyRequest {
+                message_type: "file_info".to_string(),
+                payload: RequestPayload::FileInfo {
+                    filepath: filepath.to_string(),
+                },
+                expect_generation: None,
+                trace_id: None,
+                token: None,
+            })
+        };
+
+        let response = serde_json::from_str::<DeweyFileInfoResponse>(&file_info(deleted)).unwrap();
+        assert!(response.ledger.is_none());
+        assert!(response.info.is_some());
+
+        let response = serde_json::from_str::<DeweyFileInfoResponse>(&file_info(kept)).unwrap();
+        assert_eq!(response.ledger.unwrap().filepath, *kept);
+
+        std::fs::remove_file(config::get_local_dir().join("ledger")).unwrap();
+        let response = serde_json::from_str::<DeweyErrorResponse>(&file_info(kept)).unwrap();
+        assert_eq!(response.error, "request_failed");
+    }
+
+    // a file that's renamed keeps its embeddings under the new path, without the API being asked
+    // for anything, while one that's changed as well is embedded again
+    #[test]
+    fn renamed_files_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let repo = config::get_home_dir().join("test_repo");
+        let contents = |topic: &str| {
+            (0..100)
+                .map(|i| format!("fn {}_{}() {{ {} }}\n", topic.replace(' ', "_"), i, topic))
+                .collect::<String>()
+        };
+        write_file!(repo.join("a.rs"), contents("parser tokens"));
+        write_file!(repo.join("b.rs"), contents("network retries"));
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+        let index = HNSW::build(&hnsw::HNSWParams::default()).unwrap();
+
+        let path = |name: &str| {
+            repo.join(name)
+                .canonicalize()
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        };
+        let (old, changed) = (path("a.rs"), path("b.rs"));
+        assert!(dbio::file_info(&old).unwrap().unwrap().chunk_count > 1);
+
+        std::fs::rename(repo.join("a.rs"), repo.join("renamed.rs")).unwrap();
+        std::fs::remove_file(repo.join("b.rs")).unwrap();
+        write_file!(
+            repo.join("moved.rs"),
+            contents("network retries") + "// and changed\n"
+        );
+        let (renamed, moved) = (path("renamed.rs"), path("moved.rs"));
+
+        let calls = || crate::openai::TEST_API_CALLS.with(|calls| calls.get());
+        let before = calls();
+
+        let diff = crate::ledger::sync_ledger_config(true, None).unwrap().diff;
+        assert_eq!(diff.renamed, vec![(old.clone(), renamed.clone())]);
+        assert_eq!(diff.added, vec![moved.clone()]);
+        assert_eq!(diff.removed, vec![changed.clone()]);
+        assert_eq!(calls(), before);
+
+        assert!(dbio::file_info(&old).unwrap().is_none());
+        let info = dbio::file_info(&renamed).unwrap().unwrap();
+        assert!(info.chunk_count > 1);
+        assert_eq!(
+            dbio::read_centroids()
+                .unwrap()
+                .iter()
+                .filter(|c| c.source_file.filepath == renamed)
+                .count(),
+            1
+        );
+
+        // the index from before the rename still finds the file, under its new path
+        let state = ServerState::with_index(index);
+        let options = SearchOptions {
+            save_query: false,
+            ..SearchOptions::new(5)
+        };
+        let response = state.search("parser tokens", &options).unwrap();
+        assert!(!response.results.is_empty());
+        assert!(response.results.iter().all(|r| r.filepath == renamed));
+
+        // another sync has nothing left to rename, and the changed file is left for an embed
+        let diff = crate::ledger::sync_ledger_config(true, None).unwrap().diff;
+        assert!(diff.renamed.is_empty() && diff.added.is_empty());
+        assert!(dbio::file_info(&moved).unwrap().is_none());
+    }
+
+    // files routed to a mock model are embedded, stored and searched apart from everything else
+    #[test]
+    fn model_routing_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let target = config::get_home_dir().join("test_repo");
+        for (name, ext) in [
+            ("alpha", "txt"),
+            ("beta", "txt"),
+            ("gamma", "log"),
+            ("delta", "log"),
+        ] {
+            crate::write_file!(
+                target.join(format!("{}.{}", name, ext)),
+                format!("{} notes on lighthouses and {}", name, "aaaa ".repeat(20))
+            );
+        }
+
+        let rules_path = config::get_config_dir().join("rules");
+        let rules = std::fs::read_to_string(&rules_path).unwrap();
+        crate::write_file!(
+            &rules_path,
+            format!("{}\ntxt --model mock-small\nlog --model mock-large", rules)
+        );
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let stores = config::get_paths().model_stores().unwrap();
+        assert_eq!(
+            stores.iter().map(|s| s.model.clone()).collect::<Vec<_>>(),
+            vec![
+                None,
+                Some("mock-large".to_string()),
+                Some("mock-small".to_string())
+            ]
+        );
+
+        let routed = |store: &config::DataPaths| {
+            store.scope(|| {
+                assert_eq!(
+                    dbio::get_blocks_model().unwrap().unwrap().name,
+                    config::get_embedding_model()
+                );
+
+                let mut files = dbio::read_directory_entries()
+                    .unwrap()
+                    .into_iter()
+                    .map(|(_, filepath, _)| filepath)
+                    .collect::<Vec<_>>();
+                files.sort();
+                files.dedup();
+                files
+            })
+        };
+
+        let ext_of = |f: &String| f.rsplit('.').next().unwrap().to_string();
+        assert!(routed(&stores[0]).iter().all(|f| ext_of(f) == "rs"));
+        assert_eq!(routed(&stores[1]).len(), 2);
+        assert!(routed(&stores[1]).iter().all(|f| ext_of(f) == "log"));
+        assert_eq!(routed(&stores[2]).len(), 2);
+        assert!(routed(&stores[2]).iter().all(|f| ext_of(f) == "txt"));
+
+        assert_eq!(
+            sync::file_store(&target.join("alpha.txt").to_string_lossy())
+                .unwrap()
+                .model,
+            Some("mock-small".to_string())
+        );
+
+        for store in stores.iter() {
+            assert!(store.scope(dbio::build_index).unwrap().is_some());
+        }
+
+        let state = ServerState::new().unwrap();
+        let search = |model: Option<&str>| {
+            let options = SearchOptions {
+                model: model.map(|m| m.to_string()),
+                save_query: false,
+                ..SearchOptions::new(50)
+            };
+
+            state.search("lighthouses", &options).map(|r| {
+                r.results
+                    .iter()
+                    .map(|r| ext_of(&r.filepath))
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        // each query is embedded with the model of the index it searches
+        let asked = || openai::TEST_API_MODELS.with(|models| models.take());
+        asked();
+
+        let small = search(Some("mock-small")).unwrap();
+        assert!(!small.is_empty() && small.iter().all(|e| e == "txt"));
+        assert_eq!(asked(), vec!["mock-small".to_string()]);
+        let large = search(Some("mock-large")).unwrap();
+        assert!(!large.is_empty() && large.iter().all(|e| e == "log"));
+        assert_eq!(asked(), vec!["mock-large".to_string()]);
+        let default = search(None).unwrap();
+        assert!(!default.is_empty() && default.iter().all(|e| e == "rs"));
+        assert_eq!(asked(), vec![config::get_embedding_model()]);
+
+        assert_eq!(
+            search(Some("mock-missing")).unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        assert!(asked().is_empty());
+    }
+
+    // a snapshot taken before the configured model changed is queried with the model it was
+    // embedded with, and any other model is turned away before it's asked for an embedding
+    #[test]
+    fn snapshot_model_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let target = config::get_home_dir().join("test_repo");
+        let files = get_tracked_files();
+        let topics = [
+            "parser tokens",
+            "network sockets",
+            "cache eviction",
+            "file locks",
+        ];
+        for (tf, topic) in files.iter().zip(topics) {
+            crate::write_file!(target.join(tf), format!("fn main() {{ {} }}", topic));
+        }
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+
+        let index = HNSW::build(&hnsw::HNSWParams::default()).unwrap();
+        index
+            .serialize(&config::get_data_dir().join("index"))
+            .unwrap();
+        assert!(crate::dbio::snapshot("before").is_ok());
+
+        let old_model = config::get_embedding_model();
+        let config_path = config::get_config_dir().join("config");
+        let contents = std::fs::read_to_string(&config_path).unwrap_or_default();
+        crate::write_file!(&config_path, format!("{}\nmodel mock-large\n", contents));
+        assert_eq!(config::get_embedding_model(), "mock-large");
+
+        let state = ServerState::with_index(index);
+        let search = |model: Option<&str>| {
+            let options = SearchOptions {
+                exclude_paths: true,
+                save_query: false,
+                collection: Some("snapshot:before".to_string()),
+                model: model.map(|m| m.to_string()),
+                ..SearchOptions::new(1)
+            };
+
+            state.search("parser tokens", &options)
+        };
+
+        let asked = || openai::TEST_API_MODELS.with(|models| models.take());
+        asked();
+
+        let results = search(Some(&old_model)).unwrap().results;
+        assert_eq!(
+            results[0].filepath,
+            target.join(&files[0]).to_string_lossy()
+        );
+        assert_eq!(asked(), vec![old_model.clone()]);
+
+        // the configured model and any other one don't match what the snapshot was embedded with
+        for model in [None, Some("mock-large"), Some("mock-small")] {
+            let error = search(model).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+            assert!(error.to_string().contains(&old_model));
+        }
+        assert!(asked().is_empty());
+    }
+
+    // a scheduled cycle embeds a file that changed after the last sync, and only once
+    #[test]
+    fn scheduled_maintenance_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let target = config::get_home_dir().join("test_repo");
+        let files = get_tracked_files();
+        for (i, tf) in files.iter().enumerate() {
+            crate::write_file!(target.join(tf), format!("fn main() {{ topic{} }}", i));
+        }
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(
+            crate::sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false).is_ok()
+        );
+        assert!(crate::dbio::build_index().unwrap().is_some());
+
+        let state = std::sync::Arc::new(std::sync::RwLock::new(ServerState::new().unwrap()));
+        crate::write_file!(target.join(&files[0]), "fn main() { lighthouse keeper }");
+
+        let scheduler = maintenance::Scheduler::spawn(
+            std::sync::Arc::clone(&state),
+            maintenance::Schedule::Interval(std::time::Duration::from_secs(1)),
+            None,
+        );
+
+        let mut run = None;
+        for _ in 0..300 {
+            run = state.read().unwrap().maintenance.clone();
+            if run.is_some() {
+                break;
+            }
+
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        drop(scheduler);
+
+        match run.unwrap().outcome {
+            MaintenanceOutcome::Completed { embedded, .. } => assert_eq!(embedded, 1),
+            outcome => panic!("maintenance didn't complete: {:?}", outcome),
+        }
+
+        let mut state = state.write().unwrap();
+        let options = SearchOptions {
+            exclude_paths: true,
+            save_query: false,
+            ..SearchOptions::new(1)
+        };
+        let results = state.search("lighthouse keeper", &options).unwrap().results;
+        assert_eq!(
+            results[0].filepath,
+            target.join(&files[0]).to_string_lossy()
+        );
+
+        // the ledger caught up with the file, so there's nothing left to embed
+        assert!(matches!(
+            state.maintain(None).outcome,
+            MaintenanceOutcome::Completed { embedded: 0, .. }
+        ));
+
+        // and a cycle that finds the data directory busy leaves it alone
+        let _lock = lock::DataLock::acquire(lock::LockMode::Exclusive, "test").unwrap();
+        assert!(matches!(
+            state.maintain(None).outcome,
+            MaintenanceOutcome::Skipped { .. }
+        ));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::embedding::EmbeddingSource;
+use crate::logger::Logger;
+use crate::message::{DeweyResponse, DeweyResponseItem};
+use crate::{error, info};
+
+// the results of a query as files in a directory, for tools that would rather read files:
+// one `<rank>_<filename>.txt` per result holding its chunk,
+// and a `manifest.json` describing them, written last so that its being there means they all are
+
+pub const MANIFEST: &str = "manifest.json";
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ExportedItem {
+    pub rank: usize,
+    // the file the chunk was written to, relative to the directory,
+    // `None` if the result's file couldn't be read
+    pub file: Option<String>,
+    pub filepath: String,
+    pub subset: (u64, u64),
+    pub score: f32,
+    pub path_match: bool,
+    pub file_match: bool,
+    pub stale: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ExportManifest {
+    pub query: String,
+    pub generation: u64,
+    pub items: Vec<ExportedItem>,
+}
+
+// makes `dir` ready to be written to, creating it if it doesn't exist
+//
+// a directory with anything in it is refused unless `force` is given, which empties it
+pub fn prepare_out_dir(dir: &std::path::Path, force: bool) -> Result<(), std::io::Error> {
+    if !dir.exists() {
+        return std::fs::create_dir_all(dir);
+    }
+
+    let entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    if !force {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!(
+                "{} isn't empty, use --force to clear it",
+                dir.to_string_lossy()
+            ),
+        ));
+    }
+
+    for entry in entries {
+        let path = entry.path();
+        match entry.file_type()?.is_dir() {
+            true => std::fs::remove_dir_all(&path)?,
+            false => std::fs::remove_file(&path)?,
+        }
+    }
+
+    info!("cleared {} for export", dir.to_string_lossy());
+    Ok(())
+}
+
+// the last part of `filepath` with anything but letters, digits, `.`, `-`, and `_` replaced
+fn sanitize_filename(filepath: &str) -> String {
+    let name = std::path::Path::new(filepath)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let name = name
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect::<String>();
+
+    match name.trim_matches('.').is_empty() {
+        true => "result".to_string(),
+        false => name,
+    }
+}
+
+// the text a result was embedded from: its path for path matches, the whole file for file
+// matches, and its subset of the file otherwise
+fn result_contents(item: &DeweyResponseItem) -> Result<String, std::io::Error> {
+    if item.path_match {
+        return Ok(item.filepath.clone());
+    }
+
+    crate::parsing::read_source(&EmbeddingSource {
+        filepath: item.filepath.clone(),
+        meta: Default::default(),
+        subset: (!item.file_match).then_some(item.subset),
+        hash: String::new(),
+        chunk_hash: None,
+    })
+}
+
+// writes the results of `response` to `dir`, which `prepare_out_dir` has made ready,
+// ranked from `offset + 1` like they're printed
+//
+// grouped responses are written as the chunks of each group in turn
+pub fn export_results(
+    query: &str,
+    response: &DeweyResponse,
+    offset: usize,
+    dir: &std::path::Path,
+) -> Result<ExportManifest, std::io::Error> {
+    let results = response.results.iter().chain(
+        response
+            .groups
+            .iter()
+            .flat_map(|group| group.top_chunks.iter()),
+    );
+
+    let mut items = Vec::new();
+    for (i, result) in results.enumerate() {
+        let rank = offset + i + 1;
+        let file = match result_contents(result) {
+            Ok(contents) => {
+                let file = format!("{}_{}.txt", rank, sanitize_filename(&result.filepath));
+                std::fs::write(dir.join(&file), contents)?;
+                Some(file)
+            }
+            Err(e) => {
+                error!(
+                    "not exporting result {} from {}: {}",
+                    rank, result.filepath, e
+                );
+                None
+            }
+        };
+
+        items.push(ExportedItem {
+            rank,
+            file,
+            filepath: result.filepath.clone(),
+            subset: result.subset,
+            score: result.score,
+            path_match: result.path_match,
+            file_match: result.file_match,
+            stale: result.stale,
+        });
+    }
+
+    let manifest = ExportManifest {
+        query: query.to_string(),
+        generation: response.generation,
+        items,
+    };
+
+    std::fs::write(
+        dir.join(MANIFEST),
+        serde_json::to_string_pretty(&manifest).map_err(std::io::Error::other)?,
+    )?;
+
+    info!(
+        "exported {} results to {}",
+        manifest.items.len(),
+        dir.to_string_lossy()
+    );
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_common::*;
+    use crate::{create_dir, write_file};
+
+    #[test]
+    fn export_results_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(crate::sync::sync_index(
+            true,
+            false,
+            false,
+            None,
+            crate::sync::OverQuota::Stop,
+            false
+        )
+        .is_ok());
+
+        let state = crate::ServerState::with_index(
+            crate::hnsw::HNSW::build(&crate::hnsw::HNSWParams::default()).unwrap(),
+        );
+        let options = crate::SearchOptions {
+            save_query: false,
+            exclude_paths: true,
+            ..crate::SearchOptions::new(3)
+        };
+        let response = state.search("aaaa bbbb", &options).unwrap();
+        assert!(!response.results.is_empty());
+
+        let dir = crate::config::get_home_dir().join("out");
+        assert!(prepare_out_dir(&dir, false).is_ok());
+        let manifest = export_results("aaaa bbbb", &response, 0, &dir).unwrap();
+
+        // the manifest on disk is the one returned, and names every file that was written
+        let written: ExportManifest =
+            serde_json::from_str(&std::fs::read_to_string(dir.join(MANIFEST)).unwrap()).unwrap();
+        assert_eq!(written, manifest);
+        assert_eq!(written.query, "aaaa bbbb");
+        assert_eq!(written.generation, response.generation);
+        assert_eq!(written.items.len(), response.results.len());
+
+        let mut files = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|f| f != MANIFEST)
+            .collect::<Vec<_>>();
+        files.sort();
+        let mut named = written
+            .items
+            .iter()
+            .map(|item| item.file.clone().unwrap())
+            .collect::<Vec<_>>();
+        named.sort();
+        assert_eq!(files, named);
+
+        for (i, (item, result)) in written
+            .items
+            .iter()
+            .zip(response.results.iter())
+            .enumerate()
+        {
+            assert_eq!(item.rank, i + 1);
+            assert_eq!(
+                item.file.as_deref().unwrap(),
+                format!("{}_{}.txt", i + 1, sanitize_filename(&result.filepath))
+            );
+            assert_eq!(
+                (&item.filepath, item.subset),
+                (&result.filepath, result.subset)
+            );
+
+            let contents = std::fs::read_to_string(dir.join(item.file.as_ref().unwrap())).unwrap();
+            let (start, end) = item.subset;
+            let file = std::fs::read_to_string(&item.filepath).unwrap();
+            assert_eq!(contents, file[start as usize..end as usize]);
+        }
+
+        // a directory with something in it is only written to with `force`, which clears it
+        let error = prepare_out_dir(&dir, false).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+        create_dir!(dir.join("nested"));
+        write_file!(dir.join("nested").join("leftover"), "old");
+        assert!(prepare_out_dir(&dir, true).is_ok());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        assert_eq!(sanitize_filename("/a/b/my file (1).rs"), "my_file__1_.rs");
+        assert_eq!(sanitize_filename("/a/.."), "result");
+    }
+}
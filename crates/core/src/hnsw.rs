@@ -1,15 +1,21 @@
-use rand::{thread_rng, Rng};
-use std::collections::{HashMap, HashSet};
-use std::io::{Read, Write};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Read, Seek};
+use std::sync::{Arc, Mutex, PoisonError};
 
 use serialize_macros::Serialize;
 
-use crate::cache::EmbeddingCache;
+use crate::cache::{BlockStore, EmbeddingCache, EmbeddingLookup};
 use crate::config::get_data_dir;
-use crate::dbio::{get_directory, BLOCK_SIZE};
+use crate::dbio::{get_blocks_model, get_directory, read_directory_entries, BLOCK_SIZE};
+use crate::embedding::{
+    is_chunk_meta, Embedding, EmbeddingModel, EmbeddingSource, EMBED_DIM, PATH_META, TITLE_META,
+};
 use crate::logger::Logger;
-use crate::openai::{Embedding, EMBED_DIM};
 use crate::serialization::Serialize;
+pub use crate::types::{FilterStrategy, LayerTrace, QueryTrace, SearchMode};
 use crate::{error, info};
 
 pub fn dot(a: &Embedding, b: &Embedding) -> f32 {
@@ -33,401 +39,2925 @@ pub fn normalize(embedding: &mut Embedding) {
     }
 }
 
+// embeddings within this of unit length are taken as normalized
+const UNIT_TOLERANCE: f32 = 1e-3;
+
+// unit length, or all zeros, which has no direction to normalize to
+pub fn is_normalized(embedding: &Embedding) -> bool {
+    let length = dot(embedding, embedding).sqrt();
+    length == 0.0 || (length - 1.0).abs() <= UNIT_TOLERANCE
+}
+
+// how far apart two embeddings are, which an index is built and searched with
+//
+// cosine only compares directions, which is all the normalized vectors most providers return have
+// the others keep the vectors' magnitudes, so embeddings aren't normalized under them
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    #[default]
+    Cosine,
+    // 1 - a.b, so a larger product is closer
+    Dot,
+    L2,
+}
+
+impl Metric {
+    pub fn from_string(metric: &str) -> Result<Self, String> {
+        match metric {
+            "cosine" => Ok(Metric::Cosine),
+            "dot" => Ok(Metric::Dot),
+            "l2" => Ok(Metric::L2),
+            _ => Err(format!(
+                "unknown metric {}, expected cosine, dot, or l2",
+                metric
+            )),
+        }
+    }
+
+    // the metric new indexes are built with
+    pub fn current() -> Self {
+        crate::config::get_metric()
+    }
+
+    // embeddings are only normalized for the metric that ignores their magnitude anyway
+    pub fn prepare(&self, embedding: &mut Embedding) {
+        if *self == Metric::Cosine {
+            normalize(embedding);
+        }
+    }
+}
+
+impl std::fmt::Display for Metric {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Metric::Cosine => write!(f, "cosine"),
+            Metric::Dot => write!(f, "dot"),
+            Metric::L2 => write!(f, "l2"),
+        }
+    }
+}
+
+impl Serialize for Metric {
+    fn to_bytes(&self) -> Vec<u8> {
+        let tag: u8 = match self {
+            Metric::Cosine => 0,
+            Metric::Dot => 1,
+            Metric::L2 => 2,
+        };
+
+        tag.to_bytes()
+    }
+
+    fn from_bytes(bytes: &[u8], cursor: usize) -> Result<(Self, usize), std::io::Error> {
+        let (tag, size) = u8::from_bytes(bytes, cursor)?;
+        let metric = match tag {
+            0 => Metric::Cosine,
+            1 => Metric::Dot,
+            2 => Metric::L2,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unknown metric {}", tag),
+                ))
+            }
+        };
+
+        Ok((metric, size))
+    }
+}
+
+// smaller is closer under every metric
+//
+// embeddings are normalized under cosine (see `Metric::prepare`), which leaves just their product
+pub fn distance(a: &Embedding, b: &Embedding, metric: Metric) -> f32 {
+    match metric {
+        Metric::Cosine | Metric::Dot => 1.0 - dot(a, b),
+        Metric::L2 => {
+            let mut sum = 0.;
+            for i in 0..EMBED_DIM {
+                let d = a.data[i] - b.data[i];
+                sum += d * d;
+            }
+
+            sum.sqrt()
+        }
+    }
+}
+
 type Graph = HashMap<u64, Vec<(u64, f32)>>;
 
+// a layer as searches see it, whether it's held in memory or read off disk as it's walked
+pub trait Layer {
+    // `node`'s edges, `None` if it isn't in the layer
+    fn neighbors(&self, node: u64) -> Option<Cow<'_, [(u64, f32)]>>;
+    fn contains(&self, node: u64) -> bool;
+    fn len(&self) -> usize;
+    // every node in the layer, lowest id first
+    fn nodes(&self) -> Vec<u64>;
+    fn first(&self) -> Option<u64>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Layer for Graph {
+    fn neighbors(&self, node: u64) -> Option<Cow<'_, [(u64, f32)]>> {
+        self.get(&node)
+            .map(|neighbors| Cow::Borrowed(neighbors.as_slice()))
+    }
+
+    fn contains(&self, node: u64) -> bool {
+        self.contains_key(&node)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn nodes(&self) -> Vec<u64> {
+        let mut nodes = self.keys().copied().collect::<Vec<_>>();
+        nodes.sort();
+        nodes
+    }
+
+    fn first(&self) -> Option<u64> {
+        self.keys().min().copied()
+    }
+}
+
 const CACHE_SIZE: u32 = 20 * BLOCK_SIZE as u32;
 
+// the blocks a `BlockStore` holds for queries, as many as the cache holds for building
+pub const QUERY_BLOCKS: usize = CACHE_SIZE as usize / BLOCK_SIZE;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum FilterComparator {
     Equal,
     NotEqual,
 }
 
+// filters are written `[eq|ne] value`, e.g. `eq lang:rust` or `ne tests`,
+// by both the CLI and server clients
+//
+// the comparator can be left off, in which case it's `eq`
+#[derive(Debug, Clone, PartialEq)]
 pub struct Filter {
     pub comparator: FilterComparator,
     pub value: String,
 }
 
 impl Filter {
-    pub fn from_string(input: &String) -> Result<Self, std::io::Error> {
+    pub fn from_string(input: &str) -> Result<Self, std::io::Error> {
         let parts: Vec<&str> = input.split_whitespace().collect();
-        if parts.len() != 2 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "Invalid filter format",
-            ));
-        }
-
-        let comparator = match parts[0] {
-            "eq" => FilterComparator::Equal,
-            "ne" => FilterComparator::NotEqual,
+        let (comparator, value) = match parts.as_slice() {
+            // a lone comparator is missing its value rather than being one
+            [value] if *value != "eq" && *value != "ne" => (FilterComparator::Equal, value),
+            [comparator, value] => match *comparator {
+                "eq" => (FilterComparator::Equal, value),
+                "ne" => (FilterComparator::NotEqual, value),
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!(
+                            "Invalid comparator \"{}\" in filter \"{}\", expected eq or ne",
+                            comparator, input
+                        ),
+                    ))
+                }
+            },
             _ => {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidInput,
-                    "Invalid comparator",
+                    format!(
+                        "Invalid filter format \"{}\", expected \"[eq|ne] value\"",
+                        input
+                    ),
                 ))
             }
         };
 
         Ok(Filter {
             comparator,
-            value: parts[1].to_string(),
+            value: value.to_string(),
         })
     }
 
-    pub fn compare(self: &Self, query: &str) -> bool {
+    pub fn compare(&self, query: &str) -> bool {
         match self.comparator {
             FilterComparator::Equal => query == self.value,
             FilterComparator::NotEqual => query != self.value,
         }
     }
+
+    // an embedding carries several meta tags (e.g. its extension and a fence's `lang:rust`),
+    // so `eq` passes if any of them match and `ne` passes only if none do
+    pub fn matches(&self, meta: &HashSet<String>) -> bool {
+        self.matches_tags(meta.iter())
+    }
+
+    // `matches`, over tags that don't have to be gathered into a set first
+    pub fn matches_tags<'a>(&self, tags: impl Iterator<Item = &'a String>) -> bool {
+        // the path and title markers aren't user-facing meta and are left out of filtering
+        let mut user_meta = tags.filter(|m| *m != PATH_META && *m != TITLE_META);
+        match self.comparator {
+            FilterComparator::Equal => user_meta.any(|m| self.compare(m)),
+            FilterComparator::NotEqual => user_meta.all(|m| self.compare(m)),
+        }
+    }
 }
 
 pub struct Query {
     pub embedding: Embedding,
     pub filters: Vec<Filter>,
+    pub exclude_paths: bool,
+    // collects a `QueryTrace` of the search
+    pub trace: bool,
+    pub mode: SearchMode,
+    // the only nodes that can pass `filters` and `scope`, sorted, if the meta index could tell
+    pub candidates: Option<Vec<u64>>,
+    // the only nodes the query can turn up, sorted, like the chunks of the files it's limited to
+    pub scope: Option<Vec<u64>>,
+    // the ledger's current meta of each file, which filters are checked against
+    // in place of the meta a file's embeddings were made with
+    //
+    // `candidates` has to come from somewhere other than the meta index with it,
+    // since that only knows the meta the embeddings were made with
+    pub meta: Option<Arc<crate::cache::FileMeta>>,
 }
 
-// basic in-memory nearest neighbor index
-// TODO: should we handle huge datasets, beyond what memory can hold?
-#[derive(Serialize)]
-#[allow(unused_attributes)]
-pub struct HNSW {
-    pub size: u32,
-    pub layers: Vec<Graph>,
+impl Query {
+    // whether an embedding of `source` passes every filter
+    //
+    // with `meta`, the tags its file has in the ledger now stand in for the ones it was made with,
+    // other than the tags of the chunk itself, like its `lang:` fence
+    // files the ledger doesn't have keep the meta they were made with
+    pub fn matches_filters(&self, source: &EmbeddingSource) -> bool {
+        match self
+            .meta
+            .as_ref()
+            .and_then(|meta| meta.get(&source.filepath))
+        {
+            Some(current) => self.filters.iter().all(|filter| {
+                let chunk_meta = source.meta.iter().filter(|m| is_chunk_meta(m));
+                filter.matches_tags(chunk_meta.chain(current.iter()))
+            }),
+            None => self
+                .filters
+                .iter()
+                .all(|filter| filter.matches(&source.meta)),
+        }
+    }
 }
 
-impl HNSW {
-    pub fn new(reindex: bool) -> Result<Self, std::io::Error> {
-        if !reindex {
-            info!("loading index from disk");
-            let data_dir = get_data_dir();
-            let hnsw = match Self::deserialize(data_dir.join("index").to_string_lossy().to_string())
-            {
-                Ok(h) => h,
-                Err(e) => {
-                    error!("Error reading index: {}", e);
-                    return Err(e);
-                }
-            };
+// how many of the topmost layer's nodes a thorough search starts from
+const THOROUGH_ENTRY_POINTS: usize = 4;
+
+impl SearchMode {
+    pub fn from_string(mode: &str) -> Result<Self, String> {
+        match mode {
+            "fast" => Ok(SearchMode::Fast),
+            "balanced" => Ok(SearchMode::Balanced),
+            "thorough" => Ok(SearchMode::Thorough),
+            _ => Err(format!(
+                "unknown search mode {}, expected fast, balanced, or thorough",
+                mode
+            )),
+        }
+    }
 
-            return Ok(hnsw);
+    // the candidates a query is searched with, unless it asks for more results than that
+    pub fn ef(&self) -> usize {
+        match self {
+            SearchMode::Fast => 32,
+            SearchMode::Balanced => 200,
+            SearchMode::Thorough => 400,
         }
+    }
+}
 
-        info!("building index from block files");
+impl std::fmt::Display for SearchMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SearchMode::Fast => write!(f, "fast"),
+            SearchMode::Balanced => write!(f, "balanced"),
+            SearchMode::Thorough => write!(f, "thorough"),
+        }
+    }
+}
 
-        let n = get_directory()?.len();
-        let m = n.ilog2();
-        let l = n.ilog2();
-        let p = 1.0 / m as f32;
+// layers with more nodes than this are exported as a sample around the entry point
+const EXPORT_NODE_LIMIT: usize = 500;
 
-        info!(
-            "building HNSW with \n\tn: {}\n\tm: {}\n\tl: {}\n\tp: {}",
-            n, m, l, p
-        );
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ExportNode {
+    pub id: u64,
+    // the id and the end of the path of the file it came from
+    pub label: String,
+}
 
-        let thresholds = (0..l)
-            .map(|j| p * (1.0 - p).powi((j as i32 - l as i32 + 1).abs()))
-            .collect::<Vec<_>>();
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ExportEdge {
+    pub from: u64,
+    pub to: u64,
+    pub distance: f32,
+}
 
-        // normalizing the probabilities since they don't usually add to 1
-        let thresh_sum = thresholds.iter().sum::<f32>();
-        let thresholds = thresholds
-            .iter()
-            .map(|&t| t / thresh_sum)
-            .collect::<Vec<_>>();
+// a layer of the index, as written by `HNSW::export_dot` and `HNSW::export_json`
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct GraphExport {
+    pub layer: usize,
+    // only some of the layer's nodes were exported
+    pub sampled: bool,
+    pub nodes: Vec<ExportNode>,
+    // only the edges between exported nodes
+    pub edges: Vec<ExportEdge>,
+}
+
+// the last few components of a path, enough to tell files apart in a graph
+fn truncate_path(filepath: &str) -> String {
+    const LABEL_CHARS: usize = 32;
+
+    let chars = filepath.chars().count();
+    if chars <= LABEL_CHARS {
+        return filepath.to_string();
+    }
+
+    format!(
+        "...{}",
+        filepath
+            .chars()
+            .skip(chars - LABEL_CHARS)
+            .collect::<String>()
+    )
+}
+
+pub struct QueryResults {
+    // closest first
+    pub results: Vec<(Box<Embedding>, f32)>,
+    // only collected for queries that ask for it
+    pub trace: Option<QueryTrace>,
+}
+
+// limits on how many neighbors a node can keep in each layer
+// `None` derives the bound from the size of the index
+#[derive(Default)]
+pub struct HNSWParams {
+    pub m_max: Option<usize>,
+    // the bottom layer holds every node and gets a relaxed bound, as in the paper
+    pub m_max_bottom: Option<usize>,
+    // how many candidates are considered when picking a new node's neighbors
+    pub ef_construction: Option<usize>,
+    // seeds the layer assignment, so the same blocks always build the same index
+    pub seed: Option<u64>,
+    // `None` is the configured metric
+    pub metric: Option<Metric>,
+    // how many entry points are pinned for queries to start from, `ENTRY_POINTS` if `None`
+    pub entry_points: Option<usize>,
+}
 
-        let mut orphans = HashSet::new();
-        for i in 0..n {
-            orphans.insert(i as u32);
+const EF_CONSTRUCTION: usize = 64;
+
+// how many entry points are pinned unless asked otherwise
+pub const ENTRY_POINTS: usize = 4;
+// pinned entry points are picked out of this many embeddings at random
+const ENTRY_SAMPLE: usize = 1000;
+
+// `k` nodes spread out over the embeddings, picked by farthest-point sampling
+// over a random sample of `nodes`: the first at random, then each the furthest from any picked yet
+fn farthest_points(
+    nodes: &[u64],
+    k: usize,
+    rng: &mut StdRng,
+    metric: Metric,
+    cache: &mut dyn EmbeddingLookup,
+) -> Vec<u64> {
+    // sorted, so the sample is read about a block at a time
+    let mut sample =
+        rand::seq::index::sample(rng, nodes.len(), ENTRY_SAMPLE.min(nodes.len())).into_vec();
+    sample.sort();
+    let sample = sample
+        .into_iter()
+        .filter_map(|i| cache.get(nodes[i] as u32).ok())
+        .collect::<Vec<_>>();
+
+    let first = match sample.is_empty() || k == 0 {
+        true => return Vec::new(),
+        false => rng.gen_range(0..sample.len()),
+    };
+
+    let mut picked = vec![first];
+    let mut nearest = sample
+        .iter()
+        .map(|e| distance(e, &sample[first], metric))
+        .collect::<Vec<_>>();
+    while picked.len() < k.min(sample.len()) {
+        let furthest = (0..sample.len())
+            .filter(|i| !picked.contains(i))
+            .max_by(|&a, &b| nearest[a].total_cmp(&nearest[b]))
+            .unwrap();
+
+        picked.push(furthest);
+        for (i, e) in sample.iter().enumerate() {
+            nearest[i] = nearest[i].min(distance(e, &sample[furthest], metric));
         }
+    }
 
-        // TODO: config param?
-        let mut cache = EmbeddingCache::new(CACHE_SIZE)?;
+    picked.into_iter().map(|i| sample[i].id).collect()
+}
 
-        let mut rng = thread_rng();
-        let mut layers = vec![HashMap::new(); l as usize];
-        // for each embedding e[i]
-        for i in 0..n {
-            if i % (n / 10) == 0 {
-                info!(
-                    "{} connected nodes, {} orphans, {} nodes attempted",
-                    n - orphans.len(),
-                    orphans.len(),
-                    i + 1
-                );
-            }
+// `HNSWParams` resolved against the size of the index
+struct Bounds {
+    m: usize,
+    m_max: usize,
+    m_max_bottom: usize,
+    ef_construction: usize,
+    metric: Metric,
+}
 
-            let prob = rng.gen::<f32>();
-            for j in (0..l).rev() {
-                let j = j as usize;
-                // inserting the node into layer j
-                // on insertion, form connections between the new node
-                //               and the closest m neighbors in the layer
-                //
-                // each layer is a hashmap of ids to (node_id, distance) pairs
-                // there's a gross mixing of using IDs and the actual embedding index here
-                // this whole struct really needs a refactor
-                if prob < thresholds[j] {
-                    orphans.remove(&(i as u32));
-                    let e_i = cache.get(i as u32)?;
-
-                    for k in (j as u32)..l {
-                        let k = k as usize;
-                        let layer: &mut Graph = layers.get_mut(k).unwrap();
-
-                        if layer.len() == 0 {
-                            layer.insert(e_i.id, Vec::new());
-                        } else {
-                            let distances: Vec<(u64, f32)> = layer
-                                .keys()
-                                .take(m as usize)
-                                .map(|&neighbor| {
-                                    let e_neighbor = cache.get(neighbor as u32).unwrap();
-                                    (neighbor, 1.0 - dot(&e_i, &e_neighbor))
-                                })
-                                .collect();
-
-                            let mut updates = Vec::new();
-                            for &(node, d) in distances.iter() {
-                                updates.push((node, e_i.id, d));
-                                updates.push((e_i.id, node, d));
-                            }
-
-                            for (key, value, d) in updates {
-                                let edges: &mut Vec<(u64, f32)> =
-                                    layer.entry(key).or_insert_with(Vec::new).as_mut();
-                                if !edges.contains(&(value, d)) {
-                                    edges.push((value, d));
-                                    edges.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+impl Bounds {
+    fn new(params: &HNSWParams, n: usize, metric: Metric) -> Self {
+        let m = n.max(2).ilog2() as usize;
+        let m_max = params.m_max.unwrap_or(m);
+
+        Self {
+            m,
+            m_max,
+            m_max_bottom: params.m_max_bottom.unwrap_or(2 * m_max),
+            ef_construction: params.ef_construction.unwrap_or(EF_CONSTRUCTION),
+            metric,
         }
+    }
 
-        info!("connecting {} orphans", orphans.len());
-        let bottom_layer: &mut Graph = layers.get_mut(l as usize - 1).unwrap();
+    // greedy descent from the top layer for the node's entry point,
+    // then on each layer from `level` down, form connections between the new node
+    // and the closest m neighbors found in the layer
+    //
+    // each layer is a hashmap of ids to (node_id, distance) pairs
+    // there's a gross mixing of using IDs and the actual embedding index here
+    // this whole struct really needs a refactor
+    fn insert(
+        &self,
+        layers: &mut [Graph],
+        e_i: &Embedding,
+        level: usize,
+        cache: &mut EmbeddingCache,
+    ) -> Result<(), std::io::Error> {
+        let bottom = layers.len() - 1;
+
+        let mut entry = None;
+        for (k, layer) in layers.iter_mut().enumerate() {
+            if layer.is_empty() {
+                if k >= level {
+                    layer.insert(e_i.id, Vec::new());
+                }
 
-        // sorting here makes better use of the cache + how embeddings are loaded
-        let mut orphans = orphans.iter().collect::<Vec<_>>();
-        orphans.sort_by(|a, b| a.cmp(b));
-        for (i, orphan) in orphans.iter().enumerate() {
-            if i % (orphans.len() / 10) == 0 {
-                info!("{} orphans connected", i);
+                continue;
             }
 
-            let orphan = **orphan as usize;
-            let e_orphan = cache.get(orphan as u32)?;
-            let distances: Vec<(u64, f32)> = bottom_layer
-                .keys()
-                .take(m as usize)
-                .map(|&neighbor| {
-                    let e_neighbor = cache.get(neighbor as u32).unwrap();
-                    (neighbor, 1.0 - dot(&e_orphan, &e_neighbor))
-                })
-                .collect();
+            let start = match entry {
+                Some(e) if layer.contains_key(&e) => e,
+                _ => *layer.keys().min().unwrap(),
+            };
 
-            let mut updates = Vec::new();
-            for &(node, d) in distances.iter() {
-                updates.push((node, e_orphan.id, d));
-                updates.push((e_orphan.id, node, d));
+            let ef = if k < level { 1 } else { self.ef_construction };
+            let found = search_layer(
+                layer,
+                e_i,
+                &[start],
+                ef,
+                usize::MAX,
+                self.metric,
+                &|_| true,
+                None,
+                cache,
+                None,
+            );
+            entry = found.first().map(|(e, _)| e.id);
+
+            if k < level {
+                continue;
             }
 
-            for (key, value, d) in updates {
-                let edges: &mut Vec<(u64, f32)> =
-                    bottom_layer.entry(key).or_insert_with(Vec::new).as_mut();
-                if !edges.contains(&(value, d)) {
-                    edges.push((value, d));
-                    edges.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-                }
-            }
+            let distances = found
+                .iter()
+                .take(self.m)
+                .map(|(e, d)| (e.id, *d))
+                .collect::<Vec<_>>();
+
+            let bound = if k == bottom {
+                self.m_max_bottom
+            } else {
+                self.m_max
+            };
+
+            layer.entry(e_i.id).or_default();
+            connect(layer, e_i.id, &distances, bound, self.metric, cache)?;
         }
 
-        info!("finished building index");
+        Ok(())
+    }
+}
 
-        Ok(Self {
-            size: n as u32,
-            layers,
-        })
+fn distance_to(
+    target: &Embedding,
+    node: u64,
+    metric: Metric,
+    cache: &mut dyn EmbeddingLookup,
+) -> Option<(Box<Embedding>, f32)> {
+    match cache.get(node as u32) {
+        Ok(e) => {
+            let distance = distance(target, &e, metric);
+            Some((e, distance))
+        }
+        // a deleted node is expected to be missing until the index is rebuilt
+        Err(_) if cache.is_deleted(node as u32) => None,
+        Err(e) => {
+            error!("warning: failed to load node {}: {}", node, e);
+            None
+        }
     }
+}
 
-    // please god optimize this
-    // is this better than bfs?
+// best-first search through a single layer starting from all of `entries` at once
+// returns up to `ef` of the closest nodes that pass `keep`, sorted closest-first
+//
+// nodes that fail `keep` are still traversed, they just never make it into the results
+//
+// deleted nodes have no embedding left to measure, so they're walked through
+// at the distance of the node that led to them, or as far as can be for an entry
+//
+// at most `budget` nodes have their neighbors looked at,
+// and only the neighbors in `allowed` are, when it's given (sorted)
+//
+// `trace` is filled in with what the search did, if it's given
+#[allow(clippy::too_many_arguments)]
+fn search_layer(
+    layer: &dyn Layer,
+    target: &Embedding,
+    entries: &[u64],
+    ef: usize,
+    budget: usize,
+    metric: Metric,
+    keep: &dyn Fn(&Embedding) -> bool,
+    allowed: Option<&[u64]>,
+    cache: &mut dyn EmbeddingLookup,
+    trace: Option<&mut LayerTrace>,
+) -> Vec<(Box<Embedding>, f32)> {
+    let mut scratch = LayerTrace::default();
+    let trace = trace.unwrap_or(&mut scratch);
+
+    let mut visited = HashSet::new();
+    let mut expanded = 0;
+
+    // frankly just a stupid way of using this instead of a min heap
+    // but rust f32 doesn't have Eq so i don't know how to work with it
     //
-    // dfs search through the hnsw
-    pub fn query(&self, query: &Query, k: usize, ef: usize) -> Vec<(Box<Embedding>, f32)> {
-        if ef < k {
-            panic!("ef must be greater than k");
+    // candidates are kept sorted furthest-first so the closest pops off the end
+    // results are kept sorted closest-first
+    let mut candidates: Vec<(u64, f32)> = Vec::new();
+    let mut results: Vec<(Box<Embedding>, f32)> = Vec::new();
+    for &entry in entries.iter() {
+        if !visited.insert(entry) {
+            continue;
         }
 
-        // there's gotta be a better way to blacklist
-        let mut visited = vec![false; self.size as usize];
-        let mut blacklist = vec![false; self.size as usize];
-
-        // frankly just a stupid way of using this instead of a min heap
-        // but rust f32 doesn't have Eq so i don't know how to work with it
-        let mut top_k: Vec<(u64, f32)> = Vec::new();
-
-        let mut cache = EmbeddingCache::new(CACHE_SIZE).unwrap();
-
-        let mut count = 0;
-        let mut current = *self.layers[0].keys().next().unwrap();
-        for layer in self.layers.iter() {
-            let mut stack = Vec::new();
-            stack.push(current);
-
-            while !stack.is_empty() {
-                current = stack.pop().unwrap();
-                let mut neighbors = layer
-                    .get(&current)
-                    .unwrap()
-                    .clone()
-                    .into_iter()
-                    .filter_map(|(n, _)| {
-                        if blacklist[n as usize] {
-                            return None;
-                        }
+        let (e_entry, entry_distance) = match distance_to(target, entry, metric, cache) {
+            Some((e, distance)) => (Some(e), distance),
+            None if cache.is_deleted(entry as u32) => (None, f32::MAX),
+            None => continue,
+        };
 
-                        let e_n = cache.get(n as u32).unwrap();
-                        let mut filter_pass = true;
-                        for filter in query.filters.iter() {
-                            for meta in e_n.source_file.meta.iter() {
-                                filter_pass &= filter.compare(meta);
-                            }
-                        }
+        trace.visited += 1;
 
-                        if !visited[n as usize] && filter_pass {
-                            Some((n, 1.0 - dot(&query.embedding, &e_n)))
-                        } else {
-                            blacklist[n as usize] = true;
-                            None
-                        }
-                    })
-                    .collect::<Vec<_>>();
+        let position = candidates.partition_point(|c| c.1 > entry_distance);
+        candidates.insert(position, (entry, entry_distance));
+        match e_entry {
+            Some(e_entry) if keep(&e_entry) => {
+                let position = results.partition_point(|r| r.1 < entry_distance);
+                results.insert(position, (e_entry, entry_distance));
+                results.truncate(ef);
+            }
+            _ => trace.filtered += 1,
+        }
+    }
 
-                neighbors.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-                for (neighbor, distance) in neighbors {
-                    let neighbor = neighbor as usize;
-                    if !visited[neighbor] && !blacklist[neighbor] && count < ef {
-                        top_k.push((neighbor as u64, distance));
+    while let Some((node, distance)) = candidates.pop() {
+        if results.len() >= ef && distance > results.last().unwrap().1 {
+            break;
+        }
 
-                        stack.push(neighbor as u64);
-                        visited[neighbor] = true;
-                        count += 1;
-                    }
+        if expanded >= budget {
+            break;
+        }
 
-                    if top_k.len() > k {
-                        top_k.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-                        while top_k.len() > k {
-                            top_k.pop();
-                        }
-                    }
+        expanded += 1;
+        trace.expanded += 1;
 
-                    if count >= ef {
-                        return top_k
-                            .into_iter()
-                            .map(|(node, distance)| (cache.get(node as u32).unwrap(), distance))
-                            .collect::<Vec<_>>();
-                    }
-                }
+        let neighbors = match layer.neighbors(node) {
+            Some(neighbors) => neighbors,
+            None => {
+                error!("warning: node {} missing from layer", node);
+                continue;
             }
+        };
 
-            top_k.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-            current = top_k.first().unwrap().0;
-        }
+        for &(neighbor, _) in neighbors.iter() {
+            if !visited.insert(neighbor) {
+                continue;
+            }
 
-        top_k.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-        top_k
-            .into_iter()
-            .map(|(node, distance)| (cache.get(node as u32).unwrap(), distance))
-            .collect::<Vec<_>>()
-    }
+            if allowed.is_some_and(|allowed| allowed.binary_search(&neighbor).is_err()) {
+                continue;
+            }
 
-    // not the most efficient
-    // need to find a workaround the borrow checker
-    pub fn remove_node(&mut self, target_id: u64) {
-        let layer_targets = self
-            .layers
-            .iter()
-            .enumerate()
-            .filter_map(|(i, l)| {
-                let t = l.get(&target_id);
-                if t.is_some() {
-                    Some((i, t.unwrap().clone()))
-                } else {
-                    None
+            let (e, distance) = match distance_to(target, neighbor, metric, cache) {
+                Some(d) => d,
+                None if cache.is_deleted(neighbor as u32) => {
+                    trace.visited += 1;
+                    trace.filtered += 1;
+
+                    let position = candidates.partition_point(|c| c.1 > distance);
+                    candidates.insert(position, (neighbor, distance));
+                    continue;
                 }
-            })
-            .collect::<Vec<_>>();
+                None => continue,
+            };
 
-        // removing all outgoing edges from neighbors to target_id
-        // i.e., removing all edges (neighbor -> target_id)
-        for lt in layer_targets {
-            for target in lt.1 {
-                let neighbors = self.layers[lt.0].get_mut(&target.0).unwrap();
-                neighbors.retain(|n| n.0 != target_id);
+            trace.visited += 1;
+
+            if results.len() < ef || distance < results.last().unwrap().1 {
+                let position = candidates.partition_point(|c| c.1 > distance);
+                candidates.insert(position, (neighbor, distance));
+
+                if keep(&e) {
+                    let position = results.partition_point(|r| r.1 < distance);
+                    results.insert(position, (e, distance));
+                    results.truncate(ef);
+                } else {
+                    trace.filtered += 1;
+                }
             }
         }
+    }
 
-        // removing all outgoing edges from target_id
-        // i.e., removing all edges (target_id -> neighbor)
-        for layer in self.layers.iter_mut() {
-            layer.retain(|k, _| *k != target_id);
+    trace.kept = results.len();
+    trace.exhausted = results.len() < ef;
+
+    results
+}
+
+// neighbor selection heuristic from the HNSW paper (algorithm 4)
+//
+// `candidates` must be sorted by distance to the node whose edges are being pruned
+// a candidate is only kept if it's closer to that node than to any neighbor already kept,
+// which favors neighbors spread out in different directions over a tight cluster
+// of near-duplicates. discarded candidates are used to backfill any remaining room
+fn select_neighbors(
+    candidates: &[(u64, f32)],
+    m_max: usize,
+    metric: Metric,
+    cache: &mut EmbeddingCache,
+) -> Result<Vec<(u64, f32)>, std::io::Error> {
+    let mut selected: Vec<((u64, f32), Box<Embedding>)> = Vec::new();
+    let mut discarded = Vec::new();
+    for &(candidate, d) in candidates.iter() {
+        if selected.len() >= m_max {
+            break;
+        }
+
+        let e_candidate = cache.get(candidate as u32)?;
+        if selected
+            .iter()
+            .all(|(_, e_selected)| distance(&e_candidate, e_selected, metric) >= d)
+        {
+            selected.push(((candidate, d), e_candidate));
+        } else {
+            discarded.push((candidate, d));
         }
     }
 
-    pub fn serialize(&self, filepath: &String) -> Result<(), std::io::Error> {
-        info!("serializing index to {}", filepath);
-        let mut file = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(filepath)?;
+    let mut selected = selected.into_iter().map(|(s, _)| s).collect::<Vec<_>>();
+    for d in discarded {
+        if selected.len() >= m_max {
+            break;
+        }
 
-        let bytes = self.to_bytes();
-        file.write_all(&bytes)?;
+        selected.push(d);
+    }
 
-        info!("finished serializing index");
+    selected.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
 
-        Ok(())
+    Ok(selected)
+}
+
+// forms edges in both directions between `node` and each of its neighbors in `distances`,
+// pruning any edge list that grows past `m_max`
+fn connect(
+    layer: &mut Graph,
+    node: u64,
+    distances: &[(u64, f32)],
+    m_max: usize,
+    metric: Metric,
+    cache: &mut EmbeddingCache,
+) -> Result<(), std::io::Error> {
+    let mut updates = Vec::new();
+    for &(neighbor, d) in distances.iter() {
+        updates.push((neighbor, node, d));
+        updates.push((node, neighbor, d));
     }
 
-    pub fn deserialize(filepath: String) -> Result<Self, std::io::Error> {
-        info!("deserializing index from {}", filepath);
+    for (key, value, d) in updates {
+        let edges: &mut Vec<(u64, f32)> = layer.entry(key).or_default();
+        if !edges.contains(&(value, d)) {
+            edges.push((value, d));
+            edges.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        }
 
-        let mut file = std::fs::File::open(filepath.clone())?;
-        let mut bytes = Vec::new();
-        file.read_to_end(&mut bytes)?;
+        if edges.len() > m_max {
+            *edges = select_neighbors(edges, m_max, metric, cache)?;
+        }
+    }
+
+    Ok(())
+}
 
-        let (hnsw, count) = Self::from_bytes(&bytes, 0)?;
+// the ids carrying each meta tag, for finding what passes a selective filter without the graph
+pub type MetaIds = HashMap<String, HashSet<u64>>;
 
-        if count <= 4 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "invalid index file",
-            ));
+pub fn add_meta(meta_ids: &mut MetaIds, id: u64, meta: &HashSet<String>) {
+    for tag in meta.iter().filter(|m| *m != PATH_META && *m != TITLE_META) {
+        meta_ids.entry(tag.clone()).or_default().insert(id);
+    }
+}
+
+// a filtered query falls back on something other than the graph
+// once fewer than this many of the nodes it visits pass its filters
+const SELECTIVE_PASS_RATIO: f32 = 0.1;
+
+// where the layers of a serialized index start, in place of the layer count an older index has there,
+// counting down with every change to what follows
+const SEGMENTED: u32 = u32::MAX;
+// the same, with the pinned entry points after the segment table
+const SEGMENTED_ENTRIES: u32 = u32::MAX - 1;
+
+// how many nodes' edges a paged layer keeps in memory
+const PAGED_NODES: usize = 4096;
+
+// the model sits right after the size, followed by the metric and the segment table,
+// and no model name comes anywhere near this
+const HEADER_LIMIT: u64 = 1024;
+
+// the offset and length of each layer's segment in an index file, top layer first
+type Segments = Vec<(u64, u64)>;
+
+fn invalid_index() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid index file")
+}
+
+// a layer's segment of a serialized index:
+//   - the node count, as a u32
+//   - each node's id and edge count, lowest id first, as a u64 and a u32
+//   - each node's edges, in the same order, as a u64 id and an f32 distance
+fn segment_bytes(layer: &dyn Layer) -> Vec<u8> {
+    let nodes = layer.nodes();
+    let edges = nodes
+        .iter()
+        .map(|&node| layer.neighbors(node).unwrap_or_default())
+        .collect::<Vec<_>>();
+
+    let mut bytes = (nodes.len() as u32).to_bytes();
+    for (node, neighbors) in nodes.iter().zip(edges.iter()) {
+        bytes.extend(node.to_bytes());
+        bytes.extend((neighbors.len() as u32).to_bytes());
+    }
+
+    for neighbors in edges.iter() {
+        for (neighbor, distance) in neighbors.iter() {
+            bytes.extend(neighbor.to_bytes());
+            bytes.extend(distance.to_bytes());
         }
+    }
 
-        info!("finished deserializing index");
+    bytes
+}
 
-        Ok(hnsw)
+const NODE_ENTRY_BYTES: u64 = 12;
+const EDGE_BYTES: u64 = 12;
+
+// a segment's node table: each node's id, where its edges start past the table, and how many it has
+fn read_node_table(bytes: &[u8]) -> Result<Vec<(u64, u64, u32)>, std::io::Error> {
+    let (count, mut cursor) = u32::from_bytes(bytes, 0)?;
+
+    let mut table = Vec::with_capacity(count as usize);
+    let mut offset = 0;
+    for _ in 0..count {
+        let (node, size) = u64::from_bytes(bytes, cursor)?;
+        cursor += size;
+        let (edges, size) = u32::from_bytes(bytes, cursor)?;
+        cursor += size;
+
+        table.push((node, offset, edges));
+        offset += edges as u64 * EDGE_BYTES;
     }
 
-    pub fn get_last_layer(&self) -> &Graph {
-        self.layers.last().unwrap()
+    Ok(table)
+}
+
+fn read_edges(bytes: &[u8], count: u32) -> Result<Vec<(u64, f32)>, std::io::Error> {
+    let mut cursor = 0;
+    let mut edges = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let (neighbor, size) = u64::from_bytes(bytes, cursor)?;
+        cursor += size;
+        let (distance, size) = f32::from_bytes(bytes, cursor)?;
+        cursor += size;
+
+        edges.push((neighbor, distance));
     }
 
-    pub fn print_graph(&self) {
-        for (i, layer) in self.layers.iter().enumerate() {
-            println!("Layer {} has {} nodes", i, layer.len());
-            for (node, neighbors) in layer.iter() {
-                println!(
-                    "  Node {}: {:?}",
-                    node,
-                    neighbors.iter().map(|(n, _)| n).collect::<Vec<_>>()
-                );
-            }
+    Ok(edges)
+}
+
+fn read_segment(bytes: &[u8]) -> Result<Graph, std::io::Error> {
+    let table = read_node_table(bytes)?;
+    let start = 4 + table.len() * NODE_ENTRY_BYTES as usize;
+
+    let mut graph = Graph::with_capacity(table.len());
+    for (node, offset, count) in table {
+        let edges = bytes.get(start + offset as usize..).unwrap_or_default();
+        graph.insert(node, read_edges(edges, count)?);
+    }
+
+    Ok(graph)
+}
+
+fn read_range(
+    file: &mut std::fs::File,
+    offset: u64,
+    length: u64,
+) -> Result<Vec<u8>, std::io::Error> {
+    let mut bytes = vec![0; length as usize];
+    file.seek(std::io::SeekFrom::Start(offset))?;
+    file.read_exact(&mut bytes)?;
+
+    Ok(bytes)
+}
+
+// a node's edges, shared between the nodes a paged layer keeps and the searches reading them
+type Edges = Arc<[(u64, f32)]>;
+
+// the edges of the nodes a paged layer read last, dropping the least recently used past `capacity`
+struct RecentNodes {
+    capacity: usize,
+    tick: u64,
+    edges: HashMap<u64, (Edges, u64)>,
+    // the tick each node was last used at, the least recent first
+    order: BTreeMap<u64, u64>,
+}
+
+impl RecentNodes {
+    fn get(&mut self, node: u64) -> Option<Edges> {
+        let (edges, used) = self.edges.get_mut(&node)?;
+        self.order.remove(used);
+        self.tick += 1;
+        *used = self.tick;
+        self.order.insert(self.tick, node);
+
+        Some(edges.clone())
+    }
+
+    fn insert(&mut self, node: u64, edges: Edges) {
+        self.tick += 1;
+        if let Some((_, used)) = self.edges.insert(node, (edges, self.tick)) {
+            self.order.remove(&used);
         }
+        self.order.insert(self.tick, node);
+
+        while self.edges.len() > self.capacity {
+            match self.order.pop_first() {
+                Some((_, oldest)) => self.edges.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+}
+
+// a layer left in its segment of the index file, with only its node table in memory
+//
+// a node's edges are read from the file the first time a search gets to it,
+// and the most recently used are kept around for the searches after it
+//
+// the file handle is kept open, so writing the index out again, which replaces the file,
+// leaves this reading the index it was loaded from
+struct PagedLayer {
+    file: Mutex<std::fs::File>,
+    // where the edges start in the file
+    edges_start: u64,
+    // sorted by id
+    table: Vec<(u64, u64, u32)>,
+    recent: Mutex<RecentNodes>,
+}
+
+impl PagedLayer {
+    fn open(mut file: std::fs::File, offset: u64, capacity: usize) -> Result<Self, std::io::Error> {
+        let count = u32::from_bytes(&read_range(&mut file, offset, 4)?, 0)?.0 as u64;
+        let table_bytes = read_range(&mut file, offset, 4 + count * NODE_ENTRY_BYTES)?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+            edges_start: offset + table_bytes.len() as u64,
+            table: read_node_table(&table_bytes)?,
+            recent: Mutex::new(RecentNodes {
+                capacity: capacity.max(1),
+                tick: 0,
+                edges: HashMap::new(),
+                order: BTreeMap::new(),
+            }),
+        })
+    }
+
+    fn read(&self, node: u64) -> Result<Option<Edges>, std::io::Error> {
+        let (offset, count) = match self.table.binary_search_by_key(&node, |entry| entry.0) {
+            Ok(i) => (self.table[i].1, self.table[i].2),
+            Err(_) => return Ok(None),
+        };
+
+        let mut recent = self.recent.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(edges) = recent.get(node) {
+            return Ok(Some(edges));
+        }
+
+        let bytes = read_range(
+            &mut self.file.lock().unwrap_or_else(PoisonError::into_inner),
+            self.edges_start + offset,
+            count as u64 * EDGE_BYTES,
+        )?;
+        let edges: Edges = read_edges(&bytes, count)?.into();
+        recent.insert(node, edges.clone());
+
+        Ok(Some(edges))
+    }
+
+    // the whole layer, read into memory
+    fn load(&self) -> Result<Graph, std::io::Error> {
+        let mut graph = Graph::with_capacity(self.table.len());
+        for &(node, offset, count) in self.table.iter() {
+            let bytes = read_range(
+                &mut self.file.lock().unwrap_or_else(PoisonError::into_inner),
+                self.edges_start + offset,
+                count as u64 * EDGE_BYTES,
+            )?;
+            graph.insert(node, read_edges(&bytes, count)?);
+        }
+
+        Ok(graph)
+    }
+}
+
+impl Layer for PagedLayer {
+    // a node whose edges can't be read is taken as missing from the layer, which searches go around
+    fn neighbors(&self, node: u64) -> Option<Cow<'_, [(u64, f32)]>> {
+        match self.read(node) {
+            Ok(edges) => edges.map(|edges| Cow::Owned(edges.to_vec())),
+            Err(e) => {
+                error!("failed to read the edges of node {}: {}", node, e);
+                None
+            }
+        }
+    }
+
+    fn contains(&self, node: u64) -> bool {
+        self.table
+            .binary_search_by_key(&node, |entry| entry.0)
+            .is_ok()
+    }
+
+    fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    fn nodes(&self) -> Vec<u64> {
+        self.table.iter().map(|entry| entry.0).collect()
+    }
+
+    fn first(&self) -> Option<u64> {
+        self.table.first().map(|entry| entry.0)
+    }
+}
+
+// nearest neighbor index, held in memory unless `low_memory` is set,
+// in which case the bottom layer, which has every node, is read off disk as it's searched
+//
+// an index written out since then is split into a segment for each layer,
+// with a table of where they are right after the metric
+#[derive(Serialize)]
+#[allow(unused_attributes)]
+pub struct HNSW {
+    pub size: u32,
+    // the model of the embeddings the index was built over
+    pub model: EmbeddingModel,
+    // queries have to be compared with the metric the graph was built with
+    pub metric: Metric,
+    pub layers: Vec<Graph>,
+    // filled in while the index is built,
+    // and read from the block headers the first time a deserialized index needs it
+    #[ignore]
+    meta_ids: std::sync::OnceLock<MetaIds>,
+    // the bottom layer, when it's left on disk, with an empty stand-in for it at the end of `layers`
+    #[ignore]
+    paged: Option<PagedLayer>,
+    // nodes spread out over the embeddings that queries descend from
+    // along with the entry point of the topmost layer, see `farthest_points`
+    #[ignore]
+    pub entry_points: Vec<u64>,
+}
+
+impl HNSW {
+    pub fn new(reindex: bool) -> Result<Self, std::io::Error> {
+        if !reindex {
+            info!("loading index from disk");
+            let data_dir = get_data_dir();
+            let loaded = match crate::config::get_low_memory() {
+                true => Self::deserialize_paged(&data_dir.join("index"), PAGED_NODES),
+                false => Self::deserialize(&data_dir.join("index")),
+            };
+            let hnsw = match loaded {
+                Ok(h) => h,
+                Err(e) => {
+                    error!("Error reading index: {}", e);
+                    return Err(e);
+                }
+            };
+
+            return Ok(hnsw);
+        }
+
+        Self::build(&HNSWParams::default())
+    }
+
+    pub fn build(params: &HNSWParams) -> Result<Self, std::io::Error> {
+        info!("building index from block files");
+
+        // ids aren't contiguous once files have been updated in place,
+        // so nodes go in block by block to keep the cache from reloading blocks it just dropped
+        let mut ids = get_directory()?
+            .id_map
+            .into_iter()
+            .map(|(id, block)| (block, id))
+            .collect::<Vec<_>>();
+        ids.sort();
+        let ids = ids.into_iter().map(|(_, id)| id).collect::<Vec<_>>();
+
+        let n = ids.len();
+        let model = get_blocks_model()?.unwrap_or_else(EmbeddingModel::current);
+        let m = n.ilog2();
+        let l = n.ilog2();
+        let p = 1.0 / m as f32;
+
+        let metric = params.metric.unwrap_or_else(Metric::current);
+        let bounds = Bounds::new(params, n, metric);
+
+        info!(
+            "building HNSW with \n\tn: {}\n\tm: {}\n\tl: {}\n\tp: {}\n\tm_max: {}\n\tm_max_bottom: {}\n\tef_construction: {}\n\tmetric: {}",
+            n, m, l, p, bounds.m_max, bounds.m_max_bottom, bounds.ef_construction, metric
+        );
+
+        let thresholds = (0..l)
+            .map(|j| p * (1.0 - p).powi((j as i32 - l as i32 + 1).abs()))
+            .collect::<Vec<_>>();
+
+        // normalizing the probabilities since they don't usually add to 1
+        let thresh_sum = thresholds.iter().sum::<f32>();
+        let thresholds = thresholds
+            .iter()
+            .map(|&t| t / thresh_sum)
+            .collect::<Vec<_>>();
+
+        // TODO: config param?
+        let mut cache = EmbeddingCache::new(CACHE_SIZE)?;
+
+        let mut rng = match params.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let mut layers: Vec<Graph> = vec![HashMap::new(); l as usize];
+        let bottom = l as usize - 1;
+
+        // nodes that don't land in an upper layer
+        let mut orphans = 0;
+        let mut meta_ids = MetaIds::new();
+
+        // for each embedding e[i]
+        for (i, &id) in ids.iter().enumerate() {
+            if i % std::cmp::max(n / 10, 1) == 0 {
+                info!("{} nodes inserted, {} orphans", i, orphans);
+            }
+
+            // the node goes into its top layer and every layer below it
+            let prob = rng.gen::<f32>();
+            let level = match (0..l as usize).find(|&j| prob < thresholds[j]) {
+                Some(level) => level,
+                None => {
+                    orphans += 1;
+                    bottom
+                }
+            };
+
+            let e_i = cache.get(id)?;
+            add_meta(&mut meta_ids, id as u64, &e_i.source_file.meta);
+            bounds.insert(&mut layers, &e_i, level, &mut cache)?;
+        }
+
+        info!("finished building index with {} orphans", orphans);
+
+        let entry_points = farthest_points(
+            &ids.iter().map(|&id| id as u64).collect::<Vec<_>>(),
+            params.entry_points.unwrap_or(ENTRY_POINTS),
+            &mut rng,
+            metric,
+            &mut cache,
+        );
+        info!("pinned entry points {:?}", entry_points);
+
+        Ok(Self {
+            size: n as u32,
+            model,
+            metric,
+            layers,
+            meta_ids: meta_ids.into(),
+            paged: None,
+            entry_points,
+        })
+    }
+
+    // standard HNSW search:
+    //   - a greedy descent through the upper layers, where the closest node found
+    //     in each layer becomes the entry point for the next
+    //   - a best-first expansion of the bottom layer with `ef` candidates
+    //
+    // a thorough search descends from the few closest nodes of the topmost layer
+    // and merges what each of them finds, while a fast one cuts the bottom layer short
+    pub fn query(&self, query: &Query, k: usize, ef: usize) -> QueryResults {
+        self.query_in(query, k, ef, &BlockStore::new(QUERY_BLOCKS))
+    }
+
+    // queries searching at the same time share the blocks in `store`,
+    // and the index itself is only ever read
+    pub fn query_in(&self, query: &Query, k: usize, ef: usize, store: &BlockStore) -> QueryResults {
+        if ef < k {
+            panic!("ef must be greater than k");
+        }
+
+        let mut trace = QueryTrace {
+            ef,
+            mode: query.mode,
+            ..Default::default()
+        };
+        let mut span = crate::trace::Span::start("dewey.hnsw.search");
+        let mut results = self.search(query, k, ef, store, &mut trace);
+        results.truncate(k);
+
+        span.set("k", k);
+        span.set("ef", ef);
+        span.set("results", results.len());
+        span.set("cache_hits", trace.cache_hits);
+        span.set("block_loads", trace.block_loads);
+        drop(span);
+
+        trace.distances = results.iter().map(|(_, d)| *d).collect();
+        if query.trace {
+            info!("query trace: {:?}", trace);
+        }
+
+        QueryResults {
+            results,
+            trace: query.trace.then_some(trace),
+        }
+    }
+
+    fn search(
+        &self,
+        query: &Query,
+        k: usize,
+        ef: usize,
+        store: &BlockStore,
+        trace: &mut QueryTrace,
+    ) -> Vec<(Box<Embedding>, f32)> {
+        let mut cache = store.view().unwrap();
+        let deleted = cache.deleted();
+
+        let (bottom, upper) = match self.layers.split_last() {
+            Some((_, upper)) => (self.get_last_layer(), upper),
+            None => {
+                error!("warning: querying an empty index");
+                return Vec::new();
+            }
+        };
+
+        let mut current = match self.entry_point() {
+            Some(entry) => vec![entry],
+            None => {
+                error!("warning: querying an empty index");
+                return Vec::new();
+            }
+        };
+
+        let mut sampled = false;
+        for (i, layer) in upper.iter().enumerate() {
+            if layer.is_empty() {
+                continue;
+            }
+
+            let entries = current
+                .iter()
+                .copied()
+                .filter(|node| layer.contains_key(node))
+                .collect::<Vec<_>>();
+            if entries.is_empty() {
+                error!(
+                    "warning: entry points {:?} missing from layer {}, skipping layer",
+                    current, i
+                );
+                continue;
+            }
+
+            // the entry points are sampled from the first layer that's searched
+            let width = match query.mode {
+                SearchMode::Thorough if !sampled => THOROUGH_ENTRY_POINTS,
+                _ => 1,
+            };
+            sampled = true;
+
+            let mut layer_trace = LayerTrace {
+                layer: i,
+                ..Default::default()
+            };
+            let mut next = Vec::new();
+            for &entry in entries.iter() {
+                let closest = search_layer(
+                    layer,
+                    &query.embedding,
+                    &[entry],
+                    width,
+                    usize::MAX,
+                    self.metric,
+                    &|_| true,
+                    None,
+                    &mut cache,
+                    Some(&mut layer_trace),
+                );
+
+                for (e, _) in closest {
+                    if !next.contains(&e.id) {
+                        next.push(e.id);
+                    }
+                }
+            }
+            layer_trace.kept = next.len();
+            layer_trace.exhausted = next.len() < width;
+            trace.layers.push(layer_trace);
+
+            if !next.is_empty() {
+                current = next;
+            }
+        }
+
+        // the pinned entry points each descend on their own, from the highest layer they're in,
+        // and the bottom layer is searched out from wherever they end up as well
+        for &pin in self.entry_points.iter() {
+            let mut node = pin;
+            for layer in upper.iter().skip_while(|layer| !layer.contains_key(&pin)) {
+                if let Some((e, _)) = search_layer(
+                    layer,
+                    &query.embedding,
+                    &[node],
+                    1,
+                    usize::MAX,
+                    self.metric,
+                    &|_| true,
+                    None,
+                    &mut cache,
+                    None,
+                )
+                .into_iter()
+                .next()
+                {
+                    node = e.id;
+                }
+            }
+
+            if !current.contains(&node) {
+                current.push(node);
+            }
+        }
+
+        current.retain(|&node| bottom.contains(node));
+        if current.is_empty() {
+            error!("warning: entry points missing from the bottom layer");
+            current = match bottom.first() {
+                Some(node) => vec![node],
+                None => return Vec::new(),
+            };
+        }
+
+        let passes_filters = |e: &Embedding| {
+            if deleted.contains(&(e.id as u32)) {
+                return false;
+            }
+
+            let is_path = e.source_file.meta.contains(PATH_META);
+            if query.exclude_paths && is_path {
+                return false;
+            }
+
+            // titles are only ever weighed into the scores of their files' chunks
+            if e.source_file.meta.contains(TITLE_META) {
+                return false;
+            }
+
+            if let Some(scope) = &query.scope {
+                if scope.binary_search(&e.id).is_err() {
+                    return false;
+                }
+            }
+
+            query.matches_filters(&e.source_file)
+        };
+
+        let budget = match query.mode {
+            SearchMode::Fast => ef,
+            _ => usize::MAX,
+        };
+
+        // nodes outside of `allowed` are passed over without being measured, when it's given
+        let search_bottom = |ef: usize,
+                             budget: usize,
+                             allowed: Option<&[u64]>,
+                             cache: &mut dyn EmbeddingLookup,
+                             layer_trace: &mut LayerTrace| {
+            search_layer(
+                bottom,
+                &query.embedding,
+                &current,
+                ef,
+                budget,
+                self.metric,
+                &passes_filters,
+                allowed,
+                cache,
+                Some(layer_trace),
+            )
+        };
+
+        // compares every one of `ids` with the query, adding the ones that pass to `results`
+        let scan = |ids: &[u64],
+                    results: &mut Vec<(Box<Embedding>, f32)>,
+                    cache: &mut dyn EmbeddingLookup,
+                    layer_trace: &mut LayerTrace| {
+            for &id in ids {
+                if !bottom.contains(id) || results.iter().any(|r| r.0.id == id) {
+                    continue;
+                }
+
+                if let Some((e, distance)) = distance_to(&query.embedding, id, self.metric, cache) {
+                    layer_trace.visited += 1;
+                    if passes_filters(&e) {
+                        let position = results.partition_point(|r| r.1 < distance);
+                        results.insert(position, (e, distance));
+                    }
+                }
+            }
+        };
+
+        let cap = crate::config::get_filtered_ef_cap();
+        let mut layer_trace = LayerTrace {
+            layer: upper.len(),
+            ..Default::default()
+        };
+        let mut results = Vec::new();
+        match &query.candidates {
+            // few enough nodes can pass that the graph isn't needed to find them
+            Some(candidates) if candidates.len() <= cap => {
+                trace.filter_strategy = FilterStrategy::Scan {
+                    candidates: candidates.len(),
+                };
+                scan(candidates, &mut results, &mut cache, &mut layer_trace);
+            }
+            Some(candidates) => {
+                results = search_bottom(ef, budget, Some(candidates), &mut cache, &mut layer_trace);
+
+                // the candidates aren't always connected well enough among themselves to reach k,
+                // in which case the whole graph is searched after all
+                match results.len() >= k {
+                    true => {
+                        trace.filter_strategy = FilterStrategy::Constrained {
+                            candidates: candidates.len(),
+                        }
+                    }
+                    false => {
+                        layer_trace = LayerTrace {
+                            layer: upper.len(),
+                            ..Default::default()
+                        };
+                        results = search_bottom(ef, budget, None, &mut cache, &mut layer_trace);
+                    }
+                }
+            }
+            None => results = search_bottom(ef, budget, None, &mut cache, &mut layer_trace),
+        }
+
+        // most of what a selective filter's search visits is left behind,
+        // which can run it out of nodes or budget before it has k results
+        let passed = layer_trace.visited.saturating_sub(layer_trace.filtered);
+        let selective = (passed as f32) < SELECTIVE_PASS_RATIO * layer_trace.visited as f32;
+        let short = results.len() < k && trace.filter_strategy == FilterStrategy::Graph;
+        if short && !query.filters.is_empty() && selective {
+            match self.filtered_candidates(query) {
+                Some(candidates) if candidates.len() <= cap => {
+                    trace.filter_strategy = FilterStrategy::Scan {
+                        candidates: candidates.len(),
+                    };
+                    scan(&candidates, &mut results, &mut cache, &mut layer_trace);
+                }
+                // a search that wasn't cut short already saw everything the graph could reach
+                _ if budget < usize::MAX => {
+                    let mut widened = ef;
+                    while results.len() < k && widened < cap {
+                        widened = (widened * 4).min(cap);
+                        layer_trace = LayerTrace {
+                            layer: upper.len(),
+                            ..Default::default()
+                        };
+                        results =
+                            search_bottom(widened, widened, None, &mut cache, &mut layer_trace);
+                    }
+
+                    if widened > ef {
+                        trace.filter_strategy = FilterStrategy::Widened { ef: widened };
+                    }
+                }
+                _ => {}
+            }
+        }
+        results.truncate(ef);
+
+        layer_trace.kept = results.len();
+        layer_trace.exhausted = results.len() < ef;
+        trace.layers.push(layer_trace);
+        trace.cache_hits = cache.hits;
+        trace.block_loads = cache.loads;
+
+        results
+    }
+
+    // the nodes that could pass every `eq` filter of `query`, closest or not,
+    // `None` if it has none or the meta can't be read
+    //
+    // the meta read is what the embeddings were made with, which a query's own `meta` overrides
+    fn filtered_candidates(&self, query: &Query) -> Option<Vec<u64>> {
+        if query.meta.is_some() {
+            return None;
+        }
+
+        let values = query
+            .filters
+            .iter()
+            .filter(|f| f.comparator == FilterComparator::Equal)
+            .map(|f| &f.value)
+            .collect::<Vec<_>>();
+        if values.is_empty() {
+            return None;
+        }
+
+        let meta_ids = match self.meta_ids.get() {
+            Some(meta_ids) => meta_ids,
+            None => match crate::dbio::read_meta_ids() {
+                Ok(meta_ids) => self.meta_ids.get_or_init(|| meta_ids),
+                Err(e) => {
+                    error!(
+                        "warning: failed to read the meta of the index's nodes: {}",
+                        e
+                    );
+                    return None;
+                }
+            },
+        };
+
+        let empty = HashSet::new();
+        let mut sets = values
+            .iter()
+            .map(|value| meta_ids.get(*value).unwrap_or(&empty))
+            .collect::<Vec<_>>();
+        sets.sort_by_key(|ids| ids.len());
+
+        let mut candidates = sets[0]
+            .iter()
+            .copied()
+            .filter(|id| sets[1..].iter().all(|ids| ids.contains(id)))
+            .collect::<Vec<_>>();
+        candidates.sort();
+
+        Some(candidates)
+    }
+
+    // edges aren't guaranteed to be symmetric once neighbor lists are pruned,
+    // so every node's edge list has to be checked for incoming edges to the target
+    //
+    // a paged bottom layer is read into memory first
+    pub fn remove_node(&mut self, target_id: u64) -> Result<(), std::io::Error> {
+        self.unpage()?;
+
+        let mut removed = false;
+        for layer in self.layers.iter_mut() {
+            removed |= layer.remove(&target_id).is_some();
+            for neighbors in layer.values_mut() {
+                neighbors.retain(|n| n.0 != target_id);
+            }
+        }
+
+        if removed {
+            self.size = self.size.saturating_sub(1);
+        }
+
+        if let Some(meta_ids) = self.meta_ids.get_mut() {
+            for ids in meta_ids.values_mut() {
+                ids.remove(&target_id);
+            }
+        }
+
+        self.entry_points.retain(|&pin| pin != target_id);
+
+        Ok(())
+    }
+
+    // picks the pinned entry points again, over the nodes the index has now,
+    // without touching the graph
+    pub fn recompute_entry_points(
+        &mut self,
+        k: usize,
+        seed: Option<u64>,
+    ) -> Result<(), std::io::Error> {
+        let mut rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        let nodes = match self.layers.is_empty() {
+            true => Vec::new(),
+            false => self.get_last_layer().nodes(),
+        };
+        let mut cache = EmbeddingCache::new(CACHE_SIZE)?;
+        self.entry_points = farthest_points(&nodes, k, &mut rng, self.metric, &mut cache);
+        info!("pinned entry points {:?}", self.entry_points);
+
+        Ok(())
+    }
+
+    // connects embeddings that are already in the blocks and directory to the graph
+    //
+    // they only go into the bottom layer, so the upper layers are left as they were
+    // and the new nodes are reached through their bottom layer edges
+    //
+    // a paged bottom layer is read into memory first
+    pub fn insert_nodes(&mut self, ids: &[u64]) -> Result<(), std::io::Error> {
+        if self.layers.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "can't insert into an index with no layers, rebuild it instead",
+            ));
+        }
+
+        self.unpage()?;
+
+        let bounds = Bounds::new(
+            &HNSWParams::default(),
+            self.size as usize + ids.len(),
+            self.metric,
+        );
+        let bottom = self.layers.len() - 1;
+        let mut cache = EmbeddingCache::new(CACHE_SIZE)?;
+        for &id in ids {
+            let e = cache.get(id as u32)?;
+            if let Some(meta_ids) = self.meta_ids.get_mut() {
+                add_meta(meta_ids, id, &e.source_file.meta);
+            }
+
+            bounds.insert(&mut self.layers, &e, bottom, &mut cache)?;
+            self.size += 1;
+        }
+
+        info!("inserted {} nodes into the index", ids.len());
+
+        Ok(())
+    }
+
+    // the size, model, and metric, then the offset and length of each layer's segment
+    // and the pinned entry points, then the segments themselves, top layer first
+    pub fn serialize(&self, filepath: &std::path::Path) -> Result<(), std::io::Error> {
+        info!("serializing index to {}", filepath.display());
+
+        let mut bytes = self.size.to_bytes();
+        bytes.extend(self.model.to_bytes());
+        bytes.extend(self.metric.to_bytes());
+        bytes.extend(SEGMENTED_ENTRIES.to_bytes());
+        bytes.extend((self.layers.len() as u32).to_bytes());
+
+        let segments = (0..self.layers.len())
+            .filter_map(|i| self.layer(i))
+            .map(segment_bytes)
+            .collect::<Vec<_>>();
+        let entry_points = self.entry_points.to_bytes();
+
+        let mut offset = (bytes.len() + segments.len() * 16 + entry_points.len()) as u64;
+        for segment in segments.iter() {
+            bytes.extend(offset.to_bytes());
+            bytes.extend((segment.len() as u64).to_bytes());
+            offset += segment.len() as u64;
+        }
+        bytes.extend(entry_points);
+
+        for segment in segments {
+            bytes.extend(segment);
+        }
+
+        crate::lock::write_atomic(filepath, &bytes)?;
+
+        info!("finished serializing index");
+
+        Ok(())
+    }
+
+    // reads a whole index into memory, segmented or not
+    pub fn deserialize(filepath: &std::path::Path) -> Result<Self, std::io::Error> {
+        info!("deserializing index from {}", filepath.display());
+
+        let mut file = std::fs::File::open(filepath)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let hnsw = match Self::read_segment_table(&bytes)? {
+            Some((mut hnsw, segments)) => {
+                for (offset, length) in segments {
+                    let segment = bytes
+                        .get(offset as usize..(offset + length) as usize)
+                        .ok_or_else(invalid_index)?;
+                    hnsw.layers.push(read_segment(segment)?);
+                }
+
+                hnsw
+            }
+            None => {
+                let (hnsw, count) = Self::from_bytes(&bytes, 0)?;
+                if count <= 4 {
+                    return Err(invalid_index());
+                }
+
+                hnsw
+            }
+        };
+
+        info!("finished deserializing index");
+
+        Ok(hnsw)
+    }
+
+    // reads the upper layers of a segmented index into memory and leaves the bottom layer on disk,
+    // keeping the edges of the last `capacity` nodes searches got to
+    //
+    // an index written before segments has to be read whole
+    pub fn deserialize_paged(
+        filepath: &std::path::Path,
+        capacity: usize,
+    ) -> Result<Self, std::io::Error> {
+        info!(
+            "deserializing index from {}, paging its bottom layer",
+            filepath.display()
+        );
+
+        let mut file = std::fs::File::open(filepath)?;
+        let mut header = Vec::new();
+        (&mut file).take(HEADER_LIMIT).read_to_end(&mut header)?;
+
+        // the table itself can run past the header that was read
+        let segments = match Self::read_segment_table(&header) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                let length = file.metadata()?.len();
+                Self::read_segment_table(&read_range(&mut file, 0, length)?)?
+            }
+            segments => segments?,
+        };
+
+        let (mut hnsw, segments) = match segments {
+            Some(segmented) => segmented,
+            None => {
+                info!("index isn't segmented, reading all of it");
+                return Self::deserialize(filepath);
+            }
+        };
+
+        if let Some((&(bottom, _), upper)) = segments.split_last() {
+            for &(offset, length) in upper {
+                hnsw.layers
+                    .push(read_segment(&read_range(&mut file, offset, length)?)?);
+            }
+
+            hnsw.layers.push(Graph::new());
+            hnsw.paged = Some(PagedLayer::open(file, bottom, capacity)?);
+        }
+
+        info!("finished deserializing index");
+
+        Ok(hnsw)
+    }
+
+    // an index with no layers read in yet, along with the offset and length of each layer's segment,
+    // or `None` if it was written before segments
+    fn read_segment_table(bytes: &[u8]) -> Result<Option<(Self, Segments)>, std::io::Error> {
+        let (size, mut cursor) = u32::from_bytes(bytes, 0)?;
+        let (model, count) = EmbeddingModel::from_bytes(bytes, cursor)?;
+        cursor += count;
+        let (metric, count) = Metric::from_bytes(bytes, cursor)?;
+        cursor += count;
+
+        let (marker, count) = u32::from_bytes(bytes, cursor)?;
+        cursor += count;
+        if marker != SEGMENTED && marker != SEGMENTED_ENTRIES {
+            return Ok(None);
+        }
+
+        let (layers, count) = u32::from_bytes(bytes, cursor)?;
+        cursor += count;
+
+        let mut segments = Vec::with_capacity(layers as usize);
+        for _ in 0..layers {
+            let (offset, count) = u64::from_bytes(bytes, cursor)?;
+            cursor += count;
+            let (length, count) = u64::from_bytes(bytes, cursor)?;
+            cursor += count;
+
+            segments.push((offset, length));
+        }
+
+        let entry_points = match marker == SEGMENTED_ENTRIES {
+            true => Vec::<u64>::from_bytes(bytes, cursor)?.0,
+            false => Vec::new(),
+        };
+
+        let hnsw = Self {
+            size,
+            model,
+            metric,
+            layers: Vec::with_capacity(layers as usize),
+            meta_ids: Default::default(),
+            paged: None,
+            entry_points,
+        };
+
+        Ok(Some((hnsw, segments)))
+    }
+
+    // reads the model and metric from the header of a serialized index without loading its layers
+    pub fn read_header(
+        filepath: &std::path::Path,
+    ) -> Result<(EmbeddingModel, Metric), std::io::Error> {
+        let file = std::fs::File::open(filepath)?;
+        let mut bytes = Vec::new();
+        file.take(HEADER_LIMIT).read_to_end(&mut bytes)?;
+
+        if bytes.len() < 4 {
+            return Err(invalid_index());
+        }
+
+        let (model, size) = EmbeddingModel::from_bytes(&bytes, 4)?;
+        let (metric, _) = Metric::from_bytes(&bytes, 4 + size)?;
+
+        Ok((model, metric))
+    }
+
+    // the `i`th layer from the top, whether it's paged or not
+    fn layer(&self, i: usize) -> Option<&dyn Layer> {
+        match &self.paged {
+            Some(paged) if i + 1 == self.layers.len() => Some(paged),
+            _ => self.layers.get(i).map(|layer| layer as &dyn Layer),
+        }
+    }
+
+    pub fn get_last_layer(&self) -> &dyn Layer {
+        self.layer(self.layers.len() - 1).unwrap()
+    }
+
+    pub fn is_paged(&self) -> bool {
+        self.paged.is_some()
+    }
+
+    // reads a paged bottom layer into memory, for changing the graph
+    fn unpage(&mut self) -> Result<(), std::io::Error> {
+        if let Some(paged) = &self.paged {
+            let bottom = paged.load()?;
+            if let Some(last) = self.layers.last_mut() {
+                *last = bottom;
+            }
+
+            self.paged = None;
+            info!("read the paged bottom layer into memory");
+        }
+
+        Ok(())
+    }
+
+    // the node searches start from, in the topmost layer with any nodes
+    // the lowest id is picked so that it doesn't change with the map's ordering
+    fn entry_point(&self) -> Option<u64> {
+        (0..self.layers.len()).find_map(|i| self.layer(i)?.first())
+    }
+
+    // a layer's nodes and edges, labeled with the files the nodes were embedded from
+    //
+    // layers with more than `limit` nodes are cut down to the `limit` nodes
+    // found first in a breadth-first walk out from the entry point
+    fn export_layer(&self, layer: usize, limit: usize) -> Result<GraphExport, std::io::Error> {
+        let graph = self.layer(layer).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "layer {} doesn't exist, the index has {} layers",
+                    layer,
+                    self.layers.len()
+                ),
+            )
+        })?;
+
+        let sampled = graph.len() > limit;
+        let nodes = if sampled {
+            // the entry point is only in this layer if nothing above it is empty
+            let start = self
+                .entry_point()
+                .filter(|&entry| graph.contains(entry))
+                .or_else(|| graph.first());
+
+            let mut seen = HashSet::new();
+            let mut queue = std::collections::VecDeque::from_iter(start);
+            let mut nodes = Vec::new();
+            while let Some(node) = queue.pop_front() {
+                if nodes.len() >= limit {
+                    break;
+                }
+
+                let neighbors = match graph.neighbors(node) {
+                    Some(neighbors) if seen.insert(node) => neighbors,
+                    _ => continue,
+                };
+
+                nodes.push(node);
+                queue.extend(neighbors.iter().map(|(n, _)| *n));
+            }
+
+            nodes.sort();
+            nodes
+        } else {
+            graph.nodes()
+        };
+
+        let node_set = nodes.iter().copied().collect::<HashSet<_>>();
+        let filepaths = read_directory_entries()?
+            .into_iter()
+            .map(|(id, filepath, _)| (id as u64, filepath))
+            .collect::<HashMap<_, _>>();
+
+        Ok(GraphExport {
+            layer,
+            sampled,
+            nodes: nodes
+                .iter()
+                .map(|&id| ExportNode {
+                    id,
+                    label: match filepaths.get(&id) {
+                        Some(filepath) => format!("{} {}", id, truncate_path(filepath)),
+                        None => id.to_string(),
+                    },
+                })
+                .collect(),
+            edges: nodes
+                .iter()
+                .flat_map(|&from| {
+                    graph
+                        .neighbors(from)
+                        .unwrap_or_default()
+                        .iter()
+                        .filter(|(to, _)| node_set.contains(to))
+                        .map(|&(to, distance)| ExportEdge { from, to, distance })
+                        .collect::<Vec<_>>()
+                })
+                .collect(),
+        })
+    }
+
+    // writes a layer out as a Graphviz digraph, since edges don't have to go both ways
+    pub fn export_dot(&self, layer: usize, path: &std::path::Path) -> Result<(), std::io::Error> {
+        let export = self.export_layer(layer, EXPORT_NODE_LIMIT)?;
+
+        let mut dot = format!("digraph layer_{} {{\n", layer);
+        for node in export.nodes.iter() {
+            dot.push_str(&format!("    {} [label={:?}];\n", node.id, node.label));
+        }
+
+        for edge in export.edges.iter() {
+            dot.push_str(&format!(
+                "    {} -> {} [label=\"{:.3}\"];\n",
+                edge.from, edge.to, edge.distance
+            ));
+        }
+
+        dot.push_str("}\n");
+
+        std::fs::write(path, dot)?;
+        info!(
+            "exported {} nodes and {} edges of layer {} to {}",
+            export.nodes.len(),
+            export.edges.len(),
+            layer,
+            path.display()
+        );
+
+        Ok(())
+    }
+
+    pub fn export_json(&self, layer: usize, path: &std::path::Path) -> Result<(), std::io::Error> {
+        let export = self.export_layer(layer, EXPORT_NODE_LIMIT)?;
+        std::fs::write(path, serde_json::to_string(&export)?)?;
+        info!(
+            "exported {} nodes and {} edges of layer {} to {}",
+            export.nodes.len(),
+            export.edges.len(),
+            layer,
+            path.display()
+        );
+
+        Ok(())
+    }
+
+    pub fn print_graph(&self) {
+        for (i, layer) in (0..self.layers.len())
+            .filter_map(|i| self.layer(i))
+            .enumerate()
+        {
+            println!("Layer {} has {} nodes", i, layer.len());
+            for node in layer.nodes() {
+                println!(
+                    "  Node {}: {:?}",
+                    node,
+                    layer
+                        .neighbors(node)
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|(n, _)| n)
+                        .collect::<Vec<_>>()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "full"))]
+mod tests {
+    use super::*;
+    use crate::test_common::*;
+
+    #[test]
+    fn build_max_degree_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let embeddings = setup_embeddings(300).unwrap();
+
+        let params = HNSWParams {
+            m_max: Some(4),
+            m_max_bottom: Some(8),
+            seed: Some(1),
+            ..Default::default()
+        };
+
+        let index = HNSW::build(&params);
+        assert!(index.is_ok());
+
+        let index = index.unwrap();
+        let bottom = index.layers.len() - 1;
+        for (i, layer) in index.layers.iter().enumerate() {
+            let bound = if i == bottom { 8 } else { 4 };
+            for (node, edges) in layer.iter() {
+                assert!(
+                    edges.len() <= bound,
+                    "node {} in layer {} has {} edges",
+                    node,
+                    i,
+                    edges.len()
+                );
+            }
+        }
+
+        // every node should still make it into the bottom layer
+        assert_eq!(index.layers[bottom].len(), 300);
+
+        // recall@k against comparing every embedding, which pruning shouldn't cost much of
+        // next to an index whose neighbor lists are never cut
+        let k = 10;
+        let recall = |index: &HNSW| {
+            let mut matched = 0;
+            let mut total = 0;
+            for q in embeddings.iter().step_by(10) {
+                let mut expected = embeddings
+                    .iter()
+                    .map(|e| (e.id, distance(q, e, index.metric)))
+                    .collect::<Vec<_>>();
+                expected.sort_by(|a, b| a.1.total_cmp(&b.1));
+                let expected = expected.iter().take(k).map(|e| e.0).collect::<Vec<_>>();
+
+                matched += index
+                    .query(&query_for(q), k, 100)
+                    .results
+                    .iter()
+                    .filter(|r| expected.contains(&r.0.id))
+                    .count();
+                total += k;
+            }
+
+            matched as f32 / total as f32
+        };
+
+        let unpruned = HNSW::build(&HNSWParams {
+            m_max: Some(embeddings.len()),
+            m_max_bottom: Some(embeddings.len()),
+            seed: Some(1),
+            ..Default::default()
+        })
+        .unwrap();
+        let (pruned, unpruned) = (recall(&index), recall(&unpruned));
+        assert!(
+            pruned >= unpruned - 0.05,
+            "recall@{} fell from {:.3} to {:.3} with pruning",
+            k,
+            unpruned,
+            pruned
+        );
+    }
+
+    #[test]
+    fn remove_node_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(setup_embeddings(100).is_ok());
+
+        let mut index = HNSW::build(&HNSWParams::default()).unwrap();
+        index.remove_node(7).unwrap();
+
+        for layer in index.layers.iter() {
+            assert!(!layer.contains_key(&7));
+            assert!(layer.values().all(|edges| edges.iter().all(|e| e.0 != 7)));
+        }
+    }
+
+    // ids aren't bounded by the size of the index once nodes have been removed and inserted,
+    // and nothing in a query is indexed by them
+    #[test]
+    fn query_large_ids_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let embeddings = setup_embeddings(20).unwrap();
+
+        let mut index = HNSW::build(&HNSWParams::default()).unwrap();
+        for id in 0..5 {
+            index.remove_node(id).unwrap();
+        }
+        index.remove_node(19).unwrap();
+        index.insert_nodes(&[19]).unwrap();
+        assert_eq!(index.size, 15);
+
+        let path = get_data_dir().join("index");
+        index.serialize(&path).unwrap();
+        for index in [index, HNSW::deserialize(&path).unwrap()] {
+            let results = index.query(&query_for(&embeddings[19]), 5, 50).results;
+            assert_eq!(results[0].0.id, 19);
+            assert!(results.iter().all(|(e, _)| e.id >= 5));
+        }
+    }
+
+    fn query_for(embedding: &Embedding) -> Query {
+        Query {
+            embedding: embedding.clone(),
+            filters: Vec::new(),
+            exclude_paths: false,
+            trace: false,
+            mode: SearchMode::Balanced,
+            candidates: None,
+            scope: None,
+            meta: None,
+        }
+    }
+
+    // upper layers with a single node, a node missing from the layer below,
+    // and an edge to a node that isn't in the bottom layer
+    #[test]
+    fn query_sparse_layers_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let embeddings = setup_embeddings(50).unwrap();
+
+        let mut top = HashMap::new();
+        top.insert(3, vec![]);
+
+        let mut middle = HashMap::new();
+        middle.insert(3, vec![(60, 0.5)]);
+        middle.insert(60, vec![(3, 0.5)]);
+
+        // a ring through every node
+        let mut bottom = HashMap::new();
+        for i in 0..50u64 {
+            bottom.insert(i, vec![((i + 1) % 50, 0.5), ((i + 49) % 50, 0.5)]);
+        }
+
+        let index = HNSW {
+            size: 50,
+            model: EmbeddingModel::current(),
+            metric: Metric::Cosine,
+            layers: vec![top, middle, bottom],
+            meta_ids: Default::default(),
+            paged: None,
+            entry_points: Vec::new(),
+        };
+
+        let results = index.query(&query_for(&embeddings[27]), 5, 50).results;
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[0].0.id, 27);
+        assert!(results.windows(2).all(|w| w[0].1 <= w[1].1));
+    }
+
+    #[test]
+    fn query_recall_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let embeddings = setup_embeddings(300).unwrap();
+
+        let index = HNSW::build(&HNSWParams::default()).unwrap();
+
+        let queries = 30;
+        let mut found = 0;
+        for e in embeddings.iter().step_by(embeddings.len() / queries) {
+            let results = index.query(&query_for(e), 10, 50).results;
+            if results.first().is_some_and(|r| r.0.id == e.id) {
+                found += 1;
+            }
+        }
+
+        assert!(
+            found as f32 / queries as f32 >= 0.9,
+            "only {} of {} queries found themselves",
+            found,
+            queries
+        );
+    }
+
+    // each metric finds about the same neighbors as comparing the query against everything
+    #[test]
+    fn metric_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        // the magnitudes vary, so the metrics disagree on what's closest
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut scaled = setup_embeddings(200).unwrap();
+        for e in scaled.iter_mut() {
+            let scale = rng.gen_range(0.1..4.0);
+            e.data.iter_mut().for_each(|x| *x *= scale);
+        }
+
+        let k = 5;
+        for metric in [Metric::Cosine, Metric::Dot, Metric::L2] {
+            let mut embeddings = scaled.clone();
+            embeddings.iter_mut().for_each(|e| metric.prepare(e));
+            assert!(crate::dbio::write_blocks(&embeddings).is_ok());
+
+            let params = HNSWParams {
+                seed: Some(1),
+                metric: Some(metric),
+                ..Default::default()
+            };
+            let index = HNSW::build(&params).unwrap();
+            assert_eq!(index.metric, metric);
+
+            let mut matched = 0;
+            let mut total = 0;
+            for q in embeddings.iter().step_by(10) {
+                let mut expected = embeddings
+                    .iter()
+                    .map(|e| (e.id, distance(q, e, metric)))
+                    .collect::<Vec<_>>();
+                expected.sort_by(|a, b| a.1.total_cmp(&b.1));
+                let expected = expected.iter().take(k).map(|e| e.0).collect::<Vec<_>>();
+
+                let results = index.query(&query_for(q), k, 100).results;
+                for (e, d) in results.iter() {
+                    assert!((d - distance(q, e, metric)).abs() < 1e-4);
+                }
+                assert!(results.windows(2).all(|w| w[0].1 <= w[1].1));
+
+                matched += results
+                    .iter()
+                    .filter(|r| expected.contains(&r.0.id))
+                    .count();
+                total += k;
+            }
+
+            assert!(
+                matched as f32 / total as f32 >= 0.9,
+                "{} found {} of {} neighbors",
+                metric,
+                matched,
+                total
+            );
+        }
+
+        // the metric is kept with the index, and queries under another are refused
+        let index = HNSW::build(&HNSWParams {
+            metric: Some(Metric::Dot),
+            ..Default::default()
+        })
+        .unwrap();
+        let path = get_data_dir().join("index");
+        assert!(index.serialize(&path).is_ok());
+        let index = HNSW::deserialize(&path).unwrap();
+        assert_eq!(index.metric, Metric::Dot);
+
+        let state = crate::ServerState::with_index(index);
+        let error = state
+            .search("anything", &crate::SearchOptions::new(3))
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("dot metric"));
+    }
+
+    // the modes trade nodes visited for recall, and a thorough search
+    // never does worse than a fast one on a query's closest match
+    #[test]
+    fn search_mode_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let embeddings = setup_embeddings(300).unwrap();
+
+        let params = HNSWParams {
+            seed: Some(42),
+            ..Default::default()
+        };
+        let index = HNSW::build(&params).unwrap();
+        assert!(index.layers == HNSW::build(&params).unwrap().layers);
+
+        let queries = embeddings
+            .iter()
+            .step_by(embeddings.len() / 30)
+            .collect::<Vec<_>>();
+        let run = |mode: SearchMode| {
+            let start = std::time::Instant::now();
+            let results = queries
+                .iter()
+                .map(|e| {
+                    let query = Query {
+                        mode,
+                        trace: true,
+                        ..query_for(e)
+                    };
+
+                    index.query(&query, 10, mode.ef())
+                })
+                .collect::<Vec<_>>();
+
+            let found = queries
+                .iter()
+                .zip(results.iter())
+                .filter(|(e, r)| r.results.first().is_some_and(|r| r.0.id == e.id))
+                .count();
+            let visited = results
+                .iter()
+                .flat_map(|r| r.trace.as_ref().unwrap().layers.iter())
+                .map(|l| l.visited)
+                .sum::<usize>();
+            info!(
+                "{} mode: {} of {} found themselves, {} nodes visited in {:?}",
+                mode,
+                found,
+                queries.len(),
+                visited,
+                start.elapsed()
+            );
+
+            assert!(results
+                .iter()
+                .all(|r| r.trace.as_ref().unwrap().mode == mode));
+
+            (results, found, visited)
+        };
+
+        let (fast, fast_found, fast_visited) = run(SearchMode::Fast);
+        let (_, balanced_found, balanced_visited) = run(SearchMode::Balanced);
+        let (thorough, thorough_found, thorough_visited) = run(SearchMode::Thorough);
+
+        assert!(fast_visited < balanced_visited);
+        assert!(balanced_visited <= thorough_visited);
+        assert!(thorough_found >= balanced_found.max(fast_found));
+
+        for (fast, thorough) in fast.iter().zip(thorough.iter()) {
+            let closest = |r: &QueryResults| r.results.first().map(|r| r.1).unwrap();
+            assert!(closest(thorough) <= closest(fast) + 1e-6);
+        }
+    }
+
+    #[test]
+    fn query_trace_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let embeddings = setup_embeddings(300).unwrap();
+
+        let index = HNSW::build(&HNSWParams::default()).unwrap();
+        assert!(index
+            .query(&query_for(&embeddings[0]), 10, 50)
+            .trace
+            .is_none());
+
+        let traced = |filters: Vec<Filter>| {
+            let query = Query {
+                filters,
+                trace: true,
+                ..query_for(&embeddings[0])
+            };
+
+            index.query(&query, 10, 50)
+        };
+
+        let QueryResults { results, trace } = traced(Vec::new());
+        let trace = trace.unwrap();
+        assert_eq!(trace.ef, 50);
+        assert_eq!(
+            trace.distances,
+            results.iter().map(|r| r.1).collect::<Vec<_>>()
+        );
+
+        let bottom = trace.layers.last().unwrap();
+        assert_eq!(bottom.layer, index.layers.len() - 1);
+        assert_eq!(bottom.kept, 50);
+        assert!(!bottom.exhausted);
+        for layer in trace.layers.iter() {
+            assert!(layer.kept <= trace.ef);
+            assert!(layer.expanded <= layer.visited);
+            assert!(layer.visited <= index.layers[layer.layer].len());
+        }
+
+        // every node has the rust tag, so nothing passes
+        let QueryResults { results, trace } = traced(vec![Filter::from_string("ne rust").unwrap()]);
+        let bottom = trace.unwrap().layers.pop().unwrap();
+        assert!(results.is_empty());
+        assert_eq!(bottom.kept, 0);
+        assert!(bottom.filtered > 0);
+        assert!(bottom.exhausted);
+    }
+
+    #[test]
+    fn export_graph_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(setup_embeddings(300).is_ok());
+
+        let index = HNSW::build(&HNSWParams::default()).unwrap();
+        let top = index.layers.iter().position(|l| !l.is_empty()).unwrap();
+        let path = get_data_dir().join("top.dot");
+        assert!(index.export_dot(top, &path).is_ok());
+
+        let dot = std::fs::read_to_string(&path).unwrap();
+        assert!(dot.starts_with(&format!("digraph layer_{} {{", top)));
+
+        let nodes = dot
+            .lines()
+            .filter(|l| !l.contains("->") && l.contains("[label="))
+            .count();
+        let edges = dot.lines().filter(|l| l.contains("->")).count();
+        assert_eq!(nodes, index.layers[top].len());
+        assert_eq!(
+            edges,
+            index.layers[top]
+                .values()
+                .map(|edges| edges.len())
+                .sum::<usize>()
+        );
+        assert!(dot.contains(&get_tracked_files()[0]));
+
+        let path = get_data_dir().join("top.json");
+        assert!(index.export_json(top, &path).is_ok());
+        let export: GraphExport =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(!export.sampled);
+        assert_eq!((export.nodes.len(), export.edges.len()), (nodes, edges));
+
+        // the bottom layer cut down to a connected sample
+        let bottom = index.layers.len() - 1;
+        let export = index.export_layer(bottom, 20).unwrap();
+        let ids = export.nodes.iter().map(|n| n.id).collect::<HashSet<_>>();
+        assert!(export.sampled);
+        assert_eq!(ids.len(), 20);
+        assert!(export
+            .edges
+            .iter()
+            .all(|e| ids.contains(&e.from) && ids.contains(&e.to)));
+
+        assert!(index.export_dot(index.layers.len(), &path).is_err());
+    }
+
+    #[test]
+    fn filter_parse_test() {
+        let filter = |comparator, value: &str| Filter {
+            comparator,
+            value: value.to_string(),
+        };
+
+        assert_eq!(
+            Filter::from_string("eq lang:rust").unwrap(),
+            filter(FilterComparator::Equal, "lang:rust")
+        );
+        assert_eq!(
+            Filter::from_string("  ne   tests ").unwrap(),
+            filter(FilterComparator::NotEqual, "tests")
+        );
+        assert_eq!(
+            Filter::from_string("research").unwrap(),
+            filter(FilterComparator::Equal, "research")
+        );
+
+        for malformed in ["", "gt research", "eq two values", "eq"] {
+            let error = Filter::from_string(malformed);
+            assert!(error.is_err(), "{:?} parsed", malformed);
+            assert_eq!(error.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn filter_matches_test() {
+        let meta = ["md", "lang:rust", PATH_META]
+            .iter()
+            .map(|m| m.to_string())
+            .collect::<HashSet<String>>();
+
+        let filter = |input: &str| Filter::from_string(input).unwrap();
+
+        assert!(filter("eq lang:rust").matches(&meta));
+        assert!(filter("eq md").matches(&meta));
+        assert!(!filter("eq lang:python").matches(&meta));
+        assert!(!filter(&format!("eq {}", PATH_META)).matches(&meta));
+
+        assert!(filter("ne lang:python").matches(&meta));
+        assert!(!filter("ne lang:rust").matches(&meta));
+    }
+
+    // queries sharing a block store find what each would have found on its own,
+    // and the store starts over once the blocks change under it
+    #[test]
+    fn shared_store_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let embeddings = setup_embeddings(BLOCK_SIZE + 100).unwrap();
+        let index = HNSW::build(&HNSWParams::default()).unwrap();
+
+        let query = |e: &Embedding| Query {
+            embedding: e.clone(),
+            filters: Vec::new(),
+            exclude_paths: false,
+            trace: false,
+            mode: SearchMode::Balanced,
+            candidates: None,
+            scope: None,
+            meta: None,
+        };
+        let found = |results: QueryResults| {
+            results
+                .results
+                .iter()
+                .map(|(e, d)| (e.id, *d))
+                .collect::<Vec<_>>()
+        };
+
+        let targets = embeddings.iter().step_by(100).collect::<Vec<_>>();
+        let serial = targets
+            .iter()
+            .map(|e| found(index.query(&query(e), 10, 50)))
+            .collect::<Vec<_>>();
+
+        let store = BlockStore::new(QUERY_BLOCKS);
+        let paths = crate::config::get_paths();
+        let concurrent = std::thread::scope(|scope| {
+            let handles = targets
+                .iter()
+                .map(|e| {
+                    let (paths, index, store) = (paths.clone(), &index, &store);
+                    scope.spawn(move || {
+                        paths.scope(|| found(index.query_in(&query(e), 10, 50, store)))
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(serial, concurrent);
+
+        // the store already has the deleted file's blocks, but not its tombstones
+        let deleted = targets[0].source_file.filepath.clone();
+        assert!(crate::dbio::delete_file(&deleted, None).unwrap() > 0);
+        let results = index.query_in(&query(targets[0]), 10, 50, &store).results;
+        assert!(!results.is_empty());
+        assert!(results
+            .iter()
+            .all(|(e, _)| e.source_file.filepath != deleted));
+    }
+
+    // a filter only a handful of nodes pass still finds every one of them
+    #[test]
+    fn selective_filter_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let mut embeddings = setup_embeddings(300).unwrap();
+        let tagged = vec![3, 71, 150, 222, 298];
+        for e in embeddings.iter_mut() {
+            match tagged.contains(&e.id) {
+                true => e.source_file.meta.insert("experimental".to_string()),
+                false => e.source_file.meta.insert("stable".to_string()),
+            };
+        }
+        crate::dbio::write_blocks(&embeddings).unwrap();
+
+        let found = |index: &HNSW, filter: &str, mode: SearchMode| {
+            let query = Query {
+                filters: vec![Filter::from_string(filter).unwrap()],
+                trace: true,
+                mode,
+                ..query_for(&embeddings[0])
+            };
+
+            let QueryResults { results, trace } = index.query(&query, 10, mode.ef());
+            let mut ids = results.iter().map(|r| r.0.id).collect::<Vec<_>>();
+            ids.sort();
+
+            (ids, trace.unwrap().filter_strategy)
+        };
+
+        let index = HNSW::build(&HNSWParams::default()).unwrap();
+        for mode in [SearchMode::Fast, SearchMode::Balanced, SearchMode::Thorough] {
+            assert_eq!(
+                found(&index, "eq experimental", mode),
+                (tagged.clone(), FilterStrategy::Scan { candidates: 5 })
+            );
+        }
+
+        // without an eq filter there's nothing to scan, so a fast search is widened instead
+        let cap = crate::config::get_filtered_ef_cap();
+        assert_eq!(
+            found(&index, "ne stable", SearchMode::Fast),
+            (tagged.clone(), FilterStrategy::Widened { ef: cap })
+        );
+        assert_eq!(
+            found(&index, "ne stable", SearchMode::Balanced),
+            (tagged.clone(), FilterStrategy::Graph)
+        );
+
+        // filters that plenty of nodes pass never leave the graph
+        assert_eq!(
+            found(&index, "eq stable", SearchMode::Fast).1,
+            FilterStrategy::Graph
+        );
+
+        // a deserialized index reads the meta from the blocks instead
+        let path = get_data_dir().join("index");
+        index.serialize(&path).unwrap();
+        let mut index = HNSW::deserialize(&path).unwrap();
+        assert!(index.meta_ids.get().is_none());
+        assert_eq!(
+            found(&index, "eq experimental", SearchMode::Fast),
+            (tagged.clone(), FilterStrategy::Scan { candidates: 5 })
+        );
+
+        index.remove_node(71).unwrap();
+        assert_eq!(
+            found(&index, "eq experimental", SearchMode::Fast),
+            (
+                vec![3, 150, 222, 298],
+                FilterStrategy::Scan { candidates: 4 }
+            )
+        );
+    }
+
+    // candidates from the meta index find the same results as the search without them
+    #[test]
+    fn meta_index_query_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let mut embeddings = setup_embeddings(300).unwrap();
+        for e in embeddings.iter_mut() {
+            let tag = match e.id % 10 {
+                0 => "rare",
+                1..=5 => "half",
+                _ => "other",
+            };
+            e.source_file.meta.insert(tag.to_string());
+        }
+        crate::dbio::write_blocks(&embeddings).unwrap();
+        crate::dbio::write_meta_index().unwrap();
+
+        let index = HNSW::build(&HNSWParams::default()).unwrap();
+        let meta_index = crate::dbio::read_meta_index().unwrap().unwrap();
+        let search = |filters: &[&str], preselect: bool| {
+            let filters = filters
+                .iter()
+                .map(|f| Filter::from_string(f).unwrap())
+                .collect::<Vec<_>>();
+            let query = Query {
+                candidates: preselect.then(|| meta_index.select(&filters).unwrap()),
+                filters,
+                trace: true,
+                ..query_for(&embeddings[1])
+            };
+
+            let QueryResults { results, trace } = index.query(&query, 10, 200);
+            let results = results.iter().map(|r| (r.0.id, r.1)).collect::<Vec<_>>();
+
+            (results, trace.unwrap().filter_strategy)
+        };
+
+        let check = |filters: &[&str], strategy: FilterStrategy| {
+            let (unoptimized, _) = search(filters, false);
+            let (preselected, used) = search(filters, true);
+            assert_eq!(preselected, unoptimized, "{:?}", filters);
+            assert_eq!(used, strategy, "{:?}", filters);
+        };
+
+        check(&["eq rare"], FilterStrategy::Scan { candidates: 30 });
+        check(
+            &["eq half", "ne rare"],
+            FilterStrategy::Scan { candidates: 150 },
+        );
+        check(
+            &["ne half", "ne other"],
+            FilterStrategy::Scan { candidates: 30 },
+        );
+
+        // past the cap, the graph is searched through the candidates alone
+        crate::write_file!(
+            crate::config::get_config_dir().join("config"),
+            "filtered_ef_cap 20\n"
+        );
+        check(
+            &["eq half"],
+            FilterStrategy::Constrained { candidates: 150 },
+        );
+        check(
+            &["eq rust", "ne half"],
+            FilterStrategy::Constrained { candidates: 150 },
+        );
+    }
+
+    // an index paging its bottom layer answers every query exactly as one held in memory,
+    // even with too few nodes kept to hold a search's worth of edges
+    #[test]
+    fn paged_layer_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let embeddings = setup_embeddings(300).unwrap();
+
+        let index = HNSW::build(&HNSWParams {
+            seed: Some(7),
+            ..Default::default()
+        })
+        .unwrap();
+        let path = get_data_dir().join("index");
+        index.serialize(&path).unwrap();
+
+        let full = HNSW::deserialize(&path).unwrap();
+        assert!(!full.is_paged());
+        assert!(full.layers == index.layers);
+
+        let paged = HNSW::deserialize_paged(&path, 8).unwrap();
+        assert!(paged.is_paged());
+        assert_eq!(paged.layers.len(), full.layers.len());
+        let bottom = full.layers.len() - 1;
+        assert!(paged.layers[..bottom] == full.layers[..bottom]);
+        assert_eq!(paged.get_last_layer().nodes(), full.layers[bottom].nodes());
+
+        for mode in [SearchMode::Fast, SearchMode::Balanced, SearchMode::Thorough] {
+            for e in embeddings.iter().step_by(10) {
+                let query = Query {
+                    mode,
+                    ..query_for(e)
+                };
+
+                let expected = full.query(&query, 10, mode.ef()).results;
+                let results = paged.query(&query, 10, mode.ef()).results;
+                assert_eq!(
+                    results.iter().map(|r| (r.0.id, r.1)).collect::<Vec<_>>(),
+                    expected.iter().map(|r| (r.0.id, r.1)).collect::<Vec<_>>(),
+                    "{} query for {}",
+                    mode,
+                    e.id
+                );
+            }
+        }
+
+        // written back out, a paged index is the same as the one it was read from
+        let copy = get_data_dir().join("index.copy");
+        paged.serialize(&copy).unwrap();
+        assert!(HNSW::deserialize(&copy).unwrap().layers == full.layers);
+
+        // an index written before segments is read whole either way
+        let legacy = get_data_dir().join("index.legacy");
+        std::fs::write(&legacy, index.to_bytes()).unwrap();
+        assert!(HNSW::deserialize(&legacy).unwrap().layers == full.layers);
+        let unpaged = HNSW::deserialize_paged(&legacy, 8).unwrap();
+        assert!(!unpaged.is_paged());
+        assert!(unpaged.layers == full.layers);
+        assert_eq!(
+            HNSW::read_header(&path).unwrap(),
+            HNSW::read_header(&legacy).unwrap()
+        );
+
+        // the bottom layer is only paged once it's configured,
+        // and it's read into memory to be changed
+        assert!(!HNSW::new(false).unwrap().is_paged());
+        crate::write_file!(
+            crate::config::get_config_dir().join("config"),
+            "low_memory true\n"
+        );
+        let mut paged = HNSW::new(false).unwrap();
+        assert!(paged.is_paged());
+
+        let mut full = full;
+        let removed = embeddings[150].id;
+        full.remove_node(removed).unwrap();
+        paged.remove_node(removed).unwrap();
+        assert!(!paged.is_paged());
+        assert!(paged.layers == full.layers);
+    }
+
+    // embeddings in tight clusters far apart from each other, which a single greedy descent
+    // can easily miss, replacing whatever blocks are there
+    fn setup_clusters(clusters: usize, per_cluster: usize) -> Vec<Embedding> {
+        use rand::Rng;
+
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut embeddings = setup_embeddings(clusters * per_cluster).unwrap();
+        let centers = (0..clusters)
+            .map(|_| [0.0; crate::embedding::EMBED_DIM].map(|_| rng.gen_range(-1.0..1.0)))
+            .collect::<Vec<_>>();
+
+        for (i, e) in embeddings.iter_mut().enumerate() {
+            let center = &centers[i % clusters];
+            for (x, c) in e.data.iter_mut().zip(center.iter()) {
+                *x = c + rng.gen_range(-0.05..0.05);
+            }
+            normalize(e);
+        }
+
+        crate::dbio::write_blocks(&embeddings).unwrap();
+        embeddings
+    }
+
+    // the pinned entry points are written out with the index and read back in,
+    // whether its bottom layer is paged or not
+    #[test]
+    fn entry_points_round_trip_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        setup_embeddings(300).unwrap();
+
+        let index = HNSW::build(&HNSWParams {
+            seed: Some(3),
+            entry_points: Some(6),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(index.entry_points.len(), 6);
+        assert!(index
+            .entry_points
+            .iter()
+            .all(|&pin| index.get_last_layer().contains(pin)));
+        let unique = index.entry_points.iter().collect::<HashSet<_>>();
+        assert_eq!(unique.len(), 6);
+
+        let path = get_data_dir().join("index");
+        index.serialize(&path).unwrap();
+        let full = HNSW::deserialize(&path).unwrap();
+        assert_eq!(full.entry_points, index.entry_points);
+        assert!(full.layers == index.layers);
+        let paged = HNSW::deserialize_paged(&path, 8).unwrap();
+        assert_eq!(paged.entry_points, index.entry_points);
+        assert_eq!(
+            HNSW::read_header(&path).unwrap(),
+            (index.model.clone(), index.metric)
+        );
+
+        // the same seed pins the same nodes, and pinning changes nothing about the graph
+        let unpinned = HNSW::build(&HNSWParams {
+            seed: Some(3),
+            entry_points: Some(0),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(unpinned.entry_points.is_empty());
+        assert!(unpinned.layers == index.layers);
+
+        // indices written before entry points were pinned have none
+        let legacy = get_data_dir().join("index.legacy");
+        std::fs::write(&legacy, index.to_bytes()).unwrap();
+        assert!(HNSW::deserialize(&legacy).unwrap().entry_points.is_empty());
+
+        // picked again, they're still spread over nodes in the index, and removing one unpins it
+        let mut index = full;
+        index.recompute_entry_points(ENTRY_POINTS, Some(5)).unwrap();
+        assert_eq!(index.entry_points.len(), ENTRY_POINTS);
+        assert!(index
+            .entry_points
+            .iter()
+            .all(|&pin| index.get_last_layer().contains(pin)));
+
+        let pin = index.entry_points[0];
+        index.remove_node(pin).unwrap();
+        assert!(!index.entry_points.contains(&pin));
+        assert_eq!(index.entry_points.len(), ENTRY_POINTS - 1);
+    }
+
+    // on clustered embeddings, searching out from the pinned entry points as well
+    // finds at least as many of the true nearest neighbors as the single descent does
+    #[test]
+    fn entry_points_recall_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let embeddings = setup_clusters(30, 20);
+
+        let recall = |entry_points: usize| {
+            let index = HNSW::build(&HNSWParams {
+                m_max: Some(4),
+                m_max_bottom: Some(8),
+                ef_construction: Some(16),
+                seed: Some(19),
+                entry_points: Some(entry_points),
+                ..Default::default()
+            })
+            .unwrap();
+
+            let mut found = 0;
+            let mut total = 0;
+            for q in embeddings.iter().step_by(7) {
+                let mut expected = embeddings
+                    .iter()
+                    .map(|e| (e.id, distance(q, e, index.metric)))
+                    .collect::<Vec<_>>();
+                expected.sort_by(|a, b| a.1.total_cmp(&b.1));
+                let expected = expected
+                    .iter()
+                    .take(10)
+                    .map(|(id, _)| *id)
+                    .collect::<HashSet<_>>();
+
+                let query = Query {
+                    mode: SearchMode::Fast,
+                    ..query_for(q)
+                };
+                let results = index.query(&query, 10, 10).results;
+                found += results
+                    .iter()
+                    .filter(|r| expected.contains(&r.0.id))
+                    .count();
+                total += expected.len();
+            }
+
+            found as f32 / total as f32
+        };
+
+        let without = recall(0);
+        let with = recall(ENTRY_POINTS * 4);
+        println!(
+            "recall@10 on clustered embeddings: {:.3} without pinned entry points, {:.3} with",
+            without, with
+        );
+        assert!(with >= without, "{} < {}", with, without);
     }
 }
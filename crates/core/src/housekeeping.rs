@@ -0,0 +1,332 @@
+use std::time::{Duration, SystemTime};
+
+use crate::config;
+use crate::logger::Logger;
+use crate::{error, info};
+
+// how long saved queries and logs are kept, and how much room they get altogether
+//
+// logs are gzipped once they've gone `compress_after` without being written to,
+// which is long enough that a running server has logged something since
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Policy {
+    pub query_max_age: Duration,
+    pub log_max_age: Duration,
+    pub compress_after: Duration,
+    pub max_bytes: u64,
+}
+
+// what a housekeeping run did
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    pub removed: Vec<std::path::PathBuf>,
+    pub compressed: Vec<std::path::PathBuf>,
+    pub freed_bytes: u64,
+}
+
+impl Report {
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.compressed.is_empty()
+    }
+}
+
+struct Managed {
+    path: std::path::PathBuf,
+    bytes: u64,
+    modified: SystemTime,
+}
+
+impl Managed {
+    fn read(path: std::path::PathBuf) -> Result<Self, std::io::Error> {
+        let metadata = std::fs::metadata(&path)?;
+        Ok(Self {
+            path,
+            bytes: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+}
+
+// ages out the saved queries and the logs of every dewey process,
+// leaving alone the log this process is writing to
+pub fn run(policy: &Policy) -> Result<Report, std::io::Error> {
+    let active = Logger::current_path().into_iter().collect::<Vec<_>>();
+    let report = sweep(
+        &config::get_queries_dir(),
+        &config::get_logs_dir(),
+        policy,
+        &active,
+    )?;
+
+    info!(
+        "housekeeping removed {} files ({} bytes) and compressed {}",
+        report.removed.len(),
+        report.freed_bytes,
+        report.compressed.len()
+    );
+
+    Ok(report)
+}
+
+fn sweep(
+    queries_dir: &std::path::Path,
+    logs_dir: &std::path::Path,
+    policy: &Policy,
+    active: &[std::path::PathBuf],
+) -> Result<Report, std::io::Error> {
+    let now = SystemTime::now();
+    let age = |file: &Managed| now.duration_since(file.modified).unwrap_or_default();
+
+    let mut report = Report::default();
+    let mut kept = Vec::new();
+
+    for file in list(queries_dir, |_| true)? {
+        match age(&file) > policy.query_max_age {
+            true => remove(file, &mut report)?,
+            false => kept.push(file),
+        }
+    }
+
+    for file in list(logs_dir, is_log)? {
+        if active.contains(&file.path) {
+            continue;
+        }
+
+        if age(&file) > policy.log_max_age {
+            remove(file, &mut report)?;
+        } else if age(&file) > policy.compress_after && !is_compressed(&file.path) {
+            match compress(&file.path).and_then(Managed::read) {
+                Ok(compressed) => {
+                    info!("compressed log {}", file.path.display());
+                    report.compressed.push(compressed.path.clone());
+                    kept.push(compressed);
+                }
+                // an uncompressed log is only taking up a bit more room
+                Err(e) => {
+                    error!("failed to compress log {}: {}", file.path.display(), e);
+                    kept.push(file);
+                }
+            }
+        } else {
+            kept.push(file);
+        }
+    }
+
+    // oldest first, until what's left fits
+    kept.sort_by_key(|file| file.modified);
+    let mut total = kept.iter().map(|file| file.bytes).sum::<u64>();
+    for file in kept {
+        if total <= policy.max_bytes {
+            break;
+        }
+
+        total -= file.bytes;
+        remove(file, &mut report)?;
+    }
+
+    Ok(report)
+}
+
+// the files directly under `dir`, which doesn't have to exist
+fn list(
+    dir: &std::path::Path,
+    keep: impl Fn(&std::path::Path) -> bool,
+) -> Result<Vec<Managed>, std::io::Error> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut files = Vec::new();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if !path.is_file() || !keep(&path) {
+            continue;
+        }
+
+        match Managed::read(path) {
+            Ok(file) => files.push(file),
+            // removed by someone else in the meantime
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(files)
+}
+
+fn is_log(path: &std::path::Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".log") || is_compressed(path)
+}
+
+fn is_compressed(path: &std::path::Path) -> bool {
+    path.to_string_lossy().ends_with(".log.gz")
+}
+
+// gzip keeps the log's modification time, so it goes on aging as it was
+fn compress(path: &std::path::Path) -> Result<std::path::PathBuf, std::io::Error> {
+    let status = std::process::Command::new("gzip")
+        .arg("-f")
+        .arg(path)
+        .status()?;
+
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "gzip exited with {}",
+            status
+        )));
+    }
+
+    let mut compressed = path.as_os_str().to_owned();
+    compressed.push(".gz");
+
+    Ok(compressed.into())
+}
+
+fn remove(file: Managed, report: &mut Report) -> Result<(), std::io::Error> {
+    match std::fs::remove_file(&file.path) {
+        Ok(_) => {
+            info!("housekeeping removed {}", file.path.display());
+            report.freed_bytes += file.bytes;
+            report.removed.push(file.path);
+        }
+        // another process's housekeeping got to it first
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            error!("error removing {}: {}", file.path.display(), e);
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_common::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn aged(path: &std::path::Path, contents: &str, days: u32) {
+        std::fs::write(path, contents).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() - DAY * days)
+            .unwrap();
+    }
+
+    fn names(dir: &std::path::Path) -> Vec<String> {
+        let mut names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn sweep_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let root = config::get_home_dir();
+        let queries_dir = root.join("aged_queries");
+        let logs_dir = root.join("aged_logs");
+        std::fs::create_dir_all(&queries_dir).unwrap();
+        std::fs::create_dir_all(&logs_dir).unwrap();
+
+        aged(&queries_dir.join("old"), "an old query", 200);
+        aged(&queries_dir.join("recent"), "a recent query", 1);
+        aged(&logs_dir.join("old.log"), "old log", 40);
+        aged(&logs_dir.join("older.log.gz"), "older log", 60);
+        aged(
+            &logs_dir.join("week.log"),
+            &"a week of logging\n".repeat(100),
+            10,
+        );
+        aged(&logs_dir.join("fresh.log"), "fresh log", 0);
+        aged(&logs_dir.join("active.log"), "still being written", 40);
+        aged(&logs_dir.join("notes.txt"), "not a log", 40);
+
+        let policy = Policy {
+            query_max_age: DAY * 180,
+            log_max_age: DAY * 30,
+            compress_after: DAY * 7,
+            max_bytes: u64::MAX,
+        };
+
+        let report = sweep(
+            &queries_dir,
+            &logs_dir,
+            &policy,
+            &[logs_dir.join("active.log")],
+        )
+        .unwrap();
+
+        let mut removed = report.removed.clone();
+        removed.sort();
+        assert_eq!(
+            removed,
+            vec![
+                logs_dir.join("old.log"),
+                logs_dir.join("older.log.gz"),
+                queries_dir.join("old"),
+            ]
+        );
+        assert_eq!(
+            report.freed_bytes,
+            ("an old query".len() + "old log".len() + "older log".len()) as u64
+        );
+        assert_eq!(report.compressed, vec![logs_dir.join("week.log.gz")]);
+
+        assert_eq!(names(&queries_dir), vec!["recent"]);
+        assert_eq!(
+            names(&logs_dir),
+            vec!["active.log", "fresh.log", "notes.txt", "week.log.gz"]
+        );
+
+        // compressing doesn't make a log any younger
+        let week = Managed::read(logs_dir.join("week.log.gz")).unwrap();
+        assert!(SystemTime::now().duration_since(week.modified).unwrap() > DAY * 9);
+        assert!(week.bytes < "a week of logging\n".repeat(100).len() as u64);
+
+        // over the cap, the oldest go first
+        let policy = Policy {
+            max_bytes: ("a recent query".len() + "fresh log".len()) as u64,
+            ..policy
+        };
+
+        let report = sweep(
+            &queries_dir,
+            &logs_dir,
+            &policy,
+            &[logs_dir.join("active.log")],
+        )
+        .unwrap();
+        assert_eq!(report.removed, vec![logs_dir.join("week.log.gz")]);
+        assert_eq!(report.freed_bytes, week.bytes);
+        assert!(report.compressed.is_empty());
+
+        assert_eq!(names(&queries_dir), vec!["recent"]);
+        assert_eq!(
+            names(&logs_dir),
+            vec!["active.log", "fresh.log", "notes.txt"]
+        );
+
+        // nothing left to do
+        assert!(sweep(
+            &queries_dir,
+            &logs_dir,
+            &policy,
+            &[logs_dir.join("active.log")]
+        )
+        .unwrap()
+        .is_empty());
+    }
+}
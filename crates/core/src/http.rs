@@ -0,0 +1,131 @@
+// requests written out by hand over a socket, for the embedding API and the trace exporter
+//
+// everything that goes into the head of a request is checked as it's added,
+// so nothing read from a file, the config, or the environment can end a header early
+// and start another, and the declared length is always the length of the body that's sent
+
+pub struct HttpRequest {
+    method: &'static str,
+    path: String,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+// header values can't hold anything that would end the line they're on
+fn check_value(name: &str, value: &str) -> Result<(), std::io::Error> {
+    match value.chars().any(|c| c.is_control() && c != '\t') {
+        // the value itself is left out, since it can be a credential
+        true => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} can't contain control characters", name),
+        )),
+        false => Ok(()),
+    }
+}
+
+impl HttpRequest {
+    pub fn post(host: &str, path: &str) -> Result<Self, std::io::Error> {
+        if path.is_empty() || path.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid request path {:?}", path),
+            ));
+        }
+
+        HttpRequest {
+            method: "POST",
+            path: path.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+        .header("Host", host)
+    }
+
+    pub fn header(mut self, name: &'static str, value: &str) -> Result<Self, std::io::Error> {
+        check_value(name, value)?;
+        self.headers.push((name, value.to_string()));
+        Ok(self)
+    }
+
+    pub fn json(mut self, body: &serde_json::Value) -> Result<Self, std::io::Error> {
+        self.body = serde_json::to_vec(body)?;
+        self.header("Content-Type", "application/json")
+    }
+
+    // the request as it goes over the wire, with its `Content-Length` taken from the body
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("{} {} HTTP/1.1\r\n", self.method, self.path);
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", self.body.len()));
+
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+// the head of a request and the body after it, with the length the head declares
+#[cfg(test)]
+pub fn split_request(bytes: &[u8]) -> (String, usize, Vec<u8>) {
+    let end = bytes.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8(bytes[..end].to_vec()).unwrap();
+    let declared = head
+        .lines()
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    (head, declared, bytes[end + 4..].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_test() {
+        let body = serde_json::json!({ "input": ["\n\nstarts and ends with newlines\n\n", " \t"] });
+        let request = HttpRequest::post("api.example.com", "/v1/embeddings")
+            .unwrap()
+            .header("Authorization", "Bearer key")
+            .unwrap()
+            .json(&body)
+            .unwrap()
+            .to_bytes();
+
+        let (head, declared, sent) = split_request(&request);
+        assert_eq!(declared, sent.len());
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&sent).unwrap(),
+            body
+        );
+        assert_eq!(
+            head.lines().collect::<Vec<_>>(),
+            vec![
+                "POST /v1/embeddings HTTP/1.1",
+                "Host: api.example.com",
+                "Authorization: Bearer key",
+                "Content-Type: application/json",
+                &format!("Content-Length: {}", sent.len()),
+            ]
+        );
+
+        // nothing can start a header of its own
+        for value in ["key\r\nX-Injected: 1", "key\nX-Injected: 1", "key\r"] {
+            let error = HttpRequest::post("api.example.com", "/v1/embeddings")
+                .unwrap()
+                .header("Authorization", value)
+                .err()
+                .unwrap();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+            assert!(!error.to_string().contains("key"));
+        }
+
+        assert!(HttpRequest::post("api.example.com\r\nX-Injected: 1", "/").is_err());
+        assert!(HttpRequest::post("api.example.com", "/ HTTP/1.1\r\n").is_err());
+        assert!(HttpRequest::post("api.example.com", "").is_err());
+    }
+}
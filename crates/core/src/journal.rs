@@ -0,0 +1,165 @@
+use std::io::{Read, Seek, Write};
+
+use crate::config;
+use crate::logger::Logger;
+pub use crate::types::{Entry, Outcome, Params};
+use crate::{error, info};
+
+// how much of the end of the journal is read at a time when looking for its last entries
+const TAIL_CHUNK: u64 = 64 * 1024;
+
+// runs `operation`, then appends an entry saying how it went to the journal
+//
+// `f` fills in the entry's params as it goes, so they can count what it did
+//
+// the journal is only a record, so an entry that can't be written is logged
+// and the operation's result is returned all the same
+pub fn record<T>(
+    operation: &str,
+    f: impl FnOnce(&mut Params) -> Result<T, std::io::Error>,
+) -> Result<T, std::io::Error> {
+    let timestamp = chrono::Local::now().to_rfc3339();
+    let started = std::time::Instant::now();
+    let mut params = Params::new();
+
+    let result = f(&mut params);
+
+    let written = crate::dbio::read_state_generation().and_then(|generation| {
+        append(&Entry {
+            timestamp,
+            operation: operation.to_string(),
+            params,
+            duration_ms: started.elapsed().as_millis() as u64,
+            outcome: match &result {
+                Ok(_) => Outcome::Completed,
+                Err(e) => Outcome::Failed {
+                    error: e.to_string(),
+                },
+            },
+            generation,
+        })
+    });
+
+    if let Err(e) = written {
+        error!("failed to write {} to the journal: {}", operation, e);
+    }
+
+    result
+}
+
+// an entry goes out in a single write to a file opened for appending,
+// so entries from processes writing at the same time never end up interleaved
+fn append(entry: &Entry) -> Result<(), std::io::Error> {
+    let mut line = serde_json::to_string(entry).map_err(std::io::Error::other)?;
+    line.push('\n');
+
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(config::get_paths().journal_path())?
+        .write_all(line.as_bytes())
+}
+
+// the last `n` entries of the journal, oldest first
+//
+// only the end of the journal is read, however long it's gotten,
+// and lines that don't parse (from a write cut short) are skipped
+pub fn read_recent(n: usize) -> Result<Vec<Entry>, std::io::Error> {
+    let mut file = match std::fs::File::open(config::get_paths().journal_path()) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    // reading goes back a chunk at a time until there are more lines than are needed,
+    // since the first of them is probably cut off partway
+    let mut start = file.metadata()?.len();
+    let mut tail = Vec::new();
+    while start > 0 && tail.iter().filter(|&&b| b == b'\n').count() <= n {
+        let read = TAIL_CHUNK.min(start);
+        start -= read;
+
+        let mut chunk = vec![0; read as usize];
+        file.seek(std::io::SeekFrom::Start(start))?;
+        file.read_exact(&mut chunk)?;
+        chunk.extend(tail);
+        tail = chunk;
+    }
+
+    let tail = String::from_utf8_lossy(&tail);
+    let mut lines = tail.lines().collect::<Vec<_>>();
+    if start > 0 && !lines.is_empty() {
+        lines.remove(0);
+    }
+
+    let mut entries = lines
+        .iter()
+        .rev()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str::<Entry>(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                info!("skipping malformed journal line: {}", e);
+                None
+            }
+        })
+        .take(n)
+        .collect::<Vec<_>>();
+    entries.reverse();
+
+    Ok(entries)
+}
+
+pub fn last_entry() -> Result<Option<Entry>, std::io::Error> {
+    Ok(read_recent(1)?.pop())
+}
+
+#[cfg(all(test, feature = "full"))]
+mod tests {
+    use super::*;
+    use crate::test_common::{setup, Cleanup};
+
+    // every entry is read back in order, however many chunks the journal's end is read in
+    #[test]
+    fn read_recent_test() {
+        let _cleanup = Cleanup;
+        assert!(setup().is_ok());
+
+        for i in 0..2000 {
+            let result = record("test", |params| {
+                params.insert("i".to_string(), i.into());
+                match i % 3 {
+                    0 => Err(std::io::Error::other("failed")),
+                    _ => Ok(()),
+                }
+            });
+            assert_eq!(result.is_err(), i % 3 == 0);
+        }
+
+        assert!(
+            std::fs::metadata(config::get_paths().journal_path())
+                .unwrap()
+                .len()
+                > TAIL_CHUNK * 2
+        );
+
+        let entries = read_recent(1500).unwrap();
+        assert_eq!(entries.len(), 1500);
+        for (entry, i) in entries.iter().zip(500..) {
+            assert_eq!(entry.params["i"], i);
+            assert_eq!(entry.operation, "test");
+            assert_eq!(entry.outcome == Outcome::Completed, i % 3 != 0);
+        }
+
+        assert_eq!(read_recent(5000).unwrap().len(), 2000);
+        assert_eq!(last_entry().unwrap().unwrap().params["i"], 1999);
+
+        // an entry cut short is passed over
+        let mut journal = std::fs::OpenOptions::new()
+            .append(true)
+            .open(config::get_paths().journal_path())
+            .unwrap();
+        journal.write_all(b"{\"timestamp\": \"2024").unwrap();
+        assert_eq!(last_entry().unwrap().unwrap().params["i"], 1999);
+    }
+}
@@ -1,13 +1,16 @@
 use sha2::digest::Update;
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use std::io::{BufRead, Write};
+use std::io::BufRead;
 
 use crate::logger::Logger;
+pub use crate::types::LedgerEntry;
 use crate::{error, info, lprint};
 
 // TODO: there needs to be better delineation on the different rule types
 //       Currently, MinLength and Alphanumeric act as filters,
+//       Normalize rewrites the text that's embedded for each chunk,
+//       Model picks the embedding model (and so the store) of the extension's files,
 //       while the rest act as splitting rules.
 //       Filters are applied _only_ after splitting rules.
 #[derive(Debug, PartialEq, Clone)]
@@ -18,55 +21,84 @@ pub enum IndexRuleType {
     MinLength,
     MaxLength,
     Alphanumeric,
+    Normalize,
+    Model,
 }
 
+// transforms a `--normalize` rule can list, in the order they're applied
+// regardless of the order they're listed in
+pub const NORMALIZE_TRANSFORMS: [&str; 4] = [
+    "strip-ansi",
+    "strip-html",
+    "lowercase",
+    "collapse-whitespace",
+];
+
 impl IndexRuleType {
     pub fn validate(&self, value: &str) -> bool {
+        match self.check(value) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Ignoring {}", e);
+                false
+            }
+        }
+    }
+
+    // what's wrong with `value` for a rule of this type, if anything
+    fn check(&self, value: &str) -> Result<(), String> {
         match self {
-            IndexRuleType::MinLength => {
-                if value.parse::<usize>().is_err() {
-                    error!("Ignoring invalid min length value: {}", value);
-                    false
-                } else {
-                    true
-                }
+            IndexRuleType::MinLength if value.parse::<usize>().is_err() => {
+                Err(format!("invalid min length value: {}", value))
             }
-            IndexRuleType::MaxLength => {
-                if value.parse::<usize>().is_err() {
-                    error!("Ignoring invalid max length value: {}", value);
-                    false
-                } else {
-                    true
-                }
+            IndexRuleType::MaxLength if value.parse::<usize>().is_err() => {
+                Err(format!("invalid max length value: {}", value))
             }
-            IndexRuleType::Alphanumeric => {
-                if value.to_lowercase() != "true" && value.to_lowercase() != "false" {
-                    error!("Ignoring invalid alphanumeric value: {}", value);
-                    false
-                } else {
-                    true
-                }
+            IndexRuleType::Alphanumeric
+                if value.to_lowercase() != "true" && value.to_lowercase() != "false" =>
+            {
+                Err(format!("invalid alphanumeric value: {}", value))
             }
-            IndexRuleType::Split => {
-                if value.len() == 0 {
-                    error!("Ignoring invalid empty split value");
-                    false
-                } else {
-                    true
-                }
+            IndexRuleType::Split if value.is_empty() => {
+                Err("invalid empty split value".to_string())
             }
-
-            IndexRuleType::Code => {
-                if value.to_lowercase() != "function" {
-                    error!("Ignoring invalid code value: {}", value);
-                    false
-                } else {
-                    true
-                }
+            IndexRuleType::Code if value.to_lowercase() != "function" => {
+                Err(format!("invalid code value: {}", value))
             }
-            _ => true,
+            // model names double as directory names in the data directory
+            IndexRuleType::Model
+                if value.is_empty()
+                    || !value
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+                    || value.starts_with('.') =>
+            {
+                Err(format!("invalid model value: {}", value))
+            }
+            IndexRuleType::Normalize
+                if !value
+                    .split(',')
+                    .all(|transform| NORMALIZE_TRANSFORMS.contains(&transform.trim())) =>
+            {
+                Err(format!(
+                    "invalid normalize value: {}, expected a comma-separated list of {:?}",
+                    value, NORMALIZE_TRANSFORMS
+                ))
+            }
+            _ => Ok(()),
         }
     }
+
+    // the rules that pick how a file is split, of which only the last applies
+    fn is_splitter(&self) -> bool {
+        matches!(
+            self,
+            IndexRuleType::Naive
+                | IndexRuleType::Split
+                | IndexRuleType::MaxLength
+                | IndexRuleType::Code
+        )
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -75,40 +107,134 @@ pub struct IndexRule {
     pub value: String,
 }
 
-#[derive(Debug, Clone)]
-pub struct LedgerEntry {
-    pub filepath: String,
-    pub hash: String,
-    pub meta: std::collections::HashSet<String>,
+// the rule as it'd be written in the rules file, e.g. `--split "\n\n"`
+impl std::fmt::Display for IndexRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = format!("{:?}", self.rule_type).to_lowercase();
+        match self.rule_type {
+            IndexRuleType::Naive => write!(f, "--{}", name),
+            _ => write!(f, "--{} {:?}", name, self.value),
+        }
+    }
+}
+
+// ledger lines are `filepath hash meta,meta,...`
+//
+// the hash and meta never contain spaces, so the filepath is everything before them
+// meta can be empty, leaving a trailing space, though a line that's lost it still parses
+fn parse_ledger_line(line: &str) -> Option<LedgerEntry> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let mut parts = line.rsplitn(3, ' ');
+    let mut meta = parts.next()?;
+    let mut hash = parts.next()?;
+    let mut filepath = parts.next().unwrap_or_default();
+
+    if !is_hash(hash) && is_hash(meta) {
+        (filepath, hash, meta) = (line.rsplit_once(' ')?.0, meta, "");
+    }
+
+    if filepath.is_empty() || hash.is_empty() {
+        return None;
+    }
+
+    Some(LedgerEntry {
+        filepath: filepath.to_string(),
+        hash: hash.to_string(),
+        meta: decode_meta(meta),
+    })
+}
+
+fn format_ledger_line(entry: &LedgerEntry) -> String {
+    format!(
+        "{} {} {}",
+        entry.filepath,
+        entry.hash,
+        encode_meta(&entry.meta)
+    )
+}
+
+// a ledger line with its filepath as it's stored, and back
+fn read_ledger_line(line: &str, paths: &crate::config::PathResolver) -> Option<LedgerEntry> {
+    parse_ledger_line(line).map(|entry| LedgerEntry {
+        filepath: paths.resolve(&entry.filepath),
+        ..entry
+    })
+}
+
+fn write_ledger_line(entry: &LedgerEntry, paths: &crate::config::PathResolver) -> String {
+    format_ledger_line(&LedgerEntry {
+        filepath: paths.store(&entry.filepath),
+        ..entry.clone()
+    })
+}
+
+fn is_hash(part: &str) -> bool {
+    part.len() == 64 && part.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// meta is written sorted, so the same tags always make the same line,
+// with backslashes, commas, and spaces escaped so that any tag survives the trip
+fn encode_meta(meta: &std::collections::HashSet<String>) -> String {
+    let mut meta = meta
+        .iter()
+        .map(|m| {
+            m.replace('\\', "\\\\")
+                .replace(',', "\\,")
+                .replace(' ', "\\s")
+        })
+        .collect::<Vec<_>>();
+    meta.sort();
+    meta.join(",")
+}
+
+fn decode_meta(encoded: &str) -> std::collections::HashSet<String> {
+    let mut meta = std::collections::HashSet::new();
+    let mut tag = String::new();
+    let mut chars = encoded.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('s') => tag.push(' '),
+                Some(escaped) => tag.push(escaped),
+                None => tag.push('\\'),
+            },
+            ',' => {
+                meta.insert(std::mem::take(&mut tag));
+            }
+            c => tag.push(c),
+        }
+    }
+
+    meta.insert(tag);
+    meta.remove("");
+    meta
 }
 
 pub fn read_ledger() -> Result<Vec<LedgerEntry>, std::io::Error> {
     let ledger_path = crate::config::get_local_dir().join("ledger");
-    let ledger_file = std::fs::File::open(&ledger_path).expect("Failed to open ledger file");
+    // a data directory that's never been synced has no ledger yet
+    let ledger_file = match std::fs::File::open(&ledger_path) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("failed to open ledger {}: {}", ledger_path.display(), e);
+            return Err(e);
+        }
+    };
 
+    let paths = crate::config::PathResolver::current();
     let mut reader = std::io::BufReader::new(ledger_file);
     let mut entries = Vec::new();
     let mut line = String::new();
-    while reader.read_line(&mut line).is_ok() {
-        if line.is_empty() {
-            break;
-        }
-
-        let parts: Vec<&str> = line.split_whitespace().filter(|s| !s.is_empty()).collect();
-        if parts
-            .get(0)
-            .map_or(false, |path| std::path::Path::new(path).exists())
-        {
-            entries.push(LedgerEntry {
-                filepath: parts[0].to_string(),
-                hash: parts[1].to_string(),
-                meta: parts[2]
-                    .split(",")
-                    .map(|s| s.to_string())
-                    .collect::<std::collections::HashSet<String>>(),
-            });
-        } else {
-            panic!("Malformed ledger entry: {:?}", parts);
+    while reader.read_line(&mut line)? > 0 {
+        // files deleted since the last sync are left out until the next one drops them
+        match read_ledger_line(&line, &paths) {
+            Some(entry) if std::path::Path::new(&entry.filepath).exists() => entries.push(entry),
+            Some(entry) => {
+                error!("skipping ledger entry for missing file {}", entry.filepath);
+            }
+            None => {
+                error!("skipping malformed ledger entry: {:?}", line);
+            }
         }
 
         line.clear();
@@ -117,29 +243,362 @@ pub fn read_ledger() -> Result<Vec<LedgerEntry>, std::io::Error> {
     Ok(entries)
 }
 
-// returns a list of files whose hashes are out of date with file contents
+// the ledger's entry for `filepath`, `None` if the config doesn't index it
+pub fn entry_for(filepath: &str) -> Result<Option<LedgerEntry>, std::io::Error> {
+    Ok(read_ledger()?.into_iter().find(|e| e.filepath == filepath))
+}
+
+// returns a list of files whose hashes are out of date with file contents,
+// along with every file whose extension has had its indexing rules changed
+// since the last embedding run
 pub fn get_stale_files() -> Result<Vec<LedgerEntry>, std::io::Error> {
     let ledger = read_ledger()?;
+    let changed_extensions = get_changed_rule_extensions()?;
+
     let mut stale_files = Vec::new();
+    let mut rule_stale_count = 0;
     for entry in ledger.iter() {
-        let hash = get_hash(&entry.filepath)?;
+        // a file that can't be read is stale, and skipped once it comes to embedding it
+        let hash = get_hash(&entry.filepath).unwrap_or_default();
         if hash != entry.hash {
             stale_files.push(entry.clone());
+        } else if changed_extensions.contains(get_extension(&entry.filepath)) {
+            rule_stale_count += 1;
+            stale_files.push(entry.clone());
         }
     }
 
+    if rule_stale_count > 0 {
+        lprint!(
+            info,
+            "Indexing rules changed for {:?}, marking {} unchanged files stale",
+            changed_extensions,
+            rule_stale_count
+        );
+    }
+
     Ok(stale_files)
 }
 
-fn get_hash(filepath: &String) -> Result<String, std::io::Error> {
-    let content = std::fs::read(filepath)?;
+pub fn get_hash(filepath: &String) -> Result<String, std::io::Error> {
+    Ok(hash_contents(&std::fs::read(filepath)?))
+}
+
+pub fn hash_contents(content: &[u8]) -> String {
     let mut hasher = Sha256::new();
-    Update::update(&mut hasher, &content);
-    Ok(hasher
+    Update::update(&mut hasher, content);
+    hasher
         .finalize()
         .iter()
         .map(|b| format!("{:02x}", b))
-        .collect::<String>())
+        .collect::<String>()
+}
+
+// what's wrong with a line of the rules file
+//
+// errors are rules that are dropped, while warnings are rules that are kept but do nothing
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleIssue {
+    Malformed,
+    UnknownFlag,
+    InvalidValue,
+    // a rule that never applies since a later one takes its place
+    Unreachable,
+    // an extension that no file in the ledger has
+    UnseenExtension,
+}
+
+impl RuleIssue {
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self,
+            RuleIssue::Malformed | RuleIssue::UnknownFlag | RuleIssue::InvalidValue
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct RuleDiagnostic {
+    // counted from 1, with 0 standing for the file as a whole
+    pub line: usize,
+    // the part of the line at fault
+    pub token: String,
+    pub issue: RuleIssue,
+    pub message: String,
+}
+
+impl std::fmt::Display for RuleDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.issue.is_error() {
+            true => "error",
+            false => "warning",
+        };
+
+        write!(f, "line {}: {}: {}", self.line, severity, self.message)
+    }
+}
+
+// e.g. `2 errors and 1 warning in the rules`
+pub fn summarize_rules(diagnostics: &[RuleDiagnostic]) -> String {
+    let errors = diagnostics.iter().filter(|d| d.issue.is_error()).count();
+    let warnings = diagnostics.len() - errors;
+    let plural = |n: usize, word: &str| match n {
+        1 => format!("1 {}", word),
+        n => format!("{} {}s", n, word),
+    };
+
+    format!(
+        "{} and {} in the rules",
+        plural(errors, "error"),
+        plural(warnings, "warning")
+    )
+}
+
+struct RulesLine {
+    extension: String,
+    rules: Vec<IndexRule>,
+    diagnostics: Vec<RuleDiagnostic>,
+}
+
+// a line of the rules file, `number` counted from 1
+//
+// rules with something wrong with them are left out, and the rest kept as they are
+fn parse_rules_line(line: &str, number: usize) -> Option<RulesLine> {
+    let diagnostic = |issue: RuleIssue, token: &str, message: String| RuleDiagnostic {
+        line: number,
+        token: token.to_string(),
+        issue,
+        message,
+    };
+
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.is_empty() {
+        return None;
+    }
+
+    let mut diagnostics = Vec::new();
+    if parts.len() < 2 {
+        diagnostics.push(diagnostic(
+            RuleIssue::Malformed,
+            parts[0],
+            format!("malformed index rule: {}", line),
+        ));
+    }
+
+    let extension = parts[0].to_string();
+    let mut rule = IndexRule {
+        rule_type: IndexRuleType::Naive,
+        value: "".to_string(),
+    };
+
+    let mut rules = Vec::new();
+    // the flag waiting on a value, whether the last flag was unknown,
+    // and the splitting rule that applies so far
+    let mut pending: Option<&str> = None;
+    let mut unknown = false;
+    let mut splitter: Option<String> = None;
+    let mut replace_splitter = |flag: &str, diagnostics: &mut Vec<RuleDiagnostic>| {
+        if let Some(earlier) = splitter.replace(flag.to_string()) {
+            diagnostics.push(diagnostic(
+                RuleIssue::Unreachable,
+                &earlier,
+                format!(
+                    "`{}` never applies, since the later `{}` picks how the file is split",
+                    earlier, flag
+                ),
+            ));
+        }
+    };
+
+    for part in parts.iter().skip(1) {
+        if part.starts_with("--") {
+            if let Some(flag) = pending.take() {
+                diagnostics.push(diagnostic(
+                    RuleIssue::InvalidValue,
+                    flag,
+                    format!("`{}` has no value", flag),
+                ));
+            }
+
+            unknown = false;
+            match part.to_lowercase().as_str() {
+                // naive is the only rule that doesn't take a value
+                "--naive" => {
+                    rules.push(IndexRule {
+                        rule_type: IndexRuleType::Naive,
+                        value: "".to_string(),
+                    });
+                    replace_splitter(part, &mut diagnostics);
+                }
+                "--code" => rule.rule_type = IndexRuleType::Code,
+                "--split" => rule.rule_type = IndexRuleType::Split,
+                "--maxlength" => rule.rule_type = IndexRuleType::MaxLength,
+                "--minlength" => rule.rule_type = IndexRuleType::MinLength,
+                "--alphanumeric" => rule.rule_type = IndexRuleType::Alphanumeric,
+                "--normalize" => rule.rule_type = IndexRuleType::Normalize,
+                "--model" => rule.rule_type = IndexRuleType::Model,
+                _ => {
+                    unknown = true;
+                    diagnostics.push(diagnostic(
+                        RuleIssue::UnknownFlag,
+                        part,
+                        format!("unknown rule type: {}", part),
+                    ));
+                }
+            }
+
+            if !unknown && part.to_lowercase() != "--naive" {
+                pending = Some(part);
+            }
+        } else {
+            let flag = pending.take();
+            // the value of an unknown flag has already been accounted for
+            if flag.is_none() && !std::mem::take(&mut unknown) {
+                diagnostics.push(diagnostic(
+                    RuleIssue::InvalidValue,
+                    part,
+                    format!("`{}` isn't the value of any rule", part),
+                ));
+            }
+
+            rule.value = match crate::config::expand_vars(part) {
+                Ok(value) => value,
+                Err(variable) => {
+                    diagnostics.push(diagnostic(
+                        RuleIssue::InvalidValue,
+                        part,
+                        format!(
+                            "rule value {:?} in {:?}: ${} isn't set",
+                            part, line, variable
+                        ),
+                    ));
+                    continue;
+                }
+            };
+            rule.value = rule
+                .value
+                .replace("\"", "")
+                .replace("\\n", "\n")
+                .replace("\\t", "\t")
+                .replace("\\r", "\r");
+
+            if let Err(e) = rule.rule_type.check(&rule.value) {
+                diagnostics.push(diagnostic(RuleIssue::InvalidValue, part, e));
+                continue;
+            }
+
+            if let Some(flag) = flag.filter(|_| rule.rule_type.is_splitter()) {
+                replace_splitter(flag, &mut diagnostics);
+            }
+
+            rules.push(rule);
+
+            rule = IndexRule {
+                rule_type: IndexRuleType::Naive,
+                value: "".to_string(),
+            };
+        }
+    }
+
+    if let Some(flag) = pending {
+        diagnostics.push(diagnostic(
+            RuleIssue::InvalidValue,
+            flag,
+            format!("`{}` has no value", flag),
+        ));
+    }
+
+    Some(RulesLine {
+        extension,
+        rules,
+        diagnostics,
+    })
+}
+
+// the rules of every line in `contents`, along with what's wrong with them
+//
+// a later line for the same extension replaces an earlier one
+fn parse_rules(contents: &str) -> (HashMap<String, Vec<IndexRule>>, Vec<RuleDiagnostic>) {
+    let mut rulesets = HashMap::new();
+    let mut lines = HashMap::<String, usize>::new();
+    let mut diagnostics = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let Some(parsed) = parse_rules_line(line, i + 1) else {
+            continue;
+        };
+
+        diagnostics.extend(parsed.diagnostics);
+        if let Some(earlier) = lines.insert(parsed.extension.clone(), i + 1) {
+            diagnostics.push(RuleDiagnostic {
+                line: earlier,
+                token: parsed.extension.clone(),
+                issue: RuleIssue::Unreachable,
+                message: format!(
+                    "the rules for `{}` are replaced by the ones on line {}",
+                    parsed.extension,
+                    i + 1
+                ),
+            });
+        }
+
+        rulesets.insert(parsed.extension, parsed.rules);
+    }
+
+    diagnostics.sort_by_key(|d| d.line);
+    (rulesets, diagnostics)
+}
+
+fn rules_path() -> std::path::PathBuf {
+    crate::config::get_config_dir().join("rules")
+}
+
+// everything wrong with the rules file, in the order of its lines
+//
+// on top of the rules that are dropped or never apply,
+// this warns of extensions that no file in the ledger has
+pub fn validate_rules() -> Vec<RuleDiagnostic> {
+    let contents = match std::fs::read_to_string(rules_path()) {
+        Ok(contents) => contents,
+        Err(e) => {
+            return vec![RuleDiagnostic {
+                line: 0,
+                token: rules_path().to_string_lossy().to_string(),
+                issue: RuleIssue::Malformed,
+                message: format!("couldn't read {}: {}", rules_path().display(), e),
+            }]
+        }
+    };
+
+    let (_, mut diagnostics) = parse_rules(&contents);
+
+    // the ledger's read line by line, since only the extensions matter
+    // and `read_ledger` won't have files that have gone missing
+    let ledger =
+        std::fs::read_to_string(crate::config::get_local_dir().join("ledger")).unwrap_or_default();
+    let extensions = ledger
+        .lines()
+        .filter_map(parse_ledger_line)
+        .map(|entry| get_extension(&entry.filepath).to_string())
+        .collect::<std::collections::HashSet<_>>();
+
+    for (i, line) in contents.lines().enumerate() {
+        let Some(extension) = line.split_whitespace().next() else {
+            continue;
+        };
+
+        if extension != "*" && !extensions.contains(extension) {
+            diagnostics.push(RuleDiagnostic {
+                line: i + 1,
+                token: extension.to_string(),
+                issue: RuleIssue::UnseenExtension,
+                message: format!("no file in the ledger has the extension `{}`", extension),
+            });
+        }
+    }
+
+    diagnostics.sort_by_key(|d| d.line);
+    diagnostics
 }
 
 // the rules config is housed in ~/.config/dewey/rules
@@ -149,71 +608,777 @@ fn get_hash(filepath: &String) -> Result<String, std::io::Error> {
 //   - `extension` is the file extension to which the rule applies
 //   - `rule_type` is the type of rule to apply
 //   - `value` is the value of the rule
+//
+// rules that are malformed are logged and left out, and the first time the rules are read
+// with a terminal to print to, everything wrong with them is printed there too
 pub fn get_indexing_rules() -> Result<HashMap<String, Vec<IndexRule>>, std::io::Error> {
-    let config_path = crate::config::get_config_dir();
-    let config_index_path = config_path.join("rules");
+    let contents = std::fs::read_to_string(rules_path())?;
+    let (rulesets, diagnostics) = parse_rules(&contents);
+    for diagnostic in diagnostics.iter().filter(|d| d.issue.is_error()) {
+        error!("Ignoring {}", diagnostic);
+    }
 
-    let file = std::fs::File::open(&config_index_path)?;
-    let reader = std::io::BufReader::new(file);
-    let mut rulesets = HashMap::new();
-    for line in reader.lines() {
-        let line = line?;
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() < 2 {
-            error!("Ignoring malformed index rule: {}", line);
+    static REPORTED: std::sync::Once = std::sync::Once::new();
+    if !cfg!(test) && std::io::IsTerminal::is_terminal(&std::io::stderr()) {
+        REPORTED.call_once(|| {
+            let diagnostics = validate_rules();
+            if diagnostics.is_empty() {
+                return;
+            }
+
+            for diagnostic in diagnostics.iter() {
+                eprintln!("{}", diagnostic);
+            }
+            eprintln!("{}, see dewey --check-rules", summarize_rules(&diagnostics));
+        });
+    }
+
+    Ok(rulesets)
+}
+
+// files without an extension end up with their whole path as the "extension",
+// which just means they only ever match the global rules
+pub fn get_extension(filepath: &str) -> &str {
+    filepath.split(".").last().unwrap_or(filepath)
+}
+
+// global rules are applied first, followed by any rules specific to the extension
+pub fn get_effective_rules(
+    rulesets: &HashMap<String, Vec<IndexRule>>,
+    extension: &str,
+) -> Vec<IndexRule> {
+    let mut rules = rulesets.get("*").cloned().unwrap_or_default();
+    if let Some(extension_rules) = rulesets.get(extension) {
+        rules.extend(extension_rules.clone());
+    }
+
+    rules
+}
+
+fn hash_rules(rules: &[IndexRule]) -> String {
+    let mut hasher = Sha256::new();
+    for rule in rules {
+        Update::update(
+            &mut hasher,
+            format!("{:?} {}\n", rule.rule_type, rule.value).as_bytes(),
+        );
+    }
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>()
+}
+
+// the hashes of the effective rules used in the last embedding run
+// are kept in $DATA_DIR/rules_hashes, formatted as `extension hash` on each line
+//
+// a missing file just means nothing has been embedded under tracked rules yet
+pub fn read_rules_hashes() -> Result<HashMap<String, String>, std::io::Error> {
+    let path = crate::config::get_data_dir().join("rules_hashes");
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let mut hashes = HashMap::new();
+    for line in std::fs::read_to_string(&path)?.lines() {
+        match line.split_once(" ") {
+            Some((extension, hash)) => {
+                hashes.insert(extension.to_string(), hash.trim().to_string());
+            }
+            None => {
+                error!("Ignoring malformed rules hash entry: {}", line);
+            }
+        }
+    }
+
+    Ok(hashes)
+}
+
+// records the effective rules hash of every extension present in the ledger
+pub fn write_rules_hashes(ledger: &[LedgerEntry]) -> Result<(), std::io::Error> {
+    let rulesets = get_indexing_rules()?;
+
+    let mut hashes = HashMap::new();
+    for entry in ledger.iter() {
+        let extension = get_extension(&entry.filepath);
+        if !hashes.contains_key(extension) {
+            hashes.insert(
+                extension.to_string(),
+                hash_rules(&get_effective_rules(&rulesets, extension)),
+            );
+        }
+    }
+
+    let contents = hashes
+        .iter()
+        .map(|(extension, hash)| format!("{} {}", extension, hash))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    crate::lock::write_atomic(
+        &crate::config::get_data_dir().join("rules_hashes"),
+        contents.as_bytes(),
+    )?;
+    info!("Wrote rules hashes for {} extensions", hashes.len());
+
+    Ok(())
+}
+
+// extensions whose effective rules no longer match what they were last embedded with
+pub fn get_changed_rule_extensions() -> Result<std::collections::HashSet<String>, std::io::Error> {
+    let previous = read_rules_hashes()?;
+    if previous.is_empty() {
+        return Ok(std::collections::HashSet::new());
+    }
+
+    let rulesets = get_indexing_rules()?;
+    Ok(previous
+        .into_iter()
+        .filter(|(extension, hash)| *hash != hash_rules(&get_effective_rules(&rulesets, extension)))
+        .map(|(extension, _)| extension)
+        .collect())
+}
+
+struct ConfigEntry {
+    pub filepath: String,
+    pub meta: std::collections::HashSet<String>,
+    pub follow_symlinks: bool,
+}
+
+// config ledger lines are `filepath --meta --meta ...`
+//
+// the filepath runs up to the first flag, so it can contain spaces,
+// and environment variables and a leading `~` are expanded in it
+//
+// existing paths are canonicalized, so the local ledger only ever has absolute paths
+//
+// a variable that isn't set is returned as the error
+fn parse_config_line(line: &str) -> Result<(String, Vec<&str>), String> {
+    let line = line.trim();
+    let mut path_end = line.len();
+    let mut after_whitespace = false;
+    for (i, c) in line.char_indices() {
+        if after_whitespace && line[i..].starts_with("--") {
+            path_end = i;
+            break;
+        }
+
+        after_whitespace = c.is_whitespace();
+    }
+
+    let filepath = crate::config::expand_path(line[..path_end].trim())?;
+    let filepath = filepath.canonicalize().unwrap_or(filepath);
+
+    Ok((
+        filepath.to_string_lossy().to_string(),
+        line[path_end..].split_whitespace().collect(),
+    ))
+}
+
+// glob patterns are matched against `/`-separated paths,
+// so windows separators are swapped before matching
+fn normalize_separators(path: &str) -> String {
+    if std::path::MAIN_SEPARATOR == '\\' {
+        path.replace('\\', "/")
+    } else {
+        path.to_string()
+    }
+}
+
+// globs picking out part of the ledger, like `--only ~/projects/foo/**`
+//
+// ledger paths are canonical, so the literal part of each glob before its first wildcard
+// is canonicalized too, for a glob through a symlink or a relative one to still match
+pub struct PathFilter {
+    patterns: Vec<glob::Pattern>,
+}
+
+impl PathFilter {
+    pub fn new(globs: &[String]) -> Result<Self, std::io::Error> {
+        let mut patterns = Vec::new();
+        for glob in globs.iter() {
+            let expanded = crate::config::expand_path(glob).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("bad glob {}: {}", glob, e),
+                )
+            })?;
+
+            let mut prefix = std::path::PathBuf::new();
+            let mut rest = std::path::PathBuf::new();
+            for component in expanded.components() {
+                let is_literal = !component
+                    .as_os_str()
+                    .to_string_lossy()
+                    .contains(['*', '?', '[']);
+                if is_literal && rest.as_os_str().is_empty() {
+                    prefix.push(component);
+                } else {
+                    rest.push(component);
+                }
+            }
+
+            // joining an empty `rest` would leave a trailing separator on a literal path
+            let prefix = prefix.canonicalize().unwrap_or(prefix);
+            let full = match rest.as_os_str().is_empty() {
+                true => prefix,
+                false => prefix.join(rest),
+            };
+            let full = normalize_separators(&full.to_string_lossy());
+            match glob::Pattern::new(&full) {
+                Ok(pattern) => patterns.push(pattern),
+                Err(e) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("bad glob {}: {}", glob, e),
+                    ))
+                }
+            }
+        }
+
+        Ok(Self { patterns })
+    }
+
+    pub fn matches(&self, filepath: &str) -> bool {
+        self.first_match(filepath).is_some()
+    }
+
+    // which of the globs, in the order they were given, is the first to match `filepath`
+    pub fn first_match(&self, filepath: &str) -> Option<usize> {
+        let normalized = normalize_separators(filepath);
+        self.patterns.iter().position(|p| p.matches(&normalized))
+    }
+}
+
+// config entry flag that isn't metadata, but controls how the entry is walked
+const FOLLOW_SYMLINKS_FLAG: &str = "--follow-symlinks";
+
+// config entry flag naming an acl the entry's files are kept behind, as in `--acl private`,
+// which their chunks carry as `acl:private` for the server to check requests against
+const ACL_FLAG: &str = "--acl";
+pub const ACL_META_PREFIX: &str = "acl:";
+
+// the names of the acls a chunk with `meta` is kept behind
+pub fn acls_of(meta: &std::collections::HashSet<String>) -> impl Iterator<Item = &str> {
+    meta.iter().filter_map(|m| m.strip_prefix(ACL_META_PREFIX))
+}
+
+// the meta of a config entry and whether it follows symlinks, from the flags after its path,
+// `None` if any of them isn't a flag or an `--acl` is missing its name
+fn parse_entry_flags(parts: &[&str]) -> Option<(std::collections::HashSet<String>, bool)> {
+    let mut meta = std::collections::HashSet::new();
+    let mut follow_symlinks = false;
+    let mut parts = parts.iter();
+    while let Some(part) = parts.next() {
+        if *part == FOLLOW_SYMLINKS_FLAG {
+            follow_symlinks = true;
+        } else if *part == ACL_FLAG {
+            let name = parts.next().filter(|n| !n.starts_with("--"))?;
+            meta.insert(format!("{}{}", ACL_META_PREFIX, name));
+        } else if let Some(tag) = part.strip_prefix("--") {
+            if !tag.is_empty() {
+                meta.insert(tag.to_string());
+            }
+        } else {
+            return None;
+        }
+    }
+
+    Some((meta, follow_symlinks))
+}
+
+// collects every file under `dir`
+//
+// symlinks are skipped unless `follow_symlinks` is set,
+// in which case directories are tracked by their canonical paths
+// so that link cycles are only ever walked once
+//
+// directories that can't be read are skipped, and land in `errors`
+fn walk_directory(
+    dir: &std::path::Path,
+    follow_symlinks: bool,
+    visited: &mut std::collections::HashSet<std::path::PathBuf>,
+    files: &mut Vec<std::path::PathBuf>,
+    errors: &mut Vec<(String, String)>,
+) {
+    match dir.canonicalize() {
+        Ok(canonical) => {
+            if !visited.insert(canonical) {
+                info!("skipping already visited directory {}", dir.display());
+                return;
+            }
+        }
+        Err(e) => {
+            error!("warning: failed to resolve {}: {}", dir.display(), e);
+            errors.push((dir.display().to_string(), e.to_string()));
+            return;
+        }
+    }
+
+    let mut entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .map(|e| e.path())
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            error!("warning: failed to read directory {}: {}", dir.display(), e);
+            errors.push((dir.display().to_string(), e.to_string()));
+            return;
+        }
+    };
+
+    entries.sort();
+
+    for path in entries {
+        let is_symlink = std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink());
+        if is_symlink && !follow_symlinks {
+            info!("skipping symlink {}", path.display());
             continue;
         }
 
-        let extension = parts[0].to_string();
-        let mut rule = IndexRule {
-            rule_type: IndexRuleType::Naive,
-            value: "".to_string(),
+        if path.is_dir() {
+            walk_directory(&path, follow_symlinks, visited, files, errors);
+        } else if path.is_file() {
+            files.push(path);
+        } else if is_symlink {
+            error!("warning: skipping broken symlink {}", path.display());
+        }
+    }
+}
+
+// the directory a glob starts from, its leading components up to the first with a wildcard
+fn glob_base(pattern: &str) -> std::path::PathBuf {
+    std::path::Path::new(pattern)
+        .components()
+        .take_while(|c| !c.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+        .collect()
+}
+
+// collects every file matching the glob `pattern`, under the same rules as `walk_directory`:
+// nothing in or under a symlink below the glob's base unless `follow_symlinks` is set
+//
+// glob descends into symlinked directories on a `**`, and loops on cycles,
+// so those patterns walk their base by hand and match what's found instead
+//
+// a bad pattern matches nothing, and lands in `errors`
+fn glob_files(
+    pattern: &str,
+    follow_symlinks: bool,
+    errors: &mut Vec<(String, String)>,
+) -> Vec<std::path::PathBuf> {
+    let compiled = match glob::Pattern::new(pattern) {
+        Ok(compiled) => compiled,
+        Err(e) => {
+            error!("warning: bad glob pattern {}: {}", pattern, e);
+            errors.push((pattern.to_string(), e.to_string()));
+            return Vec::new();
+        }
+    };
+
+    let base = glob_base(pattern);
+    if pattern.contains("**") {
+        // `*` doesn't match across directories, same as in `glob::glob`
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+
+        let root = match base.as_os_str().is_empty() {
+            true => std::path::Path::new("."),
+            false => base.as_path(),
         };
+        if !root.is_dir() {
+            return Vec::new();
+        }
 
-        let mut rules = Vec::new();
-        for part in parts.iter().skip(1) {
-            if part.starts_with("--") {
-                match part.to_lowercase().as_str() {
-                    "--code" => rule.rule_type = IndexRuleType::Code,
-                    "--split" => rule.rule_type = IndexRuleType::Split,
-                    "--maxlength" => rule.rule_type = IndexRuleType::MaxLength,
-                    "--minlength" => rule.rule_type = IndexRuleType::MinLength,
-                    "--alphanumeric" => rule.rule_type = IndexRuleType::Alphanumeric,
-                    _ => {
-                        error!("Ignoring unknown rule type: {}", part);
-                    }
+        let mut files = Vec::new();
+        walk_directory(
+            root,
+            follow_symlinks,
+            &mut std::collections::HashSet::new(),
+            &mut files,
+            errors,
+        );
+
+        return files
+            .into_iter()
+            .map(|f| match base.as_os_str().is_empty() {
+                true => f.strip_prefix(".").map_or(f.clone(), |f| f.to_path_buf()),
+                false => f,
+            })
+            .filter(|f| compiled.matches_path_with(f, options))
+            .collect();
+    }
+
+    // the pattern's already been checked, so glob can't fail on it
+    glob::glob(pattern)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|f| {
+            let linked = f
+                .ancestors()
+                .take_while(|a| *a != base)
+                .find(|a| std::fs::symlink_metadata(a).is_ok_and(|m| m.file_type().is_symlink()));
+
+            match linked {
+                Some(link) if !follow_symlinks => {
+                    info!("skipping {} under symlink {}", f.display(), link.display());
+                    false
                 }
-            } else {
-                rule.value = part.to_string();
-                rule.value = rule
-                    .value
-                    .replace("\"", "")
-                    .replace("\\n", "\n")
-                    .replace("\\t", "\t")
-                    .replace("\\r", "\r");
-
-                if !rule.rule_type.validate(&rule.value) {
-                    continue;
+                _ => true,
+            }
+        })
+        .collect()
+}
+
+// how many paths of each kind of change a sync reports
+const DIFF_SAMPLES: usize = 5;
+
+// what a ledger sync went through, on top of what it changed
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct SyncReport {
+    // config ledger lines that weren't comments or blank
+    pub entries_scanned: usize,
+    // files tracked in the new ledger
+    pub kept: usize,
+    pub ignored_by_gitignore: usize,
+    // config entries for paths that don't exist
+    pub missing_paths: Vec<String>,
+    // config lines with unset variables or arguments that aren't flags
+    pub malformed_lines: Vec<String>,
+    // paths that couldn't be read, with why, e.g. a directory without permission
+    //
+    // the rest of the sync goes on without them
+    pub errors: Vec<(String, String)>,
+    pub diff: LedgerDiff,
+}
+
+// what a ledger sync changed, or would have changed if it was refused
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct LedgerDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    // `(old, new)` paths of files that moved without changing,
+    // whose embeddings go with them instead of being made again
+    #[serde(default)]
+    pub renamed: Vec<(String, String)>,
+    // files that are still tracked, but under different meta tags
+    pub meta_changed: Vec<String>,
+    pub unchanged: usize,
+    // more of the ledger would have been removed than `ledger_removal_limit` allows,
+    // and the sync wasn't confirmed, so the ledger was left alone
+    pub refused: bool,
+}
+
+impl LedgerDiff {
+    // with `renames`, a removed file and an added one are taken for a rename when they're the only
+    // ones with their hash, and they have the same meta
+    //
+    // copies of the same contents can't be told apart, so they're left as they are
+    fn new(previous: &[LedgerEntry], current: &[LedgerEntry], renames: bool) -> Self {
+        let entries = previous
+            .iter()
+            .chain(current.iter())
+            .map(|e| (e.filepath.as_str(), e))
+            .collect::<HashMap<_, _>>();
+        let previous = previous
+            .iter()
+            .map(|e| (e.filepath.as_str(), &e.meta))
+            .collect::<HashMap<_, _>>();
+        let current_paths = current
+            .iter()
+            .map(|e| e.filepath.as_str())
+            .collect::<std::collections::HashSet<_>>();
+
+        let mut diff = Self::default();
+        for entry in current.iter() {
+            match previous.get(entry.filepath.as_str()) {
+                None => diff.added.push(entry.filepath.clone()),
+                Some(meta) if **meta != entry.meta => {
+                    diff.meta_changed.push(entry.filepath.clone())
                 }
+                Some(_) => diff.unchanged += 1,
+            }
+        }
 
-                rules.push(rule);
+        diff.removed = previous
+            .keys()
+            .filter(|filepath| !current_paths.contains(*filepath))
+            .map(|filepath| filepath.to_string())
+            .collect();
+
+        if renames {
+            let mut hashes: HashMap<&str, (Vec<&str>, Vec<&str>)> = HashMap::new();
+            for filepath in diff.removed.iter() {
+                hashes
+                    .entry(&entries[filepath.as_str()].hash)
+                    .or_default()
+                    .0
+                    .push(filepath);
+            }
+            for filepath in diff.added.iter() {
+                hashes
+                    .entry(&entries[filepath.as_str()].hash)
+                    .or_default()
+                    .1
+                    .push(filepath);
+            }
 
-                rule = IndexRule {
-                    rule_type: IndexRuleType::Naive,
-                    value: "".to_string(),
-                };
+            diff.renamed = hashes
+                .into_values()
+                .filter_map(|(removed, added)| match (&removed[..], &added[..]) {
+                    ([old], [new]) if entries[old].meta == entries[new].meta => {
+                        Some((old.to_string(), new.to_string()))
+                    }
+                    _ => None,
+                })
+                .collect();
+
+            let moved = diff
+                .renamed
+                .iter()
+                .flat_map(|(old, new)| [old.clone(), new.clone()])
+                .collect::<std::collections::HashSet<_>>();
+            diff.added.retain(|filepath| !moved.contains(filepath));
+            diff.removed.retain(|filepath| !moved.contains(filepath));
+        }
+
+        diff.added.sort();
+        diff.removed.sort();
+        diff.renamed.sort();
+        diff.meta_changed.sort();
+
+        diff
+    }
+
+    fn previous_len(&self) -> usize {
+        self.removed.len() + self.renamed.len() + self.meta_changed.len() + self.unchanged
+    }
+
+    // the fraction of the previous ledger that's being removed
+    fn removed_fraction(&self) -> f32 {
+        match self.previous_len() {
+            0 => 0.0,
+            n => self.removed.len() as f32 / n as f32,
+        }
+    }
+
+    fn summary(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "Ledger changes: {} added, {} removed, {} renamed, {} with new meta, {} unchanged",
+            self.added.len(),
+            self.removed.len(),
+            self.renamed.len(),
+            self.meta_changed.len(),
+            self.unchanged
+        )];
+
+        let renamed = self
+            .renamed
+            .iter()
+            .map(|(old, new)| format!("{} -> {}", old, new))
+            .collect::<Vec<_>>();
+        for (sign, paths) in [
+            ("+", &self.added),
+            ("-", &self.removed),
+            (">", &renamed),
+            ("~", &self.meta_changed),
+        ] {
+            for path in paths.iter().take(DIFF_SAMPLES) {
+                lines.push(format!("  {} {}", sign, path));
+            }
+
+            if paths.len() > DIFF_SAMPLES {
+                lines.push(format!(
+                    "  {} ... and {} more",
+                    sign,
+                    paths.len() - DIFF_SAMPLES
+                ));
             }
         }
 
-        rulesets.insert(extension, rules);
+        lines
     }
+}
 
-    Ok(rulesets)
+// the ledger as the last sync left it, for diffing against
+//
+// unlike `read_ledger`, entries for files that have since gone missing are kept,
+// since those are exactly the ones being removed
+fn read_previous_ledger() -> Vec<LedgerEntry> {
+    let paths = crate::config::PathResolver::current();
+    std::fs::read_to_string(crate::config::get_local_dir().join("ledger"))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| read_ledger_line(line, &paths))
+        .collect()
 }
 
-struct ConfigEntry {
-    pub filepath: String,
-    pub meta: std::collections::HashSet<String>,
+// drops the `removed` files from the ledger and adds entries for files
+// that were embedded outside of `sync_index`, replacing whatever the ledger already has for them
+pub fn update_entries(
+    removed: &std::collections::HashSet<String>,
+    entries: &[LedgerEntry],
+) -> Result<(), std::io::Error> {
+    let replaced = entries
+        .iter()
+        .map(|e| e.filepath.as_str())
+        .collect::<std::collections::HashSet<_>>();
+
+    let ledger_path = crate::config::get_local_dir().join("ledger");
+    let _lock = crate::lock::LedgerLock::acquire(&ledger_path)?;
+
+    let mut ledger = read_previous_ledger()
+        .into_iter()
+        .filter(|e| !replaced.contains(e.filepath.as_str()) && !removed.contains(&e.filepath))
+        .collect::<Vec<_>>();
+    ledger.extend(entries.iter().cloned());
+
+    let paths = crate::config::PathResolver::current();
+    let contents = ledger
+        .iter()
+        .map(|e| write_ledger_line(e, &paths) + "\n")
+        .collect::<String>();
+
+    crate::lock::write_atomic(&ledger_path, contents.as_bytes())?;
+    info!(
+        "recorded {} ledger entries, {} in the ledger",
+        entries.len(),
+        ledger.len()
+    );
+
+    Ok(())
+}
+
+// how many times an edit to the config ledger is made again
+// before giving up on whatever keeps changing it
+const LEDGER_EDIT_ATTEMPTS: usize = 5;
+
+// rewrites the config ledger with `edit`, which gets its contents and gives back new ones,
+// or `None` to leave it as it is
+//
+// the edit is made under the ledger's lock and renamed into place, so tools editing it at once
+// take turns and nothing ever reads half of it
+//
+// something that doesn't take the lock, like an editor, can still save over it mid-edit,
+// so its contents are hashed as they're read, and if they've changed by the time the edit
+// is written, it's made again on top of what's there now instead of writing over them
+fn edit_config_ledger(edit: impl Fn(&str) -> Option<String>) -> Result<bool, std::io::Error> {
+    let ledger_path = crate::config::get_config_dir().join("ledger");
+    let read = || match std::fs::read_to_string(&ledger_path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        read => read,
+    };
+
+    let _lock = crate::lock::LedgerLock::acquire(&ledger_path)?;
+    for _ in 0..LEDGER_EDIT_ATTEMPTS {
+        let contents = read()?;
+        let hash = hash_contents(contents.as_bytes());
+        let edited = match edit(&contents) {
+            Some(edited) => edited,
+            None => return Ok(false),
+        };
+
+        if hash_contents(read()?.as_bytes()) != hash {
+            info!(
+                "{} changed while it was being edited, merging",
+                ledger_path.display()
+            );
+            continue;
+        }
+
+        crate::lock::write_atomic(&ledger_path, edited.as_bytes())?;
+        return Ok(true);
+    }
+
+    Err(std::io::Error::other(format!(
+        "{} kept changing while it was being edited",
+        ledger_path.display()
+    )))
+}
+
+// the path of a config ledger line, `None` for comments, blank lines, and unset variables
+fn config_line_path(line: &str) -> Option<String> {
+    if line.trim_start().starts_with('#') || line.trim().is_empty() {
+        return None;
+    }
+
+    parse_config_line(line).ok().map(|(filepath, _)| filepath)
+}
+
+fn parse_new_entry(entry: &str) -> Result<String, std::io::Error> {
+    parse_config_line(entry)
+        .map(|(filepath, _)| filepath)
+        .map_err(|variable| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "can't add {:?} to the ledger: ${} isn't set",
+                    entry, variable
+                ),
+            )
+        })
+}
+
+// adds `entry`, a config ledger line like `~/notes --notes`, to the config ledger,
+// replacing any other lines for the same path
+//
+// returns whether the ledger changed, which it doesn't if `entry` is already in it
+pub fn add_entry(entry: &str) -> Result<bool, std::io::Error> {
+    let entry = entry.trim();
+    let filepath = parse_new_entry(entry)?;
+
+    let changed = edit_config_ledger(|contents| {
+        let same_path = |line: &&str| config_line_path(line).as_ref() == Some(&filepath);
+        if contents.lines().filter(same_path).eq([entry]) {
+            return None;
+        }
+
+        let mut lines = contents
+            .lines()
+            .filter(|line| !same_path(line))
+            .collect::<Vec<_>>();
+        lines.push(entry);
+
+        Some(lines.iter().map(|line| format!("{}\n", line)).collect())
+    })?;
+
+    if changed {
+        info!("added {} to the config ledger", entry);
+    }
+
+    Ok(changed)
+}
+
+// removes every line for `filepath` from the config ledger, leaving commented out ones
+//
+// returns whether there were any
+pub fn remove_entry(filepath: &str) -> Result<bool, std::io::Error> {
+    let filepath = parse_new_entry(filepath)?;
+
+    let changed = edit_config_ledger(|contents| {
+        let same_path = |line: &&str| config_line_path(line).as_ref() == Some(&filepath);
+        if !contents.lines().any(|line| same_path(&line)) {
+            return None;
+        }
+
+        Some(
+            contents
+                .lines()
+                .filter(|line| !same_path(line))
+                .map(|line| format!("{}\n", line))
+                .collect(),
+        )
+    })?;
+
+    if changed {
+        info!("removed {} from the config ledger", filepath);
+    }
+
+    Ok(changed)
 }
 
 // current functionality is that it uses .gitignore files
@@ -228,81 +1393,139 @@ struct ConfigEntry {
 // according to what's in `~/.config/dewey/ledger`
 //
 // files in the config ledger can be commented out with `#`
-pub fn sync_ledger_config() -> Result<(), Box<dyn std::error::Error>> {
-    let config_path = crate::config::get_config_dir();
-    let config_ledger_path = config_path.join("ledger");
+//
+// a sync that would drop more than `ledger_removal_limit` of the ledger
+// is refused unless `confirmed`, and the diff comes back marked `refused`
+//
+// entries and files that can't be read are skipped and listed in the report,
+// rather than stopping the sync
+//
+// `progress` gets the lines a sync has to say about itself as it goes, which are logged either way
+pub fn sync_ledger_config(
+    confirmed: bool,
+    progress: Option<&dyn Fn(&str)>,
+) -> Result<SyncReport, std::io::Error> {
+    let notify = |message: &str| {
+        if let Some(progress) = progress {
+            progress(message);
+        }
+    };
+    let say = |message: String| {
+        info!("{}", message);
+        notify(&message);
+    };
+    let warn = |message: String| {
+        error!("{}", message);
+        notify(&message);
+    };
 
-    let config_ledger = std::fs::read_to_string(&config_ledger_path)?;
-    let mut config_ledger = config_ledger
-        .lines()
-        .filter(|line| {
-            let parts: Vec<&str> = line.split_whitespace().filter(|s| !s.is_empty()).collect();
-            let cond = parts
-                .get(0)
-                .map_or(false, |path| std::path::Path::new(path).exists())
-                && parts.iter().skip(1).all(|&s| s.starts_with("--"));
+    let config_path = crate::config::get_config_dir();
+    let config_ledger_path = config_path.join("ledger");
 
-            if !cond {
-                error!("Ignoring malformed ledger entry: {}", line);
-            }
+    let mut report = SyncReport::default();
 
-            cond
-        })
-        .map(|line| {
-            let parts = line
-                .split_whitespace()
-                .filter(|s| !s.is_empty())
-                .collect::<Vec<&str>>();
-            let filepath = parts[0];
+    let mut config_ledger = Vec::new();
+    for line in std::fs::read_to_string(&config_ledger_path)?.lines() {
+        if line.trim_start().starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
 
-            let mut meta = std::collections::HashSet::new();
-            for part in parts.iter().skip(1) {
-                meta.insert(part.to_string());
+        report.entries_scanned += 1;
+        let (filepath, parts) = match parse_config_line(line) {
+            Ok(parsed) => parsed,
+            Err(variable) => {
+                warn(format!(
+                    "Ignoring ledger entry {:?}: ${} isn't set",
+                    line.trim(),
+                    variable
+                ));
+                report.malformed_lines.push(line.trim().to_string());
+                continue;
             }
+        };
 
-            ConfigEntry {
-                filepath: filepath.to_string(),
-                meta,
+        let (meta, follow_symlinks) = match parse_entry_flags(&parts) {
+            Some(flags) => flags,
+            None => {
+                error!(
+                    "Ignoring malformed ledger entry: {} {}",
+                    filepath,
+                    parts.join(" ")
+                );
+                report.malformed_lines.push(line.trim().to_string());
+                continue;
             }
-        })
-        .collect::<Vec<_>>();
+        };
+
+        // a glob is only missing if the directory it starts from is
+        if !std::path::Path::new(&filepath).exists() && !glob_base(&filepath).is_dir() {
+            error!("Ignoring ledger entry for missing path {}", filepath);
+            report.missing_paths.push(filepath);
+            continue;
+        }
+
+        config_ledger.push(ConfigEntry {
+            filepath,
+            meta,
+            follow_symlinks,
+        });
+    }
 
     let mut meta_index = 0;
     let mut config_entries = Vec::new();
 
+    // canonical paths of every file kept so far,
+    // so that files reachable through multiple links or entries are only tracked once
+    let mut seen = std::collections::HashSet::new();
+
     for config_entry in config_ledger.iter_mut() {
         let entry = &mut config_entry.filepath;
         if entry.starts_with("#") {
             continue;
         }
 
+        let follow_symlinks = config_entry.follow_symlinks;
         let path = std::path::Path::new(&entry);
-        if path.is_dir() && (!entry.ends_with("*") || !entry.ends_with("**")) {
-            if entry.ends_with("/") {
-                entry.push_str("**/*");
-            } else {
-                entry.push_str("/**/*");
-            }
-        }
 
-        info!("searching for files in {}", entry);
+        // directories are walked by hand rather than globbed,
+        // since glob descends into symlinked directories and loops on cycles
+        let directory = if path.is_dir() {
+            info!("searching for files in {}", entry);
+
+            let mut files = Vec::new();
+            walk_directory(
+                path,
+                follow_symlinks,
+                &mut std::collections::HashSet::new(),
+                &mut files,
+                &mut report.errors,
+            );
+
+            files
+        } else {
+            info!("searching for files matching {}", entry);
 
-        let directory = glob::glob(&entry)
-            .expect("Failed to read glob pattern")
-            .filter_map(Result::ok)
-            .collect::<Vec<_>>();
+            glob_files(entry, follow_symlinks, &mut report.errors)
+        };
 
         // there has to be a better way of dealing with go pkg directories than this
         let mut gitignore_globs = Vec::new();
         for file in directory.iter() {
             if file.ends_with(".gitignore") {
                 let gitignore = file.clone();
-                let file = std::fs::File::open(&gitignore)?;
-                let reader = std::io::BufReader::new(file);
+                let lines = match std::fs::read_to_string(&gitignore) {
+                    Ok(contents) => contents,
+                    Err(e) => {
+                        error!("warning: failed to read {}: {}", gitignore.display(), e);
+                        report
+                            .errors
+                            .push((gitignore.display().to_string(), e.to_string()));
+                        continue;
+                    }
+                };
 
                 let root = std::path::Path::new(&gitignore).parent().unwrap();
-                for line in reader.lines() {
-                    let line = line?;
+                for line in lines.lines() {
                     if line.starts_with("#") || line.is_empty() {
                         continue;
                     }
@@ -311,18 +1534,15 @@ pub fn sync_ledger_config() -> Result<(), Box<dyn std::error::Error>> {
                         continue;
                     }
 
-                    let line = match line.strip_prefix("/") {
-                        Some(line) => line,
-                        None => line.as_str(),
-                    };
+                    let line = line.strip_prefix("/").unwrap_or(line);
 
                     let full_path = root.join(line);
-                    let is_dir = match glob::glob(&full_path.to_string_lossy().to_string()) {
+                    let is_dir = match glob::glob(full_path.to_string_lossy().as_ref()) {
                         Ok(matches) => matches.peekable().any(|m| m.is_ok() && m.unwrap().is_dir()),
                         Err(_) => false,
                     } || full_path.is_dir();
 
-                    let full_path = full_path.to_string_lossy().to_string();
+                    let full_path = normalize_separators(&full_path.to_string_lossy());
 
                     let full_path = match is_dir {
                         true => {
@@ -338,86 +1558,146 @@ pub fn sync_ledger_config() -> Result<(), Box<dyn std::error::Error>> {
                     gitignore_globs.push(full_path);
                 }
 
-                gitignore_globs.push(root.join(".gitignore").to_string_lossy().to_string());
-                gitignore_globs.push(root.join(".git/**/*").to_string_lossy().to_string());
+                gitignore_globs.push(normalize_separators(
+                    &root.join(".gitignore").to_string_lossy(),
+                ));
+                gitignore_globs.push(normalize_separators(
+                    &root.join(".git").join("**").join("*").to_string_lossy(),
+                ));
             }
         }
 
+        // a bad line in a .gitignore just doesn't ignore anything
+        let gitignore_globs = gitignore_globs
+            .iter()
+            .filter_map(|glob| glob::Pattern::new(glob).ok())
+            .collect::<Vec<_>>();
+
         let mut kept = 0;
-        config_entries.extend(
-            directory
-                .iter()
-                .filter(|f| {
-                    for glob in gitignore_globs.iter() {
-                        if glob::Pattern::new(glob)
-                            .unwrap()
-                            .matches(f.to_str().unwrap())
-                        {
-                            return false;
-                        }
-                    }
+        for f in directory.iter() {
+            let normalized = normalize_separators(&f.to_string_lossy());
+            if gitignore_globs.iter().any(|glob| glob.matches(&normalized)) {
+                report.ignored_by_gitignore += 1;
+                continue;
+            }
 
-                    if f.is_file() {
-                        kept += 1;
-                        return true;
-                    } else {
-                        return false;
-                    }
-                })
-                .map(|f| {
-                    let filepath = f.to_string_lossy().to_string();
+            if !f.is_file() {
+                continue;
+            }
 
-                    (filepath, meta_index)
-                }),
-        );
+            let canonical = match f.canonicalize() {
+                Ok(canonical) => canonical,
+                Err(e) => {
+                    error!("warning: failed to resolve {}: {}", f.display(), e);
+                    report.errors.push((f.display().to_string(), e.to_string()));
+                    continue;
+                }
+            };
+
+            if !seen.insert(canonical.clone()) {
+                info!("skipping duplicate file {}", f.display());
+                continue;
+            }
+
+            kept += 1;
+            config_entries.push((canonical.to_string_lossy().to_string(), meta_index));
+        }
 
         meta_index += 1;
 
-        lprint!(info, "Kept {} files from {}", kept, entry);
+        say(format!("Kept {} files from {}", kept, entry));
     }
 
     info!("{} config entries", config_entries.len());
 
-    let new_ledger = config_entries
-        .into_iter()
-        .map(|s| LedgerEntry {
-            filepath: s.0.clone(),
-            hash: get_hash(&s.0).unwrap(),
-            meta: config_ledger[s.1].meta.clone(),
-        })
-        .collect::<Vec<_>>();
-
-    lprint!(info, "New ledger size: {}", new_ledger.len());
-
-    match std::fs::OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(crate::config::get_local_dir().join("ledger"))
-    {
-        Ok(mut file) => {
-            for entry in new_ledger {
-                let mut meta_string = String::new();
-                for (i, m) in entry.meta.iter().enumerate() {
-                    if i < entry.meta.len() - 1 {
-                        meta_string.push_str(&format!("{},", &m[2..]));
-                    } else {
-                        meta_string.push_str(&m[2..]);
-                    }
-                }
-
-                meta_string = meta_string.trim().to_string();
-                writeln!(file, "{} {} {}", entry.filepath, entry.hash, meta_string)?;
+    // a file that can't be read is left out of the ledger
+    let mut new_ledger = Vec::new();
+    for (filepath, meta_index) in config_entries {
+        match get_hash(&filepath) {
+            Ok(hash) => new_ledger.push(LedgerEntry {
+                filepath,
+                hash,
+                meta: config_ledger[meta_index].meta.clone(),
+            }),
+            Err(e) => {
+                error!("warning: failed to read {}: {}", filepath, e);
+                report.errors.push((filepath, e.to_string()));
             }
         }
-        Err(e) => {
-            error!("Failed to write ledger file: {}", e);
-        }
     }
 
-    Ok(())
+    report.kept = new_ledger.len();
+
+    // renames move embeddings around the store, whose lock goes ahead of the ledger's
+    // like it does everywhere else both are held, so it's only taken if a rename's expected
+    //
+    // without the index there are no embeddings to move, and renames are a removal and an addition
+    let renaming = cfg!(feature = "index")
+        && !LedgerDiff::new(&read_previous_ledger(), &new_ledger, true)
+            .renamed
+            .is_empty();
+    let _data_lock = match renaming {
+        true => Some(crate::lock::DataLock::acquire(
+            crate::lock::LockMode::Exclusive,
+            "sync_ledger",
+        )?),
+        false => None,
+    };
+
+    // held from the diff through the write, so nothing else edits the ledger in between
+    let ledger_path = crate::config::get_local_dir().join("ledger");
+    let _lock = crate::lock::LedgerLock::acquire(&ledger_path)?;
+
+    report.diff = LedgerDiff::new(&read_previous_ledger(), &new_ledger, renaming);
+    for line in report.diff.summary() {
+        say(line);
+    }
+
+    let limit = crate::config::get_ledger_removal_limit();
+    if report.diff.removed_fraction() > limit && !confirmed {
+        let diff = &report.diff;
+        let warning = format!(
+            "warning: the sync would remove {} of {} ledger entries (more than {:.0}%), rerun with --yes to go through with it",
+            diff.removed.len(),
+            diff.previous_len(),
+            limit * 100.0
+        );
+        warn(warning);
+
+        report.diff.refused = true;
+        return Ok(report);
+    }
+
+    // the store goes first, so a ledger that isn't written leaves the renames for the next sync
+    #[cfg(feature = "index")]
+    if !report.diff.renamed.is_empty() {
+        let moved = crate::dbio::rename_files(&report.diff.renamed)?;
+        say(format!(
+            "Moved {} embeddings to the new paths of {} renamed files",
+            moved,
+            report.diff.renamed.len()
+        ));
+    }
+
+    say(format!("New ledger size: {}", new_ledger.len()));
+
+    let paths = crate::config::PathResolver::current();
+    let contents = new_ledger
+        .iter()
+        .map(|entry| write_ledger_line(entry, &paths) + "\n")
+        .collect::<String>();
+    if let Err(e) = crate::lock::write_atomic(&ledger_path, contents.as_bytes()) {
+        error!("Failed to write ledger file: {}", e);
+    }
+
+    for (path, e) in report.errors.iter() {
+        notify(&format!("warning: skipped {}: {}", path, e));
+    }
+
+    Ok(report)
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "full"))]
 mod tests {
     use super::*;
     use crate::test_common::*;
@@ -446,12 +1726,102 @@ mod tests {
         assert!(rules.get("md").unwrap().len() == 1);
     }
 
+    #[test]
+    fn validate_rules_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(sync_ledger_config(true, None).is_ok());
+
+        // only rust files are in the test ledger
+        let diagnose = |rules: &str| {
+            write_file!(crate::config::get_config_dir().join("rules"), rules);
+            validate_rules()
+                .into_iter()
+                .map(|d| (d.line, d.token, d.issue))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            diagnose("* --minlength 0 --maxlength 512\nrs --naive --normalize lowercase"),
+            vec![]
+        );
+
+        assert_eq!(
+            diagnose("rs"),
+            vec![(1, "rs".to_string(), RuleIssue::Malformed)]
+        );
+
+        assert_eq!(
+            diagnose("* --minlength 0 --maxlenght 512"),
+            vec![(1, "--maxlenght".to_string(), RuleIssue::UnknownFlag)]
+        );
+
+        assert_eq!(
+            diagnose("* --minlength ten --alphanumeric maybe\nrs --split --code function 128"),
+            vec![
+                (1, "ten".to_string(), RuleIssue::InvalidValue),
+                (1, "maybe".to_string(), RuleIssue::InvalidValue),
+                (2, "--split".to_string(), RuleIssue::InvalidValue),
+                (2, "128".to_string(), RuleIssue::InvalidValue),
+            ]
+        );
+        assert_eq!(
+            diagnose("rs --model"),
+            vec![(1, "--model".to_string(), RuleIssue::InvalidValue)]
+        );
+
+        // the last splitting rule on a line is the one that applies,
+        // and the last line for an extension replaces the rest
+        assert_eq!(
+            diagnose("rs --naive --split \\n --maxlength 128\nrs --split \\n\n* --minlength 1"),
+            vec![
+                (1, "--naive".to_string(), RuleIssue::Unreachable),
+                (1, "--split".to_string(), RuleIssue::Unreachable),
+                (1, "rs".to_string(), RuleIssue::Unreachable),
+            ]
+        );
+
+        assert_eq!(
+            diagnose("md --split \\n\nrs --naive"),
+            vec![(1, "md".to_string(), RuleIssue::UnseenExtension)]
+        );
+
+        // the rules themselves are read the same as ever
+        write_file!(
+            crate::config::get_config_dir().join("rules"),
+            "* --minlength ten --maxlength 128\nrs --naive --split \\n"
+        );
+        let rules = get_indexing_rules().unwrap();
+        assert_eq!(
+            rules["*"].iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+            vec!["--maxlength \"128\""]
+        );
+        assert_eq!(
+            rules["rs"]
+                .iter()
+                .map(|r| r.to_string())
+                .collect::<Vec<_>>(),
+            vec!["--naive", "--split \"\\n\""]
+        );
+
+        std::fs::remove_file(crate::config::get_config_dir().join("rules")).unwrap();
+        let diagnostics = validate_rules();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, 0);
+        assert!(diagnostics[0].issue.is_error());
+        assert_eq!(
+            summarize_rules(&diagnostics),
+            "1 error and 0 warnings in the rules"
+        );
+    }
+
     #[test]
     fn read_ledger_test() {
         let _cleanup = Cleanup;
 
         assert!(setup().is_ok());
-        assert!(sync_ledger_config().is_ok());
+        assert!(sync_ledger_config(true, None).is_ok());
 
         let entries = read_ledger();
         assert!(entries.is_ok());
@@ -475,6 +1845,31 @@ mod tests {
         }
     }
 
+    // deleted files and lines that don't parse are skipped, and a missing ledger is an error
+    #[test]
+    fn read_ledger_missing_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(sync_ledger_config(true, None).is_ok());
+
+        let ledger = read_ledger().unwrap();
+        std::fs::remove_file(&ledger[0].filepath).unwrap();
+        let ledger_path = crate::config::get_local_dir().join("ledger");
+        let contents = std::fs::read_to_string(&ledger_path).unwrap();
+        write_file!(&ledger_path, format!("{}\nnot a ledger line\n", contents));
+
+        let entries = read_ledger().unwrap();
+        assert_eq!(entries.len(), ledger.len() - 1);
+        assert!(entries.iter().all(|e| e.filepath != ledger[0].filepath));
+
+        std::fs::remove_file(&ledger_path).unwrap();
+        assert_eq!(
+            read_ledger().unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+
     // checking whether the tracked files are added to a fresh ledger
     // and unchecked files are not added
     #[test]
@@ -482,7 +1877,10 @@ mod tests {
         let _cleanup = Cleanup;
 
         assert!(setup().is_ok());
-        assert!(sync_ledger_config().is_ok());
+        let report = sync_ledger_config(true, None).unwrap();
+        assert_eq!(report.kept, get_tracked_files().len());
+        assert_eq!(report.entries_scanned, 1);
+        assert!(report.errors.is_empty());
 
         let ledger_path = crate::config::get_local_dir().join("ledger");
 
@@ -492,7 +1890,7 @@ mod tests {
         let contents = ledger_result.unwrap();
         let lines = contents
             .split("\n")
-            .filter(|l| l.len() > 0)
+            .filter(|l| !l.is_empty())
             .collect::<Vec<&str>>();
 
         println!("local ledger contents:\n{}", contents);
@@ -508,15 +1906,174 @@ mod tests {
         }
     }
 
+    // dropping most of the ledger needs confirming, and leaves the ledger alone until it is
+    #[test]
+    fn sync_ledger_removal_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let diff = sync_ledger_config(false, None).unwrap().diff;
+        assert!(!diff.refused);
+        assert_eq!(diff.added.len(), get_tracked_files().len());
+        assert!(diff.removed.is_empty());
+
+        let ledger_path = crate::config::get_local_dir().join("ledger");
+        let before = std::fs::read_to_string(&ledger_path).unwrap();
+
+        let src = crate::config::get_home_dir().join("test_repo").join("src");
+        write_file!(
+            crate::config::get_config_dir().join("ledger"),
+            format!("{} --rust", src.display())
+        );
+
+        let diff = sync_ledger_config(false, None).unwrap().diff;
+        assert!(diff.refused);
+        assert_eq!(diff.removed.len(), 3);
+        assert!(diff.added.len() == 1 && diff.added[0].ends_with("f.md"));
+        assert!(diff.meta_changed.len() == 1 && diff.meta_changed[0].ends_with("e.rs"));
+        assert_eq!(std::fs::read_to_string(&ledger_path).unwrap(), before);
+
+        let diff = sync_ledger_config(true, None).unwrap().diff;
+        assert!(!diff.refused);
+        assert_eq!(diff.removed.len(), 3);
+        assert_eq!(read_ledger().unwrap().len(), 2);
+    }
+
+    // bad entries and unreadable directories end up in the report without stopping the sync
+    #[test]
+    fn sync_report_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let root = crate::config::get_home_dir();
+        let config_ledger_path = crate::config::get_config_dir().join("ledger");
+        let config_ledger = std::fs::read_to_string(&config_ledger_path).unwrap();
+
+        let locked = root.join("locked");
+        crate::create_dir!(&locked);
+        write_file!(locked.join("hidden.rs"), "fn hidden() {}");
+
+        write_file!(
+            &config_ledger_path,
+            format!(
+                "{}\n\n# a comment\n{} --gone\n$DEWEY_UNSET_TEST_VAR/x --rust\n{} --rust stray\n{} --locked",
+                config_ledger,
+                root.join("missing").display(),
+                root.join("test_repo").display(),
+                locked.display()
+            )
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+        }
+
+        let progress = std::cell::RefCell::new(Vec::new());
+        let report = sync_ledger_config(
+            true,
+            Some(&|line: &str| progress.borrow_mut().push(line.to_string())),
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let report = report.unwrap();
+        assert_eq!(report.entries_scanned, 5);
+        assert_eq!(
+            report.missing_paths,
+            vec![root.join("missing").to_string_lossy().to_string()]
+        );
+        assert_eq!(report.malformed_lines.len(), 2);
+        assert!(report.malformed_lines[0].contains("DEWEY_UNSET_TEST_VAR"));
+        assert!(report.malformed_lines[1].ends_with("--rust stray"));
+
+        // the untracked files, .gitignore, and .git/whatever
+        assert_eq!(report.ignored_by_gitignore, get_untracked_files().len() + 2);
+
+        // root can read the directory anyway
+        let tracked = get_tracked_files().len();
+        match report.errors.is_empty() {
+            true => assert_eq!(report.kept, tracked + 1),
+            false => {
+                assert_eq!(report.kept, tracked);
+                assert!(report.errors[0].0.ends_with("locked"));
+            }
+        }
+        assert_eq!(report.diff.added.len(), report.kept);
+        assert_eq!(read_ledger().unwrap().len(), report.kept);
+
+        let progress = progress.into_inner();
+        assert!(progress.iter().any(|l| l.starts_with("Ledger changes:")));
+        assert!(progress.iter().any(|l| l.contains("DEWEY_UNSET_TEST_VAR")));
+    }
+
+    // home-relative and variable entries land in the local ledger as absolute paths,
+    // and entries with unset variables are skipped
+    #[test]
+    fn sync_ledger_expansion_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(sync_ledger_config(true, None).is_ok());
+        let mut expected = read_local_ledger_paths();
+        expected.sort();
+
+        let flags = get_meta()
+            .iter()
+            .map(|m| format!("--{}", m))
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        let config_ledger_path = crate::config::get_config_dir().join("ledger");
+        write_file!(
+            &config_ledger_path,
+            format!(
+                "# tracked through home\n~/test_repo {}\n$DEWEY_UNSET_TEST_VAR/elsewhere --rust",
+                flags
+            )
+        );
+
+        let diff = sync_ledger_config(true, None).unwrap().diff;
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+
+        let mut paths = read_local_ledger_paths();
+        paths.sort();
+        assert_eq!(paths, expected);
+        assert!(paths.iter().all(|p| std::path::Path::new(p).is_absolute()));
+
+        std::env::set_var(
+            "DEWEY_TEST_REPO",
+            crate::config::get_home_dir().join("test_repo"),
+        );
+        write_file!(
+            &config_ledger_path,
+            format!("${{DEWEY_TEST_REPO}} {}", flags)
+        );
+
+        let diff = sync_ledger_config(true, None).unwrap().diff;
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+
+        let mut paths = read_local_ledger_paths();
+        paths.sort();
+        assert_eq!(paths, expected);
+    }
+
     // tests updating an existing ledger to include new files
     #[test]
     fn sync_ledger_config_update_new() {
         let _cleanup = Cleanup;
 
         assert!(setup().is_ok());
-        assert!(sync_ledger_config().is_ok());
+        assert!(sync_ledger_config(true, None).is_ok());
 
-        let new_files = vec![
+        let new_files = [
             crate::config::get_home_dir()
                 .join("test_repo")
                 .join("new_rs.rs"),
@@ -533,7 +2090,7 @@ mod tests {
             println!("wrote file {}", nf.to_str().unwrap());
         }
 
-        assert!(sync_ledger_config().is_ok());
+        assert!(sync_ledger_config(true, None).is_ok());
 
         let ledger_path = crate::config::get_local_dir().join("ledger");
 
@@ -543,7 +2100,7 @@ mod tests {
         let contents = ledger_result.unwrap();
         let lines = contents
             .split("\n")
-            .filter(|l| l.len() > 0)
+            .filter(|l| !l.is_empty())
             .collect::<Vec<&str>>();
 
         println!("local ledger contents:\n{}", contents);
@@ -564,4 +2121,362 @@ mod tests {
             assert!(tracked_files.iter().any(|f| items[0].contains(f)));
         }
     }
+
+    // meta read back from the ledger is written out the same way it was read,
+    // whichever writer rewrites it
+    #[test]
+    fn ledger_round_trip_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let target = crate::config::get_home_dir().join("test_repo");
+        let config_ledger_path = crate::config::get_config_dir().join("ledger");
+        let ledger_path = crate::config::get_local_dir().join("ledger");
+        write_file!(
+            &config_ledger_path,
+            format!("{} --rust --x --with,comma --back\\slash", target.display())
+        );
+
+        let expected = ["rust", "x", "with,comma", "back\\slash"]
+            .iter()
+            .map(|m| m.to_string())
+            .collect::<std::collections::HashSet<_>>();
+
+        assert!(sync_ledger_config(true, None).is_ok());
+        let first = std::fs::read_to_string(&ledger_path).unwrap();
+        let entries = read_ledger().unwrap();
+        assert_eq!(entries.len(), get_tracked_files().len());
+        assert!(entries.iter().all(|e| e.meta == expected));
+
+        assert!(update_entries(&std::collections::HashSet::new(), &[]).is_ok());
+        assert_eq!(std::fs::read_to_string(&ledger_path).unwrap(), first);
+
+        let diff = sync_ledger_config(true, None).unwrap().diff;
+        assert!(diff.meta_changed.is_empty() && diff.added.is_empty());
+        assert_eq!(diff.unchanged, entries.len());
+        assert_eq!(std::fs::read_to_string(&ledger_path).unwrap(), first);
+
+        // entries without any meta
+        write_file!(&config_ledger_path, format!("{}", target.display()));
+        let diff = sync_ledger_config(true, None).unwrap().diff;
+        assert_eq!(diff.meta_changed.len(), entries.len());
+
+        let contents = std::fs::read_to_string(&ledger_path).unwrap();
+        assert!(contents.lines().all(|l| l.ends_with(' ')));
+        assert!(read_ledger().unwrap().iter().all(|e| e.meta.is_empty()));
+
+        assert!(update_entries(&std::collections::HashSet::new(), &[]).is_ok());
+        assert_eq!(std::fs::read_to_string(&ledger_path).unwrap(), contents);
+
+        // an editor trimming the trailing space doesn't lose the entry
+        let trimmed = contents.lines().next().unwrap().trim_end();
+        let entry = parse_ledger_line(trimmed).unwrap();
+        assert!(entry.filepath.ends_with(".rs") && entry.meta.is_empty());
+        assert_eq!(format_ledger_line(&entry), contents.lines().next().unwrap());
+    }
+
+    // tools adding and removing entries at once don't lose each other's lines
+    #[test]
+    fn concurrent_entries_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let target = crate::config::get_home_dir().join("test_repo");
+        let config_ledger_path = crate::config::get_config_dir().join("ledger");
+        let original = std::fs::read_to_string(&config_ledger_path).unwrap();
+
+        let dirs = (0..8)
+            .map(|i| {
+                let dir = target.join(format!("tool{}", i));
+                crate::create_dir!(&dir);
+                write_file!(dir.join("notes.txt"), format!("notes from tool {}", i));
+                dir.canonicalize().unwrap().to_string_lossy().to_string()
+            })
+            .collect::<Vec<_>>();
+
+        let paths = crate::config::get_paths();
+        let run = |f: &(dyn Fn(usize, &str) + Sync)| {
+            std::thread::scope(|scope| {
+                for (i, dir) in dirs.iter().enumerate() {
+                    let paths = paths.clone();
+                    scope.spawn(move || paths.scope(|| f(i, dir)));
+                }
+            })
+        };
+
+        run(&|i, dir| assert!(add_entry(&format!("{} --tool{}", dir, i)).unwrap()));
+
+        let contents = std::fs::read_to_string(&config_ledger_path).unwrap();
+        assert!(contents.starts_with(original.trim_end()));
+        for (i, dir) in dirs.iter().enumerate() {
+            let line = format!("{} --tool{}", dir, i);
+            assert_eq!(contents.lines().filter(|l| *l == line).count(), 1);
+        }
+
+        // adding an entry again changes nothing, and new flags replace the old ones
+        assert!(!add_entry(&format!("{} --tool0", dirs[0])).unwrap());
+        assert!(add_entry(&format!("{} --renamed", dirs[0])).unwrap());
+        let contents = std::fs::read_to_string(&config_ledger_path).unwrap();
+        assert!(!contents.contains("--tool0"));
+        assert!(contents.contains(&format!("{} --renamed", dirs[0])));
+
+        assert!(sync_ledger_config(true, None).is_ok());
+        let ledger = read_ledger().unwrap();
+        assert!(dirs
+            .iter()
+            .all(|dir| ledger.iter().any(|e| e.filepath.starts_with(dir.as_str()))));
+
+        run(&|i, dir| {
+            if i % 2 == 0 {
+                assert!(remove_entry(dir).unwrap());
+            }
+        });
+
+        let contents = std::fs::read_to_string(&config_ledger_path).unwrap();
+        for (i, dir) in dirs.iter().enumerate() {
+            assert_eq!(contents.contains(dir.as_str()), i % 2 == 1);
+        }
+        assert!(!remove_entry(&dirs[0]).unwrap());
+
+        // an edit made underneath one in progress is merged rather than written over
+        let attempts = std::cell::Cell::new(0);
+        assert!(edit_config_ledger(|contents| {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() == 1 {
+                write_file!(
+                    &config_ledger_path,
+                    format!("{}# saved by an editor\n", contents)
+                );
+            }
+
+            Some(format!("{}# added by a tool\n", contents))
+        })
+        .unwrap());
+        assert_eq!(attempts.get(), 2);
+
+        let contents = std::fs::read_to_string(&config_ledger_path).unwrap();
+        assert!(contents.ends_with("# saved by an editor\n# added by a tool\n"));
+    }
+
+    // windows paths, spaces, and CRLF line endings through the ledger and config formats
+    #[test]
+    fn parse_paths_test() {
+        let entry = parse_ledger_line("C:\\Users\\Me My Docs\\notes.md abc123 rust,code\r\n");
+        assert!(entry.is_some());
+
+        let entry = entry.unwrap();
+        assert_eq!(entry.filepath, "C:\\Users\\Me My Docs\\notes.md");
+        assert_eq!(entry.hash, "abc123");
+        assert_eq!(
+            entry.meta,
+            std::collections::HashSet::from(["rust".to_string(), "code".to_string()])
+        );
+
+        let entry = parse_ledger_line("/home/me/my docs/a.rs abc123 \n").unwrap();
+        assert_eq!(entry.filepath, "/home/me/my docs/a.rs");
+        assert!(entry.meta.is_empty());
+
+        assert!(parse_ledger_line("abc123\n").is_none());
+
+        let (filepath, flags) =
+            parse_config_line("C:\\Users\\Me My Docs  --notes --follow-symlinks\r").unwrap();
+        assert_eq!(filepath, "C:\\Users\\Me My Docs");
+        assert_eq!(flags, vec!["--notes", "--follow-symlinks"]);
+
+        let (filepath, flags) = parse_config_line("/home/me/some--dir").unwrap();
+        assert_eq!(filepath, "/home/me/some--dir");
+        assert!(flags.is_empty());
+
+        let (filepath, _) = parse_config_line("~/notes --md").unwrap();
+        assert_eq!(
+            std::path::PathBuf::from(filepath),
+            crate::config::get_home_dir().join("notes")
+        );
+
+        let (filepath, _) = parse_config_line("~no_such_dewey_user/notes --md").unwrap();
+        assert_eq!(filepath, "~no_such_dewey_user/notes");
+
+        assert_eq!(
+            parse_config_line("${DEWEY_UNSET_TEST_VAR}/notes --md").unwrap_err(),
+            "DEWEY_UNSET_TEST_VAR"
+        );
+    }
+
+    #[test]
+    fn expand_config_test() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\nme:x:1000:1000::/home/me:/bin/sh\n";
+        assert_eq!(
+            crate::config::user_home(passwd, "me"),
+            Some(std::path::PathBuf::from("/home/me"))
+        );
+        assert_eq!(crate::config::user_home(passwd, "nobody"), None);
+
+        std::env::set_var("DEWEY_EXPAND_TEST_VAR", "/srv/notes");
+        assert_eq!(
+            crate::config::expand_vars("$DEWEY_EXPAND_TEST_VAR/a ${DEWEY_EXPAND_TEST_VAR}b $ $5"),
+            Ok("/srv/notes/a /srv/notesb $ $5".to_string())
+        );
+        assert_eq!(
+            crate::config::expand_vars("${DEWEY_UNSET_TEST_VAR}"),
+            Err("DEWEY_UNSET_TEST_VAR".to_string())
+        );
+    }
+
+    fn read_local_ledger_paths() -> Vec<String> {
+        std::fs::read_to_string(crate::config::get_local_dir().join("ledger"))
+            .unwrap()
+            .lines()
+            .map(|l| l.split(" ").next().unwrap().to_string())
+            .collect()
+    }
+
+    // symlinks are skipped by default, so neither the loop nor the outside file is tracked
+    #[cfg(unix)]
+    #[test]
+    fn sync_ledger_config_skip_symlinks() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let outside = setup_symlinks().unwrap();
+        assert!(sync_ledger_config(true, None).is_ok());
+
+        let paths = read_local_ledger_paths();
+        assert_eq!(paths.len(), get_tracked_files().len());
+        assert!(!paths
+            .iter()
+            .any(|p| p.contains("outside") || p.contains("loop")));
+        assert!(!paths.contains(&outside.to_string_lossy().to_string()));
+    }
+
+    // following symlinks pulls in the outside file under its canonical path,
+    // and walks the loop only once
+    #[cfg(unix)]
+    #[test]
+    fn sync_ledger_config_follow_symlinks() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let outside = setup_symlinks().unwrap();
+
+        let config_ledger_path = crate::config::get_config_dir().join("ledger");
+        let config_ledger = std::fs::read_to_string(&config_ledger_path).unwrap();
+        write_file!(
+            &config_ledger_path,
+            format!("{} --follow-symlinks", config_ledger)
+        );
+
+        assert!(sync_ledger_config(true, None).is_ok());
+
+        let paths = read_local_ledger_paths();
+        assert_eq!(paths.len(), get_tracked_files().len() + 1);
+        assert!(paths.contains(
+            &outside
+                .canonicalize()
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        ));
+        assert!(!paths.iter().any(|p| p.contains("loop")));
+
+        let ledger = read_ledger().unwrap();
+        assert!(ledger.iter().all(|e| !e.meta.contains("follow-symlinks")));
+    }
+
+    // a `**` glob goes through the loop at most once, and only takes what's under a symlink
+    // when it's following them, same as an entry for the directory
+    #[cfg(unix)]
+    #[test]
+    fn sync_ledger_config_glob_symlinks() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let outside = setup_symlinks().unwrap();
+        let outside = outside.canonicalize().unwrap();
+
+        let target = crate::config::get_home_dir().join("test_repo");
+        let pattern = format!("{}/**/*", target.display());
+        let through_loop = |files: &[std::path::PathBuf]| {
+            files
+                .iter()
+                .any(|f| f.components().any(|c| c.as_os_str() == "loop"))
+        };
+
+        let mut errors = Vec::new();
+        let files = glob_files(&pattern, false, &mut errors);
+        assert!(errors.is_empty());
+        assert!(!through_loop(&files));
+        assert!(!files.iter().any(|f| f.ends_with("outside.rs")));
+
+        let files = glob_files(&pattern, true, &mut errors);
+        assert!(errors.is_empty());
+        assert!(!through_loop(&files));
+        assert!(files.iter().any(|f| f.canonicalize().unwrap() == outside));
+
+        // without a `**` glob can't loop, but still only goes through the link when it's following them
+        let through = format!("{}/*/*.rs", target.display());
+        assert!(!through_loop(&glob_files(&through, false, &mut errors)));
+        assert!(through_loop(&glob_files(&through, true, &mut errors)));
+        assert!(errors.is_empty());
+
+        let config_ledger_path = crate::config::get_config_dir().join("ledger");
+        let config_ledger = std::fs::read_to_string(&config_ledger_path).unwrap();
+        let config_ledger = config_ledger.replacen(target.to_str().unwrap(), &pattern, 1);
+        write_file!(&config_ledger_path, &config_ledger);
+
+        assert!(sync_ledger_config(true, None).is_ok());
+        let paths = read_local_ledger_paths();
+        assert_eq!(paths.len(), get_tracked_files().len());
+        assert!(!paths.contains(&outside.to_string_lossy().to_string()));
+
+        write_file!(
+            &config_ledger_path,
+            format!("{} --follow-symlinks", config_ledger)
+        );
+
+        assert!(sync_ledger_config(true, None).is_ok());
+        let paths = read_local_ledger_paths();
+        assert_eq!(paths.len(), get_tracked_files().len() + 1);
+        assert!(paths.contains(&outside.to_string_lossy().to_string()));
+        assert!(!paths.iter().any(|p| p.contains("loop")));
+    }
+
+    // changing the rules for an extension should only mark that extension's files as stale
+    #[test]
+    fn rules_change_stale_files() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let notes = crate::config::get_home_dir()
+            .join("test_repo")
+            .join("notes.txt");
+        write_file!(&notes, "some notes");
+
+        assert!(sync_ledger_config(true, None).is_ok());
+
+        let ledger = read_ledger().unwrap();
+        assert!(write_rules_hashes(&ledger).is_ok());
+        assert!(get_stale_files().unwrap().is_empty());
+
+        let rules_path = crate::config::get_config_dir().join("rules");
+        let rules = std::fs::read_to_string(&rules_path).unwrap();
+        write_file!(
+            &rules_path,
+            rules.replace("rs --naive", "rs --maxlength 128")
+        );
+
+        let stale = get_stale_files().unwrap();
+        assert_eq!(stale.len(), get_tracked_files().len());
+        assert!(stale.iter().all(|e| e.filepath.ends_with(".rs")));
+
+        assert!(write_rules_hashes(&ledger).is_ok());
+        let rules = std::fs::read_to_string(&rules_path).unwrap();
+        write_file!(&rules_path, format!("{}\ntxt --split \\n", rules));
+
+        let stale = get_stale_files().unwrap();
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].filepath, notes.to_string_lossy());
+    }
 }
@@ -1,211 +1,123 @@
-use std::io::{Read, Write};
-
-use crate::hnsw::{Filter, Query, HNSW};
-use crate::logger::Logger;
-use crate::message::{DeweyResponse, DeweyResponseItem, RequestPayload};
-use crate::openai::{embed, EmbeddingSource};
+use crate::message::{Granularity, GroupBy, GroupScore};
 
+#[cfg(feature = "full")]
+pub mod analysis;
+// the private modules are mostly there for the engine, which only comes with `full`
+#[cfg(feature = "index")]
+#[cfg_attr(not(feature = "full"), allow(dead_code))]
 mod cache;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(any(feature = "index", feature = "embed"))]
 pub mod config;
+#[cfg(feature = "index")]
 pub mod dbio;
+#[cfg(any(feature = "index", feature = "embed"))]
+#[cfg_attr(not(feature = "full"), allow(dead_code))]
+mod embedding;
+#[cfg(feature = "full")]
+mod engine;
+#[cfg(feature = "full")]
+pub mod export;
+#[cfg(feature = "index")]
 pub mod hnsw;
+#[cfg(feature = "full")]
+pub mod housekeeping;
+#[cfg(any(feature = "embed", all(feature = "otel", feature = "index")))]
+mod http;
+#[cfg(feature = "index")]
+pub mod journal;
+#[cfg(any(feature = "index", feature = "embed"))]
 pub mod ledger;
+#[cfg(any(feature = "index", feature = "embed"))]
+pub mod lock;
 pub mod logger;
+#[cfg(feature = "full")]
+pub mod maintenance;
 pub mod message;
+#[cfg(feature = "embed")]
+#[cfg_attr(not(feature = "full"), allow(dead_code))]
 mod openai;
+#[cfg(feature = "embed")]
+#[cfg_attr(not(feature = "full"), allow(dead_code))]
 mod parsing;
+#[cfg(feature = "full")]
+pub mod selftest;
 pub mod serialization;
+#[cfg(feature = "full")]
+pub mod sync;
+#[cfg(feature = "full")]
 pub mod test_common;
-
-// all server operations should go through this arc-mutexed state
-// this is needed for thread safety with the addition of db-altering operations
-pub struct ServerState {
-    index: hnsw::HNSW,
+#[cfg(any(feature = "index", feature = "embed"))]
+pub mod trace;
+pub mod types;
+pub mod version;
+
+#[cfg(feature = "client")]
+pub use client::DeweyClient;
+#[cfg(feature = "full")]
+pub use engine::{
+    error_response, Dewey, ServerState, DEFAULT_DEDUPE_THRESHOLD, SNAPSHOT_COLLECTION,
+};
+
+// everything about a query besides its text
+pub struct SearchOptions {
+    pub k: usize,
+    // how many results are skipped before the `k` that are returned
+    pub offset: usize,
+    // in `hnsw::Filter`'s syntax
+    pub filters: Vec<String>,
+    // skips the embeddings of file paths, matching only on file contents
+    pub exclude_paths: bool,
+    pub group_by: Option<GroupBy>,
+    pub group_score: GroupScore,
+    // keeps a copy of the query in the queries directory
+    pub save_query: bool,
+    // ranks boilerplate chunks like any other, without the penalty
+    pub include_boilerplate: bool,
+    // skips the server's cache of recent queries, though the results still replace what's in it
+    pub no_cache: bool,
+    // returns a `hnsw::QueryTrace` of the search with the results
+    pub debug: bool,
+    // the model whose index is searched, the configured one if it's `None`
+    //
+    // every model's embeddings are kept apart, so a query only ever sees one of them
+    pub model: Option<String>,
+    // how much of the index is searched, along with how many candidates it's searched for
+    pub mode: types::SearchMode,
+    // whether the results are chunks or whole files
+    pub granularity: Granularity,
+    // file paths or globs the results are limited to, every file if it's `None`
+    pub paths: Option<Vec<String>>,
+    // the collection searched, the live index if it's `None`
+    pub collection: Option<String>,
+    // drops results more similar than this to one ranked ahead of them, keeping every one if it's `None`
+    pub dedupe_threshold: Option<f32>,
+    // the acls results can be kept behind, leaving out any behind another,
+    // or every result whatever its acls if it's `None`, as for local queries
+    pub acls: Option<std::collections::BTreeSet<String>>,
 }
 
-impl ServerState {
-    pub fn new() -> Result<Self, std::io::Error> {
-        Ok(Self {
-            index: HNSW::new(false)?,
-        })
-    }
-
-    pub fn query(&self, payload: RequestPayload) -> Result<String, std::io::Error> {
-        let (query, filters, k) = match payload {
-            RequestPayload::Query { query, filters, k } => (query, filters, k),
-            _ => {
-                error!("malformed query request: {:?}", payload);
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "malformed query request",
-                ));
-            }
-        };
-
-        info!("payload unpacked");
-
-        let timestamp = chrono::Utc::now().timestamp_micros();
-        let path = config::get_local_dir()
-            .join("queries")
-            .join(timestamp.to_string());
-        match std::fs::write(path.clone(), query) {
-            Ok(_) => {
-                info!("Wrote query to {}", path.to_string_lossy());
-            }
-            Err(e) => {
-                error!(
-                    "error writing query to file {}: {}",
-                    path.to_string_lossy(),
-                    e
-                );
-                return Err(e);
-            }
-        };
-
-        let embedding = match embed(&EmbeddingSource {
-            filepath: path.to_string_lossy().to_string(),
-            meta: std::collections::HashSet::new(),
-            subset: None,
-        }) {
-            Ok(e) => e,
-            Err(e) => {
-                error!("Failed to create embedding: {}", e);
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
-            }
-        };
-
-        info!("embedding created");
-
-        let filters = filters
-            .iter()
-            .map(|f| Filter::from_string(&f.to_string()).unwrap())
-            .collect::<Vec<Filter>>();
-
-        let query = Query { embedding, filters };
-
-        let mut index_results = Vec::new();
-        let result = self.index.query(&query, k, 200);
-
-        index_results.extend(result.iter().map(|p| DeweyResponseItem {
-            filepath: p.0.source_file.filepath.clone(),
-            subset: match p.0.source_file.subset {
-                Some(s) => s,
-                None => (0, 0),
-            },
-        }));
-
-        let response = DeweyResponse {
-            results: index_results,
-        };
-
-        let response = match serde_json::to_string(&response) {
-            Ok(serialized_response) => serialized_response,
-            Err(e) => {
-                error!("Failed to serialize response: {}", e);
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
-            }
-        };
-
-        Ok(response)
-    }
-
-    // this returns an empty json object {} on success
-    // or an object with just an `error` key on error
-    pub fn reindex(&mut self, payload: RequestPayload) -> Result<String, std::io::Error> {
-        let filepath = match payload {
-            RequestPayload::Edit { filepath } => filepath,
-            _ => {
-                error!("malformed edit request: {:?}", payload);
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "malformed edit request",
-                ));
-            }
-        };
-
-        let response = match crate::dbio::update_file_embeddings(&filepath, &mut self.index) {
-            Ok(_) => "{}".to_string(),
-            Err(e) => format!("{{error: {}}}", e),
-        };
-
-        Ok(response)
-    }
-}
-
-pub struct DeweyClient {
-    pub address: String,
-    pub port: u32,
-}
-
-impl DeweyClient {
-    pub fn new(address: String, port: u32) -> Self {
-        Self { address, port }
-    }
-
-    fn send(
-        &self,
-        message: message::DeweyRequest,
-    ) -> Result<message::DeweyResponse, std::io::Error> {
-        let destination = format!("{}:{}", self.address, self.port);
-        let mut stream = std::net::TcpStream::connect(destination.clone())?;
-
-        let message = serde_json::to_string(&message)?;
-        let mut bytes = Vec::new();
-        bytes.extend((message.len() as u32).to_be_bytes());
-        bytes.extend_from_slice(message.as_bytes());
-
-        match stream.write(&bytes) {
-            Ok(_) => {
-                stream.flush().unwrap();
-            }
-            Err(e) => {
-                error!("Failed to write response: {}", e);
-                return Err(e);
-            }
-        };
-
-        let mut length_bytes = [0u8; 4];
-        stream.read_exact(&mut length_bytes)?;
-        let length = u32::from_be_bytes(length_bytes) as usize;
-
-        let mut buffer = vec![0u8; length];
-        stream.read_exact(&mut buffer)?;
-        let buffer = String::from_utf8_lossy(&buffer);
-
-        match serde_json::from_str(&buffer) {
-            Ok(resp) => Ok(resp),
-            Err(e) => {
-                error!("Failed to parse response: {}", e);
-                error!("buffer: {:?}", buffer);
-                return Err(e.into());
-            }
+impl SearchOptions {
+    pub fn new(k: usize) -> Self {
+        Self {
+            k,
+            offset: 0,
+            filters: Vec::new(),
+            exclude_paths: false,
+            group_by: None,
+            group_score: GroupScore::Max,
+            save_query: true,
+            include_boilerplate: false,
+            no_cache: false,
+            debug: false,
+            model: None,
+            mode: types::SearchMode::Balanced,
+            granularity: Granularity::Chunk,
+            paths: None,
+            collection: None,
+            dedupe_threshold: None,
+            acls: None,
         }
     }
-
-    pub fn query(
-        &self,
-        request: String,
-        k: usize,
-        filters: Vec<String>,
-    ) -> Result<message::DeweyResponse, std::io::Error> {
-        let message = message::DeweyRequest {
-            message_type: "query".to_string(),
-            payload: message::RequestPayload::Query {
-                query: request,
-                k,
-                filters,
-            },
-        };
-
-        self.send(message)
-    }
-
-    pub fn reindex(&self, filepath: String) -> Result<message::DeweyResponse, std::io::Error> {
-        let message = message::DeweyRequest {
-            message_type: "edit".to_string(),
-            payload: message::RequestPayload::Edit { filepath },
-        };
-
-        self.send(message)
-    }
 }
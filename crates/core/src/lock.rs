@@ -0,0 +1,223 @@
+use std::io::{Read, Seek, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::logger::Logger;
+use crate::{error, info};
+
+// advisory lock over the data directory, kept in $DATA_DIR/.lock
+//
+// operations that modify the data directory hold it exclusively,
+// while readers hold it shared as they load blocks and the index
+//
+// the lock is released when the `DataLock` is dropped
+pub struct DataLock {
+    // the lock belongs to the open file and goes away with it
+    _file: std::fs::File,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+// whether a held lock is waited on or treated as an error
+static WAIT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_wait(wait: bool) {
+    WAIT.store(wait, Ordering::Relaxed);
+}
+
+pub fn get_lock_path() -> std::path::PathBuf {
+    crate::config::get_paths().lock_path()
+}
+
+impl DataLock {
+    // `operation` is recorded alongside the pid as the holder of the lock,
+    // so that anyone blocked on it can say who they're waiting on
+    //
+    // snapshots are only ever read, so nothing gets to hold one exclusively
+    pub fn acquire(mode: LockMode, operation: &str) -> Result<Self, std::io::Error> {
+        if let (LockMode::Exclusive, Some(snapshot)) = (mode, crate::config::get_paths().snapshot) {
+            error!("{} refused, snapshot {} is read-only", operation, snapshot);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("snapshot {} is read-only", snapshot),
+            ));
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(get_lock_path())?;
+
+        let try_lock = match mode {
+            LockMode::Shared => file.try_lock_shared(),
+            LockMode::Exclusive => file.try_lock(),
+        };
+
+        match try_lock {
+            Ok(()) => {}
+            Err(std::fs::TryLockError::WouldBlock) => {
+                let holder = read_holder(&mut file);
+                if !WAIT.load(Ordering::Relaxed) {
+                    error!("{} blocked, data directory locked by {}", operation, holder);
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::WouldBlock,
+                        format!(
+                            "data directory is locked by {}, rerun with --wait to wait for it",
+                            holder
+                        ),
+                    ));
+                }
+
+                crate::lprint!(
+                    info,
+                    "Waiting on the data directory lock held by {}",
+                    holder
+                );
+                match mode {
+                    LockMode::Shared => file.lock_shared()?,
+                    LockMode::Exclusive => file.lock()?,
+                }
+            }
+            Err(std::fs::TryLockError::Error(e)) => {
+                error!("failed to lock {}: {}", get_lock_path().display(), e);
+                return Err(e);
+            }
+        }
+
+        // several readers can share the lock, and the last one in is the one recorded
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{} {}", std::process::id(), operation)?;
+
+        info!("acquired {:?} data directory lock for {}", mode, operation);
+
+        Ok(Self { _file: file })
+    }
+}
+
+// the `pid operation` of whoever last took the lock
+fn read_holder(file: &mut std::fs::File) -> String {
+    let mut contents = String::new();
+    if file.rewind().is_err() || file.read_to_string(&mut contents).is_err() {
+        return "an unknown process".to_string();
+    }
+
+    match contents.trim().split_once(' ') {
+        Some((pid, operation)) => format!("pid {} ({})", pid, operation),
+        None => "an unknown process".to_string(),
+    }
+}
+
+// advisory lock over one of the ledgers, kept next to it in `.<name>.lock`
+//
+// it's held across a read, edit, and rewrite of the ledger, so that tools editing it at once
+// take turns instead of each writing over the other's lines
+//
+// ledger edits are quick, so it's always waited on
+pub struct LedgerLock {
+    _file: std::fs::File,
+}
+
+impl LedgerLock {
+    pub fn acquire(ledger_path: &std::path::Path) -> Result<Self, std::io::Error> {
+        let mut name = std::ffi::OsString::from(".");
+        name.push(ledger_path.file_name().unwrap_or_default());
+        name.push(".lock");
+
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(ledger_path.with_file_name(name))?;
+        file.lock()?;
+
+        Ok(Self { _file: file })
+    }
+}
+
+// writes to a sibling temp file and renames it over `path`
+//
+// data files are never modified in place, which keeps readers from seeing partial writes
+// and keeps hard-linked snapshots from changing underneath us
+pub fn write_atomic(path: &std::path::Path, bytes: &[u8]) -> Result<(), std::io::Error> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&temp_path)?;
+
+    file.write_all(bytes)?;
+    file.sync_all()?;
+
+    std::fs::rename(&temp_path, path)
+}
+
+#[cfg(all(test, feature = "full"))]
+mod tests {
+    use super::*;
+    use crate::test_common::*;
+
+    #[test]
+    fn lock_contention_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+
+        // readers share the lock
+        let first = DataLock::acquire(LockMode::Shared, "reader").unwrap();
+        let second = DataLock::acquire(LockMode::Shared, "reader");
+        assert!(second.is_ok());
+        drop((first, second));
+
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let holder = std::thread::spawn(move || {
+            let _lock = DataLock::acquire(LockMode::Exclusive, "holder").unwrap();
+            locked_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+
+        locked_rx.recv().unwrap();
+
+        let start = std::time::Instant::now();
+        let error = crate::sync::sync_index(
+            true,
+            false,
+            false,
+            None,
+            crate::sync::OverQuota::Stop,
+            false,
+        )
+        .unwrap_err();
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+        assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+        assert!(error
+            .to_string()
+            .contains(&format!("pid {} (holder)", std::process::id())));
+
+        assert!(DataLock::acquire(LockMode::Shared, "reader").is_err());
+
+        release_tx.send(()).unwrap();
+        holder.join().unwrap();
+
+        assert!(crate::sync::sync_index(
+            true,
+            false,
+            false,
+            None,
+            crate::sync::OverQuota::Stop,
+            false
+        )
+        .is_ok());
+    }
+}
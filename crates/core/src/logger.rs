@@ -1,66 +1,151 @@
 use std::io::Write;
-use std::sync::Once;
+use std::sync::{Mutex, PoisonError};
 
+// a log file that can't be opened, e.g. on a read-only filesystem, leaves the logger on stderr
 pub struct Logger {
-    file: std::fs::File,
+    file: Option<std::fs::File>,
+    path: std::path::PathBuf,
 }
 
-static mut INSTANCE: Option<Logger> = None;
-static INIT: Once = Once::new();
+// which part of dewey is logging, so the binaries can keep their logs apart
+//
+// the library shares the plain `name.log` with anything that doesn't pick a target
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogTarget {
+    Library,
+    Cli,
+    Server,
+}
+
+impl LogTarget {
+    pub fn file_name(&self, name: &str) -> String {
+        match self {
+            LogTarget::Library => format!("{}.log", name),
+            LogTarget::Cli => format!("{}-cli.log", name),
+            LogTarget::Server => format!("{}-server.log", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    pub level: String,
+    pub message: String,
+}
+
+// every write goes through the lock, so lines from different threads never interleave
+static INSTANCE: Mutex<Option<Logger>> = Mutex::new(None);
+
+// everything logged in this process since the last `take_captured`
+#[cfg(any(test, feature = "regression"))]
+static CAPTURED: Mutex<Vec<LogRecord>> = Mutex::new(Vec::new());
+
+const LEVELS: [&str; 2] = ["INFO", "ERROR"];
 
 impl Logger {
-    pub fn init(filename: String) -> &'static Logger {
-        unsafe {
-            INIT.call_once(|| {
-                INSTANCE = Some(Logger {
-                    file: std::fs::OpenOptions::new()
-                        .create(true)
-                        .append(true)
-                        .open(filename)
-                        .expect("Failed to open log file"),
-                });
+    // outside of test builds the first call picks the file for the rest of the process,
+    // while tests can point the logger somewhere else as often as they like
+    pub fn init(logs_dir: &std::path::Path, name: &str, target: LogTarget) {
+        let mut instance = INSTANCE.lock().unwrap_or_else(PoisonError::into_inner);
+        if instance.is_some() && !(cfg!(test) || cfg!(feature = "regression")) {
+            return;
+        }
+
+        let path = logs_dir.join(target.file_name(name));
+        let file = match std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+        {
+            Ok(file) => Some(file),
+            Err(e) => {
+                eprintln!(
+                    "couldn't open {}, logging to stderr instead: {}",
+                    path.display(),
+                    e
+                );
+                None
+            }
+        };
+
+        *instance = Some(Logger { file, path });
+    }
+
+    fn write(level: &str, message: String) {
+        let line = format!("{} [{}]: {}\n", chrono::Local::now(), level, message);
+
+        #[cfg(any(test, feature = "regression"))]
+        CAPTURED
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(LogRecord {
+                level: level.to_string(),
+                message,
             });
 
-            INSTANCE.as_ref().unwrap()
+        // anything logged before `init`, or that the file won't take, still shows up somewhere
+        let mut instance = INSTANCE.lock().unwrap_or_else(PoisonError::into_inner);
+        let written = match instance.as_mut().and_then(|i| i.file.as_mut()) {
+            Some(file) => file.write_all(line.as_bytes()).is_ok(),
+            None => false,
+        };
+
+        if !written {
+            let _ = std::io::stderr().write_all(line.as_bytes());
         }
     }
 
+    // the file this process is logging to, if the logger's been set up with one
+    pub fn current_path() -> Option<std::path::PathBuf> {
+        INSTANCE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .filter(|instance| instance.file.is_some())
+            .map(|instance| instance.path.clone())
+    }
+
     #[allow(dead_code)]
     pub fn info(message: String) {
-        unsafe {
-            if INSTANCE.is_none() {
-                panic!("Logger not initialized");
-            }
-
-            let mut file = INSTANCE
-                .as_ref()
-                .unwrap()
-                .file
-                .try_clone()
-                .expect("Failed to clone file");
-
-            let message = format!("{} [INFO]: {}", chrono::Local::now(), message);
-            writeln!(file, "{}", message).expect("Failed to write to log file");
-        }
+        Self::write("INFO", message);
     }
 
     pub fn error(message: String) {
-        unsafe {
-            if INSTANCE.is_none() {
-                panic!("Logger not initialized");
-            }
+        Self::write("ERROR", message);
+    }
 
-            let mut file = INSTANCE
-                .as_ref()
-                .unwrap()
-                .file
-                .try_clone()
-                .expect("Failed to clone file");
+    #[cfg(any(test, feature = "regression"))]
+    pub fn take_captured() -> Vec<LogRecord> {
+        std::mem::take(&mut *CAPTURED.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
 
-            let message = format!("{} [ERROR]: {}", chrono::Local::now(), message);
-            writeln!(file, "{}", message).expect("Failed to write to log file");
+// the records of a log file, for reading what another process logged
+//
+// lines that don't start a record belong to the message before them
+pub fn parse_records(contents: &str) -> Vec<LogRecord> {
+    let mut records: Vec<LogRecord> = Vec::new();
+    for line in contents.lines() {
+        let start = LEVELS.iter().find_map(|level| {
+            let marker = format!(" [{}]: ", level);
+            line.find(&marker)
+                .map(|i| (level, line[i + marker.len()..].to_string()))
+        });
+
+        match (start, records.last_mut()) {
+            (Some((level, message)), _) => records.push(LogRecord {
+                level: level.to_string(),
+                message,
+            }),
+            (None, Some(record)) => {
+                record.message.push('\n');
+                record.message.push_str(line);
+            }
+            (None, None) => {}
         }
     }
+
+    records
 }
 
 #[macro_export]
@@ -84,3 +169,111 @@ macro_rules! lprint {
         Logger::$method(format!($($arg)*));
     }
 }
+
+#[cfg(all(test, feature = "full"))]
+mod tests {
+    use super::*;
+    use crate::test_common::*;
+
+    // every record from every thread is captured and written whole
+    #[test]
+    fn concurrent_logging_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let logs_dir = crate::config::get_home_dir();
+        Logger::init(&logs_dir, "concurrent", LogTarget::Server);
+
+        let threads = 16;
+        let per_thread = 200;
+        std::thread::scope(|scope| {
+            for t in 0..threads {
+                scope.spawn(move || {
+                    for i in 0..per_thread {
+                        crate::info!("concurrent_logging_test {} {} {}", t, i, "x".repeat(64));
+                    }
+                });
+            }
+        });
+
+        let captured = Logger::take_captured()
+            .into_iter()
+            .filter(|r| r.message.starts_with("concurrent_logging_test "))
+            .collect::<Vec<_>>();
+        assert_eq!(captured.len(), threads * per_thread);
+        assert!(captured.iter().all(|r| r.level == "INFO"));
+
+        for t in 0..threads {
+            let prefix = format!("concurrent_logging_test {} ", t);
+            let ours = captured
+                .iter()
+                .filter(|r| r.message.starts_with(&prefix))
+                .map(|r| {
+                    r.message
+                        .split(' ')
+                        .nth(2)
+                        .unwrap()
+                        .parse::<usize>()
+                        .unwrap()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(ours, (0..per_thread).collect::<Vec<_>>());
+        }
+
+        let contents =
+            std::fs::read_to_string(logs_dir.join(LogTarget::Server.file_name("concurrent")))
+                .unwrap();
+        let written = parse_records(&contents)
+            .into_iter()
+            .filter(|r| r.message.starts_with("concurrent_logging_test "))
+            .collect::<Vec<_>>();
+        assert_eq!(written.len(), threads * per_thread);
+        assert!(written
+            .iter()
+            .all(|r| r.message.ends_with(&"x".repeat(64)) && !r.message.contains('\n')));
+    }
+
+    #[test]
+    fn parse_records_test() {
+        let contents = "2024-01-01 10:00:00.000 +00:00 [INFO]: first\n\
+                        2024-01-01 10:00:01.000 +00:00 [ERROR]: second\n\
+                        continued\n";
+
+        assert_eq!(
+            parse_records(contents),
+            vec![
+                LogRecord {
+                    level: "INFO".to_string(),
+                    message: "first".to_string(),
+                },
+                LogRecord {
+                    level: "ERROR".to_string(),
+                    message: "second\ncontinued".to_string(),
+                },
+            ]
+        );
+    }
+
+    // a log directory that can't be written to leaves the logger on stderr instead of panicking
+    #[test]
+    fn unwritable_log_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let missing = crate::config::get_home_dir().join("missing").join("logs");
+        Logger::init(&missing, "unwritable", LogTarget::Server);
+        assert_eq!(Logger::current_path(), None);
+
+        crate::info!("unwritable_log_test");
+        assert!(Logger::take_captured()
+            .iter()
+            .any(|r| r.message == "unwritable_log_test"));
+        assert!(!missing.exists());
+
+        // setup picks the log file back up
+        assert!(setup().is_ok());
+        assert!(Logger::current_path().is_some());
+    }
+}
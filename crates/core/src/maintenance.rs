@@ -0,0 +1,191 @@
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::sync::{Arc, RwLock};
+
+use chrono::Timelike;
+
+use crate::config;
+use crate::housekeeping;
+use crate::logger::Logger;
+use crate::message::MaintenanceOutcome;
+use crate::{error, info};
+
+// the longest the scheduler sleeps between checking whether a cycle is due
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+// when a server runs its maintenance
+//
+// an interval counts from the last cycle, and the first runs as the server starts,
+// while hours of the local day each get a single cycle
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    Interval(std::time::Duration),
+    Hours(Vec<u32>),
+}
+
+impl Schedule {
+    pub fn is_due(
+        &self,
+        last: Option<chrono::DateTime<chrono::Local>>,
+        now: chrono::DateTime<chrono::Local>,
+    ) -> bool {
+        match self {
+            Schedule::Interval(interval) => match last {
+                Some(last) => (now - last)
+                    .to_std()
+                    .is_ok_and(|elapsed| elapsed >= *interval),
+                None => true,
+            },
+            Schedule::Hours(hours) => {
+                hours.contains(&now.hour())
+                    && !last.is_some_and(|last| {
+                        last.date_naive() == now.date_naive() && last.hour() == now.hour()
+                    })
+            }
+        }
+    }
+
+    fn poll_interval(&self) -> std::time::Duration {
+        match self {
+            Schedule::Interval(interval) => std::cmp::min(*interval, POLL_INTERVAL),
+            Schedule::Hours(_) => POLL_INTERVAL,
+        }
+    }
+}
+
+// hours as `3`, `1,13`, `22-2`, or any mix of them, with ranges wrapping past midnight
+pub fn parse_hours(spec: &str) -> Option<Vec<u32>> {
+    let hour = |h: &str| h.trim().parse::<u32>().ok().filter(|h| *h < 24);
+
+    let mut hours = Vec::new();
+    for part in spec.split(',') {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (hour(start)?, hour(end)?);
+                let mut h = start;
+                hours.push(h);
+                while h != end {
+                    h = (h + 1) % 24;
+                    hours.push(h);
+                }
+            }
+            None => hours.push(hour(part)?),
+        }
+    }
+
+    hours.sort();
+    hours.dedup();
+
+    Some(hours)
+}
+
+// runs maintenance on `state` whenever `schedule` says it's due, until it's dropped
+//
+// the thread works against the paths of whoever started it
+pub struct Scheduler {
+    stop: Option<Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Scheduler {
+    pub fn spawn(
+        state: Arc<RwLock<crate::ServerState>>,
+        schedule: Schedule,
+        housekeeping: Option<housekeeping::Policy>,
+    ) -> Self {
+        let (stop, stopped) = std::sync::mpsc::channel::<()>();
+        let paths = config::get_paths();
+        let thread = std::thread::spawn(move || {
+            paths.scope(|| {
+                let mut last = None;
+                // the sender going away is the signal to stop
+                while let Err(RecvTimeoutError::Timeout) =
+                    stopped.recv_timeout(schedule.poll_interval())
+                {
+                    let now = chrono::Local::now();
+                    if !schedule.is_due(last, now) {
+                        continue;
+                    }
+
+                    last = Some(now);
+
+                    let mut state = state
+                        .write()
+                        .unwrap_or_else(std::sync::PoisonError::into_inner);
+                    match &state.maintain(housekeeping.as_ref()).outcome {
+                        MaintenanceOutcome::Completed {
+                            embedded,
+                            reblocked,
+                            housekeeping,
+                            backed_up,
+                        } => {
+                            info!(
+                                "maintenance embedded {} files, reblocked: {}, housekeeping: {}, backed up: {}",
+                                embedded, reblocked, housekeeping, backed_up
+                            );
+                        }
+                        MaintenanceOutcome::Skipped { reason } => {
+                            info!("skipping maintenance: {}", reason);
+                        }
+                        MaintenanceOutcome::Failed { error } => {
+                            error!("maintenance failed: {}", error);
+                        }
+                    }
+                }
+            })
+        });
+
+        Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("maintenance thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hours_test() {
+        assert_eq!(parse_hours("3"), Some(vec![3]));
+        assert_eq!(parse_hours("13, 1"), Some(vec![1, 13]));
+        assert_eq!(parse_hours("2-4,3"), Some(vec![2, 3, 4]));
+        assert_eq!(parse_hours("22-1"), Some(vec![0, 1, 22, 23]));
+        assert_eq!(parse_hours("24"), None);
+        assert_eq!(parse_hours("3,"), None);
+        assert_eq!(parse_hours("nightly"), None);
+    }
+
+    #[test]
+    fn schedule_test() {
+        use chrono::TimeZone;
+
+        let at = |day: u32, hour: u32, minute: u32| {
+            chrono::Local
+                .with_ymd_and_hms(2024, 5, day, hour, minute, 0)
+                .unwrap()
+        };
+
+        let hours = Schedule::Hours(vec![3]);
+        assert!(hours.is_due(None, at(1, 3, 0)));
+        assert!(!hours.is_due(None, at(1, 4, 0)));
+        assert!(!hours.is_due(Some(at(1, 3, 0)), at(1, 3, 59)));
+        assert!(hours.is_due(Some(at(1, 3, 0)), at(2, 3, 1)));
+
+        let interval = Schedule::Interval(std::time::Duration::from_secs(60 * 60));
+        assert!(interval.is_due(None, at(1, 3, 0)));
+        assert!(!interval.is_due(Some(at(1, 3, 0)), at(1, 3, 59)));
+        assert!(interval.is_due(Some(at(1, 3, 0)), at(1, 4, 0)));
+    }
+}
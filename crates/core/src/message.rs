@@ -1,29 +1,792 @@
+#[cfg(feature = "embed")]
+use crate::info;
+#[cfg(feature = "embed")]
+use crate::logger::Logger;
+use crate::types::{
+    BlockReport, EmbedSettings, Entry, FileInfo, LedgerEntry, QueryTrace, SearchMode, StableChunk,
+};
+
+// the version of the messages below, carried by their schema
+// bumped whenever one changes in a way the other end can't read
+pub const PROTOCOL_VERSION: u32 = 1;
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct DeweyRequest {
     pub message_type: String,
     pub payload: RequestPayload,
+    // the state generation the client's last response came with,
+    // so a request made after something changed is turned away with `stale_generation`
+    #[serde(default)]
+    pub expect_generation: Option<u64>,
+    // joins the spans made handling the request to the client's trace, with the `otel` feature
+    #[serde(default)]
+    pub trace_id: Option<String>,
+    // the capability that lets the request see past the acls named for it in the config
+    // (see `config::get_token_acls`), only what isn't behind any acl without one
+    #[serde(default)]
+    pub token: Option<String>,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(untagged)]
 pub enum RequestPayload {
     Query {
         k: usize,
+        // how many results are skipped before the `k` that are returned, for paging through them
+        #[serde(default)]
+        offset: usize,
         query: String,
         filters: Vec<String>,
+        // skips the embeddings of file paths, matching only on file contents
+        #[serde(default)]
+        exclude_paths: bool,
+        // groups the results by file or directory instead of returning individual chunks
+        #[serde(default)]
+        group_by: Option<GroupBy>,
+        #[serde(default)]
+        group_score: GroupScore,
+        // skips writing the query to the queries directory
+        #[serde(default)]
+        discard_query: bool,
+        // ranks boilerplate chunks without their penalty
+        #[serde(default)]
+        include_boilerplate: bool,
+        // searches the index even if the same query was answered recently
+        #[serde(default)]
+        no_cache: bool,
+        // includes a trace of how the index was searched in the response
+        #[serde(default)]
+        debug: bool,
+        // the model whose index is searched, the configured one if it isn't given
+        #[serde(default)]
+        model: Option<String>,
+        // how much of the index is searched, `balanced` if it isn't given
+        #[serde(default)]
+        search_mode: SearchMode,
+        // whether chunks or whole files are searched, `chunk` if it isn't given
+        #[serde(default)]
+        granularity: Granularity,
+        // file paths or globs the results are limited to, every file if it isn't given
+        #[serde(default)]
+        paths: Option<Vec<String>>,
+        // the collection searched, e.g. `snapshot:<label>`, the live index if it isn't given
+        #[serde(default)]
+        collection: Option<String>,
+        // drops results whose cosine similarity to one ranked ahead of them is over this,
+        // keeping every one if it isn't given
+        #[serde(default)]
+        dedupe_threshold: Option<f32>,
     },
     Edit {
         filepath: String,
+        // byte ranges of the file that changed, to only re-embed the chunks they touch
+        // the whole file is re-embedded without them
+        #[serde(default)]
+        ranges: Option<Vec<(u64, u64)>>,
+        // the collection the edit is for, which can only be the live index
+        #[serde(default)]
+        collection: Option<String>,
+        // embeds the file before answering, rather than queueing it until its edits go quiet
+        #[serde(default)]
+        sync: bool,
+    },
+    // this reads back as an `Edit` without ranges, so the server has to take either
+    FileInfo {
+        // or a chunk's stable id, for the file the chunk is in now
+        filepath: String,
     },
+    // the empty payloads have to stay last, since an empty struct matches any payload
+    // they're told apart by the request's `message_type` instead
+    Flush {},
+    Stats {},
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum GroupBy {
+    File,
+    // the first `depth` directories of the path, below the home directory if it's in it
+    Directory { depth: usize },
+}
+
+// how a group is scored from the similarities of its chunks
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum GroupScore {
+    #[default]
+    Max,
+    Mean,
+}
+
+// what a query's results are: single chunks, or whole files matched by the mean of their chunks
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Granularity {
+    #[default]
+    Chunk,
+    File,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct DeweyResponseItem {
     pub filepath: String,
     pub subset: (u64, u64),
+    // cosine similarity to the query
+    #[serde(default)]
+    pub score: f32,
+    // the result matched the file's path rather than its contents
+    #[serde(default)]
+    pub path_match: bool,
+    // the file changed since the chunk was embedded,
+    // and `subset` is where the chunk's text was found again, if it was
+    #[serde(default)]
+    pub stale: bool,
+    // the result is a whole file, matched by its centroid, and `subset` is `(0, 0)`
+    #[serde(default)]
+    pub file_match: bool, // unix seconds of when the chunk was embedded, 0 if that isn't known
+    #[serde(default)]
+    pub embedded_at: i64,
+    // an id for the chunk that outlives re-embeds of it, which `file_info` takes for a filepath
+    // `None` for path matches, file matches, and chunks from before their hashes were kept
+    #[serde(default)]
+    pub stable_id: Option<String>,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+// how much of the sha256 of a result's text a citation keeps
+#[cfg(feature = "embed")]
+const CITATION_HASH_CHARS: usize = 12;
+
+// a result as something to cite, like `path:L10-L42`
+//
+// the lines and hash are of the file as it is when the citation's made,
+// which can differ from when the result was embedded
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Citation {
+    pub filepath: String,
+    // 1-based and inclusive, `None` for path matches
+    // and files that are gone or shrank past where the result was
+    pub lines: Option<(usize, usize)>,
+    // the start of the sha256 of the cited text, when there are lines
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CitationFormat {
+    // `path:L10-L42`
+    #[default]
+    Plain,
+    // `[path:L10-L42](path#L10-L42)`
+    Markdown,
+    Json,
+}
+
+impl Citation {
+    // only the file up to the end of the result is read, see `parsing::read_lines`
+    #[cfg(feature = "embed")]
+    pub fn from_item(item: &DeweyResponseItem) -> Self {
+        let source = crate::embedding::EmbeddingSource {
+            filepath: item.filepath.clone(),
+            meta: Default::default(),
+            // whole files are matched with a subset of (0, 0)
+            subset: (!item.file_match).then_some(item.subset),
+            hash: String::new(),
+            chunk_hash: None,
+        };
+
+        let read = match item.path_match {
+            true => Ok(None),
+            false => crate::parsing::read_lines(&source),
+        };
+
+        let (lines, hash) = match read {
+            Ok(Some((lines, text))) => {
+                let mut hash = crate::ledger::hash_contents(text.as_bytes());
+                hash.truncate(CITATION_HASH_CHARS);
+                (Some(lines), Some(hash))
+            }
+            Ok(None) => (None, None),
+            Err(e) => {
+                info!("no lines to cite in {}: {}", item.filepath, e);
+                (None, None)
+            }
+        };
+
+        Self {
+            filepath: item.filepath.clone(),
+            lines,
+            hash,
+        }
+    }
+
+    // `L10-L42`, or `L10` for a single line
+    fn line_anchor(&self) -> Option<String> {
+        self.lines.map(|(first, last)| match first == last {
+            true => format!("L{}", first),
+            false => format!("L{}-L{}", first, last),
+        })
+    }
+
+    pub fn format(&self, format: CitationFormat) -> String {
+        match format {
+            CitationFormat::Plain => self.to_string(),
+            CitationFormat::Markdown => {
+                let target = self.filepath.replace(' ', "%20");
+                match self.line_anchor() {
+                    Some(anchor) => format!("[{}]({}#{})", self, target, anchor),
+                    None => format!("[{}]({})", self, target),
+                }
+            }
+            CitationFormat::Json => serde_json::to_string(self).unwrap(),
+        }
+    }
+}
+
+impl std::fmt::Display for Citation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.line_anchor() {
+            Some(anchor) => write!(f, "{}:{}", self.filepath, anchor),
+            None => write!(f, "{}", self.filepath),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DeweyResponseGroup {
+    pub key: String,
+    pub score: f32,
+    // the group's best chunks, best first
+    pub top_chunks: Vec<DeweyResponseItem>,
+}
+
+// grouped queries fill `groups` and leave `results` empty
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct DeweyResponse {
     pub results: Vec<DeweyResponseItem>,
+    #[serde(default)]
+    pub groups: Vec<DeweyResponseGroup>,
+    // the embedding API couldn't be reached,
+    // and the query was searched with the embedding it got the last time it was made
+    #[serde(default)]
+    pub degraded: bool,
+    // only for queries made with `debug`
+    #[serde(default)]
+    pub trace: Option<QueryTrace>,
+    // the search mode the index was searched with
+    #[serde(default)]
+    pub mode: SearchMode,
+    // the index hasn't been rebuilt since the last bulk embed,
+    // so the newest embeddings can't be found yet
+    #[serde(default)]
+    pub index_behind: bool,
+    // how many results or groups the query turned up, before it was cut to its page
+    #[serde(default)]
+    pub total_candidates: usize,
+    // there are results past this page
+    #[serde(default)]
+    pub has_more: bool,
+    // the state generation the results were found in
+    #[serde(default)]
+    pub generation: u64,
+    // the paths a query was limited to that matched no embedded file
+    #[serde(default)]
+    pub unknown_paths: Vec<String>,
+    // results left out for being behind an acl the request's token doesn't grant
+    #[serde(default)]
+    pub filtered_by_acl: usize,
+}
+
+// sent in place of a response when a request can't be served
+//
+// `error` is a machine-readable kind, e.g. `invalid_filter`
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DeweyErrorResponse {
+    pub error: String,
+    pub message: String,
+    // the filters of an `invalid_filter` error that failed to parse
+    #[serde(default)]
+    pub invalid_filters: Vec<String>,
+    // the file of an `unreadable_file` error, which went away or can't be read anymore
+    #[serde(default)]
+    pub filepath: Option<String>,
+    // the server's state generation, which a `stale_generation` error is about
+    #[serde(default)]
+    pub generation: u64,
+}
+
+impl DeweyErrorResponse {
+    pub fn new(error: &str, message: String) -> Self {
+        Self {
+            error: error.to_string(),
+            message,
+            invalid_filters: Vec::new(),
+            filepath: None,
+            generation: 0,
+        }
+    }
+}
+
+// an edit's response, carrying the state generation it left behind
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DeweyEditResponse {
+    pub generation: u64,
+    // whether the file is waiting to be embedded again, rather than done already
+    #[serde(default)]
+    pub queued: bool,
+}
+
+// `index_writes` counts every time the server has written its index since it started,
+// and `reembeds` every file it's embedded again for edits
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DeweyFlushResponse {
+    // whether there were edits to write
+    pub flushed: bool,
+    pub index_writes: u64,
+    // the nodes in the server's index, to compare against the one on disk
+    pub index_nodes: usize,
+    #[serde(default)]
+    pub generation: u64,
+    #[serde(default)]
+    pub reembeds: u64,
+}
+
+// `info` is what the index holds of the file, `ledger` what the config says it should
+// neither means the file isn't indexed
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DeweyFileInfoResponse {
+    pub filepath: String,
+    pub info: Option<FileInfo>,
+    pub ledger: Option<LedgerEntry>,
+    #[serde(default)]
+    pub generation: u64,
+    // the file is behind an acl the request's token doesn't grant,
+    // so it's answered as if it weren't indexed
+    #[serde(default)]
+    pub filtered_by_acl: bool,
+    // the chunk the request's stable id was found at, whose file `filepath` is
+    #[serde(default)]
+    pub chunk: Option<StableChunk>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DeweyStatsResponse {
+    pub blocks: Vec<BlockReport>,
+    pub recommendation: Option<String>,
+    // the worker and batch limits embeddings are made with
+    #[serde(default)]
+    pub embed_settings: EmbedSettings,
+    #[serde(default)]
+    pub query_cache: QueryCacheStats,
+    // the last scheduled maintenance, if one has run since the server started
+    #[serde(default)]
+    pub maintenance: Option<MaintenanceRun>,
+    // the last operation in the data directory's journal
+    #[serde(default)]
+    pub journal: Option<Entry>,
+    // the current state generation, bumped by everything that changes what can be searched
+    #[serde(default)]
+    pub generation: u64,
+    #[serde(default)]
+    pub health: ServerHealth,
+    // files with edits queued until they go quiet
+    #[serde(default)]
+    pub pending_edits: usize,
+}
+
+// whether any of a server's request handlers has panicked since it started
+//
+// the server keeps serving afterwards, with whatever state the handler left behind
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ServerHealth {
+    pub healthy: bool,
+    // requests that panicked or were handled after one had
+    pub degraded_requests: u64,
+    pub last_panic: Option<String>,
+    // what the server was built from, for spotting a client and server that don't match
+    #[serde(default)]
+    pub version: Option<crate::version::BuildInfo>,
+}
+
+impl Default for ServerHealth {
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            degraded_requests: 0,
+            last_panic: None,
+            version: None,
+        }
+    }
+}
+
+// when a server last ran its scheduled maintenance and what came of it
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct MaintenanceRun {
+    // RFC 3339, in local time
+    pub finished: String,
+    pub outcome: MaintenanceOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case", tag = "status")]
+pub enum MaintenanceOutcome {
+    Completed {
+        // files that changed since they were embedded
+        embedded: usize,
+        reblocked: bool,
+        // saved queries and logs removed or compressed
+        housekeeping: usize,
+        // whether the data directory was copied to `backup_dir`
+        #[serde(default)]
+        backed_up: bool,
+    },
+    // something else held the data directory lock
+    Skipped {
+        reason: String,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+// how often a server has answered queries from its cache since it started
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct QueryCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+// JSON Schema (draft 2020-12) for every message, for clients that aren't written in Rust
+//
+// the types are under `$defs`, with `payloads` pointing at the payload each `message_type` takes,
+// and `responses` at what it's answered with--any request can be answered with `error` instead
+#[cfg(feature = "schema")]
+pub fn json_schema() -> serde_json::Value {
+    let mut generator = schemars::generate::SchemaSettings::draft2020_12().into_generator();
+
+    let request = generator.subschema_for::<DeweyRequest>();
+    generator.subschema_for::<RequestPayload>();
+    // the payloads are untagged, so they're only told apart by where they are in `RequestPayload`
+    let payloads = ["query", "edit", "file_info", "flush", "stats"]
+        .iter()
+        .enumerate()
+        .map(|(i, message_type)| {
+            (
+                message_type.to_string(),
+                serde_json::json!({ "$ref": format!("#/$defs/RequestPayload/anyOf/{}", i) }),
+            )
+        })
+        .collect::<serde_json::Map<_, _>>();
+
+    let responses = serde_json::json!({
+        "query": generator.subschema_for::<DeweyResponse>(),
+        "edit": generator.subschema_for::<DeweyEditResponse>(),
+        "file_info": generator.subschema_for::<DeweyFileInfoResponse>(),
+        "flush": generator.subschema_for::<DeweyFlushResponse>(),
+        "stats": generator.subschema_for::<DeweyStatsResponse>(),
+        "error": generator.subschema_for::<DeweyErrorResponse>(),
+    });
+
+    serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$id": format!("urn:dewey:protocol:{}", PROTOCOL_VERSION),
+        "title": "dewey messages",
+        "version": PROTOCOL_VERSION,
+        "request": request,
+        "payloads": payloads,
+        "responses": responses,
+        "$defs": generator.take_definitions(true),
+    })
+}
+
+#[cfg(all(test, feature = "schema"))]
+mod tests {
+    use super::*;
+    use crate::types::{FilterStrategy, LayerTrace, Outcome, Params};
+
+    // checks `message` against the part of the schema at `pointer`,
+    // and that it reads back as a `T` that writes out the same
+    fn round_trip<T: serde::Serialize + serde::de::DeserializeOwned>(
+        schema: &serde_json::Value,
+        pointer: &str,
+        message: &T,
+    ) {
+        let value = serde_json::to_value(message).unwrap();
+        assert!(
+            validates(schema, pointer, &value),
+            "{} doesn't match {}",
+            value,
+            pointer
+        );
+
+        let read = serde_json::from_value::<T>(value.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&read).unwrap(), value);
+    }
+
+    fn validates(schema: &serde_json::Value, pointer: &str, value: &serde_json::Value) -> bool {
+        let mut schema = schema.clone();
+        schema["$ref"] = schema.pointer(pointer).unwrap()["$ref"].clone();
+        jsonschema::validator_for(&schema).unwrap().is_valid(value)
+    }
+
+    // a chunk's stable id, as `embedding::stable_id` writes them
+    const STABLE_ID: &str = "6f1c2a3b-4d5e-8f60-9172-83a4b5c6d7e8";
+
+    fn request(message_type: &str, payload: RequestPayload) -> DeweyRequest {
+        DeweyRequest {
+            message_type: message_type.to_string(),
+            payload,
+            expect_generation: Some(3),
+            trace_id: Some("0af7651916cd43dd8448eb211c80319c".to_string()),
+            token: Some("token".to_string()),
+        }
+    }
+
+    #[test]
+    fn json_schema_test() {
+        let schema = json_schema();
+        assert_eq!(schema["version"], PROTOCOL_VERSION);
+
+        let query = RequestPayload::Query {
+            k: 5,
+            offset: 10,
+            query: "aaaa".to_string(),
+            filters: vec!["ne python".to_string()],
+            exclude_paths: true,
+            group_by: Some(GroupBy::Directory { depth: 2 }),
+            group_score: GroupScore::Mean,
+            discard_query: true,
+            include_boilerplate: false,
+            no_cache: true,
+            debug: true,
+            model: Some("text-embedding-3-small".to_string()),
+            search_mode: SearchMode::Thorough,
+            granularity: Granularity::File,
+            paths: Some(vec!["/home/src/*.rs".to_string()]),
+            collection: Some("snapshot:label".to_string()),
+            dedupe_threshold: Some(0.5),
+        };
+        let payloads = [
+            ("query", query),
+            (
+                "edit",
+                RequestPayload::Edit {
+                    filepath: "/home/a.rs".to_string(),
+                    ranges: Some(vec![(0, 12)]),
+                    collection: None,
+                    sync: false,
+                },
+            ),
+            (
+                "file_info",
+                RequestPayload::FileInfo {
+                    filepath: "/home/a.rs".to_string(),
+                },
+            ),
+            ("flush", RequestPayload::Flush {}),
+            ("stats", RequestPayload::Stats {}),
+        ];
+        for (message_type, payload) in payloads {
+            let value = serde_json::to_value(&payload).unwrap();
+            assert!(validates(
+                &schema,
+                &format!("/payloads/{}", message_type),
+                &value
+            ));
+
+            // a `FileInfo` reads back as an `Edit` without ranges, so it can't write out the same
+            let request = request(message_type, payload);
+            match message_type {
+                "file_info" => assert!(validates(
+                    &schema,
+                    "/request",
+                    &serde_json::to_value(&request).unwrap()
+                )),
+                _ => round_trip(&schema, "/request", &request),
+            }
+        }
+
+        // the fields a request can't do without
+        let missing = serde_json::json!({ "k": 5, "filters": [] });
+        assert!(!validates(&schema, "/payloads/query", &missing));
+        let mode =
+            serde_json::json!({ "k": 5, "query": "aaaa", "filters": [], "search_mode": "slow" });
+        assert!(!validates(&schema, "/payloads/query", &mode));
+
+        let item = DeweyResponseItem {
+            filepath: "/home/a.rs".to_string(),
+            subset: (0, 12),
+            score: 0.5,
+            path_match: false,
+            stale: true,
+            file_match: false,
+            embedded_at: 1_700_000_000,
+            stable_id: Some(STABLE_ID.to_string()),
+        };
+        let trace = QueryTrace {
+            ef: 64,
+            mode: SearchMode::Fast,
+            filter_strategy: FilterStrategy::Widened { ef: 128 },
+            layers: vec![LayerTrace {
+                layer: 0,
+                visited: 10,
+                expanded: 4,
+                filtered: 1,
+                kept: 3,
+                exhausted: true,
+            }],
+            distances: vec![0.25],
+            cache_hits: 2,
+            block_loads: 1,
+        };
+        round_trip(
+            &schema,
+            "/responses/query",
+            &DeweyResponse {
+                results: vec![item.clone()],
+                groups: vec![DeweyResponseGroup {
+                    key: "/home".to_string(),
+                    score: 0.5,
+                    top_chunks: vec![item],
+                }],
+                degraded: true,
+                trace: Some(trace),
+                mode: SearchMode::Fast,
+                index_behind: true,
+                total_candidates: 20,
+                has_more: true,
+                generation: 3,
+                unknown_paths: vec!["/home/missing.rs".to_string()],
+                filtered_by_acl: 1,
+            },
+        );
+
+        round_trip(
+            &schema,
+            "/responses/edit",
+            &DeweyEditResponse {
+                generation: 4,
+                queued: true,
+            },
+        );
+        round_trip(
+            &schema,
+            "/responses/flush",
+            &DeweyFlushResponse {
+                flushed: true,
+                index_writes: 2,
+                index_nodes: 100,
+                generation: 4,
+                reembeds: 3,
+            },
+        );
+
+        // one tag, since sets come back in whatever order they hash to
+        let meta = ["acl:private".to_string()]
+            .into_iter()
+            .collect::<std::collections::HashSet<_>>();
+        round_trip(
+            &schema,
+            "/responses/file_info",
+            &DeweyFileInfoResponse {
+                filepath: "/home/a.rs".to_string(),
+                info: Some(FileInfo {
+                    chunk_count: 2,
+                    blocks: vec![0],
+                    ids: vec![1, 2, 3],
+                    subsets: vec![Some((0, 0)), Some((0, 12)), None],
+                    stable_ids: vec![None, Some(STABLE_ID.to_string()), None],
+                    meta: meta.clone(),
+                    last_embedded_hash: "abc".to_string(),
+                    embedded_at: 1_700_000_000,
+                }),
+                ledger: Some(LedgerEntry {
+                    filepath: "/home/a.rs".to_string(),
+                    hash: "abc".to_string(),
+                    meta,
+                }),
+                generation: 4,
+                filtered_by_acl: false,
+                chunk: Some(StableChunk {
+                    stable_id: STABLE_ID.to_string(),
+                    id: 2,
+                    filepath: "/home/a.rs".to_string(),
+                    subset: Some((0, 12)),
+                }),
+            },
+        );
+
+        let mut params = Params::new();
+        params.insert("files".to_string(), serde_json::json!(3));
+        round_trip(
+            &schema,
+            "/responses/stats",
+            &DeweyStatsResponse {
+                blocks: vec![BlockReport {
+                    block_number: 0,
+                    embeddings: 10,
+                    bytes: 4096,
+                    fill_factor: 0.5,
+                    files: 2,
+                    unreachable: 1,
+                    unreachable_bytes: 128,
+                }],
+                recommendation: Some("reblock".to_string()),
+                embed_settings: EmbedSettings::default(),
+                query_cache: QueryCacheStats {
+                    hits: 1,
+                    misses: 2,
+                    entries: 1,
+                },
+                maintenance: Some(MaintenanceRun {
+                    finished: "2026-01-01T00:00:00+00:00".to_string(),
+                    outcome: MaintenanceOutcome::Completed {
+                        embedded: 1,
+                        reblocked: false,
+                        housekeeping: 2,
+                        backed_up: true,
+                    },
+                }),
+                journal: Some(Entry {
+                    timestamp: "2026-01-01T00:00:00+00:00".to_string(),
+                    operation: "embed".to_string(),
+                    params,
+                    duration_ms: 12,
+                    outcome: Outcome::Failed {
+                        error: "quota".to_string(),
+                    },
+                    generation: 4,
+                }),
+                generation: 4,
+                health: ServerHealth {
+                    healthy: false,
+                    degraded_requests: 1,
+                    last_panic: Some("panic requested".to_string()),
+                    version: Some(crate::version::BuildInfo::current()),
+                },
+                pending_edits: 1,
+            },
+        );
+
+        let mut error = DeweyErrorResponse::new("invalid_filter", "bad filter".to_string());
+        error.invalid_filters = vec!["gt 3".to_string()];
+        error.filepath = Some("/home/a.rs".to_string());
+        round_trip(&schema, "/responses/error", &error);
+    }
 }
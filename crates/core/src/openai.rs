@@ -1,20 +1,16 @@
+use std::collections::HashMap;
 use std::env;
 use std::io::{Read, Write};
 use std::net::{TcpStream, ToSocketAddrs};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-use rand::Rng;
-
-use serialize_macros::Serialize;
-
+use crate::embedding::{Embedding, EmbeddingSource, EMBED_DIM};
+use crate::http::HttpRequest;
 use crate::logger::Logger;
-use crate::parsing::{batch_sources, read_source, TOKEN_LIMIT};
-use crate::serialization::Serialize;
+use crate::parsing::{batch_sources, Batch, SkippedSource};
 use crate::{error, info};
 
-pub const EMBED_DIM: usize = 1536;
-
 #[derive(Debug, Clone)]
 struct RequestParams {
     host: String,
@@ -22,6 +18,7 @@ struct RequestParams {
     port: u16,
     model: String,
     authorization_token: String,
+    timeouts: crate::config::ApiTimeouts,
 }
 
 impl RequestParams {
@@ -30,43 +27,254 @@ impl RequestParams {
             host: "api.openai.com".to_string(),
             path: "/v1/embeddings".to_string(),
             port: 443,
-            model: "text-embedding-3-small".to_string(),
-            authorization_token: env::var("OPENAI_API_KEY")
-                .expect("OPENAI_API_KEY environment variable not set"),
+            model: crate::config::get_embedding_model(),
+            authorization_token: match env::var("OPENAI_API_KEY") {
+                Ok(key) => key,
+                // unit tests and self-tests never reach the API
+                Err(_) if cfg!(test) || uses_test_embeddings() => String::new(),
+                Err(e) => panic!("OPENAI_API_KEY environment variable not set: {:?}", e),
+            },
+            timeouts: crate::config::get_api_timeouts(),
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct EmbeddingSource {
-    pub filepath: String,
-    pub meta: std::collections::HashSet<String>,
-    pub subset: Option<(u64, u64)>,
-}
-
-#[derive(Debug, Clone, Serialize)]
-pub struct Embedding {
-    pub id: u64,
-    pub source_file: EmbeddingSource,
-    pub data: [f32; EMBED_DIM],
-}
-
 trait EmbeddingApiClient {
     fn embedding_api_call(
         params: &RequestParams,
-        batch: &Vec<(EmbeddingSource, String)>,
+        batch: &[(EmbeddingSource, String)],
     ) -> Result<Vec<Embedding>, std::io::Error>;
 }
 
+// where each item of a batch came from, for error messages
+fn batch_files(batch: &[(EmbeddingSource, String)]) -> String {
+    batch
+        .iter()
+        .map(|(source, _)| match source.subset {
+            Some((start, end)) => format!("{} ({}..{})", source.filepath, start, end),
+            None => source.filepath.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// the API turns down a whole batch over one empty or oversized input,
+// so those are dropped before the request goes out
+fn valid_inputs(
+    batch: &[(EmbeddingSource, String)],
+    max_input_tokens: usize,
+) -> Vec<(EmbeddingSource, String)> {
+    batch
+        .iter()
+        .filter(|(source, text)| {
+            if text.is_empty() {
+                error!("dropping empty input from {}", source.filepath);
+                false
+            } else if text.len() > max_input_tokens {
+                error!(
+                    "dropping input of {} characters from {}, the limit is {}",
+                    text.len(),
+                    source.filepath,
+                    max_input_tokens
+                );
+                false
+            } else {
+                true
+            }
+        })
+        .cloned()
+        .collect()
+}
+
+// pairs each embedding in an API response with the batch item it was made from
+//
+// the API answers inputs in order, so a response that doesn't line up with the batch
+// can't be matched back to its sources at all
+fn parse_embeddings(
+    response: &serde_json::Value,
+    batch: &[(EmbeddingSource, String)],
+) -> Result<Vec<Embedding>, std::io::Error> {
+    let invalid = |message: String| {
+        error!("{}", message);
+        std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+    };
+
+    let data = match response["data"].as_array() {
+        Some(data) => data,
+        None => {
+            error!("Failed to parse data from JSON: {:?}", response);
+            return Err(invalid(format!(
+                "Failed to parse data from JSON for batch {}",
+                batch_files(batch)
+            )));
+        }
+    };
+
+    if data.len() != batch.len() {
+        return Err(invalid(format!(
+            "API returned {} embeddings for a batch of {}: {}",
+            data.len(),
+            batch.len(),
+            batch_files(batch)
+        )));
+    }
+
+    let mut embeddings = Vec::new();
+    for (datum, (source, _)) in data.iter().zip(batch) {
+        let values = match datum["embedding"].as_array() {
+            Some(values) if values.len() == EMBED_DIM => values,
+            Some(values) => {
+                return Err(invalid(format!(
+                    "API returned an embedding of {} dimensions for {}, expected {}",
+                    values.len(),
+                    source.filepath,
+                    EMBED_DIM
+                )))
+            }
+            None => {
+                return Err(invalid(format!(
+                    "API returned no embedding for {}",
+                    source.filepath
+                )))
+            }
+        };
+
+        let mut embedding = Embedding {
+            id: 0,
+            data: [0.0; EMBED_DIM],
+            source_file: source.clone(),
+            embedded_at: 0,
+        };
+
+        for (i, value) in values.iter().enumerate() {
+            embedding.data[i] = match value.as_f64() {
+                Some(value) => value as f32,
+                None => {
+                    return Err(invalid(format!(
+                        "API returned a non-numeric value {} in the embedding for {}",
+                        value, source.filepath
+                    )))
+                }
+            };
+        }
+
+        embeddings.push(embedding);
+    }
+
+    Ok(embeddings)
+}
+
+// a connection that gives up once its batch's deadline passes,
+// however slowly the other end trickles out bytes
+#[derive(Debug)]
+struct DeadlineStream {
+    stream: TcpStream,
+    timeout: std::time::Duration,
+    deadline: std::time::Instant,
+}
+
+impl DeadlineStream {
+    // every read and write waits at most the timeout, and never past the deadline
+    fn next_timeout(&self) -> Result<std::time::Duration, std::io::Error> {
+        let remaining = self
+            .deadline
+            .saturating_duration_since(std::time::Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "batch deadline passed",
+            ));
+        }
+
+        Ok(remaining.min(self.timeout))
+    }
+}
+
+// an expired socket timeout reads as `WouldBlock`,
+// which TLS would take as a nonblocking socket with nothing ready yet
+fn timed_out(e: std::io::Error) -> std::io::Error {
+    match e.kind() {
+        std::io::ErrorKind::WouldBlock => {
+            std::io::Error::new(std::io::ErrorKind::TimedOut, "OpenAI API timed out")
+        }
+        _ => e,
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.next_timeout()?))?;
+        self.stream.read(buf).map_err(timed_out)
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.next_timeout()?))?;
+        self.stream.write(buf).map_err(timed_out)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// the io error under a failed TLS handshake, if it was the connection that failed
+fn handshake_io_kind(e: &(dyn std::error::Error + 'static)) -> Option<std::io::ErrorKind> {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<std::io::Error>() {
+            return Some(e.kind());
+        }
+
+        source = e.source();
+    }
+
+    None
+}
+
+// the request for the embeddings of `batch`,
+// whose texts go into the body as they are, whatever they start or end with
+fn embedding_request(
+    params: &RequestParams,
+    batch: &[(EmbeddingSource, String)],
+) -> Result<HttpRequest, std::io::Error> {
+    let body = serde_json::json!({
+        "model": params.model,
+        "input": batch.iter().map(|pair| pair.1.as_str()).collect::<Vec<_>>(),
+    });
+
+    HttpRequest::post(&params.host, &params.path)?
+        .header("Accept", "*/*")?
+        .header(
+            "Authorization",
+            &format!("Bearer {}", params.authorization_token),
+        )?
+        .json(&body)
+}
+
 struct ApiClient;
 impl EmbeddingApiClient for ApiClient {
     fn embedding_api_call(
         params: &RequestParams,
-        batch: &Vec<(EmbeddingSource, String)>,
+        batch: &[(EmbeddingSource, String)],
     ) -> Result<Vec<Embedding>, std::io::Error> {
-        let duration = std::time::Duration::from_secs(30);
+        let limits = crate::config::get_provider_limits(&params.model);
+        let batch = valid_inputs(batch, limits.max_input_tokens);
+        if batch.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let timeouts = params.timeouts;
+        let deadline = std::time::Instant::now() + timeouts.deadline;
+
+        // a failed lookup is as good as no network
         let address = (params.host.clone(), params.port)
-            .to_socket_addrs()?
+            .to_socket_addrs()
+            .map_err(|e| {
+                error!("Failed to resolve {}: {}", params.host, e);
+                std::io::Error::new(std::io::ErrorKind::NetworkUnreachable, e)
+            })?
             .next()
             .ok_or_else(|| {
                 error!(
@@ -79,60 +287,46 @@ impl EmbeddingApiClient for ApiClient {
                 )
             })?;
 
-        let stream = match TcpStream::connect_timeout(&address, duration) {
-            Ok(stream) => stream,
-            Err(e) => {
-                error!("Failed to connect to OpenAI API: {:?}", e);
-                return Err(e);
-            }
+        let stream =
+            match TcpStream::connect_timeout(&address, timeouts.timeout.min(timeouts.deadline)) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Failed to connect to OpenAI API: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+        let stream = DeadlineStream {
+            stream,
+            timeout: timeouts.timeout,
+            deadline,
         };
 
-        match stream.set_read_timeout(Some(duration)) {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Failed to set read timeout: {:?}", e);
-                return Err(e);
-            }
-        }
+        let connector = native_tls::TlsConnector::new().map_err(|e| {
+            error!("Failed to create TLS connector: {}", e);
+            std::io::Error::other(e)
+        })?;
 
-        match stream.set_write_timeout(Some(duration)) {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Failed to set write timeout: {:?}", e);
-                return Err(e);
+        let mut stream = match connector.connect(&params.host, stream) {
+            Ok(stream) => stream,
+            Err(native_tls::HandshakeError::Failure(e)) => {
+                error!("Failed to establish TLS connection: {}", e);
+                let kind = handshake_io_kind(&e).unwrap_or(std::io::ErrorKind::ConnectionAborted);
+                return Err(std::io::Error::new(kind, e));
             }
-        }
+            Err(native_tls::HandshakeError::WouldBlock(_)) => {
+                error!("TLS handshake with OpenAI API timed out");
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "TLS handshake timed out",
+                ));
+            }
+        };
 
-        let connector = native_tls::TlsConnector::new().expect("Failed to create TLS connector");
-        let mut stream = connector
-            .connect(&params.host, stream)
-            .expect("Failed to establish TLS connection");
+        let request = embedding_request(params, &batch)?.to_bytes();
+        let request_text = || String::from_utf8_lossy(&request).to_string();
 
-        let body = serde_json::json!({
-            "model": params.model,
-            "input": batch.iter().map(|pair| pair.1.clone()).collect::<Vec<String>>(),
-        });
-        let json = serde_json::json!(body);
-        let json_string = serde_json::to_string(&json)?;
-
-        let auth_string = "Authorization: Bearer ".to_string() + &params.authorization_token;
-
-        let request = format!(
-            "POST {} HTTP/1.1\r\n\
-        Host: {}\r\n\
-        Content-Type: application/json\r\n\
-        Content-Length: {}\r\n\
-        Accept: */*\r\n\
-        {}\r\n\r\n\
-        {}",
-            params.path,
-            params.host,
-            json_string.len(),
-            auth_string,
-            json_string.trim()
-        );
-
-        match stream.write_all(request.as_bytes()) {
+        match stream.write_all(&request) {
             Ok(_) => (),
             Err(e) => {
                 error!("Failed to write to OpenAI stream: {:?}", e);
@@ -195,7 +389,7 @@ impl EmbeddingApiClient for ApiClient {
         let response_json = serde_json::from_str(&body);
 
         if response_json.is_err() {
-            error!("request: {}", request);
+            error!("request: {}", request_text());
             error!("Failed to parse JSON: {}", body);
             error!("Headers: {}", headers.join("\n"));
             return Err(std::io::Error::new(
@@ -205,52 +399,86 @@ impl EmbeddingApiClient for ApiClient {
         }
 
         let response_json: serde_json::Value = response_json.unwrap();
-        let data = match response_json["data"].as_array() {
-            Some(data) => data,
-            _ => {
-                error!("batch: {:?}", batch);
-                error!("Failed to parse data from JSON: {:?}", response_json);
-                error!("Request: {}", request);
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Failed to parse data from JSON",
-                ));
-            }
-        };
+        parse_embeddings(&response_json, &batch).inspect_err(|_| {
+            error!("Request: {}", request_text());
+        })
+    }
+}
 
-        let mut embeddings = Vec::new();
-        for (i, datum) in data.iter().enumerate() {
-            let mut embedding = Embedding {
-                id: 0,
-                data: [0.0; 1536],
-                source_file: batch[i].0.clone(),
-            };
+// stands in for the API under testing
+//
+// every word in the text is hashed into a dimension, so texts sharing words
+// land near each other and tests can check what a query retrieves
+//
+// every embedding also shares a common component--real embeddings are never orthogonal,
+// and the index can't link clusters of texts without any words in common otherwise
+fn test_embedding(text: &str) -> [f32; EMBED_DIM] {
+    use std::hash::{Hash, Hasher};
+
+    let mut data = [1.0 / (EMBED_DIM as f32).sqrt(); EMBED_DIM];
+    for token in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+    {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        token.to_lowercase().hash(&mut hasher);
+        data[(hasher.finish() % EMBED_DIM as u64) as usize] += 1.0;
+    }
 
-            for (i, value) in datum["embedding"].as_array().unwrap().iter().enumerate() {
-                embedding.data[i] = value.as_f64().unwrap() as f32;
-            }
+    data
+}
 
-            embeddings.push(embedding);
-        }
+// how many requests the test client has answered on the current thread,
+// the models they asked for, and the error it fails them with, if any
+#[cfg(test)]
+thread_local! {
+    pub static TEST_API_CALLS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    pub static TEST_API_MODELS: std::cell::RefCell<Vec<String>> =
+        const { std::cell::RefCell::new(Vec::new()) };
+    pub static TEST_API_FAILURE: std::cell::Cell<Option<std::io::ErrorKind>> =
+        const { std::cell::Cell::new(None) };
+}
 
-        Ok(embeddings)
-    }
+// set by `use_test_embeddings`, for builds that would otherwise go to the API
+static TEST_EMBEDDINGS: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+// every embedding from here on, for the rest of the process, comes from the test client
+//
+// this is for `--self-test`, which runs the whole pipeline without a network or an API key
+pub fn use_test_embeddings() {
+    TEST_EMBEDDINGS.store(true, std::sync::atomic::Ordering::SeqCst);
+}
+
+pub(crate) fn uses_test_embeddings() -> bool {
+    cfg!(test)
+        || cfg!(feature = "regression")
+        || TEST_EMBEDDINGS.load(std::sync::atomic::Ordering::SeqCst)
 }
 
 struct TestApiCall;
 impl EmbeddingApiClient for TestApiCall {
+    #[cfg_attr(not(test), allow(unused_variables))]
     fn embedding_api_call(
-        _params: &RequestParams,
-        batch: &Vec<(EmbeddingSource, String)>,
+        params: &RequestParams,
+        batch: &[(EmbeddingSource, String)],
     ) -> Result<Vec<Embedding>, std::io::Error> {
+        #[cfg(test)]
+        {
+            TEST_API_CALLS.with(|calls| calls.set(calls.get() + 1));
+            TEST_API_MODELS.with(|models| models.borrow_mut().push(params.model.clone()));
+            if let Some(kind) = TEST_API_FAILURE.with(|failure| failure.get()) {
+                return Err(std::io::Error::new(kind, "test API failure"));
+            }
+        }
+
         let mut embeddings = Vec::new();
-        let mut rng = rand::thread_rng();
 
         for (i, b) in batch.iter().enumerate() {
             let embedding = Embedding {
                 id: i as u64,
-                data: [0.0; 1536].map(|_| rng.gen()),
+                data: test_embedding(&b.1),
                 source_file: b.0.clone(),
+                embedded_at: 0,
             };
 
             embeddings.push(embedding);
@@ -260,66 +488,183 @@ impl EmbeddingApiClient for TestApiCall {
     }
 }
 
+// how many embeddings the pipeline is holding that haven't been written to a block yet,
+// and the most it's held at once, counted across every worker thread
+#[cfg(test)]
+pub static TEST_RETAINED: std::sync::atomic::AtomicIsize = std::sync::atomic::AtomicIsize::new(0);
+#[cfg(test)]
+pub static TEST_PEAK_RETAINED: std::sync::atomic::AtomicIsize =
+    std::sync::atomic::AtomicIsize::new(0);
+
+pub fn track_retained(_change: isize) {
+    #[cfg(test)]
+    {
+        use std::sync::atomic::Ordering;
+        let retained = TEST_RETAINED.fetch_add(_change, Ordering::SeqCst) + _change;
+        TEST_PEAK_RETAINED.fetch_max(retained, Ordering::SeqCst);
+    }
+}
+
 // multithreaded wrapper over the actual bulk API call
-pub fn embed_bulk(sources: &Vec<EmbeddingSource>) -> Result<Vec<Embedding>, std::io::Error> {
+//
+// embeddings keyed by the model they were made with, and the sources that couldn't be read
+type BulkEmbeddings = (HashMap<String, Vec<Embedding>>, Vec<SkippedSource>);
+
+// each batch is sent to the model it was routed to,
+// and the embeddings come back keyed by that model since they can't be mixed
+pub fn embed_bulk(sources: &Vec<EmbeddingSource>) -> Result<BulkEmbeddings, std::io::Error> {
+    let mut embeddings = HashMap::<String, Vec<Embedding>>::new();
+    let skipped = embed_streaming(sources, |model, batch| {
+        embeddings.entry(model).or_default().extend(batch);
+        Ok(())
+    })?;
+
+    Ok((embeddings, skipped))
+}
+
+type ApiCall =
+    fn(&RequestParams, &[(EmbeddingSource, String)]) -> Result<Vec<Embedding>, std::io::Error>;
+
+// a batch that couldn't reach the API is tried again, each time on a fresh connection,
+// until it's out of attempts
+fn embed_batch(
+    api_call: ApiCall,
+    params: &RequestParams,
+    chunks: &[(EmbeddingSource, String)],
+) -> Result<Vec<Embedding>, std::io::Error> {
+    let attempts = params.timeouts.attempts;
+    let mut attempt = 1;
+    let mut span = crate::trace::Span::start("dewey.embed");
+    span.set("model", params.model.as_str());
+    span.set("inputs", chunks.len());
+    loop {
+        match api_call(params, chunks) {
+            Err(e) if is_network_error(&e) && attempt < attempts => {
+                error!(
+                    "attempt {} of {} to embed batch failed, retrying: {}",
+                    attempt, attempts, e
+                );
+
+                std::thread::sleep(std::time::Duration::from_millis(100 * attempt as u64));
+                attempt += 1;
+            }
+            result => {
+                span.set("attempts", attempt);
+                span.set("error", result.is_err());
+                return result;
+            }
+        }
+    }
+}
+
+// like `embed_bulk`, but every batch goes to `on_batch` as soon as it's embedded
+//
+// only a batch per worker can wait on `on_batch` at once, so workers stop making requests
+// while it falls behind instead of piling up embeddings in memory
+//
+// the first error from `on_batch` stops the rest of the batches from being sent
+//
+// returns the sources that were skipped since they couldn't be read
+pub fn embed_streaming(
+    sources: &Vec<EmbeddingSource>,
+    mut on_batch: impl FnMut(String, Vec<Embedding>) -> Result<(), std::io::Error>,
+) -> Result<Vec<SkippedSource>, std::io::Error> {
     let params = RequestParams::new();
 
-    // there's probably a better programmatic way of determining this
-    const NUM_THREADS: usize = 8;
+    let settings = crate::config::get_embed_settings();
     let mut thread_pool = Vec::new();
-    let (tx, rx) = std::sync::mpsc::channel::<Vec<(EmbeddingSource, String)>>();
+    let (tx, rx) = std::sync::mpsc::channel::<Batch>();
     let rx = Arc::new(Mutex::new(rx));
+    let (done_tx, done_rx) =
+        std::sync::mpsc::sync_channel::<(String, Vec<Embedding>)>(settings.workers);
 
-    let api_call = if cfg!(feature = "regression") {
+    let api_call: ApiCall = if uses_test_embeddings() {
         TestApiCall::embedding_api_call
     } else {
         ApiClient::embedding_api_call
     };
 
+    info!("embedding API: {}", params.timeouts);
+
     // API requests need batched up to keep from exceeding token limits
-    let batches = batch_sources(&sources)?;
+    let (batches, skipped) = batch_sources(sources, &settings)?;
 
-    let embeddings = Arc::new(Mutex::new(Vec::new()));
     let count = Arc::new(Mutex::new(0));
-    for i in 0..std::cmp::min(NUM_THREADS, batches.len()) {
+    let failed = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    for i in 0..std::cmp::min(settings.workers, batches.len()) {
         let thread_rx = Arc::clone(&rx);
+        let done_tx = done_tx.clone();
         let params = params.clone();
-        let embeddings = Arc::clone(&embeddings);
         let count = Arc::clone(&count);
-        let thread = thread::spawn(move || loop {
-            let batch = thread_rx.lock().unwrap().recv();
-            match batch {
-                Ok(batch) => {
-                    match api_call(&params, &batch) {
-                        Ok(new_embeddings) => {
-                            let mut embeddings = embeddings.lock().unwrap();
-                            embeddings.extend(new_embeddings);
-
-                            let mut count = count.lock().unwrap();
-                            *count += 1;
-                            if *count % 100 == 0 {
-                                info!("{} embeddings made", *count);
+        let failed = Arc::clone(&failed);
+        let context = crate::trace::Context::current();
+        let thread = thread::spawn(move || {
+            context.attach(|| loop {
+                let batch = thread_rx.lock().unwrap().recv();
+                match batch {
+                    Ok(batch) => {
+                        let params = RequestParams {
+                            model: batch.model.clone(),
+                            ..params.clone()
+                        };
+
+                        match embed_batch(api_call, &params, &batch.chunks) {
+                            Ok(mut new_embeddings) => {
+                                let now = chrono::Utc::now().timestamp();
+                                for embedding in new_embeddings.iter_mut() {
+                                    embedding.embedded_at = now;
+                                }
+
+                                track_retained(new_embeddings.len() as isize);
+
+                                {
+                                    let mut count = count.lock().unwrap();
+                                    *count += 1;
+                                    if *count % 100 == 0 {
+                                        info!("{} embeddings made", *count);
+                                    }
+                                }
+
+                                // blocks until the receiving end catches up,
+                                // and fails once it's given up on the rest
+                                if done_tx.send((batch.model, new_embeddings)).is_err() {
+                                    break;
+                                }
                             }
-                        }
-                        Err(e) => {
-                            error!("Failed to embed batch {}: {:?}", batch.len(), e);
-                            continue;
-                        }
-                    };
-                }
-                Err(e) => {
-                    error!("Error in thread {}, exiting: {}", i, e);
-                    break;
+                            Err(e) => {
+                                error!(
+                                    "Failed to embed batch {} with {}{}: {:?}",
+                                    batch.chunks.len(),
+                                    batch.model,
+                                    if is_network_error(&e) {
+                                        " (retryable)"
+                                    } else {
+                                        ""
+                                    },
+                                    e
+                                );
+
+                                failed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                                continue;
+                            }
+                        };
+                    }
+                    Err(e) => {
+                        error!("Error in thread {}, exiting: {}", i, e);
+                        break;
+                    }
                 }
-            }
+            })
         });
 
         thread_pool.push(thread);
     }
 
+    // the workers hold the only senders left, so the receiver closes once they're all done
+    drop(done_tx);
+
     info!("working through {} batches", batches.len());
 
-    // TODO: figure out a process for dealing with failed batches
     let mut retries = 0;
     for batch in batches.iter() {
         while let Err(e) = tx.send(batch.clone()) {
@@ -336,40 +681,283 @@ pub fn embed_bulk(sources: &Vec<EmbeddingSource>) -> Result<Vec<Embedding>, std:
     }
 
     drop(tx);
+    drop(batches);
+
+    let mut result = Ok(skipped);
+    for (model, embeddings) in done_rx.iter() {
+        track_retained(-(embeddings.len() as isize));
+        if let Err(e) = on_batch(model, embeddings) {
+            error!("stopping embedding: {}", e);
+            result = Err(e);
+            break;
+        }
+    }
 
+    // the workers still waiting on a batch see it's been dropped and stop
+    drop(done_rx);
     for thread in thread_pool {
         thread.join().unwrap();
     }
 
-    let embeddings = Arc::try_unwrap(embeddings).unwrap().into_inner().unwrap();
-    Ok(embeddings)
+    let failed = failed.load(std::sync::atomic::Ordering::Relaxed);
+    if failed > 0 {
+        error!("{} batches failed to embed and were left out", failed);
+    }
+
+    result
+}
+
+// embeds `query` directly, with `source` standing in for wherever it came from
+// errors that mean the embedding API couldn't be reached,
+// rather than that it turned the request down
+pub fn is_network_error(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(
+        e.kind(),
+        ConnectionRefused
+            | ConnectionReset
+            | ConnectionAborted
+            | NotConnected
+            | TimedOut
+            | WouldBlock
+            | UnexpectedEof
+            | HostUnreachable
+            | NetworkUnreachable
+            | NetworkDown
+            | AddrNotAvailable
+    )
 }
 
-pub fn embed(source: &EmbeddingSource) -> Result<Embedding, std::io::Error> {
-    let query = read_source(source)?;
-    if query.len() == 0 || query.len() > TOKEN_LIMIT {
+// queries are held to the same `max_input_tokens` as the chunks they're compared against
+pub fn embed_text(source: &EmbeddingSource, query: &str) -> Result<Embedding, std::io::Error> {
+    let limit =
+        crate::config::get_provider_limits(&crate::config::get_embedding_model()).max_input_tokens;
+    if query.trim().is_empty() || query.len() > limit {
         error!("Invalid query size: {}", query.len());
-        error!("Query must be between 1 and {} characters", TOKEN_LIMIT);
+        error!("Query must be between 1 and {} characters", limit);
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidInput,
-            "Failed to read source",
+            format!(
+                "query is {} characters, but must be between 1 and {}",
+                query.len(),
+                limit
+            ),
         ));
     }
 
-    let api_call = if cfg!(feature = "regression") {
+    let api_call = if uses_test_embeddings() {
         TestApiCall::embedding_api_call
     } else {
         ApiClient::embedding_api_call
     };
 
-    match api_call(
-        &RequestParams::new(),
-        &vec![(source.clone(), query.clone())],
-    ) {
-        Ok(embeddings) => Ok(embeddings[0].clone()),
+    let params = RequestParams::new();
+    let mut span = crate::trace::Span::start("dewey.embed");
+    span.set("model", params.model.as_str());
+    span.set("inputs", 1usize);
+    match api_call(&params, &[(source.clone(), query.to_string())]) {
+        Ok(embeddings) => embeddings.into_iter().next().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("no embedding returned for query \"{}\"", query),
+            )
+        }),
         Err(e) => {
             error!("Failed to embed query \"{}\": {:?}", query, e);
-            return Err(e);
+            Err(e)
         }
     }
 }
+
+#[cfg(all(test, feature = "full"))]
+mod tests {
+    use super::*;
+    use crate::parsing::TOKEN_LIMIT;
+    use crate::test_common::{setup, Cleanup};
+
+    fn source(filepath: &str) -> EmbeddingSource {
+        EmbeddingSource {
+            filepath: filepath.to_string(),
+            meta: std::collections::HashSet::new(),
+            subset: Some((0, 1)),
+            hash: String::new(),
+            chunk_hash: None,
+        }
+    }
+
+    fn batch(files: &[&str]) -> Vec<(EmbeddingSource, String)> {
+        files
+            .iter()
+            .map(|f| (source(f), format!("contents of {}", f)))
+            .collect()
+    }
+
+    // a response like the API's, with an embedding per entry of `values`
+    fn response(values: Vec<serde_json::Value>) -> serde_json::Value {
+        serde_json::json!({
+            "object": "list",
+            "data": values
+                .into_iter()
+                .enumerate()
+                .map(|(i, embedding)| serde_json::json!({
+                    "object": "embedding",
+                    "index": i,
+                    "embedding": embedding,
+                }))
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    fn embedding(value: f64) -> serde_json::Value {
+        serde_json::json!(vec![value; EMBED_DIM])
+    }
+
+    #[test]
+    fn parse_embeddings_test() {
+        let batch = batch(&["a.rs", "b.rs"]);
+        let embeddings =
+            parse_embeddings(&response(vec![embedding(0.25), embedding(0.5)]), &batch).unwrap();
+
+        assert_eq!(embeddings.len(), 2);
+        assert_eq!(embeddings[0].source_file.filepath, "a.rs");
+        assert_eq!(embeddings[1].source_file.filepath, "b.rs");
+        assert!(embeddings[1].data.iter().all(|v| *v == 0.5));
+    }
+
+    // texts that start and end with newlines used to leave the declared length
+    // longer than the body, and the API waiting for the rest of it
+    #[test]
+    fn embedding_request_test() {
+        let _cleanup = Cleanup;
+        assert!(setup().is_ok());
+
+        let mut params = RequestParams::new();
+        params.authorization_token = "test-key".to_string();
+        let batch = vec![
+            (
+                source("a.md"),
+                "\n\nstarts and ends with newlines\n\n".to_string(),
+            ),
+            (source("b.md"), "\r\n \t\r\n".to_string()),
+        ];
+
+        let request = embedding_request(&params, &batch).unwrap().to_bytes();
+        let (head, declared, body) = crate::http::split_request(&request);
+        assert_eq!(declared, body.len());
+        assert!(head.lines().any(|l| l == "Authorization: Bearer test-key"));
+
+        let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(body["input"], serde_json::json!([&batch[0].1, &batch[1].1]));
+
+        // a key from the environment can't add headers of its own
+        params.authorization_token = "test-key\r\nX-Injected: 1".to_string();
+        let error = embedding_request(&params, &batch).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn mismatched_response_test() {
+        let batch = batch(&["a.rs", "b.rs"]);
+
+        // more embeddings than inputs used to index past the end of the batch
+        let e = parse_embeddings(
+            &response(vec![embedding(0.1), embedding(0.2), embedding(0.3)]),
+            &batch,
+        )
+        .unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("a.rs (0..1), b.rs (0..1)"));
+
+        // and fewer would quietly mislabel whatever came back
+        let e = parse_embeddings(&response(vec![embedding(0.1)]), &batch).unwrap_err();
+        assert!(e.to_string().contains("1 embeddings for a batch of 2"));
+
+        let e = parse_embeddings(&serde_json::json!({ "error": "nope" }), &batch).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn malformed_embedding_test() {
+        let batch = batch(&["a.rs", "b.rs"]);
+
+        let e = parse_embeddings(
+            &response(vec![embedding(0.1), serde_json::Value::Null]),
+            &batch,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("no embedding for b.rs"));
+
+        let mut nulls = vec![serde_json::json!(0.1); EMBED_DIM];
+        nulls[7] = serde_json::Value::Null;
+        let e = parse_embeddings(
+            &response(vec![serde_json::json!(nulls), embedding(0.1)]),
+            &batch,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("non-numeric value null"));
+
+        let e = parse_embeddings(
+            &response(vec![embedding(0.1), serde_json::json!([0.1, 0.2])]),
+            &batch,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("2 dimensions for b.rs"));
+    }
+
+    #[test]
+    fn valid_inputs_test() {
+        let mut batch = batch(&["a.rs", "empty.rs", "b.rs", "long.rs"]);
+        batch[1].1 = String::new();
+        batch[3].1 = "x".repeat(TOKEN_LIMIT + 1);
+
+        let valid = valid_inputs(&batch, TOKEN_LIMIT);
+        let files = valid
+            .iter()
+            .map(|(source, _)| source.filepath.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(files, vec!["a.rs", "b.rs"]);
+    }
+
+    // a server that accepts connections but never answers can't hold a worker past
+    // the batch deadline, and the batch is tried again on a new connection
+    #[test]
+    fn batch_deadline_test() {
+        let _cleanup = Cleanup;
+        assert!(setup().is_ok());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accepted = Arc::new(Mutex::new(Vec::new()));
+
+        let server_accepted = Arc::clone(&accepted);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                // held open without a response
+                server_accepted.lock().unwrap().push(stream.unwrap());
+            }
+        });
+
+        let params = RequestParams {
+            host: "127.0.0.1".to_string(),
+            path: "/v1/embeddings".to_string(),
+            port,
+            model: crate::config::DEFAULT_EMBEDDING_MODEL.to_string(),
+            authorization_token: String::new(),
+            timeouts: crate::config::ApiTimeouts {
+                timeout: std::time::Duration::from_secs(30),
+                deadline: std::time::Duration::from_millis(300),
+                attempts: 2,
+            },
+        };
+
+        let start = std::time::Instant::now();
+        let e = embed_batch(ApiClient::embedding_api_call, &params, &batch(&["a.rs"])).unwrap_err();
+        let elapsed = start.elapsed();
+
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+        assert!(is_network_error(&e));
+        assert!(elapsed >= std::time::Duration::from_millis(600));
+        assert!(elapsed < std::time::Duration::from_secs(10));
+        assert_eq!(accepted.lock().unwrap().len(), 2);
+    }
+}
@@ -1,12 +1,69 @@
-use std::io::Read;
+use std::io::{Read, Seek};
 
-use crate::ledger::{get_indexing_rules, IndexRuleType};
-use crate::openai::EmbeddingSource;
+use crate::embedding::{is_field_source, EmbeddingSource, PATH_META, TITLE_META};
+use crate::ledger::{
+    get_effective_rules, get_extension, get_indexing_rules, IndexRule, IndexRuleType,
+    NORMALIZE_TRANSFORMS,
+};
+pub use crate::types::TOKEN_LIMIT;
 
 use crate::logger::Logger;
-use crate::{error, info, lprint};
+use crate::{error, info};
 
 pub fn read_source(source: &EmbeddingSource) -> Result<String, std::io::Error> {
+    Ok(read_normalized(source)?.0)
+}
+
+// (normalized, raw) offset pairs at which the normalized contents drift from the file,
+// i.e. just past every dropped \r and every replaced invalid sequence
+type OffsetMap = Vec<(usize, usize)>;
+
+// lossy UTF-8 with CRLF -> LF, which is what every chunk is split and embedded from
+pub fn normalize_contents(bytes: &[u8]) -> String {
+    normalize_with_offsets(bytes).0
+}
+
+fn normalize_with_offsets(bytes: &[u8]) -> (String, OffsetMap) {
+    let mut contents = String::with_capacity(bytes.len());
+    let mut offsets = Vec::new();
+    let mut raw = 0;
+    for chunk in bytes.utf8_chunks() {
+        let mut valid = chunk.valid();
+        while let Some(i) = valid.find("\r\n") {
+            contents.push_str(&valid[..i]);
+            contents.push('\n');
+            raw += i + 2;
+            offsets.push((contents.len(), raw));
+            valid = &valid[i + 2..];
+        }
+
+        contents.push_str(valid);
+        raw += valid.len();
+
+        if !chunk.invalid().is_empty() {
+            contents.push(char::REPLACEMENT_CHARACTER);
+            raw += chunk.invalid().len();
+            offsets.push((contents.len(), raw));
+        }
+    }
+
+    (contents, offsets)
+}
+
+// the byte offset in the file of `offset` into its normalized contents
+//
+// an offset sitting on the \n of a CRLF maps to its \r,
+// so a chunk ending there leaves the \r out and a chunk starting there takes the whole CRLF
+fn raw_offset(offsets: &OffsetMap, offset: usize) -> usize {
+    match offsets.partition_point(|(normalized, _)| *normalized <= offset) {
+        0 => offset,
+        i => offsets[i - 1].1 + offset - offsets[i - 1].0,
+    }
+}
+
+// subsets are byte offsets into the file as it is on disk,
+// so only the subset itself is read and normalized
+fn read_normalized(source: &EmbeddingSource) -> Result<(String, OffsetMap), std::io::Error> {
     let mut file = match std::fs::File::open(&source.filepath) {
         Ok(file) => file,
         Err(e) => {
@@ -16,126 +73,822 @@ pub fn read_source(source: &EmbeddingSource) -> Result<String, std::io::Error> {
     };
 
     let mut buffer = Vec::new();
-    let contents = match file.read_to_end(&mut buffer) {
-        Ok(_) => String::from_utf8_lossy(&buffer).to_string(),
+    let read = match source.subset {
+        Some((start, end)) => {
+            let length = file.metadata()?.len();
+            if start > end || end > length {
+                error!(
+                    "subset {:?} out of bounds for file {}",
+                    (start, end),
+                    source.filepath
+                );
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "subset out of bounds",
+                ));
+            }
+
+            file.seek(std::io::SeekFrom::Start(start))
+                .and_then(|_| file.take(end - start).read_to_end(&mut buffer))
+        }
+        None => file.read_to_end(&mut buffer),
+    };
+
+    if let Err(e) = read {
+        error!("Failed to read from file {}: {:?}", source.filepath, e);
+        return Err(e);
+    }
+
+    Ok(normalize_with_offsets(&buffer))
+}
+
+// the first and last lines of part of a file, 1-based and inclusive
+pub type LineRange = (usize, usize);
+
+// the 1-based, inclusive lines the subset of `source` covers in the file as it is now,
+// along with the subset's text
+//
+// the file is only read as far as the end of the subset: what's before it a buffer at a time,
+// just to count its lines, and the subset itself with `read_source`
+//
+// a subset running past the end of a file that shrank since it was embedded is cut off there,
+// and there are no lines if none of it is left
+pub fn read_lines(source: &EmbeddingSource) -> Result<Option<(LineRange, String)>, std::io::Error> {
+    let length = std::fs::metadata(&source.filepath)?.len();
+    let (start, end) = source.subset.unwrap_or((0, length));
+    let end = end.min(length);
+    if start >= end {
+        return Ok(None);
+    }
+
+    let mut before = std::fs::File::open(&source.filepath)?.take(start);
+    let mut buffer = vec![0; READ_BUFFER_SIZE];
+    let mut first = 1;
+    loop {
+        match before.read(&mut buffer)? {
+            0 => break,
+            read => first += buffer[..read].iter().filter(|b| **b == b'\n').count(),
+        }
+    }
+
+    let text = read_source(&EmbeddingSource {
+        subset: Some((start, end)),
+        ..source.clone()
+    })?;
+
+    // a newline closing the subset ends its last line rather than starting another
+    let bytes = text.as_bytes();
+    let last = first
+        + bytes[..bytes.len().saturating_sub(1)]
+            .iter()
+            .filter(|b| **b == b'\n')
+            .count();
+
+    Ok(Some(((first, last), text)))
+}
+
+// titles are capped to this many chars, and looked for in this many bytes of the file
+const TITLE_LENGTH: usize = 200;
+const TITLE_SEARCH_BYTES: u64 = 64 * 1024;
+
+// the transforms named across every `--normalize` rule in `rules`
+fn normalize_transforms(rules: &[IndexRule]) -> Vec<&'static str> {
+    NORMALIZE_TRANSFORMS
+        .into_iter()
+        .filter(|transform| {
+            rules
+                .iter()
+                .filter(|rule| rule.rule_type == IndexRuleType::Normalize)
+                .any(|rule| rule.value.split(',').any(|t| t.trim() == *transform))
+        })
+        .collect()
+}
+
+// the text a chunk is embedded as, once `transforms` are applied to it
+//
+// only the embedded text changes, the chunk's subset still points at the original bytes
+pub fn normalize_text(text: &str, transforms: &[&str]) -> String {
+    let mut text = text.to_string();
+    for transform in transforms {
+        text = match *transform {
+            "strip-ansi" => strip_ansi(&text),
+            "strip-html" => strip_html(&text),
+            "lowercase" => text.to_lowercase(),
+            "collapse-whitespace" => collapse_whitespace(&text),
+            _ => text,
+        };
+    }
+
+    text
+}
+
+// queries are normalized with the global rules, since they're matched against every extension
+pub fn normalize_query(query: &str) -> String {
+    let rules = get_indexing_rules().unwrap_or_default();
+    normalize_text(
+        query,
+        &normalize_transforms(&get_effective_rules(&rules, "*")),
+    )
+}
+
+// the rules in `query_rules` in the config directory, for cutting boilerplate out of queries
+// before they're embedded
+//
+// a line is a rule and its value, which is the rest of the line:
+// `prefix TEXT` and `suffix TEXT` drop TEXT from either end of the query,
+// `between START END` keeps only what's between the markers, which can't have spaces in them,
+// and `collapse-whitespace` takes no value
+//
+// values can be quoted to keep spaces at their ends, and lines starting with `#` are comments
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryRule {
+    Prefix(String),
+    Suffix(String),
+    Between(String, String),
+    CollapseWhitespace,
+}
+
+fn unquote(value: &str) -> String {
+    let value = match value.len() > 1 && value.starts_with('"') && value.ends_with('"') {
+        true => &value[1..value.len() - 1],
+        false => value,
+    };
+
+    value
+        .replace("\\n", "\n")
+        .replace("\\t", "\t")
+        .replace("\\r", "\r")
+}
+
+// malformed rules are logged and left out
+pub fn parse_query_rules(contents: &str) -> Vec<QueryRule> {
+    let mut rules = Vec::new();
+    for line in contents.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (kind, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let value = value.trim();
+        let rule = match kind {
+            "prefix" if !value.is_empty() => QueryRule::Prefix(unquote(value)),
+            "suffix" if !value.is_empty() => QueryRule::Suffix(unquote(value)),
+            "between" => match value.split_whitespace().collect::<Vec<_>>()[..] {
+                [start, end] => QueryRule::Between(unquote(start), unquote(end)),
+                _ => {
+                    error!(
+                        "Ignoring query rule without a start and end marker: {}",
+                        line
+                    );
+                    continue;
+                }
+            },
+            "collapse-whitespace" if value.is_empty() => QueryRule::CollapseWhitespace,
+            "prefix" | "suffix" | "collapse-whitespace" => {
+                error!("Ignoring query rule with an invalid value: {}", line);
+                continue;
+            }
+            _ => {
+                error!("Ignoring unknown query rule: {}", line);
+                continue;
+            }
+        };
+
+        rules.push(rule);
+    }
+
+    rules
+}
+
+// no file means no rules
+pub fn get_query_rules() -> Vec<QueryRule> {
+    let path = crate::config::get_config_dir().join("query_rules");
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => parse_query_rules(&contents),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
         Err(e) => {
-            error!("Failed to read from file {}: {:?}", source.filepath, e);
-            return Err(e);
+            error!("error reading query rules from {}: {}", path.display(), e);
+            Vec::new()
+        }
+    }
+}
+
+pub fn preprocess_query(query: &str) -> String {
+    apply_query_rules(query, &get_query_rules())
+}
+
+// the rules go in order, and a query they'd leave empty is left as it was
+pub fn apply_query_rules(query: &str, rules: &[QueryRule]) -> String {
+    let mut prepared = query.trim().to_string();
+    for rule in rules {
+        prepared = match rule {
+            QueryRule::Prefix(prefix) => prepared
+                .strip_prefix(prefix.as_str())
+                .unwrap_or(&prepared)
+                .trim()
+                .to_string(),
+            QueryRule::Suffix(suffix) => prepared
+                .strip_suffix(suffix.as_str())
+                .unwrap_or(&prepared)
+                .trim()
+                .to_string(),
+            QueryRule::Between(start, end) => {
+                let inner = prepared.find(start.as_str()).and_then(|i| {
+                    let rest = &prepared[i + start.len()..];
+                    rest.find(end.as_str())
+                        .map(|j| rest[..j].trim().to_string())
+                });
+
+                inner.unwrap_or(prepared)
+            }
+            QueryRule::CollapseWhitespace => collapse_whitespace(&prepared),
+        };
+    }
+
+    match prepared.trim().is_empty() {
+        true => query.to_string(),
+        false => prepared,
+    }
+}
+
+// drops escape sequences: CSI (`ESC [ ... final`), OSC (`ESC ] ... BEL` or `ESC ] ... ESC \`),
+// and two-character escapes
+fn strip_ansi(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            stripped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    stripped
+}
+
+// drops anything that looks like a tag, comment or doctype,
+// leaving a `<` that doesn't open one alone
+fn strip_html(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('<') {
+        stripped.push_str(&rest[..i]);
+        let tag = &rest[i..];
+
+        let opens_tag = tag[1..]
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '/' || c == '!' || c == '?');
+        match tag.find('>') {
+            Some(end) if opens_tag => rest = &tag[end + 1..],
+            _ => {
+                stripped.push('<');
+                rest = &tag[1..];
+            }
+        }
+    }
+
+    stripped.push_str(rest);
+    stripped
+}
+
+// every run of whitespace becomes a single newline if it spans lines, or a single space if not
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut run: Option<char> = None;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if run != Some('\n') {
+                run = Some(if c == '\n' { '\n' } else { ' ' });
+            }
+
+            continue;
+        }
+
+        if let Some(whitespace) = run.take() {
+            collapsed.push(whitespace);
         }
+
+        collapsed.push(c);
+    }
+
+    if let Some(whitespace) = run {
+        collapsed.push(whitespace);
+    }
+
+    collapsed
+}
+
+// words per shingle in chunk signatures
+const SHINGLE_WORDS: usize = 3;
+
+// how many of the smallest shingle hashes make up a signature
+const SIGNATURE_SHINGLES: usize = 4;
+
+// a cheap signature for spotting near-identical chunks across files
+//
+// the chunk's words are lowercased and hashed in overlapping shingles,
+// and the smallest few shingle hashes are combined into the signature,
+// so chunks that differ in a word or two mostly still collide
+//
+// `None` for chunks without any words
+pub fn chunk_signature(contents: &str) -> Option<u64> {
+    use std::hash::{Hash, Hasher};
+
+    let words = contents
+        .split_whitespace()
+        .map(|w| w.to_lowercase())
+        .collect::<Vec<_>>();
+
+    if words.is_empty() {
+        return None;
+    }
+
+    let mut shingles = words
+        .windows(SHINGLE_WORDS.min(words.len()))
+        .map(|shingle| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            shingle.hash(&mut hasher);
+            hasher.finish()
+        })
+        .collect::<Vec<_>>();
+
+    shingles.sort();
+    shingles.dedup();
+    shingles.truncate(SIGNATURE_SHINGLES);
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    shingles.hash(&mut hasher);
+
+    Some(hasher.finish())
+}
+
+// the source for a file's path embedding
+//
+// the subset is empty since the path isn't part of the file's contents
+pub fn path_source(source: &EmbeddingSource) -> EmbeddingSource {
+    let mut meta = source.meta.clone();
+    meta.insert(PATH_META.to_string());
+
+    EmbeddingSource {
+        filepath: source.filepath.clone(),
+        meta,
+        subset: Some((0, 0)),
+        hash: source.hash.clone(),
+        chunk_hash: None,
+    }
+}
+
+// the source for a file's title embedding, which like the path's has an empty subset
+pub fn title_source(source: &EmbeddingSource) -> EmbeddingSource {
+    let mut meta = source.meta.clone();
+    meta.insert(TITLE_META.to_string());
+
+    EmbeddingSource {
+        filepath: source.filepath.clone(),
+        meta,
+        subset: Some((0, 0)),
+        hash: source.hash.clone(),
+        chunk_hash: None,
+    }
+}
+
+// the title of a file: its first heading for markdown,
+// the first line of a leading docstring or doc comment for anything else,
+// and failing those its name with `-` and `_` turned into spaces
+// e.g. `/home/me/notes/invoice-2023-q3.md` with no heading -> `invoice 2023 q3`
+pub fn file_title(filepath: &str) -> String {
+    let mut head = Vec::new();
+    if let Ok(file) = std::fs::File::open(filepath) {
+        let _ = file.take(TITLE_SEARCH_BYTES).read_to_end(&mut head);
+    }
+
+    let contents = normalize_contents(&head);
+    let title = match get_extension(filepath) {
+        "md" | "markdown" => markdown_title(&contents),
+        _ => doc_title(&contents),
     };
 
-    // TODO: we can easily get away without loading the entire file into memory
-    let contents = match source.subset {
-        Some((start, end)) => contents[start as usize..end as usize].to_string(),
-        _ => contents,
+    let title = title.unwrap_or_else(|| {
+        std::path::Path::new(filepath)
+            .file_stem()
+            .map(|s| s.to_string_lossy().replace(['-', '_'], " "))
+            .unwrap_or_default()
+    });
+
+    title.trim().chars().take(TITLE_LENGTH).collect()
+}
+
+// the first ATX heading, e.g. `# Invoices` -> `Invoices`
+fn markdown_title(contents: &str) -> Option<String> {
+    contents.lines().find_map(|line| {
+        let line = line.trim_start();
+        let text = line.trim_start_matches('#');
+        let level = line.len() - text.len();
+        if !(1..=6).contains(&level) || !(text.is_empty() || text.starts_with([' ', '\t'])) {
+            return None;
+        }
+
+        let title = text.trim().trim_end_matches('#').trim();
+        (!title.is_empty()).then(|| title.to_string())
+    })
+}
+
+// the first line of a docstring or doc comment, if that's what the file opens with
+fn doc_title(contents: &str) -> Option<String> {
+    const MARKERS: [&str; 6] = ["\"\"\"", "'''", "//!", "///", "/**", "/*!"];
+
+    let mut lines = contents.lines().map(str::trim).skip_while(|l| l.is_empty());
+    let first = lines.next()?;
+    let marker = MARKERS.iter().find(|m| first.starts_with(*m))?;
+
+    // a docstring can open on a line of its own
+    let rest = first[marker.len()..].trim_start_matches(['*', '!']);
+    let title = match rest.trim().is_empty() {
+        true => lines
+            .next()?
+            .trim_start_matches(*marker)
+            .trim_start_matches('*'),
+        false => rest,
     };
 
-    // CRLF -> LF
-    let contents = contents.replace("\r\n", "\n");
+    let title = title
+        .trim()
+        .trim_end_matches(*marker)
+        .trim_end_matches("*/")
+        .trim();
+    (!title.is_empty()).then(|| title.to_string())
+}
 
-    Ok(contents)
+// the path of a file relative to the home directory, with separators turned into spaces
+// e.g. `/home/me/notes/invoice-2023-q3.md` -> `notes invoice-2023-q3.md`
+pub fn path_chunk(filepath: &str) -> String {
+    let path = std::path::Path::new(filepath);
+    let home_dir = crate::config::get_home_dir();
+    let relative = path.strip_prefix(&home_dir).unwrap_or(path);
+
+    relative
+        .to_string_lossy()
+        .split(std::path::is_separator)
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
-// TODO: a proper tokenizer
-pub const TOKEN_LIMIT: usize = 8192;
-fn separator_split(
-    source: &EmbeddingSource,
-    separator: &String,
-) -> Result<Vec<(String, (usize, usize))>, std::io::Error> {
-    let contents = read_source(&source)?;
-    let chars = contents.chars().collect::<Vec<char>>();
+// chunk contents paired with their (start, end) offsets in the source file
+type Chunks = Vec<(String, (usize, usize))>;
+
+// chunks with an extra meta tag, e.g. the language of a fenced code block
+type TaggedChunk = (String, (usize, usize), Option<String>);
+type TaggedChunks = Vec<TaggedChunk>;
 
+// splitters take the (normalized) contents to split and the rule's argument
+type SplitFunction = fn(&str, &str) -> Result<Chunks, std::io::Error>;
+
+// every splitter goes through this so that they all agree on chunk boundaries
+//
+// chunks are at most `max_length` bytes and only ever end on a char boundary,
+// and offsets are byte offsets shifted by `offset`,
+// so reading a chunk's subset back out of the source gives exactly the chunk
+fn length_split(contents: &str, max_length: usize, offset: usize) -> Chunks {
     let mut chunks = Vec::new();
-    let mut chunk = String::new();
-    let mut i = 0;
-    while i < chars.len() {
-        let window = String::from_iter(&chars[i..i + separator.len()]);
-        if window == *separator || chunk.len() >= TOKEN_LIMIT {
-            chunks.push((chunk.clone(), (i - chunk.len(), i)));
-            chunk.clear();
-
-            i += separator.len();
-        } else {
-            let c = chars[i].to_string();
-            chunk.push_str(&c);
-            i += c.len();
+    let mut start = 0;
+    for (i, c) in contents.char_indices() {
+        if i > start && i + c.len_utf8() - start > max_length {
+            chunks.push((contents[start..i].to_string(), (start + offset, i + offset)));
+            start = i;
         }
     }
 
-    if !chunk.is_empty() {
+    if start < contents.len() {
         chunks.push((
-            chunk.clone(),
-            (contents.len() - chunk.len(), contents.len()),
+            contents[start..].to_string(),
+            (start + offset, contents.len() + offset),
         ));
     }
 
+    chunks
+}
+
+// the separators themselves are left out of the chunks
+//
+// an empty separator would match between every character, so it's split naively instead
+fn separator_split(contents: &str, separator: &str) -> Result<Chunks, std::io::Error> {
+    if separator.is_empty() {
+        return naive_split(contents, separator);
+    }
+
+    let mut sections = Vec::new();
+    let mut start = 0;
+    for (i, _) in contents.match_indices(separator) {
+        sections.push((start, i));
+        start = i + separator.len();
+    }
+
+    sections.push((start, contents.len()));
+
+    let mut chunks = Vec::new();
+    for (start, end) in sections {
+        if end > start {
+            chunks.extend(length_split(&contents[start..end], TOKEN_LIMIT, start));
+        }
+    }
+
     Ok(chunks)
 }
 
 // this only has a _separator argument so it can be used as a function pointer
-fn naive_split(
-    source: &EmbeddingSource,
-    _separator: &String,
-) -> Result<Vec<(String, (usize, usize))>, std::io::Error> {
-    let source_contents = read_source(&source)?;
-    let chars = source_contents.chars().collect::<Vec<_>>();
+fn naive_split(contents: &str, _separator: &str) -> Result<Chunks, std::io::Error> {
+    Ok(length_split(contents, TOKEN_LIMIT, 0))
+}
 
-    let mut chunks = Vec::new();
-    let mut chunk = String::new();
-    let mut i = 0;
-    while i < chars.len() {
-        if chunk.len() >= TOKEN_LIMIT {
-            chunks.push((chunk.clone(), (i - chunk.len(), i)));
-            chunk.clear();
-            i += 1;
-        } else {
-            let c = chars[i].to_string();
-            chunk.push_str(&c);
-            i += c.len();
+fn max_length_split(contents: &str, max_length: &str) -> Result<Chunks, std::io::Error> {
+    let max_length = max_length.parse::<usize>().unwrap();
+
+    Ok(length_split(
+        contents,
+        std::cmp::min(max_length, TOKEN_LIMIT),
+        0,
+    ))
+}
+
+// how much of a file is read at once when it's split as it's read
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+// the most bytes a `StreamSplit` has held at once, between a chunk being put together
+// and the chunks waiting to be taken
+#[cfg(test)]
+thread_local! {
+    pub static PEAK_SPLIT_BYTES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+// where the end of `bytes` could still normalize differently once more is read:
+// a \r that could be the start of a CRLF, or a UTF-8 sequence that's been cut off
+fn held_back_from(bytes: &[u8]) -> usize {
+    if bytes.last() == Some(&b'\r') {
+        return bytes.len() - 1;
+    }
+
+    for back in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - back];
+        if byte & 0xC0 == 0x80 {
+            continue;
         }
+
+        let width = match byte {
+            0xC2..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF4 => 4,
+            _ => 1,
+        };
+
+        return match width > back {
+            true => bytes.len() - back,
+            false => bytes.len(),
+        };
     }
 
-    if !chunk.is_empty() {
-        chunks.push((
-            chunk.clone(),
-            (source_contents.len() - chunk.len(), source_contents.len()),
-        ));
+    bytes.len()
+}
+
+// a file normalized as it's read, a piece at a time, into the same contents
+// `normalize_with_offsets` would make of the whole of it
+//
+// the offsets are kept from the chunk being split onward, and are in terms of the whole file
+struct NormalizedReader<R: Read> {
+    reader: R,
+    // the end of the last read, held back until the next one says how it normalizes
+    held: Vec<u8>,
+    // how far into the file and into its normalized contents the pieces so far reach
+    raw: usize,
+    normalized: usize,
+    offsets: OffsetMap,
+    done: bool,
+}
+
+impl<R: Read> NormalizedReader<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            held: Vec::new(),
+            raw: 0,
+            normalized: 0,
+            offsets: Vec::new(),
+            done: false,
+        }
     }
 
-    Ok(chunks)
+    // the next piece of the normalized contents, `None` at the end of the file
+    fn next_piece(&mut self) -> Result<Option<String>, std::io::Error> {
+        while !self.done {
+            let mut buffer = std::mem::take(&mut self.held);
+            let start = buffer.len();
+            buffer.resize(start + READ_BUFFER_SIZE, 0);
+
+            let read = match self.reader.read(&mut buffer[start..]) {
+                Ok(read) => read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                    buffer.truncate(start);
+                    self.held = buffer;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            buffer.truncate(start + read);
+            let end = match read {
+                0 => {
+                    self.done = true;
+                    buffer.len()
+                }
+                _ => held_back_from(&buffer),
+            };
+
+            self.held = buffer.split_off(end);
+            if buffer.is_empty() {
+                continue;
+            }
+
+            let (piece, offsets) = normalize_with_offsets(&buffer);
+            self.offsets.extend(
+                offsets
+                    .into_iter()
+                    .map(|(normalized, raw)| (normalized + self.normalized, raw + self.raw)),
+            );
+            self.raw += buffer.len();
+            self.normalized += piece.len();
+
+            return Ok(Some(piece));
+        }
+
+        Ok(None)
+    }
+
+    // drops the offsets that nothing from `offset` onward needs anymore
+    fn forget_before(&mut self, offset: usize) {
+        let needed = self
+            .offsets
+            .partition_point(|(normalized, _)| *normalized <= offset);
+        self.offsets.drain(..needed.saturating_sub(1));
+    }
 }
 
-fn max_length_split(
-    source: &EmbeddingSource,
-    max_length: &String,
-) -> Result<Vec<(String, (usize, usize))>, std::io::Error> {
-    let source_contents = read_source(&source)?;
-    let chars = source_contents.chars().collect::<Vec<_>>();
-    let max_length = max_length.parse::<usize>().unwrap();
+// the chunks of a file, split as it's read into the same chunks `separator_split`
+// (or `length_split`, without a separator) would split the whole of it into,
+// with offsets into the file on disk
+//
+// at most a chunk's worth of the section being split is held at once, along with a read buffer
+struct StreamSplit<R: Read> {
+    reader: NormalizedReader<R>,
+    separator: String,
+    max_length: usize,
+    // the contents read past the last chunk, and where they start in the normalized contents
+    pending: String,
+    pending_start: usize,
+    ready: std::collections::VecDeque<(String, (usize, usize))>,
+}
 
-    let mut chunks = Vec::new();
-    let mut chunk = String::new();
-    let mut i = 0;
-    while i < chars.len() {
-        if chunk.len() >= TOKEN_LIMIT || chunk.len() >= max_length {
-            chunks.push((chunk.clone(), (i - chunk.len(), i)));
-            chunk.clear();
-            i += 1;
-        } else {
-            let c = chars[i].to_string();
-            chunk.push_str(&c);
-            i += c.len();
+impl<R: Read> StreamSplit<R> {
+    fn new(reader: R, separator: &str, max_length: usize) -> Self {
+        Self {
+            reader: NormalizedReader::new(reader),
+            separator: separator.to_string(),
+            max_length,
+            pending: String::new(),
+            pending_start: 0,
+            ready: std::collections::VecDeque::new(),
         }
     }
 
-    if !chunk.is_empty() {
-        chunks.push((
-            chunk.clone(),
-            (source_contents.len() - chunk.len(), source_contents.len()),
+    // moves the first `end` bytes of what's pending out as chunks,
+    // along with the `skip` bytes of separator after them
+    fn take_pending(&mut self, end: usize, skip: usize) {
+        for (chunk, (start, end)) in
+            length_split(&self.pending[..end], self.max_length, self.pending_start)
+        {
+            let raw = (
+                raw_offset(&self.reader.offsets, start),
+                raw_offset(&self.reader.offsets, end),
+            );
+            self.ready.push_back((chunk, raw));
+        }
+
+        self.pending.drain(..end + skip);
+        self.pending_start += end + skip;
+        self.reader.forget_before(self.pending_start);
+    }
+
+    fn push(&mut self, piece: &str) {
+        self.pending.push_str(piece);
+
+        #[cfg(test)]
+        {
+            let held = self.pending.len() + self.ready.iter().map(|c| c.0.len()).sum::<usize>();
+            PEAK_SPLIT_BYTES.with(|peak| peak.set(peak.get().max(held)));
+        }
+
+        // every section ending in what's pending is whole
+        if !self.separator.is_empty() {
+            while let Some(i) = self.pending.find(&self.separator) {
+                self.take_pending(i, self.separator.len());
+            }
+        }
+
+        // a separator could still be starting in the last few bytes,
+        // but every full chunk before them is as it'd be with the whole section
+        while self.pending.len() > self.max_length {
+            let mut end = self.max_length;
+            while !self.pending.is_char_boundary(end) {
+                end -= 1;
+            }
+
+            if end == 0 {
+                end = self.pending.chars().next().map_or(0, |c| c.len_utf8());
+            }
+
+            let certain = (self.pending.len() + 1).saturating_sub(self.separator.len());
+            if end > certain {
+                break;
+            }
+
+            self.take_pending(end, 0);
+        }
+    }
+}
+
+impl<R: Read> Iterator for StreamSplit<R> {
+    type Item = Result<(String, (usize, usize)), std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(chunk) = self.ready.pop_front() {
+                return Some(Ok(chunk));
+            }
+
+            match self.reader.next_piece() {
+                Ok(Some(piece)) => self.push(&piece),
+                Ok(None) if self.pending.is_empty() => return None,
+                Ok(None) => self.take_pending(self.pending.len(), 0),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+// opens `source` to be split as it's read, only reading its subset if it has one
+fn open_source(
+    source: &EmbeddingSource,
+) -> Result<std::io::Take<std::io::BufReader<std::fs::File>>, std::io::Error> {
+    let mut file = match std::fs::File::open(&source.filepath) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Failed to open file: {:?}", e);
+            return Err(e);
+        }
+    };
+
+    let (start, end) = match source.subset {
+        Some((start, end)) => (start, end),
+        None => (0, u64::MAX),
+    };
+
+    if source.subset.is_some() && (start > end || end > file.metadata()?.len()) {
+        error!(
+            "subset {:?} out of bounds for file {}",
+            (start, end),
+            source.filepath
+        );
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "subset out of bounds",
         ));
     }
 
-    Ok(chunks)
+    file.seek(std::io::SeekFrom::Start(start))?;
+    Ok(std::io::BufReader::with_capacity(READ_BUFFER_SIZE, file).take(end - start))
 }
 
 struct FunctionDefinition {
@@ -145,306 +898,2006 @@ struct FunctionDefinition {
     pub end: usize,
 }
 
+// the argument is the extension of the file the contents came from
+//
 // TODO: other languages here
 #[allow(unused_assignments)]
-fn function_split(
-    source: &EmbeddingSource,
-    _max_length: &String,
-) -> Result<Vec<(String, (usize, usize))>, std::io::Error> {
-    let filepath = std::path::PathBuf::from(&source.filepath);
+fn function_split(contents: &str, extension: &str) -> Result<Chunks, std::io::Error> {
     let mut language_fn = None;
     let mut language = "";
-    match filepath.extension() {
-        Some(ext) => match ext.to_str() {
-            Some("rs") => {
-                language = "rust";
-                language_fn = Some(tree_sitter_rust::language());
-            }
-            Some("py") => {
-                language = "python";
-                language_fn = Some(tree_sitter_python::language());
-            }
-            Some("js") => {
-                language = "javascript";
-                language_fn = Some(tree_sitter_javascript::language());
-            }
-            _ => {
-                error!(
-                    "Unsupported file extension {}, using a naive split instead",
-                    ext.to_str().unwrap_or("_empty")
-                );
-                return naive_split(source, _max_length);
-            }
-        },
+    match extension {
+        "rs" => {
+            language = "rust";
+            language_fn = Some(tree_sitter_rust::language());
+        }
+        "py" => {
+            language = "python";
+            language_fn = Some(tree_sitter_python::language());
+        }
+        "js" => {
+            language = "javascript";
+            language_fn = Some(tree_sitter_javascript::language());
+        }
         _ => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Missing file extension",
-            ));
+            error!(
+                "Unsupported file extension {}, using a naive split instead",
+                extension
+            );
+            return naive_split(contents, extension);
         }
     }
 
-    let language_fn = language_fn.unwrap();
+    let language_fn = language_fn.unwrap();
+
+    // TODO: it's probably pretty stupid to initialize
+    //       a separate parser for each call
+    //       i'd imagine there's a much smarter way to go about this
+    let mut parser = tree_sitter::Parser::new();
+    match parser.set_language(&language_fn) {
+        Ok(_) => {}
+        Err(e) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Failed to set language: {}", e),
+            ));
+        }
+    };
+
+    let query = match language {
+        "rust" => tree_sitter::Query::new(
+            &language_fn,
+            r#"
+            (function_item
+                name: (identifier) @func_name
+                parameters: (parameters) @func_params
+                return_type: (type_identifier)? @return_type
+                body: (block)? @func_body
+            ) @func_def
+
+            (function_signature_item
+                name: (identifier) @func_name
+                parameters: (parameters) @func_params
+                return_type: (type_identifier)? @return_type
+            ) @func_def
+            "#,
+        )
+        .unwrap(),
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unsupported language: {}", language),
+            ))
+        }
+    };
+
+    let tree = parser
+        .parse(contents, None)
+        .expect("failed to parse source");
+    let mut query_cursor = tree_sitter::QueryCursor::new();
+    let matches = query_cursor.matches(&query, tree.root_node(), contents.as_bytes());
+
+    let mut definitions = Vec::new();
+    for match_ in matches {
+        let mut definition = FunctionDefinition {
+            definition: String::new(),
+            name: String::new(),
+            begin: 0,
+            end: 0,
+        };
+
+        for capture in match_.captures {
+            let range = capture.node.byte_range();
+            match query.capture_names()[capture.index as usize] {
+                "func_def" => {
+                    definition.definition = contents[range.clone()].to_string();
+                    definition.begin = range.start;
+                    definition.end = range.end;
+                }
+                "func_name" => definition.name = contents[range].to_string(),
+
+                _ => {}
+            }
+        }
+
+        definitions.push(definition);
+    }
+
+    let mut chunks = Vec::new();
+    for definition in definitions {
+        // if the function definition is too big for a single chunk,
+        // we just run a naive split on it
+        //
+        // note that offsets are shifted by `definition.begin`
+        // since they're to be in reference to the file start
+        if definition.definition.len() > TOKEN_LIMIT {
+            chunks.extend(length_split(
+                &definition.definition,
+                TOKEN_LIMIT,
+                definition.begin,
+            ));
+        } else {
+            chunks.push((definition.definition, (definition.begin, definition.end)));
+        }
+    }
+
+    Ok(chunks)
+}
+
+// extensions whose fenced code blocks are split apart from the surrounding prose
+const MARKDOWN_EXTENSIONS: [&str; 2] = ["md", "markdown"];
+
+// a fenced code block
+// `outer` covers the fence lines, while `start` and `end` only cover the code between them
+struct Fence {
+    outer: (usize, usize),
+    start: usize,
+    end: usize,
+    language: Option<String>,
+}
+
+// finds ``` and ~~~ fenced code blocks, line by line
+// a fence left open runs to the end of the contents
+fn find_fences(contents: &str) -> Vec<Fence> {
+    let mut fences = Vec::new();
+
+    // the opening fence's marker (e.g. "````"), line start, code start, and language
+    let mut open: Option<(String, usize, usize, Option<String>)> = None;
+    let mut offset = 0;
+    for line in contents.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+
+        let trimmed = line.trim();
+        match &open {
+            None => {
+                let fence_char = match trimmed.chars().next() {
+                    Some(c) if c == '`' || c == '~' => c,
+                    _ => continue,
+                };
+
+                let marker_len = trimmed.chars().take_while(|c| *c == fence_char).count();
+                if marker_len < 3 {
+                    continue;
+                }
+
+                let language = trimmed[marker_len..]
+                    .split_whitespace()
+                    .next()
+                    .map(|l| l.to_lowercase());
+
+                open = Some((
+                    fence_char.to_string().repeat(marker_len),
+                    line_start,
+                    offset,
+                    language,
+                ));
+            }
+            Some((marker, outer_start, start, language)) => {
+                let fence_char = marker.chars().next().unwrap();
+                if trimmed.len() >= marker.len() && trimmed.chars().all(|c| c == fence_char) {
+                    fences.push(Fence {
+                        outer: (*outer_start, offset),
+                        start: *start,
+                        end: line_start,
+                        language: language.clone(),
+                    });
+
+                    open = None;
+                }
+            }
+        }
+    }
+
+    if let Some((_, outer_start, start, language)) = open {
+        fences.push(Fence {
+            outer: (outer_start, contents.len()),
+            start,
+            end: contents.len(),
+            language,
+        });
+    }
+
+    fences
+}
+
+// the extension `function_split` knows a fence language by
+// only languages with a function query are listed
+fn fence_extension(language: &str) -> Option<&'static str> {
+    match language {
+        "rust" | "rs" => Some("rs"),
+        _ => None,
+    }
+}
+
+// prose is split with the file's own split function, and each fence's code is kept apart from it,
+// tagged with its language and split by function where possible
+//
+// the fence lines themselves are left out of the chunks
+fn split_markdown(
+    contents: &str,
+    split_function: SplitFunction,
+    rule_arg: &str,
+) -> Result<TaggedChunks, std::io::Error> {
+    let shift = |chunks: Chunks, offset: usize, tag: Option<String>| {
+        chunks
+            .into_iter()
+            .map(move |(c, (start, end))| (c, (start + offset, end + offset), tag.clone()))
+    };
+
+    let mut chunks = Vec::new();
+    let mut prose_start = 0;
+    for fence in find_fences(contents) {
+        let prose = &contents[prose_start..fence.outer.0];
+        chunks.extend(shift(split_function(prose, rule_arg)?, prose_start, None));
+        prose_start = fence.outer.1;
+
+        let code = &contents[fence.start..fence.end];
+        let code_chunks = match fence.language.as_deref().and_then(fence_extension) {
+            Some(extension) => match function_split(code, extension) {
+                Ok(code_chunks) if !code_chunks.is_empty() => code_chunks,
+                _ => length_split(code, TOKEN_LIMIT, 0),
+            },
+            None => length_split(code, TOKEN_LIMIT, 0),
+        };
+
+        let tag = fence.language.map(|l| format!("lang:{}", l));
+        chunks.extend(shift(code_chunks, fence.start, tag));
+    }
+
+    let prose = &contents[prose_start..];
+    chunks.extend(shift(split_function(prose, rule_arg)?, prose_start, None));
+
+    Ok(chunks)
+}
+
+// a chunk along with the filter rule that dropped it, if one did
+type SplitChunk = (TaggedChunk, Option<String>);
+
+// a source split up by its indexing rules, a chunk at a time
+struct Split {
+    // the rule that picked the splitter, or `--naive` if none did
+    rule: String,
+    chunks: Box<dyn Iterator<Item = Result<SplitChunk, std::io::Error>>>,
+}
+
+// the splitter a rule picks
+struct Splitter {
+    function: SplitFunction,
+    arg: String,
+    rule: String,
+    // the separator and chunk length a `StreamSplit` splits the same way as `function` with,
+    // if it can
+    stream: Option<(String, usize)>,
+}
+
+// the splitter the last splitting rule in `rules` picks
+fn pick_splitter(rules: &[IndexRule], extension: &str) -> Splitter {
+    let mut picked = Splitter {
+        function: naive_split,
+        arg: "".to_string(),
+        rule: "--naive".to_string(),
+        stream: Some((String::new(), TOKEN_LIMIT)),
+    };
+
+    for rule in rules.iter() {
+        let (function, arg, stream): (SplitFunction, String, _) = match rule.rule_type {
+            IndexRuleType::Split => (
+                separator_split,
+                rule.value.clone(),
+                Some((rule.value.clone(), TOKEN_LIMIT)),
+            ),
+            IndexRuleType::MaxLength => (
+                max_length_split,
+                rule.value.clone(),
+                Some((
+                    String::new(),
+                    std::cmp::min(rule.value.parse::<usize>().unwrap(), TOKEN_LIMIT),
+                )),
+            ),
+            IndexRuleType::Code => (function_split, extension.to_string(), None),
+            _ => continue,
+        };
+
+        picked = Splitter {
+            function,
+            arg,
+            rule: rule.to_string(),
+            stream,
+        };
+    }
+
+    picked
+}
+
+// whether a chunk gets past a filter rule, which every chunk gets past a rule that isn't one
+fn passes_filter(rule: &IndexRule, chunk: &TaggedChunk) -> bool {
+    let (contents, _, _) = chunk;
+    match rule.rule_type {
+        // measured on the normalized contents, since the range is in terms of the file
+        IndexRuleType::MinLength => {
+            let min_length = rule.value.parse::<usize>().unwrap();
+            contents.len() >= min_length
+        }
+        IndexRuleType::Alphanumeric => contents
+            .chars()
+            .any(|c| c.is_alphanumeric() || c.is_whitespace()),
+        _ => true,
+    }
+}
+
+// splits a source into chunks according to the indexing rules for its extension
+//
+// splitting by function or by markdown fence takes the whole file, which is read up front
+// unless it's over `max_parse_bytes`; every other file is split as it's read,
+// as are files too big to read whole, which fall back to their rule's separator or a naive split
+fn split_source(
+    source: &EmbeddingSource,
+    indexing_rules: &std::collections::HashMap<String, Vec<IndexRule>>,
+) -> Result<Split, std::io::Error> {
+    let extension = get_extension(&source.filepath);
+    let rules = get_effective_rules(indexing_rules, extension);
+    let splitter = pick_splitter(&rules, extension);
+    let markdown = MARKDOWN_EXTENSIONS.contains(&extension);
+
+    let size = match source.subset {
+        Some((start, end)) => end.saturating_sub(start),
+        None => std::fs::metadata(&source.filepath)?.len(),
+    };
+
+    let whole = markdown || splitter.stream.is_none();
+    let max_parse_bytes = crate::config::get_max_parse_bytes();
+    let (rule, chunks): (String, Box<dyn Iterator<Item = _>>) =
+        if whole && size <= max_parse_bytes as u64 {
+            let (contents, offsets) = read_normalized(source)?;
+            let split = if markdown {
+                split_markdown(&contents, splitter.function, &splitter.arg)?
+            } else {
+                (splitter.function)(&contents, &splitter.arg)?
+                    .into_iter()
+                    .map(|(c, range)| (c, range, None))
+                    .collect()
+            };
+
+            // the splitters work on the normalized contents, but subsets point into the file
+            let chunks = split
+                .into_iter()
+                .map(|(contents, (start, end), tag)| {
+                    Ok((
+                        contents,
+                        (raw_offset(&offsets, start), raw_offset(&offsets, end)),
+                        tag,
+                    ))
+                })
+                .collect::<Vec<_>>();
+
+            (splitter.rule, Box::new(chunks.into_iter()))
+        } else {
+            let (rule, (separator, max_length)) = match splitter.stream {
+                Some(stream) => (splitter.rule, stream),
+                None => {
+                    info!(
+                        "{} is over max_parse_bytes ({}), splitting it naively as it's read",
+                        source.filepath, max_parse_bytes
+                    );
+                    ("--naive".to_string(), (String::new(), TOKEN_LIMIT))
+                }
+            };
+
+            let chunks = StreamSplit::new(open_source(source)?, &separator, max_length)
+                .map(|chunk| chunk.map(|(contents, range)| (contents, range, None)));
+
+            (rule, Box::new(chunks))
+        };
+
+    // a chunk is dropped by the first filter rule it doesn't get past
+    let chunks = chunks.map(move |chunk: Result<TaggedChunk, std::io::Error>| {
+        chunk.map(|chunk| {
+            let removed_by = rules
+                .iter()
+                .find(|rule| !passes_filter(rule, &chunk))
+                .map(|rule| rule.to_string());
+
+            (chunk, removed_by)
+        })
+    });
+
+    Ok(Split {
+        rule,
+        chunks: Box::new(chunks),
+    })
+}
+
+// polynomial hash over the bytes of a chunk, rolled along the file by `find_chunk`
+const CHUNK_HASH_BASE: u64 = 257;
+
+pub fn chunk_hash(contents: &str) -> u64 {
+    hash_bytes(contents.as_bytes())
+}
+
+// whether `s` is written like a `stable_id`, rather than a path
+pub fn is_stable_id(s: &str) -> bool {
+    s.len() == 36
+        && s.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0u64, |hash, &b| {
+        hash.wrapping_mul(CHUNK_HASH_BASE).wrapping_add(b as u64)
+    })
+}
+
+// the byte offset of a `length` byte window of `contents` hashing to `hash`,
+// the one nearest `near` if several match
+pub fn find_chunk(contents: &str, length: usize, hash: u64, near: usize) -> Option<usize> {
+    let bytes = contents.as_bytes();
+    if length == 0 || length > bytes.len() {
+        return None;
+    }
+
+    // the weight of the byte leaving the window
+    let top = (1..length).fold(1u64, |p, _| p.wrapping_mul(CHUNK_HASH_BASE));
+
+    let mut best: Option<usize> = None;
+    let mut rolling = hash_bytes(&bytes[..length]);
+    for start in 0..=(bytes.len() - length) {
+        if start > 0 {
+            rolling = rolling
+                .wrapping_sub((bytes[start - 1] as u64).wrapping_mul(top))
+                .wrapping_mul(CHUNK_HASH_BASE)
+                .wrapping_add(bytes[start + length - 1] as u64);
+        }
+
+        if rolling == hash
+            && contents.is_char_boundary(start)
+            && contents.is_char_boundary(start + length)
+            && best.is_none_or(|b| start.abs_diff(near) < b.abs_diff(near))
+        {
+            best = Some(start);
+        }
+    }
+
+    best
+}
+
+// the source of a single chunk of `source`, carrying the chunk's tag in its meta
+fn chunk_source(
+    source: &EmbeddingSource,
+    window: (usize, usize),
+    tag: Option<String>,
+    contents: &str,
+) -> EmbeddingSource {
+    let mut chunk = EmbeddingSource {
+        filepath: source.filepath.clone(),
+        meta: source.meta.clone(),
+        subset: Some((window.0 as u64, window.1 as u64)),
+        hash: source.hash.clone(),
+        chunk_hash: Some(chunk_hash(contents)),
+    };
+
+    if let Some(tag) = tag {
+        chunk.meta.insert(tag);
+    }
+
+    chunk
+}
+
+// the chunks `source` is split into under the current indexing rules,
+// as they'd be embedded by `batch_sources`
+pub fn split_chunks(source: &EmbeddingSource) -> Result<Vec<EmbeddingSource>, std::io::Error> {
+    let indexing_rules = get_indexing_rules()?;
+
+    let mut chunks = Vec::new();
+    for chunk in split_source(source, &indexing_rules)?.chunks {
+        let ((contents, window, tag), removed_by) = chunk?;
+        if removed_by.is_none() && !is_blank(&contents) {
+            chunks.push(chunk_source(source, window, tag, &contents));
+        }
+    }
+
+    Ok(chunks)
+}
+
+// the API refuses blank text, and there'd be nothing to match in it anyway
+fn is_blank(contents: &str) -> bool {
+    contents.trim().is_empty()
+}
+
+// the model `filepath` is embedded with, from the last `--model` rule covering it
+pub fn route_model(
+    indexing_rules: &std::collections::HashMap<String, Vec<IndexRule>>,
+    filepath: &str,
+) -> String {
+    get_effective_rules(indexing_rules, get_extension(filepath))
+        .into_iter()
+        .rev()
+        .find(|rule| rule.rule_type == IndexRuleType::Model)
+        .map(|rule| rule.value)
+        .unwrap_or_else(crate::config::get_embedding_model)
+}
+
+// splits a chunk whose embedded text would be over `limit` into sub-chunks that aren't,
+// which happens with chunks of a source that already has a subset from an earlier split,
+// or with transforms that grow the text, like lowercasing some multibyte characters
+//
+// the chunk is read back out of the file to get its offsets,
+// so the sub-chunks' subsets point at exactly their bytes even across a CRLF
+fn cap_chunk(
+    source: &EmbeddingSource,
+    chunk: (String, (usize, usize), Option<String>),
+    transforms: &[&str],
+    limit: usize,
+) -> Result<TaggedChunks, std::io::Error> {
+    let (contents, window, tag) = chunk;
+    if normalize_text(&contents, transforms).len() <= limit {
+        return Ok(vec![(contents, window, tag)]);
+    }
+
+    let (read, offsets) = read_normalized(&EmbeddingSource {
+        subset: Some((window.0 as u64, window.1 as u64)),
+        ..source.clone()
+    })?;
+    if read != contents {
+        error!(
+            "chunk {:?} of {} no longer matches the file, leaving it whole",
+            window, source.filepath
+        );
+        return Ok(vec![(contents, window, tag)]);
+    }
+
+    // a chunk already under the limit is only too big once transformed, so it's halved
+    let max_length = match contents.len() > limit {
+        true => limit,
+        false => contents.len().div_ceil(2),
+    };
+
+    let pieces = length_split(&contents, max_length, 0);
+    if pieces.len() < 2 {
+        return Ok(vec![(contents, window, tag)]);
+    }
+
+    let mut capped = Vec::new();
+    for (piece, (start, end)) in pieces {
+        let piece_window = (
+            window.0 + raw_offset(&offsets, start),
+            window.0 + raw_offset(&offsets, end),
+        );
+
+        capped.extend(cap_chunk(
+            source,
+            (piece, piece_window, tag.clone()),
+            transforms,
+            limit,
+        )?);
+    }
+
+    Ok(capped)
+}
+
+// a chunk as `batch_sources` embeds it
+#[derive(Debug, Clone)]
+pub struct PlannedChunk {
+    pub source: EmbeddingSource,
+    // the text that's embedded, once the normalize rules are applied
+    pub text: String,
+    // the rule that split the chunk off, marked `(capped)` if it was cut down to the token limit
+    pub rule: String,
+    pub tag: Option<String>,
+}
+
+// a chunk that a filter rule, or being blank, kept from being embedded
+#[derive(Debug, Clone)]
+pub struct RemovedChunk {
+    pub subset: (u64, u64),
+    pub length: usize,
+    pub rule: String,
+    pub removed_by: String,
+}
+
+// how a source is embedded: the model, the transforms its text goes through,
+// and what its chunks come out as
+#[derive(Debug, Clone)]
+pub struct ChunkPlan {
+    pub model: String,
+    pub transforms: Vec<&'static str>,
+    pub chunks: Vec<PlannedChunk>,
+    pub removed: Vec<RemovedChunk>,
+}
+
+// the chunks `batch_sources` embeds a source as, planned one at a time as the source is split
+//
+// the chunks left out along the way gather in `removed`
+pub struct PlannedChunks {
+    pub model: String,
+    pub transforms: Vec<&'static str>,
+    pub removed: Vec<RemovedChunk>,
+    // what the model's provider takes, which chunks are capped to
+    max_input_tokens: usize,
+    source: EmbeddingSource,
+    split: Split,
+    // chunks capped out of one that was split off, waiting to be taken
+    ready: std::collections::VecDeque<PlannedChunk>,
+}
+
+// path and title embeddings are made from the path and title alone,
+// and sources that already have a subset are chunks from an earlier split
+pub fn planned_chunks(
+    source: &EmbeddingSource,
+    indexing_rules: &std::collections::HashMap<String, Vec<IndexRule>>,
+) -> Result<PlannedChunks, std::io::Error> {
+    let is_path = source.meta.contains(PATH_META);
+    let is_title = source.meta.contains(TITLE_META);
+
+    // path and title embeddings are left as they are
+    let transforms = match is_path || is_title {
+        true => Vec::new(),
+        false => normalize_transforms(&get_effective_rules(
+            indexing_rules,
+            get_extension(&source.filepath),
+        )),
+    };
+
+    let split = if is_path {
+        Split {
+            rule: "path".to_string(),
+            chunks: Box::new(std::iter::once(Ok((
+                (path_chunk(&source.filepath), (0, 0), None),
+                None,
+            )))),
+        }
+    } else if is_title {
+        Split {
+            rule: "title".to_string(),
+            chunks: Box::new(std::iter::once(Ok((
+                (file_title(&source.filepath), (0, 0), None),
+                None,
+            )))),
+        }
+    } else if let Some((start, end)) = source.subset {
+        Split {
+            rule: "subset".to_string(),
+            chunks: Box::new(std::iter::once(Ok((
+                (read_source(source)?, (start as usize, end as usize), None),
+                None,
+            )))),
+        }
+    } else {
+        split_source(source, indexing_rules)?
+    };
+
+    let model = route_model(indexing_rules, &source.filepath);
+    Ok(PlannedChunks {
+        max_input_tokens: crate::config::get_provider_limits(&model).max_input_tokens,
+        model,
+        transforms,
+        removed: Vec::new(),
+        source: source.clone(),
+        split,
+        ready: std::collections::VecDeque::new(),
+    })
+}
+
+fn removed_chunk(
+    (contents, window, _): &TaggedChunk,
+    rule: &str,
+    removed_by: String,
+) -> RemovedChunk {
+    RemovedChunk {
+        subset: (window.0 as u64, window.1 as u64),
+        length: contents.chars().count(),
+        rule: rule.to_string(),
+        removed_by,
+    }
+}
+
+impl PlannedChunks {
+    // the chunks a split-off chunk is embedded as, once it's capped and blanks are left out
+    fn plan(&mut self, chunk: TaggedChunk) -> Result<(), std::io::Error> {
+        // the splitters keep to the limit, but a chunk can still end up over it
+        let capped = match is_field_source(&self.source) {
+            true => vec![chunk],
+            false => cap_chunk(&self.source, chunk, &self.transforms, self.max_input_tokens)?,
+        };
+
+        let rule = match capped.len() {
+            1 => self.split.rule.clone(),
+            _ => format!("{} (capped)", self.split.rule),
+        };
+
+        for chunk in capped {
+            let text = normalize_text(&chunk.0, &self.transforms);
+            if is_blank(&text) {
+                self.removed
+                    .push(removed_chunk(&chunk, &rule, "blank".to_string()));
+                continue;
+            }
+
+            // the chunk hash stays over the original text, which is what `find_chunk` looks for
+            let (contents, window, tag) = chunk;
+            self.ready.push_back(PlannedChunk {
+                source: chunk_source(&self.source, window, tag.clone(), &contents),
+                text,
+                rule: rule.clone(),
+                tag,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Iterator for PlannedChunks {
+    type Item = Result<PlannedChunk, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(chunk) = self.ready.pop_front() {
+                return Some(Ok(chunk));
+            }
+
+            let (chunk, removed_by) = match self.split.chunks.next()? {
+                Ok(chunk) => chunk,
+                Err(e) => return Some(Err(e)),
+            };
+
+            match removed_by {
+                Some(removed_by) => {
+                    let removed = removed_chunk(&chunk, &self.split.rule, removed_by);
+                    self.removed.push(removed);
+                }
+                None => {
+                    if let Err(e) = self.plan(chunk) {
+                        return Some(Err(e));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// the chunks `batch_sources` embeds `source` as, all at once, without embedding anything
+pub fn plan_chunks(
+    source: &EmbeddingSource,
+    indexing_rules: &std::collections::HashMap<String, Vec<IndexRule>>,
+) -> Result<ChunkPlan, std::io::Error> {
+    let mut planned = planned_chunks(source, indexing_rules)?;
+    let chunks = planned.by_ref().collect::<Result<Vec<_>, _>>()?;
+
+    Ok(ChunkPlan {
+        model: planned.model,
+        transforms: planned.transforms,
+        chunks,
+        removed: planned.removed,
+    })
+}
+
+// whether an embedded chunk is over the `max_input_tokens` of the model of the store in scope,
+// as happens once the model's provider is switched for one with less context
+//
+// a chunk whose bytes already fit isn't read, since normalizing rarely grows text
+pub fn over_input_limit(
+    source: &EmbeddingSource,
+    indexing_rules: &std::collections::HashMap<String, Vec<IndexRule>>,
+) -> Result<bool, std::io::Error> {
+    let limit =
+        crate::config::get_provider_limits(&crate::config::get_embedding_model()).max_input_tokens;
+    let length = match source.subset {
+        Some((start, end)) => (end - start) as usize,
+        None => return Ok(false),
+    };
+
+    if is_field_source(source) || length <= limit {
+        return Ok(false);
+    }
+
+    let transforms = normalize_transforms(&get_effective_rules(
+        indexing_rules,
+        get_extension(&source.filepath),
+    ));
+
+    Ok(normalize_text(&read_source(source)?, &transforms).len() > limit)
+}
+
+// a source that couldn't be read, e.g. a file that went away or lost its permissions,
+// which is left out of an embedding run instead of failing all of it
+//
+// it also goes out as the inner error of an `std::io::Error`,
+// for callers that only embed the one file to tell it apart from other failures
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SkippedSource {
+    pub filepath: String,
+    pub reason: String,
+}
+
+impl SkippedSource {
+    pub fn new(filepath: &str, e: &std::io::Error) -> Self {
+        Self {
+            filepath: filepath.to_string(),
+            reason: e.to_string(),
+        }
+    }
+
+    pub fn into_error(self, kind: std::io::ErrorKind) -> std::io::Error {
+        std::io::Error::new(kind, self)
+    }
+
+    // the skip an error was made from with `into_error`
+    pub fn from_error(e: &std::io::Error) -> Option<&Self> {
+        e.get_ref().and_then(|inner| inner.downcast_ref::<Self>())
+    }
+}
+
+impl std::fmt::Display for SkippedSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "couldn't read {}: {}", self.filepath, self.reason)
+    }
+}
+
+impl std::error::Error for SkippedSource {}
+
+// chunks that go out in a single request, to a single model
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub model: String,
+    pub chunks: Vec<(EmbeddingSource, String)>,
+}
+
+// a provider that takes fewer items than the settings allow has the final say
+fn max_batch_items(
+    settings: &crate::config::EmbedSettings,
+    limits: &mut std::collections::HashMap<String, crate::config::ProviderLimits>,
+    model: &str,
+) -> usize {
+    let limits = *limits
+        .entry(model.to_string())
+        .or_insert_with(|| crate::config::get_provider_limits(model));
+
+    std::cmp::min(settings.max_batch_items, limits.max_batch_items)
+}
+
+// whether a chunk `length` long has to start a new batch
+// rather than going into one that has `items` chunks, `tokens` long between them
+fn batch_full(
+    settings: &crate::config::EmbedSettings,
+    max_batch_items: usize,
+    items: usize,
+    tokens: usize,
+    length: usize,
+) -> bool {
+    length + tokens >= settings.max_batch_tokens || items >= max_batch_items
+}
+
+// how many batches `batch_sources` would make of chunks added in the same order,
+// counted from their lengths alone so that nothing of them has to be held on to
+pub struct BatchCounter<'a> {
+    settings: &'a crate::config::EmbedSettings,
+    // the chunks in each model's open batch
+    open: std::collections::HashMap<String, usize>,
+    limits: std::collections::HashMap<String, crate::config::ProviderLimits>,
+    pub batches: usize,
+}
+
+impl<'a> BatchCounter<'a> {
+    pub fn new(settings: &'a crate::config::EmbedSettings) -> Self {
+        Self {
+            settings,
+            open: std::collections::HashMap::new(),
+            limits: std::collections::HashMap::new(),
+            batches: 0,
+        }
+    }
+
+    // adds the chunks of one source, by their lengths in tokens
+    //
+    // like `Batcher::add_chunks`, only the source's own chunks count toward a batch's tokens
+    pub fn add(&mut self, model: &str, lengths: impl IntoIterator<Item = usize>) {
+        let max_batch_items = max_batch_items(self.settings, &mut self.limits, model);
+        let items = self.open.entry(model.to_string()).or_default();
+
+        let mut tokens = 0;
+        for length in lengths {
+            if *items > 0 && batch_full(self.settings, max_batch_items, *items, tokens, length) {
+                (*items, tokens) = (0, 0);
+            }
+
+            if *items == 0 {
+                self.batches += 1;
+            }
+
+            *items += 1;
+            tokens += length;
+        }
+    }
+}
+
+// batches being filled a source at a time
+struct Batcher<'a> {
+    settings: &'a crate::config::EmbedSettings,
+    batches: Vec<Batch>,
+    // the batch each model's chunks are currently going into
+    open: std::collections::HashMap<String, usize>,
+    // the provider limits of each model, as they're needed
+    limits: std::collections::HashMap<String, crate::config::ProviderLimits>,
+}
+
+impl Batcher<'_> {
+    // adds `chunks` to the open batch for `model` as they come, opening new ones as it fills,
+    // and returns how many were added
+    fn add_chunks(
+        &mut self,
+        model: &str,
+        chunks: impl Iterator<Item = Result<PlannedChunk, std::io::Error>>,
+    ) -> Result<usize, std::io::Error> {
+        let batches = &mut self.batches;
+        let mut current = *self.open.entry(model.to_string()).or_insert_with(|| {
+            batches.push(Batch {
+                model: model.to_string(),
+                chunks: Vec::new(),
+            });
+
+            batches.len() - 1
+        });
+
+        let max_batch_items = max_batch_items(self.settings, &mut self.limits, model);
+
+        let mut added = 0;
+        let mut split_len = 0;
+        for chunk in chunks {
+            let chunk = chunk?;
+            if batch_full(
+                self.settings,
+                max_batch_items,
+                self.batches[current].chunks.len(),
+                split_len,
+                chunk.text.len(),
+            ) {
+                self.batches.push(Batch {
+                    model: model.to_string(),
+                    chunks: Vec::new(),
+                });
+
+                current = self.batches.len() - 1;
+                self.open.insert(model.to_string(), current);
+                split_len = 0;
+            }
+
+            split_len += chunk.text.len();
+            self.batches[current]
+                .chunks
+                .push((chunk.source, chunk.text));
+            added += 1;
+        }
+
+        Ok(added)
+    }
+
+    // where the batches stand, to take back whatever's added after it with `rollback`
+    fn checkpoint(&self) -> BatchCheckpoint {
+        BatchCheckpoint {
+            batches: self.batches.len(),
+            open: self
+                .open
+                .iter()
+                .map(|(model, &i)| (model.clone(), i, self.batches[i].chunks.len()))
+                .collect(),
+        }
+    }
+
+    fn rollback(&mut self, checkpoint: BatchCheckpoint) {
+        self.batches.truncate(checkpoint.batches);
+        self.open.clear();
+        for (model, i, len) in checkpoint.open {
+            self.batches[i].chunks.truncate(len);
+            self.open.insert(model, i);
+        }
+    }
+
+    // splits `source` into the batches as it's read, returning how many chunks it added
+    //
+    // a source that can't be read all the way through is taken back out of the batches
+    fn add(
+        &mut self,
+        source: &EmbeddingSource,
+        indexing_rules: &std::collections::HashMap<String, Vec<IndexRule>>,
+    ) -> Result<usize, std::io::Error> {
+        let mut planned = planned_chunks(source, indexing_rules)?;
+        let model = planned.model.clone();
+
+        let checkpoint = self.checkpoint();
+        let added = self.add_chunks(&model, planned.by_ref());
+        if added.is_err() {
+            self.rollback(checkpoint);
+        }
+
+        added
+    }
+}
+
+// the batches as they were before something was added to them
+struct BatchCheckpoint {
+    batches: usize,
+    // the open batch of each model, and how many chunks it had
+    open: Vec<(String, usize, usize)>,
+}
+
+// a batch is closed off once it reaches either of the limits in `settings`,
+// or the `max_batch_items` of the model's provider if that's lower
+//
+// chunks are capped to the `max_input_tokens` of their model's provider
+//
+// a chunk bigger than `max_batch_tokens` still goes out, in a batch of its own
+//
+// chunks routed to different models never share a batch
+//
+// sources that can't be read are left out, and come back alongside the batches
+//
+// blank chunks are dropped, and a file left with none is skipped, path embedding and all
+//
+// sources are split as they're read, chunks going into batches as they come,
+// so no more than a chunk's worth of a file is held outside the batches
+pub fn batch_sources(
+    sources: &Vec<EmbeddingSource>,
+    settings: &crate::config::EmbedSettings,
+) -> Result<(Vec<Batch>, Vec<SkippedSource>), std::io::Error> {
+    let indexing_rules = get_indexing_rules()?;
+    info!(
+        "batching {} sources with rules: {:?}",
+        sources.len(),
+        indexing_rules
+    );
+
+    // whether a file has anything to embed isn't known until it's split,
+    // so the path and title embeddings of files still to be split wait on them,
+    // and go in ahead of their contents unless there turn out to be none
+    let whole_files = sources
+        .iter()
+        .filter(|source| !is_field_source(source) && source.subset.is_none())
+        .map(|source| source.filepath.as_str())
+        .collect::<std::collections::HashSet<_>>();
+
+    let mut split_files = std::collections::HashSet::new();
+    let mut empty = std::collections::HashSet::new();
+    let mut waiting: std::collections::HashMap<&str, Vec<&EmbeddingSource>> =
+        std::collections::HashMap::new();
+
+    let mut batcher = Batcher {
+        settings,
+        batches: Vec::new(),
+        open: std::collections::HashMap::new(),
+        limits: std::collections::HashMap::new(),
+    };
+
+    let mut skipped = Vec::new();
+    let mut add = |batcher: &mut Batcher, source: &EmbeddingSource| match batcher
+        .add(source, &indexing_rules)
+    {
+        Ok(added) => Some(added),
+        Err(e) => {
+            error!(
+                "skipping {}, which couldn't be read: {}",
+                source.filepath, e
+            );
+            skipped.push(SkippedSource::new(&source.filepath, &e));
+            None
+        }
+    };
+
+    for source in sources {
+        let filepath = source.filepath.as_str();
+        let is_field = is_field_source(source);
+        if is_field {
+            if empty.contains(filepath) {
+                continue;
+            }
+
+            if whole_files.contains(filepath) && !split_files.contains(filepath) {
+                waiting.entry(filepath).or_default().push(source);
+                continue;
+            }
+        }
+
+        if is_field || source.subset.is_some() {
+            add(&mut batcher, source);
+            continue;
+        }
+
+        let checkpoint = batcher.checkpoint();
+        let paths = waiting.remove(filepath).unwrap_or_default();
+        for path in paths.iter() {
+            add(&mut batcher, path);
+        }
+
+        split_files.insert(filepath);
+        if add(&mut batcher, source) == Some(0) {
+            info!("skipping {}, which has nothing to embed", filepath);
+            empty.insert(filepath);
+            batcher.rollback(checkpoint);
+        }
+    }
+
+    let mut batches = batcher.batches;
+    batches.retain(|batch| !batch.chunks.is_empty());
+
+    info!(
+        "batched {} sources into {} batches",
+        sources.len(),
+        batches.len()
+    );
+
+    println!(
+        "batched {} sources into {} batches",
+        sources.len(),
+        batches.len()
+    );
+
+    Ok((batches, skipped))
+}
+
+#[cfg(all(test, feature = "full"))]
+mod tests {
+    use super::*;
+    use crate::test_common::*;
+    use crate::write_file;
+
+    use rand::Rng;
+
+    fn random_unicode_string(len: usize) -> String {
+        let mut rng = rand::thread_rng();
+        (0..len)
+            .map(|_| char::from(rng.gen_range(0x0020..0x007E) as u8))
+            .collect()
+    }
+
+    // ascii-only contents split into back-to-back windows of `length` bytes
+    fn contiguous_windows(contents: &str, length: usize) -> Vec<(String, (usize, usize))> {
+        (0..contents.len())
+            .step_by(length)
+            .map(|start| {
+                let end = std::cmp::min(start + length, contents.len());
+                (contents[start..end].to_string(), (start, end))
+            })
+            .collect()
+    }
+
+    // shared fixture documents covering multibyte text, CRLF line endings,
+    // trailing separators, and contents past the token limit
+    fn fixture_documents() -> Vec<(&'static str, String)> {
+        vec![
+            ("ascii.rs", "fn main() {}\n\nstruct A;\n".repeat(40)),
+            ("multibyte.md", "héllo wörld ✓ 日本語 🦀\n".repeat(700)),
+            ("crlf.txt", "one\r\ntwo\r\n\r\nthree\r\n".repeat(50)),
+            ("trailing.txt", "ends with separators\n\n".to_string()),
+            ("long.txt", "x".repeat(TOKEN_LIMIT * 2 + 17)),
+            ("empty.txt", String::new()),
+        ]
+    }
+
+    // batches stay within the configured item and size limits, and still carry every chunk
+    #[test]
+    fn batch_limits_test() {
+        let _cleanup = Cleanup;
+        assert!(setup().is_ok());
+
+        let target = crate::config::get_home_dir().join("test_repo");
+        let sources = get_tracked_files()
+            .iter()
+            .map(|f| EmbeddingSource {
+                filepath: target.join(f).to_string_lossy().to_string(),
+                meta: std::collections::HashSet::new(),
+                subset: None,
+                hash: String::new(),
+                chunk_hash: None,
+            })
+            .collect::<Vec<_>>();
+
+        let chunks = |batches: &Vec<Batch>| batches.iter().map(|b| b.chunks.len()).sum::<usize>();
+
+        let unlimited = batch_sources(&sources, &crate::config::get_embed_settings())
+            .unwrap()
+            .0;
+
+        write_file!(
+            crate::config::get_config_dir().join("config"),
+            "embed_workers 2\nmax_batch_items 3\nmax_batch_tokens 2048\n"
+        );
+
+        let settings = crate::config::get_embed_settings();
+        assert_eq!(settings.workers, 2);
+
+        let batches = batch_sources(&sources, &settings).unwrap().0;
+        assert!(batches.len() > unlimited.len());
+        assert_eq!(chunks(&batches), chunks(&unlimited));
+        for batch in batches.iter() {
+            assert!(!batch.chunks.is_empty() && batch.chunks.len() <= 3);
+            assert!(batch.chunks.iter().map(|(_, c)| c.len()).sum::<usize>() <= 2048);
+        }
+
+        let embeddings = crate::openai::embed_bulk(&sources).unwrap().0;
+        assert_eq!(
+            embeddings.values().map(|e| e.len()).sum::<usize>(),
+            chunks(&unlimited)
+        );
+    }
+
+    // every splitter has to produce in-order, non-overlapping chunks within the limit
+    // whose subsets read back as exactly the chunk contents
+    #[test]
+    fn split_fixtures_test() {
+        let _cleanup = Cleanup;
+        assert!(setup().is_ok());
+
+        let root = crate::config::get_home_dir();
+        let splitters: Vec<(&str, SplitFunction, &str, usize)> = vec![
+            ("separator", separator_split, "\n", TOKEN_LIMIT),
+            ("separator", separator_split, "\n\n", TOKEN_LIMIT),
+            ("naive", naive_split, "", TOKEN_LIMIT),
+            ("max_length", max_length_split, "7", 7),
+        ];
+
+        for (name, contents) in fixture_documents() {
+            let filepath = root.join(name);
+            write_file!(&filepath, contents);
+
+            let source = EmbeddingSource {
+                filepath: filepath.to_string_lossy().to_string(),
+                meta: std::collections::HashSet::new(),
+                subset: None,
+                hash: String::new(),
+                chunk_hash: None,
+            };
+
+            let (normalized, offsets) = normalize_with_offsets(&std::fs::read(&filepath).unwrap());
+            assert_eq!(normalized, read_source(&source).unwrap());
+
+            for (splitter, split_function, arg, limit) in splitters.iter() {
+                let chunks = split_function(&normalized, arg).unwrap();
+
+                let mut last_end = 0;
+                for (chunk, (start, end)) in chunks.iter() {
+                    assert!(*start >= last_end, "{} on {} overlaps", splitter, name);
+                    assert!(!chunk.is_empty() && chunk.len() <= *limit);
+                    last_end = *end;
+
+                    let subset = read_source(&EmbeddingSource {
+                        subset: Some((
+                            raw_offset(&offsets, *start) as u64,
+                            raw_offset(&offsets, *end) as u64,
+                        )),
+                        hash: String::new(),
+                        chunk_hash: None,
+                        ..source.clone()
+                    })
+                    .unwrap();
+
+                    assert_eq!(
+                        &subset,
+                        chunk,
+                        "{} on {} at {:?}",
+                        splitter,
+                        name,
+                        (start, end)
+                    );
+                }
+
+                // only the separator splitter drops anything from the contents
+                let expected = match *splitter {
+                    "separator" => normalized.replace(arg, ""),
+                    _ => normalized.clone(),
+                };
+
+                let joined = chunks.iter().map(|c| c.0.clone()).collect::<String>();
+                assert_eq!(joined, expected, "{} on {}", splitter, name);
+            }
+        }
+
+        // boundaries never fall inside a char, even when that leaves a chunk short
+        let filepath = root.join("boundaries.txt");
+        write_file!(&filepath, "aé日🦀b");
+        let chunks = max_length_split(
+            &read_source(&EmbeddingSource {
+                filepath: filepath.to_string_lossy().to_string(),
+                meta: std::collections::HashSet::new(),
+                subset: None,
+                hash: String::new(),
+                chunk_hash: None,
+            })
+            .unwrap(),
+            "4",
+        )
+        .unwrap();
+
+        assert_eq!(
+            chunks,
+            vec![
+                ("aé".to_string(), (0, 3)),
+                ("日".to_string(), (3, 6)),
+                ("🦀".to_string(), (6, 10)),
+                ("b".to_string(), (10, 11)),
+            ]
+        );
+    }
+
+    // reads at most a few bytes at a time, to land reads inside CRLFs and multibyte chars
+    struct Trickle<R: Read>(R);
+
+    impl<R: Read> Read for Trickle<R> {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            let length = std::cmp::min(buffer.len(), 3);
+            self.0.read(&mut buffer[..length])
+        }
+    }
+
+    // splitting a file as it's read gives the same chunks as splitting the whole of it
+    #[test]
+    fn stream_split_test() {
+        let _cleanup = Cleanup;
+        assert!(setup().is_ok());
+
+        let root = crate::config::get_home_dir();
+        let mut documents = fixture_documents()
+            .into_iter()
+            .map(|(name, contents)| (name, contents.into_bytes()))
+            .collect::<Vec<_>>();
+        documents.push(("overlapping.txt", "aaXaaaXaaaa".repeat(30).into_bytes()));
+        documents.push(("invalid.txt", b"ab\r\n\xe6\x97\xa5\xff\xe6\x97\r".repeat(9)));
+
+        let splitters: Vec<(SplitFunction, &str, &str, usize)> = vec![
+            (separator_split, "\n", "\n", TOKEN_LIMIT),
+            (separator_split, "\n\n", "\n\n", TOKEN_LIMIT),
+            (separator_split, "aa", "aa", TOKEN_LIMIT),
+            (naive_split, "", "", TOKEN_LIMIT),
+            (max_length_split, "7", "", 7),
+            (max_length_split, "2", "", 2),
+        ];
+
+        for (name, contents) in documents {
+            let filepath = root.join(name);
+            std::fs::write(&filepath, contents).unwrap();
+
+            let (normalized, offsets) = normalize_with_offsets(&std::fs::read(&filepath).unwrap());
+            for (split_function, arg, separator, max_length) in splitters.iter() {
+                let expected = split_function(&normalized, arg)
+                    .unwrap()
+                    .into_iter()
+                    .map(|(chunk, (start, end))| {
+                        (
+                            chunk,
+                            (raw_offset(&offsets, start), raw_offset(&offsets, end)),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+
+                for trickle in [false, true] {
+                    let file = std::fs::File::open(&filepath).unwrap();
+                    let reader: Box<dyn Read> = match trickle {
+                        true => Box::new(Trickle(file)),
+                        false => Box::new(file),
+                    };
+
+                    let streamed = StreamSplit::new(reader, separator, *max_length)
+                        .collect::<Result<Vec<_>, _>>()
+                        .unwrap();
+
+                    assert_eq!(streamed, expected, "{:?} on {}", arg, name);
+                }
+            }
+        }
+    }
+
+    // a file far bigger than a chunk is split without holding much more than a chunk of it
+    #[test]
+    fn stream_large_file_test() {
+        let _cleanup = Cleanup;
+        assert!(setup().is_ok());
+
+        let size = 100 * 1024 * 1024;
+        let filepath = crate::config::get_home_dir().join("large.txt");
+        std::fs::File::create(&filepath)
+            .unwrap()
+            .set_len(size as u64)
+            .unwrap();
+
+        let source = EmbeddingSource {
+            filepath: filepath.to_string_lossy().to_string(),
+            meta: std::collections::HashSet::new(),
+            subset: None,
+            hash: String::new(),
+            chunk_hash: None,
+        };
+
+        PEAK_SPLIT_BYTES.with(|peak| peak.set(0));
+
+        let split = split_source(&source, &std::collections::HashMap::new()).unwrap();
+        assert_eq!(split.rule, "--naive");
+
+        let mut count = 0;
+        let mut last_end = 0;
+        for chunk in split.chunks {
+            let ((chunk, (start, end), _), removed_by) = chunk.unwrap();
+            assert_eq!(removed_by, None);
+            assert_eq!((start, end - start), (last_end, chunk.len()));
+            last_end = end;
+            count += 1;
+        }
+
+        assert_eq!(last_end, size);
+        assert_eq!(count, size.div_ceil(TOKEN_LIMIT));
+
+        let peak = PEAK_SPLIT_BYTES.with(|peak| peak.get());
+        assert!(
+            peak > 0 && peak <= TOKEN_LIMIT + READ_BUFFER_SIZE,
+            "{}",
+            peak
+        );
+    }
+
+    // code files over `max_parse_bytes` are split naively as they're read instead of by function
+    #[test]
+    fn max_parse_bytes_test() {
+        let _cleanup = Cleanup;
+        assert!(setup().is_ok());
+
+        let contents = "fn first() {\n    1;\n}\n\nfn second() {\n    2;\n}\n".repeat(4);
+        let filepath = crate::config::get_home_dir().join("functions.rs");
+        write_file!(&filepath, contents.clone());
+
+        let source = EmbeddingSource {
+            filepath: filepath.to_string_lossy().to_string(),
+            meta: std::collections::HashSet::new(),
+            subset: None,
+            hash: String::new(),
+            chunk_hash: None,
+        };
+
+        let mut indexing_rules = std::collections::HashMap::new();
+        indexing_rules.insert(
+            "rs".to_string(),
+            vec![IndexRule {
+                rule_type: IndexRuleType::Code,
+                value: String::new(),
+            }],
+        );
+
+        let split = |indexing_rules| {
+            let split = split_source(&source, indexing_rules).unwrap();
+            let chunks = split
+                .chunks
+                .map(|chunk| chunk.unwrap().0 .0)
+                .collect::<Vec<_>>();
+
+            (split.rule, chunks)
+        };
+
+        let (rule, chunks) = split(&indexing_rules);
+        assert!(rule.starts_with("--code"));
+        assert_eq!(chunks.len(), 8);
+
+        write_file!(
+            crate::config::get_config_dir().join("config"),
+            format!("max_parse_bytes {}\n", contents.len() - 1)
+        );
+
+        let (rule, chunks) = split(&indexing_rules);
+        assert_eq!(rule, "--naive");
+        assert_eq!(chunks, vec![contents]);
+    }
+
+    // subsets of a CRLF file are offsets into the file on disk,
+    // and reading one back gives exactly the text that was embedded for it
+    #[test]
+    fn crlf_subset_test() {
+        let _cleanup = Cleanup;
+        assert!(setup().is_ok());
 
-    // TODO: it's probably pretty stupid to initialize
-    //       a separate parser for each call
-    //       i'd imagine there's a much smarter way to go about this
-    let mut parser = tree_sitter::Parser::new();
-    match parser.set_language(&language_fn) {
-        Ok(_) => {}
-        Err(e) => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Failed to set language: {}", e),
-            ));
-        }
-    };
+        let filepath = crate::config::get_home_dir().join("crlf_subsets.txt");
+        let mut raw = "one\r\ntwo\r\n\r\nthree\r\n".repeat(60).into_bytes();
+        raw.extend_from_slice(b"invalid \xff\xfe bytes\r\n");
+        raw.extend_from_slice("héllo\r\nwörld\r\n".repeat(40).as_bytes());
+        write_file!(&filepath, &raw);
+
+        let source = EmbeddingSource {
+            filepath: filepath.to_string_lossy().to_string(),
+            meta: std::collections::HashSet::new(),
+            subset: None,
+            hash: String::new(),
+            chunk_hash: None,
+        };
 
-    let query = match language {
-        "rust" => tree_sitter::Query::new(
-            &language_fn,
-            r#"
-            (function_item
-                name: (identifier) @func_name
-                parameters: (parameters) @func_params
-                return_type: (type_identifier)? @return_type
-                body: (block)? @func_body
-            ) @func_def
+        let normalized = read_source(&source).unwrap();
+        assert_eq!(
+            normalized,
+            String::from_utf8_lossy(&raw).replace("\r\n", "\n")
+        );
 
-            (function_signature_item
-                name: (identifier) @func_name
-                parameters: (parameters) @func_params
-                return_type: (type_identifier)? @return_type
-            ) @func_def
-            "#,
-        )
-        .unwrap(),
-        _ => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Unsupported language: {}", language),
-            ))
-        }
-    };
+        let rules_path = crate::config::get_config_dir().join("rules");
+        let rules = std::fs::read_to_string(&rules_path).unwrap();
+        for rule in ["", "txt --maxlength 7"] {
+            write_file!(&rules_path, format!("{}\n{}", rules, rule));
+
+            let batches =
+                batch_sources(&vec![source.clone()], &crate::config::get_embed_settings())
+                    .unwrap()
+                    .0;
+            let chunks = batches
+                .into_iter()
+                .flat_map(|b| b.chunks)
+                .collect::<Vec<_>>();
+            assert!(chunks.len() > 1);
+
+            for (chunk, embedded) in chunks.iter() {
+                let (start, end) = chunk.subset.unwrap();
+                assert_eq!(&read_source(chunk).unwrap(), embedded, "{:?}", (start, end));
+                assert_eq!(
+                    &normalize_contents(&raw[start as usize..end as usize]),
+                    embedded
+                );
+                assert_eq!(chunk.chunk_hash, Some(chunk_hash(embedded)));
+            }
 
-    let contents = match read_source(source) {
-        Ok(contents) => contents,
-        Err(e) => {
-            error!("error reading file {}", source.filepath);
-            return Err(e);
+            let joined = chunks.iter().map(|(_, c)| c.clone()).collect::<String>();
+            assert_eq!(joined, normalized, "{}", rule);
+            assert_eq!(chunks.last().unwrap().0.subset.unwrap().1, raw.len() as u64);
         }
-    };
 
-    let tree = parser
-        .parse(&contents, None)
-        .expect("failed to parse source");
-    let mut query_cursor = tree_sitter::QueryCursor::new();
-    let matches = query_cursor.matches(&query, tree.root_node(), contents.as_bytes());
+        assert!(read_source(&EmbeddingSource {
+            subset: Some((0, raw.len() as u64 + 1)),
+            ..source.clone()
+        })
+        .is_err());
+    }
 
-    let mut definitions = Vec::new();
-    for match_ in matches {
-        let mut definition = FunctionDefinition {
-            definition: String::new(),
-            name: String::new(),
-            begin: 0,
-            end: 0,
+    // lines are counted in bytes read up to the subset, whatever characters are before it,
+    // and a subset past the end of a file that shrank is cut off there
+    #[test]
+    fn read_lines_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let filepath = crate::config::get_home_dir()
+            .join("test_repo")
+            .join("lines.txt");
+        let contents = "héllo wörld\r\nλ\n\n日本語のテキスト\nlast line";
+        write_file!(&filepath, contents);
+
+        let source = |subset: Option<(u64, u64)>| EmbeddingSource {
+            filepath: filepath.to_string_lossy().to_string(),
+            meta: std::collections::HashSet::new(),
+            subset,
+            hash: String::new(),
+            chunk_hash: None,
         };
+        let offset = |text: &str| contents.find(text).unwrap() as u64;
+
+        assert_eq!(read_lines(&source(None)).unwrap().unwrap().0, (1, 5));
+
+        let start = offset("日本語");
+        let end = offset("last");
+        let (lines, text) = read_lines(&source(Some((start, end)))).unwrap().unwrap();
+        assert_eq!(lines, (4, 4));
+        assert_eq!(text, "日本語のテキスト\n");
+
+        let (lines, text) = read_lines(&source(Some((0, offset("λ") + 3))))
+            .unwrap()
+            .unwrap();
+        assert_eq!(lines, (1, 2));
+        assert_eq!(text, "héllo wörld\nλ\n");
+
+        // the file's cut down to its first two lines
+        write_file!(&filepath, "héllo wörld\r\nλ\n");
+        let (lines, text) = read_lines(&source(Some((offset("λ"), end))))
+            .unwrap()
+            .unwrap();
+        assert_eq!(lines, (2, 2));
+        assert_eq!(text, "λ\n");
+        assert!(read_lines(&source(Some((start, end)))).unwrap().is_none());
+
+        std::fs::remove_file(&filepath).unwrap();
+        assert!(read_lines(&source(Some((start, end)))).is_err());
+    }
 
-        for capture in match_.captures {
-            let range = capture.node.byte_range();
-            match query.capture_names()[capture.index as usize] {
-                "func_def" => {
-                    definition.definition = contents[range.clone()].to_string();
-                    definition.begin = range.start;
-                    definition.end = range.end;
-                }
-                "func_name" => definition.name = contents[range].to_string(),
+    // a 50 KB line without separators, as a chunk left over from an earlier split
+    // and as text that lowercasing grows past the limit
+    #[test]
+    fn oversized_chunk_test() {
+        let _cleanup = Cleanup;
+        assert!(setup().is_ok());
 
-                _ => {}
+        let filepath = crate::config::get_home_dir().join("single_line.txt");
+        let mut contents = "İstanbul ünïcödé 日本語 🦀 ".repeat(50 * 1024 / 40);
+        contents.push_str("\r\nend");
+        write_file!(&filepath, &contents);
+
+        let whole = EmbeddingSource {
+            filepath: filepath.to_string_lossy().to_string(),
+            meta: std::collections::HashSet::new(),
+            subset: Some((0, contents.len() as u64)),
+            hash: String::new(),
+            chunk_hash: None,
+        };
+        let source = EmbeddingSource {
+            subset: None,
+            ..whole.clone()
+        };
+
+        let rules_path = crate::config::get_config_dir().join("rules");
+        let rules = std::fs::read_to_string(&rules_path).unwrap();
+        for (source, rule, transforms) in [
+            (&whole, "", vec![]),
+            (&source, "txt --normalize lowercase", vec!["lowercase"]),
+        ] {
+            write_file!(&rules_path, format!("{}\n{}", rules, rule));
+
+            let chunks = batch_sources(&vec![source.clone()], &crate::config::get_embed_settings())
+                .unwrap()
+                .0
+                .into_iter()
+                .flat_map(|b| b.chunks)
+                .collect::<Vec<_>>();
+            assert!(chunks.len() > 1);
+
+            let mut end = 0;
+            for (chunk, embedded) in chunks.iter() {
+                assert!(embedded.len() <= TOKEN_LIMIT, "{} bytes", embedded.len());
+
+                let subset = chunk.subset.unwrap();
+                assert_eq!(subset.0, end);
+                end = subset.1;
+
+                let original = read_source(chunk).unwrap();
+                assert_eq!(&normalize_text(&original, &transforms), embedded);
+                assert_eq!(chunk.chunk_hash, Some(chunk_hash(&original)));
             }
+            assert_eq!(end, contents.len() as u64);
         }
+    }
 
-        definitions.push(definition);
+    #[test]
+    fn normalize_text_test() {
+        assert_eq!(
+            normalize_text("a  \t b\n\n    c   ", &["collapse-whitespace"]),
+            "a b\nc "
+        );
+        assert_eq!(
+            normalize_text("\x1b[1;31mred\x1b[0m \x1b]0;title\x07done", &["strip-ansi"]),
+            "red done"
+        );
+        assert_eq!(
+            normalize_text("<p class=\"x\">1 < 2</p><!-- c -->", &["strip-html"]),
+            "1 < 2"
+        );
+        assert_eq!(
+            normalize_text("<B>Hello</B>   World", &NORMALIZE_TRANSFORMS),
+            "hello world"
+        );
     }
 
-    let mut chunks = Vec::new();
-    for definition in definitions {
-        // if the function definition is too big for a single chunk,
-        // we just run a naive split on it
-        //
-        // note that we add `definition.begin` to each index position
-        // since they're to be in reference to the file start
-        if definition.definition.len() >= TOKEN_LIMIT {
-            let chars = definition.definition.chars().collect::<Vec<_>>();
-            let mut chunk = String::new();
-            let mut i = 0;
-            while i < chars.len() {
-                if chunk.len() >= TOKEN_LIMIT {
-                    chunks.push((
-                        chunk.clone(),
-                        (i - chunk.len() + definition.begin, i + definition.begin),
-                    ));
-                    chunk.clear();
-                    i += 1;
-                } else {
-                    let c = chars[i].to_string();
-                    chunk.push_str(&c);
-                    i += c.len();
-                }
-            }
+    #[test]
+    fn file_title_test() {
+        let _cleanup = Cleanup;
+        assert!(setup().is_ok());
 
-            if !chunk.is_empty() {
-                chunks.push((
-                    chunk.clone(),
-                    (
-                        definition.definition.len() - chunk.len() + definition.begin,
-                        definition.definition.len() + definition.begin,
-                    ),
-                ));
-            }
-        } else {
-            chunks.push((definition.definition, (definition.begin, definition.end)));
-        }
+        let root = crate::config::get_home_dir();
+        let title = |name: &str, contents: &str| {
+            let filepath = root.join(name);
+            write_file!(&filepath, contents);
+            file_title(&filepath.to_string_lossy())
+        };
+
+        assert_eq!(
+            title(
+                "notes.md",
+                "intro\n#hashtag\n\n## Quarterly invoices ##\n# Later"
+            ),
+            "Quarterly invoices"
+        );
+        assert_eq!(
+            title("lib.rs", "\n//! Block storage for embeddings\n//! more"),
+            "Block storage for embeddings"
+        );
+        assert_eq!(
+            title("tool.py", "\"\"\"\n    Sync the ledger.\n\"\"\"\nimport os"),
+            "Sync the ledger."
+        );
+        assert_eq!(title("one.py", "'''Parse rules.'''"), "Parse rules.");
+        assert_eq!(
+            title("api.c", "/**\n * Client for the API\n */"),
+            "Client for the API"
+        );
+
+        // files without a heading or leading doc comment fall back on their name
+        assert_eq!(
+            title("invoice-2023_q3.md", "no heading here"),
+            "invoice 2023 q3"
+        );
+        assert_eq!(title("main.rs", "fn main() {}\n/// later"), "main");
+        assert_eq!(
+            file_title(&root.join("missing.rs").to_string_lossy()),
+            "missing"
+        );
     }
 
-    Ok(chunks)
-}
+    #[test]
+    fn query_rules_test() {
+        let _cleanup = Cleanup;
+        assert!(setup().is_ok());
 
-pub fn batch_sources(
-    sources: &Vec<EmbeddingSource>,
-) -> Result<Vec<Vec<(EmbeddingSource, String)>>, std::io::Error> {
-    let indexing_rules = get_indexing_rules()?;
-    let base = Vec::new();
-    let global_rules = indexing_rules.get("*").unwrap_or(&base);
-    info!(
-        "batching {} sources with rules: {:?}",
-        sources.len(),
-        indexing_rules
-    );
+        let rules = parse_query_rules(
+            "# agent boilerplate\n\
+             prefix Answer using the context:\n\
+             suffix \" Be brief.\"\n\
+             between <question> </question>\n\
+             collapse-whitespace\n\
+             between <only-one>\n\
+             prefix\n\
+             collapse-whitespace now\n\
+             strip everything\n",
+        );
+        assert_eq!(
+            rules,
+            vec![
+                QueryRule::Prefix("Answer using the context:".to_string()),
+                QueryRule::Suffix(" Be brief.".to_string()),
+                QueryRule::Between("<question>".to_string(), "</question>".to_string()),
+                QueryRule::CollapseWhitespace,
+            ]
+        );
 
-    // API requests need batched up to keep from exceeding token limits
-    let mut batches: Vec<Vec<(EmbeddingSource, String)>> = vec![Vec::new()];
-    for source in sources {
-        let extension = match source.filepath.split(".").last() {
-            Some(extension) => extension,
-            _ => {
-                error!("Failed to get extension from file: {:?}", source.filepath);
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    "Failed to get extension from file",
-                ));
-            }
+        assert_eq!(
+            apply_query_rules(
+                "Answer using the context: how do  blocks\n work? Be brief.",
+                &rules
+            ),
+            "how do blocks\nwork?"
+        );
+        assert_eq!(
+            apply_query_rules(
+                "context <question> where is the index? </question> thanks",
+                &rules
+            ),
+            "where is the index?"
+        );
+        assert_eq!(
+            apply_query_rules("no boilerplate", &rules),
+            "no boilerplate"
+        );
+
+        // nothing's left of a query that's all boilerplate, so it's kept whole
+        assert_eq!(
+            apply_query_rules("Answer using the context:", &rules),
+            "Answer using the context:"
+        );
+    }
+
+    // normalized chunks are smaller in their batches, but their subsets still point at the file
+    #[test]
+    fn normalize_rules_test() {
+        let _cleanup = Cleanup;
+        assert!(setup().is_ok());
+
+        let filepath = crate::config::get_home_dir().join("indented.txt");
+        let contents = (0..200)
+            .map(|i| format!("{}line {}\n", " ".repeat(i % 40), i))
+            .collect::<String>();
+        write_file!(&filepath, &contents);
+
+        let source = EmbeddingSource {
+            filepath: filepath.to_string_lossy().to_string(),
+            meta: std::collections::HashSet::new(),
+            subset: None,
+            hash: String::new(),
+            chunk_hash: None,
         };
 
-        let mut rules = global_rules.clone();
-        if let Some(extension_rules) = indexing_rules.get(extension) {
-            rules.extend(extension_rules.clone());
-        }
-
-        let mut rule_arg = "".to_string();
-        let split_function: fn(
-            &EmbeddingSource,
-            &String,
-        ) -> Result<Vec<(String, (usize, usize))>, std::io::Error> = {
-            let mut rule_type = "".to_string();
-            for rule in rules.iter() {
-                match rule.rule_type {
-                    IndexRuleType::Split => {
-                        rule_arg = rule.value.clone();
-                        rule_type = "separator".to_string();
-                    }
-                    IndexRuleType::MaxLength => {
-                        rule_arg = rule.value.clone();
-                        rule_type = "max_length".to_string();
-                    }
-                    IndexRuleType::Code => {
-                        rule_type = "code".to_string();
-                    }
-                    _ => (),
-                }
-            }
+        let batched = || {
+            batch_sources(&vec![source.clone()], &crate::config::get_embed_settings())
+                .unwrap()
+                .0
+                .into_iter()
+                .flat_map(|b| b.chunks)
+                .collect::<Vec<_>>()
+        };
 
-            match rule_type.as_str() {
-                "separator" => separator_split,
-                "max_length" => max_length_split,
-                "code" => function_split,
-                _ => naive_split,
-            }
+        let batch_len = |chunks: &Vec<(EmbeddingSource, String)>| {
+            chunks.iter().map(|(_, c)| c.len()).sum::<usize>()
         };
 
-        let mut contents_split = split_function(&source, &rule_arg)?;
+        let rules_path = crate::config::get_config_dir().join("rules");
+        let rules = std::fs::read_to_string(&rules_path).unwrap();
 
-        // there's probably a better way to apply these filters
-        // in conjunction with the splitters
-        for rule in rules {
-            match rule.rule_type {
-                IndexRuleType::MinLength => {
-                    let min_length = rule.value.parse::<usize>().unwrap();
-                    contents_split.retain(|(_, range)| range.1 - range.0 >= min_length);
-                }
-                IndexRuleType::Alphanumeric => {
-                    contents_split.retain(|(contents, _)| {
-                        contents
-                            .chars()
-                            .any(|c| c.is_alphanumeric() || c.is_whitespace())
-                    });
-                }
-                _ => (),
-            }
+        let plain = batched();
+
+        // invalid transforms leave the rule out entirely
+        write_file!(
+            &rules_path,
+            format!("{}\ntxt --normalize collapse-whitespace,bogus", rules)
+        );
+        assert!(!get_indexing_rules().unwrap()["txt"]
+            .iter()
+            .any(|r| r.rule_type == IndexRuleType::Normalize));
+        assert_eq!(
+            batched().into_iter().map(|(_, c)| c).collect::<Vec<_>>(),
+            plain.iter().map(|(_, c)| c.clone()).collect::<Vec<_>>()
+        );
+
+        write_file!(
+            &rules_path,
+            format!("{}\ntxt --normalize collapse-whitespace", rules)
+        );
+        let collapsed = batched();
+        assert!(batch_len(&collapsed) < batch_len(&plain));
+
+        for (chunk, embedded) in collapsed.iter() {
+            let original = read_source(chunk).unwrap();
+            assert_eq!(chunk.chunk_hash, Some(chunk_hash(&original)));
+            assert_eq!(
+                embedded,
+                &normalize_text(&original, &["collapse-whitespace"])
+            );
+            assert!(!embedded.contains("  "));
         }
 
-        let mut split = batches.last_mut().unwrap();
-        let mut split_len = 0;
-        for (contents, window) in contents_split {
-            if contents.len() + split_len >= TOKEN_LIMIT {
-                batches.push(Vec::new());
+        assert_eq!(
+            collapsed.iter().map(|(c, _)| c.subset).collect::<Vec<_>>(),
+            plain.iter().map(|(c, _)| c.subset).collect::<Vec<_>>()
+        );
 
-                split = batches.last_mut().unwrap();
-                split_len = 0;
-            }
+        // queries only pick up the global rules
+        assert_eq!(normalize_query("Some   Query"), "Some   Query");
+        write_file!(
+            &rules_path,
+            rules.replace(
+                "* --minlength 0",
+                "* --normalize lowercase,collapse-whitespace --minlength 0"
+            )
+        );
+        assert_eq!(normalize_query("Some   Query"), "some query");
+    }
 
-            if contents.len() > 0 {
-                split_len += contents.len();
-                let new_source = EmbeddingSource {
-                    filepath: source.filepath.clone(),
-                    meta: source.meta.clone(),
-                    subset: Some((window.0 as u64, window.1 as u64)),
-                };
-                split.push((new_source, contents));
-            }
+    // plans keep the chunks batches are made of, and say what happened to the rest
+    #[test]
+    fn plan_chunks_test() {
+        let _cleanup = Cleanup;
+        assert!(setup().is_ok());
+
+        let filepath = crate::config::get_home_dir().join("notes.plan");
+        let contents = "Alpha Beta\n\nhi\n\n        \n\nGamma Delta\n";
+        write_file!(&filepath, contents);
+
+        let rules_path = crate::config::get_config_dir().join("rules");
+        let rules = std::fs::read_to_string(&rules_path).unwrap();
+        write_file!(
+            &rules_path,
+            format!(
+                "{}\nplan --split \\n\\n --minlength 5 --normalize lowercase",
+                rules
+            )
+        );
+
+        let source = EmbeddingSource {
+            filepath: filepath.to_string_lossy().to_string(),
+            meta: std::collections::HashSet::new(),
+            subset: None,
+            hash: String::new(),
+            chunk_hash: None,
+        };
+
+        let plan = plan_chunks(&source, &get_indexing_rules().unwrap()).unwrap();
+        let text = |subset: (u64, u64)| contents[subset.0 as usize..subset.1 as usize].trim();
+
+        assert_eq!(plan.transforms, vec!["lowercase"]);
+        assert_eq!(
+            plan.chunks
+                .iter()
+                .map(|c| text(c.source.subset.unwrap()))
+                .collect::<Vec<_>>(),
+            vec!["Alpha Beta", "Gamma Delta"]
+        );
+        for chunk in plan.chunks.iter() {
+            assert_eq!(chunk.rule, "--split \"\\n\\n\"");
+            assert_eq!(
+                chunk.text.trim(),
+                text(chunk.source.subset.unwrap()).to_lowercase()
+            );
         }
-    }
 
-    batches.retain(|batch| !batch.is_empty());
+        let mut removed = plan.removed.clone();
+        removed.sort_by_key(|c| c.subset);
+        assert_eq!(removed.len(), 2);
+        assert_eq!(text(removed[0].subset), "hi");
+        assert_eq!(removed[0].removed_by, "--minlength \"5\"");
+        assert_eq!(text(removed[1].subset), "");
+        assert_eq!(removed[1].removed_by, "blank");
+
+        // batches are made of exactly the planned chunks
+        let batched = batch_sources(&vec![source.clone()], &crate::config::get_embed_settings())
+            .unwrap()
+            .0
+            .into_iter()
+            .flat_map(|b| b.chunks)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            batched
+                .iter()
+                .map(|(c, t)| (c.subset, t.clone()))
+                .collect::<Vec<_>>(),
+            plan.chunks
+                .iter()
+                .map(|c| (c.source.subset, c.text.clone()))
+                .collect::<Vec<_>>()
+        );
+    }
 
-    info!(
-        "batched {} sources into {} batches",
-        sources.len(),
-        batches.len()
-    );
+    // fenced code is split out of the prose, tagged with its language,
+    // and the fence lines never land in a chunk
+    #[test]
+    fn markdown_fences_test() {
+        let _cleanup = Cleanup;
+        assert!(setup().is_ok());
 
-    println!(
-        "batched {} sources into {} batches",
-        sources.len(),
-        batches.len()
-    );
+        let contents = [
+            "# Usage\n\nSome prose.\n\n",
+            "```Rust ignore\nfn first() {\n    1;\n}\n\nfn second() -> u8 {\n    2\n}\n```\n",
+            "\nMore prose.\n\n",
+            "~~~python\ndef third():\n    pass\n~~~\n",
+            "\nLast words.\n\n````\nunclosed ```\n",
+        ]
+        .concat();
+
+        let filepath = crate::config::get_home_dir().join("README.md");
+        write_file!(&filepath, contents.clone());
+
+        let source = EmbeddingSource {
+            filepath: filepath.to_string_lossy().to_string(),
+            meta: std::collections::HashSet::new(),
+            subset: None,
+            hash: String::new(),
+            chunk_hash: None,
+        };
 
-    Ok(batches)
-}
+        let mut indexing_rules = std::collections::HashMap::new();
+        indexing_rules.insert(
+            "md".to_string(),
+            vec![IndexRule {
+                rule_type: IndexRuleType::Split,
+                value: "\n\n".to_string(),
+            }],
+        );
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::test_common::*;
-    use crate::write_file;
+        let chunks = split_source(&source, &indexing_rules)
+            .unwrap()
+            .chunks
+            .map(|chunk| chunk.unwrap().0)
+            .collect::<Vec<_>>();
+
+        let expected = |chunk: &str, tag: Option<&str>| {
+            let start = contents.find(chunk).unwrap();
+            (
+                chunk.to_string(),
+                (start, start + chunk.len()),
+                tag.map(|t| t.to_string()),
+            )
+        };
 
-    use rand::Rng;
+        assert_eq!(
+            chunks,
+            vec![
+                expected("# Usage", None),
+                expected("Some prose.", None),
+                expected("fn first() {\n    1;\n}", Some("lang:rust")),
+                expected("fn second() -> u8 {\n    2\n}", Some("lang:rust")),
+                expected("\nMore prose.", None),
+                expected("def third():\n    pass\n", Some("lang:python")),
+                expected("\nLast words.", None),
+                expected("unclosed ```\n", None),
+            ]
+        );
 
-    fn random_unicode_string(len: usize) -> String {
-        let mut rng = rand::thread_rng();
-        (0..len)
-            .map(|_| char::from(rng.gen_range(0x0020..0x007E) as u8))
-            .collect()
+        for (chunk, (start, end), _) in chunks.iter() {
+            assert_eq!(&contents[*start..*end], chunk);
+        }
     }
 
     #[test]
@@ -479,12 +2932,15 @@ mod tests {
         println!("wrote to file: {:?}", contents);
 
         let split = separator_split(
-            &EmbeddingSource {
+            &read_source(&EmbeddingSource {
                 filepath: filepath.to_string_lossy().to_string(),
                 meta: std::collections::HashSet::new(),
                 subset: None,
-            },
-            &"\n".to_string(),
+                hash: String::new(),
+                chunk_hash: None,
+            })
+            .unwrap(),
+            "\n",
         );
 
         assert!(split.is_ok());
@@ -502,6 +2958,17 @@ mod tests {
             assert_eq!(split[i].0, truth[i].0);
             assert_eq!(split[i].1, truth[i].1);
         }
+
+        // contents shorter than the separator, or no separator at all
+        assert!(separator_split("", "=====").unwrap().is_empty());
+        assert_eq!(
+            separator_split("==", "=====").unwrap(),
+            vec![("==".to_string(), (0, 2))]
+        );
+        assert_eq!(
+            separator_split("abc", "").unwrap(),
+            vec![("abc".to_string(), (0, 3))]
+        );
     }
 
     #[test]
@@ -512,35 +2979,30 @@ mod tests {
         let mut rng = rand::thread_rng();
 
         // TODO: more than just utf-8 in the file generation
-        let mut truth: Vec<(String, (usize, usize))> = Vec::new();
-        for _ in 0..rng.gen_range(2..10) {
-            let subset = if let Some(last) = truth.last() {
-                (last.1 .1 + 1, last.1 .1 + 1 + TOKEN_LIMIT)
-            } else {
-                (0, TOKEN_LIMIT)
-            };
+        let contents = (0..rng.gen_range(2..10))
+            .map(|_| random_unicode_string(TOKEN_LIMIT))
+            .collect::<Vec<String>>()
+            .join("\n");
 
-            truth.push((random_unicode_string(TOKEN_LIMIT), subset));
-        }
+        // chunks are contiguous, so the newlines are carried into the following chunk
+        let mut truth = contiguous_windows(&contents, TOKEN_LIMIT);
 
         let root = crate::config::get_home_dir();
-        let contents = truth
-            .iter()
-            .map(|p| p.0.clone())
-            .collect::<Vec<String>>()
-            .join("\n");
 
         let filepath = root.join("testing.rs");
         write_file!(filepath.clone(), contents.clone());
         println!("wrote to file: {:?}", contents);
 
         let split = naive_split(
-            &EmbeddingSource {
+            &read_source(&EmbeddingSource {
                 filepath: filepath.to_string_lossy().to_string(),
                 meta: std::collections::HashSet::new(),
                 subset: None,
-            },
-            &String::new(),
+                hash: String::new(),
+                chunk_hash: None,
+            })
+            .unwrap(),
+            "",
         );
 
         assert!(split.is_ok());
@@ -565,35 +3027,29 @@ mod tests {
         let mut rng = rand::thread_rng();
 
         // TODO: more than just utf-8 in the file generation
-        let mut truth: Vec<(String, (usize, usize))> = Vec::new();
         let length = 100;
-        for _ in 0..rng.gen_range(2..10) {
-            let subset = if let Some(last) = truth.last() {
-                (last.1 .1 + 1, last.1 .1 + 1 + length)
-            } else {
-                (0, length)
-            };
+        let contents = (0..rng.gen_range(2..10))
+            .map(|_| random_unicode_string(length))
+            .collect::<Vec<String>>()
+            .join("\n");
 
-            truth.push((random_unicode_string(length), subset));
-        }
+        let mut truth = contiguous_windows(&contents, length);
 
         let root = crate::config::get_home_dir();
-        let contents = truth
-            .iter()
-            .map(|p| p.0.clone())
-            .collect::<Vec<String>>()
-            .join("\n");
 
         let filepath = root.join("testing.rs");
         write_file!(filepath.clone(), contents.clone());
         println!("wrote to file: {:?}", contents);
 
         let split = max_length_split(
-            &EmbeddingSource {
+            &read_source(&EmbeddingSource {
                 filepath: filepath.to_string_lossy().to_string(),
                 meta: std::collections::HashSet::new(),
                 subset: None,
-            },
+                hash: String::new(),
+                chunk_hash: None,
+            })
+            .unwrap(),
             &length.to_string(),
         );
 
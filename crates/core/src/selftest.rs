@@ -0,0 +1,452 @@
+use std::collections::HashSet;
+
+use crate::logger::Logger;
+use crate::message::DeweyResponse;
+use crate::{config, dbio, embedding, info, ledger, openai, sync, Dewey, SearchOptions};
+
+pub use crate::openai::use_test_embeddings;
+
+// the files the self-test indexes, each with words none of the others have
+// so that a query for them can only find the one file
+const FIXTURES: [(&str, &str); 3] = [
+    (
+        "alpha.rs",
+        "fn kestrel_lantern() {\n    let marmalade = quartz_orbit();\n}\n",
+    ),
+    (
+        "bravo.md",
+        "# Tundra Sonnet\n\nBasalt violins hum beneath the glacier.\n",
+    ),
+    (
+        "charlie.txt",
+        "Porcelain hurricanes rarely visit cinnamon harbors.\n",
+    ),
+];
+
+// what bravo.md is rewritten to before it's reindexed
+const EDITED: &str = "# Saffron Ledger\n\nCopper zeppelins drift over walnut canyons.\n";
+
+const RULES: &str = "* --minlength 0 --maxlength 512\nrs --naive\nmd --split \\n\\n";
+
+const META: &str = "selftest";
+
+// one step of the pipeline, in the order they run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stage {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub duration: std::time::Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    // what the stage saw, e.g. how many embeddings it found
+    Passed(String),
+    Failed(String),
+    // an earlier stage failed, and every stage builds on the ones before it
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    // where everything was written, which is gone afterwards unless it was kept
+    pub dir: std::path::PathBuf,
+    pub kept: bool,
+    pub stages: Vec<Stage>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.stages
+            .iter()
+            .all(|stage| matches!(stage.outcome, Outcome::Passed(_)))
+    }
+}
+
+// the stages in the order they run, each of which checks what it did
+type StageFn = fn(&mut Fixture) -> Result<String, std::io::Error>;
+const STAGES: [(&str, StageFn); 10] = [
+    ("ledger", ledger_stage),
+    ("chunking", chunking_stage),
+    ("embedding", embedding_stage),
+    ("blocks", blocks_stage),
+    ("directory", directory_stage),
+    ("index", index_stage),
+    ("query", query_stage),
+    ("reindex", reindex_stage),
+    ("delete", delete_stage),
+    ("compaction", compaction_stage),
+];
+
+// the temp dir the stages work in, and the handle on its index once there is one
+struct Fixture {
+    root: std::path::PathBuf,
+    files: Vec<String>,
+    dewey: Option<Dewey>,
+}
+
+impl Fixture {
+    fn index_dir(&self) -> std::path::PathBuf {
+        self.root.join("index")
+    }
+
+    fn file(&self, name: &str) -> String {
+        self.files
+            .iter()
+            .find(|f| f.ends_with(name))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn dewey(&self) -> Result<&Dewey, std::io::Error> {
+        self.dewey
+            .as_ref()
+            .ok_or_else(|| std::io::Error::other("the index was never opened"))
+    }
+
+    fn dewey_mut(&mut self) -> Result<&mut Dewey, std::io::Error> {
+        self.dewey
+            .as_mut()
+            .ok_or_else(|| std::io::Error::other("the index was never opened"))
+    }
+
+    fn query(&self, query: &str) -> Result<DeweyResponse, std::io::Error> {
+        let options = SearchOptions {
+            save_query: false,
+            no_cache: true,
+            ..SearchOptions::new(FIXTURES.len() * 2)
+        };
+
+        self.dewey()?.query(query, &options)
+    }
+}
+
+fn check(ok: bool, message: impl FnOnce() -> String) -> Result<(), std::io::Error> {
+    match ok {
+        true => Ok(()),
+        false => Err(std::io::Error::other(message())),
+    }
+}
+
+// runs the whole pipeline, from syncing the ledger to compacting the blocks,
+// against a few files of its own in a temp dir
+//
+// embeddings come from the test client, so nothing goes over the network,
+// and nothing outside the temp dir is touched apart from the log
+//
+// the temp dir is removed afterwards unless `keep` is set
+pub fn run(keep: bool) -> Result<Report, std::io::Error> {
+    openai::use_test_embeddings();
+
+    let micros = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_micros())
+        .unwrap_or_default();
+    let root =
+        std::env::temp_dir().join(format!("dewey-self-test-{}-{}", std::process::id(), micros));
+
+    let mut fixture = create_fixture(&root)?;
+    let paths = config::DataPaths::at(&fixture.index_dir());
+
+    let mut stages = Vec::new();
+    let mut failed = false;
+    for (name, stage) in STAGES {
+        let started = std::time::Instant::now();
+        let outcome = match failed {
+            true => Outcome::Skipped,
+            false => match paths.scope(|| stage(&mut fixture)) {
+                Ok(detail) => Outcome::Passed(detail),
+                Err(e) => {
+                    failed = true;
+                    Outcome::Failed(e.to_string())
+                }
+            },
+        };
+
+        info!("self-test stage {}: {:?}", name, outcome);
+        stages.push(Stage {
+            name,
+            outcome,
+            duration: started.elapsed(),
+        });
+    }
+
+    // the index is written out as the handle is dropped, which has to happen before it's removed
+    paths.scope(|| fixture.dewey = None);
+    if !keep {
+        std::fs::remove_dir_all(&root)?;
+    }
+
+    Ok(Report {
+        dir: root,
+        kept: keep,
+        stages,
+    })
+}
+
+fn create_fixture(root: &std::path::Path) -> Result<Fixture, std::io::Error> {
+    let files_dir = root.join("files");
+    std::fs::create_dir_all(&files_dir)?;
+
+    let mut files = Vec::new();
+    for (name, contents) in FIXTURES {
+        std::fs::write(files_dir.join(name), contents)?;
+        files.push(files_dir.join(name).canonicalize()?);
+    }
+
+    let paths = config::DataPaths::at(&root.join("index"));
+    paths.create()?;
+    std::fs::write(
+        paths.config_dir.join("ledger"),
+        format!("{} --{}\n", files_dir.canonicalize()?.display(), META),
+    )?;
+    std::fs::write(paths.config_dir.join("rules"), RULES)?;
+
+    Ok(Fixture {
+        root: root.to_path_buf(),
+        files: files
+            .iter()
+            .map(|f| f.to_string_lossy().to_string())
+            .collect(),
+        dewey: None,
+    })
+}
+
+fn ledger_stage(fixture: &mut Fixture) -> Result<String, std::io::Error> {
+    let report = ledger::sync_ledger_config(true, None)?;
+    let tracked = ledger::read_ledger()?
+        .into_iter()
+        .map(|entry| entry.filepath)
+        .collect::<HashSet<_>>();
+
+    for file in fixture.files.iter() {
+        check(tracked.contains(file), || {
+            format!("{} isn't in the ledger", file)
+        })?;
+    }
+
+    Ok(format!(
+        "{} files tracked from {} config entries",
+        report.kept, report.entries_scanned
+    ))
+}
+
+fn chunking_stage(fixture: &mut Fixture) -> Result<String, std::io::Error> {
+    let mut chunks = 0;
+    for file in fixture.files.iter() {
+        let explanation = sync::explain_chunks(file)?;
+        check(!explanation.chunks.is_empty(), || {
+            format!("{} has no chunks", file)
+        })?;
+
+        chunks += explanation.chunks.len();
+    }
+
+    Ok(format!("{} chunks", chunks))
+}
+
+fn embedding_stage(fixture: &mut Fixture) -> Result<String, std::io::Error> {
+    let sources = ledger::read_ledger()?
+        .into_iter()
+        .map(|entry| embedding::EmbeddingSource {
+            filepath: entry.filepath,
+            meta: entry.meta,
+            subset: None,
+            hash: entry.hash,
+            chunk_hash: None,
+        })
+        .collect::<Vec<_>>();
+
+    let (embeddings, skipped) = openai::embed_bulk(&sources)?;
+    check(skipped.is_empty(), || {
+        format!("{} files couldn't be read", skipped.len())
+    })?;
+
+    let embeddings = embeddings.into_values().flatten().collect::<Vec<_>>();
+    let embedded = embeddings
+        .iter()
+        .map(|e| e.source_file.filepath.as_str())
+        .collect::<HashSet<_>>();
+    for file in fixture.files.iter() {
+        check(embedded.contains(file.as_str()), || {
+            format!("{} wasn't embedded", file)
+        })?;
+    }
+
+    check(
+        embeddings.iter().all(|e| e.data.iter().any(|v| *v != 0.0)),
+        || "an embedding came back empty".to_string(),
+    )?;
+
+    Ok(format!("{} embeddings", embeddings.len()))
+}
+
+fn blocks_stage(fixture: &mut Fixture) -> Result<String, std::io::Error> {
+    sync::sync_index(true, false, false, None, sync::OverQuota::Stop, false)?;
+
+    let blocks = dbio::get_all_blocks()?;
+    let written = blocks
+        .iter()
+        .map(|be| be.embedding.source_file.filepath.as_str())
+        .collect::<HashSet<_>>();
+    for file in fixture.files.iter() {
+        check(written.contains(file.as_str()), || {
+            format!("{} has no embeddings in the blocks", file)
+        })?;
+    }
+
+    Ok(format!(
+        "{} embeddings in {} blocks",
+        blocks.len(),
+        dbio::block_report()?.len()
+    ))
+}
+
+fn directory_stage(fixture: &mut Fixture) -> Result<String, std::io::Error> {
+    let entries = dbio::read_directory_entries()?;
+    let listed = entries
+        .iter()
+        .map(|(_, filepath, _)| filepath.as_str())
+        .collect::<HashSet<_>>();
+    for file in fixture.files.iter() {
+        check(listed.contains(file.as_str()), || {
+            format!("{} isn't in the directory", file)
+        })?;
+    }
+
+    let blocks = dbio::get_all_blocks()?.len();
+    check(entries.len() == blocks, || {
+        format!(
+            "the directory has {} entries for {} embeddings",
+            entries.len(),
+            blocks
+        )
+    })?;
+
+    Ok(format!("{} entries", entries.len()))
+}
+
+// opening the handle builds the index, since there isn't one yet
+fn index_stage(fixture: &mut Fixture) -> Result<String, std::io::Error> {
+    let dewey = Dewey::open(&fixture.index_dir())?;
+    check(config::get_data_dir().join("index").exists(), || {
+        "no index was written".to_string()
+    })?;
+
+    let (model, metric) = crate::hnsw::HNSW::read_header(&config::get_data_dir().join("index"))?;
+    fixture.dewey = Some(dewey);
+
+    Ok(format!(
+        "{} embeddings with {}, {} metric",
+        dbio::read_directory_entries()?.len(),
+        model.name,
+        metric
+    ))
+}
+
+fn query_stage(fixture: &mut Fixture) -> Result<String, std::io::Error> {
+    for (name, contents) in FIXTURES {
+        let file = fixture.file(name);
+        let response = fixture.query(contents)?;
+        check(
+            response.results.first().map(|r| r.filepath.as_str()) == Some(file.as_str()),
+            || format!("a query for {} didn't find it first", file),
+        )?;
+    }
+
+    Ok(format!("{} files found", FIXTURES.len()))
+}
+
+fn reindex_stage(fixture: &mut Fixture) -> Result<String, std::io::Error> {
+    let file = fixture.file(FIXTURES[1].0);
+    std::fs::write(&file, EDITED)?;
+
+    let meta = ledger::entry_for(&file)?
+        .map(|entry| entry.meta)
+        .unwrap_or_default();
+    let count = fixture
+        .dewey_mut()?
+        .insert_document(std::path::Path::new(&file), meta)?;
+
+    let response = fixture.query(EDITED)?;
+    check(
+        response.results.first().map(|r| r.filepath.as_str()) == Some(file.as_str()),
+        || format!("a query for the edit to {} didn't find it first", file),
+    )?;
+
+    Ok(format!("{} embeddings for the edited file", count))
+}
+
+fn delete_stage(fixture: &mut Fixture) -> Result<String, std::io::Error> {
+    let (name, contents) = FIXTURES[2];
+    let file = fixture.file(name);
+    let count = fixture
+        .dewey_mut()?
+        .delete_file(std::path::Path::new(&file))?;
+    check(count > 0, || format!("nothing of {} was removed", file))?;
+
+    let response = fixture.query(contents)?;
+    check(response.results.iter().all(|r| r.filepath != file), || {
+        format!("{} was still found after it was deleted", file)
+    })?;
+
+    Ok(format!("{} embeddings removed", count))
+}
+
+// the deleted file's embeddings are still in the blocks until they're compacted
+fn compaction_stage(fixture: &mut Fixture) -> Result<String, std::io::Error> {
+    fixture.dewey_mut()?.flush()?;
+    dbio::reblock(false)?;
+
+    let deleted = fixture.file(FIXTURES[2].0);
+    let blocks = dbio::get_all_blocks()?;
+    check(
+        blocks
+            .iter()
+            .all(|be| be.embedding.source_file.filepath != deleted),
+        || format!("{} is still in the blocks", deleted),
+    )?;
+
+    let reports = dbio::block_report()?;
+    check(dbio::compaction_recommendation(&reports).is_none(), || {
+        "the blocks still need compacting".to_string()
+    })?;
+
+    Ok(format!(
+        "{} embeddings in {} blocks",
+        blocks.len(),
+        reports.len()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_common::{setup, Cleanup};
+
+    // every stage passes, and the temp dir goes away unless it's kept
+    #[test]
+    fn self_test_test() {
+        let _cleanup = Cleanup;
+        assert!(setup().is_ok());
+
+        let report = run(false).unwrap();
+        for stage in report.stages.iter() {
+            println!("{}: {:?}", stage.name, stage.outcome);
+        }
+
+        assert!(report.passed());
+        assert_eq!(report.stages.len(), STAGES.len());
+        assert!(!report.dir.exists());
+
+        let report = run(true).unwrap();
+        assert!(report.passed());
+        assert!(report
+            .dir
+            .join("index")
+            .join("data")
+            .join("directory")
+            .exists());
+        std::fs::remove_dir_all(&report.dir).unwrap();
+    }
+}
@@ -1,6 +1,5 @@
-use crate::info;
-use crate::logger::Logger;
-use crate::openai::EMBED_DIM;
+#[cfg(any(feature = "index", feature = "embed"))]
+use crate::embedding::EMBED_DIM;
 
 pub trait Serialize {
     fn to_bytes(&self) -> Vec<u8>;
@@ -9,6 +8,14 @@ pub trait Serialize {
         Self: Sized;
 }
 
+// reading past the end of the bytes means the data was cut short or isn't what we think it is
+fn truncated() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "not enough bytes to deserialize",
+    )
+}
+
 macro_rules! primitive_serialize {
     ($($t:ty),*) => {
         $(
@@ -19,7 +26,10 @@ macro_rules! primitive_serialize {
 
                 fn from_bytes(bytes: &[u8], cursor: usize) -> Result<(Self, usize), std::io::Error> {
                     let size = std::mem::size_of::<Self>();
-                    let value = Self::from_be_bytes(bytes[cursor..cursor + size].try_into().unwrap());
+                    let value = match bytes.get(cursor..cursor + size) {
+                        Some(b) => Self::from_be_bytes(b.try_into().unwrap()),
+                        None => return Err(truncated()),
+                    };
 
                     Ok((value, size))
                 }
@@ -43,7 +53,11 @@ impl Serialize for String {
         cursor += count;
 
         let len = len as usize;
-        let value = String::from_utf8(bytes[cursor..cursor + len].to_vec()).unwrap();
+        let value = match bytes.get(cursor..cursor + len) {
+            Some(b) => String::from_utf8(b.to_vec())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+            None => return Err(truncated()),
+        };
 
         Ok((value, len + count))
     }
@@ -62,7 +76,7 @@ impl<T: Serialize> Serialize for Option<T> {
     }
 
     fn from_bytes(bytes: &[u8], mut cursor: usize) -> Result<(Self, usize), std::io::Error> {
-        let has_value = bytes[cursor] == 1;
+        let has_value = *bytes.get(cursor).ok_or_else(truncated)? == 1;
         cursor += 1;
         if has_value {
             let (value, size) = T::from_bytes(bytes, cursor)?;
@@ -106,6 +120,7 @@ macro_rules! tuple_serialize_impl {
 tuple_serialize_impl!(0: T0);
 tuple_serialize_impl!(0: T0, 1: T1);
 
+#[cfg(any(feature = "index", feature = "embed"))]
 macro_rules! array_serialize_impl {
     ($t:ty, $len:expr) => {
         impl Serialize for [$t; $len] {
@@ -134,6 +149,7 @@ macro_rules! array_serialize_impl {
     };
 }
 
+#[cfg(any(feature = "index", feature = "embed"))]
 array_serialize_impl!(f32, EMBED_DIM);
 
 impl<T: Serialize> Serialize for Vec<T> {
@@ -0,0 +1,2450 @@
+// embedding files into the store: syncing it with the ledger, bulk syncs, and re-embedding edits
+//
+// what's kept on disk, and reading it back, is `dbio`'s
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use crate::config::{get_boilerplate_threshold, get_data_dir, get_paths, PathResolver};
+use crate::dbio::{
+    add_tombstones, auto_snapshot, block_numbers, bump_blocks_generation, bump_state_generation,
+    directory_line, get_block_numbers, get_blocks_model, get_directory, is_store_normalized,
+    next_embedding_id, prepare_stored, read_directory, read_embedding_block,
+    read_embedding_block_headers, read_generation, read_stable_ids, replace_files, stable_id_line,
+    update_centroids, write_centroids, write_directory, write_generation, write_meta_index,
+    write_stable_ids, write_tombstones, Centroids, DirectoryEntry, EmbeddingBlock, BLOCK_SIZE,
+    STABLE_IDS_FILE,
+};
+use crate::embedding::{
+    is_field_source, Embedding, EmbeddingModel, EmbeddingSource, BOILERPLATE_META, PATH_META,
+    TITLE_META,
+};
+use crate::hnsw::{Metric, HNSW};
+use crate::journal::{self, Params};
+use crate::lock::{write_atomic, DataLock, LockMode};
+use crate::logger::Logger;
+use crate::openai::{embed_bulk, embed_streaming};
+use crate::parsing::{
+    batch_sources, chunk_hash, chunk_signature, file_title, normalize_contents, over_input_limit,
+    path_source, plan_chunks, route_model, split_chunks, title_source, SkippedSource, TOKEN_LIMIT,
+};
+use crate::serialization::Serialize;
+use crate::{error, info, lprint};
+
+// drops the stable ids of `removed` and adds those of `added`, after an edit
+fn update_stable_ids(
+    removed: &HashSet<u64>,
+    added: &[Embedding],
+    paths: &PathResolver,
+) -> Result<(), std::io::Error> {
+    let mut stable_ids = read_stable_ids()?;
+    stable_ids.retain(|_, id| !removed.contains(id));
+    for e in added {
+        if let Some(stable_id) = e.source_file.stable_id(paths) {
+            stable_ids.entry(stable_id).or_insert(e.id);
+        }
+    }
+
+    write_stable_ids(&stable_ids)
+}
+
+// the ledger's files that need embedding, which is all of them for a full embed,
+// and the ones among them that can't be read
+fn stale_sources(
+    ledger: &[crate::ledger::LedgerEntry],
+    full_embed: bool,
+) -> Result<(Vec<EmbeddingSource>, Vec<SkippedSource>), std::io::Error> {
+    let stale = match full_embed {
+        true => ledger.to_vec(),
+        false => {
+            let mut stale = crate::ledger::get_stale_files()?;
+            let oversized = oversized_files()?;
+            let known = stale
+                .iter()
+                .map(|e| e.filepath.clone())
+                .collect::<HashSet<_>>();
+            stale.extend(
+                ledger
+                    .iter()
+                    .filter(|e| oversized.contains(&e.filepath) && !known.contains(&e.filepath))
+                    .cloned(),
+            );
+
+            stale
+        }
+    };
+
+    let mut sources = Vec::new();
+    let mut skipped = Vec::new();
+    for entry in stale.iter() {
+        match crate::ledger::get_hash(&entry.filepath) {
+            Ok(hash) => sources.push(EmbeddingSource {
+                filepath: entry.filepath.clone(),
+                meta: entry.meta.clone(),
+                subset: None,
+                hash,
+                chunk_hash: None,
+            }),
+            Err(e) => {
+                error!("skipping {}, which couldn't be read: {}", entry.filepath, e);
+                skipped.push(SkippedSource::new(&entry.filepath, &e));
+            }
+        }
+    }
+
+    Ok((sources, skipped))
+}
+
+// files with chunks over the `max_input_tokens` of their store's model,
+// which fit the provider they were embedded for but not the one configured now
+//
+// stores whose provider takes as much as the splitters ever make aren't looked through
+fn oversized_files() -> Result<HashSet<String>, std::io::Error> {
+    let indexing_rules = crate::ledger::get_indexing_rules()?;
+    let mut oversized = HashSet::new();
+    for store in get_paths().model_stores()? {
+        store.scope(|| {
+            let model = crate::config::get_embedding_model();
+            let limit = crate::config::get_provider_limits(&model).max_input_tokens;
+            if limit >= TOKEN_LIMIT || !get_data_dir().is_dir() {
+                return Ok(());
+            }
+
+            let block_numbers = get_block_numbers()?;
+            if block_numbers.is_empty() {
+                return Ok(());
+            }
+
+            let directory = get_directory()?;
+            let mut found = HashSet::new();
+            for block_number in block_numbers {
+                for header in read_embedding_block_headers(block_number)? {
+                    let filepath = &header.source_file.filepath;
+                    if found.contains(filepath)
+                        || directory.id_map.get(&(header.id as u32)) != Some(&block_number)
+                    {
+                        continue;
+                    }
+
+                    // a file that can't be read is left to the rest of the sync
+                    if let Ok(true) = over_input_limit(&header.source_file, &indexing_rules) {
+                        found.insert(filepath.clone());
+                    }
+                }
+            }
+
+            if !found.is_empty() {
+                lprint!(
+                    info,
+                    "{} files have chunks over the {} characters {} takes, re-chunking them",
+                    found.len(),
+                    limit,
+                    model
+                );
+            }
+
+            oversized.extend(found);
+
+            Ok::<(), std::io::Error>(())
+        })?;
+    }
+
+    Ok(oversized)
+}
+
+// prints the files an embedding run had to leave out, once each
+fn report_skipped(skipped: &mut Vec<SkippedSource>) {
+    skipped.sort_by(|a, b| a.filepath.cmp(&b.filepath));
+    skipped.dedup_by(|a, b| a.filepath == b.filepath);
+    if skipped.is_empty() {
+        return;
+    }
+
+    lprint!(
+        info,
+        "skipped {} files that couldn't be read:",
+        skipped.len()
+    );
+    for skip in skipped.iter() {
+        lprint!(info, "  {}: {}", skip.filepath, skip.reason);
+    }
+}
+
+// the stores a sync writes, and the sources to embed for each model
+type Routing = (
+    Vec<crate::config::DataPaths>,
+    HashMap<String, Vec<EmbeddingSource>>,
+);
+
+// every model gets a store of its own, and the stores that are already there
+// are written again even without anything routed to them, to drop files that moved away
+fn route_stores(sources: &[EmbeddingSource]) -> Result<Routing, std::io::Error> {
+    let indexing_rules = crate::ledger::get_indexing_rules()?;
+    let paths = get_paths();
+    let mut stores = paths.model_stores()?;
+    let mut routed: HashMap<String, Vec<EmbeddingSource>> = HashMap::new();
+    for source in sources.iter() {
+        let model = route_model(&indexing_rules, &source.filepath);
+        let store = paths.for_model(&model);
+        if !stores.contains(&store) {
+            stores.push(store);
+        }
+
+        routed.entry(model).or_default().push(source.clone());
+    }
+
+    Ok((stores, routed))
+}
+
+// vectors from another model can't be compared against the ones already embedded
+fn check_store_models(
+    stores: &[crate::config::DataPaths],
+    routed: &HashMap<String, Vec<EmbeddingSource>>,
+) -> Result<(), std::io::Error> {
+    for store in stores.iter() {
+        store.scope(|| {
+            let current = EmbeddingModel::current();
+            if !routed.contains_key(&current.name) {
+                return Ok(());
+            }
+
+            match get_blocks_model()? {
+                Some(model) if model != current => {
+                    lprint!(
+                        error,
+                        "existing embeddings were made with {}, but the configured model is {}; run a full embed (-f) to re-embed everything",
+                        model,
+                        current
+                    );
+
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!(
+                            "embedding model changed from {} to {}",
+                            model.name, current.name
+                        ),
+                    ))
+                }
+                _ => Ok(()),
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+// what a sync does when what it would embed is over the configured `EmbedQuota`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverQuota {
+    // stops before anything is embedded, listing what would have been
+    Stop,
+    // embeds as many files as fit, in priority order, and leaves the rest for a resumed sync
+    Partial,
+}
+
+// the files a partial sync left for later, one to a line in $DATA_DIR/sync_remainder
+const REMAINDER_FILE: &str = "sync_remainder";
+
+// how many of the files over the quota are listed when a sync stops
+const QUOTA_LISTED: usize = 50;
+
+pub fn read_sync_remainder() -> Result<Vec<String>, std::io::Error> {
+    let paths = PathResolver::current();
+    match std::fs::read_to_string(get_data_dir().join(REMAINDER_FILE)) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| paths.resolve(line))
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_sync_remainder(files: &[String]) -> Result<(), std::io::Error> {
+    let path = get_data_dir().join(REMAINDER_FILE);
+    if files.is_empty() {
+        return match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+
+    let paths = PathResolver::current();
+    let contents = files
+        .iter()
+        .map(|f| format!("{}\n", paths.store(f)))
+        .collect::<String>();
+    write_atomic(&path, contents.as_bytes())
+}
+
+// how much embedding a file would send, path embedding and all
+#[derive(Debug)]
+struct FileEstimate {
+    filepath: String,
+    chunks: usize,
+    tokens: usize,
+}
+
+// splits `sources` the way embedding them would, without embedding anything,
+// in priority order: smallest first, or with `only`, in the order of the first glob they match
+//
+// tokens are in characters, like `max_batch_tokens`
+fn estimate_sources(
+    sources: &[EmbeddingSource],
+    only: Option<&crate::ledger::PathFilter>,
+) -> Result<Vec<FileEstimate>, std::io::Error> {
+    let path_sources = sources.iter().map(path_source).collect::<Vec<_>>();
+    let title_sources = sources.iter().map(title_source).collect::<Vec<_>>();
+    let mut planned = sources.to_vec();
+    planned.extend(path_sources);
+    planned.extend(title_sources);
+
+    let mut estimates = sources
+        .iter()
+        .map(|s| {
+            (
+                s.filepath.clone(),
+                FileEstimate {
+                    filepath: s.filepath.clone(),
+                    chunks: 0,
+                    tokens: 0,
+                },
+            )
+        })
+        .collect::<HashMap<_, _>>();
+
+    let (batches, _) = batch_sources(&planned, &crate::config::get_embed_settings())?;
+    for (source, text) in batches.iter().flat_map(|b| b.chunks.iter()) {
+        if let Some(estimate) = estimates.get_mut(&source.filepath) {
+            estimate.chunks += 1;
+            estimate.tokens += text.len();
+        }
+    }
+
+    let mut estimates = estimates.into_values().collect::<Vec<_>>();
+    estimates.sort_by(|a, b| {
+        let glob = |e: &FileEstimate| only.and_then(|only| only.first_match(&e.filepath));
+        glob(a)
+            .cmp(&glob(b))
+            .then(a.tokens.cmp(&b.tokens))
+            .then(a.filepath.cmp(&b.filepath))
+    });
+
+    Ok(estimates)
+}
+
+// how many of `estimates`, from the start, fit in `quota` together
+fn fit_quota(estimates: &[FileEstimate], quota: &crate::config::EmbedQuota) -> usize {
+    let mut chunks = 0;
+    let mut tokens = 0;
+    estimates
+        .iter()
+        .take_while(|e| {
+            chunks += e.chunks;
+            tokens += e.tokens;
+            quota.allows(chunks, tokens)
+        })
+        .count()
+}
+
+// synchronizes the index with the current ledger
+// TODO: ledgers need to include subsets of files
+//       we also need a proper tokenizer
+//
+// a partial sync keeps the existing embeddings of files that aren't stale
+// and only re-embeds the rest
+//
+// `dry_run` only reports how many files would be embedded
+// `snapshot` takes an automatic snapshot before a full embed replaces every block
+// with `only`, just the files it matches are embedded, and every other file keeps
+// the embeddings it has, even on a full embed
+//
+// files that can't be read are left out rather than failing the sync, and come back skipped
+// a skipped file keeps its old embeddings if it's found unreadable before anything is embedded
+//
+// what's embedded is estimated first when there's a quota in the config, and a sync over it
+// either stops or embeds what fits, depending on `over_quota`
+// the files a partial sync leaves out are kept in the sync remainder,
+// which is what's embedded with `resume` instead of the stale files
+//
+// everything but a dry run goes in the journal
+pub fn sync_index(
+    full_embed: bool,
+    dry_run: bool,
+    snapshot: bool,
+    only: Option<&crate::ledger::PathFilter>,
+    over_quota: OverQuota,
+    resume: bool,
+) -> Result<Vec<SkippedSource>, std::io::Error> {
+    let run = |params: &mut Params| {
+        run_sync_index(
+            full_embed, dry_run, snapshot, only, over_quota, resume, params,
+        )
+    };
+
+    if dry_run {
+        return run(&mut Params::new());
+    }
+
+    journal::record("sync_index", |params| {
+        params.insert("full".to_string(), full_embed.into());
+        params.insert("scoped".to_string(), only.is_some().into());
+        params.insert("resumed".to_string(), resume.into());
+        let skipped = run(params)?;
+        params.insert("skipped".to_string(), skipped.len().into());
+
+        Ok(skipped)
+    })
+}
+
+fn run_sync_index(
+    full_embed: bool,
+    dry_run: bool,
+    snapshot: bool,
+    only: Option<&crate::ledger::PathFilter>,
+    over_quota: OverQuota,
+    resume: bool,
+    params: &mut Params,
+) -> Result<Vec<SkippedSource>, std::io::Error> {
+    let mode = match dry_run {
+        true => LockMode::Shared,
+        false => LockMode::Exclusive,
+    };
+    let _lock = DataLock::acquire(mode, "sync_index")?;
+
+    let ledger = crate::ledger::read_ledger()?;
+    let (mut stale_sources, mut skipped) = match resume {
+        // the remainder is embedded again whether or not it's changed
+        true => {
+            let remainder = read_sync_remainder()?.into_iter().collect::<HashSet<_>>();
+            if remainder.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "no partial sync to resume",
+                ));
+            }
+
+            let entries = ledger
+                .iter()
+                .filter(|e| remainder.contains(&e.filepath))
+                .cloned()
+                .collect::<Vec<_>>();
+            lprint!(
+                info,
+                "resuming with {} of the {} files left over",
+                entries.len(),
+                remainder.len()
+            );
+
+            stale_sources(&entries, true)?
+        }
+        false => stale_sources(&ledger, full_embed)?,
+    };
+
+    if let Some(only) = only {
+        let total = stale_sources.len();
+        stale_sources.retain(|s| only.matches(&s.filepath));
+        skipped.retain(|s| only.matches(&s.filepath));
+        lprint!(
+            info,
+            "{} of {} files match --only",
+            stale_sources.len(),
+            total
+        );
+    }
+
+    let quota = crate::config::get_embed_quota();
+    let mut remainder = None;
+    if quota.is_limited() && !stale_sources.is_empty() {
+        let estimates = estimate_sources(&stale_sources, only)?;
+        let chunks = estimates.iter().map(|e| e.chunks).sum::<usize>();
+        let tokens = estimates.iter().map(|e| e.tokens).sum::<usize>();
+        lprint!(
+            info,
+            "embedding {} files would send {} chunks, about {} tokens",
+            estimates.len(),
+            chunks,
+            tokens
+        );
+
+        if !quota.allows(chunks, tokens) {
+            let fits = fit_quota(&estimates, &quota);
+            let over = format!(
+                "that's over the quota ({}), which fits the first {} files",
+                quota, fits
+            );
+
+            if dry_run || over_quota == OverQuota::Stop {
+                lprint!(info, "{}, out of:", over);
+                for e in estimates.iter().take(QUOTA_LISTED) {
+                    lprint!(
+                        info,
+                        "  {}: {} chunks, {} tokens",
+                        e.filepath,
+                        e.chunks,
+                        e.tokens
+                    );
+                }
+                if estimates.len() > QUOTA_LISTED {
+                    lprint!(info, "  and {} more", estimates.len() - QUOTA_LISTED);
+                }
+            }
+
+            match over_quota {
+                _ if dry_run => {}
+                OverQuota::Stop => {
+                    return Err(std::io::Error::other(format!(
+                        "{}; nothing was embedded, rerun with --partial to embed what fits",
+                        over
+                    )))
+                }
+                OverQuota::Partial => {
+                    let left = estimates[fits..]
+                        .iter()
+                        .map(|e| e.filepath.clone())
+                        .collect::<Vec<_>>();
+                    lprint!(info, "{}, leaving {} for --resume", over, left.len());
+
+                    let left_out = left.iter().collect::<HashSet<_>>();
+                    stale_sources.retain(|s| !left_out.contains(&s.filepath));
+                    params.insert("remainder".to_string(), left.len().into());
+                    remainder = Some(left);
+                }
+            }
+        }
+    }
+
+    lprint!(info, "{} files to embed", stale_sources.len());
+    params.insert("files".to_string(), stale_sources.len().into());
+
+    // the remainder is only cleared by a sync that embedded everything in it
+    let covered = resume || (full_embed && only.is_none());
+    let update_remainder = || match &remainder {
+        Some(left) => write_sync_remainder(left),
+        None if covered => write_sync_remainder(&[]),
+        None => Ok(()),
+    };
+
+    // a scoped embed keeps everything else, so it's held to the same models as a partial one,
+    // as does one that only gets partway through, or picks up where one left off
+    let keep_existing = !full_embed || only.is_some() || resume || remainder.is_some();
+    let (stores, routed) = route_stores(&stale_sources)?;
+    if keep_existing {
+        check_store_models(&stores, &routed)?;
+    }
+
+    if dry_run {
+        for (model, sources) in routed.iter() {
+            lprint!(info, "{} files for {}", sources.len(), model);
+        }
+
+        report_skipped(&mut skipped);
+        return Ok(skipped);
+    }
+
+    if stale_sources.is_empty() && keep_existing {
+        lprint!(info, "index is up to date, nothing to embed");
+        update_remainder()?;
+        report_skipped(&mut skipped);
+        return Ok(skipped);
+    }
+
+    bump_state_generation()?;
+    let kept_files = kept_files(&ledger, &stale_sources);
+
+    // every file also gets an embedding of its path,
+    // so that files can be found by name even when their contents don't mention it,
+    // and one of its title, which queries can weigh into the scores of its chunks
+    let path_sources = stale_sources.iter().map(path_source).collect::<Vec<_>>();
+    let title_sources = stale_sources.iter().map(title_source).collect::<Vec<_>>();
+    let mut sources = stale_sources;
+    sources.extend(path_sources);
+    sources.extend(title_sources);
+
+    // the kept embeddings go in first, then the new ones as each batch comes back
+    let mut writers = Vec::new();
+    for store in stores.iter() {
+        let mut writer = StoreWriter::new(store, "sync_index")?;
+        if full_embed && snapshot {
+            store.scope(|| auto_snapshot("embed"))?;
+        }
+
+        if keep_existing {
+            writer.keep_existing(|e| kept_files.contains(&e.source_file.filepath))?;
+        }
+
+        writers.push((store.scope(crate::config::get_embedding_model), writer));
+    }
+
+    skipped.extend(embed_streaming(
+        &sources,
+        |model, embeddings| match writers.iter_mut().find(|(m, _)| *m == model) {
+            Some((_, writer)) => embeddings.into_iter().try_for_each(|e| writer.push(e)),
+            None => Err(std::io::Error::other(format!(
+                "embeddings came back for {}, which has no store",
+                model
+            ))),
+        },
+    )?);
+
+    for (store, (_, writer)) in stores.iter().zip(writers) {
+        match writer.finish()? {
+            0 => remove_empty_store(store)?,
+            _ => store.scope(write_meta_index)?,
+        }
+    }
+
+    // the rules hashes cover the whole ledger, which a scoped embed didn't look at
+    if only.is_none() && !resume && remainder.is_none() {
+        crate::ledger::write_rules_hashes(&ledger)?;
+    }
+
+    update_remainder()?;
+    report_skipped(&mut skipped);
+    Ok(skipped)
+}
+
+// the files whose embeddings a partial sync keeps,
+// which are the ones still in the ledger that aren't being embedded again
+fn kept_files(
+    ledger: &[crate::ledger::LedgerEntry],
+    stale_sources: &[EmbeddingSource],
+) -> HashSet<String> {
+    let stale_files = stale_sources
+        .iter()
+        .map(|s| s.filepath.as_str())
+        .collect::<HashSet<_>>();
+
+    ledger
+        .iter()
+        .filter(|e| !stale_files.contains(e.filepath.as_str()))
+        .map(|e| e.filepath.clone())
+        .collect()
+}
+
+// a store that's left with nothing in it goes away entirely,
+// apart from the configured model's, which is the data directory itself
+fn remove_empty_store(store: &crate::config::DataPaths) -> Result<(), std::io::Error> {
+    match &store.model {
+        Some(model) => {
+            lprint!(
+                info,
+                "nothing is embedded with {} anymore, removing its store",
+                model
+            );
+            store.scope(|| std::fs::remove_dir_all(get_data_dir()))
+        }
+        None => Ok(()),
+    }
+}
+
+// writes the blocks of a store one at a time as embeddings come in, numbering them from 0,
+// so that a sync only holds about a block's worth of embeddings at once
+//
+// blocks and the directory are staged in $DATA_DIR/staging, and the store is left alone
+// until `finish` tags boilerplate and swaps them in
+struct StoreWriter {
+    store: crate::config::DataPaths,
+    _lock: Option<DataLock>,
+    staging: std::path::PathBuf,
+    directory: std::io::BufWriter<std::fs::File>,
+    stable_ids: std::io::BufWriter<std::fs::File>,
+    pending: Vec<Embedding>,
+    // the chunk signatures of each staged block
+    signatures: Vec<Vec<Option<u64>>>,
+    // the distinct files with a chunk of each signature
+    signature_files: HashMap<u64, HashSet<String>>,
+    centroids: Centroids,
+    next_id: u64,
+    // how the staged directory's filepaths are written
+    paths: PathResolver,
+}
+
+impl StoreWriter {
+    // the configured model's store is expected to be locked already
+    fn new(store: &crate::config::DataPaths, operation: &str) -> Result<Self, std::io::Error> {
+        store.scope(|| {
+            std::fs::create_dir_all(get_data_dir())?;
+            let lock = match store.model {
+                Some(_) => Some(DataLock::acquire(LockMode::Exclusive, operation)?),
+                None => None,
+            };
+
+            // whatever's left in staging is from a sync that didn't finish
+            let staging = get_data_dir().join("staging");
+            if staging.exists() {
+                std::fs::remove_dir_all(&staging)?;
+            }
+            std::fs::create_dir_all(&staging)?;
+
+            let directory = std::fs::File::create(staging.join("directory"))?;
+            let stable_ids = std::fs::File::create(staging.join(STABLE_IDS_FILE))?;
+
+            Ok(Self {
+                store: store.clone(),
+                _lock: lock,
+                staging,
+                directory: std::io::BufWriter::new(directory),
+                stable_ids: std::io::BufWriter::new(stable_ids),
+                pending: Vec::new(),
+                signatures: Vec::new(),
+                signature_files: HashMap::new(),
+                centroids: Centroids::default(),
+                next_id: 0,
+                paths: PathResolver::current(),
+            })
+        })
+    }
+
+    // carries over the embeddings already in the store that `keep` is true for,
+    // reading one block at a time
+    fn keep_existing(&mut self, keep: impl Fn(&Embedding) -> bool) -> Result<(), std::io::Error> {
+        let store = self.store.clone();
+        let metric = Metric::current();
+        let normalized = store.scope(is_store_normalized);
+        for block_number in store.scope(block_numbers)? {
+            let block = store.scope(|| read_embedding_block(block_number))?;
+            for mut e in block.embeddings {
+                prepare_stored(metric, normalized, &mut e);
+                if keep(&e) {
+                    self.push(e)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, mut embedding: Embedding) -> Result<(), std::io::Error> {
+        embedding.id = self.next_id;
+        self.next_id += 1;
+        self.centroids.add(&embedding);
+        self.pending.push(embedding);
+        crate::openai::track_retained(1);
+
+        if self.pending.len() >= BLOCK_SIZE {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let block_number = self.signatures.len() as u64;
+        let embeddings = std::mem::take(&mut self.pending);
+
+        let signatures = chunk_signatures(&embeddings);
+        for (e, signature) in embeddings.iter().zip(signatures.iter()) {
+            if let Some(signature) = signature {
+                self.signature_files
+                    .entry(*signature)
+                    .or_default()
+                    .insert(e.source_file.filepath.clone());
+            }
+
+            writeln!(
+                self.directory,
+                "{}",
+                directory_line(&DirectoryEntry::of(e), block_number as u32, &self.paths)
+            )?;
+
+            if let Some(stable_id) = e.source_file.stable_id(&self.paths) {
+                writeln!(self.stable_ids, "{}", stable_id_line(&stable_id, e.id))?;
+            }
+        }
+        self.signatures.push(signatures);
+
+        let count = embeddings.len();
+        let path = self.staging.join(block_number.to_string());
+        self.store.scope(|| {
+            EmbeddingBlock::new(block_number, EmbeddingModel::current(), embeddings).write_to(&path)
+        })?;
+        crate::openai::track_retained(-(count as isize));
+
+        Ok(())
+    }
+
+    // tags boilerplate over everything that was written and swaps it in for the store's blocks,
+    // leaving the index behind them
+    //
+    // returns how many embeddings the store has now
+    fn finish(mut self) -> Result<u64, std::io::Error> {
+        self.flush()?;
+        let centroids = std::mem::take(&mut self.centroids);
+        self.directory.flush()?;
+        self.directory.get_ref().sync_all()?;
+        self.stable_ids.flush()?;
+        self.stable_ids.get_ref().sync_all()?;
+
+        let store = self.store.clone();
+        store.scope(|| {
+            let model = crate::config::get_embedding_model();
+
+            // counted over every embedding, since a kept chunk becomes boilerplate
+            // once enough newly embedded files share it
+            let frequencies = self
+                .signature_files
+                .iter()
+                .map(|(signature, files)| (*signature, files.len()))
+                .collect::<HashMap<_, _>>();
+
+            let mut tagged = 0;
+            for (block_number, signatures) in self.signatures.iter().enumerate() {
+                let path = self.staging.join(block_number.to_string());
+                let (mut block, _) = EmbeddingBlock::from_bytes(&std::fs::read(&path)?, 0)?;
+
+                let before = block
+                    .embeddings
+                    .iter()
+                    .map(|e| e.source_file.meta.contains(BOILERPLATE_META))
+                    .collect::<Vec<_>>();
+                tagged += tag_boilerplate(&mut block.embeddings, signatures, &frequencies);
+
+                if block
+                    .embeddings
+                    .iter()
+                    .zip(before)
+                    .any(|(e, b)| e.source_file.meta.contains(BOILERPLATE_META) != b)
+                {
+                    block.write_to(&path)?;
+                }
+            }
+
+            lprint!(
+                info,
+                "{} chunks embedded with {}, {} tagged as boilerplate",
+                self.next_id,
+                model,
+                tagged
+            );
+
+            let data_dir = get_data_dir();
+            for block_number in block_numbers()? {
+                std::fs::remove_file(data_dir.join(block_number.to_string()))?;
+            }
+
+            for block_number in 0..self.signatures.len() {
+                std::fs::rename(
+                    self.staging.join(block_number.to_string()),
+                    data_dir.join(block_number.to_string()),
+                )?;
+            }
+
+            std::fs::rename(self.staging.join("directory"), data_dir.join("directory"))?;
+            info!("Wrote directory with {} entries", self.next_id);
+            std::fs::rename(
+                self.staging.join(STABLE_IDS_FILE),
+                data_dir.join(STABLE_IDS_FILE),
+            )?;
+
+            std::fs::remove_dir_all(&self.staging)?;
+
+            // ids start over from 0, so the old ones mean nothing to the blocks,
+            // or to the index, now
+            write_tombstones(&HashSet::new())?;
+
+            write_frequencies(&frequencies)?;
+            write_centroids(centroids.into_embeddings())?;
+            let mut generation = read_generation()?;
+            generation.blocks += 1;
+            generation.renumbered = generation.blocks;
+            write_generation(&generation)?;
+
+            Ok(self.next_id)
+        })
+    }
+}
+
+// how many files a bulk sync embeds before writing them out
+const BULK_ROUND_FILES: usize = 512;
+
+// `sync_index` for large loads, which writes each round of `BULK_ROUND_FILES` files
+// to new blocks as soon as it's embedded instead of holding every embedding until the end
+//
+// the data directory is only locked while blocks are written, not while the embedding API works,
+// and the index is left alone for `build_index` (-r) to build once everything is in
+//
+// returns how many files were embedded
+pub fn bulk_sync_index(full_embed: bool, snapshot: bool) -> Result<usize, std::io::Error> {
+    bulk_sync(full_embed, snapshot, BULK_ROUND_FILES)
+}
+
+fn bulk_sync(
+    full_embed: bool,
+    snapshot: bool,
+    round_files: usize,
+) -> Result<usize, std::io::Error> {
+    let lock_store = |store: &crate::config::DataPaths| match store.model {
+        Some(_) => DataLock::acquire(LockMode::Exclusive, "bulk_sync_index").map(Some),
+        None => Ok(None),
+    };
+
+    let (ledger, sources, stores, mut skipped) = {
+        let _lock = DataLock::acquire(LockMode::Exclusive, "bulk_sync_index")?;
+
+        let ledger = crate::ledger::read_ledger()?;
+        let (sources, mut skipped) = stale_sources(&ledger, full_embed)?;
+        let (stores, routed) = route_stores(&sources)?;
+        if !full_embed {
+            check_store_models(&stores, &routed)?;
+        }
+
+        if sources.is_empty() && !full_embed {
+            lprint!(info, "index is up to date, nothing to embed");
+            report_skipped(&mut skipped);
+            return Ok(0);
+        }
+
+        // a bulk embed has nothing to leave a remainder with, so one over the quota never starts
+        let quota = crate::config::get_embed_quota();
+        if quota.is_limited() {
+            let estimates = estimate_sources(&sources, None)?;
+            let fits = fit_quota(&estimates, &quota);
+            if fits < estimates.len() {
+                return Err(std::io::Error::other(format!(
+                    "embedding {} files is over the quota ({}), which fits {} of them; embed without --bulk to use --partial",
+                    estimates.len(),
+                    quota,
+                    fits
+                )));
+            }
+        }
+
+        bump_state_generation()?;
+
+        // the stale files' old embeddings go before any new ones are added
+        let kept_files = kept_files(&ledger, &sources);
+        for store in stores.iter() {
+            let mut writer = StoreWriter::new(store, "bulk_sync_index")?;
+            if full_embed && snapshot {
+                store.scope(|| auto_snapshot("embed"))?;
+            }
+
+            if !full_embed {
+                writer.keep_existing(|e| kept_files.contains(&e.source_file.filepath))?;
+            }
+
+            writer.finish()?;
+        }
+
+        (ledger, sources, stores, skipped)
+    };
+
+    lprint!(
+        info,
+        "{} files to embed, {} at a time",
+        sources.len(),
+        round_files
+    );
+
+    let mut done = 0;
+    // the files of `sources` that couldn't be read once it came to embedding them
+    let mut unread = 0;
+    for round in sources.chunks(round_files.max(1)) {
+        let mut round_sources = round.to_vec();
+        round_sources.extend(round.iter().map(path_source));
+        round_sources.extend(round.iter().map(title_source));
+        let (mut embedded, round_skipped) = embed_bulk(&round_sources)?;
+        unread += round_skipped.len();
+        skipped.extend(round_skipped);
+
+        let _lock = DataLock::acquire(LockMode::Exclusive, "bulk_sync_index")?;
+        for store in stores.iter() {
+            store.scope(|| {
+                let _lock = lock_store(store)?;
+                match embedded.remove(&crate::config::get_embedding_model()) {
+                    Some(embeddings) => append_blocks(embeddings),
+                    None => Ok(()),
+                }
+            })?;
+        }
+
+        done += round.len();
+        lprint!(info, "Embedded {} of {} files", done, sources.len());
+    }
+
+    // boilerplate depends on every file, so it's only tagged once everything's in
+    let _lock = DataLock::acquire(LockMode::Exclusive, "bulk_sync_index")?;
+    for store in stores.iter() {
+        let mut writer = StoreWriter::new(store, "bulk_sync_index")?;
+        writer.keep_existing(|_| true)?;
+        if writer.finish()? == 0 {
+            remove_empty_store(store)?;
+        }
+    }
+
+    crate::ledger::write_rules_hashes(&ledger)?;
+
+    report_skipped(&mut skipped);
+    Ok(sources.len() - unread)
+}
+
+// adds `embeddings` to the store in scope, in new blocks after the existing ones
+// and with ids after the highest one already there
+fn append_blocks(mut embeddings: Vec<Embedding>) -> Result<(), std::io::Error> {
+    let mut directory = read_directory()?;
+    let next_id = next_embedding_id(directory.iter().map(|(entry, _)| entry.id))?;
+    let next_block = directory.iter().map(|d| d.1 as u64 + 1).max().unwrap_or(0);
+
+    for (i, e) in embeddings.iter_mut().enumerate() {
+        e.id = next_id + i as u64;
+    }
+
+    let model = EmbeddingModel::current();
+    for (i, block) in embeddings.chunks(BLOCK_SIZE).enumerate() {
+        let block_number = next_block + i as u64;
+        EmbeddingBlock::new(block_number, model.clone(), block.to_vec())
+            .write_to(&get_data_dir().join(block_number.to_string()))?;
+
+        directory.extend(
+            block
+                .iter()
+                .map(|e| (DirectoryEntry::of(e), block_number as u32)),
+        );
+    }
+
+    write_directory(&directory)?;
+    bump_blocks_generation()
+}
+
+// the store `filepath` is embedded into under the current rules
+pub fn file_store(filepath: &str) -> Result<crate::config::DataPaths, std::io::Error> {
+    let indexing_rules = crate::ledger::get_indexing_rules()?;
+    Ok(get_paths().for_model(&route_model(&indexing_rules, filepath)))
+}
+
+// embeds `sources` for the store in scope
+//
+// sources routed to another model are refused before anything is embedded,
+// since their vectors couldn't go in this store
+fn embed_store(sources: &Vec<EmbeddingSource>) -> Result<Vec<Embedding>, std::io::Error> {
+    let indexing_rules = crate::ledger::get_indexing_rules()?;
+    let model = crate::config::get_embedding_model();
+    if let Some(source) = sources
+        .iter()
+        .find(|s| route_model(&indexing_rules, &s.filepath) != model)
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "{} is embedded with {}, not {}",
+                source.filepath,
+                route_model(&indexing_rules, &source.filepath),
+                model
+            ),
+        ));
+    }
+
+    // these are single files, so one that can't be read fails the whole thing
+    let (mut embedded, skipped) = embed_bulk(sources)?;
+    if let Some(skip) = skipped.into_iter().next() {
+        return Err(skip.into_error(std::io::ErrorKind::Other));
+    }
+
+    Ok(embedded.remove(&model).unwrap_or_default())
+}
+
+pub fn get_frequencies_path() -> std::path::PathBuf {
+    get_data_dir().join("frequencies")
+}
+
+// the frequency table is kept in $DATA_DIR/frequencies, formatted as `signature files`
+// on each line, where `files` is how many distinct files have a chunk with that signature
+//
+// a missing file just means nothing has been counted yet
+pub fn read_frequencies() -> Result<HashMap<u64, usize>, std::io::Error> {
+    let path = get_frequencies_path();
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let mut frequencies = HashMap::new();
+    for line in std::fs::read_to_string(&path)?.lines() {
+        match line
+            .split_once(' ')
+            .and_then(|(s, f)| Some((s.parse::<u64>().ok()?, f.parse::<usize>().ok()?)))
+        {
+            Some((signature, files)) => {
+                frequencies.insert(signature, files);
+            }
+            None => {
+                error!("Ignoring malformed frequency entry: {}", line);
+            }
+        }
+    }
+
+    Ok(frequencies)
+}
+
+// only signatures shared by more than one file are worth keeping
+fn write_frequencies(frequencies: &HashMap<u64, usize>) -> Result<(), std::io::Error> {
+    let contents = frequencies
+        .iter()
+        .filter(|(_, &files)| files > 1)
+        .map(|(signature, files)| format!("{} {}", signature, files))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    write_atomic(&get_frequencies_path(), contents.as_bytes())
+}
+
+// the signature of each embedding's chunk
+// path and title embeddings and chunks of files that can't be read have none
+fn chunk_signatures(embeddings: &[Embedding]) -> Vec<Option<u64>> {
+    let mut files: HashMap<String, Option<Vec<u8>>> = HashMap::new();
+    embeddings
+        .iter()
+        .map(|e| {
+            if is_field_source(&e.source_file) {
+                return None;
+            }
+
+            // subsets are offsets into the raw file, so the chunk is normalized after slicing
+            let (start, end) = e.source_file.subset?;
+            let bytes = files
+                .entry(e.source_file.filepath.clone())
+                .or_insert_with(|| std::fs::read(&e.source_file.filepath).ok())
+                .as_ref()?;
+
+            chunk_signature(&normalize_contents(
+                bytes.get(start as usize..end as usize)?,
+            ))
+        })
+        .collect()
+}
+
+// tags the embeddings whose chunks show up in at least the configured number of files,
+// and untags the ones that no longer do
+//
+// returns how many were tagged
+fn tag_boilerplate(
+    embeddings: &mut [Embedding],
+    signatures: &[Option<u64>],
+    frequencies: &HashMap<u64, usize>,
+) -> usize {
+    let threshold = get_boilerplate_threshold();
+
+    let mut tagged = 0;
+    for (e, signature) in embeddings.iter_mut().zip(signatures) {
+        let files = signature.and_then(|s| frequencies.get(&s).copied());
+        if files.is_some_and(|files| files >= threshold) {
+            e.source_file.meta.insert(BOILERPLATE_META.to_string());
+            tagged += 1;
+        } else {
+            e.source_file.meta.remove(BOILERPLATE_META);
+        }
+    }
+
+    tagged
+}
+
+// a chunk of `explain_chunks`, kept or removed
+#[derive(Debug, serde::Serialize)]
+pub struct ExplainedChunk {
+    pub index: usize,
+    pub bytes: (u64, u64),
+    // 1-based and inclusive
+    pub lines: (usize, usize),
+    pub length: usize,
+    pub rule: String,
+    pub tag: Option<String>,
+    // the filter that removed the chunk, `blank` if there was nothing left of it to embed
+    pub removed_by: Option<String>,
+}
+
+// how a file would be chunked under the current indexing rules
+#[derive(Debug, serde::Serialize)]
+pub struct ChunkExplanation {
+    pub filepath: String,
+    pub model: String,
+    pub transforms: Vec<String>,
+    pub chunks: Vec<ExplainedChunk>,
+    pub removed: Vec<ExplainedChunk>,
+}
+
+// the lines a byte range of `contents` covers
+fn line_range(contents: &[u8], (start, end): (u64, u64)) -> (usize, usize) {
+    let line_at = |offset: usize| {
+        let offset = offset.min(contents.len());
+        contents[..offset].iter().filter(|b| **b == b'\n').count() + 1
+    };
+
+    let start = start as usize;
+    let end = (end as usize).max(start + 1) - 1;
+
+    (line_at(start), line_at(end))
+}
+
+// the chunks embedding `filepath` would make with the current indexing rules,
+// along with the ones the rules would drop
+//
+// nothing is embedded or written, and the data directory isn't locked
+pub fn explain_chunks(filepath: &str) -> Result<ChunkExplanation, std::io::Error> {
+    let filepath = std::fs::canonicalize(filepath)?
+        .to_string_lossy()
+        .to_string();
+
+    let meta = crate::ledger::entry_for(&filepath)?
+        .map(|entry| entry.meta)
+        .unwrap_or_default();
+
+    let source = EmbeddingSource {
+        filepath: filepath.clone(),
+        meta,
+        subset: None,
+        hash: String::new(),
+        chunk_hash: None,
+    };
+
+    let indexing_rules = crate::ledger::get_indexing_rules()?;
+    let plan = plan_chunks(&source, &indexing_rules)?;
+    let contents = std::fs::read(&filepath)?;
+
+    let chunks = plan
+        .chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let bytes = chunk.source.subset.unwrap_or((0, 0));
+            ExplainedChunk {
+                index,
+                bytes,
+                lines: line_range(&contents, bytes),
+                length: chunk.text.chars().count(),
+                rule: chunk.rule,
+                tag: chunk.tag,
+                removed_by: None,
+            }
+        })
+        .collect();
+
+    let mut removed = plan.removed;
+    removed.sort_by_key(|chunk| chunk.subset);
+    let removed = removed
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| ExplainedChunk {
+            index,
+            bytes: chunk.subset,
+            lines: line_range(&contents, chunk.subset),
+            length: chunk.length,
+            rule: chunk.rule,
+            tag: None,
+            removed_by: Some(chunk.removed_by),
+        })
+        .collect();
+
+    Ok(ChunkExplanation {
+        filepath,
+        model: plan.model,
+        transforms: plan.transforms.iter().map(|t| t.to_string()).collect(),
+        chunks,
+        removed,
+    })
+}
+
+// embeds a file that isn't in the store yet, or again if it is, and adds it to the ledger
+//
+// like `import_jsonl`, the index is only updated in memory
+//
+// returns the number of embeddings made
+pub fn insert_file(
+    filepath: &str,
+    meta: HashSet<String>,
+    index: Option<&mut HNSW>,
+) -> Result<usize, std::io::Error> {
+    journal::record("insert_file", |params| {
+        params.insert("filepath".to_string(), filepath.into());
+        let inserted = run_insert_file(filepath, meta, index)?;
+        params.insert("embeddings".to_string(), inserted.into());
+
+        Ok(inserted)
+    })
+}
+
+fn run_insert_file(
+    filepath: &str,
+    meta: HashSet<String>,
+    index: Option<&mut HNSW>,
+) -> Result<usize, std::io::Error> {
+    let _lock = DataLock::acquire(LockMode::Exclusive, "insert_file")?;
+    bump_state_generation()?;
+
+    let source = EmbeddingSource {
+        filepath: filepath.to_string(),
+        meta,
+        subset: None,
+        hash: crate::ledger::get_hash(&filepath.to_string())
+            .map_err(|e| unreadable(filepath, e))?,
+        chunk_hash: None,
+    };
+
+    let mut embeddings = embed_store(&vec![path_source(&source), title_source(&source), source])?;
+
+    // the frequency table isn't recounted for a single file, that waits for the next sync
+    let signatures = chunk_signatures(&embeddings);
+    tag_boilerplate(&mut embeddings, &signatures, &read_frequencies()?);
+
+    let count = embeddings.len();
+    replace_files(&HashSet::from([filepath.to_string()]), embeddings, index)?;
+
+    lprint!(info, "Inserted {} with {} embeddings", filepath, count);
+
+    Ok(count)
+}
+
+// re-embeds a file in place, in the blocks that hold it
+//
+// with `ranges`, only the chunks overlapping those byte ranges of the file are re-embedded
+// and every other chunk keeps its id and vector.
+// chunks whose offsets moved (e.g. after an edit that changed the file's length)
+// can't be matched up with their old embeddings, so they're re-embedded too
+//
+// without `ranges` the whole file is re-embedded
+//
+// a file's chunks can be spread over several blocks after appends,
+// and the new embeddings go into the first of them with room
+//
+// the blocks and directory are written here, but writing `index` back out is left to the caller,
+// so that a burst of edits doesn't rewrite the whole index for each one
+pub fn update_file_embeddings(
+    filepath: &str,
+    ranges: Option<&[(u64, u64)]>,
+    index: &mut HNSW,
+) -> Result<(), std::io::Error> {
+    journal::record("update_file_embeddings", |params| {
+        params.insert("filepath".to_string(), filepath.into());
+        params.insert("ranges".to_string(), ranges.map(|r| r.len()).into());
+        run_update_file_embeddings(filepath, ranges, index, params)
+    })
+}
+
+fn run_update_file_embeddings(
+    filepath: &str,
+    ranges: Option<&[(u64, u64)]>,
+    index: &mut HNSW,
+    params: &mut Params,
+) -> Result<(), std::io::Error> {
+    let _lock = DataLock::acquire(LockMode::Exclusive, "update_file_embeddings")?;
+    bump_state_generation()?;
+
+    let directory = match get_directory() {
+        Ok(d) => d,
+        Err(e) => {
+            error!("error reading directory: {}", e);
+            return Err(e);
+        }
+    };
+
+    let existing = read_directory()?;
+    let id_start = next_embedding_id(existing.iter().map(|(entry, _)| entry.id))?;
+
+    let target_blocks = match directory.file_map.get(filepath) {
+        Some(b) => b.clone(),
+        None => {
+            error!(
+                "filepath {} not catalogued in Directory, aborting update",
+                filepath
+            );
+            return Ok(());
+        }
+    };
+
+    let current = EmbeddingModel::current();
+    let mut blocks = Vec::new();
+    for &block_number in target_blocks.iter() {
+        let block = read_embedding_block(block_number)?;
+        if block.model != current {
+            error!(
+                "block {} was embedded with {}, but the configured model is {}",
+                block_number, block.model, current
+            );
+
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "embedding model changed from {} to {}, a full embed is needed",
+                    block.model.name, current.name
+                ),
+            ));
+        }
+
+        blocks.push((block_number, block));
+    }
+
+    let file_embeddings = || {
+        blocks
+            .iter()
+            .flat_map(|(_, block)| block.embeddings.iter())
+            .filter(|e| e.source_file.filepath == filepath)
+    };
+
+    // the path embedding carries the file's meta without any chunk tags
+    let mut meta = HashSet::new();
+    let mut old_chunks = HashMap::new();
+    let mut old_title = None;
+    for e in file_embeddings() {
+        if e.source_file.meta.contains(PATH_META) {
+            meta = e.source_file.meta.clone();
+            meta.remove(PATH_META);
+        } else if e.source_file.meta.contains(TITLE_META) {
+            old_title = Some((e.id, e.source_file.chunk_hash));
+        } else if let Some(subset) = e.source_file.subset {
+            old_chunks.insert(subset, e.id);
+        }
+    }
+
+    let source = EmbeddingSource {
+        filepath: filepath.to_string(),
+        meta,
+        subset: None,
+        hash: crate::ledger::get_hash(&filepath.to_string())
+            .map_err(|e| unreadable(filepath, e))?,
+        chunk_hash: None,
+    };
+
+    let (to_delete, sources) = match ranges {
+        Some(ranges) => {
+            // the title is only embedded again if the edits changed it
+            let mut kept = HashSet::new();
+            let mut sources = Vec::new();
+            match old_title {
+                Some((id, hash)) if hash == Some(chunk_hash(&file_title(filepath))) => {
+                    kept.insert(id);
+                }
+                _ => sources.push(title_source(&source)),
+            }
+
+            for chunk in split_chunks(&source).map_err(|e| unreadable(filepath, e))? {
+                let subset = chunk.subset.unwrap();
+                let edited = ranges.iter().any(|&range| overlaps(subset, range));
+                match old_chunks.get(&subset) {
+                    Some(&id) if !edited => {
+                        kept.insert(id);
+                    }
+                    _ => sources.push(chunk),
+                }
+            }
+
+            let to_delete = old_chunks
+                .into_values()
+                .chain(old_title.map(|(id, _)| id))
+                .filter(|id| !kept.contains(id))
+                .collect::<HashSet<_>>();
+
+            (to_delete, sources)
+        }
+        None => {
+            let to_delete = file_embeddings().map(|e| e.id).collect::<HashSet<_>>();
+
+            (
+                to_delete,
+                vec![path_source(&source), title_source(&source), source.clone()],
+            )
+        }
+    };
+
+    info!(
+        "updating {} across {} blocks: {} embeddings removed, {} sources to embed",
+        filepath,
+        blocks.len(),
+        to_delete.len(),
+        sources.len()
+    );
+    params.insert("removed".to_string(), to_delete.len().into());
+
+    let mut new_embeddings = match sources.is_empty() {
+        true => Vec::new(),
+        false => embed_store(&sources)?,
+    };
+
+    for (i, e) in new_embeddings.iter_mut().enumerate() {
+        e.id = id_start + i as u64;
+    }
+
+    // the frequency table isn't recounted for a single file, that waits for the next sync
+    let signatures = chunk_signatures(&new_embeddings);
+    tag_boilerplate(&mut new_embeddings, &signatures, &read_frequencies()?);
+
+    let new_ids = new_embeddings.iter().map(|e| e.id).collect::<Vec<_>>();
+    params.insert("embedded".to_string(), new_ids.len().into());
+
+    // an unchanged chunk embedded again keeps its stable id, which moves to its new id
+    update_stable_ids(&to_delete, &new_embeddings, &PathResolver::current())?;
+
+    for (_, block) in blocks.iter_mut() {
+        block.embeddings.retain(|e| !to_delete.contains(&e.id));
+
+        // the embeddings kept through a range edit now belong to the file as it is
+        for e in block.embeddings.iter_mut() {
+            if e.source_file.filepath == filepath {
+                e.source_file.hash = source.hash.clone();
+            }
+        }
+    }
+
+    // the new embeddings fill the file's blocks first, then the last block in the store,
+    // and whatever's left goes into fresh blocks, so that no block grows past BLOCK_SIZE
+    let room = blocks
+        .iter()
+        .map(|(_, block)| BLOCK_SIZE.saturating_sub(block.embeddings.len()))
+        .sum::<usize>();
+    let store_blocks = block_numbers()?;
+    if let Some(&last) = store_blocks.last() {
+        if new_embeddings.len() > room
+            && !target_blocks.contains(&last)
+            && read_embedding_block_headers(last)?.len() < BLOCK_SIZE
+        {
+            let block = read_embedding_block(last)?;
+            if block.model == current {
+                blocks.push((last, block));
+            }
+        }
+    }
+
+    // a block left empty stays that way until the next reblock
+    let mut placed = Vec::new();
+    let mut pending = new_embeddings.into_iter();
+    for (block_number, block) in blocks.iter_mut() {
+        let room = BLOCK_SIZE.saturating_sub(block.embeddings.len());
+        for e in pending.by_ref().take(room) {
+            placed.push((DirectoryEntry::of(&e), *block_number as u32));
+            block.embeddings.push(e);
+        }
+    }
+
+    let fresh = store_blocks.last().map_or(0, |last| last + 1)..;
+    for (block_number, chunk) in fresh.zip(pending.collect::<Vec<_>>().chunks(BLOCK_SIZE)) {
+        placed.extend(
+            chunk
+                .iter()
+                .map(|e| (DirectoryEntry::of(e), block_number as u32)),
+        );
+        blocks.push((
+            block_number,
+            EmbeddingBlock::new(block_number, current.clone(), chunk.to_vec()),
+        ));
+    }
+
+    for (block_number, block) in blocks.iter_mut() {
+        block.write_to(&get_data_dir().join(block_number.to_string()))?;
+    }
+
+    let mut entries = existing
+        .into_iter()
+        .filter(|(entry, _)| !to_delete.contains(&(entry.id as u64)))
+        .collect::<Vec<_>>();
+    entries.extend(placed);
+
+    write_directory(&entries)?;
+    add_tombstones(to_delete.iter().copied())?;
+
+    for node in to_delete {
+        index.remove_node(node)?;
+    }
+
+    index.insert_nodes(&new_ids)?;
+
+    update_centroids(&HashSet::from([filepath.to_string()]))
+}
+
+// a failure to read `filepath`, carrying it along as a `SkippedSource`
+fn unreadable(filepath: &str, e: std::io::Error) -> std::io::Error {
+    SkippedSource::new(filepath, &e).into_error(e.kind())
+}
+
+// whether a chunk's `subset` touches the edited `range`
+//
+// an empty range is an insertion point, and touches the chunk it falls in
+fn overlaps(subset: (u64, u64), range: (u64, u64)) -> bool {
+    subset.0 < range.1.max(range.0 + 1) && range.0 < subset.1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dbio::{
+        build_index, file_info, find_stable_id, get_all_blocks, list_files, BlockEmbedding,
+    };
+    use crate::test_common::*;
+    use crate::write_file;
+
+    // a file should be found by a word in its name that its contents never mention
+    #[test]
+    fn path_embedding_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        // the fixture files are all identical, which leaves the index nothing to tell apart
+        let target = crate::config::get_home_dir().join("test_repo");
+        let words = ["alpha", "bravo", "charlie", "delta", "echo", "foxtrot"];
+        for (i, tf) in get_tracked_files().iter().enumerate() {
+            write_file!(target.join(tf), words[i..i + 3].join(" "));
+        }
+
+        write_file!(
+            target.join("invoice-2023-q3.rs"),
+            "lorem ipsum dolor sit amet"
+        );
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+
+        let index = HNSW::build(&crate::hnsw::HNSWParams::default()).unwrap();
+
+        let embedding = crate::openai::embed_text(
+            &EmbeddingSource {
+                filepath: String::new(),
+                meta: HashSet::new(),
+                subset: None,
+                hash: String::new(),
+                chunk_hash: None,
+            },
+            "invoice",
+        )
+        .unwrap();
+
+        let mut query = crate::hnsw::Query {
+            embedding,
+            filters: Vec::new(),
+            exclude_paths: false,
+            trace: false,
+            mode: crate::hnsw::SearchMode::Balanced,
+            candidates: None,
+            scope: None,
+            meta: None,
+        };
+
+        let results = index.query(&query, 3, 50).results;
+        assert!(results.first().is_some_and(|r| {
+            r.0.source_file.filepath.ends_with("invoice-2023-q3.rs")
+                && r.0.source_file.meta.contains(PATH_META)
+                && r.0.source_file.subset == Some((0, 0))
+        }));
+
+        query.exclude_paths = true;
+        let results = index.query(&query, 3, 200).results;
+        assert!(!results.is_empty());
+        assert!(results
+            .iter()
+            .all(|r| !r.0.source_file.meta.contains(PATH_META)));
+    }
+
+    // editing one chunk of a file re-embeds just that chunk
+    #[test]
+    fn range_update_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let filepath = crate::config::get_home_dir().join("test_repo").join("a.rs");
+        let contents = (0..300).map(|i| format!("word{} ", i)).collect::<String>();
+        write_file!(&filepath, &contents);
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+
+        let mut index = HNSW::build(&crate::hnsw::HNSWParams::default()).unwrap();
+
+        let filepath = filepath.to_string_lossy().to_string();
+        let file_embeddings = || {
+            let mut embeddings = get_all_blocks()
+                .unwrap()
+                .into_iter()
+                .map(|be| *be.embedding)
+                .filter(|e| e.source_file.filepath == filepath)
+                .collect::<Vec<_>>();
+            embeddings.sort_by_key(|e| e.source_file.subset);
+            embeddings
+        };
+
+        let before = file_embeddings();
+        assert!(before.len() > 3);
+
+        // same-length edit, so every other chunk stays where it was
+        let start = contents.find("word100 ").unwrap();
+        let edited = contents.replacen("word100 ", "edit100 ", 1);
+        write_file!(&filepath, &edited);
+
+        let range = (start as u64, start as u64 + 8);
+        assert!(update_file_embeddings(&filepath, Some(&[range]), &mut index).is_ok());
+
+        let after = file_embeddings();
+        assert_eq!(before.len(), after.len());
+
+        let mut changed = 0;
+        for (b, a) in before.iter().zip(after.iter()) {
+            assert_eq!(b.source_file.subset, a.source_file.subset);
+            if overlaps(a.source_file.subset.unwrap(), range) {
+                changed += 1;
+                assert_ne!(b.id, a.id);
+                assert!(index.get_last_layer().contains(a.id));
+                assert!(!index.get_last_layer().contains(b.id));
+            } else {
+                assert_eq!(b.id, a.id);
+                assert_eq!(b.data, a.data);
+            }
+        }
+
+        assert_eq!(changed, 1);
+
+        // the directory knows about the new chunk, so the index can be rebuilt over it
+        assert!(HNSW::build(&crate::hnsw::HNSWParams::default()).is_ok());
+    }
+
+    // unchanged chunks keep their stable ids through a re-embed, and an edited one gets a new one
+    #[test]
+    fn stable_id_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let filepath = crate::config::get_home_dir().join("test_repo").join("a.rs");
+        let contents = (0..300).map(|i| format!("word{} ", i)).collect::<String>();
+        write_file!(&filepath, &contents);
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+
+        let mut index = HNSW::build(&crate::hnsw::HNSWParams::default()).unwrap();
+
+        let filepath = filepath.to_string_lossy().to_string();
+        let stable_ids = || {
+            let info = file_info(&filepath).unwrap().unwrap();
+            info.subsets
+                .into_iter()
+                .zip(info.stable_ids)
+                .zip(info.ids)
+                .filter_map(|((subset, stable_id), id)| Some((subset?, (stable_id?, id))))
+                .collect::<std::collections::BTreeMap<_, _>>()
+        };
+
+        let before = stable_ids();
+        assert!(before.len() > 3);
+        for (subset, (stable_id, id)) in before.iter() {
+            let chunk = find_stable_id(stable_id).unwrap().unwrap();
+            assert_eq!((chunk.id, chunk.subset), (*id, Some(*subset)));
+        }
+
+        // every id changes, but the stable ids don't
+        assert!(update_file_embeddings(&filepath, None, &mut index).is_ok());
+        let reembedded = stable_ids();
+        assert_eq!(reembedded.len(), before.len());
+        for (subset, (stable_id, id)) in reembedded.iter() {
+            assert_eq!(before[subset].0, *stable_id);
+            assert_ne!(before[subset].1, *id);
+            assert_eq!(find_stable_id(stable_id).unwrap().unwrap().id, *id);
+        }
+
+        let start = contents.find("word100 ").unwrap();
+        write_file!(&filepath, contents.replacen("word100 ", "edit100 ", 1));
+        let range = (start as u64, start as u64 + 8);
+        assert!(update_file_embeddings(&filepath, Some(&[range]), &mut index).is_ok());
+
+        let edited = stable_ids();
+        let mut changed = 0;
+        for (subset, (stable_id, id)) in edited.iter() {
+            let (old, _) = &reembedded[subset];
+            if overlaps(*subset, range) {
+                changed += 1;
+                assert_ne!(old, stable_id);
+                assert!(find_stable_id(old).unwrap().is_none());
+            } else {
+                assert_eq!(old, stable_id);
+            }
+
+            assert_eq!(find_stable_id(stable_id).unwrap().unwrap().id, *id);
+        }
+        assert_eq!(changed, 1);
+
+        // a full sync renumbers everything, and the map follows
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+        let synced = stable_ids();
+        assert_eq!(
+            synced.values().map(|(s, _)| s).collect::<Vec<_>>(),
+            edited.values().map(|(s, _)| s).collect::<Vec<_>>()
+        );
+        let paths = PathResolver::current();
+        let all = get_all_blocks()
+            .unwrap()
+            .iter()
+            .filter_map(|be| be.embedding.source_file.stable_id(&paths))
+            .collect::<HashSet<_>>();
+        assert_eq!(
+            read_stable_ids()
+                .unwrap()
+                .into_keys()
+                .collect::<HashSet<_>>(),
+            all
+        );
+
+        // a map that's fallen behind is rebuilt by the lookup that misses
+        std::fs::remove_file(get_data_dir().join(STABLE_IDS_FILE)).unwrap();
+        for (stable_id, id) in synced.values() {
+            assert_eq!(find_stable_id(stable_id).unwrap().unwrap().id, *id);
+        }
+        assert!(get_data_dir().join(STABLE_IDS_FILE).exists());
+    }
+
+    // switching models in the config should stop incremental embeds and queries
+    // until everything is re-embedded
+    #[test]
+    fn model_switch_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+        assert_eq!(get_blocks_model().unwrap(), Some(EmbeddingModel::current()));
+
+        let index = HNSW::build(&crate::hnsw::HNSWParams::default()).unwrap();
+        let index_path = get_data_dir().join("index");
+        assert!(index.serialize(&index_path).is_ok());
+        assert_eq!(HNSW::read_header(&index_path).unwrap().0, index.model);
+
+        let config = crate::config::get_config_dir().join("config");
+        write_file!(
+            &config,
+            "# switching models\nmodel text-embedding-3-large\n"
+        );
+        assert_eq!(
+            crate::config::get_embedding_model(),
+            "text-embedding-3-large"
+        );
+
+        let target = crate::config::get_home_dir().join("test_repo");
+        write_file!(target.join("a.rs"), "fn changed() {}");
+
+        let error = sync_index(false, false, false, None, OverQuota::Stop, false).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(
+            get_blocks_model().unwrap().unwrap().name,
+            crate::config::DEFAULT_EMBEDDING_MODEL
+        );
+
+        let state = crate::ServerState::new().unwrap();
+        let error = state
+            .query(
+                crate::message::RequestPayload::Query {
+                    query: "changed".to_string(),
+                    filters: Vec::new(),
+                    k: 1,
+                    offset: 0,
+                    exclude_paths: false,
+                    group_by: None,
+                    group_score: crate::message::GroupScore::Max,
+                    discard_query: false,
+                    include_boilerplate: false,
+                    no_cache: false,
+                    debug: false,
+                    model: None,
+                    search_mode: crate::hnsw::SearchMode::Balanced,
+                    granularity: crate::message::Granularity::Chunk,
+                    paths: None,
+                    collection: None,
+                    dedupe_threshold: None,
+                },
+                None,
+            )
+            .unwrap_err();
+        assert!(error.to_string().contains("text-embedding-3-large"));
+
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+        assert_eq!(get_blocks_model().unwrap(), Some(EmbeddingModel::current()));
+        assert_eq!(EmbeddingModel::current().name, "text-embedding-3-large");
+    }
+
+    // a bulk sync ends up with the same embeddings as a regular one, and the index catches up on -r
+    #[test]
+    fn bulk_sync_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let target = crate::config::get_home_dir().join("test_repo");
+        let words = ["alpha", "bravo", "charlie", "delta", "echo", "foxtrot"];
+        for (i, tf) in get_tracked_files().iter().enumerate() {
+            write_file!(target.join(tf), words[i..i + 3].join(" "));
+        }
+
+        let stored = || {
+            let mut stored = get_all_blocks()
+                .unwrap()
+                .into_iter()
+                .map(|be| {
+                    let mut meta = be
+                        .embedding
+                        .source_file
+                        .meta
+                        .iter()
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    meta.sort();
+                    (
+                        be.embedding.source_file.filepath.clone(),
+                        be.embedding.source_file.subset,
+                        meta,
+                    )
+                })
+                .collect::<Vec<_>>();
+            stored.sort();
+            stored
+        };
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+        assert!(read_generation().unwrap().index_behind());
+        assert!(build_index().unwrap().is_some());
+        assert!(!read_generation().unwrap().index_behind());
+        let regular = stored();
+
+        let files = crate::ledger::read_ledger().unwrap().len();
+        assert_eq!(bulk_sync(true, false, 2).unwrap(), files);
+        assert_eq!(stored(), regular);
+        assert!(read_generation().unwrap().index_behind());
+
+        // every id is in the directory once, in the block it's stored in
+        let directory = get_directory().unwrap();
+        let blocks = get_all_blocks().unwrap();
+        assert_eq!(directory.id_map.len(), blocks.len());
+        for be in blocks.iter() {
+            assert_eq!(
+                directory.id_map.get(&(be.embedding.id as u32)),
+                Some(&be.block_number)
+            );
+        }
+
+        let state = crate::ServerState::new().unwrap();
+        let search = |state: &crate::ServerState| {
+            state
+                .search(
+                    "alpha",
+                    &crate::SearchOptions {
+                        no_cache: true,
+                        ..crate::SearchOptions::new(3)
+                    },
+                )
+                .unwrap()
+        };
+        assert!(search(&state).index_behind);
+
+        assert!(build_index().unwrap().is_some());
+        let state = crate::ServerState::new().unwrap();
+        let response = search(&state);
+        assert!(!response.index_behind);
+        assert!(!response.results.is_empty());
+
+        // a partial bulk sync only embeds what changed
+        write_file!(target.join("a.rs"), "golf hotel india");
+        assert_eq!(bulk_sync(false, false, 2).unwrap(), 1);
+        assert!(get_all_blocks().unwrap().iter().any(|be| be
+            .embedding
+            .source_file
+            .filepath
+            .ends_with("a.rs")
+            && be.embedding.source_file.subset != Some((0, 0))));
+        assert!(read_generation().unwrap().index_behind());
+    }
+
+    // embeddings go to disk a block at a time as they come back,
+    // so a sync never holds more than a block and the batches in flight
+    #[test]
+    fn streaming_sync_test() {
+        use std::sync::atomic::Ordering;
+
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+
+        let config = crate::config::get_config_dir();
+        write_file!(
+            config.join("config"),
+            "embed_workers 2\nmax_batch_items 8\n"
+        );
+        let rules = std::fs::read_to_string(config.join("rules")).unwrap();
+        write_file!(config.join("rules"), format!("{}\ntxt --split \\n", rules));
+
+        let target = crate::config::get_home_dir().join("test_repo");
+        for i in 0..10 {
+            let lines = (0..1000)
+                .map(|j| format!("line{}x{} of file{}", j, i, i))
+                .collect::<Vec<_>>();
+            write_file!(target.join(format!("bulk{}.txt", i)), lines.join("\n"));
+        }
+
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+
+        crate::openai::TEST_RETAINED.store(0, Ordering::SeqCst);
+        crate::openai::TEST_PEAK_RETAINED.store(0, Ordering::SeqCst);
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+
+        let stored = get_all_blocks().unwrap();
+        assert!(stored.len() > 10000);
+        assert_eq!(crate::openai::TEST_RETAINED.load(Ordering::SeqCst), 0);
+
+        // a block waiting to be written, plus a batch with each worker and each slot of the channel
+        let settings = crate::config::get_embed_settings();
+        let bound = BLOCK_SIZE + 2 * settings.workers * settings.max_batch_items;
+        let peak = crate::openai::TEST_PEAK_RETAINED.load(Ordering::SeqCst) as usize;
+        assert!(peak > 0 && peak <= bound, "{} retained at once", peak);
+
+        // ids run from 0 and the directory has every one of them, in its block
+        let directory = get_directory().unwrap();
+        assert_eq!(directory.len(), stored.len());
+        for (i, be) in stored.iter().enumerate() {
+            assert_eq!(be.embedding.id, i as u64);
+            assert_eq!(
+                directory.id_map.get(&(be.embedding.id as u32)),
+                Some(&be.block_number)
+            );
+        }
+        assert!(!get_data_dir().join("staging").exists());
+
+        // a partial sync carries the rest over block by block
+        write_file!(target.join("bulk0.txt"), "just the one line");
+        assert!(sync_index(false, false, false, None, OverQuota::Stop, false).is_ok());
+
+        let resynced = get_all_blocks().unwrap();
+        assert_eq!(
+            resynced
+                .iter()
+                .filter(|be| be.embedding.source_file.filepath.ends_with("bulk0.txt"))
+                .count(),
+            3
+        );
+        assert_eq!(
+            resynced.len(),
+            stored
+                .iter()
+                .filter(|be| !be.embedding.source_file.filepath.ends_with("bulk0.txt"))
+                .count()
+                + 3
+        );
+    }
+
+    // --only limits what gets embedded, and the rest of the embeddings are carried over
+    #[test]
+    fn only_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+
+        let file_hashes = |blocks: &[BlockEmbedding]| {
+            blocks
+                .iter()
+                .map(|be| {
+                    (
+                        be.embedding.source_file.filepath.clone(),
+                        be.embedding.source_file.hash.clone(),
+                    )
+                })
+                .collect::<HashMap<_, _>>()
+        };
+        let before = file_hashes(&get_all_blocks().unwrap());
+
+        let target = crate::config::get_home_dir().join("test_repo");
+        let path = |name: &str| target.join(name).to_string_lossy().to_string();
+        write_file!(target.join("a.rs"), "fn changed_a() {}");
+        write_file!(target.join("b.rs"), "fn changed_b() {}");
+
+        let only = crate::ledger::PathFilter::new(&[format!("{}/a.*", target.display())]);
+        let only = only.unwrap();
+        assert!(only.matches(&path("a.rs")));
+        assert!(!only.matches(&path("b.rs")));
+
+        // a dry run with --only changes nothing
+        assert!(sync_index(false, true, false, Some(&only), OverQuota::Stop, false).is_ok());
+        assert_eq!(file_hashes(&get_all_blocks().unwrap()), before);
+
+        assert!(sync_index(false, false, false, Some(&only), OverQuota::Stop, false).is_ok());
+        let after = file_hashes(&get_all_blocks().unwrap());
+        assert_eq!(after.len(), before.len());
+        assert_ne!(after[&path("a.rs")], before[&path("a.rs")]);
+        assert_eq!(after[&path("b.rs")], before[&path("b.rs")]);
+        assert_eq!(after[&path("c.rs")], before[&path("c.rs")]);
+
+        // a full embed with --only still leaves the other files alone
+        let only = crate::ledger::PathFilter::new(&["**/src/*.rs".to_string()]).unwrap();
+        let stored = get_all_blocks().unwrap();
+        assert!(sync_index(true, false, false, Some(&only), OverQuota::Stop, false).is_ok());
+        let resynced = get_all_blocks().unwrap();
+        assert_eq!(resynced.len(), stored.len());
+        assert_eq!(file_hashes(&resynced)[&path("b.rs")], before[&path("b.rs")]);
+
+        let directory = get_directory().unwrap();
+        assert_eq!(directory.len(), resynced.len());
+        let index = build_index().unwrap().unwrap();
+        assert_eq!(index.size as usize, directory.len());
+    }
+
+    // a sync over the quota stops before embedding anything, or embeds the smallest files
+    // that fit and leaves the rest for resumed syncs to get through
+    #[test]
+    fn embed_quota_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+
+        let ledger = crate::ledger::read_ledger().unwrap();
+        let estimates = estimate_sources(&stale_sources(&ledger, true).unwrap().0, None).unwrap();
+        assert!(estimates.windows(2).all(|w| w[0].tokens <= w[1].tokens));
+
+        // every file fits on its own, but not all of them together
+        let total = estimates.iter().map(|e| e.chunks).sum::<usize>();
+        let quota = estimates[..estimates.len() / 2]
+            .iter()
+            .map(|e| e.chunks)
+            .sum::<usize>()
+            .max(estimates.iter().map(|e| e.chunks).max().unwrap());
+        assert!(quota < total);
+        write_file!(
+            crate::config::get_config_dir().join("config"),
+            format!("max_run_chunks {}\n", quota)
+        );
+
+        let embedded = || {
+            get_all_blocks()
+                .unwrap()
+                .into_iter()
+                .filter(|be| !be.embedding.source_file.meta.contains(PATH_META))
+                .map(|be| be.embedding.source_file.filepath)
+                .collect::<HashSet<_>>()
+        };
+
+        let error = sync_index(true, false, false, None, OverQuota::Stop, false).unwrap_err();
+        assert!(error.to_string().contains("--partial"), "{}", error);
+        assert!(embedded().is_empty());
+        assert!(read_sync_remainder().unwrap().is_empty());
+
+        // the smallest files go first
+        sync_index(true, false, false, None, OverQuota::Partial, false).unwrap();
+        let fits = fit_quota(&estimates, &crate::config::get_embed_quota());
+        assert!(fits > 0 && fits < estimates.len());
+        assert_eq!(
+            embedded(),
+            estimates[..fits]
+                .iter()
+                .map(|e| e.filepath.clone())
+                .collect::<HashSet<_>>()
+        );
+        assert_eq!(
+            read_sync_remainder().unwrap(),
+            estimates[fits..]
+                .iter()
+                .map(|e| e.filepath.clone())
+                .collect::<Vec<_>>()
+        );
+
+        for _ in 0..estimates.len() {
+            if read_sync_remainder().unwrap().is_empty() {
+                break;
+            }
+
+            let before = embedded().len();
+            sync_index(false, false, false, None, OverQuota::Partial, true).unwrap();
+            assert!(embedded().len() > before);
+        }
+
+        assert_eq!(embedded().len(), estimates.len());
+        assert!(!get_data_dir().join(REMAINDER_FILE).exists());
+
+        let error = sync_index(false, false, false, None, OverQuota::Partial, true).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    // switching to a provider with less context re-chunks what no longer fits,
+    // and batches and queries are held to the new provider's limits
+    #[test]
+    fn provider_limits_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+
+        let config = crate::config::get_config_dir().join("config");
+        let model = crate::config::get_embedding_model();
+        let largest_chunk = || {
+            let indexing_rules = crate::ledger::get_indexing_rules().unwrap();
+            let ledger = crate::ledger::read_ledger().unwrap();
+            stale_sources(&ledger, true)
+                .unwrap()
+                .0
+                .iter()
+                .flat_map(|source| plan_chunks(source, &indexing_rules).unwrap().chunks)
+                .map(|chunk| chunk.text.len())
+                .max()
+                .unwrap()
+        };
+        let largest_batch = || {
+            let ledger = crate::ledger::read_ledger().unwrap();
+            let sources = stale_sources(&ledger, true).unwrap().0;
+            batch_sources(&sources, &crate::config::get_embed_settings())
+                .unwrap()
+                .0
+                .iter()
+                .map(|batch| batch.chunks.len())
+                .max()
+                .unwrap()
+        };
+
+        write_file!(&config, "max_input_tokens 8192\nmax_batch_items 4\n");
+        let before = largest_chunk();
+        assert!(before > 2);
+        assert!(largest_batch() <= 4);
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false)
+            .unwrap()
+            .is_empty());
+        assert!(stale_sources(&crate::ledger::read_ledger().unwrap(), false)
+            .unwrap()
+            .0
+            .is_empty());
+
+        // the model's own line wins over the plain keys
+        let limit = before / 2;
+        write_file!(
+            &config,
+            format!(
+                "max_input_tokens 8192\nmax_batch_items 4\nprovider {} max_input_tokens {} max_batch_items 2\n",
+                model, limit
+            )
+        );
+        assert_eq!(
+            crate::config::get_provider_limits(&model),
+            crate::config::ProviderLimits {
+                max_input_tokens: limit,
+                max_batch_items: 2,
+            }
+        );
+        assert!(largest_chunk() <= limit);
+        assert!(largest_batch() <= 2);
+
+        // nothing changed on disk, but the oversized files are stale all the same
+        assert!(crate::ledger::get_stale_files().unwrap().is_empty());
+        let ledger = crate::ledger::read_ledger().unwrap();
+        let oversized = stale_sources(&ledger, false).unwrap().0;
+        assert!(!oversized.is_empty());
+
+        assert!(sync_index(false, true, false, None, OverQuota::Stop, false).is_ok());
+        assert_eq!(
+            stale_sources(&ledger, false).unwrap().0.len(),
+            oversized.len()
+        );
+
+        assert!(
+            sync_index(false, false, false, None, OverQuota::Stop, false)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(stale_sources(&ledger, false).unwrap().0.is_empty());
+
+        let source = EmbeddingSource {
+            filepath: String::new(),
+            meta: HashSet::new(),
+            subset: None,
+            hash: String::new(),
+            chunk_hash: None,
+        };
+        let error = crate::openai::embed_text(&source, &"x".repeat(limit + 1)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(crate::openai::embed_text(&source, &"x".repeat(limit)).is_ok());
+    }
+
+    // a file that can't be read is skipped, and the rest of the sync goes through
+    #[test]
+    fn unreadable_file_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false)
+            .unwrap()
+            .is_empty());
+
+        let target = crate::config::get_home_dir().join("test_repo");
+        let unreadable = target.join("a.rs");
+        let filepath = unreadable.to_string_lossy().to_string();
+        let count_of = |blocks: &[BlockEmbedding], path: &str| {
+            blocks
+                .iter()
+                .filter(|be| be.embedding.source_file.filepath == path)
+                .count()
+        };
+        let before = count_of(&get_all_blocks().unwrap(), &filepath);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&unreadable, std::fs::Permissions::from_mode(0o000)).unwrap();
+        }
+
+        // root reads it anyway, so a directory takes its place instead
+        if std::fs::read(&unreadable).is_ok() {
+            std::fs::remove_file(&unreadable).unwrap();
+            crate::create_dir!(&unreadable);
+        }
+
+        write_file!(target.join("b.rs"), "fn changed() {}");
+
+        // the partial sync keeps what the file had
+        let skipped = sync_index(false, false, false, None, OverQuota::Stop, false).unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].filepath, filepath);
+
+        let blocks = get_all_blocks().unwrap();
+        assert_eq!(count_of(&blocks, &filepath), before);
+        assert!(blocks
+            .iter()
+            .any(|be| be.embedding.source_file.filepath.ends_with("b.rs")
+                && be.embedding.source_file.hash
+                    == crate::ledger::hash_contents(b"fn changed() {}")));
+
+        // a full embed has nothing to keep for it
+        let skipped = sync_index(true, false, false, None, OverQuota::Stop, false).unwrap();
+        assert!(skipped.len() == 1 && skipped[0].filepath == filepath);
+        let blocks = get_all_blocks().unwrap();
+        assert_eq!(count_of(&blocks, &filepath), 0);
+        assert_eq!(
+            count_of(&blocks, &target.join("c.rs").to_string_lossy()),
+            before
+        );
+        assert_eq!(get_directory().unwrap().len(), blocks.len());
+
+        // an edit of a file that went away names it
+        let index = build_index().unwrap().unwrap();
+        let mut state = crate::ServerState::with_index(index);
+        write_file!(target.join("c.rs"), "fn gone() {}");
+        assert!(state
+            .reindex(crate::message::RequestPayload::Edit {
+                filepath: target.join("c.rs").to_string_lossy().to_string(),
+                ranges: None,
+                collection: None,
+                sync: true,
+            })
+            .is_ok());
+        std::fs::remove_file(target.join("c.rs")).unwrap();
+
+        let response = state
+            .reindex(crate::message::RequestPayload::Edit {
+                filepath: target.join("c.rs").to_string_lossy().to_string(),
+                ranges: None,
+                collection: None,
+                sync: true,
+            })
+            .unwrap();
+        let response =
+            serde_json::from_str::<crate::message::DeweyErrorResponse>(&response).unwrap();
+        assert_eq!(response.error, "unreadable_file");
+        assert_eq!(
+            response.filepath,
+            Some(target.join("c.rs").to_string_lossy().to_string())
+        );
+    }
+
+    // files with nothing to embed sync without leaving anything in the directory
+    #[test]
+    fn blank_files_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let blank_files = setup_blank_files().unwrap();
+        let target = crate::config::get_home_dir().join("test_repo");
+        let path = |file: &str| target.join(file).to_string_lossy().to_string();
+
+        let report = crate::ledger::sync_ledger_config(true, None).unwrap();
+        assert_eq!(report.kept, get_tracked_files().len() + blank_files.len());
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false)
+            .unwrap()
+            .is_empty());
+
+        let check = || {
+            let directory = get_directory().unwrap();
+            for file in blank_files.iter() {
+                assert!(!directory.file_map.contains_key(&path(file)), "{}", file);
+            }
+            for file in get_tracked_files().iter() {
+                assert!(directory.file_map.contains_key(&path(file)), "{}", file);
+            }
+        };
+        check();
+
+        // an edit that blanks out a file leaves it with nothing too
+        let mut index = build_index().unwrap().unwrap();
+        write_file!(target.join("a.rs"), "\n\n");
+        assert!(update_file_embeddings(&path("a.rs"), None, &mut index).is_ok());
+        assert!(!get_directory()
+            .unwrap()
+            .file_map
+            .contains_key(&path("a.rs")));
+
+        // it isn't catalogued anymore, so it takes a sync to embed its contents again
+        write_file!(target.join("a.rs"), "b".repeat(10000));
+        assert!(
+            sync_index(false, false, false, None, OverQuota::Stop, false)
+                .unwrap()
+                .is_empty()
+        );
+        check();
+    }
+
+    // re-embedding a file moves its time forward and leaves every other file's alone
+    #[test]
+    fn embedded_at_test() {
+        let _cleanup = Cleanup;
+
+        assert!(setup().is_ok());
+        let start = chrono::Utc::now().timestamp();
+        assert!(crate::ledger::sync_ledger_config(true, None).is_ok());
+        assert!(sync_index(true, false, false, None, OverQuota::Stop, false).is_ok());
+        let end = chrono::Utc::now().timestamp();
+
+        let target = crate::config::get_home_dir().join("test_repo");
+        let path = |file: &str| target.join(file).to_string_lossy().to_string();
+        let embedded_at = || {
+            get_tracked_files()
+                .iter()
+                .map(|file| {
+                    (
+                        path(file),
+                        file_info(&path(file)).unwrap().unwrap().embedded_at,
+                    )
+                })
+                .collect::<HashMap<_, _>>()
+        };
+
+        let before = embedded_at();
+        assert!(before.values().all(|t| (start..=end).contains(t)));
+
+        let listed = list_files().unwrap();
+        assert_eq!(listed.len(), before.len());
+        for file in listed.iter() {
+            assert_eq!(file.embedded_at, before[&file.filepath]);
+            assert_eq!(
+                file.chunk_count,
+                file_info(&file.filepath).unwrap().unwrap().chunk_count
+            );
+        }
+
+        // the times are in seconds
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+
+        let edited = path("a.rs");
+        write_file!(target.join("a.rs"), "c".repeat(10000));
+        let mut index = build_index().unwrap().unwrap();
+        assert!(update_file_embeddings(&edited, None, &mut index).is_ok());
+
+        let after = embedded_at();
+        assert!(after[&edited] > before[&edited]);
+        for (file, t) in after.iter().filter(|(file, _)| **file != edited) {
+            assert_eq!(*t, before[file], "{}", file);
+        }
+    }
+}
@@ -73,13 +73,13 @@ macro_rules! test_print {
 
 pub fn setup() -> Result<(), std::io::Error> {
     test_print!("===BEGIN SETUP===");
-    crate::config::setup();
+    crate::config::setup(crate::logger::LogTarget::Library)?;
     let root = crate::config::get_home_dir();
     let config = crate::config::get_config_dir();
 
     let target = root.join("test_repo");
 
-    let ledger_contents = vec![format!(
+    let ledger_contents = [format!(
         "{} {}",
         target.to_str().unwrap(),
         get_meta()
@@ -93,7 +93,7 @@ pub fn setup() -> Result<(), std::io::Error> {
     write_file!(config.join("ledger"), ledger_contents.clone());
     test_print!("set ledger with:\n{}\n", ledger_contents);
 
-    let rule_contents = vec![
+    let rule_contents = [
         "* --minlength 0 --maxlength 512 --alphanumeric true",
         "rs --naive",
         "md --split \\n",
@@ -134,3 +134,89 @@ pub fn setup() -> Result<(), std::io::Error> {
 
     Ok(())
 }
+
+// writes `n` random, normalized embeddings straight into the data directory
+// in place of an embedding run against the API
+//
+// embeddings are spread across the tracked files of the test repo
+pub fn setup_embeddings(n: usize) -> Result<Vec<crate::embedding::Embedding>, std::io::Error> {
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+    let target = crate::config::get_home_dir().join("test_repo");
+    let tracked_files = get_tracked_files();
+    let meta = get_meta()
+        .into_iter()
+        .collect::<std::collections::HashSet<_>>();
+
+    let embeddings = (0..n)
+        .map(|i| {
+            let mut embedding = crate::embedding::Embedding {
+                id: i as u64,
+                source_file: crate::embedding::EmbeddingSource {
+                    filepath: target
+                        .join(&tracked_files[i % tracked_files.len()])
+                        .to_string_lossy()
+                        .to_string(),
+                    meta: meta.clone(),
+                    subset: Some((i as u64 * 10, i as u64 * 10 + 10)),
+                    hash: String::new(),
+                    chunk_hash: None,
+                },
+                embedded_at: 0,
+                data: [0.0; crate::embedding::EMBED_DIM].map(|_| rng.gen_range(-1.0..1.0)),
+            };
+
+            crate::hnsw::normalize(&mut embedding);
+            embedding
+        })
+        .collect::<Vec<_>>();
+
+    crate::dbio::write_blocks(&embeddings)?;
+    test_print!("wrote {} test embeddings", n);
+
+    Ok(embeddings)
+}
+
+// adds files to the test repo with nothing in them to embed
+//
+// `short.txt` is shorter than the separator its extension is split on
+pub fn setup_blank_files() -> Result<Vec<String>, std::io::Error> {
+    let target = crate::config::get_home_dir().join("test_repo");
+    let rules = crate::config::get_config_dir().join("rules");
+
+    let mut rule_contents = std::fs::read_to_string(&rules)?;
+    rule_contents.push_str("\ntxt --split =====");
+    write_file!(&rules, rule_contents);
+
+    let files = [
+        ("empty.rs", ""),
+        ("blank.rs", " \n\t\n  \r\n"),
+        ("short.txt", "=="),
+    ];
+    for (file, contents) in files.iter() {
+        write_file!(target.join(file), contents);
+    }
+    test_print!("set blank files in {}", target.to_str().unwrap());
+
+    Ok(files.iter().map(|(file, _)| file.to_string()).collect())
+}
+
+// adds a self-referential directory symlink and a link to a file outside the test repo
+//
+// returns the path of the outside file
+#[cfg(unix)]
+pub fn setup_symlinks() -> Result<std::path::PathBuf, std::io::Error> {
+    let root = crate::config::get_home_dir();
+    let target = root.join("test_repo");
+
+    let outside = root.join("outside");
+    create_dir!(&outside);
+    write_file!(outside.join("outside.rs"), "c".repeat(10000));
+
+    std::os::unix::fs::symlink(&target, target.join("loop"))?;
+    std::os::unix::fs::symlink(outside.join("outside.rs"), target.join("outside.rs"))?;
+    test_print!("set symlinks in {}", target.to_str().unwrap());
+
+    Ok(outside.join("outside.rs"))
+}
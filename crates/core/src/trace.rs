@@ -0,0 +1,617 @@
+// spans around the server's work--handling requests, embedding, loading blocks, searching the index
+//
+// with the `otel` feature, finished spans are sent as OTLP/HTTP JSON to the collector at
+// OTEL_EXPORTER_OTLP_ENDPOINT or the `otel_endpoint` config value (e.g. `http://localhost:4318`),
+// and without it every span is a no-op that keeps nothing
+//
+// a span started outside of any trace starts one of its own,
+// which the spans started inside of it on the same thread join
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Self {
+        Value::Int(value as i64)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(value: u64) -> Self {
+        Value::Int(value as i64)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Str(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Str(value)
+    }
+}
+
+// ends, and is exported, when it's dropped
+pub struct Span {
+    #[cfg(feature = "otel")]
+    data: otel::SpanData,
+}
+
+impl Span {
+    pub fn start(name: &'static str) -> Span {
+        #[cfg(not(feature = "otel"))]
+        let _ = name;
+
+        Span {
+            #[cfg(feature = "otel")]
+            data: otel::SpanData::start(name),
+        }
+    }
+
+    pub fn set(&mut self, key: &'static str, value: impl Into<Value>) {
+        #[cfg(feature = "otel")]
+        self.data.attributes.push((key, value.into()));
+        #[cfg(not(feature = "otel"))]
+        let _ = (key, value);
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        self.data.end();
+    }
+}
+
+// runs `f` under the trace the client asked for, or a new one
+//
+// `trace_id` is either a W3C `traceparent`, whose span becomes the parent of the spans here,
+// or a 32 character hex trace id--anything else is hashed into one
+pub fn in_trace<T>(trace_id: Option<&str>, f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "otel")]
+    return otel::Context::from_request(trace_id).attach(f);
+    #[cfg(not(feature = "otel"))]
+    {
+        let _ = trace_id;
+        f()
+    }
+}
+
+// the trace and span this thread is in, to carry into threads it starts
+#[derive(Clone, Default)]
+pub struct Context {
+    #[cfg(feature = "otel")]
+    inner: Option<otel::Context>,
+}
+
+impl Context {
+    pub fn current() -> Context {
+        Context {
+            #[cfg(feature = "otel")]
+            inner: otel::Context::current(),
+        }
+    }
+
+    pub fn attach<T>(&self, f: impl FnOnce() -> T) -> T {
+        #[cfg(feature = "otel")]
+        if let Some(context) = &self.inner {
+            return context.attach(f);
+        }
+
+        f()
+    }
+}
+
+#[cfg(feature = "otel")]
+pub use otel::{take_finished, use_memory_exporter, FinishedSpan};
+
+#[cfg(feature = "otel")]
+mod otel {
+    use std::cell::RefCell;
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Mutex, OnceLock};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::Value;
+    use crate::error;
+    use crate::logger::Logger;
+
+    // spans are sent in batches of up to this many
+    const EXPORT_BATCH: usize = 512;
+    // and a batch is sent once no span has finished for this long
+    const EXPORT_WAIT: Duration = Duration::from_millis(500);
+
+    #[derive(Debug, Clone)]
+    pub struct FinishedSpan {
+        pub name: &'static str,
+        pub trace_id: String,
+        pub span_id: String,
+        pub parent_span_id: Option<String>,
+        pub start_ns: u64,
+        pub end_ns: u64,
+        pub attributes: Vec<(&'static str, Value)>,
+    }
+
+    impl FinishedSpan {
+        pub fn attribute(&self, key: &str) -> Option<&Value> {
+            self.attributes
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v)
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct Context {
+        trace_id: [u8; 16],
+        // the innermost open span, last
+        stack: Vec<[u8; 8]>,
+    }
+
+    thread_local! {
+        static CURRENT: RefCell<Option<Context>> = const { RefCell::new(None) };
+    }
+
+    impl Context {
+        pub fn current() -> Option<Context> {
+            CURRENT.with(|c| c.borrow().clone())
+        }
+
+        pub fn from_request(trace_id: Option<&str>) -> Context {
+            let trace_id = trace_id.map(str::trim).filter(|id| !id.is_empty());
+            let mut context = Context {
+                trace_id: rand::random(),
+                stack: Vec::new(),
+            };
+
+            let Some(id) = trace_id else {
+                return context;
+            };
+
+            let parts = id.split('-').collect::<Vec<_>>();
+            match parts.as_slice() {
+                [_, trace, parent, _] if parse_hex::<16>(trace).is_some() => {
+                    context.trace_id = parse_hex(trace).unwrap();
+                    if let Some(parent) = parse_hex(parent) {
+                        context.stack.push(parent);
+                    }
+                }
+                _ => match parse_hex(id) {
+                    Some(trace) => context.trace_id = trace,
+                    None => {
+                        use sha2::Digest;
+                        let digest = sha2::Sha256::digest(id.as_bytes());
+                        context.trace_id.copy_from_slice(&digest[..16]);
+                    }
+                },
+            }
+
+            context
+        }
+
+        pub fn attach<T>(&self, f: impl FnOnce() -> T) -> T {
+            // put back even if `f` panics, since the thread may go on to handle something else
+            struct Restore(Option<Context>);
+            impl Drop for Restore {
+                fn drop(&mut self) {
+                    let previous = self.0.take();
+                    CURRENT.with(|c| *c.borrow_mut() = previous);
+                }
+            }
+
+            let _restore = Restore(CURRENT.with(|c| c.borrow_mut().replace(self.clone())));
+            f()
+        }
+    }
+
+    // a hex id of exactly `N` bytes that isn't all zeroes, which OTLP treats as no id
+    fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+        if hex.len() != N * 2 || !hex.is_ascii() {
+            return None;
+        }
+
+        let mut bytes = [0u8; N];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+
+        bytes.iter().any(|b| *b != 0).then_some(bytes)
+    }
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn now_ns() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0)
+    }
+
+    pub struct SpanData {
+        name: &'static str,
+        trace_id: [u8; 16],
+        span_id: [u8; 8],
+        parent: Option<[u8; 8]>,
+        start_ns: u64,
+        // whether the span started the thread's trace, and so ends it
+        owns_trace: bool,
+        pub attributes: Vec<(&'static str, Value)>,
+    }
+
+    impl SpanData {
+        pub fn start(name: &'static str) -> SpanData {
+            let span_id: [u8; 8] = rand::random();
+            let (trace_id, parent, owns_trace) = CURRENT.with(|c| {
+                let mut current = c.borrow_mut();
+                let owns_trace = current.is_none();
+                let context = current.get_or_insert_with(|| Context::from_request(None));
+                let parent = context.stack.last().copied();
+                context.stack.push(span_id);
+
+                (context.trace_id, parent, owns_trace)
+            });
+
+            SpanData {
+                name,
+                trace_id,
+                span_id,
+                parent,
+                start_ns: now_ns(),
+                owns_trace,
+                attributes: Vec::new(),
+            }
+        }
+
+        pub fn end(&mut self) {
+            CURRENT.with(|c| {
+                let mut current = c.borrow_mut();
+                if self.owns_trace {
+                    *current = None;
+                } else if let Some(context) = current.as_mut() {
+                    if let Some(position) = context.stack.iter().rposition(|s| *s == self.span_id) {
+                        context.stack.truncate(position);
+                    }
+                }
+            });
+
+            export(FinishedSpan {
+                name: self.name,
+                trace_id: to_hex(&self.trace_id),
+                span_id: to_hex(&self.span_id),
+                parent_span_id: self.parent.map(|p| to_hex(&p)),
+                start_ns: self.start_ns,
+                end_ns: now_ns(),
+                attributes: std::mem::take(&mut self.attributes),
+            });
+        }
+    }
+
+    static MEMORY_MODE: AtomicBool = AtomicBool::new(false);
+    static MEMORY: Mutex<Vec<FinishedSpan>> = Mutex::new(Vec::new());
+    static EXPORTER: OnceLock<Option<mpsc::Sender<FinishedSpan>>> = OnceLock::new();
+
+    // keeps finished spans in memory for `take_finished` instead of sending them anywhere
+    pub fn use_memory_exporter() {
+        MEMORY_MODE.store(true, Ordering::SeqCst);
+    }
+
+    pub fn take_finished() -> Vec<FinishedSpan> {
+        std::mem::take(&mut *MEMORY.lock().unwrap_or_else(|p| p.into_inner()))
+    }
+
+    fn export(span: FinishedSpan) {
+        if MEMORY_MODE.load(Ordering::SeqCst) {
+            MEMORY.lock().unwrap_or_else(|p| p.into_inner()).push(span);
+            return;
+        }
+
+        let exporter = EXPORTER.get_or_init(|| {
+            let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+                .ok()
+                .filter(|e| !e.trim().is_empty())
+                .or_else(|| crate::config::get_config_value("otel_endpoint"))?;
+            let endpoint = match Endpoint::parse(&endpoint) {
+                Ok(endpoint) => endpoint,
+                Err(e) => {
+                    error!("not exporting spans: {}", e);
+                    return None;
+                }
+            };
+
+            let (tx, rx) = mpsc::channel();
+            std::thread::spawn(move || export_loop(endpoint, rx));
+
+            Some(tx)
+        });
+
+        if let Some(tx) = exporter {
+            let _ = tx.send(span);
+        }
+    }
+
+    fn export_loop(endpoint: Endpoint, rx: mpsc::Receiver<FinishedSpan>) {
+        while let Ok(first) = rx.recv() {
+            let mut batch = vec![first];
+            while batch.len() < EXPORT_BATCH {
+                match rx.recv_timeout(EXPORT_WAIT) {
+                    Ok(span) => batch.push(span),
+                    Err(_) => break,
+                }
+            }
+
+            if let Err(e) = endpoint.post(&otlp_json(&batch)) {
+                error!(
+                    "failed to export {} spans to {}: {}",
+                    batch.len(),
+                    endpoint.host,
+                    e
+                );
+            }
+        }
+    }
+
+    fn otlp_value(value: &Value) -> serde_json::Value {
+        match value {
+            // 64 bit integers are strings in OTLP's JSON
+            Value::Int(i) => serde_json::json!({ "intValue": i.to_string() }),
+            Value::Float(f) => serde_json::json!({ "doubleValue": f }),
+            Value::Bool(b) => serde_json::json!({ "boolValue": b }),
+            Value::Str(s) => serde_json::json!({ "stringValue": s }),
+        }
+    }
+
+    pub(super) fn otlp_json(spans: &[FinishedSpan]) -> serde_json::Value {
+        let spans = spans
+            .iter()
+            .map(|span| {
+                let attributes = span
+                    .attributes
+                    .iter()
+                    .map(|(k, v)| serde_json::json!({ "key": k, "value": otlp_value(v) }))
+                    .collect::<Vec<_>>();
+
+                serde_json::json!({
+                    "traceId": span.trace_id,
+                    "spanId": span.span_id,
+                    "parentSpanId": span.parent_span_id.clone().unwrap_or_default(),
+                    "name": span.name,
+                    // SPAN_KIND_SERVER for requests, SPAN_KIND_INTERNAL otherwise
+                    "kind": if span.name == "dewey.request" { 2 } else { 1 },
+                    "startTimeUnixNano": span.start_ns.to_string(),
+                    "endTimeUnixNano": span.end_ns.to_string(),
+                    "attributes": attributes,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        { "key": "service.name", "value": { "stringValue": "dewey" } },
+                    ],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "dewey", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans,
+                }],
+            }],
+        })
+    }
+
+    struct Endpoint {
+        tls: bool,
+        host: String,
+        port: u16,
+        path: String,
+    }
+
+    impl Endpoint {
+        // spans go to `/v1/traces` under the endpoint, unless it already names that path
+        fn parse(endpoint: &str) -> Result<Endpoint, std::io::Error> {
+            let invalid = || {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid OTLP endpoint \"{}\"", endpoint),
+                )
+            };
+
+            let endpoint = endpoint.trim();
+            let (tls, rest) = match endpoint.split_once("://") {
+                Some(("http", rest)) => (false, rest),
+                Some(("https", rest)) => (true, rest),
+                _ => return Err(invalid()),
+            };
+
+            let (authority, path) = match rest.find('/') {
+                Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+                None => (rest, ""),
+            };
+
+            let (host, port) = match authority.rsplit_once(':') {
+                Some((host, port)) => (host, port.parse::<u16>().map_err(|_| invalid())?),
+                None => (authority, if tls { 443 } else { 80 }),
+            };
+            if host.is_empty() {
+                return Err(invalid());
+            }
+
+            let path = match path.ends_with("/v1/traces") {
+                true => path.to_string(),
+                false => format!("{}/v1/traces", path),
+            };
+
+            Ok(Endpoint {
+                tls,
+                host: host.to_string(),
+                port,
+                path,
+            })
+        }
+
+        fn post(&self, body: &serde_json::Value) -> Result<(), std::io::Error> {
+            let request = crate::http::HttpRequest::post(&self.host, &self.path)?
+                .header("Connection", "close")?
+                .json(body)?
+                .to_bytes();
+
+            let stream = std::net::TcpStream::connect((self.host.as_str(), self.port))?;
+            stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+            stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+
+            let mut response = String::new();
+            match self.tls {
+                true => {
+                    let connector =
+                        native_tls::TlsConnector::new().map_err(std::io::Error::other)?;
+                    let mut stream = connector
+                        .connect(&self.host, stream)
+                        .map_err(std::io::Error::other)?;
+                    stream.write_all(&request)?;
+                    stream.read_to_string(&mut response)?;
+                }
+                false => {
+                    let mut stream = stream;
+                    stream.write_all(&request)?;
+                    stream.read_to_string(&mut response)?;
+                }
+            }
+
+            let status = response.lines().next().unwrap_or_default();
+            match status.split_whitespace().nth(1) {
+                Some(code) if code.starts_with('2') => Ok(()),
+                _ => Err(std::io::Error::other(format!(
+                    "collector responded with \"{}\"",
+                    status
+                ))),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn endpoint_test() {
+            let endpoint = Endpoint::parse("http://localhost:4318").unwrap();
+            assert_eq!(
+                (endpoint.tls, endpoint.host.as_str(), endpoint.port),
+                (false, "localhost", 4318)
+            );
+            assert_eq!(endpoint.path, "/v1/traces");
+
+            let endpoint = Endpoint::parse("https://collector.example/otlp/v1/traces/").unwrap();
+            assert_eq!((endpoint.tls, endpoint.port), (true, 443));
+            assert_eq!(endpoint.path, "/otlp/v1/traces");
+
+            assert!(Endpoint::parse("localhost:4318").is_err());
+            assert!(Endpoint::parse("http://:4318").is_err());
+        }
+    }
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans_test() {
+        use_memory_exporter();
+        take_finished();
+
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let context = in_trace(Some(trace_id), || {
+            let mut request = Span::start("dewey.request");
+            request.set("message_type", "query");
+
+            let context = Context::current();
+            std::thread::spawn(move || {
+                context.attach(|| {
+                    let mut embed = Span::start("dewey.embed");
+                    embed.set("inputs", 1usize);
+                })
+            })
+            .join()
+            .unwrap();
+
+            let mut search = Span::start("dewey.hnsw.search");
+            search.set("k", 10usize);
+            search.set("ef", 40usize);
+            drop(search);
+
+            Context::current()
+        });
+        // the trace ends with the request
+        assert!(context.inner.is_some());
+        assert!(Context::current().inner.is_none());
+
+        let spans = take_finished();
+        let names = spans.iter().map(|s| s.name).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["dewey.embed", "dewey.hnsw.search", "dewey.request"]
+        );
+        assert!(spans.iter().all(|s| s.trace_id == trace_id));
+
+        let request = &spans[2];
+        assert_eq!(request.parent_span_id, None);
+        assert_eq!(
+            request.attribute("message_type"),
+            Some(&Value::from("query"))
+        );
+        for span in &spans[..2] {
+            assert_eq!(span.parent_span_id.as_ref(), Some(&request.span_id));
+            assert!(span.start_ns >= request.start_ns && span.end_ns <= request.end_ns);
+        }
+        assert_eq!(spans[1].attribute("k"), Some(&Value::Int(10)));
+
+        // a traceparent's span is the parent of the request
+        in_trace(
+            Some("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            || Span::start("dewey.request"),
+        );
+        // and ids that aren't hex are hashed, the same way every time
+        in_trace(Some("request-1"), || Span::start("dewey.request"));
+        in_trace(Some("request-1"), || Span::start("dewey.request"));
+        let spans = take_finished();
+        assert_eq!(spans[0].trace_id, trace_id);
+        assert_eq!(spans[0].parent_span_id.as_deref(), Some("00f067aa0ba902b7"));
+        assert_eq!(spans[1].trace_id, spans[2].trace_id);
+        assert_ne!(spans[1].trace_id, trace_id);
+
+        let json = otel::otlp_json(&spans);
+        let exported = &json["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(exported.as_array().unwrap().len(), 3);
+        assert_eq!(exported[0]["traceId"], trace_id);
+    }
+}
@@ -0,0 +1,201 @@
+// what the index, the ledger, the journal, and the config hand out in responses,
+// kept apart from them so that a `client` build can read the responses without the rest of the crate
+//
+// each is re-exported from where it's used, e.g. `dbio::FileInfo`
+
+// how much of the index a query looks through, trading recall for speed
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    // a small ef, and the bottom layer stops expanding after `ef` nodes
+    Fast,
+    #[default]
+    Balanced,
+    // a larger ef, searched from several entry points in the topmost layer
+    Thorough,
+}
+
+// how the search went through a single layer
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct LayerTrace {
+    pub layer: usize,
+    // nodes whose distance to the query was measured
+    pub visited: usize,
+    // nodes whose neighbors were looked at
+    pub expanded: usize,
+    // nodes that were close enough to keep, but didn't pass the query's filters
+    pub filtered: usize,
+    // the candidates the layer ended with, at most `ef`
+    pub kept: usize,
+    // the layer ran out of nodes to look at before it had `ef` candidates
+    pub exhausted: bool,
+}
+
+// how a filtered query narrowed down its search, or made up for one that came up short
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase", tag = "strategy")]
+pub enum FilterStrategy {
+    // the graph search found enough on its own, or there was nothing else to try
+    #[default]
+    Graph,
+    // the bottom layer was searched again with a larger ef and budget
+    Widened {
+        ef: usize,
+    },
+    // the nodes that could pass were compared against the query one by one
+    Scan {
+        candidates: usize,
+    },
+    // the bottom layer was only searched through the nodes the meta index said could pass
+    Constrained {
+        candidates: usize,
+    },
+}
+
+impl std::fmt::Display for FilterStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FilterStrategy::Graph => write!(f, "graph search"),
+            FilterStrategy::Widened { ef } => write!(f, "widened to ef {}", ef),
+            FilterStrategy::Scan { candidates } => write!(f, "scanned {} candidates", candidates),
+            FilterStrategy::Constrained { candidates } => {
+                write!(f, "searched through {} candidates", candidates)
+            }
+        }
+    }
+}
+
+// how a query traversed the index, for working out why its results are what they are
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct QueryTrace {
+    pub ef: usize,
+    #[serde(default)]
+    pub mode: SearchMode,
+    #[serde(default)]
+    pub filter_strategy: FilterStrategy,
+    // top layer first
+    pub layers: Vec<LayerTrace>,
+    // of the final results, closest first
+    pub distances: Vec<f32>,
+    // embedding lookups answered from blocks in memory, and blocks read from disk
+    #[serde(default)]
+    pub cache_hits: usize,
+    #[serde(default)]
+    pub block_loads: usize,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct BlockReport {
+    pub block_number: u64,
+    pub embeddings: usize,
+    pub bytes: u64,
+    // embeddings / BLOCK_SIZE
+    pub fill_factor: f32,
+    pub files: usize,
+    // embeddings the directory doesn't point to this block, which nothing can reach
+    pub unreachable: usize,
+    pub unreachable_bytes: u64,
+}
+
+// what the store holds of one file
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct FileInfo {
+    pub chunk_count: usize,
+    // every block holding embeddings of the file, in order
+    pub blocks: Vec<u64>,
+    pub ids: Vec<u64>,
+    // the byte range of each of `ids`, with `(0, 0)` for the path and title embeddings
+    pub subsets: Vec<Option<(u64, u64)>>,
+    // `EmbeddingSource::stable_id` of each of `ids`
+    #[serde(default)]
+    pub stable_ids: Vec<Option<String>>,
+    pub meta: std::collections::HashSet<String>,
+    // the ledger hash of the file when it was last embedded,
+    // empty if it was embedded before hashes were kept
+    pub last_embedded_hash: String,
+    // unix seconds of the file's newest embedding, 0 if its blocks don't say
+    #[serde(default)]
+    pub embedded_at: i64,
+}
+
+// a chunk found by its stable id
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct StableChunk {
+    pub stable_id: String,
+    // the id the chunk has in the blocks now, which a sync or edit can change
+    pub id: u64,
+    pub filepath: String,
+    pub subset: Option<(u64, u64)>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct LedgerEntry {
+    pub filepath: String,
+    pub hash: String,
+    pub meta: std::collections::HashSet<String>,
+}
+
+// what an operation was asked to do and how much of it it did, which differs between operations
+pub type Params = serde_json::Map<String, serde_json::Value>;
+
+// one operation that changed the data directory, as `$DATA_DIR/journal.log` keeps it
+//
+// the journal is JSON lines, oldest first, and is only ever appended to
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Entry {
+    // RFC 3339, in local time, when the operation started
+    pub timestamp: String,
+    pub operation: String,
+    pub params: Params,
+    pub duration_ms: u64,
+    pub outcome: Outcome,
+    // the state generation the operation left behind
+    pub generation: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case", tag = "status")]
+pub enum Outcome {
+    Completed,
+    Failed { error: String },
+}
+
+// how embedding requests are spread out, from the config's
+// `embed_workers`, `max_batch_items`, and `max_batch_tokens`
+//
+// local embedding servers want a worker or two with small batches,
+// while OpenAI is fine with more of both
+//
+// batch sizes are in characters until there's a proper tokenizer
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct EmbedSettings {
+    pub workers: usize,
+    pub max_batch_items: usize,
+    pub max_batch_tokens: usize,
+}
+
+// TODO: a proper tokenizer
+pub const TOKEN_LIMIT: usize = 8192;
+
+pub const DEFAULT_EMBED_WORKERS: usize = 8;
+
+impl Default for EmbedSettings {
+    fn default() -> Self {
+        Self {
+            workers: DEFAULT_EMBED_WORKERS,
+            max_batch_items: usize::MAX,
+            max_batch_tokens: TOKEN_LIMIT,
+        }
+    }
+}
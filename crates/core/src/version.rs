@@ -0,0 +1,58 @@
+// what a binary was built from, for telling a CLI built last month apart from today's server
+
+// the features of this build, as they're named in the manifest
+const FEATURES: [(&str, bool); 10] = [
+    ("cli", cfg!(feature = "cli")),
+    ("client", cfg!(feature = "client")),
+    ("embed", cfg!(feature = "embed")),
+    ("full", cfg!(feature = "full")),
+    ("index", cfg!(feature = "index")),
+    ("otel", cfg!(feature = "otel")),
+    ("regression", cfg!(feature = "regression")),
+    ("schema", cfg!(feature = "schema")),
+    ("server", cfg!(feature = "server")),
+    ("stdout", cfg!(feature = "stdout")),
+];
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct BuildInfo {
+    // the crate's version
+    pub version: String,
+    // the commit built from, from `GIT_COMMIT` or git itself when the build could ask either
+    pub git_hash: Option<String>,
+    pub features: Vec<String>,
+    // see `message::PROTOCOL_VERSION`
+    pub protocol: u32,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: option_env!("DEWEY_GIT_HASH").map(|hash| hash.to_string()),
+            features: FEATURES
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| name.to_string())
+                .collect(),
+            protocol: crate::message::PROTOCOL_VERSION,
+        }
+    }
+}
+
+// `dewey 0.1.0 (1a2b3c4d5e6f), protocol 1, features: cli, client, embed, full, index, server`
+impl std::fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "dewey {}", self.version)?;
+        if let Some(hash) = &self.git_hash {
+            write!(f, " ({})", hash)?;
+        }
+
+        let features = match self.features.is_empty() {
+            true => "none".to_string(),
+            false => self.features.join(", "),
+        };
+        write!(f, ", protocol {}, features: {}", self.protocol, features)
+    }
+}
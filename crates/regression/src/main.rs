@@ -1,6 +1,5 @@
-use std::io::BufRead;
-
-use dewey_lib::logger::Logger;
+use dewey_lib::config;
+use dewey_lib::logger::{parse_records, LogRecord, LogTarget, Logger};
 use dewey_lib::lprint;
 
 struct TestServer {
@@ -11,40 +10,32 @@ struct TestServer {
 impl TestServer {
     pub fn new() -> std::io::Result<Self> {
         let port = get_free_port();
+        let _ = std::fs::remove_file(config::get_log_path(LogTarget::Server));
+
         // this is assuming that the tests are being run from the workspace level
-        let mut process = std::process::Command::new("./target/debug/dewey_server")
+        let process = std::process::Command::new("./target/debug/dewey_server")
             .args(["-p", &port.to_string()])
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
+            .args(data_dir_args())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
             .stdin(std::process::Stdio::null())
             .spawn()?;
 
         let start = std::time::Instant::now();
-        let mut reader = std::io::BufReader::new(process.stdout.take().unwrap());
-        let mut line = String::new();
         loop {
-            if (std::time::Instant::now() - start).as_secs() > 5 {
-                panic!("Error: timed out waiting for `dewey_server` regression testing stdout",);
+            if let Some(regression) = compiled_for_regression(LogTarget::Server) {
+                if !regression {
+                    panic!("Error: `dewey_server` isn't compiled for regression testing");
+                }
+
+                break;
             }
 
-            match reader.read_line(&mut line) {
-                Ok(_) => {
-                    if line.contains("Compiled for regression testing: ") {
-                        let split = line.split(": ").collect::<Vec<_>>();
-                        if split.last().unwrap().trim() != "true" {
-                            lprint!(error, "Regression check: {:?}", split);
-                            panic!("Error: `dewey_server` isn't compiled for regression testing");
-                        }
-
-                        break;
-                    } else {
-                        if line.len() > 0 {
-                            lprint!(info, "server process output: {}", line);
-                        }
-                    }
-                }
-                Err(e) => panic!("error reading server output: {}", e),
+            if (std::time::Instant::now() - start).as_secs() > 5 {
+                panic!("Error: timed out waiting for `dewey_server` to start logging");
             }
+
+            std::thread::sleep(std::time::Duration::from_millis(50));
         }
 
         Ok(Self { process, port })
@@ -58,6 +49,31 @@ impl Drop for TestServer {
     }
 }
 
+// what the `target` process has logged in this run
+fn read_log(target: LogTarget) -> Vec<LogRecord> {
+    std::fs::read_to_string(config::get_log_path(target))
+        .map(|contents| parse_records(&contents))
+        .unwrap_or_default()
+}
+
+// whether the `target` process logged that it was compiled for regression testing,
+// or `None` if it hasn't said yet
+fn compiled_for_regression(target: LogTarget) -> Option<bool> {
+    read_log(target).iter().find_map(|r| {
+        r.message
+            .strip_prefix("Compiled for regression testing: ")
+            .map(|value| value.trim() == "true")
+    })
+}
+
+// points a binary at the harness's data directory, which `main` scopes everything to
+fn data_dir_args() -> [String; 2] {
+    [
+        String::from("--data-dir"),
+        config::get_local_dir().to_string_lossy().to_string(),
+    ]
+}
+
 fn get_free_port() -> u16 {
     let listener = std::net::TcpListener::bind("127.0.0.1:0")
         .expect("Failed to bind address while trying to get free port");
@@ -68,10 +84,7 @@ fn get_free_port() -> u16 {
 }
 
 fn query_test(port: u32) {
-    let client = dewey_lib::DeweyClient {
-        address: String::from("127.0.0.1"),
-        port,
-    };
+    let client = dewey_lib::DeweyClient::new(String::from("127.0.0.1"), port);
 
     let mut retries = 0;
     let max_retries = 5;
@@ -94,7 +107,400 @@ fn query_test(port: u32) {
     assert!(response.is_ok());
 
     let response = response.unwrap();
-    assert!(response.results.len() > 0);
+    assert!(!response.results.is_empty());
+}
+
+fn stats_test(port: u32) {
+    let client = dewey_lib::DeweyClient::new(String::from("127.0.0.1"), port);
+
+    let response = client.stats();
+    assert!(response.is_ok());
+
+    let response = response.unwrap();
+    assert!(!response.blocks.is_empty());
+    assert!(response.blocks.iter().all(|b| b.embeddings > 0));
+    assert_eq!(
+        response.embed_settings,
+        dewey_lib::config::get_embed_settings()
+    );
+
+    let journal = response.journal.unwrap();
+    assert_eq!(journal.outcome, dewey_lib::journal::Outcome::Completed);
+    assert_eq!(journal.generation, response.generation);
+}
+
+// the server, the client, and both binaries were built together, so they all agree on the protocol
+fn version_test(port: u32) {
+    let client = dewey_lib::DeweyClient::new(String::from("127.0.0.1"), port);
+    let current = dewey_lib::version::BuildInfo::current();
+
+    let server = client.server_version().unwrap().unwrap();
+    assert_eq!(server.protocol, dewey_lib::message::PROTOCOL_VERSION);
+    assert_eq!(server.protocol, current.protocol);
+    assert_eq!(server.version, current.version);
+    assert_eq!(server.git_hash, current.git_hash);
+
+    for binary in ["./target/debug/dewey", "./target/debug/dewey_server"] {
+        let output = std::process::Command::new(binary)
+            .arg("--version")
+            .env_remove("OPENAI_API_KEY")
+            .stdin(std::process::Stdio::null())
+            .output()
+            .unwrap();
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        assert!(
+            stdout.contains(&format!("protocol {}", current.protocol)),
+            "{}",
+            stdout
+        );
+    }
+}
+
+// a bad filter gets an error naming it, and the server keeps serving afterwards
+fn bad_filter_test(port: u32) {
+    let client = dewey_lib::DeweyClient::new(String::from("127.0.0.1"), port);
+
+    let filters = vec![
+        String::from("gt rust"),
+        String::from("rust"),
+        String::from("eq a b"),
+    ];
+    let error = client
+        .query(String::from("testing"), 10, filters)
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+    let message = error.to_string();
+    assert!(message.contains("\"gt rust\""), "{}", message);
+    assert!(message.contains("\"eq a b\""), "{}", message);
+    assert!(!message.contains("\"rust\""), "{}", message);
+
+    for _ in 0..3 {
+        let response = client.query(String::from("testing"), 10, vec![String::from("rust")]);
+        assert!(!response.unwrap().results.is_empty());
+    }
+}
+
+// requests that can't be read get an error back or a closed connection,
+// and the server goes on serving the ones after them
+fn malformed_request_test(port: u32) {
+    use std::io::{Read, Write};
+
+    let connect = || std::net::TcpStream::connect(format!("127.0.0.1:{}", port)).unwrap();
+    let send = |bytes: &[u8]| {
+        let mut stream = connect();
+        stream.write_all(bytes).unwrap();
+        // the server can turn the request away and hang up before the rest of it is sent
+        let _ = stream.shutdown(std::net::Shutdown::Write);
+
+        let mut length_bytes = [0u8; 4];
+        stream.read_exact(&mut length_bytes).unwrap();
+        let mut buffer = vec![0u8; u32::from_be_bytes(length_bytes) as usize];
+        stream.read_exact(&mut buffer).unwrap();
+
+        serde_json::from_slice::<dewey_lib::message::DeweyErrorResponse>(&buffer).unwrap()
+    };
+
+    // garbage reads as a length far past what the server takes
+    let response = send(b"this isn't a request");
+    assert_eq!(response.error, "invalid_request");
+
+    // a frame that ends before its length says it should
+    let mut truncated = 100u32.to_be_bytes().to_vec();
+    truncated.extend_from_slice(b"{\"message_type\": \"qu");
+    let response = send(&truncated);
+    assert_eq!(response.error, "invalid_request");
+    assert!(
+        response.message.contains("of 100 bytes"),
+        "{}",
+        response.message
+    );
+
+    let body = b"{\"message_type\": 5}";
+    let mut unparsable = (body.len() as u32).to_be_bytes().to_vec();
+    unparsable.extend_from_slice(body);
+    assert_eq!(send(&unparsable).error, "invalid_request");
+
+    // a connection closed before it sends anything
+    drop(connect());
+
+    let client = dewey_lib::DeweyClient::new(String::from("127.0.0.1"), port);
+    let response = client.query(String::from("testing"), 10, Vec::new());
+    assert!(!response.unwrap().results.is_empty());
+}
+
+// a handler that panics gets an `internal_error` back, and the server goes on serving
+// the requests after it, with its stats saying it's degraded
+fn handler_panic_test(port: u32) {
+    use std::io::{Read, Write};
+
+    let body = b"{\"message_type\": \"panic\", \"payload\": {}}";
+    let mut request = (body.len() as u32).to_be_bytes().to_vec();
+    request.extend_from_slice(body);
+
+    let mut stream = std::net::TcpStream::connect(format!("127.0.0.1:{}", port)).unwrap();
+    stream.write_all(&request).unwrap();
+
+    let mut length_bytes = [0u8; 4];
+    stream.read_exact(&mut length_bytes).unwrap();
+    let mut buffer = vec![0u8; u32::from_be_bytes(length_bytes) as usize];
+    stream.read_exact(&mut buffer).unwrap();
+
+    let response = serde_json::from_slice::<dewey_lib::message::DeweyErrorResponse>(&buffer);
+    assert_eq!(response.unwrap().error, "internal_error");
+
+    let client = dewey_lib::DeweyClient::new(String::from("127.0.0.1"), port);
+    for _ in 0..3 {
+        let response = client.query(String::from("testing"), 10, Vec::new());
+        assert!(!response.unwrap().results.is_empty());
+    }
+    client.flush().unwrap();
+
+    let health = client.stats().unwrap().health;
+    assert!(!health.healthy);
+    assert!(health.degraded_requests >= 6, "{:?}", health);
+    assert_eq!(health.last_panic.as_deref(), Some("panic requested"));
+}
+
+// a burst of edits is written out in far fewer index writes than edits,
+// and a flush leaves the index on disk matching the server's
+fn edit_debounce_test(port: u32) {
+    let client = dewey_lib::DeweyClient::new(String::from("127.0.0.1"), port);
+
+    let before = client.flush().unwrap();
+
+    let filepath = dewey_lib::config::get_home_dir()
+        .join("test_repo")
+        .join("a.rs");
+    for i in 0..10 {
+        std::fs::write(&filepath, format!("fn edit_{}() {{}}\n", i)).unwrap();
+        client
+            .reindex(filepath.to_string_lossy().to_string(), None, false)
+            .unwrap();
+    }
+
+    let after = client.flush().unwrap();
+    assert!(after.flushed);
+
+    let writes = after.index_writes - before.index_writes;
+    assert!((1..=3).contains(&writes), "{} index writes", writes);
+
+    let index_path = dewey_lib::config::get_data_dir().join("index");
+    let index = dewey_lib::hnsw::HNSW::deserialize(&index_path).unwrap();
+    assert_eq!(index.get_last_layer().len(), after.index_nodes);
+
+    // nothing's pending after a flush
+    assert!(!client.flush().unwrap().flushed);
+}
+
+// a burst of edits of one file is queued and embedded once after it goes quiet,
+// while a sync edit is embedded before it's answered
+fn edit_coalesce_test(port: u32) {
+    let client = dewey_lib::DeweyClient::new(String::from("127.0.0.1"), port);
+
+    let before = client.flush().unwrap();
+
+    let filepath = dewey_lib::config::get_home_dir()
+        .join("test_repo")
+        .join("a.rs");
+    for i in 0..20 {
+        std::fs::write(&filepath, format!("fn coalesce_{}() {{}}\n", i)).unwrap();
+        let response = client
+            .reindex(filepath.to_string_lossy().to_string(), None, false)
+            .unwrap();
+        assert!(response.queued);
+    }
+    assert_eq!(client.stats().unwrap().pending_edits, 1);
+
+    let deadline = std::time::Instant::now()
+        + dewey_lib::config::get_edit_quiet_period()
+        + std::time::Duration::from_secs(10);
+    while client.stats().unwrap().pending_edits > 0 {
+        assert!(std::time::Instant::now() < deadline, "edits never drained");
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    let after = client.flush().unwrap();
+    assert_eq!(after.reembeds - before.reembeds, 1);
+
+    let response = client
+        .reindex(filepath.to_string_lossy().to_string(), None, true)
+        .unwrap();
+    assert!(!response.queued);
+    assert_eq!(client.stats().unwrap().pending_edits, 0);
+    assert_eq!(client.flush().unwrap().reembeds - after.reembeds, 1);
+}
+
+// pipes a query into the CLI, which queries the index locally
+fn stdin_test() {
+    use std::io::Write;
+
+    let queries_dir = dewey_lib::config::get_queries_dir();
+    let saved_queries = || std::fs::read_dir(&queries_dir).unwrap().count();
+    let before = saved_queries();
+
+    let mut process = std::process::Command::new("./target/debug/dewey")
+        .args(["--stdin", "-k", "5"])
+        .args(data_dir_args())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    process
+        .stdin
+        .take()
+        .unwrap()
+        .write_all("this is a test query\nread from stdin".as_bytes())
+        .unwrap();
+
+    let output = process.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let results = stdout
+        .lines()
+        .filter(|l| l.trim_start().starts_with(|c: char| c.is_ascii_digit()))
+        .count();
+    assert_eq!(results, 5, "{}", stdout);
+
+    // stdin queries are only kept with --save-query
+    assert_eq!(saved_queries(), before);
+}
+
+// a data directory that isn't there is an error until --init creates it
+fn data_dir_test() {
+    let data_dir = config::get_home_dir().join("missing_data_dir");
+    let run = |init: bool| {
+        std::process::Command::new("./target/debug/dewey")
+            .args(["-s", "--data-dir", &data_dir.to_string_lossy()])
+            .args(init.then_some("--init"))
+            .stdin(std::process::Stdio::null())
+            .output()
+            .unwrap()
+    };
+
+    let output = run(false);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--init"), "{}", stderr);
+    assert!(!data_dir.exists());
+
+    let output = run(true);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(data_dir.join("config").join("rules").exists());
+    assert!(data_dir.join("data").is_dir());
+}
+
+// the self-test passes every stage without an API key, and cleans up after itself
+fn self_test_test() {
+    let output = std::process::Command::new("./target/debug/dewey")
+        .arg("--self-test")
+        .env_remove("OPENAI_API_KEY")
+        .stdin(std::process::Stdio::null())
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    for stage in ["ledger", "embedding", "index", "query", "compaction"] {
+        assert!(
+            stdout
+                .lines()
+                .any(|line| line.starts_with(stage) && line.contains("passed")),
+            "{}",
+            stdout
+        );
+    }
+    assert!(!stdout.contains("Kept"), "{}", stdout);
+}
+
+// queries against one shared state on 1, 2, and then 4 threads,
+// each of which has to find what the queries found one at a time
+//
+// the throughput at each thread count is printed next to the single thread's,
+// which only scales as far as there are cores to run on
+fn concurrent_query_benchmark() {
+    let state = dewey_lib::ServerState::new().unwrap();
+    let options = dewey_lib::SearchOptions {
+        save_query: false,
+        no_cache: true,
+        ..dewey_lib::SearchOptions::new(5)
+    };
+
+    let queries = (0..64)
+        .map(|i| format!("testing {}", i))
+        .collect::<Vec<_>>();
+    let found = |query: &String| {
+        state
+            .search(query, &options)
+            .unwrap()
+            .results
+            .into_iter()
+            .map(|r| (r.filepath, r.subset))
+            .collect::<Vec<_>>()
+    };
+
+    let serial = queries.iter().map(found).collect::<Vec<_>>();
+
+    let paths = config::get_paths();
+    let mut single = None;
+    for threads in [1, 2, 4] {
+        let start = std::time::Instant::now();
+        let results = std::thread::scope(|scope| {
+            let handles = (0..threads)
+                .map(|t| {
+                    let (paths, queries, found) = (paths.clone(), &queries, &found);
+                    scope.spawn(move || {
+                        paths.scope(|| {
+                            queries
+                                .iter()
+                                .skip(t)
+                                .step_by(threads)
+                                .map(|q| (q.clone(), found(q)))
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap())
+                .collect::<std::collections::HashMap<_, _>>()
+        });
+
+        let rate = queries.len() as f64 / start.elapsed().as_secs_f64();
+        for (query, expected) in queries.iter().zip(serial.iter()) {
+            assert_eq!(&results[query], expected, "{}", query);
+        }
+
+        let single = *single.get_or_insert(rate);
+        lprint!(
+            info,
+            "{} threads: {:.0} queries/s, {:.2}x a single thread",
+            threads,
+            rate,
+            rate / single
+        );
+    }
 }
 
 macro_rules! test {
@@ -110,45 +516,52 @@ macro_rules! test {
 // gotta be careful with the process handle + whatnot
 fn main() {
     let _cleanup = dewey_lib::test_common::Cleanup;
+
+    // the binaries get a data directory of their own through --data-dir,
+    // leaving the home layout alone
+    let paths =
+        config::data_dir_paths(Some(&config::get_home_dir().join("data_dir")), true).unwrap();
+    paths.scope(run);
+}
+
+fn run() {
     dewey_lib::test_common::setup().unwrap();
 
-    let mut cli_process = std::process::Command::new("./target/debug/dewey")
+    let _ = std::fs::remove_file(config::get_log_path(LogTarget::Cli));
+    let output = std::process::Command::new("./target/debug/dewey")
         .args(["-rsebf"])
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
+        .args(data_dir_args())
         .stdin(std::process::Stdio::null())
-        .spawn()
+        .output()
         .unwrap();
 
-    let start = std::time::Instant::now();
-    let mut reader = std::io::BufReader::new(cli_process.stdout.take().unwrap());
-    let mut line = String::new();
-    loop {
-        if (std::time::Instant::now() - start).as_secs() > 5 {
-            panic!("Error: timed out waiting for `dewey` regression testing stdout",);
-        }
-
-        match reader.read_line(&mut line) {
-            Ok(_) => {
-                if line.contains("Compiled for regression testing: ") {
-                    let split = line.split(": ").collect::<Vec<_>>();
-                    if split.last().unwrap().trim() != "true" {
-                        panic!("Error: `dewey` isn't compiled for regression testing");
-                    }
-
-                    break;
-                } else {
-                    if line.len() > 0 {
-                        lprint!(info, "cli process output: {}", line);
-                    }
-                }
-            }
-            Err(e) => panic!("error reading server output: {}", e),
-        }
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        lprint!(info, "cli process output: {}", line);
     }
 
-    cli_process.wait().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    match compiled_for_regression(LogTarget::Cli) {
+        Some(true) => {}
+        Some(false) => panic!("Error: `dewey` isn't compiled for regression testing"),
+        None => panic!("Error: `dewey` never logged whether it's compiled for regression testing"),
+    }
 
     let server = TestServer::new().unwrap();
-    test!(query_test(server.port as u32))
+    test!(query_test(server.port as u32));
+    test!(stats_test(server.port as u32));
+    test!(version_test(server.port as u32));
+    test!(bad_filter_test(server.port as u32));
+    test!(malformed_request_test(server.port as u32));
+    test!(edit_debounce_test(server.port as u32));
+    test!(edit_coalesce_test(server.port as u32));
+    test!(handler_panic_test(server.port as u32));
+    test!(stdin_test());
+    test!(data_dir_test());
+    test!(self_test_test());
+    test!(concurrent_query_benchmark());
 }
@@ -85,7 +85,7 @@ pub fn byte_serialize_derive(input: TokenStream) -> TokenStream {
                     }
                 });
 
-                let deserialization_fields = fields.named.iter().filter_map(|field| {
+                let deserialization_fields = fields.named.iter().map(|field| {
                     let ignore = field
                         .attrs
                         .iter()
@@ -94,7 +94,7 @@ pub fn byte_serialize_derive(input: TokenStream) -> TokenStream {
                     let field_name = &field.ident;
                     let ty = &field.ty;
                     if !ignore {
-                        Some(quote! {
+                        quote! {
                             #field_name: {
                                 let (value, count) = <#ty>::from_bytes(bytes, cursor)?;
                                 cursor += count;
@@ -102,11 +102,11 @@ pub fn byte_serialize_derive(input: TokenStream) -> TokenStream {
 
                                 value
                             },
-                        })
+                        }
                     } else {
-                        Some(quote! {
+                        quote! {
                             #field_name: Default::default(),
-                        })
+                        }
                     }
                 });
 